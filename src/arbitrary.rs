@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Implementations of proptest's Arbitrary for the typed target params and
+// tables, so that table handling can be property tested. Every value
+// generated is one which the params' FromStr implementation would accept,
+// so that a value's string representation parses back to the same value.
+
+use std::ops::Range;
+
+use proptest::{collection, option, prelude::*, sample};
+
+use crate::{
+    cachedev::{
+        CacheDevTargetTable, CacheIoMode, CachePolicy, CacheTargetParams, CacheTunable,
+        MAX_CACHE_BLOCK_SIZE, MIN_CACHE_BLOCK_SIZE,
+    },
+    core::Device,
+    lineardev::{
+        Direction, DustTargetParams, FeatureArg, FlakeyTargetParams, LinearDevTargetParams,
+        LinearDevTargetTable, LinearTargetParams,
+    },
+    logwrites::LogWritesTargetParams,
+    thindev::{ThinDevTargetTable, ThinTargetParams},
+    thindevid::ThinDevId,
+    thinpooldev::{
+        ThinPoolDevTargetTable, ThinPoolFeature, ThinPoolTargetParams, MAX_DATA_BLOCK_SIZE,
+        MIN_DATA_BLOCK_SIZE,
+    },
+    units::{Bytes, DataBlocks, Sectors},
+};
+
+/// The range of segment lengths, small enough that the lengths of any
+/// number of segments generated may be summed
+const SEGMENT_LENGTH: Range<u64> = 1..1 << 32;
+
+/// The most segments generated in a linear table
+const MAX_SEGMENTS: usize = 8;
+
+fn sectors(range: Range<u64>) -> impl Strategy<Value = Sectors> {
+    range.prop_map(Sectors)
+}
+
+impl Arbitrary for Device {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Device>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u32>(), any::<u32>())
+            .prop_map(|(major, minor)| Device { major, minor })
+            .boxed()
+    }
+}
+
+impl Arbitrary for ThinDevId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ThinDevId>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (0u64..1 << 24)
+            .prop_map(|id| ThinDevId::new_u64(id).expect("is below limit"))
+            .boxed()
+    }
+}
+
+impl Arbitrary for LinearTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<LinearTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Device>(), any::<u64>())
+            .prop_map(|(device, offset)| LinearTargetParams::new(device, Sectors(offset)))
+            .boxed()
+    }
+}
+
+fn feature_arg() -> impl Strategy<Value = FeatureArg> {
+    prop_oneof![
+        Just(FeatureArg::DropWrites),
+        Just(FeatureArg::ErrorWrites),
+        (
+            any::<u64>(),
+            prop_oneof![Just(Direction::Reads), Just(Direction::Writes)],
+            any::<u8>(),
+            any::<u64>(),
+        )
+            .prop_map(|(offset, direction, value, flags)| {
+                FeatureArg::CorruptBioByte(offset, direction, value, flags)
+            }),
+    ]
+}
+
+impl Arbitrary for FlakeyTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<FlakeyTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<Device>(),
+            any::<u64>(),
+            any::<u32>(),
+            any::<u32>(),
+            collection::vec(feature_arg(), 0..3),
+        )
+            .prop_map(|(device, offset, up, down, features)| {
+                FlakeyTargetParams::new(device, Sectors(offset), up, down, features)
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for DustTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<DustTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Device>(), any::<u64>(), 9u32..13)
+            .prop_map(|(device, offset, shift)| {
+                DustTargetParams::new(device, Sectors(offset), Bytes(1 << shift))
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for LinearDevTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<LinearDevTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            any::<DustTargetParams>().prop_map(LinearDevTargetParams::Dust),
+            any::<FlakeyTargetParams>().prop_map(LinearDevTargetParams::Flakey),
+            any::<LinearTargetParams>().prop_map(LinearDevTargetParams::Linear),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for LinearDevTargetTable {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<LinearDevTargetTable>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        collection::vec(
+            (sectors(SEGMENT_LENGTH), any::<LinearDevTargetParams>()),
+            1..MAX_SEGMENTS,
+        )
+        .prop_map(|segments| {
+            let mut table = LinearDevTargetTable::new(Vec::new());
+            table.append(segments);
+            table
+        })
+        .boxed()
+    }
+}
+
+impl Arbitrary for ThinTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ThinTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<Device>(),
+            any::<ThinDevId>(),
+            option::of(any::<Device>()),
+        )
+            .prop_map(|(pool, thin_id, origin)| ThinTargetParams::new(pool, thin_id, origin))
+            .boxed()
+    }
+}
+
+impl Arbitrary for ThinDevTargetTable {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ThinDevTargetTable>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (sectors(SEGMENT_LENGTH), any::<ThinTargetParams>())
+            .prop_map(|(length, params)| ThinDevTargetTable::new(Sectors(0), length, params))
+            .boxed()
+    }
+}
+
+impl Arbitrary for ThinPoolTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ThinPoolTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<Device>(),
+            any::<Device>(),
+            1..=*MAX_DATA_BLOCK_SIZE / *MIN_DATA_BLOCK_SIZE,
+            any::<u64>(),
+            sample::subsequence(
+                vec![
+                    ThinPoolFeature::IgnoreDiscard,
+                    ThinPoolFeature::NoDiscardPassdown,
+                    ThinPoolFeature::ErrorIfNoSpace,
+                    ThinPoolFeature::SkipBlockZeroing,
+                ],
+                0..=4,
+            ),
+        )
+            .prop_map(|(meta, data, blocks, low_water_mark, features)| {
+                ThinPoolTargetParams::new(
+                    meta,
+                    data,
+                    MIN_DATA_BLOCK_SIZE * blocks,
+                    DataBlocks(low_water_mark),
+                    features
+                        .iter()
+                        .map(|feature| feature.as_str().to_owned())
+                        .collect(),
+                )
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for ThinPoolDevTargetTable {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ThinPoolDevTargetTable>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (sectors(SEGMENT_LENGTH), any::<ThinPoolTargetParams>())
+            .prop_map(|(length, params)| ThinPoolDevTargetTable::new(Sectors(0), length, params))
+            .boxed()
+    }
+}
+
+fn cache_tunable() -> impl Strategy<Value = CacheTunable> {
+    prop_oneof![
+        any::<u64>().prop_map(|val| CacheTunable::MigrationThreshold(Sectors(val))),
+        any::<u64>().prop_map(CacheTunable::SequentialThreshold),
+        any::<u64>().prop_map(CacheTunable::RandomThreshold),
+        any::<u64>().prop_map(CacheTunable::ReadPromoteAdjustment),
+        any::<u64>().prop_map(CacheTunable::WritePromoteAdjustment),
+        any::<u64>().prop_map(CacheTunable::DiscardPromoteAdjustment),
+    ]
+}
+
+impl Arbitrary for CacheTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<CacheTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            (any::<Device>(), any::<Device>(), any::<Device>()),
+            1..=*MAX_CACHE_BLOCK_SIZE / *MIN_CACHE_BLOCK_SIZE,
+            sample::select(vec![
+                CacheIoMode::Writethrough,
+                CacheIoMode::Writeback,
+                CacheIoMode::Passthrough,
+            ]),
+            any::<bool>(),
+            sample::select(vec![
+                CachePolicy::Default,
+                CachePolicy::Smq,
+                CachePolicy::Mq,
+                CachePolicy::Cleaner,
+            ]),
+            collection::vec(cache_tunable(), 0..3),
+        )
+            .prop_map(
+                |((meta, cache, origin), blocks, io_mode, metadata2, policy, tunables)| {
+                    let mut params = CacheTargetParams::new(
+                        meta,
+                        cache,
+                        origin,
+                        MIN_CACHE_BLOCK_SIZE * blocks,
+                        if metadata2 {
+                            vec!["metadata2".to_owned()]
+                        } else {
+                            vec![]
+                        },
+                        String::new(),
+                        vec![],
+                    );
+                    params.set_io_mode(io_mode);
+                    params.set_policy(policy, &tunables);
+                    params
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for CacheDevTargetTable {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<CacheDevTargetTable>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (sectors(SEGMENT_LENGTH), any::<CacheTargetParams>())
+            .prop_map(|(length, params)| CacheDevTargetTable::new(Sectors(0), length, params))
+            .boxed()
+    }
+}
+
+impl Arbitrary for LogWritesTargetParams {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<LogWritesTargetParams>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Device>(), any::<Device>())
+            .prop_map(|(device, log_device)| LogWritesTargetParams::new(device, log_device))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{
+        result::DmError,
+        shared::{TargetParams, TargetTable},
+    };
+
+    use super::*;
+
+    fn assert_params_round_trip<P>(params: P) -> Result<(), TestCaseError>
+    where
+        P: TargetParams + FromStr<Err = DmError>,
+    {
+        prop_assert_eq!(params.to_string().parse::<P>().unwrap(), params);
+        Ok(())
+    }
+
+    fn assert_table_round_trip<T>(table: T) -> Result<(), TestCaseError>
+    where
+        T: TargetTable,
+    {
+        prop_assert_eq!(T::from_raw_table(&table.to_raw_table()).unwrap(), table);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn test_device_round_trip(device in any::<Device>()) {
+            prop_assert_eq!(device.to_string().parse::<Device>().unwrap(), device);
+        }
+
+        #[test]
+        fn test_linear_params_round_trip(params in any::<LinearDevTargetParams>()) {
+            assert_params_round_trip(params)?;
+        }
+
+        #[test]
+        fn test_linear_table_round_trip(table in any::<LinearDevTargetTable>()) {
+            assert_table_round_trip(table)?;
+        }
+
+        #[test]
+        fn test_thin_table_round_trip(table in any::<ThinDevTargetTable>()) {
+            assert_params_round_trip(table.table.params.clone())?;
+            assert_table_round_trip(table)?;
+        }
+
+        #[test]
+        fn test_thinpool_table_round_trip(table in any::<ThinPoolDevTargetTable>()) {
+            assert_params_round_trip(table.table.params.clone())?;
+            assert_table_round_trip(table)?;
+        }
+
+        #[test]
+        fn test_cache_table_round_trip(table in any::<CacheDevTargetTable>()) {
+            assert_params_round_trip(table.table.params.clone())?;
+            assert_table_round_trip(table)?;
+        }
+
+        #[test]
+        fn test_log_writes_params_round_trip(params in any::<LogWritesTargetParams>()) {
+            assert_params_round_trip(params)?;
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A small dmsetup-like tool built on the public API of this crate.
+
+use std::{
+    env,
+    error::Error,
+    io::{self, BufRead},
+    process,
+};
+
+use devicemapper::{
+    stats_create, stats_delete, stats_list, stats_print, DevId, DmError, DmFlags, DmName,
+    DmOptions, DmResult, DmUuid, ErrorEnum, StatsRange, StatsRegionSpec, StatsStep, DM,
+};
+
+const USAGE: &str = "\
+Usage: dmtool <command> [<args>]
+
+Devices are named by their DM name, or by their uuid if prefixed with
+\"uuid:\".
+
+Commands:
+    ls                              list all devices
+    info <device>                   show a device's identifiers and state
+    table <device>                  show a device's active table
+    status <device>                 show a device's status
+    create <name> [<uuid>]          create a device with no table
+    load <device>                   load a table, read from stdin, into a
+                                    device's inactive slot
+    resume <device>                 resume a device, activating any loaded
+                                    table
+    suspend <device>                suspend a device
+    remove <device>                 remove a device
+    message <device> <sector> <msg> send a message to a device's target
+    stats list <device>             list a device's stats regions
+    stats create <device>           create a stats region for a whole device
+    stats print <device> <region>   show the counters of a stats region
+    stats delete <device> <region>  delete a stats region";
+
+fn invalid(msg: &str) -> DmError {
+    DmError::Dm(ErrorEnum::Invalid, format!("{msg}\n\n{USAGE}"))
+}
+
+fn dev_id(arg: &str) -> DmResult<DevId<'_>> {
+    match arg.strip_prefix("uuid:") {
+        Some(uuid) => Ok(DevId::Uuid(DmUuid::new(uuid)?)),
+        None => Ok(DevId::Name(DmName::new(arg)?)),
+    }
+}
+
+fn parse_u64(arg: &str, desc: &str) -> DmResult<u64> {
+    arg.parse::<u64>()
+        .map_err(|_| invalid(&format!("{desc} \"{arg}\" is not a number")))
+}
+
+/// Read a table in dmsetup's format, one "<start> <length> <type> <params>"
+/// line per target.
+fn read_table() -> Result<Vec<(u64, u64, String, String)>, Box<dyn Error>> {
+    let mut table = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(4, char::is_whitespace);
+        let (start, length, target_type) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(start), Some(length), Some(target_type)) => (start, length, target_type),
+            _ => return Err(invalid(&format!("table line \"{line}\" has too few fields")).into()),
+        };
+        table.push((
+            parse_u64(start, "start")?,
+            parse_u64(length, "length")?,
+            target_type.to_string(),
+            fields.next().unwrap_or("").trim().to_string(),
+        ));
+    }
+    Ok(table)
+}
+
+fn print_table(table: &[(u64, u64, String, String)]) {
+    for (start, length, target_type, params) in table {
+        println!("{start} {length} {target_type} {params}");
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+    let dm = DM::from_env()?;
+
+    match args.as_slice() {
+        ["ls"] => {
+            for (name, device, _) in dm.list_devices()? {
+                println!("{name}\t({device})");
+            }
+        }
+        ["info", device] => {
+            let info = dm.device_info(&dev_id(device)?)?;
+            println!(
+                "Name:        {}",
+                info.name().map_or("".into(), |n| n.to_string())
+            );
+            println!(
+                "UUID:        {}",
+                info.uuid().map_or("".into(), |u| u.to_string())
+            );
+            println!("Device:      {}", info.device());
+            println!("Open count:  {}", info.open_count());
+            println!("Event:       {}", info.event_nr());
+            println!(
+                "State:       {}",
+                if info.flags().contains(DmFlags::DM_SUSPEND) {
+                    "SUSPENDED"
+                } else {
+                    "ACTIVE"
+                }
+            );
+            println!(
+                "Tables:      {}{}",
+                if info.flags().contains(DmFlags::DM_ACTIVE_PRESENT) {
+                    "LIVE "
+                } else {
+                    ""
+                },
+                if info.flags().contains(DmFlags::DM_INACTIVE_PRESENT) {
+                    "INACTIVE"
+                } else {
+                    ""
+                }
+            );
+        }
+        ["table", device] => {
+            let options = DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE);
+            print_table(&dm.table_status(&dev_id(device)?, options)?.1);
+        }
+        ["status", device] => {
+            print_table(&dm.table_status(&dev_id(device)?, DmOptions::default())?.1);
+        }
+        ["create", name] => {
+            dm.device_create(DmName::new(name)?, None, DmOptions::default())?;
+        }
+        ["create", name, uuid] => {
+            dm.device_create(
+                DmName::new(name)?,
+                Some(DmUuid::new(uuid)?),
+                DmOptions::default(),
+            )?;
+        }
+        ["load", device] => {
+            dm.table_load(&dev_id(device)?, &read_table()?, DmOptions::default())?;
+        }
+        ["resume", device] => {
+            dm.device_suspend(&dev_id(device)?, DmOptions::default())?;
+        }
+        ["suspend", device] => {
+            dm.device_suspend(
+                &dev_id(device)?,
+                DmOptions::default().set_flags(DmFlags::DM_SUSPEND),
+            )?;
+        }
+        ["remove", device] => {
+            dm.device_remove(&dev_id(device)?, DmOptions::default())?;
+        }
+        ["message", device, sector, msg @ ..] if !msg.is_empty() => {
+            let sector = parse_u64(sector, "sector")?;
+            if let (_, Some(output)) =
+                dm.target_msg(&dev_id(device)?, Some(sector), &msg.join(" "))?
+            {
+                println!("{output}");
+            }
+        }
+        ["stats", "list", device] => {
+            for region in stats_list(&dm, &dev_id(device)?, None)? {
+                println!(
+                    "{}: {}+{} {} {} {}",
+                    region.region_id,
+                    *region.start,
+                    *region.length,
+                    *region.area_size,
+                    region.program_id,
+                    region.aux_data
+                );
+            }
+        }
+        ["stats", "create", device] => {
+            let spec = StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1));
+            println!("{}", stats_create(&dm, &dev_id(device)?, &spec)?);
+        }
+        ["stats", "print", device, region] => {
+            let region_id = parse_u64(region, "region id")?;
+            for area in stats_print(&dm, &dev_id(device)?, region_id, false)? {
+                let c = area.counters;
+                println!(
+                    "{}+{} {} {} {} {} {} {} {} {} {} {} {} {} {}",
+                    *area.start,
+                    *area.length,
+                    c.reads,
+                    c.reads_merged,
+                    c.read_sectors,
+                    c.read_ticks,
+                    c.writes,
+                    c.writes_merged,
+                    c.write_sectors,
+                    c.write_ticks,
+                    c.in_flight,
+                    c.io_ticks,
+                    c.weighted_io_ticks,
+                    c.total_read_ticks,
+                    c.total_write_ticks
+                );
+            }
+        }
+        ["stats", "delete", device, region] => {
+            stats_delete(&dm, &dev_id(device)?, parse_u64(region, "region id")?)?;
+        }
+        [] => return Err(invalid("no command given").into()),
+        _ => return Err(invalid(&format!("invalid command \"{}\"", args.join(" "))).into()),
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        eprintln!("dmtool: {err}");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,1370 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A module to contain functionality for querying and manipulating the block
+// devices which back DM devices.
+
+use std::{
+    cmp::min,
+    fmt,
+    fs::{read_to_string, File, OpenOptions},
+    io::ErrorKind,
+    mem::size_of,
+    os::unix::{
+        fs::{FileExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    libc::{c_int, c_uint, O_DIRECT, O_EXCL},
+};
+
+use crate::{
+    core::{claims, errors, Device, DM, SYSFS_DEV_BLOCK_PATH},
+    result::{DmError, DmResult, ErrorEnum},
+    units::{Bytes, MetaBlocks, Sectors},
+};
+
+/// Directory containing device nodes named by "<major>:<minor>"
+const DEV_BLOCK_PATH: &str = "/dev/block";
+
+// send IOCTL via blkgetsize64
+ioctl_read!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkgetsize64,
+    0x12,
+    114,
+    u64
+);
+
+// send IOCTL via blksszget
+ioctl_read_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blksszget,
+    request_code_none!(0x12, 104),
+    c_int
+);
+
+// send IOCTL via blkpbszget
+ioctl_read_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkpbszget,
+    request_code_none!(0x12, 123),
+    c_uint
+);
+
+// send IOCTL via blkiomin
+ioctl_read_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkiomin,
+    request_code_none!(0x12, 120),
+    c_uint
+);
+
+// send IOCTL via blkioopt
+ioctl_read_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkioopt,
+    request_code_none!(0x12, 121),
+    c_uint
+);
+
+// send IOCTL via blkdiscard
+ioctl_write_ptr_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkdiscard,
+    request_code_none!(0x12, 119),
+    [u64; 2]
+);
+
+// send IOCTL via blksecdiscard
+ioctl_write_ptr_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blksecdiscard,
+    request_code_none!(0x12, 125),
+    [u64; 2]
+);
+
+// send IOCTL via blkreportzone
+ioctl_readwrite_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkreportzone,
+    request_code_readwrite!(0x12, 130, size_of::<BlkZoneReport>()),
+    ZoneReportBuf
+);
+
+// send IOCTL via blkresetzone
+ioctl_write_ptr!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkresetzone,
+    0x12,
+    131,
+    BlkZoneRange
+);
+
+// send IOCTL via blkgetzonesz
+ioctl_read!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    blkgetzonesz,
+    0x12,
+    132,
+    u32
+);
+
+/// Alignment of the buffer used for O_DIRECT writes, sufficient for any
+/// logical block size.
+pub(crate) const DIRECT_IO_ALIGN: usize = 4096;
+
+/// The largest single write issued when wiping a device
+const WIPE_CHUNK_SIZE: usize = 1 << 20; // 1 MiB
+
+/// The path of the device node for the given device number.
+pub(crate) fn device_path(device: Device) -> PathBuf {
+    [DEV_BLOCK_PATH, &device.to_string()].iter().collect()
+}
+
+/// The error returned when the block device at the given path can not be
+/// opened.
+fn open_error(path: &Path, err: std::io::Error) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to open block device {}: {}",
+        path.display(),
+        err
+    )))
+}
+
+/// Open the block device at the given path with the given options.
+pub(crate) fn open_blkdev(path: &Path, options: &OpenOptions) -> DmResult<File> {
+    options.open(path).map_err(|err| open_error(path, err))
+}
+
+/// Whether the block device with the given device number is claimed
+/// exclusively by some other user, i.e., whether opening it with O_EXCL
+/// fails because it is busy.
+pub(crate) fn device_claimed(device: Device) -> DmResult<bool> {
+    let path = device_path(device);
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(O_EXCL)
+        .open(&path)
+    {
+        Ok(_) => Ok(false),
+        Err(err) if err.raw_os_error() == Some(Errno::EBUSY as i32) => Ok(true),
+        Err(err) => Err(open_error(&path, err)),
+    }
+}
+
+/// Get the size of an open block device.
+pub(crate) fn blkdev_file_size(file: &File) -> DmResult<Bytes> {
+    let mut val: u64 = 0;
+
+    unsafe { blkgetsize64(file.as_raw_fd(), &mut val) }
+        .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+    Ok(Bytes(u128::from(val)))
+}
+
+/// Get the logical block size of an open block device.
+pub(crate) fn blkdev_file_logical_block_size(file: &File) -> DmResult<Bytes> {
+    let mut val: c_int = 0;
+
+    unsafe { blksszget(file.as_raw_fd(), &mut val) }
+        .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+    Ok(Bytes(val as u128))
+}
+
+/// Get the size of the block device at the given path.
+pub fn blkdev_size(path: &Path) -> DmResult<Bytes> {
+    blkdev_file_size(&open_blkdev(path, OpenOptions::new().read(true))?)
+}
+
+/// Get the size of the block device with the given device number.
+pub fn device_size(device: Device) -> DmResult<Bytes> {
+    blkdev_size(&device_path(device))
+}
+
+/// Verify that the block device with the given device number is not
+/// claimed by any other user, by opening it with O_EXCL, which fails if
+/// a filesystem is mounted on the device, a DM table refers to it, or any
+/// other user holds it exclusively. If the device is claimed, the error
+/// lists whichever of those users could be identified.
+pub fn check_exclusive(dm: &DM, device: Device) -> DmResult<()> {
+    if device_claimed(device)? {
+        return Err(DmError::Core(errors::Error::InUse(
+            device.to_string(),
+            claims(dm, device)?,
+        )));
+    }
+    Ok(())
+}
+
+/// Verify that a target segment which starts at `offset` on `device` and
+/// extends for `length` sectors fits on the device.
+pub fn check_segment_fits(device: Device, offset: Sectors, length: Sectors) -> DmResult<()> {
+    let size = device_size(device)?.sectors();
+    match offset.checked_add(length) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "segment at offset {offset} with length {length} does not fit on device {device} of size {size}"
+            ),
+        )),
+    }
+}
+
+/// Overwrite `length` sectors of the block device at `path` with zeroes,
+/// starting at `offset`. The writes bypass the page cache, and the device
+/// is synced before returning, so that the zeroes are seen by anything that
+/// subsequently opens the device, including the kernel.
+///
+/// Both `offset` and `length` must be multiples of the device's logical
+/// block size.
+pub fn wipe_sectors(path: &Path, offset: Sectors, length: Sectors) -> DmResult<()> {
+    let file = open_blkdev(path, OpenOptions::new().write(true).custom_flags(O_DIRECT))?;
+
+    let block_size = *blkdev_file_logical_block_size(&file)?;
+    let (start, len) = (*offset.bytes(), *length.bytes());
+    if start % block_size != 0 || len % block_size != 0 {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "offset {offset} and length {length} must be multiples of the logical block size, {block_size} bytes, of {}",
+                path.display()
+            ),
+        ));
+    }
+
+    let size = blkdev_file_size(&file)?;
+    let end = start + len;
+    if end > *size {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "region at offset {offset} with length {length} extends beyond the end of {} of size {}",
+                path.display(),
+                size.sectors()
+            ),
+        ));
+    }
+
+    // O_DIRECT requires that the buffer be aligned in memory, so pick out an
+    // aligned region of a slightly oversized buffer.
+    let buf = vec![0u8; WIPE_CHUNK_SIZE + DIRECT_IO_ALIGN];
+    let align = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    let zeroes = &buf[align..align + WIPE_CHUNK_SIZE];
+
+    let mut pos = start;
+    while pos < end {
+        let count = min(end - pos, WIPE_CHUNK_SIZE as u128) as usize;
+        file.write_all_at(&zeroes[..count], pos as u64)
+            .map_err(|err| {
+                DmError::Core(errors::Error::GeneralIo(format!(
+                    "failed to wipe {} at byte offset {}: {}",
+                    path.display(),
+                    pos,
+                    err
+                )))
+            })?;
+        pos += count as u128;
+    }
+
+    file.sync_all().map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to sync {}: {}",
+            path.display(),
+            err
+        )))
+    })?;
+    debug!("Wiped {} at offset {}", length, offset);
+    Ok(())
+}
+
+/// Zero the superblock of a thin pool or cache metadata device, which both
+/// occupy the first metadata block of the device, so that a new pool or
+/// cache created on the device does not pick up stale metadata.
+pub fn wipe_metadata_superblock(path: &Path) -> DmResult<()> {
+    wipe_sectors(path, Sectors(0), MetaBlocks(1).sectors())
+}
+
+/// A signature of existing data, such as a filesystem or the metadata of a
+/// volume manager, which may be found at the start of a block device.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DeviceSignature {
+    /// An ext2, ext3, or ext4 filesystem
+    Ext,
+    /// An XFS filesystem
+    Xfs,
+    /// A btrfs filesystem
+    Btrfs,
+    /// A FAT filesystem
+    Vfat,
+    /// An NTFS filesystem
+    Ntfs,
+    /// A swap area
+    Swap,
+    /// A LUKS encrypted device
+    Luks,
+    /// A member of an MD RAID array with a version 1.1 or 1.2 superblock
+    MdRaid,
+    /// An LVM2 physical volume
+    LvmPhysicalVolume,
+    /// A bcache backing or cache device
+    Bcache,
+    /// Thin pool metadata
+    ThinPoolMetadata,
+    /// Cache metadata
+    CacheMetadata,
+}
+
+impl fmt::Display for DeviceSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeviceSignature::Ext => "ext2/3/4 filesystem",
+            DeviceSignature::Xfs => "XFS filesystem",
+            DeviceSignature::Btrfs => "btrfs filesystem",
+            DeviceSignature::Vfat => "FAT filesystem",
+            DeviceSignature::Ntfs => "NTFS filesystem",
+            DeviceSignature::Swap => "swap area",
+            DeviceSignature::Luks => "LUKS header",
+            DeviceSignature::MdRaid => "MD RAID superblock",
+            DeviceSignature::LvmPhysicalVolume => "LVM2 physical volume label",
+            DeviceSignature::Bcache => "bcache superblock",
+            DeviceSignature::ThinPoolMetadata => "thin pool metadata",
+            DeviceSignature::CacheMetadata => "cache metadata",
+        })
+    }
+}
+
+/// The magic values which identify each signature, with their offsets in
+/// bytes from the start of the device.
+const SIGNATURE_MAGICS: &[(DeviceSignature, usize, &[u8])] = &[
+    (DeviceSignature::Ext, 1080, &[0x53, 0xef]),
+    (DeviceSignature::Xfs, 0, b"XFSB"),
+    (DeviceSignature::Btrfs, 65600, b"_BHRfS_M"),
+    (DeviceSignature::Vfat, 54, b"FAT12   "),
+    (DeviceSignature::Vfat, 54, b"FAT16   "),
+    (DeviceSignature::Vfat, 82, b"FAT32   "),
+    (DeviceSignature::Ntfs, 3, b"NTFS    "),
+    // The magic is at the end of the first page, for each page size.
+    (DeviceSignature::Swap, 4086, b"SWAPSPACE2"),
+    (DeviceSignature::Swap, 4086, b"SWAP-SPACE"),
+    (DeviceSignature::Swap, 8182, b"SWAPSPACE2"),
+    (DeviceSignature::Swap, 16374, b"SWAPSPACE2"),
+    (DeviceSignature::Swap, 65526, b"SWAPSPACE2"),
+    (DeviceSignature::Luks, 0, b"LUKS\xba\xbe"),
+    (DeviceSignature::MdRaid, 0, &[0xfc, 0x4e, 0x2b, 0xa9]),
+    (DeviceSignature::MdRaid, 4096, &[0xfc, 0x4e, 0x2b, 0xa9]),
+    // The label may be in any of the first four sectors.
+    (DeviceSignature::LvmPhysicalVolume, 0, b"LABELONE"),
+    (DeviceSignature::LvmPhysicalVolume, 512, b"LABELONE"),
+    (DeviceSignature::LvmPhysicalVolume, 1024, b"LABELONE"),
+    (DeviceSignature::LvmPhysicalVolume, 1536, b"LABELONE"),
+    (
+        DeviceSignature::Bcache,
+        4120,
+        &[
+            0xc6, 0x85, 0x73, 0xf6, 0x4e, 0x1a, 0x45, 0xca, 0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba,
+            0x6d, 0x81,
+        ],
+    ),
+    // The magic, 27022010 for thin pool metadata and 06142003 for cache
+    // metadata, follows the checksum, flags, block number and uuid of the
+    // superblock, as a little-endian u64.
+    (
+        DeviceSignature::ThinPoolMetadata,
+        32,
+        &[0xba, 0x52, 0x9c, 0x01, 0, 0, 0, 0],
+    ),
+    (
+        DeviceSignature::CacheMetadata,
+        32,
+        &[0x33, 0xb8, 0x5d, 0x00, 0, 0, 0, 0],
+    ),
+];
+
+/// The number of bytes read from a device to probe it for signatures,
+/// enough to cover every magic
+const SIGNATURE_PROBE_SIZE: usize = 69632; // 68 KiB
+
+/// Find the signatures whose magic values appear in `buf`, which holds the
+/// start of a device, each signature at most once.
+fn match_signatures(buf: &[u8]) -> Vec<DeviceSignature> {
+    let mut result = Vec::new();
+    for (signature, offset, magic) in SIGNATURE_MAGICS {
+        if buf.get(*offset..offset + magic.len()) == Some(*magic) && !result.contains(signature) {
+            result.push(*signature);
+        }
+    }
+    result
+}
+
+/// Probe the block device at `path`, starting at `offset`, for signatures
+/// of existing data, such as a filesystem, a swap area, a LUKS header, or
+/// RAID or LVM metadata. Only the start of the range is examined, so
+/// metadata which is kept at the end of a device, e.g., an MD RAID
+/// superblock of version 0.90 or 1.0, is not found.
+pub fn probe_signatures(path: &Path, offset: Sectors) -> DmResult<Vec<DeviceSignature>> {
+    let file = open_blkdev(path, OpenOptions::new().read(true))?;
+    let mut buf = vec![0u8; SIGNATURE_PROBE_SIZE];
+    let mut filled = 0;
+    // The device may be shorter than the probe.
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], *offset.bytes() as u64 + filled as u64) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => {
+                return Err(DmError::Core(errors::Error::GeneralIo(format!(
+                    "failed to read {}: {}",
+                    path.display(),
+                    err
+                ))))
+            }
+        }
+    }
+    Ok(match_signatures(&buf[..filled]))
+}
+
+/// Verify that the block device with the given device number holds no
+/// signature of existing data at `offset`, so that a table mapped over it
+/// does not overwrite a filesystem or volume which is still wanted. If
+/// `force` is true, any signatures found are logged and ignored.
+///
+/// The tables of linear, crypt and integrity devices, and
+/// `AeadCryptStack`, check their backing devices with this; the devices of
+/// a thin pool or cache are checked when they are set up with
+/// `LinearDev::setup_checked`.
+pub fn check_signatures(device: Device, offset: Sectors, force: bool) -> DmResult<()> {
+    let signatures = probe_signatures(&device_path(device), offset)?;
+    if signatures.is_empty() {
+        return Ok(());
+    }
+    let found = signatures
+        .iter()
+        .map(|signature| signature.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if force {
+        warn!(
+            "Ignoring existing {} on device {} at offset {}",
+            found, device, offset
+        );
+        Ok(())
+    } else {
+        Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("device {device} contains an existing {found} at offset {offset}; force is required to overwrite it"),
+        ))
+    }
+}
+
+/// The kind of discard to issue for a range of a block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiscardKind {
+    /// An ordinary discard, via BLKDISCARD. The device may deallocate the
+    /// range, but the previous contents may still be recoverable.
+    Discard,
+    /// A secure discard, via BLKSECDISCARD. The device must ensure that the
+    /// previous contents of the range can not be recovered.
+    Secure,
+}
+
+/// Discard `length` sectors of the block device at `path`, starting at
+/// `offset`, so that thinly provisioned or flash storage under the device
+/// may reclaim the space. This is intended for use on ranges which have
+/// been released, e.g., when a segment is removed from a linear device; the
+/// contents of the range are undefined afterwards.
+///
+/// Returns an error if the device does not support the requested kind of
+/// discard.
+pub fn discard_sectors(
+    path: &Path,
+    offset: Sectors,
+    length: Sectors,
+    kind: DiscardKind,
+) -> DmResult<()> {
+    let file = open_blkdev(path, OpenOptions::new().write(true))?;
+
+    let size = blkdev_file_size(&file)?.sectors();
+    match offset.checked_add(length) {
+        Some(end) if end <= size => (),
+        _ => {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "region at offset {offset} with length {length} extends beyond the end of {} of size {size}",
+                    path.display()
+                ),
+            ))
+        }
+    }
+
+    let range = [*offset.bytes() as u64, *length.bytes() as u64];
+    match kind {
+        DiscardKind::Discard => unsafe { blkdiscard(file.as_raw_fd(), &range) },
+        DiscardKind::Secure => unsafe { blksecdiscard(file.as_raw_fd(), &range) },
+    }
+    .map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to discard {} at offset {} with length {}: {}",
+            path.display(),
+            offset,
+            length,
+            err
+        )))
+    })?;
+    debug!("Discarded {} at offset {}", length, offset);
+    Ok(())
+}
+
+/// The number of zones requested from the kernel in a single zone report
+const ZONE_REPORT_BATCH: usize = 128;
+
+/// Zone report flag indicating that the capacity field of each zone is valid
+const BLK_ZONE_REP_CAPACITY: u32 = 1;
+
+/// Layout of struct blk_zone in linux/blkzoned.h
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+pub struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    type_: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+/// Layout of struct blk_zone_report in linux/blkzoned.h, without the
+/// trailing flexible array of zones
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+pub struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+/// A struct blk_zone_report followed by room for a batch of zones
+#[repr(C)]
+pub struct ZoneReportBuf {
+    header: BlkZoneReport,
+    zones: [BlkZone; ZONE_REPORT_BATCH],
+}
+
+/// Layout of struct blk_zone_range in linux/blkzoned.h
+#[repr(C)]
+#[allow(dead_code)]
+pub struct BlkZoneRange {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+/// The type of a zone on a zoned block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZoneType {
+    /// The zone may be written at random.
+    Conventional,
+    /// The zone must be written sequentially.
+    SequentialWriteRequired,
+    /// The zone should be written sequentially.
+    SequentialWritePreferred,
+    /// A zone type unknown to this library
+    Unknown(u8),
+}
+
+impl From<u8> for ZoneType {
+    fn from(val: u8) -> ZoneType {
+        match val {
+            1 => ZoneType::Conventional,
+            2 => ZoneType::SequentialWriteRequired,
+            3 => ZoneType::SequentialWritePreferred,
+            _ => ZoneType::Unknown(val),
+        }
+    }
+}
+
+/// The condition of a zone on a zoned block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZoneCondition {
+    /// The zone has no write pointer, i.e., it is a conventional zone.
+    NotWritePointer,
+    /// The zone is empty.
+    Empty,
+    /// The zone was opened implicitly by a write.
+    ImplicitOpen,
+    /// The zone was opened explicitly.
+    ExplicitOpen,
+    /// The zone is closed.
+    Closed,
+    /// The zone is read-only.
+    ReadOnly,
+    /// The zone is full.
+    Full,
+    /// The zone is offline and may not be read or written.
+    Offline,
+    /// A zone condition unknown to this library
+    Unknown(u8),
+}
+
+impl From<u8> for ZoneCondition {
+    fn from(val: u8) -> ZoneCondition {
+        match val {
+            0x0 => ZoneCondition::NotWritePointer,
+            0x1 => ZoneCondition::Empty,
+            0x2 => ZoneCondition::ImplicitOpen,
+            0x3 => ZoneCondition::ExplicitOpen,
+            0x4 => ZoneCondition::Closed,
+            0xd => ZoneCondition::ReadOnly,
+            0xe => ZoneCondition::Full,
+            0xf => ZoneCondition::Offline,
+            _ => ZoneCondition::Unknown(val),
+        }
+    }
+}
+
+/// A zone of a zoned block device, as reported by BLKREPORTZONE.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Zone {
+    /// The first sector of the zone
+    pub start: Sectors,
+    /// The length of the zone
+    pub length: Sectors,
+    /// The number of sectors of the zone which may be written, which may
+    /// be less than its length
+    pub capacity: Sectors,
+    /// The position of the write pointer of the zone
+    pub write_pointer: Sectors,
+    /// The type of the zone
+    pub zone_type: ZoneType,
+    /// The condition of the zone
+    pub condition: ZoneCondition,
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "zone at {} with length {}: {:?}, {:?}, write pointer at {}",
+            self.start, self.length, self.zone_type, self.condition, self.write_pointer
+        )
+    }
+}
+
+/// Get the zone size of the block device at `path`. Returns 0 if the device
+/// is not zoned.
+pub fn zone_size(path: &Path) -> DmResult<Sectors> {
+    let file = open_blkdev(path, OpenOptions::new().read(true))?;
+    let mut val: u32 = 0;
+
+    unsafe { blkgetzonesz(file.as_raw_fd(), &mut val) }
+        .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+    Ok(Sectors(u64::from(val)))
+}
+
+/// Get all the zones of the zoned block device at `path`, starting with
+/// the zone which contains the sector `start`.
+pub fn report_zones(path: &Path, start: Sectors) -> DmResult<Vec<Zone>> {
+    let file = open_blkdev(path, OpenOptions::new().read(true))?;
+    let size = blkdev_file_size(&file)?.sectors();
+
+    let mut buf = ZoneReportBuf {
+        header: BlkZoneReport::default(),
+        zones: [BlkZone::default(); ZONE_REPORT_BATCH],
+    };
+    let mut result = Vec::new();
+    let mut sector = *start;
+    while sector < *size {
+        buf.header = BlkZoneReport {
+            sector,
+            nr_zones: ZONE_REPORT_BATCH as u32,
+            flags: 0,
+        };
+        unsafe { blkreportzone(file.as_raw_fd(), &mut buf) }.map_err(|err| {
+            DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to report zones of {} from sector {}: {}",
+                path.display(),
+                sector,
+                err
+            )))
+        })?;
+
+        let count = min(buf.header.nr_zones as usize, ZONE_REPORT_BATCH);
+        if count == 0 {
+            break;
+        }
+        let has_capacity = buf.header.flags & BLK_ZONE_REP_CAPACITY != 0;
+        for zone in &buf.zones[..count] {
+            result.push(Zone {
+                start: Sectors(zone.start),
+                length: Sectors(zone.len),
+                capacity: Sectors(if has_capacity {
+                    zone.capacity
+                } else {
+                    zone.len
+                }),
+                write_pointer: Sectors(zone.wp),
+                zone_type: ZoneType::from(zone.type_),
+                condition: ZoneCondition::from(zone.cond),
+            });
+        }
+        let last = &buf.zones[count - 1];
+        sector = last.start + last.len;
+    }
+    Ok(result)
+}
+
+/// Reset the write pointers of the zones of the zoned block device at
+/// `path` in the range starting at `offset` and extending for `length`
+/// sectors, discarding their contents. The range must be zone aligned.
+pub fn reset_zones(path: &Path, offset: Sectors, length: Sectors) -> DmResult<()> {
+    check_zone_aligned(path, offset, length)?;
+
+    let file = open_blkdev(path, OpenOptions::new().write(true))?;
+    let range = BlkZoneRange {
+        sector: *offset,
+        nr_sectors: *length,
+    };
+    unsafe { blkresetzone(file.as_raw_fd(), &range) }.map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to reset zones of {} at offset {} with length {}: {}",
+            path.display(),
+            offset,
+            length,
+            err
+        )))
+    })?;
+    debug!("Reset zones at offset {} with length {}", offset, length);
+    Ok(())
+}
+
+/// Verify that both ends of the range of the block device at `path`
+/// starting at `offset` and extending for `length` sectors fall on zone
+/// boundaries, as the zoned and zone-aware targets require. The end of the
+/// device is also considered a zone boundary, since the last zone may be
+/// smaller than the others. Any range is aligned on a device which is not
+/// zoned.
+pub fn check_zone_aligned(path: &Path, offset: Sectors, length: Sectors) -> DmResult<()> {
+    let zone_size = zone_size(path)?;
+    if zone_size == Sectors(0) {
+        return Ok(());
+    }
+
+    let size = blkdev_size(path)?.sectors();
+    let end = match offset.checked_add(length) {
+        Some(end) if end <= size => end,
+        _ => {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "region at offset {offset} with length {length} extends beyond the end of {} of size {size}",
+                    path.display()
+                ),
+            ))
+        }
+    };
+    if !offset.is_aligned(zone_size) || (!end.is_aligned(zone_size) && end != size) {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "region at offset {offset} with length {length} is not aligned to the zone size, {zone_size}, of {}",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The I/O topology of a block device, as reported by the kernel in the
+/// device's sysfs queue directory, or, in ioctl-only mode, by the block
+/// device ioctls.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlkDevTopology {
+    /// The smallest unit the device is able to address
+    pub logical_block_size: Bytes,
+    /// The smallest unit the device can write without a read-modify-write
+    pub physical_block_size: Bytes,
+    /// The preferred minimum unit for random I/O
+    pub minimum_io_size: Bytes,
+    /// The preferred unit for sustained I/O, 0 if not reported
+    pub optimal_io_size: Bytes,
+    /// The granularity of discards, 0 if discard is not supported
+    pub discard_granularity: Bytes,
+    /// The largest discard the device will accept in a single request
+    pub max_discard: Sectors,
+}
+
+impl BlkDevTopology {
+    /// Whether the device supports discards.
+    pub fn supports_discard(&self) -> bool {
+        self.discard_granularity != Bytes(0)
+    }
+}
+
+/// Read a single numeric attribute from a sysfs queue directory.
+fn read_queue_attr(queue_path: &Path, attr: &str) -> DmResult<u128> {
+    let path = queue_path.join(attr);
+    let value = read_to_string(&path).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to read {}: {}",
+            path.display(),
+            err
+        )))
+    })?;
+    value.trim().parse::<u128>().map_err(|_| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "unexpected value \"{}\" in {}",
+            value.trim(),
+            path.display()
+        )))
+    })
+}
+
+/// Get the I/O topology of the block device with the given device number
+/// from the block device ioctls. The kernel reports the discard limits of a
+/// device only in sysfs, so the device is taken not to support discard.
+fn device_topology_ioctl(device: Device) -> DmResult<BlkDevTopology> {
+    let file = open_blkdev(&device_path(device), OpenOptions::new().read(true))?;
+    let read_attr = |ioctl: unsafe fn(c_int, *mut c_uint) -> nix::Result<c_int>| {
+        let mut val: c_uint = 0;
+        unsafe { ioctl(file.as_raw_fd(), &mut val) }
+            .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+        Ok::<_, DmError>(Bytes(u128::from(val)))
+    };
+
+    Ok(BlkDevTopology {
+        logical_block_size: blkdev_file_logical_block_size(&file)?,
+        physical_block_size: read_attr(blkpbszget)?,
+        minimum_io_size: read_attr(blkiomin)?,
+        optimal_io_size: read_attr(blkioopt)?,
+        discard_granularity: Bytes(0),
+        max_discard: Sectors(0),
+    })
+}
+
+/// Get the I/O topology of the block device with the given device number.
+pub fn device_topology(dm: &DM, device: Device) -> DmResult<BlkDevTopology> {
+    if dm.config().ioctl_only() {
+        return device_topology_ioctl(device);
+    }
+
+    let dev_path = [SYSFS_DEV_BLOCK_PATH, &device.to_string()]
+        .iter()
+        .collect::<PathBuf>();
+
+    // A partition has no queue directory of its own; it shares the queue of
+    // the whole disk, which is its parent in sysfs.
+    let queue_path = match dev_path.join("queue").metadata() {
+        Ok(_) => dev_path.join("queue"),
+        Err(err) if err.kind() == ErrorKind::NotFound => dev_path.join("..").join("queue"),
+        Err(err) => {
+            return Err(DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to examine {}: {}",
+                dev_path.display(),
+                err
+            ))))
+        }
+    };
+
+    Ok(BlkDevTopology {
+        logical_block_size: Bytes(read_queue_attr(&queue_path, "logical_block_size")?),
+        physical_block_size: Bytes(read_queue_attr(&queue_path, "physical_block_size")?),
+        minimum_io_size: Bytes(read_queue_attr(&queue_path, "minimum_io_size")?),
+        optimal_io_size: Bytes(read_queue_attr(&queue_path, "optimal_io_size")?),
+        discard_granularity: Bytes(read_queue_attr(&queue_path, "discard_granularity")?),
+        max_discard: Bytes(read_queue_attr(&queue_path, "discard_max_bytes")?).sectors(),
+    })
+}
+
+/// Verify that `chunk_size` is a valid chunk size for a target which
+/// requires its chunk size to be between `min` and `max`, inclusive, and a
+/// multiple of `granularity`.
+fn check_chunk_range(
+    chunk_size: Sectors,
+    min: Sectors,
+    max: Sectors,
+    granularity: Sectors,
+) -> DmResult<()> {
+    if chunk_size < min || chunk_size > max {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("chunk size {chunk_size} is not between {min} and {max}"),
+        ));
+    }
+    if !chunk_size.is_aligned(granularity) {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("chunk size {chunk_size} is not a multiple of {granularity}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Verify that `chunk_size` is usable as the chunk size of a target which
+/// requires its chunk size to be between `min` and `max`, inclusive, and a
+/// multiple of `granularity`, when that target is built over `device`.
+///
+/// The chunk size must also be a multiple of the device's logical block
+/// size. A chunk size which is not a multiple of the device's physical
+/// block size or discard granularity is permitted, but a warning is logged,
+/// since it will result in degraded performance or, for a thin pool,
+/// discards not being passed down to the device.
+pub fn check_chunk_size(
+    dm: &DM,
+    device: Device,
+    chunk_size: Sectors,
+    min: Sectors,
+    max: Sectors,
+    granularity: Sectors,
+) -> DmResult<()> {
+    check_chunk_range(chunk_size, min, max, granularity)?;
+
+    let topology = device_topology(dm, device)?;
+    let chunk_bytes = chunk_size.bytes();
+    if !chunk_bytes.is_aligned(topology.logical_block_size) {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "chunk size {chunk_size} is not a multiple of the logical block size, {} bytes, of device {device}",
+                *topology.logical_block_size
+            ),
+        ));
+    }
+    if !chunk_bytes.is_aligned(topology.physical_block_size) {
+        warn!(
+            "Chunk size {} is not a multiple of the physical block size, {} bytes, of device {}",
+            chunk_size, *topology.physical_block_size, device
+        );
+    }
+    if !chunk_bytes.is_aligned(topology.discard_granularity) {
+        warn!(
+            "Chunk size {} is not a multiple of the discard granularity, {} bytes, of device {}",
+            chunk_size, *topology.discard_granularity, device
+        );
+    }
+    Ok(())
+}
+
+/// The default chunk size of a striped target, used when the backing
+/// devices prefer a smaller unit of I/O. This is also LVM's default.
+const DEFAULT_STRIPE_CHUNK_SIZE: Bytes = Bytes(64 * 1024);
+
+/// The chunk size and alignment suited to a striped target over some set of
+/// devices, as derived from their I/O topology by `stripe_geometry`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StripeGeometry {
+    /// A chunk size which is a multiple of the preferred minimum I/O size of
+    /// every device, so that no chunk splits a device's unit of I/O, e.g.,
+    /// the chunk of an underlying RAID array.
+    pub chunk_size: Sectors,
+    /// The alignment of the offset of each stripe on its device, which is a
+    /// multiple of the optimal I/O size of every device which reports one.
+    pub alignment: Sectors,
+}
+
+fn gcd(a: u128, b: u128) -> u128 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// The least common multiple of some sizes, none of which is 0.
+fn lcm(sizes: impl Iterator<Item = Bytes>) -> Bytes {
+    Bytes(sizes.fold(1, |acc, size| acc / gcd(acc, *size) * *size))
+}
+
+/// The stripe geometry suited to devices of the given topologies.
+fn geometry_from_topologies(topologies: &[BlkDevTopology]) -> StripeGeometry {
+    let granularity = lcm(topologies.iter().flat_map(|topology| {
+        [
+            topology.logical_block_size,
+            topology.physical_block_size,
+            topology.minimum_io_size,
+        ]
+        .into_iter()
+        .filter(|size| *size != Bytes(0))
+    }));
+    let chunk_size = if granularity < DEFAULT_STRIPE_CHUNK_SIZE {
+        // Round the default up to a multiple of the granularity.
+        Bytes(((*DEFAULT_STRIPE_CHUNK_SIZE + *granularity - 1) / *granularity) * *granularity)
+    } else {
+        granularity
+    };
+    let alignment = lcm(topologies
+        .iter()
+        .map(|topology| topology.optimal_io_size)
+        .filter(|size| *size != Bytes(0))
+        .chain([granularity]));
+    StripeGeometry {
+        chunk_size: chunk_size.sectors(),
+        alignment: alignment.sectors(),
+    }
+}
+
+/// Suggest the chunk size and stripe alignment of a striped target over the
+/// given devices, from their I/O topology. Stripes laid out this way do not
+/// split an I/O unit of any device, such as the chunk of an underlying RAID
+/// array or the preferred write size of an NVMe namespace.
+pub fn stripe_geometry(dm: &DM, devices: &[Device]) -> DmResult<StripeGeometry> {
+    if devices.is_empty() {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            "a striped target requires at least one device".to_string(),
+        ));
+    }
+    let topologies = devices
+        .iter()
+        .map(|device| device_topology(dm, *device))
+        .collect::<DmResult<Vec<_>>>()?;
+    Ok(geometry_from_topologies(&topologies))
+}
+
+/// Verify that `chunk_size` and the offsets of the stripes, each given with
+/// its device, are usable by a striped target over those devices.
+///
+/// The chunk size and every offset must be a multiple of the logical block
+/// size of every device. A chunk size which is not a multiple of the minimum
+/// I/O size of every device, or an offset which is not aligned as suggested
+/// by `stripe_geometry`, is permitted, but a warning is logged, since the
+/// misaligned stripes will degrade performance.
+pub fn check_stripe_geometry(
+    dm: &DM,
+    stripes: &[(Device, Sectors)],
+    chunk_size: Sectors,
+) -> DmResult<()> {
+    let devices = stripes
+        .iter()
+        .map(|(device, _)| *device)
+        .collect::<Vec<_>>();
+    let geometry = stripe_geometry(dm, &devices)?;
+    for (device, offset) in stripes {
+        let topology = device_topology(dm, *device)?;
+        if !chunk_size.bytes().is_aligned(topology.logical_block_size) || chunk_size == Sectors(0) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "chunk size {chunk_size} is not a non-zero multiple of the logical block size, {} bytes, of device {device}",
+                    *topology.logical_block_size
+                ),
+            ));
+        }
+        if !offset.bytes().is_aligned(topology.logical_block_size) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "stripe offset {offset} is not a multiple of the logical block size, {} bytes, of device {device}",
+                    *topology.logical_block_size
+                ),
+            ));
+        }
+        if !chunk_size.bytes().is_aligned(topology.minimum_io_size) {
+            warn!(
+                "Chunk size {} is not a multiple of the minimum I/O size, {} bytes, of device {}",
+                chunk_size, *topology.minimum_io_size, device
+            );
+        }
+        if !offset.is_aligned(geometry.alignment) {
+            warn!(
+                "Stripe offset {} on device {} is not aligned to {}",
+                offset, device, geometry.alignment
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{
+        core::{devnode_to_devno, DmConfig},
+        testing::test_with_spec,
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that signatures are recognized by their magic values, and
+    /// that each is reported once.
+    fn test_match_signatures() {
+        let mut buf = vec![0u8; SIGNATURE_PROBE_SIZE];
+        assert_eq!(match_signatures(&buf), vec![]);
+
+        buf[1080..1082].copy_from_slice(&[0x53, 0xef]);
+        buf[4086..4096].copy_from_slice(b"SWAPSPACE2");
+        buf[65526..65536].copy_from_slice(b"SWAPSPACE2");
+        assert_eq!(
+            match_signatures(&buf),
+            vec![DeviceSignature::Ext, DeviceSignature::Swap]
+        );
+
+        let mut buf = vec![0u8; 64];
+        buf[32..40].copy_from_slice(&27_022_010u64.to_le_bytes());
+        assert_eq!(
+            match_signatures(&buf),
+            vec![DeviceSignature::ThinPoolMetadata]
+        );
+        assert_eq!(match_signatures(b"XFSB"), vec![DeviceSignature::Xfs]);
+    }
+
+    #[test]
+    /// Verify that a signature is found at the offset probed, and not
+    /// elsewhere, and that a short file is probed.
+    fn test_probe_signatures() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0u8; 4096]).unwrap();
+        file.write_all(b"LUKS\xba\xbe").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(probe_signatures(file.path(), Sectors(0)).unwrap(), vec![]);
+        assert_eq!(
+            probe_signatures(file.path(), Sectors(8)).unwrap(),
+            vec![DeviceSignature::Luks]
+        );
+    }
+
+    #[test]
+    /// Verify that chunk sizes outside the permitted range or not a
+    /// multiple of the required granularity are rejected.
+    fn test_check_chunk_range() {
+        let (min, max, granularity) = (Sectors(128), Sectors(2048), Sectors(128));
+        assert_matches!(
+            check_chunk_range(Sectors(128), min, max, granularity),
+            Ok(_)
+        );
+        assert_matches!(
+            check_chunk_range(Sectors(2048), min, max, granularity),
+            Ok(_)
+        );
+        assert_matches!(
+            check_chunk_range(Sectors(64), min, max, granularity),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            check_chunk_range(Sectors(4096), min, max, granularity),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            check_chunk_range(Sectors(192), min, max, granularity),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that the suggested chunk size respects the I/O unit of every
+    /// device and is no smaller than the default, and that the alignment
+    /// respects every device's optimal I/O size.
+    fn test_geometry_from_topologies() {
+        let topology = |block: u128, min_io: u128, opt_io: u128| BlkDevTopology {
+            logical_block_size: Bytes(512),
+            physical_block_size: Bytes(block),
+            minimum_io_size: Bytes(min_io),
+            optimal_io_size: Bytes(opt_io),
+            discard_granularity: Bytes(0),
+            max_discard: Sectors(0),
+        };
+
+        assert_eq!(
+            geometry_from_topologies(&[topology(512, 512, 0), topology(4096, 4096, 0)]),
+            StripeGeometry {
+                chunk_size: Sectors(128),
+                alignment: Sectors(8),
+            }
+        );
+
+        // A RAID array with a 512 KiB chunk over three data disks, and an
+        // NVMe namespace preferring 128 KiB writes
+        assert_eq!(
+            geometry_from_topologies(&[
+                topology(4096, 512 * 1024, 3 * 512 * 1024),
+                topology(4096, 128 * 1024, 128 * 1024),
+            ]),
+            StripeGeometry {
+                chunk_size: Sectors(1024),
+                alignment: Sectors(3072),
+            }
+        );
+
+        // A granularity which does not divide the default chunk size
+        assert_eq!(
+            geometry_from_topologies(&[topology(4096, 3 * 4096, 0)]).chunk_size,
+            Sectors(144)
+        );
+    }
+
+    /// Verify that wiping a region zeroes exactly that region and that a
+    /// region which extends beyond the end of the device is rejected.
+    fn test_wipe_sectors(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let mut f = OpenOptions::new().write(true).open(paths[0]).unwrap();
+        f.write_all(&[0xffu8; 8192]).unwrap();
+        f.sync_all().unwrap();
+
+        wipe_sectors(paths[0], Sectors(8), Sectors(4)).unwrap();
+
+        let mut contents = [0u8; 8192];
+        File::open(paths[0])
+            .unwrap()
+            .read_exact_at(&mut contents, 0)
+            .unwrap();
+        assert!(contents[..4096].iter().all(|b| *b == 0xff));
+        assert!(contents[4096..6144].iter().all(|b| *b == 0));
+        assert!(contents[6144..].iter().all(|b| *b == 0xff));
+
+        let size = blkdev_size(paths[0]).unwrap().sectors();
+        assert_matches!(
+            wipe_sectors(paths[0], size - Sectors(8), Sectors(16)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    fn loop_test_wipe_sectors() {
+        test_with_spec(1, test_wipe_sectors);
+    }
+
+    /// Verify that a range within the device can be discarded and that a
+    /// range which extends beyond the end of the device is rejected.
+    fn test_discard_sectors(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        discard_sectors(paths[0], Sectors(8), Sectors(8), DiscardKind::Discard).unwrap();
+
+        let size = blkdev_size(paths[0]).unwrap().sectors();
+        assert_matches!(
+            discard_sectors(paths[0], size, Sectors(8), DiscardKind::Discard),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    fn loop_test_discard_sectors() {
+        test_with_spec(1, test_discard_sectors);
+    }
+
+    /// Verify that the topology read from the block device ioctls agrees
+    /// with that read from sysfs, apart from the discard limits, which the
+    /// ioctls do not report.
+    fn test_device_topology_ioctl(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let device = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let dm = DM::new().unwrap();
+        let sysfs = device_topology(&dm, device).unwrap();
+        let ioctl = device_topology_ioctl(device).unwrap();
+        assert_eq!(
+            ioctl,
+            BlkDevTopology {
+                discard_granularity: Bytes(0),
+                max_discard: Sectors(0),
+                ..sysfs
+            }
+        );
+        assert!(!ioctl.supports_discard());
+
+        let dm = DM::with_config(DmConfig::default().set_ioctl_only(true)).unwrap();
+        assert_eq!(device_topology(&dm, device).unwrap(), ioctl);
+    }
+
+    #[test]
+    fn loop_test_device_topology_ioctl() {
+        test_with_spec(1, test_device_topology_ioctl);
+    }
+
+    #[test]
+    /// Verify that zone types and conditions are decoded correctly,
+    /// including values unknown to this library.
+    fn test_zone_type_condition() {
+        assert_eq!(ZoneType::from(2), ZoneType::SequentialWriteRequired);
+        assert_eq!(ZoneType::from(9), ZoneType::Unknown(9));
+        assert_eq!(ZoneCondition::from(0xe), ZoneCondition::Full);
+        assert_eq!(ZoneCondition::from(0x7), ZoneCondition::Unknown(0x7));
+    }
+
+    #[test]
+    /// Verify that the structs passed to the zone ioctls have the layout
+    /// the kernel expects.
+    fn test_zone_struct_sizes() {
+        assert_eq!(size_of::<BlkZone>(), 64);
+        assert_eq!(size_of::<BlkZoneReport>(), 16);
+        assert_eq!(size_of::<BlkZoneRange>(), 16);
+    }
+
+    /// Verify that a loop device, which is not zoned, reports a zone size
+    /// of 0 and that any range on it is considered zone aligned.
+    fn test_zone_size_unzoned(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        assert_eq!(zone_size(paths[0]).unwrap(), Sectors(0));
+        check_zone_aligned(paths[0], Sectors(3), Sectors(5)).unwrap();
+    }
+
+    #[test]
+    fn loop_test_zone_size_unzoned() {
+        test_with_spec(1, test_zone_size_unzoned);
+    }
+}
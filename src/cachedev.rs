@@ -7,19 +7,28 @@ use std::{
     fmt,
     path::PathBuf,
     str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 
+#[cfg(devicemapper41supported)]
+use semver::Version;
+
+#[cfg(devicemapper41supported)]
+use crate::core::DmCapabilities;
 use crate::{
+    blkdev::check_chunk_size,
     consts::IEC,
     core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
-    lineardev::{LinearDev, LinearDevTargetParams},
+    lineardev::{LinearDev, LinearDevTargetParams, LinearDevTargetTable},
+    profiles::CacheProfile,
     result::{DmError, DmResult, ErrorEnum},
     shared::{
         device_create, device_exists, device_match, get_status, get_status_line_fields,
-        make_unexpected_value_error, parse_device, parse_value, DmDevice, TargetLine, TargetParams,
-        TargetTable, TargetTypeBuf,
+        make_unexpected_value_error, message, parse_device, parse_value, DmDevice, TargetLine,
+        TargetParams, TargetTable, TargetTypeBuf,
     },
-    units::{DataBlocks, MetaBlocks, Sectors},
+    units::{Bytes, DataBlocks, MetaBlocks, Sectors, MAX_META_DEV_SIZE},
 };
 
 // Specified in kernel docs
@@ -28,7 +37,242 @@ pub const MIN_CACHE_BLOCK_SIZE: Sectors = Sectors(64); // 32 KiB
 /// The maximum size recommended in the docs for a cache block.
 pub const MAX_CACHE_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
 
-const CACHE_TARGET_NAME: &str = "cache";
+pub(crate) const CACHE_TARGET_NAME: &str = "cache";
+
+/// The interval at which the status of a cache is polled while it is cleaned
+const CLEAN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Estimates of the metadata used by a cache, as made by lvm2
+/// The metadata which is allowed for transactions in progress
+const CACHE_METADATA_TRANSACTION_OVERHEAD: Bytes = Bytes(4 * IEC::Mi as u128);
+/// The metadata required by each cache block for its mapping, its hint,
+/// and the overhead of the hint array
+const CACHE_METADATA_BYTES_PER_BLOCK: u128 = 16 + 20 + 8;
+
+/// The feature argument which selects version 2 of the metadata format
+const METADATA2_FEATURE: &str = "metadata2";
+
+/// The format of a cache's metadata.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheMetadataFormat {
+    /// The original format, used unless the "metadata2" feature is given.
+    V1,
+    /// The format which keeps the dirty bits of the cache blocks in a
+    /// separate bitset, which makes writeback caches faster to commit.
+    V2,
+}
+
+/// The IO mode of a cache, which determines how writes are handled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheIoMode {
+    /// Writes are completed only when written to both the cache and the
+    /// origin.
+    Writethrough,
+    /// Writes to cached blocks are completed when written to the cache; the
+    /// blocks are written back to the origin later.
+    Writeback,
+    /// All IO bypasses the cache, and writes to cached blocks invalidate
+    /// them. The cache must be clean to be put in this mode.
+    Passthrough,
+}
+
+impl CacheIoMode {
+    /// The IO mode as it appears in the feature args of a table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheIoMode::Writethrough => "writethrough",
+            CacheIoMode::Writeback => "writeback",
+            CacheIoMode::Passthrough => "passthrough",
+        }
+    }
+}
+
+impl fmt::Display for CacheIoMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for CacheIoMode {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<CacheIoMode> {
+        match s {
+            "writethrough" => Ok(CacheIoMode::Writethrough),
+            "writeback" => Ok(CacheIoMode::Writeback),
+            "passthrough" => Ok(CacheIoMode::Passthrough),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown cache IO mode \"{s}\""),
+            )),
+        }
+    }
+}
+
+impl serde::Serialize for CacheIoMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CacheIoMode {
+    fn deserialize<D>(deserializer: D) -> Result<CacheIoMode, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value
+            .parse::<CacheIoMode>()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A cache replacement policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CachePolicy {
+    /// The kernel's default policy, currently smq
+    Default,
+    /// The stochastic multiqueue policy
+    Smq,
+    /// The multiqueue policy, now an alias for smq
+    Mq,
+    /// A policy which writes back all dirty blocks and promotes none, used
+    /// to decommission a cache
+    Cleaner,
+}
+
+impl CachePolicy {
+    /// The policy name as it appears in a table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CachePolicy::Default => "default",
+            CachePolicy::Smq => "smq",
+            CachePolicy::Mq => "mq",
+            CachePolicy::Cleaner => "cleaner",
+        }
+    }
+}
+
+impl fmt::Display for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for CachePolicy {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<CachePolicy> {
+        match s {
+            "default" => Ok(CachePolicy::Default),
+            "smq" => Ok(CachePolicy::Smq),
+            "mq" => Ok(CachePolicy::Mq),
+            "cleaner" => Ok(CachePolicy::Cleaner),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown cache policy \"{s}\""),
+            )),
+        }
+    }
+}
+
+impl serde::Serialize for CachePolicy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CachePolicy {
+    fn deserialize<D>(deserializer: D) -> Result<CachePolicy, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value
+            .parse::<CachePolicy>()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A tunable of a cache or its policy. Tunables may be given as policy
+/// arguments when the table is built, or changed on a live cache with
+/// `CacheDev::set_tunable`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheTunable {
+    /// The maximum amount of data which may be migrated between the cache
+    /// and the origin at once
+    MigrationThreshold(Sectors),
+    /// The number of contiguous IOs after which a stream is considered
+    /// sequential; accepted but ignored by smq
+    SequentialThreshold(u64),
+    /// The number of intervening IOs after which a stream is considered
+    /// random; accepted but ignored by smq
+    RandomThreshold(u64),
+    /// The adjustment to the promotion threshold for reads; accepted but
+    /// ignored by smq
+    ReadPromoteAdjustment(u64),
+    /// The adjustment to the promotion threshold for writes; accepted but
+    /// ignored by smq
+    WritePromoteAdjustment(u64),
+    /// The adjustment to the promotion threshold for discards; accepted but
+    /// ignored by smq
+    DiscardPromoteAdjustment(u64),
+}
+
+impl CacheTunable {
+    /// The key of the tunable.
+    pub fn key(&self) -> &'static str {
+        match self {
+            CacheTunable::MigrationThreshold(_) => "migration_threshold",
+            CacheTunable::SequentialThreshold(_) => "sequential_threshold",
+            CacheTunable::RandomThreshold(_) => "random_threshold",
+            CacheTunable::ReadPromoteAdjustment(_) => "read_promote_adjustment",
+            CacheTunable::WritePromoteAdjustment(_) => "write_promote_adjustment",
+            CacheTunable::DiscardPromoteAdjustment(_) => "discard_promote_adjustment",
+        }
+    }
+
+    /// The value of the tunable.
+    pub fn value(&self) -> u64 {
+        match self {
+            CacheTunable::MigrationThreshold(value) => **value,
+            CacheTunable::SequentialThreshold(value)
+            | CacheTunable::RandomThreshold(value)
+            | CacheTunable::ReadPromoteAdjustment(value)
+            | CacheTunable::WritePromoteAdjustment(value)
+            | CacheTunable::DiscardPromoteAdjustment(value) => *value,
+        }
+    }
+
+    /// Parse a tunable from its key and value.
+    pub fn from_pair(key: &str, value: &str) -> DmResult<CacheTunable> {
+        let value = parse_value::<u64>(value, key)?;
+        match key {
+            "migration_threshold" => Ok(CacheTunable::MigrationThreshold(Sectors(value))),
+            "sequential_threshold" => Ok(CacheTunable::SequentialThreshold(value)),
+            "random_threshold" => Ok(CacheTunable::RandomThreshold(value)),
+            "read_promote_adjustment" => Ok(CacheTunable::ReadPromoteAdjustment(value)),
+            "write_promote_adjustment" => Ok(CacheTunable::WritePromoteAdjustment(value)),
+            "discard_promote_adjustment" => Ok(CacheTunable::DiscardPromoteAdjustment(value)),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown cache tunable \"{key}\""),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CacheTunable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.key(), self.value())
+    }
+}
 
 /// Struct representing params for a cache target
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -70,6 +314,90 @@ impl CacheTargetParams {
             policy_args: policy_args.into_iter().collect::<HashMap<_, _>>(),
         }
     }
+
+    /// The IO mode given in the feature args. The kernel's default, if no
+    /// mode is given, is writeback.
+    pub fn io_mode(&self) -> CacheIoMode {
+        [
+            CacheIoMode::Passthrough,
+            CacheIoMode::Writethrough,
+            CacheIoMode::Writeback,
+        ]
+        .iter()
+        .find(|mode| self.feature_args.contains(mode.as_str()))
+        .copied()
+        .unwrap_or(CacheIoMode::Writeback)
+    }
+
+    /// Set the IO mode in the feature args, leaving all other parameters
+    /// unchanged.
+    pub fn set_io_mode(&mut self, io_mode: CacheIoMode) {
+        for mode in [
+            CacheIoMode::Writethrough,
+            CacheIoMode::Writeback,
+            CacheIoMode::Passthrough,
+        ] {
+            self.feature_args.remove(mode.as_str());
+        }
+        self.feature_args.insert(io_mode.as_str().to_owned());
+    }
+
+    /// The metadata format selected by the feature args.
+    pub fn metadata_format(&self) -> CacheMetadataFormat {
+        if self.feature_args.contains(METADATA2_FEATURE) {
+            CacheMetadataFormat::V2
+        } else {
+            CacheMetadataFormat::V1
+        }
+    }
+
+    /// Set the replacement policy and its arguments, replacing any previous
+    /// policy arguments and leaving all other parameters unchanged.
+    pub fn set_policy(&mut self, policy: CachePolicy, tunables: &[CacheTunable]) {
+        self.policy = policy.as_str().to_owned();
+        self.policy_args = tunables
+            .iter()
+            .map(|tunable| (tunable.key().to_owned(), tunable.value().to_string()))
+            .collect();
+    }
+}
+
+/// The version of the cache target which introduced a feature argument, if
+/// it has not always been accepted.
+#[cfg(devicemapper41supported)]
+fn feature_min_version(feature: &str) -> Option<Version> {
+    match feature {
+        METADATA2_FEATURE => Some(Version::new(1, 10, 0)),
+        "no_discard_passdown" => Some(Version::new(2, 1, 0)),
+        _ => None,
+    }
+}
+
+#[cfg(devicemapper41supported)]
+impl CacheTargetParams {
+    /// Check that the running kernel's cache target accepts every feature
+    /// argument, so that a table with these params does not fail to load.
+    /// If the cache target is not yet loaded, its version is unknown and no
+    /// feature argument is rejected.
+    pub fn check_features(&self, capabilities: &DmCapabilities) -> DmResult<()> {
+        capabilities.check_features(CACHE_TARGET_NAME, &self.feature_args, feature_min_version)
+    }
+
+    /// Remove every feature argument which the running kernel's cache
+    /// target does not accept, logging a warning for each.
+    pub fn remove_unsupported_features(&mut self, capabilities: &DmCapabilities) {
+        for (feature, min) in capabilities.unsupported_features(
+            CACHE_TARGET_NAME,
+            &self.feature_args,
+            feature_min_version,
+        ) {
+            warn!(
+                "Omitting feature argument {}, which requires cache target version {} or later",
+                feature, min
+            );
+            self.feature_args.remove(&feature);
+        }
+    }
 }
 
 impl fmt::Display for CacheTargetParams {
@@ -160,7 +488,7 @@ impl TargetParams for CacheTargetParams {
         } else {
             format!(
                 "{} {}",
-                self.policy_args.len(),
+                2 * self.policy_args.len(),
                 self.policy_args
                     .iter()
                     .map(|(k, v)| format!("{k} {v}"))
@@ -558,6 +886,14 @@ impl DmDevice<CacheDevTargetTable> for CacheDev {
 impl CacheDev {
     /// Construct a new CacheDev with the given data and meta devs.
     /// Returns an error if the device is already known to the kernel.
+    /// Returns an error if the metadata device is too small; see
+    /// `CacheDev::check_meta_size`.
+    /// Precondition: the metadata device does not contain any cache
+    /// metadata. `wipe_metadata_superblock` may be used to ensure this.
+    /// The backing devices of the metadata and cache devices are not probed
+    /// for signatures of existing data here; to refuse to build a new cache
+    /// over a filesystem or other volume, set up those devices with
+    /// `LinearDev::setup_checked`. The origin is expected to hold data.
     pub fn new(
         dm: &DM,
         name: &DmName,
@@ -566,13 +902,48 @@ impl CacheDev {
         cache: LinearDev,
         origin: LinearDev,
         cache_block_size: Sectors,
+    ) -> DmResult<CacheDev> {
+        let table = CacheDev::gen_default_table(&meta, &cache, &origin, cache_block_size);
+        CacheDev::create(dm, name, uuid, meta, cache, origin, table)
+    }
+
+    /// Construct a new CacheDev, as `CacheDev::new` does, with the cache
+    /// block size, IO mode, and replacement policy of `profile`.
+    pub fn new_from_profile(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        meta: LinearDev,
+        cache: LinearDev,
+        origin: LinearDev,
+        profile: &CacheProfile,
+    ) -> DmResult<CacheDev> {
+        let table = CacheDev::gen_profile_table(&meta, &cache, &origin, profile);
+        CacheDev::create(dm, name, uuid, meta, cache, origin, table)
+    }
+
+    /// Create a new CacheDev with the given table, which must not already
+    /// be known to the kernel.
+    fn create(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        meta: LinearDev,
+        cache: LinearDev,
+        origin: LinearDev,
+        table: CacheDevTargetTable,
     ) -> DmResult<CacheDev> {
         if device_exists(dm, name)? {
             let err_msg = format!("cachedev {name} already exists");
             return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
         }
 
-        let table = CacheDev::gen_default_table(&meta, &cache, &origin, cache_block_size);
+        CacheDev::check_meta_size(
+            &meta,
+            &cache,
+            table.table.params.cache_block_size,
+            table.table.params.metadata_format(),
+        )?;
         let dev_info = device_create(dm, name, uuid, &table, DmOptions::private())?;
 
         Ok(CacheDev {
@@ -584,6 +955,128 @@ impl CacheDev {
         })
     }
 
+    /// Verify that `cache_block_size` is a valid cache block size for a
+    /// cache with the given cache and origin devices: it must be within the
+    /// range permitted by the kernel, a multiple of the minimum cache block
+    /// size, and a multiple of the logical block size of both devices.
+    pub fn check_cache_block_size(
+        dm: &DM,
+        cache: &LinearDev,
+        origin: &LinearDev,
+        cache_block_size: Sectors,
+    ) -> DmResult<()> {
+        for dev in [cache, origin] {
+            check_chunk_size(
+                dm,
+                dev.device(),
+                cache_block_size,
+                MIN_CACHE_BLOCK_SIZE,
+                MAX_CACHE_BLOCK_SIZE,
+                MIN_CACHE_BLOCK_SIZE,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The metadata required by a cache of `cache_size` with the given
+    /// cache block size and metadata format, without the allowance made by
+    /// `CacheDev::recommended_meta_size` for transactions in progress.
+    fn required_meta_bytes(
+        cache_size: Sectors,
+        cache_block_size: Sectors,
+        format: CacheMetadataFormat,
+    ) -> Bytes {
+        // A smaller cache block size is not permitted by the kernel, and
+        // would only overstate the metadata required.
+        let blocks = u128::from(cache_size / cache_block_size.max(MIN_CACHE_BLOCK_SIZE));
+        let bytes = blocks * CACHE_METADATA_BYTES_PER_BLOCK;
+        match format {
+            CacheMetadataFormat::V1 => Bytes(bytes),
+            // One dirty bit for each cache block
+            CacheMetadataFormat::V2 => Bytes(bytes + (blocks + 7) / 8),
+        }
+    }
+
+    /// Round bytes of metadata up to a whole number of metadata blocks.
+    fn meta_size(bytes: Bytes) -> Sectors {
+        let meta_block_bytes = *MetaBlocks(1).bytes();
+        MetaBlocks(
+            u64::try_from((*bytes + meta_block_bytes - 1) / meta_block_bytes).unwrap_or(u64::MAX),
+        )
+        .sectors()
+    }
+
+    /// The recommended size of the metadata device of a cache with a cache
+    /// device of `cache_size`, the given cache block size, and the given
+    /// metadata format.
+    ///
+    /// As lvm2 does, 44 bytes of metadata are allowed for each cache block,
+    /// for its mapping and its hint, and a further 4 MiB for transactions
+    /// in progress. Version 2 of the metadata format requires a further bit
+    /// for each cache block. The result is a whole number of metadata
+    /// blocks.
+    pub fn recommended_meta_size(
+        cache_size: Sectors,
+        cache_block_size: Sectors,
+        format: CacheMetadataFormat,
+    ) -> Sectors {
+        CacheDev::meta_size(
+            CacheDev::required_meta_bytes(cache_size, cache_block_size, format)
+                + CACHE_METADATA_TRANSACTION_OVERHEAD,
+        )
+    }
+
+    /// Verify that `meta` is large enough to be the metadata device of a
+    /// cache over `cache` with the given cache block size and metadata
+    /// format: it must hold the mapping and hint of every cache block. A
+    /// metadata device smaller than the size recommended by
+    /// `CacheDev::recommended_meta_size` is permitted, but a warning is
+    /// logged. A cache which requires more metadata than the kernel can
+    /// use is rejected; a larger cache block size must be chosen.
+    pub fn check_meta_size(
+        meta: &LinearDev,
+        cache: &LinearDev,
+        cache_block_size: Sectors,
+        format: CacheMetadataFormat,
+    ) -> DmResult<()> {
+        let required = CacheDev::meta_size(CacheDev::required_meta_bytes(
+            cache.size(),
+            cache_block_size,
+            format,
+        ));
+        if required > MAX_META_DEV_SIZE.sectors() {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "a cache of size {} with cache block size {cache_block_size} requires {required} of metadata, more than the kernel can use, {}",
+                    cache.size(),
+                    MAX_META_DEV_SIZE.sectors()
+                ),
+            ));
+        }
+        let meta_size = meta.size();
+        if meta_size < required {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "metadata device size {meta_size} is less than the {required} required by a cache of size {} with cache block size {cache_block_size}",
+                    cache.size()
+                ),
+            ));
+        }
+        let recommended = CacheDev::recommended_meta_size(cache.size(), cache_block_size, format);
+        if meta_size < recommended {
+            warn!(
+                "Metadata device size {} is less than the {} recommended for a cache of size {} with cache block size {}",
+                meta_size,
+                recommended,
+                cache.size(),
+                cache_block_size
+            );
+        }
+        Ok(())
+    }
+
     /// Set up a cache device from the given metadata and data devices.
     pub fn setup(
         dm: &DM,
@@ -645,6 +1138,20 @@ impl CacheDev {
         Ok(())
     }
 
+    /// Grow the cache's origin device by appending segments, each given as
+    /// a length and the params of its target, to its table. The cache is
+    /// resumed with the new size.
+    pub fn extend_origin(
+        &mut self,
+        dm: &DM,
+        segments: Vec<(Sectors, LinearDevTargetParams)>,
+    ) -> DmResult<()> {
+        let mut table = self.origin_dev.table().clone();
+        table.append(segments);
+        self.set_origin_table(dm, table.table)?;
+        self.resume(dm)
+    }
+
     /// Set the table for the existing cache sub-device.
     /// This action puts the device in a state where it is ready to be resumed.
     /// Warning: It is the client's responsibility to make sure the designated
@@ -691,6 +1198,170 @@ impl CacheDev {
         Ok(())
     }
 
+    /// Change a tunable of the cache or its policy on the live cache.
+    pub fn set_tunable(&self, dm: &DM, tunable: CacheTunable) -> DmResult<()> {
+        message(dm, self, &tunable.to_string())
+    }
+
+    /// Switch the cache to a different replacement policy with the given
+    /// tunables, by reloading its table with all other parameters
+    /// unchanged. Switching to `CachePolicy::Cleaner` causes all dirty
+    /// blocks to be written back to the origin.
+    pub fn set_policy(
+        &mut self,
+        dm: &DM,
+        policy: CachePolicy,
+        tunables: &[CacheTunable],
+    ) -> DmResult<()> {
+        let mut table = self.table.clone();
+        table.table.params.set_policy(policy, tunables);
+
+        self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
+        self.table_load(dm, &table, DmOptions::default())?;
+        self.table = table;
+        self.resume(dm)?;
+
+        Ok(())
+    }
+
+    /// Switch the live cache to a different IO mode by reloading its table
+    /// with all other parameters unchanged.
+    ///
+    /// The cache is suspended with a flush, so that all writes issued in
+    /// the old mode are complete before the new table is loaded. A cache
+    /// may only be put in passthrough mode when it has no dirty blocks;
+    /// `clean` may be used to ensure this.
+    pub fn set_io_mode(&mut self, dm: &DM, io_mode: CacheIoMode) -> DmResult<()> {
+        if self.table.table.params.io_mode() == io_mode {
+            return Ok(());
+        }
+
+        if io_mode == CacheIoMode::Passthrough {
+            match self.status(dm, DmOptions::default())? {
+                CacheDevStatus::Working(status) if status.performance.dirty == 0 => (),
+                CacheDevStatus::Working(status) => {
+                    return Err(DmError::Dm(
+                        ErrorEnum::Invalid,
+                        format!(
+                            "cache {} has {} dirty blocks and can not be put in passthrough mode",
+                            self.name(),
+                            status.performance.dirty
+                        ),
+                    ))
+                }
+                CacheDevStatus::Error | CacheDevStatus::Fail => {
+                    return Err(DmError::Dm(
+                        ErrorEnum::Error,
+                        format!("cache {} has failed", self.name()),
+                    ))
+                }
+            }
+        }
+
+        let mut table = self.table.clone();
+        table.table.params.set_io_mode(io_mode);
+
+        self.suspend(dm, DmOptions::default())?;
+        self.table_load(dm, &table, DmOptions::default())?;
+        self.table = table;
+        self.resume(dm)?;
+
+        Ok(())
+    }
+
+    /// Switch the cache to the cleaner policy and wait, polling its status,
+    /// until no dirty blocks remain in the cache, so that the origin holds
+    /// all the data. Returns an error if dirty blocks remain after
+    /// `timeout`; the cache is left with the cleaner policy.
+    pub fn clean(&mut self, dm: &DM, timeout: Duration) -> DmResult<()> {
+        self.set_policy(dm, CachePolicy::Cleaner, &[])?;
+
+        let start = Instant::now();
+        loop {
+            let dirty = match self.status(dm, DmOptions::default())? {
+                CacheDevStatus::Working(status) => status.performance.dirty,
+                CacheDevStatus::Error | CacheDevStatus::Fail => {
+                    return Err(DmError::Dm(
+                        ErrorEnum::Error,
+                        format!("cache {} failed while being cleaned", self.name()),
+                    ))
+                }
+            };
+            if dirty == 0 {
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                return Err(DmError::Dm(
+                    ErrorEnum::Error,
+                    format!(
+                        "cache {} still has {} dirty blocks after {:?}",
+                        self.name(),
+                        dirty,
+                        timeout
+                    ),
+                ));
+            }
+            thread::sleep(CLEAN_POLL_INTERVAL);
+        }
+    }
+
+    /// Remove the cache from the origin, leaving a linear device with the
+    /// same name, UUID, and contents as the cache device, which maps the
+    /// segments of the origin directly.
+    ///
+    /// The cache is first put in writethrough mode, so that no new writes
+    /// dirty its blocks, and cleaned with `clean`. Then the cache device is
+    /// suspended with a flush, its status is checked again to verify that
+    /// no dirty blocks remain, the table of the origin is loaded into it,
+    /// and the origin, cache, and meta sub-devices are torn down.
+    ///
+    /// If an error occurs, the devices are left in the kernel, resumed, and
+    /// the cache may be set up again with `CacheDev::setup`.
+    pub fn detach(mut self, dm: &DM, timeout: Duration) -> DmResult<LinearDev> {
+        if self.table.table.params.io_mode() == CacheIoMode::Writeback {
+            self.set_io_mode(dm, CacheIoMode::Writethrough)?;
+        }
+        self.clean(dm, timeout)?;
+
+        let name = self.name().to_owned();
+        let uuid = self.uuid().map(|uuid| uuid.to_owned());
+        let table = LinearDevTargetTable::new(self.origin_dev.table().table.clone());
+
+        self.suspend(dm, DmOptions::default())?;
+        let loaded = match self.status(dm, DmOptions::default()) {
+            Ok(CacheDevStatus::Working(status)) if status.performance.dirty == 0 => dm
+                .table_load(
+                    &DevId::Name(&name),
+                    &table.to_raw_table(),
+                    DmOptions::default(),
+                )
+                .map(|_| ()),
+            Ok(CacheDevStatus::Working(status)) => Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!(
+                    "cache {} has {} dirty blocks after it was suspended",
+                    name, status.performance.dirty
+                ),
+            )),
+            Ok(CacheDevStatus::Error | CacheDevStatus::Fail) => Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("cache {name} has failed"),
+            )),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = loaded {
+            self.resume(dm)?;
+            return Err(err);
+        }
+        self.resume(dm)?;
+
+        self.meta_dev.teardown(dm)?;
+        self.cache_dev.teardown(dm)?;
+        self.origin_dev.teardown(dm)?;
+
+        LinearDev::setup(dm, &name, uuid.as_deref(), table.table)
+    }
+
     /// Generate a table to be passed to DM. The format of the table
     /// entries is:
     /// <start sec (0)> <length> "cache" <cache-specific string>
@@ -721,6 +1392,29 @@ impl CacheDev {
         )
     }
 
+    /// Generate a table, as `gen_default_table` does, but with the cache
+    /// block size, IO mode, and replacement policy of `profile`.
+    fn gen_profile_table(
+        meta: &LinearDev,
+        cache: &LinearDev,
+        origin: &LinearDev,
+        profile: &CacheProfile,
+    ) -> CacheDevTargetTable {
+        CacheDevTargetTable::new(
+            Sectors::default(),
+            origin.size(),
+            CacheTargetParams::new(
+                meta.device(),
+                cache.device(),
+                origin.device(),
+                profile.cache_block_size,
+                vec![profile.io_mode.as_str().to_owned()],
+                profile.policy.as_str().to_owned(),
+                vec![],
+            ),
+        )
+    }
+
     /// Parse pairs of arguments from a slice
     fn parse_pairs(start_index: usize, vals: &[&str]) -> DmResult<(usize, Vec<(String, String)>)> {
         let num_pairs: usize = parse_value(vals[start_index], "number of pairs")?;
@@ -813,12 +1507,46 @@ pub fn minimal_cachedev(dm: &DM, paths: &[&Path]) -> CacheDev {
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{
+        io::{Read, Write},
+        path::Path,
+    };
 
     use crate::testing::test_with_spec;
 
     use super::*;
 
+    #[test]
+    /// Verify the recommended metadata size for each metadata format.
+    fn test_recommended_meta_size() {
+        // 1 GiB of 32 KiB blocks, 32768 blocks in all
+        let cache_size = Sectors(2 * IEC::Mi);
+        assert_eq!(
+            CacheDev::recommended_meta_size(
+                cache_size,
+                MIN_CACHE_BLOCK_SIZE,
+                CacheMetadataFormat::V1
+            ),
+            Sectors(8192 + 2816)
+        );
+        assert_eq!(
+            CacheDev::recommended_meta_size(
+                cache_size,
+                MIN_CACHE_BLOCK_SIZE,
+                CacheMetadataFormat::V2
+            ),
+            Sectors(8192 + 2824)
+        );
+        assert_eq!(
+            CacheDev::required_meta_bytes(
+                cache_size,
+                MIN_CACHE_BLOCK_SIZE * 2u64,
+                CacheMetadataFormat::V1
+            ),
+            Bytes(16384 * 44)
+        );
+    }
+
     // Test creating a minimal cache dev.
     // Verify that status method executes and gives reasonable values.
     fn test_minimal_cache_dev(paths: &[&Path]) {
@@ -1069,4 +1797,223 @@ mod tests {
     fn loop_test_suspend() {
         test_with_spec(2, test_suspend);
     }
+
+    #[test]
+    /// Verify that the policy argument count of a generated table counts
+    /// keys and values separately, so that the table round-trips.
+    fn test_policy_args_round_trip() {
+        let table = "cache 42:42 42:43 42:44 64 1 writethrough smq 2 migration_threshold 4096";
+        let params = table.parse::<CacheTargetParams>().unwrap();
+        assert_eq!(
+            params.policy_args,
+            [("migration_threshold".to_string(), "4096".to_string())]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        );
+        assert_eq!(params.to_string(), table);
+        assert_eq!(
+            params.to_string().parse::<CacheTargetParams>().unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    /// Verify that tunables round-trip through their key and value and that
+    /// setting a policy replaces the policy arguments.
+    fn test_policy_tunables() {
+        let tunables = [
+            CacheTunable::MigrationThreshold(Sectors(4096)),
+            CacheTunable::SequentialThreshold(512),
+            CacheTunable::RandomThreshold(4),
+            CacheTunable::ReadPromoteAdjustment(4),
+            CacheTunable::WritePromoteAdjustment(8),
+            CacheTunable::DiscardPromoteAdjustment(1),
+        ];
+        for tunable in tunables {
+            assert_eq!(
+                CacheTunable::from_pair(tunable.key(), &tunable.value().to_string()).unwrap(),
+                tunable
+            );
+        }
+        assert_eq!(
+            CacheTunable::MigrationThreshold(Sectors(4096)).to_string(),
+            "migration_threshold 4096"
+        );
+        assert_matches!(CacheTunable::from_pair("unknown", "1"), Err(_));
+        assert_matches!(CacheTunable::from_pair("random_threshold", "x"), Err(_));
+        assert_eq!(
+            "cleaner".parse::<CachePolicy>().unwrap(),
+            CachePolicy::Cleaner
+        );
+
+        let mut params = "cache 42:42 42:43 42:44 64 1 writethrough default 2 random_threshold 4"
+            .parse::<CacheTargetParams>()
+            .unwrap();
+        params.set_policy(CachePolicy::Smq, &tunables[..1]);
+        assert_eq!(params.policy, "smq");
+        assert_eq!(
+            params.param_str(),
+            "42:42 42:43 42:44 64 1 writethrough smq 2 migration_threshold 4096"
+        );
+    }
+
+    /// Verify that a tunable changed on a live cache is reported in its
+    /// status, and that switching policy preserves the rest of the table.
+    fn test_set_policy(paths: &[&Path]) {
+        assert!(paths.len() >= 2);
+
+        let dm = DM::new().unwrap();
+        let mut cache = minimal_cachedev(&dm, paths);
+
+        let status = |cache: &CacheDev| match cache.status(&dm, DmOptions::default()).unwrap() {
+            CacheDevStatus::Working(status) => status,
+            status => panic!("unexpected cache status: {status:?}"),
+        };
+
+        cache
+            .set_tunable(&dm, CacheTunable::MigrationThreshold(Sectors(4096)))
+            .unwrap();
+        assert_eq!(
+            status(&cache).core_args,
+            vec![("migration_threshold".to_string(), "4096".to_string())]
+        );
+
+        let params = cache.table().table.params.clone();
+        cache.set_policy(&dm, CachePolicy::Cleaner, &[]).unwrap();
+        assert_eq!(status(&cache).policy, "cleaner");
+        let new_params = CacheDev::read_kernel_table(&dm, &DevId::Name(cache.name()))
+            .unwrap()
+            .table
+            .params;
+        assert_eq!(new_params.policy, "cleaner");
+        assert_eq!(new_params.feature_args, params.feature_args);
+        assert_eq!(new_params.cache_block_size, params.cache_block_size);
+
+        cache.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_set_policy() {
+        test_with_spec(2, test_set_policy);
+    }
+
+    /// Verify that data written through a cache in the given IO mode is
+    /// present on the linear device which is left when the cache is
+    /// detached, and that the sub-devices are removed.
+    fn check_detach(paths: &[&Path], io_mode: CacheIoMode) {
+        assert!(paths.len() >= 2);
+
+        let dm = DM::new().unwrap();
+        let mut cache = minimal_cachedev(&dm, paths);
+        cache.set_io_mode(&dm, io_mode).unwrap();
+        let name = cache.name().to_owned();
+        let size = cache.size();
+        let sub_devices = [
+            cache.meta_dev.name().to_owned(),
+            cache.cache_dev.name().to_owned(),
+            cache.origin_dev.name().to_owned(),
+        ];
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(cache.devnode())
+            .unwrap();
+        file.write_all(&[0xa5; 4096]).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+
+        let mut linear = cache.detach(&dm, Duration::from_secs(60)).unwrap();
+        assert_eq!(linear.name(), &*name);
+        assert_eq!(linear.size(), size);
+        for sub_device in sub_devices.iter() {
+            assert!(!device_exists(&dm, sub_device).unwrap());
+        }
+
+        let mut buf = [0u8; 4096];
+        OpenOptions::new()
+            .read(true)
+            .open(linear.devnode())
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+
+        linear.teardown(&dm).unwrap();
+    }
+
+    fn test_detach(paths: &[&Path]) {
+        check_detach(paths, CacheIoMode::Writethrough);
+    }
+
+    #[test]
+    fn loop_test_detach() {
+        test_with_spec(2, test_detach);
+    }
+
+    /// Verify that the dirty blocks of a writeback cache are written back
+    /// to the origin before the cache is detached.
+    fn test_detach_writeback(paths: &[&Path]) {
+        check_detach(paths, CacheIoMode::Writeback);
+    }
+
+    #[test]
+    fn loop_test_detach_writeback() {
+        test_with_spec(2, test_detach_writeback);
+    }
+
+    #[test]
+    /// Verify that the IO mode is read from and written to the feature
+    /// args without disturbing other feature args.
+    fn test_io_mode() {
+        let mut params = "cache 42:42 42:43 42:44 64 1 metadata2 default 0"
+            .parse::<CacheTargetParams>()
+            .unwrap();
+        assert_eq!(params.io_mode(), CacheIoMode::Writeback);
+
+        params.set_io_mode(CacheIoMode::Passthrough);
+        assert_eq!(params.io_mode(), CacheIoMode::Passthrough);
+        params.set_io_mode(CacheIoMode::Writethrough);
+        assert_eq!(params.io_mode(), CacheIoMode::Writethrough);
+        assert_eq!(
+            params.feature_args,
+            ["metadata2", "writethrough"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<HashSet<_>>()
+        );
+        assert_matches!("writearound".parse::<CacheIoMode>(), Err(_));
+    }
+
+    /// Verify that the IO mode of a live cache can be switched between all
+    /// modes, and that the change is reflected in its status.
+    fn test_set_io_mode(paths: &[&Path]) {
+        assert!(paths.len() >= 2);
+
+        let dm = DM::new().unwrap();
+        let mut cache = minimal_cachedev(&dm, paths);
+
+        let feature_args = |cache: &CacheDev| match cache.status(&dm, DmOptions::default()).unwrap()
+        {
+            CacheDevStatus::Working(status) => status.feature_args,
+            status => panic!("unexpected cache status: {status:?}"),
+        };
+
+        for mode in [
+            CacheIoMode::Writeback,
+            CacheIoMode::Writethrough,
+            CacheIoMode::Passthrough,
+            CacheIoMode::Writeback,
+        ] {
+            cache.set_io_mode(&dm, mode).unwrap();
+            assert_eq!(cache.table().table.params.io_mode(), mode);
+            assert_eq!(feature_args(&cache), vec![mode.to_string()]);
+        }
+
+        cache.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_set_io_mode() {
+        test_with_spec(2, test_set_io_mode);
+    }
 }
@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// The versions of the DM targets which the running kernel provides.
+
+use std::collections::HashMap;
+
+use semver::{Version, VersionReq};
+
+use crate::{
+    core::{dm::DM, errors},
+    result::{DmError, DmResult},
+};
+
+/// The DM targets available in the running kernel and their versions, as
+/// reported by `DM::list_versions`.
+///
+/// Only targets which are built in or whose modules are already loaded are
+/// listed. The kernel loads the module of a target the first time a table
+/// which uses it is loaded, so a missing target may simply not have been
+/// used yet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DmCapabilities {
+    targets: HashMap<String, Version>,
+}
+
+impl DmCapabilities {
+    /// Get the versions of the targets available in the running kernel.
+    pub fn new(dm: &DM) -> DmResult<DmCapabilities> {
+        Ok(DmCapabilities {
+            targets: dm
+                .list_versions()?
+                .into_iter()
+                .map(|(name, major, minor, patch)| {
+                    (
+                        name,
+                        Version::new(u64::from(major), u64::from(minor), u64::from(patch)),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// The version of the given target, or None if it is not available.
+    pub fn target_version(&self, target: &str) -> Option<&Version> {
+        self.targets.get(target)
+    }
+
+    /// Check that the given target is available and that its version
+    /// satisfies `requirement`, a semver version requirement such as
+    /// ">=1.19". Returns the version of the target.
+    ///
+    /// ```no_run
+    /// use devicemapper::{DmCapabilities, DM};
+    ///
+    /// let dm = DM::new().unwrap();
+    /// let capabilities = DmCapabilities::new(&dm).unwrap();
+    /// capabilities.require_target("thin-pool", ">=1.19").unwrap();
+    /// ```
+    pub fn require_target(&self, target: &str, requirement: &str) -> DmResult<&Version> {
+        let req = VersionReq::parse(requirement).map_err(|err| {
+            DmError::Core(errors::Error::InvalidArgument(format!(
+                "\"{requirement}\" is not a valid version requirement: {err}"
+            )))
+        })?;
+        match self.targets.get(target) {
+            Some(version) if req.matches(version) => Ok(version),
+            version => Err(DmError::Core(errors::Error::UnsupportedTarget(
+                target.to_string(),
+                requirement.to_string(),
+                version.cloned(),
+            ))),
+        }
+    }
+
+    /// The feature arguments among `features` which the running kernel's
+    /// version of `target` does not accept, each with the version of the
+    /// target which introduced it. `min_version` gives the version which
+    /// introduced a feature argument, or None if it has always been
+    /// accepted or is not known. If the target is not listed, its version
+    /// is unknown and every feature argument is assumed to be accepted.
+    pub(crate) fn unsupported_features<'a, I>(
+        &self,
+        target: &str,
+        features: I,
+        min_version: fn(&str) -> Option<Version>,
+    ) -> Vec<(String, Version)>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let version = match self.targets.get(target) {
+            Some(version) => version,
+            None => return Vec::new(),
+        };
+        features
+            .into_iter()
+            .filter_map(|feature| {
+                min_version(feature)
+                    .filter(|min| version < min)
+                    .map(|min| (feature.clone(), min))
+            })
+            .collect()
+    }
+
+    /// Return an error if the running kernel's version of `target` does not
+    /// accept one of `features`. See `unsupported_features`.
+    pub(crate) fn check_features<'a, I>(
+        &self,
+        target: &str,
+        features: I,
+        min_version: fn(&str) -> Option<Version>,
+    ) -> DmResult<()>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        match self
+            .unsupported_features(target, features, min_version)
+            .into_iter()
+            .next()
+        {
+            Some((feature, min)) => {
+                warn!(
+                    "Feature argument {} requires {} target version {} or later",
+                    feature, target, min
+                );
+                Err(DmError::Core(errors::Error::UnsupportedTarget(
+                    target.to_string(),
+                    format!(">={min}"),
+                    self.targets.get(target).cloned(),
+                )))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestGuard;
+
+    use super::*;
+
+    fn capabilities() -> DmCapabilities {
+        DmCapabilities {
+            targets: [("thin-pool".to_string(), Version::new(1, 22, 0))]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    /// Verify that a requirement is checked against the target's version
+    /// and that the error names the target and the versions involved.
+    fn test_require_target() {
+        let capabilities = capabilities();
+        assert_eq!(
+            capabilities.require_target("thin-pool", ">=1.19").unwrap(),
+            &Version::new(1, 22, 0)
+        );
+        assert_matches!(
+            capabilities.require_target("thin-pool", ">=1.23"),
+            Err(DmError::Core(errors::Error::UnsupportedTarget(target, req, Some(version))))
+                if target == "thin-pool" && req == ">=1.23" && version == Version::new(1, 22, 0)
+        );
+        assert_matches!(
+            capabilities.require_target("cache", ">=1.0"),
+            Err(DmError::Core(errors::Error::UnsupportedTarget(_, _, None)))
+        );
+        assert_matches!(
+            capabilities.require_target("thin-pool", "one"),
+            Err(DmError::Core(errors::Error::InvalidArgument(_)))
+        );
+    }
+
+    #[test]
+    /// Verify that only feature arguments introduced after the target's
+    /// version are reported, and none if the target's version is unknown.
+    fn test_unsupported_features() {
+        fn min_version(feature: &str) -> Option<Version> {
+            match feature {
+                "old" => Some(Version::new(1, 1, 0)),
+                "new" => Some(Version::new(1, 23, 0)),
+                _ => None,
+            }
+        }
+        let features = ["old".to_string(), "new".to_string(), "other".to_string()];
+        let capabilities = capabilities();
+        assert_eq!(
+            capabilities.unsupported_features("thin-pool", &features, min_version),
+            vec![("new".to_string(), Version::new(1, 23, 0))]
+        );
+        assert_matches!(
+            capabilities.check_features("thin-pool", &features, min_version),
+            Err(DmError::Core(errors::Error::UnsupportedTarget(_, req, _))) if req == ">=1.23.0"
+        );
+        assert_matches!(
+            capabilities.check_features("thin-pool", &features[..1], min_version),
+            Ok(())
+        );
+        assert_eq!(
+            capabilities.unsupported_features("cache", &features, min_version),
+            vec![]
+        );
+    }
+
+    #[test]
+    /// Verify that the linear target, which is always built in, is listed.
+    fn sudo_test_capabilities() {
+        let _guard = TestGuard::new();
+        let capabilities = DmCapabilities::new(&DM::new().unwrap()).unwrap();
+        assert_matches!(capabilities.require_target("linear", ">=1.0"), Ok(_));
+    }
+}
@@ -0,0 +1,275 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::result::{DmError, DmResult, ErrorEnum};
+
+#[cfg(target_os = "linux")]
+/// Control path for user space to pass IOCTL to kernel DM
+const DM_CTL_PATH: &str = "/dev/mapper/control";
+#[cfg(target_os = "android")]
+/// Control path for user space to pass IOCTL to kernel DM
+const DM_CTL_PATH: &str = "/dev/device-mapper";
+
+/// Start with a large buffer to make BUFFER_FULL rare. Libdm does this too.
+const MIN_BUF_SIZE: usize = 16 * 1024;
+
+/// Number of device remove retry attempts
+const DM_REMOVE_RETRIES: usize = 5;
+
+/// Delay between remove attempts
+const DM_REMOVE_MSLEEP_DELAY: u64 = 200;
+
+/// The DM control node to use
+const ENV_CONTROL_PATH: &str = "DM_CONTROL_PATH";
+/// The number of attempts made to remove a busy device
+const ENV_REMOVE_RETRIES: &str = "DM_REMOVE_RETRIES";
+/// The delay between attempts to remove a busy device, in milliseconds
+const ENV_REMOVE_DELAY: &str = "DM_REMOVE_RETRY_DELAY_MS";
+/// If set, to any value, do not synchronize with udev, as libdm
+const ENV_DISABLE_UDEV: &str = "DM_DISABLE_UDEV";
+/// The longest to wait for udev to process an event, in milliseconds
+const ENV_UDEV_TIMEOUT: &str = "DM_UDEV_TIMEOUT_MS";
+/// The initial size of the ioctl buffer, in bytes
+const ENV_BUFFER_SIZE: &str = "DM_BUFFER_SIZE";
+/// If set, to any value, never read /proc or /sys
+const ENV_IOCTL_ONLY: &str = "DM_IOCTL_ONLY";
+
+/// Configuration of a DM context, passed to `DM::with_config`.
+///
+/// `DmConfig::default()` gives the built-in behavior, which `DM::new` uses.
+/// `DmConfig::from_env()`, which `DM::from_env` uses,
+/// starts from the defaults and overrides them from the environment, so that
+/// a deployment can tune a program without changing it. The variables are:
+///
+/// * `DM_CONTROL_PATH`: the DM control node
+/// * `DM_REMOVE_RETRIES`: the number of attempts made to remove a busy device
+/// * `DM_REMOVE_RETRY_DELAY_MS`: the delay between those attempts
+/// * `DM_DISABLE_UDEV`: if set, to any value, do not synchronize with udev
+/// * `DM_UDEV_TIMEOUT_MS`: the longest to wait for udev to process an event
+/// * `DM_BUFFER_SIZE`: the initial size of the ioctl buffer, in bytes
+/// * `DM_IOCTL_ONLY`: if set, to any value, never read /proc or /sys
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DmConfig {
+    control_path: PathBuf,
+    remove_retries: usize,
+    remove_retry_delay: Duration,
+    udev_sync: bool,
+    udev_timeout: Option<Duration>,
+    buffer_size: usize,
+    ioctl_only: bool,
+}
+
+impl Default for DmConfig {
+    fn default() -> DmConfig {
+        DmConfig {
+            control_path: PathBuf::from(DM_CTL_PATH),
+            remove_retries: DM_REMOVE_RETRIES,
+            remove_retry_delay: Duration::from_millis(DM_REMOVE_MSLEEP_DELAY),
+            udev_sync: true,
+            udev_timeout: None,
+            buffer_size: MIN_BUF_SIZE,
+            ioctl_only: false,
+        }
+    }
+}
+
+/// Parse the value of the environment variable `name`, as looked up by
+/// `var`, if it is set.
+fn env_value<T, F>(var: &F, name: &str) -> DmResult<Option<T>>
+where
+    T: FromStr,
+    F: Fn(&str) -> Option<OsString>,
+{
+    match var(name).map(OsString::into_string) {
+        Some(Ok(value)) => value.trim().parse::<T>().map(Some).map_err(|_| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("invalid value \"{value}\" for environment variable {name}"),
+            )
+        }),
+        None => Ok(None),
+        Some(Err(_)) => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("value of environment variable {name} is not valid unicode"),
+        )),
+    }
+}
+
+impl DmConfig {
+    /// The default configuration, overridden from the environment. Return
+    /// an error if any variable has a value which can not be parsed.
+    pub fn from_env() -> DmResult<DmConfig> {
+        DmConfig::from_vars(|var| env::var_os(var))
+    }
+
+    /// The default configuration, overridden from the variables which
+    /// `var` looks up.
+    fn from_vars<F>(var: F) -> DmResult<DmConfig>
+    where
+        F: Fn(&str) -> Option<OsString>,
+    {
+        let mut config = DmConfig::default();
+        if let Some(path) = var(ENV_CONTROL_PATH) {
+            config = config.set_control_path(Path::new(&path));
+        }
+        if let Some(retries) = env_value(&var, ENV_REMOVE_RETRIES)? {
+            config = config.set_remove_retries(retries);
+        }
+        if let Some(delay) = env_value(&var, ENV_REMOVE_DELAY)? {
+            config = config.set_remove_retry_delay(Duration::from_millis(delay));
+        }
+        if var(ENV_DISABLE_UDEV).is_some() {
+            config = config.set_udev_sync(false);
+        }
+        if let Some(timeout) = env_value(&var, ENV_UDEV_TIMEOUT)? {
+            config = config.set_udev_timeout(Some(Duration::from_millis(timeout)));
+        }
+        if let Some(size) = env_value(&var, ENV_BUFFER_SIZE)? {
+            config = config.set_buffer_size(size);
+        }
+        if var(ENV_IOCTL_ONLY).is_some() {
+            config = config.set_ioctl_only(true);
+        }
+        Ok(config)
+    }
+
+    /// Set the DM control node. Consumes self.
+    pub fn set_control_path(mut self, path: &Path) -> DmConfig {
+        self.control_path = path.to_owned();
+        self
+    }
+
+    /// Set the number of attempts made to remove a busy device. At least
+    /// one attempt is always made. Consumes self.
+    pub fn set_remove_retries(mut self, retries: usize) -> DmConfig {
+        self.remove_retries = retries;
+        self
+    }
+
+    /// Set the delay between attempts to remove a busy device. Consumes
+    /// self.
+    pub fn set_remove_retry_delay(mut self, delay: Duration) -> DmConfig {
+        self.remove_retry_delay = delay;
+        self
+    }
+
+    /// Set whether to synchronize with udev, i.e., whether to wait for udev
+    /// to finish processing the events generated by an ioctl before
+    /// returning from it. Consumes self.
+    pub fn set_udev_sync(mut self, udev_sync: bool) -> DmConfig {
+        self.udev_sync = udev_sync;
+        self
+    }
+
+    /// Set the longest to wait for udev to process an event. If None, wait
+    /// indefinitely. If the wait times out, a warning is logged and the
+    /// ioctl returns as if udev had finished. Consumes self.
+    pub fn set_udev_timeout(mut self, timeout: Option<Duration>) -> DmConfig {
+        self.udev_timeout = timeout;
+        self
+    }
+
+    /// Set the initial size of the ioctl buffer. A larger buffer makes it
+    /// less likely that an ioctl with a large result must be repeated.
+    /// Consumes self.
+    pub fn set_buffer_size(mut self, size: usize) -> DmConfig {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Set whether to work in ioctl-only mode, in which /proc and /sys are
+    /// never read, and only the DM control node, block device nodes, and
+    /// their ioctls are used, e.g., under a strict seccomp or landlock
+    /// policy. Consumes self.
+    pub fn set_ioctl_only(mut self, ioctl_only: bool) -> DmConfig {
+        self.ioctl_only = ioctl_only;
+        self
+    }
+
+    /// Retrieve the DM control node
+    pub fn control_path(&self) -> &Path {
+        &self.control_path
+    }
+
+    /// Retrieve the number of attempts made to remove a busy device
+    pub fn remove_retries(&self) -> usize {
+        self.remove_retries
+    }
+
+    /// Retrieve the delay between attempts to remove a busy device
+    pub fn remove_retry_delay(&self) -> Duration {
+        self.remove_retry_delay
+    }
+
+    /// Retrieve whether to synchronize with udev
+    pub fn udev_sync(&self) -> bool {
+        self.udev_sync
+    }
+
+    /// Retrieve the longest to wait for udev to process an event
+    pub fn udev_timeout(&self) -> Option<Duration> {
+        self.udev_timeout
+    }
+
+    /// Retrieve the initial size of the ioctl buffer
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Retrieve whether to work in ioctl-only mode
+    pub fn ioctl_only(&self) -> bool {
+        self.ioctl_only
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// The config from the variables of `vars`, rather than from the
+    /// environment, which is shared between test threads.
+    fn from_vars(vars: &[(&str, &str)]) -> DmResult<DmConfig> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect::<HashMap<_, _>>();
+        DmConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    /// Verify that variables override the defaults, and that an unparseable
+    /// value is rejected.
+    fn test_from_vars() {
+        assert_eq!(from_vars(&[]).unwrap(), DmConfig::default());
+
+        let config = from_vars(&[
+            (ENV_REMOVE_RETRIES, "3"),
+            (ENV_DISABLE_UDEV, ""),
+            (ENV_UDEV_TIMEOUT, "1500"),
+        ])
+        .unwrap();
+        assert_eq!(config.remove_retries(), 3);
+        assert!(!config.udev_sync());
+        assert_eq!(config.udev_timeout(), Some(Duration::from_millis(1500)));
+        assert_eq!(config.buffer_size(), MIN_BUF_SIZE);
+        assert!(!config.ioctl_only());
+
+        assert!(from_vars(&[(ENV_IOCTL_ONLY, "")]).unwrap().ioctl_only());
+
+        assert_matches!(
+            from_vars(&[(ENV_BUFFER_SIZE, "large")]),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+}
@@ -12,6 +12,9 @@ use crate::{
     result::{DmError, DmResult},
 };
 
+/// Path to the sysfs directory of block devices, indexed by "<major>:<minor>"
+pub(crate) const SYSFS_DEV_BLOCK_PATH: &str = "/sys/dev/block";
+
 /// A struct containing the device's major and minor numbers
 ///
 /// Also allows conversion to/from a single 64bit dev_t value.
@@ -30,10 +33,20 @@ impl fmt::Display for Device {
     }
 }
 
+/// Parse a device number in "<major>:<minor>" format, or the path of a block
+/// device node, e.g., "/dev/sda1", which is resolved to its device number.
 impl FromStr for Device {
     type Err = DmError;
 
     fn from_str(s: &str) -> Result<Device, DmError> {
+        if s.starts_with('/') {
+            return match devnode_to_devno(Path::new(s))? {
+                Some(devno) => Ok(Device::from(devno)),
+                None => Err(DmError::Core(errors::Error::InvalidArgument(format!(
+                    "path \"{s}\" is not a block device node"
+                )))),
+            };
+        }
         let vals = s.split(':').collect::<Vec<_>>();
         if vals.len() != 2 {
             let err_msg = format!("value \"{s}\" split into wrong number of fields");
@@ -55,6 +68,25 @@ impl FromStr for Device {
     }
 }
 
+impl serde::Serialize for Device {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Device {
+    fn deserialize<D>(deserializer: D) -> Result<Device, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value.parse::<Device>().map_err(serde::de::Error::custom)
+    }
+}
+
 impl From<dev_t> for Device {
     fn from(val: dev_t) -> Device {
         let major = unsafe { major(val) };
@@ -171,4 +203,32 @@ mod tests {
         let dev2 = Device::from(0xabcd_ef12_3456_7890);
         assert_eq!(dev2.to_kdev_t(), None);
     }
+
+    #[test]
+    /// Verify that minor numbers which do not fit in 8 bits survive
+    /// conversion to and from both dev_t and kdev_t.
+    fn test_large_minor_conversion() {
+        let dev = Device {
+            major: 253,
+            minor: 0x1_2345,
+        };
+        assert_eq!(Device::from(dev_t::from(dev)), dev);
+        assert_eq!(Device::from_kdev_t(dev.to_kdev_t().unwrap()), dev);
+        assert_eq!("253:74565".parse::<Device>().unwrap(), dev);
+    }
+
+    #[test]
+    /// Verify that device numbers round-trip through their string form, and
+    /// that paths which are not block device nodes are rejected.
+    fn test_device_from_str() {
+        let dev = Device { major: 8, minor: 3 };
+        assert_eq!(dev.to_string(), "8:3");
+        assert_eq!(dev.to_string().parse::<Device>().unwrap(), dev);
+
+        assert!("8".parse::<Device>().is_err());
+        assert!("8:3:1".parse::<Device>().is_err());
+        assert!("8:x".parse::<Device>().is_err());
+        assert!("/dev/null".parse::<Device>().is_err());
+        assert!("/nonexistent".parse::<Device>().is_err());
+    }
 }
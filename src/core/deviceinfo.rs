@@ -120,4 +120,12 @@ impl DeviceInfo {
     pub fn flags(&self) -> DmFlags {
         self.flags
     }
+
+    /// Whether the device is suspended internally by the kernel, e.g.,
+    /// a thin device while its pool is suspended, rather than by a DM
+    /// suspend ioctl. Most operations on such a device block or fail until
+    /// the kernel resumes it.
+    pub fn is_internally_suspended(&self) -> bool {
+        self.flags.contains(DmFlags::DM_INTERNAL_SUSPEND)
+    }
 }
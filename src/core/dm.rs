@@ -3,12 +3,18 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::{
+    cell::Cell,
     cmp,
-    fs::File,
+    collections::HashMap,
+    fs::{self, File},
     io::{Cursor, Read, Write},
     mem::size_of,
     os::unix::io::{AsRawFd, RawFd},
+    path::Path,
     slice, str,
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
 use nix::{errno, libc::ioctl as nix_ioctl};
@@ -24,34 +30,41 @@ use crate::{
         dm_options::DmOptions,
         dm_udev_sync::{UdevSync, UdevSyncAction},
         errors,
+        events::{DmChanges, EventSnapshot},
+        inuse::{self, device_in_use, Holder},
+        privileges::PrivilegeReport,
         types::{DevId, DmName, DmNameBuf, DmUuid},
         util::{
-            align_to, c_struct_from_slice, mut_slice_from_c_str, slice_from_c_struct,
-            str_from_byte_slice, str_from_c_str,
+            c_struct_from_slice, mut_slice_from_c_str, slice_from_c_struct, str_from_byte_slice,
+            str_from_c_str,
         },
     },
     result::{DmError, DmResult, ErrorEnum},
+    shared::read_only_options,
+    units::Bytes,
 };
 
-#[cfg(target_os = "linux")]
-/// Control path for user space to pass IOCTL to kernel DM
-const DM_CTL_PATH: &str = "/dev/mapper/control";
-#[cfg(target_os = "android")]
-/// Control path for user space to pass IOCTL to kernel DM
-const DM_CTL_PATH: &str = "/dev/device-mapper";
+/// Directory holding the per-name device nodes or symlinks of DM devices
+pub(crate) const DM_DEV_DIR: &str = "/dev/mapper";
 
-/// Start with a large buffer to make BUFFER_FULL rare. Libdm does this too.
-const MIN_BUF_SIZE: usize = 16 * 1024;
+/// Present only while udev is running
+const UDEV_CONTROL_PATH: &str = "/run/udev/control";
 
-/// Number of device remove retry attempts
-const DM_REMOVE_RETRIES: usize = 5;
+/// Delay between checks for a device to be internally resumed
+const DM_INTERNAL_SUSPEND_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-/// Delay between remove attempts
-const DM_REMOVE_MSLEEP_DELAY: u64 = 200;
+/// The largest minor number the kernel allows, 2^MINORBITS - 1
+const DM_MAX_MINOR: u32 = (1 << 20) - 1;
+
+/// Delay between checks for udev to move a renamed device's node
+const DM_RENAME_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// Context needed for communicating with devicemapper.
 pub struct DM {
     file: File,
+    config: DmConfig,
+    // The kernel's ioctl interface version, once it has been queried
+    kernel_version: Mutex<Option<(u32, u32, u32)>>,
 }
 
 impl DmOptions {
@@ -61,11 +74,32 @@ impl DmOptions {
         id: Option<&DevId<'_>>,
         allowable_flags: DmFlags,
     ) -> DmResult<dmi::Struct_dm_ioctl> {
-        let clean_flags = allowable_flags & self.flags();
+        let mut clean_flags = allowable_flags & self.flags();
         let event_nr = self.udev_flags().bits() << dmi::DM_UDEV_FLAGS_SHIFT;
+
+        // The kernel decodes the requested minor number from the dev field,
+        // encoded as a kdev_t; it ignores the major number.
+        let mut dev = 0;
+        if let Some(minor) = self.minor() {
+            if allowable_flags.contains(DmFlags::DM_PERSISTENT_DEV) {
+                if minor > DM_MAX_MINOR {
+                    return Err(DmError::Core(errors::Error::InvalidArgument(format!(
+                        "minor number {minor} is larger than the maximum, {DM_MAX_MINOR}"
+                    ))));
+                }
+                clean_flags |= DmFlags::DM_PERSISTENT_DEV;
+                dev = u64::from(
+                    Device { major: 0, minor }
+                        .to_kdev_t()
+                        .expect("minor number is no larger than DM_MAX_MINOR"),
+                );
+            }
+        }
+
         let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
             flags: clean_flags.bits(),
             event_nr,
+            dev,
             data_start: size_of::<dmi::Struct_dm_ioctl>() as u32,
             ..Default::default()
         };
@@ -82,14 +116,43 @@ impl DmOptions {
 }
 
 impl DM {
-    /// Create a new context for communicating with DM.
+    /// Create a new context for communicating with DM, with the default
+    /// configuration.
     pub fn new() -> DmResult<DM> {
+        DM::with_config(DmConfig::default())
+    }
+
+    /// Create a new context for communicating with DM, configured by
+    /// `DmConfig::from_env`.
+    pub fn from_env() -> DmResult<DM> {
+        DM::with_config(DmConfig::from_env()?)
+    }
+
+    /// Create a new context for communicating with DM, using the given
+    /// configuration.
+    pub fn with_config(config: DmConfig) -> DmResult<DM> {
         Ok(DM {
-            file: File::open(DM_CTL_PATH)
+            file: File::open(config.control_path())
                 .map_err(|err| DmError::Core(errors::Error::ContextInit(err.to_string())))?,
+            config,
+            kernel_version: Mutex::new(None),
         })
     }
 
+    /// The configuration of this context.
+    pub fn config(&self) -> &DmConfig {
+        &self.config
+    }
+
+    /// Check that this process has the privileges which DM requires:
+    /// CAP_SYS_ADMIN, and read-write access to the DM control node. This
+    /// does not need a DM context, so a long-running process may call it at
+    /// startup and, if `PrivilegeReport::check` fails, exit with a clear
+    /// message rather than failing on its first DM operation.
+    pub fn check_privileges() -> PrivilegeReport {
+        PrivilegeReport::new(DmConfig::from_env().unwrap_or_default().control_path())
+    }
+
     fn hdr_set_name(hdr: &mut dmi::Struct_dm_ioctl, name: &DmName) -> DmResult<()> {
         let _ = name
             .as_bytes()
@@ -112,32 +175,49 @@ impl DM {
     }
 
     // Make the ioctl call specified by the given ioctl number.
-    // Set the required DM version to the lowest that supports the given ioctl.
+    // Set the required DM version to the lowest that supports the given ioctl
+    // and its flags. If the kernel's ioctl interface is older than that, drop
+    // any optional flags it does not support and retry, or else return an
+    // UnsupportedIoctl error.
     fn do_ioctl(
         &self,
         ioctl: u8,
         hdr: &mut dmi::Struct_dm_ioctl,
         in_data: Option<&[u8]>,
+    ) -> DmResult<(DeviceInfo, Vec<u8>)> {
+        self.do_ioctl_with_buffer(ioctl, hdr, in_data, &mut Vec::new())
+    }
+
+    // Make the ioctl call as do_ioctl does, but use the given buffer for the
+    // request and response, so that a caller making many calls need only
+    // allocate it once.
+    fn do_ioctl_with_buffer(
+        &self,
+        ioctl: u8,
+        hdr: &mut dmi::Struct_dm_ioctl,
+        in_data: Option<&[u8]>,
+        buffer: &mut Vec<u8>,
     ) -> DmResult<(DeviceInfo, Vec<u8>)> {
         let op = request_code_readwrite!(dmi::DM_IOCTL, ioctl, size_of::<dmi::Struct_dm_ioctl>());
         #[cfg(target_os = "android")]
         let op = op as i32;
 
-        let ioctl_version = dmi::ioctl_to_version(ioctl);
+        let ioctl_version = dmi::ioctl_flags_to_version(ioctl, hdr.flags);
         hdr.version[0] = ioctl_version.0;
         hdr.version[1] = ioctl_version.1;
         hdr.version[2] = ioctl_version.2;
 
         // Begin udev sync transaction and set DM_UDEV_PRIMARY_SOURCE_FLAG
         // if ioctl command generates uevents.
-        let sync = UdevSync::begin(hdr, ioctl)?;
+        let sync = UdevSync::begin(hdr, ioctl, &self.config)?;
 
         let data_size = cmp::max(
-            MIN_BUF_SIZE,
+            self.config.buffer_size(),
             size_of::<dmi::Struct_dm_ioctl>() + in_data.map_or(0, |x| x.len()),
         );
 
-        let mut buffer: Vec<u8> = Vec::with_capacity(data_size);
+        buffer.clear();
+        buffer.reserve(data_size);
         let mut buffer_hdr;
         loop {
             hdr.data_size = buffer.capacity() as u32;
@@ -160,6 +240,50 @@ impl DM {
             if let Err(err) = unsafe {
                 convert_ioctl_res!(nix_ioctl(self.file.as_raw_fd(), op, buffer.as_mut_ptr()))
             } {
+                // On a version mismatch the kernel returns EINVAL and reports
+                // its own version in the output header.
+                let requested = (hdr.version[0], hdr.version[1], hdr.version[2]);
+                let kernel = (
+                    buffer_hdr.version[0],
+                    buffer_hdr.version[1],
+                    buffer_hdr.version[2],
+                );
+                if err == errno::Errno::EINVAL && kernel.0 == requested.0 && kernel < requested {
+                    let unsupported = dmi::unsupported_flags(hdr.flags, kernel);
+                    if unsupported & !dmi::OPTIONAL_FLAGS == 0
+                        && dmi::ioctl_to_version(ioctl) <= kernel
+                    {
+                        warn!(
+                            "Kernel DM ioctl interface version {}.{}.{} does not support flags {:?}, retrying without them",
+                            kernel.0,
+                            kernel.1,
+                            kernel.2,
+                            DmFlags::from_bits_truncate(unsupported)
+                        );
+                        hdr.flags &= !unsupported;
+                        let ioctl_version = dmi::ioctl_flags_to_version(ioctl, hdr.flags);
+                        hdr.version[0] = ioctl_version.0;
+                        hdr.version[1] = ioctl_version.1;
+                        hdr.version[2] = ioctl_version.2;
+                        continue;
+                    }
+
+                    sync.cancel();
+                    return Err(DmError::Core(errors::Error::UnsupportedIoctl(
+                        ioctl,
+                        Version::new(
+                            u64::from(requested.0),
+                            u64::from(requested.1),
+                            u64::from(requested.2),
+                        ),
+                        Version::new(
+                            u64::from(kernel.0),
+                            u64::from(kernel.1),
+                            u64::from(kernel.2),
+                        ),
+                    )));
+                }
+
                 // Cancel udev sync and clean up semaphore
                 sync.cancel();
                 return Err(DmError::Core(errors::Error::Ioctl(
@@ -221,6 +345,51 @@ impl DM {
         ))
     }
 
+    /// The kernel's ioctl interface version, as returned by `Self::version`.
+    /// The version is queried once and cached in this context, so callers
+    /// may check it, or use the `supports_*` methods, as often as they like.
+    pub fn kernel_ioctl_version(&self) -> DmResult<(u32, u32, u32)> {
+        let mut kernel_version = self
+            .kernel_version
+            .lock()
+            .expect("no thread panics while holding the lock");
+        match *kernel_version {
+            Some(version) => Ok(version),
+            None => {
+                let version = self.version()?;
+                *kernel_version = Some(version);
+                Ok(version)
+            }
+        }
+    }
+
+    fn kernel_supports(&self, version: (u32, u32, u32)) -> DmResult<bool> {
+        let kernel_version = self.kernel_ioctl_version()?;
+        Ok(kernel_version.0 == version.0 && kernel_version >= version)
+    }
+
+    /// Whether the kernel supports the `DM_DEFERRED_REMOVE` flag.
+    pub fn supports_deferred_remove(&self) -> DmResult<bool> {
+        self.kernel_supports((4, 27, 0))
+    }
+
+    /// Whether the kernel supports `Self::arm_poll`.
+    pub fn supports_arm_poll(&self) -> DmResult<bool> {
+        self.kernel_supports((4, 37, 0))
+    }
+
+    /// Whether the kernel reports each device's event number in the list
+    /// returned by `Self::list_devices`.
+    pub fn supports_event_nr_in_list(&self) -> DmResult<bool> {
+        self.kernel_supports((4, 37, 0))
+    }
+
+    /// Whether the kernel is able to report each device's uuid when listing
+    /// devices, rather than requiring a separate query per device.
+    pub fn supports_uuid_in_list(&self) -> DmResult<bool> {
+        self.kernel_supports((4, 45, 0))
+    }
+
     /// Remove all DM devices and tables. Use discouraged other than
     /// for debugging.
     ///
@@ -236,6 +405,25 @@ impl DM {
         Ok(())
     }
 
+    /// Remove all DM devices and tables, as [`Self::remove_all`] does, but
+    /// refuse with an `InUse` error if any DM device has a mounted
+    /// filesystem or is an active swap area.
+    ///
+    /// Valid flags: `DM_DEFERRED_REMOVE`
+    pub fn remove_all_checked(&self, options: DmOptions) -> DmResult<()> {
+        for (name, device, _) in self.list_devices()? {
+            let in_use = device_in_use(self, device)?;
+            if !in_use.is_empty() {
+                return Err(DmError::Core(errors::Error::InUse(
+                    name.to_string(),
+                    in_use,
+                )));
+            }
+        }
+
+        self.remove_all(options)
+    }
+
     /// Returns a list of tuples containing DM device names, a Device, which
     /// holds their major and minor device numbers, and on kernels that
     /// support it, each device's last event_nr.
@@ -276,7 +464,10 @@ impl DM {
                 // drivers/md/dm-ioctl.c:list_devices
                 let event_nr = if event_nr_set {
                     // offsetof "name" in Struct_dm_name_list.
-                    let offset = align_to(name_offset + dm_name.len() + 1, size_of::<u64>());
+                    let offset = Bytes((name_offset + dm_name.len() + 1) as u128)
+                        .round_up_to(Bytes(size_of::<u64>() as u128))
+                        .expect("offset within the ioctl buffer does not overflow")
+                        .0 as usize;
                     let nr = u32::from_ne_bytes(
                         result[offset..offset + size_of::<u32>()]
                             .try_into()
@@ -306,8 +497,103 @@ impl DM {
         Ok(devs)
     }
 
+    /// List the devices with their event numbers, compare them with those
+    /// in `snapshot`, and update `snapshot`. Returns the devices which have
+    /// been added, removed, or have had an event since the snapshot was
+    /// taken. This is a cheap way to poll for changes for callers which can
+    /// not block in `Self::device_wait` or poll `Self::file`.
+    ///
+    /// On kernels which do not report event numbers when listing devices,
+    /// each device's event number is retrieved separately.
+    pub fn changed_since(&self, snapshot: &mut EventSnapshot) -> DmResult<DmChanges> {
+        let mut devices = Vec::new();
+        for (name, device, event_nr) in self.list_devices()? {
+            let event_nr = match event_nr {
+                Some(event_nr) => event_nr,
+                None => match self.device_info(&DevId::Name(&name)) {
+                    Ok(info) => info.event_nr(),
+                    Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                        if *err == errno::Errno::ENXIO =>
+                    {
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                },
+            };
+            devices.push((name, device, event_nr));
+        }
+        Ok(snapshot.update(devices))
+    }
+
+    /// Get the DeviceInfo of every DM device, keyed by the device's name.
+    /// The devices are listed, and then the info of each is retrieved,
+    /// reusing a single ioctl buffer for all of them. A device which is
+    /// removed after it has been listed is omitted.
+    pub fn devices_info(&self) -> DmResult<HashMap<DmNameBuf, DeviceInfo>> {
+        let names: Vec<DmNameBuf> = self
+            .list_devices()?
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        self.devices_info_of(&names)
+    }
+
+    /// Get the DeviceInfo of every DM device, as [`Self::devices_info`]
+    /// does, but divide the devices among up to `jobs` threads, each of
+    /// which retrieves the info of its share of the devices. This may be
+    /// quicker on a system with many devices.
+    pub fn devices_info_parallel(&self, jobs: usize) -> DmResult<HashMap<DmNameBuf, DeviceInfo>> {
+        let names: Vec<DmNameBuf> = self
+            .list_devices()?
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        if names.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let chunk_size = (names.len() + jobs.max(1) - 1) / jobs.max(1);
+        thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || self.devices_info_of(chunk)))
+                .collect();
+
+            let mut infos = HashMap::new();
+            for handle in handles {
+                infos.extend(handle.join().expect("thread does not panic")?);
+            }
+            Ok(infos)
+        })
+    }
+
+    fn devices_info_of(&self, names: &[DmNameBuf]) -> DmResult<HashMap<DmNameBuf, DeviceInfo>> {
+        let mut buffer = Vec::new();
+        let mut infos = HashMap::new();
+        for name in names {
+            let id = DevId::Name(name);
+            let mut hdr = DmOptions::default().to_ioctl_hdr(Some(&id), DmFlags::empty())?;
+            match self.do_ioctl_with_buffer(
+                dmi::DM_DEV_STATUS_CMD as u8,
+                &mut hdr,
+                None,
+                &mut buffer,
+            ) {
+                Ok((info, _)) => {
+                    infos.insert(name.clone(), info);
+                }
+                Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                    if *err == errno::Errno::ENXIO => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(infos)
+    }
+
     /// Create a DM device. It starts out in a "suspended" state.
     ///
+    /// To request a specific minor number, use `DmOptions::set_minor`.
+    ///
     /// Valid flags: `DM_READONLY`, `DM_PERSISTENT_DEV`
     ///
     /// # Example
@@ -336,8 +622,12 @@ impl DM {
         }
 
         debug!("Creating device {} (uuid={:?})", name, uuid);
-        self.do_ioctl(dmi::DM_DEV_CREATE_CMD as u8, &mut hdr, None)
-            .map(|(hdr, _)| hdr)
+        let info = self
+            .do_ioctl(dmi::DM_DEV_CREATE_CMD as u8, &mut hdr, None)
+            .map(|(hdr, _)| hdr)?;
+        #[cfg(test)]
+        crate::testing::record_device(name);
+        Ok(info)
     }
 
     fn try_device_remove(
@@ -378,18 +668,48 @@ impl DM {
     /// devices will succeed, and it will be removed when no longer
     /// used.
     ///
+    /// A device which is busy is retried a few times. If `options` has a
+    /// deadline, retrying stops once it has passed, and the method returns
+    /// a `DeadlineExceeded` error. The deadline bounds only the retries: it
+    /// is checked between attempts, so an attempt in progress, including
+    /// its wait for udev to process the removal, which is bounded by the
+    /// udev timeout of the `DmConfig`, may run past it.
+    ///
     /// Valid flags: `DM_DEFERRED_REMOVE`
     pub fn device_remove(&self, id: &DevId<'_>, options: DmOptions) -> DmResult<DeviceInfo> {
         debug!("Removing device {}", id);
-        match retry_with_index(
-            Fixed::from_millis(DM_REMOVE_MSLEEP_DELAY).take(DM_REMOVE_RETRIES - 1),
-            |i| {
-                debug!("Device remove attempt {} of {}", i, DM_REMOVE_RETRIES);
-                self.try_device_remove(id, options)
-            },
-        ) {
+        // If a deadline is set, shorten the last delay to end at the
+        // deadline, and stop retrying once it has passed.
+        let start = Instant::now();
+        let deadline_passed = Cell::new(false);
+        let retries = cmp::max(self.config.remove_retries(), 1);
+        let delays = Fixed::from(self.config.remove_retry_delay())
+            .take(retries - 1)
+            .map_while(|delay| match options.deadline() {
+                None => Some(delay),
+                Some(deadline) => match deadline.checked_sub(start.elapsed()) {
+                    Some(remaining) if !remaining.is_zero() => Some(cmp::min(delay, remaining)),
+                    _ => {
+                        deadline_passed.set(true);
+                        None
+                    }
+                },
+            });
+        match retry_with_index(delays, |i| {
+            debug!("Device remove attempt {} of {}", i, retries);
+            self.try_device_remove(id, options)
+        }) {
             Ok(deviceinfo) => Ok(deviceinfo),
             Err(err) => match err {
+                RetryError::Operation { tries, .. } if deadline_passed.get() => {
+                    Err(DmError::Core(errors::Error::DeadlineExceeded(
+                        id.to_string(),
+                        options
+                            .deadline()
+                            .expect("deadline_passed implies a deadline"),
+                        tries,
+                    )))
+                }
                 RetryError::Operation { error, .. } => Err(error),
                 _ => Err(DmError::Core(errors::Error::UdevSync(
                     "Error retrying ioctl".to_string(),
@@ -398,6 +718,24 @@ impl DM {
         }
     }
 
+    /// Remove a DM device and its mapping tables, as [`Self::device_remove`]
+    /// does, but refuse with an `InUse` error if the device, or any device
+    /// stacked above it, has a mounted filesystem or is an active swap area.
+    ///
+    /// Valid flags: `DM_DEFERRED_REMOVE`
+    pub fn device_remove_checked(
+        &self,
+        id: &DevId<'_>,
+        options: DmOptions,
+    ) -> DmResult<DeviceInfo> {
+        let in_use = device_in_use(self, self.device_info(id)?.device())?;
+        if !in_use.is_empty() {
+            return Err(DmError::Core(errors::Error::InUse(id.to_string(), in_use)));
+        }
+
+        self.device_remove(id, options)
+    }
+
     /// Change a DM device's name OR set the device's uuid for the first time.
     ///
     /// Prerequisite: if `new == DevId::Name(new_name)`, `old_name != new_name`
@@ -420,8 +758,76 @@ impl DM {
         Self::hdr_set_name(&mut hdr, old_name)?;
 
         debug!("Renaming device {} to {}", old_name, new);
-        self.do_ioctl(dmi::DM_DEV_RENAME_CMD as u8, &mut hdr, Some(&data_in))
-            .map(|(hdr, _)| hdr)
+        let info = self
+            .do_ioctl(dmi::DM_DEV_RENAME_CMD as u8, &mut hdr, Some(&data_in))
+            .map(|(hdr, _)| hdr)?;
+        #[cfg(test)]
+        if let DevId::Name(new_name) = *new {
+            crate::testing::record_rename(old_name, new_name);
+        }
+        Ok(info)
+    }
+
+    /// Change a DM device's name or set its uuid, as `Self::device_rename`,
+    /// and then reconcile the device's node in `/dev/mapper`, so that the
+    /// caller does not race against a stale node for the old name.
+    ///
+    /// If udev is running, wait at most `timeout` for it to remove
+    /// `/dev/mapper/<old_name>` and to create `/dev/mapper/<new_name>`.
+    /// Otherwise, rename the node directly. Nothing is done if the device
+    /// had no node under its old name, or if only its uuid was set.
+    pub fn device_rename_wait(
+        &self,
+        old_name: &DmName,
+        new: &DevId<'_>,
+        timeout: Duration,
+    ) -> DmResult<DeviceInfo> {
+        let old_path = Path::new(DM_DEV_DIR).join(old_name.to_string());
+        let had_node = fs::symlink_metadata(&old_path).is_ok();
+
+        let info = self.device_rename(old_name, new)?;
+
+        let new_name = match *new {
+            DevId::Name(name) if had_node => name,
+            _ => return Ok(info),
+        };
+        let new_path = Path::new(DM_DEV_DIR).join(new_name.to_string());
+        let node_moved =
+            || fs::symlink_metadata(&old_path).is_err() && fs::symlink_metadata(&new_path).is_ok();
+
+        if !Path::new(UDEV_CONTROL_PATH).exists() {
+            if !node_moved() {
+                debug!(
+                    "Renaming node {} to {}",
+                    old_path.display(),
+                    new_path.display()
+                );
+                fs::rename(&old_path, &new_path).map_err(|err| {
+                    DmError::Core(errors::Error::GeneralIo(format!(
+                        "failed to rename {} to {}: {}",
+                        old_path.display(),
+                        new_path.display(),
+                        err
+                    )))
+                })?;
+            }
+            return Ok(info);
+        }
+
+        let deadline = Instant::now() + timeout;
+        while !node_moved() {
+            if Instant::now() >= deadline {
+                let err_msg = format!(
+                    "udev did not move {} to {} within {:?}",
+                    old_path.display(),
+                    new_path.display(),
+                    timeout
+                );
+                return Err(DmError::Core(errors::Error::UdevSync(err_msg)));
+            }
+            thread::sleep(DM_RENAME_POLL_INTERVAL);
+        }
+        Ok(info)
     }
 
     /// Suspend or resume a DM device, depending on if `DM_SUSPEND` flag
@@ -463,6 +869,109 @@ impl DM {
             .map(|(hdr, _)| hdr)
     }
 
+    /// Suspend or resume a DM device, as `Self::device_suspend`, but wait at
+    /// most `timeout` for the ioctl to complete. A suspend may block
+    /// indefinitely, for example while a filesystem on the device is
+    /// frozen, or while IO is queued on a thin pool which is out of space.
+    ///
+    /// The ioctl is made on a separate thread, using a duplicate of this
+    /// context. If it does not complete in time, a `SuspendTimedOut` error
+    /// is returned, but the ioctl remains outstanding and the device may
+    /// become suspended later; the caller may need to resume it.
+    pub fn device_suspend_timeout(
+        &self,
+        id: &DevId<'_>,
+        options: DmOptions,
+        timeout: Duration,
+    ) -> DmResult<DeviceInfo> {
+        let dm = DM {
+            file: self
+                .file
+                .try_clone()
+                .map_err(|err| DmError::Core(errors::Error::ContextInit(err.to_string())))?,
+            config: self.config.clone(),
+            kernel_version: Mutex::new(None),
+        };
+        let (name, uuid) = match *id {
+            DevId::Name(name) => (Some(name.to_owned()), None),
+            DevId::Uuid(uuid) => (None, Some(uuid.to_owned())),
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let id = name
+                .as_deref()
+                .map(DevId::Name)
+                .or_else(|| uuid.as_deref().map(DevId::Uuid))
+                .expect("one of name or uuid is set");
+            // The receiver is gone if the suspend has timed out.
+            let _ = sender.send(dm.device_suspend(&id, options));
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                warn!("Suspend of device {} timed out after {:?}", id, timeout);
+                Err(DmError::Core(errors::Error::SuspendTimedOut(
+                    id.to_string(),
+                    timeout,
+                )))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("suspend of device {id} was abandoned"),
+            )),
+        }
+    }
+
+    /// Reload a device's current table and resume it, so that the targets
+    /// are constructed again from the same table. This picks up changes
+    /// in the devices beneath, such as a backing device which has grown.
+    ///
+    /// A read-only device stays read-only. Returns an error without
+    /// loading anything if the device already has an inactive table, since
+    /// resuming would activate that table instead. If the active table
+    /// changes between the time that it is read and the time that the
+    /// device is resumed, the reloaded table is cleared, unless it has
+    /// already been replaced by another one, and an error is returned.
+    pub fn device_refresh(&self, id: &DevId<'_>) -> DmResult<DeviceInfo> {
+        let table_options = DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE);
+        let (info, table) = self.table_status(id, table_options)?;
+        if table.is_empty() {
+            let err_msg = format!("device {id} has no active table to refresh");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        if info.flags().contains(DmFlags::DM_INACTIVE_PRESENT) {
+            let err_msg = format!(
+                "device {id} has an inactive table; it must be cleared or activated before the device is refreshed"
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let load_options = read_only_options(
+            &table,
+            DmOptions::default().set_flags(info.flags() & DmFlags::DM_READONLY),
+        )?;
+        self.table_load(id, &table, load_options)?;
+
+        let (_, current) = self.table_status(id, table_options)?;
+        if current != table {
+            let (_, inactive) = self.table_status(
+                id,
+                table_options
+                    .set_flags(DmFlags::DM_STATUS_TABLE | DmFlags::DM_QUERY_INACTIVE_TABLE),
+            )?;
+            if inactive == table {
+                self.table_clear(id)?;
+            }
+            let err_msg = format!("table of device {id} changed while it was being refreshed");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        self.device_suspend(id, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))?;
+        self.device_suspend(id, DmOptions::default())
+    }
+
     /// Get DeviceInfo for a device. This is also returned by other
     /// methods, but if just the DeviceInfo is desired then this just
     /// gets it.
@@ -474,6 +983,51 @@ impl DM {
             .map(|(hdr, _)| hdr)
     }
 
+    /// Wait until the device is no longer internally suspended, polling its
+    /// info, and return the info. Return an `InternallySuspended` error if
+    /// the device is still internally suspended after `timeout`.
+    ///
+    /// A thin pool internally suspends its thin devices while it is itself
+    /// suspended, and an operation on a thin device in that state may fail
+    /// in ways which do not make the cause clear.
+    pub fn wait_until_not_internally_suspended(
+        &self,
+        id: &DevId<'_>,
+        timeout: Duration,
+    ) -> DmResult<DeviceInfo> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let info = self.device_info(id)?;
+            if !info.is_internally_suspended() {
+                return Ok(info);
+            }
+            if Instant::now() >= deadline {
+                return Err(DmError::Core(errors::Error::InternallySuspended(
+                    id.to_string(),
+                    timeout,
+                )));
+            }
+            trace!("Device {} is internally suspended, waiting", id);
+            thread::sleep(DM_INTERNAL_SUSPEND_POLL_INTERVAL);
+        }
+    }
+
+    /// Check whether a device with the given name or uuid exists, with a
+    /// single status ioctl. The errors which the kernel returns for a
+    /// device which does not exist are mapped to false; all other errors
+    /// are returned.
+    pub fn device_exists(&self, id: &DevId<'_>) -> DmResult<bool> {
+        match self.device_info(id) {
+            Ok(_) => Ok(true),
+            Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                if *err == errno::Errno::ENXIO || *err == errno::Errno::ENODEV =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
     /// Wait for a device to report an event.
     ///
     /// Once an event occurs, this function behaves just like
@@ -492,11 +1046,25 @@ impl DM {
         debug!("Waiting on event for {}", id);
         let (hdr_out, data_out) = self.do_ioctl(dmi::DM_DEV_WAIT_CMD as u8, &mut hdr, None)?;
 
+        if inactive_query_dropped(options, &hdr_out) {
+            return Ok((hdr_out, Vec::new()));
+        }
         let status = DM::parse_table_status(hdr.target_count, &data_out)?;
 
         Ok((hdr_out, status))
     }
 
+    /// Wait until the event number of the device differs from `event_nr`,
+    /// i.e., until it reports an event, and return its info.
+    pub(crate) fn device_wait_event(&self, id: &DevId<'_>, event_nr: u32) -> DmResult<DeviceInfo> {
+        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty())?;
+        hdr.event_nr = event_nr;
+
+        trace!("Waiting on event after {} for {}", event_nr, id);
+        self.do_ioctl(dmi::DM_DEV_WAIT_CMD as u8, &mut hdr, None)
+            .map(|(hdr, _)| hdr)
+    }
+
     /// Load targets for a device into its inactive table slot.
     ///
     /// `targets` is an array of `(sector_start, sector_length, type, params)`.
@@ -551,8 +1119,10 @@ impl DM {
                 .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
 
             // Size of the largest single member of dm_target_spec
-            let align_to_size = size_of::<u64>();
-            let aligned_len = align_to(params.len() + 1usize, align_to_size);
+            let aligned_len = Bytes((params.len() + 1) as u128)
+                .round_up_to(Bytes(size_of::<u64>() as u128))
+                .expect("length of params does not overflow")
+                .0 as usize;
             targ.next = (size_of::<dmi::Struct_dm_target_spec>() + aligned_len) as u32;
 
             cursor
@@ -595,6 +1165,8 @@ impl DM {
     /// table for this device.
     ///
     /// If DM_QUERY_INACTIVE_TABLE is set, instead return for the
+    /// inactive table. If the kernel's DM ioctl interface is older than
+    /// 4.16, which introduced that flag, no devices are returned for the
     /// inactive table.
     ///
     /// Valid flags: DM_QUERY_INACTIVE_TABLE
@@ -602,9 +1174,9 @@ impl DM {
         let mut hdr = options.to_ioctl_hdr(Some(id), DmFlags::DM_QUERY_INACTIVE_TABLE)?;
 
         debug!("Querying dependencies for {}", id);
-        let (_, data_out) = self.do_ioctl(dmi::DM_TABLE_DEPS_CMD as u8, &mut hdr, None)?;
+        let (hdr_out, data_out) = self.do_ioctl(dmi::DM_TABLE_DEPS_CMD as u8, &mut hdr, None)?;
 
-        if data_out.is_empty() {
+        if data_out.is_empty() || inactive_query_dropped(options, &hdr_out) {
             Ok(vec![])
         } else {
             let result = &data_out[..];
@@ -626,6 +1198,67 @@ impl DM {
         }
     }
 
+    /// Find everything which holds the given device open: the DM devices
+    /// whose active or inactive tables refer to it, any other block devices
+    /// stacked on it, according to its sysfs holders directory, and any
+    /// filesystems mounted or swap areas activated on it. In ioctl-only mode,
+    /// only the DM devices are found.
+    ///
+    /// Block devices which are not DM devices may be queried with
+    /// `Self::holders_of_device`.
+    pub fn holders(&self, id: &DevId<'_>) -> DmResult<Vec<Holder>> {
+        self.holders_of_device(self.device_info(id)?.device())
+    }
+
+    /// Find everything which holds the block device with the given device
+    /// number open. See `Self::holders`.
+    pub fn holders_of_device(&self, device: Device) -> DmResult<Vec<Holder>> {
+        let mut result = Vec::new();
+        let mut dm_devices = Vec::new();
+        for (name, dm_device, _) in self.list_devices()? {
+            dm_devices.push(dm_device);
+            if dm_device == device {
+                continue;
+            }
+            let id = DevId::Name(&name);
+            let mut deps = Vec::new();
+            for options in [
+                DmOptions::default(),
+                DmOptions::default().set_flags(DmFlags::DM_QUERY_INACTIVE_TABLE),
+            ] {
+                match self.table_deps(&id, options) {
+                    Ok(table_deps) => deps.extend(table_deps),
+                    // The device was removed since it was listed.
+                    Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                        if *err == errno::Errno::ENXIO =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            if deps.contains(&device) {
+                result.push(Holder::Dm {
+                    name,
+                    device: dm_device,
+                });
+            }
+        }
+
+        // Other block devices, filesystems and swap areas can only be found
+        // in /proc and /sys.
+        if !self.config().ioctl_only() {
+            for holder in inuse::holders(self, device)? {
+                if !dm_devices.contains(&holder) {
+                    result.push(Holder::Block { device: holder });
+                }
+            }
+            result.extend(inuse::mounts(&[device])?.into_iter().map(Holder::Other));
+            result.extend(inuse::swaps(&[device])?.into_iter().map(Holder::Other));
+        }
+        Ok(result)
+    }
+
     /// Parse a device's table. The table value is in buf, count indicates the
     /// expected number of lines.
     /// Trims trailing white space off final entry on each line. This
@@ -680,7 +1313,10 @@ impl DM {
     /// targets with metadata will not cause a metadata write.
     ///
     /// If DM_QUERY_INACTIVE_TABLE is set, instead return the status of the
-    /// inactive table.
+    /// inactive table. If the kernel's DM ioctl interface is older than
+    /// 4.16, which introduced that flag, it is dropped and no targets are
+    /// returned; DM_QUERY_INACTIVE_TABLE is then not set in the flags of
+    /// the returned DeviceInfo.
     ///
     /// Valid flags: DM_NOFLUSH, DM_STATUS_TABLE, DM_QUERY_INACTIVE_TABLE
     ///
@@ -710,6 +1346,9 @@ impl DM {
         debug!("Retrieving table status for {}", id);
         let (hdr_out, data_out) = self.do_ioctl(dmi::DM_TABLE_STATUS_CMD as u8, &mut hdr, None)?;
 
+        if inactive_query_dropped(options, &hdr_out) {
+            return Ok((hdr_out, Vec::new()));
+        }
         let status = DM::parse_table_status(hdr_out.target_count, &data_out)?;
 
         Ok((hdr_out, status))
@@ -777,7 +1416,13 @@ impl DM {
         data_in.extend(msg.as_bytes());
         data_in.push(b'\0');
 
-        debug!("Sending target message \"{}\" to {}", msg, id);
+        // A crypt target's key may be set by message; keep it out of the log.
+        let logged = if msg.starts_with("key set ") {
+            "key set <redacted>"
+        } else {
+            msg
+        };
+        debug!("Sending target message \"{}\" to {}", logged, id);
         let (hdr_out, data_out) =
             self.do_ioctl(dmi::DM_TARGET_MSG_CMD as u8, &mut hdr, Some(&data_in))?;
 
@@ -817,13 +1462,21 @@ impl AsRawFd for DM {
     }
 }
 
+// Whether DM_QUERY_INACTIVE_TABLE was requested but dropped by do_ioctl
+// because the kernel does not support it, in which case the data returned
+// is that of the active table.
+fn inactive_query_dropped(options: DmOptions, info: &DeviceInfo) -> bool {
+    options.flags().contains(DmFlags::DM_QUERY_INACTIVE_TABLE)
+        && !info.flags().contains(DmFlags::DM_QUERY_INACTIVE_TABLE)
+}
+
 #[cfg(test)]
 mod tests {
 
     use crate::{
-        core::errors::Error,
+        core::{errors::Error, freeze::freeze_filesystems, types::DevIdBuf},
         result::DmError,
-        testing::{test_name, test_uuid},
+        testing::{test_name, test_uuid, TestGuard},
     };
 
     use super::*;
@@ -831,12 +1484,82 @@ mod tests {
     #[test]
     /// Test that some version can be obtained.
     fn sudo_test_version() {
+        let _guard = TestGuard::new();
         assert_matches!(DM::new().unwrap().version(), Ok(_));
     }
 
+    #[test]
+    /// Verify that the privileges of the tests, which run as root, suffice.
+    fn sudo_test_check_privileges() {
+        let _guard = TestGuard::new();
+        let report = DM::check_privileges();
+        assert_eq!(report.cap_sys_admin, Some(true));
+        assert!(report.check().is_ok());
+    }
+
+    #[test]
+    /// Test that the cached ioctl interface version is the kernel's and
+    /// that it is consistent with the supported features.
+    fn sudo_test_kernel_ioctl_version() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let version = dm.kernel_ioctl_version().unwrap();
+        assert_eq!(version, dm.version().unwrap());
+        assert_eq!(dm.kernel_ioctl_version().unwrap(), version);
+        assert_eq!(dm.supports_arm_poll().unwrap(), version >= (4, 37, 0));
+        if dm.supports_uuid_in_list().unwrap() {
+            assert!(dm.supports_deferred_remove().unwrap());
+        }
+    }
+
+    #[test]
+    /// Verify that a created, changed, and removed test device is reported
+    /// as such.
+    fn sudo_test_changed_since() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let mut snapshot = EventSnapshot::new();
+        dm.changed_since(&mut snapshot).unwrap();
+
+        let name = test_name("example-dev").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let changes = dm.changed_since(&mut snapshot).unwrap();
+        assert!(changes.added.contains(&name));
+        assert!(dm.changed_since(&mut snapshot).unwrap().is_empty());
+
+        let id = DevId::Name(&name);
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        // Renaming a device with an active table raises an event on it, so
+        // renaming it away and back changes its event number but not its
+        // name.
+        let other_name = test_name("example-dev-2").expect("is valid DM name");
+        dm.device_rename(&name, &DevId::Name(&other_name)).unwrap();
+        dm.device_rename(&other_name, &id).unwrap();
+        let event_nr = dm.device_info(&id).unwrap().event_nr();
+        assert_ne!(snapshot.event_nr(&name), Some(event_nr));
+        assert!(dm
+            .changed_since(&mut snapshot)
+            .unwrap()
+            .changed
+            .contains(&name));
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+        let changes = dm.changed_since(&mut snapshot).unwrap();
+        assert!(changes.removed.contains(&name));
+        assert_eq!(snapshot.event_nr(&name), None);
+    }
+
     #[test]
     /// Test that versions for some targets can be obtained.
     fn sudo_test_versions() {
+        let _guard = TestGuard::new();
         assert!(!DM::new().unwrap().list_versions().unwrap().is_empty());
     }
 
@@ -844,6 +1567,7 @@ mod tests {
     /// Verify that if no devices have been created the list of test devices
     /// is empty.
     fn sudo_test_list_devices_empty() {
+        let _guard = TestGuard::new();
         assert!(DM::new().unwrap().list_test_devices().unwrap().is_empty());
     }
 
@@ -851,6 +1575,7 @@ mod tests {
     /// Verify that if one test device has been created, it will be the only
     /// test device listed.
     fn sudo_test_list_devices() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         dm.device_create(&name, None, DmOptions::default()).unwrap();
@@ -869,9 +1594,39 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    /// Verify that the info of every test device is retrieved, both
+    /// serially and in parallel.
+    fn sudo_test_devices_info() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let names = [
+            test_name("example-dev-1").expect("is valid DM name"),
+            test_name("example-dev-2").expect("is valid DM name"),
+        ];
+        for name in &names {
+            dm.device_create(name, None, DmOptions::default()).unwrap();
+        }
+
+        let infos = dm.devices_info().unwrap();
+        for name in &names {
+            assert_eq!(infos[name].name(), Some(&**name));
+        }
+        let parallel_infos = dm.devices_info_parallel(2).unwrap();
+        for name in &names {
+            assert_eq!(parallel_infos[name].device(), infos[name].device());
+        }
+
+        for name in &names {
+            dm.device_remove(&DevId::Name(name), DmOptions::default())
+                .unwrap();
+        }
+    }
+
     #[test]
     /// Test that device creation gives a device with the expected name.
     fn sudo_test_create() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         let result = dm.device_create(&name, None, DmOptions::default()).unwrap();
@@ -883,9 +1638,60 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    /// Verify that a requested minor number is encoded in the header of a
+    /// create ioctl, and that one which is too large is rejected.
+    fn test_minor_hdr() {
+        let allowable = DmFlags::DM_READONLY | DmFlags::DM_PERSISTENT_DEV;
+        let hdr = DmOptions::default()
+            .set_minor(0x1_2345)
+            .to_ioctl_hdr(None, allowable)
+            .unwrap();
+        assert_eq!(
+            Device::from_kdev_t(hdr.dev as u32),
+            Device {
+                major: 0,
+                minor: 0x1_2345
+            }
+        );
+        assert!(DmFlags::from_bits_truncate(hdr.flags).contains(DmFlags::DM_PERSISTENT_DEV));
+
+        let hdr = DmOptions::default()
+            .set_minor(0x1_2345)
+            .to_ioctl_hdr(None, DmFlags::empty())
+            .unwrap();
+        assert_eq!(hdr.dev, 0);
+        assert_eq!(hdr.flags, 0);
+
+        assert_matches!(
+            DmOptions::default()
+                .set_minor(DM_MAX_MINOR + 1)
+                .to_ioctl_hdr(None, allowable),
+            Err(DmError::Core(Error::InvalidArgument(_)))
+        );
+    }
+
+    #[test]
+    /// Verify that a device created with a requested minor number has it.
+    fn sudo_test_create_minor() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let minor = DM_MAX_MINOR - 0x10;
+        let result = dm
+            .device_create(&name, None, DmOptions::default().set_minor(minor))
+            .unwrap();
+
+        assert_eq!(result.device().minor, minor);
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+    }
+
     #[test]
     /// Verify that creation with a UUID results in correct name and UUID.
     fn sudo_test_create_uuid() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
@@ -903,6 +1709,7 @@ mod tests {
     #[test]
     /// Verify that resetting uuid fails.
     fn sudo_test_rename_uuid() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
@@ -924,6 +1731,7 @@ mod tests {
     /// Verify that resetting uuid to same uuid fails.
     /// Since a device with that UUID already exists, the UUID can not be used.
     fn sudo_test_rename_uuid_id() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
@@ -942,6 +1750,7 @@ mod tests {
     /// Verify that setting a new uuid succeeds.
     /// Note that the uuid is not set in the returned dev_info.
     fn sudo_test_set_uuid() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         dm.device_create(&name, None, DmOptions::default()).unwrap();
@@ -962,6 +1771,7 @@ mod tests {
     /// Test that device rename to same name fails.
     /// Since a device with that name already exists, the name can not be used.
     fn sudo_test_rename_id() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         dm.device_create(&name, None, DmOptions::default()).unwrap();
@@ -980,6 +1790,7 @@ mod tests {
     /// Verify that the only test device in the list of devices is a device
     /// with the new name.
     fn sudo_test_rename() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         dm.device_create(&name, None, DmOptions::default()).unwrap();
@@ -1018,9 +1829,228 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    /// Verify that after a rename which waits for the device's node, the
+    /// node is found under the new name and not under the old one.
+    fn sudo_test_rename_wait() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        let new_name = test_name("example-dev-2").expect("is valid DM name");
+        dm.device_rename_wait(&name, &DevId::Name(&new_name), Duration::from_secs(10))
+            .unwrap();
+        assert!(!Path::new(DM_DEV_DIR).join(name.to_string()).exists());
+        assert!(Path::new(DM_DEV_DIR).join(new_name.to_string()).exists());
+
+        dm.device_remove(&DevId::Name(&new_name), DmOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    /// Verify that a device stacked on another is found as its holder, and
+    /// that a device with nothing stacked on it has no holders.
+    fn sudo_test_holders() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let lower = test_name("example-dev").expect("is valid DM name");
+        let lower_info = dm
+            .device_create(&lower, None, DmOptions::default())
+            .unwrap();
+        let lower_id = DevId::Name(&lower);
+        dm.table_load(
+            &lower_id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&lower_id, DmOptions::default()).unwrap();
+
+        let upper = test_name("example-dev-2").expect("is valid DM name");
+        let upper_info = dm
+            .device_create(&upper, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &DevId::Name(&upper),
+            &[(0, 1, "linear".into(), format!("{} 0", lower_info.device()))],
+            DmOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            dm.holders(&lower_id).unwrap(),
+            vec![Holder::Dm {
+                name: upper.clone(),
+                device: upper_info.device(),
+            }]
+        );
+        assert_eq!(dm.holders(&DevId::Name(&upper)).unwrap(), vec![]);
+
+        dm.device_remove(&DevId::Name(&upper), DmOptions::default())
+            .unwrap();
+        assert_eq!(dm.holders(&lower_id).unwrap(), vec![]);
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    /// Verify that, in ioctl-only mode, the devices stacked on a device
+    /// are found from the DM tables, that the stack is not in use, that no
+    /// filesystems are frozen, and that device numbers are parsed to names.
+    fn sudo_test_ioctl_only() {
+        let _guard = TestGuard::new();
+        let dm = DM::with_config(DmConfig::default().set_ioctl_only(true)).unwrap();
+        let lower = test_name("example-dev").expect("is valid DM name");
+        let lower_info = dm
+            .device_create(&lower, None, DmOptions::default())
+            .unwrap();
+        let lower_id = DevId::Name(&lower);
+        dm.table_load(
+            &lower_id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&lower_id, DmOptions::default()).unwrap();
+
+        let upper = test_name("example-dev-2").expect("is valid DM name");
+        let upper_info = dm
+            .device_create(&upper, None, DmOptions::default())
+            .unwrap();
+        let upper_id = DevId::Name(&upper);
+        dm.table_load(
+            &upper_id,
+            &[(0, 1, "linear".into(), format!("{} 0", lower_info.device()))],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&upper_id, DmOptions::default()).unwrap();
+
+        assert_eq!(
+            inuse::holders(&dm, lower_info.device()).unwrap(),
+            vec![upper_info.device()]
+        );
+        let stacked = inuse::stacked_devices(&dm, lower_info.device()).unwrap();
+        assert_eq!(stacked.len(), 2);
+        assert!(stacked.contains(&lower_info.device()));
+        assert!(stacked.contains(&upper_info.device()));
+        assert_eq!(
+            dm.holders(&lower_id).unwrap(),
+            vec![Holder::Dm {
+                name: upper.clone(),
+                device: upper_info.device(),
+            }]
+        );
+        assert_eq!(device_in_use(&dm, lower_info.device()).unwrap(), vec![]);
+        assert!(freeze_filesystems(&dm, lower_info.device())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            DevId::parse_with(&dm, &upper_info.device().to_string()).unwrap(),
+            DevIdBuf::Name(upper.clone())
+        );
+
+        dm.device_remove(&upper_id, DmOptions::default()).unwrap();
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    /// Verify that a device on which nothing is mounted can be removed by
+    /// the checked remove method.
+    fn sudo_test_remove_checked() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+
+        dm.device_remove_checked(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+        assert!(dm.list_test_devices().unwrap().is_empty());
+    }
+
+    #[test]
+    /// Verify that removing a device which is held open by another device
+    /// gives up once the deadline has passed, reporting the attempts made.
+    fn sudo_test_remove_deadline() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let lower = test_name("example-dev").expect("is valid DM name");
+        let lower_info = dm
+            .device_create(&lower, None, DmOptions::default())
+            .unwrap();
+        let lower_id = DevId::Name(&lower);
+        dm.table_load(
+            &lower_id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&lower_id, DmOptions::default()).unwrap();
+
+        let upper = test_name("example-dev-2").expect("is valid DM name");
+        dm.device_create(&upper, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &DevId::Name(&upper),
+            &[(0, 1, "linear".into(), format!("{} 0", lower_info.device()))],
+            DmOptions::default(),
+        )
+        .unwrap();
+
+        let retries = dm.config().remove_retries();
+        let delay = dm.config().remove_retry_delay();
+        let deadline = 2 * delay;
+        let start = Instant::now();
+        assert_matches!(
+            dm.device_remove(&lower_id, DmOptions::default().set_deadline(deadline)),
+            Err(DmError::Core(Error::DeadlineExceeded(_, d, tries))) if d == deadline && tries > 1 && tries < retries as u64
+        );
+        assert!(start.elapsed() < delay * retries as u32);
+
+        dm.device_remove(&DevId::Name(&upper), DmOptions::default())
+            .unwrap();
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    /// Verify that a device number, and the device node of a DM device,
+    /// are parsed to the name of the device.
+    fn sudo_test_dev_id_parse_device() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let info = dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        let expected = DevIdBuf::Name(name.clone());
+        assert_eq!(DevId::parse(&info.device().to_string()).unwrap(), expected);
+        assert_eq!(
+            DevId::parse(&format!("/dev/dm-{}", info.device().minor)).unwrap(),
+            expected
+        );
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
     #[test]
     /// Renaming a device that does not exist yields an error.
     fn sudo_test_rename_non_existant() {
+        let _guard = TestGuard::new();
         let new_name = test_name("new_name").expect("is valid DM name");
         assert_matches!(
             DM::new().unwrap().device_rename(
@@ -1034,6 +2064,7 @@ mod tests {
     #[test]
     /// Removing a device that does not exist yields an error.
     fn sudo_test_remove_non_existant() {
+        let _guard = TestGuard::new();
         assert_matches!(
             DM::new().unwrap().device_remove(
                 &DevId::Name(&test_name("junk").expect("is valid DM name")),
@@ -1046,6 +2077,7 @@ mod tests {
     #[test]
     /// A newly created device has no deps.
     fn sudo_test_empty_deps() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         dm.device_create(&name, None, DmOptions::default()).unwrap();
@@ -1061,6 +2093,7 @@ mod tests {
     #[test]
     /// Table status on a non-existant name should return an error.
     fn sudo_test_table_status_non_existant() {
+        let _guard = TestGuard::new();
         assert_matches!(
             DM::new().unwrap().table_status(
                 &DevId::Name(&test_name("junk").expect("is valid DM name")),
@@ -1073,6 +2106,7 @@ mod tests {
     #[test]
     /// Table status on a non-existant name with TABLE_STATUS flag errors.
     fn sudo_test_table_status_non_existant_table() {
+        let _guard = TestGuard::new();
         let name = test_name("junk").expect("is valid DM name");
         assert_matches!(
             DM::new().unwrap().table_status(
@@ -1089,6 +2123,7 @@ mod tests {
     /// be empty.
     /// The UUID of the returned info should be the device's UUID.
     fn sudo_test_table_status() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         let uuid = test_uuid("uuid").expect("is valid DM UUID");
@@ -1115,10 +2150,101 @@ mod tests {
         );
     }
 
+    #[test]
+    /// Verify that refreshing a device leaves it active with the same
+    /// table and still read-only, and that a device without a table or
+    /// with an inactive table can not be refreshed.
+    fn sudo_test_refresh() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let id = DevId::Name(&name);
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        assert_matches!(dm.device_refresh(&id), Err(_));
+
+        let table = vec![(0, 8, "zero".to_string(), String::new())];
+        let read_only = DmOptions::default().set_flags(DmFlags::DM_READONLY);
+        dm.table_load(&id, &table, read_only).unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        let info = dm.device_refresh(&id).unwrap();
+        assert!(info.flags().contains(DmFlags::DM_READONLY));
+        assert!(info.flags().contains(DmFlags::DM_ACTIVE_PRESENT));
+        assert!(!info.flags().contains(DmFlags::DM_INACTIVE_PRESENT));
+        assert!(!info.flags().contains(DmFlags::DM_SUSPEND));
+        assert_eq!(
+            dm.table_status(
+                &id,
+                DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE)
+            )
+            .unwrap()
+            .1,
+            table
+        );
+
+        dm.table_load(&id, &table, read_only).unwrap();
+        assert_matches!(dm.device_refresh(&id), Err(_));
+        assert!(dm
+            .device_info(&id)
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_INACTIVE_PRESENT));
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    /// Verify that a suspend and resume with a generous timeout complete.
+    fn sudo_test_suspend_timeout() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+
+        let id = DevId::Name(&name);
+        let timeout = Duration::from_secs(60);
+        let info = dm
+            .device_suspend_timeout(
+                &id,
+                DmOptions::default().set_flags(DmFlags::DM_SUSPEND),
+                timeout,
+            )
+            .unwrap();
+        assert!(info.flags().contains(DmFlags::DM_SUSPEND));
+        let info = dm
+            .device_suspend_timeout(&id, DmOptions::default(), timeout)
+            .unwrap();
+        assert!(!info.flags().contains(DmFlags::DM_SUSPEND));
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    /// Verify that a device is reported to exist by name and uuid exactly
+    /// while it exists.
+    fn sudo_test_device_exists() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
+        assert!(!dm.device_exists(&DevId::Name(&name)).unwrap());
+        assert!(!dm.device_exists(&DevId::Uuid(&uuid)).unwrap());
+
+        dm.device_create(&name, Some(&uuid), DmOptions::default())
+            .unwrap();
+        assert!(dm.device_exists(&DevId::Name(&name)).unwrap());
+        assert!(dm.device_exists(&DevId::Uuid(&uuid)).unwrap());
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+        assert!(!dm.device_exists(&DevId::Name(&name)).unwrap());
+    }
+
     #[test]
     /// Verify that creating a device with the same name twice fails.
     /// Verify that creating a device with the same uuid twice fails.
     fn sudo_test_double_creation() {
+        let _guard = TestGuard::new();
         let dm = DM::new().unwrap();
         let name = test_name("example-dev").expect("is valid DM name");
         let uuid = test_uuid("uuid").expect("is valid DM UUID");
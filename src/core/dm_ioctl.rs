@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::collections::HashMap;
+use std::{cmp, collections::HashMap};
 
 pub use devicemapper_sys::{
     dm_ioctl as Struct_dm_ioctl, dm_name_list as Struct_dm_name_list,
@@ -43,6 +43,24 @@ lazy_static! {
     ]);
 }
 
+// Map input flags which were added to the ioctl interface after 4.0.0 to
+// the version which introduced them. Older kernels ignore flags they do not
+// know, so a command using one of these flags must require that version.
+// The versions are those checked by libdm/ioctl/libdm-iface.c.
+const FLAG_VERSIONS: &[(u32, (u32, u32, u32))] = &[
+    (DM_QUERY_INACTIVE_TABLE_FLAG, (4, 16, 0)),
+    (DM_UUID_FLAG, (4, 19, 0)),
+    (DM_SECURE_DATA_FLAG, (4, 20, 0)),
+    (DM_DEFERRED_REMOVE, (4, 27, 0)),
+];
+
+// Input flags which may be dropped if the kernel does not support them.
+// Without DM_SECURE_DATA the kernel does not wipe its buffers after use.
+// Without DM_QUERY_INACTIVE_TABLE the kernel reports on the active table
+// instead, so the commands which take it discard the data they get back;
+// the kernel leaves the flag set in the output header only if it was sent.
+pub(crate) const OPTIONAL_FLAGS: u32 = DM_SECURE_DATA_FLAG | DM_QUERY_INACTIVE_TABLE_FLAG;
+
 // Map device-mapper ioctl commands to (major, minor, patchlevel)
 // tuple specifying the required kernel ioctl interface version.
 pub(crate) fn ioctl_to_version(ioctl: u8) -> (u32, u32, u32) {
@@ -53,3 +71,56 @@ pub(crate) fn ioctl_to_version(ioctl: u8) -> (u32, u32, u32) {
         unreachable!("Unknown device-mapper ioctl command: {}", ioctl);
     }
 }
+
+// The lowest kernel ioctl interface version which supports the given ioctl
+// command together with the given input flags.
+pub(crate) fn ioctl_flags_to_version(ioctl: u8, flags: u32) -> (u32, u32, u32) {
+    FLAG_VERSIONS
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, version)| *version)
+        .fold(ioctl_to_version(ioctl), cmp::max)
+}
+
+// The input flags among the given flags which the given kernel ioctl
+// interface version does not support.
+pub(crate) fn unsupported_flags(flags: u32, version: (u32, u32, u32)) -> u32 {
+    FLAG_VERSIONS
+        .iter()
+        .filter(|(flag, flag_version)| flags & flag != 0 && *flag_version > version)
+        .fold(0, |acc, (flag, _)| acc | flag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the required version is raised by flags introduced after
+    /// the command, and that unsupported flags are found.
+    fn test_flag_versions() {
+        assert_eq!(
+            ioctl_flags_to_version(DM_DEV_STATUS_CMD as u8, 0),
+            (4, 0, 0)
+        );
+        assert_eq!(
+            ioctl_flags_to_version(
+                DM_TABLE_STATUS_CMD as u8,
+                DM_QUERY_INACTIVE_TABLE_FLAG | DM_SECURE_DATA_FLAG
+            ),
+            (4, 20, 0)
+        );
+        assert_eq!(
+            unsupported_flags(
+                DM_QUERY_INACTIVE_TABLE_FLAG | DM_SECURE_DATA_FLAG | DM_DEFERRED_REMOVE,
+                (4, 19, 0)
+            ),
+            DM_SECURE_DATA_FLAG | DM_DEFERRED_REMOVE
+        );
+        assert_eq!(unsupported_flags(DM_NOFLUSH_FLAG, (4, 0, 0)), 0);
+        assert_eq!(
+            unsupported_flags(DM_QUERY_INACTIVE_TABLE_FLAG, (4, 15, 0)) & !OPTIONAL_FLAGS,
+            0
+        );
+    }
+}
@@ -1,6 +1,8 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use std::time::Duration;
+
 use crate::core::dm_flags::{DmFlags, DmUdevFlags};
 
 /// Encapsulates options for device mapper calls
@@ -8,6 +10,8 @@ use crate::core::dm_flags::{DmFlags, DmUdevFlags};
 pub struct DmOptions {
     flags: DmFlags,
     udev_flags: DmUdevFlags,
+    deadline: Option<Duration>,
+    minor: Option<u32>,
 }
 
 impl DmOptions {
@@ -25,6 +29,27 @@ impl DmOptions {
         self
     }
 
+    /// Set the overall time budget for an operation which retries, e.g.,
+    /// `DM::device_remove`, covering its retries and the delays between
+    /// them. If the budget is used up before the operation succeeds, it
+    /// fails with a `DeadlineExceeded` error. The budget is checked between
+    /// attempts, so a single attempt, including its wait for udev, may
+    /// overrun it. Replace the previous value.
+    /// Consumes self.
+    pub fn set_deadline(mut self, deadline: Duration) -> DmOptions {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Request a specific minor number for a device, setting
+    /// `DM_PERSISTENT_DEV`. Only `DM::device_create` uses the minor number;
+    /// it fails if the minor number is already in use or is larger than
+    /// the kernel allows. Replace the previous value. Consumes self.
+    pub fn set_minor(mut self, minor: u32) -> DmOptions {
+        self.minor = Some(minor);
+        self
+    }
+
     /// Retrieve the flags value
     pub fn flags(&self) -> DmFlags {
         self.flags
@@ -35,6 +60,16 @@ impl DmOptions {
         self.udev_flags
     }
 
+    /// Retrieve the overall time budget, if one has been set.
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    /// Retrieve the requested minor number, if one has been set.
+    pub fn minor(&self) -> Option<u32> {
+        self.minor
+    }
+
     /// Set default udev flags for a private (internal) device.
     pub fn private() -> DmOptions {
         DmOptions::default().set_udev_flags(
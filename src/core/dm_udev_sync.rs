@@ -2,10 +2,13 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::{core::dm_ioctl as dmi, result::DmResult};
+use crate::{
+    core::{dm_ioctl as dmi, DmConfig},
+    result::DmResult,
+};
 
 pub trait UdevSyncAction {
-    fn begin(hdr: &mut dmi::Struct_dm_ioctl, ioctl: u8) -> DmResult<UdevSync>;
+    fn begin(hdr: &mut dmi::Struct_dm_ioctl, ioctl: u8, config: &DmConfig) -> DmResult<UdevSync>;
     fn end(self, flags: u32) -> DmResult<()>;
     fn cancel(self);
     fn is_active(&self) -> bool;
@@ -20,6 +23,9 @@ pub mod sync_semaphore {
         semctl as libc_semctl,
         semget as libc_semget,
         semop as libc_semop,
+        time_t,
+        timespec,
+        EAGAIN,
         EEXIST,
         ENOMEM,
         ENOSPC,
@@ -33,14 +39,14 @@ pub mod sync_semaphore {
 
     use rand::Rng;
     use retry::{delay::NoDelay, retry, OperationResult};
-    use std::io;
+    use std::{io, time::Duration};
 
-    use crate::core::sysvsem::seminfo;
+    use crate::core::sysvsem::{seminfo, semtimedop};
 
     use crate::{
         core::dm_flags::{DmFlags, DmUdevFlags},
         core::sysvsem::{semun, GETVAL, SEM_INFO, SETVAL},
-        core::{dm_ioctl as dmi, errors},
+        core::{dm_ioctl as dmi, errors, DmConfig},
         result::{DmError, DmResult},
     };
 
@@ -167,6 +173,8 @@ pub mod sync_semaphore {
                 return Err(DmError::Core(errors::Error::UdevSync(err.to_string())));
             }
         };
+        #[cfg(test)]
+        crate::testing::record_semaphore(cookie, semid);
         let sem_arg: semun = semun { val: 1 };
         if let Err(err) = semctl(semid, 0, SETVAL, Some(sem_arg)) {
             error!("Failed to initialize udev notification semaphore: {}", err);
@@ -198,6 +206,8 @@ pub mod sync_semaphore {
             );
             return Err(DmError::Core(errors::Error::UdevSync(err.to_string())));
         };
+        #[cfg(test)]
+        crate::testing::forget_semaphore(semid);
         Ok(())
     }
 
@@ -247,8 +257,9 @@ pub mod sync_semaphore {
     ///
     /// This function blocks until the value of the first semaphore in the set
     /// identified by semid reaches zero (normally as a result of the dmsetup
-    /// udev_complete invoked at the end of udev rule processing).
-    fn notify_sem_wait(cookie: u32, semid: i32) -> DmResult<()> {
+    /// udev_complete invoked at the end of udev rule processing), or until
+    /// the timeout, if any, expires, in which case a warning is logged.
+    fn notify_sem_wait(cookie: u32, semid: i32, timeout: Option<Duration>) -> DmResult<()> {
         if let Err(err) = notify_sem_dec(cookie, semid) {
             error!(
                 concat!(
@@ -266,14 +277,35 @@ pub mod sync_semaphore {
             sem_op: 0,
             sem_flg: 0,
         };
-        let r = unsafe { libc_semop(semid, &mut sb, 1) };
+        let r = match timeout {
+            Some(timeout) => {
+                let ts = timespec {
+                    tv_sec: timeout.as_secs() as time_t,
+                    tv_nsec: timeout.subsec_nanos() as _,
+                };
+                unsafe { semtimedop(semid, &mut sb, 1, &ts) }
+            }
+            None => unsafe { libc_semop(semid, &mut sb, 1) },
+        };
         match r {
             i if i < 0 => {
-                error!(
-                    "Failed to wait on notification semaphore {} for cookie {}",
-                    semid, cookie
-                );
-                Err(DmError::udev_sync_error_from_os())
+                let err = io::Error::last_os_error();
+                match timeout {
+                    Some(timeout) if err.raw_os_error() == Some(EAGAIN) => {
+                        warn!(
+                            "Timed out after {:?} waiting on notification semaphore {} for cookie {}",
+                            timeout, semid, cookie
+                        );
+                        Ok(())
+                    }
+                    _ => {
+                        error!(
+                            "Failed to wait on notification semaphore {} for cookie {}",
+                            semid, cookie
+                        );
+                        Err(DmError::Core(errors::Error::UdevSync(err.to_string())))
+                    }
+                }
             }
             _ => Ok(()),
         }
@@ -283,6 +315,7 @@ pub mod sync_semaphore {
     pub struct UdevSync {
         cookie: u32,
         semid: Option<i32>,
+        timeout: Option<Duration>,
     }
 
     impl UdevSyncAction for UdevSync {
@@ -290,14 +323,18 @@ pub mod sync_semaphore {
         ///
         /// Allocate a SysV semaphore according to the device-mapper udev cookie
         /// protocol and set the initial state of the semaphore counter.
-        fn begin(hdr: &mut dmi::Struct_dm_ioctl, ioctl: u8) -> DmResult<Self> {
+        /// Nothing is allocated if udev sync is disabled in the config.
+        fn begin(hdr: &mut dmi::Struct_dm_ioctl, ioctl: u8, config: &DmConfig) -> DmResult<Self> {
             match ioctl as u32 {
                 dmi::DM_DEV_REMOVE_CMD | dmi::DM_DEV_RENAME_CMD | dmi::DM_DEV_SUSPEND_CMD
-                    if *SYSV_SEM_SUPPORTED && (hdr.flags & DmFlags::DM_SUSPEND.bits()) == 0 => {}
+                    if config.udev_sync()
+                        && *SYSV_SEM_SUPPORTED
+                        && (hdr.flags & DmFlags::DM_SUSPEND.bits()) == 0 => {}
                 _ => {
                     return Ok(UdevSync {
                         cookie: 0,
                         semid: None,
+                        timeout: None,
                     });
                 }
             };
@@ -328,6 +365,7 @@ pub mod sync_semaphore {
             Ok(UdevSync {
                 cookie: hdr.event_nr,
                 semid: Some(semid),
+                timeout: config.udev_timeout(),
             })
         }
 
@@ -348,7 +386,7 @@ pub mod sync_semaphore {
                     }
                 }
                 trace!("Waiting on {:?}", self);
-                notify_sem_wait(self.cookie, semid)?;
+                notify_sem_wait(self.cookie, semid, self.timeout)?;
                 trace!("Destroying {:?}", self);
                 if let Err(err) = notify_sem_destroy(self.cookie, semid) {
                     error!("Failed to clean up notification semaphore: {}", err);
@@ -407,7 +445,12 @@ pub mod sync_semaphore {
             let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
                 ..Default::default()
             };
-            let sync = UdevSync::begin(&mut hdr, dmi::DM_TABLE_STATUS_CMD as u8).unwrap();
+            let sync = UdevSync::begin(
+                &mut hdr,
+                dmi::DM_TABLE_STATUS_CMD as u8,
+                &DmConfig::default(),
+            )
+            .unwrap();
             assert_eq!(sync.cookie, 0);
             assert_eq!(sync.semid, None);
             assert_eq!(hdr.event_nr, 0);
@@ -419,7 +462,12 @@ pub mod sync_semaphore {
             let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
                 ..Default::default()
             };
-            let sync = UdevSync::begin(&mut hdr, dmi::DM_TABLE_STATUS_CMD as u8).unwrap();
+            let sync = UdevSync::begin(
+                &mut hdr,
+                dmi::DM_TABLE_STATUS_CMD as u8,
+                &DmConfig::default(),
+            )
+            .unwrap();
             assert_eq!(sync.cookie, 0);
             assert_eq!(sync.semid, None);
             assert_eq!(hdr.event_nr, 0);
@@ -431,7 +479,9 @@ pub mod sync_semaphore {
             let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
                 ..Default::default()
             };
-            let sync = UdevSync::begin(&mut hdr, dmi::DM_DEV_REMOVE_CMD as u8).unwrap();
+            let sync =
+                UdevSync::begin(&mut hdr, dmi::DM_DEV_REMOVE_CMD as u8, &DmConfig::default())
+                    .unwrap();
             assert_ne!((sync.cookie & !dmi::DM_UDEV_FLAGS_MASK), 0);
             assert!(sync.semid.unwrap() >= 0);
             assert!(notify_sem_dec(sync.cookie, sync.semid.unwrap()).is_ok());
@@ -448,7 +498,9 @@ pub mod sync_semaphore {
             let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
                 ..Default::default()
             };
-            let sync = UdevSync::begin(&mut hdr, dmi::DM_DEV_REMOVE_CMD as u8).unwrap();
+            let sync =
+                UdevSync::begin(&mut hdr, dmi::DM_DEV_REMOVE_CMD as u8, &DmConfig::default())
+                    .unwrap();
             assert_ne!((sync.cookie & !dmi::DM_UDEV_FLAGS_MASK), 0);
             assert!(sync.semid.unwrap() >= 0);
             assert_eq!(
@@ -464,7 +516,9 @@ pub mod sync_semaphore {
             let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
                 ..Default::default()
             };
-            let sync = UdevSync::begin(&mut hdr, dmi::DM_DEV_REMOVE_CMD as u8).unwrap();
+            let sync =
+                UdevSync::begin(&mut hdr, dmi::DM_DEV_REMOVE_CMD as u8, &DmConfig::default())
+                    .unwrap();
             assert_ne!((sync.cookie & !dmi::DM_UDEV_FLAGS_MASK), 0);
             assert!(sync.semid.unwrap() >= 0);
             assert_eq!(
@@ -479,7 +533,10 @@ pub mod sync_semaphore {
 #[cfg(target_os = "android")]
 pub mod sync_noop {
     use super::UdevSyncAction;
-    use crate::{core::dm_ioctl as dmi, result::DmResult};
+    use crate::{
+        core::{dm_ioctl as dmi, DmConfig},
+        result::DmResult,
+    };
 
     #[derive(Debug)]
     pub struct UdevSync {
@@ -488,7 +545,7 @@ pub mod sync_noop {
     }
 
     impl UdevSyncAction for UdevSync {
-        fn begin(hdr: &mut dmi::Struct_dm_ioctl, ioctl: u8) -> DmResult<Self> {
+        fn begin(hdr: &mut dmi::Struct_dm_ioctl, ioctl: u8, _config: &DmConfig) -> DmResult<Self> {
             debug!("Created noop UdevSync {{ cookie: {}, semid: {} }}", 0, -1);
             Ok(UdevSync {
                 cookie: 0,
@@ -4,12 +4,18 @@
 
 /*! Definition for low level error class for core methods !*/
 
-use std::{self, path::PathBuf};
+use std::{self, path::PathBuf, time::Duration};
 
-use crate::core::deviceinfo::DeviceInfo;
+use semver::Version;
+
+use crate::core::{deviceinfo::DeviceInfo, inuse::InUse};
 
 #[derive(Clone, Debug)]
 /// Internal error for low-level devicemapper operations
+///
+/// Variants are added as new failures become distinguishable, so the enum
+/// is non-exhaustive; callers matching on it must have a wildcard arm.
+#[non_exhaustive]
 pub enum Error {
     /// An error returned on failure to create a devicemapper context
     ContextInit(String),
@@ -42,6 +48,46 @@ pub enum Error {
 
     /// An error synchronizing with udev
     UdevSync(String),
+
+    /// An error returned when an operation is refused because the device
+    /// it would affect is in use.
+    InUse(String, Vec<InUse>),
+
+    /// An error returned when a suspend of the given device did not
+    /// complete within the given time. The suspend may still complete
+    /// later.
+    SuspendTimedOut(String, Duration),
+
+    /// An error returned when the given device remained internally
+    /// suspended, e.g., by the thin pool it belongs to, for the given time.
+    InternallySuspended(String, Duration),
+
+    /// An error returned when a DM target is not available in the running
+    /// kernel, or its version does not satisfy a requirement. The fields are
+    /// the target, the version requirement, and the available version, if
+    /// any.
+    UnsupportedTarget(String, String, Option<Version>),
+
+    /// An error returned when the kernel's ioctl interface is older than
+    /// the version required by an ioctl command or one of its flags. The
+    /// fields are the command, the required version, and the kernel's
+    /// version.
+    UnsupportedIoctl(u8, Version, Version),
+
+    /// An error returned when the process lacks privileges which DM
+    /// requires. The field describes the missing privileges.
+    InsufficientPrivileges(String),
+
+    /// An error returned when an operation on the given device did not
+    /// succeed within the overall time budget set in its options. The
+    /// fields are the device, the budget, and the number of attempts made.
+    DeadlineExceeded(String, Duration, u64),
+
+    /// An error returned when the transaction id of the given thin pool is
+    /// not that which was expected, because the pool has been modified
+    /// concurrently. The fields are the pool, the expected transaction id,
+    /// and the actual transaction id.
+    TransactionIdMismatch(String, u64, u64),
 }
 
 impl std::fmt::Display for Error {
@@ -72,6 +118,47 @@ impl std::fmt::Display for Error {
             Error::UdevSync(err) => {
                 write!(f, "failed to perform udev sync operation: {}", err)
             }
+            Error::InUse(id, in_use) => write!(
+                f,
+                "device {} is in use: {}",
+                id,
+                in_use
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::SuspendTimedOut(id, timeout) => write!(
+                f,
+                "suspend of device {id} did not complete within {timeout:?}"
+            ),
+            Error::InternallySuspended(id, timeout) => write!(
+                f,
+                "device {id} remained internally suspended for {timeout:?}"
+            ),
+            Error::UnsupportedTarget(target, req, Some(version)) => write!(
+                f,
+                "DM target {target} has version {version}, but version {req} is required"
+            ),
+            Error::UnsupportedTarget(target, req, None) => write!(
+                f,
+                "DM target {target} is not available, but version {req} is required"
+            ),
+            Error::UnsupportedIoctl(ioctl, required, version) => write!(
+                f,
+                "DM ioctl command {ioctl} requires ioctl interface version {required}, but the kernel provides version {version}"
+            ),
+            Error::InsufficientPrivileges(report) => {
+                write!(f, "insufficient privileges for DM: {report}")
+            }
+            Error::DeadlineExceeded(id, deadline, attempts) => write!(
+                f,
+                "operation on device {id} did not succeed within {deadline:?}, after {attempts} attempts"
+            ),
+            Error::TransactionIdMismatch(id, expected, actual) => write!(
+                f,
+                "transaction id of thin pool {id} is {actual}, expected {expected}; the pool has been modified concurrently"
+            ),
         }
     }
 }
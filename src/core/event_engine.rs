@@ -0,0 +1,405 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Waiting for events on DM devices, using DM_DEV_ARM_POLL where the kernel
+// supports it, and otherwise a thread per device blocked in DM_DEV_WAIT.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(devicemapper437supported)]
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+#[cfg(devicemapper437supported)]
+use nix::poll::{poll, PollFd, PollFlags};
+
+#[cfg(devicemapper437supported)]
+use crate::core::events::EventSnapshot;
+use crate::{
+    core::{
+        config::DmConfig,
+        dm::DM,
+        dm_flags::DmFlags,
+        errors,
+        types::{DevId, DmName, DmNameBuf},
+    },
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+/// The time remaining until the deadline, or None if there is no deadline.
+fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+#[cfg(devicemapper437supported)]
+struct ArmPoll {
+    dm: DM,
+    snapshot: EventSnapshot,
+}
+
+#[cfg(devicemapper437supported)]
+impl ArmPoll {
+    fn new(dm: DM) -> DmResult<ArmPoll> {
+        let mut snapshot = EventSnapshot::new();
+        dm.arm_poll()?;
+        dm.changed_since(&mut snapshot)?;
+        Ok(ArmPoll { dm, snapshot })
+    }
+
+    fn wait(
+        &mut self,
+        watched: &HashSet<DmNameBuf>,
+        deadline: Option<Instant>,
+    ) -> DmResult<Vec<DmNameBuf>> {
+        loop {
+            let timeout = match remaining(deadline) {
+                None => -1,
+                Some(remaining) => remaining.as_millis().min(i32::MAX as u128) as i32,
+            };
+            let mut fds = [PollFd::new(self.dm.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, timeout) {
+                Ok(0) => return Ok(Vec::new()),
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => {
+                    return Err(DmError::Core(errors::Error::GeneralIo(format!(
+                        "failed to poll DM control node: {err}"
+                    ))))
+                }
+            }
+
+            // Rearm before looking for the changes, so that no event is
+            // missed.
+            self.dm.arm_poll()?;
+            let changes = self.dm.changed_since(&mut self.snapshot)?;
+            let events: Vec<DmNameBuf> = changes
+                .changed
+                .into_iter()
+                .chain(changes.removed)
+                .filter(|name| watched.contains(name))
+                .collect();
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+}
+
+struct DevWait {
+    config: DmConfig,
+    sender: Sender<DmNameBuf>,
+    receiver: Receiver<DmNameBuf>,
+    // A flag per watched device, set to stop its thread
+    stops: HashMap<DmNameBuf, Arc<AtomicBool>>,
+}
+
+impl DevWait {
+    fn new(config: DmConfig) -> DevWait {
+        let (sender, receiver) = mpsc::channel();
+        DevWait {
+            config,
+            sender,
+            receiver,
+            stops: HashMap::new(),
+        }
+    }
+
+    fn watch(&mut self, name: &DmName, event_nr: u32) -> DmResult<()> {
+        let dm = DM::with_config(self.config.clone())?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let sender = self.sender.clone();
+        let name = name.to_owned();
+        self.stops.insert(name.clone(), Arc::clone(&stop));
+
+        thread::spawn(move || {
+            let mut event_nr = event_nr;
+            loop {
+                let result = dm.device_wait_event(&DevId::Name(&name), event_nr);
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match result {
+                    Ok(info) => {
+                        event_nr = info.event_nr();
+                        if sender.send(name.clone()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                        if *err == Errno::ENXIO =>
+                    {
+                        // The device has been removed or renamed.
+                        let _ = sender.send(name.clone());
+                        break;
+                    }
+                    Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                        if *err == Errno::EINTR =>
+                    {
+                        continue
+                    }
+                    Err(err) => {
+                        warn!("Failed to wait for events on device {}: {}", name, err);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn unwatch(&mut self, name: &DmName) {
+        if let Some(stop) = self.stops.remove(name) {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn wait(
+        &mut self,
+        watched: &HashSet<DmNameBuf>,
+        deadline: Option<Instant>,
+    ) -> DmResult<Vec<DmNameBuf>> {
+        loop {
+            let first = match remaining(deadline) {
+                None => self.receiver.recv().ok(),
+                Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                    Ok(name) => Some(name),
+                    Err(RecvTimeoutError::Timeout) => return Ok(Vec::new()),
+                    Err(RecvTimeoutError::Disconnected) => None,
+                },
+            }
+            .expect("the engine holds a sender, so the channel is not disconnected");
+
+            let mut events: Vec<DmNameBuf> = Some(first)
+                .into_iter()
+                .chain(self.receiver.try_iter())
+                .filter(|name| watched.contains(name))
+                .collect();
+            events.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+            events.dedup();
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+}
+
+impl Drop for DevWait {
+    fn drop(&mut self) {
+        for stop in self.stops.values() {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+enum Backend {
+    #[cfg(devicemapper437supported)]
+    ArmPoll(ArmPoll),
+    DevWait(DevWait),
+}
+
+/// Waits for events on a set of watched DM devices.
+///
+/// Where the kernel supports `DM::arm_poll`, i.e., from DM ioctl interface
+/// version 4.37, the engine polls the DM control node, and compares the
+/// event numbers of the devices to find those which reported events, as
+/// described in the crate documentation. On older kernels, the engine
+/// starts a thread for each watched device, which waits for its events with
+/// the DM_DEV_WAIT ioctl. The API is the same in either case.
+///
+/// A thread waiting on a device in DM_DEV_WAIT holds a reference to it in
+/// the kernel, so only devices with an active table, whose removal wakes
+/// the thread, may be watched. A thread of a device which is no longer
+/// watched exits once the device next reports an event or is removed.
+///
+/// ```no_run
+/// use devicemapper::{DmEventEngine, DmName};
+///
+/// let mut engine = DmEventEngine::new().unwrap();
+/// engine.watch(DmName::new("pool").unwrap()).unwrap();
+/// loop {
+///     for name in engine.wait(None).unwrap() {
+///         println!("event on {name}");
+///     }
+/// }
+/// ```
+pub struct DmEventEngine {
+    dm: DM,
+    watched: HashSet<DmNameBuf>,
+    backend: Backend,
+}
+
+impl DmEventEngine {
+    /// Make an engine with the default configuration, which uses
+    /// `DM::arm_poll` if the kernel supports it.
+    pub fn new() -> DmResult<DmEventEngine> {
+        DmEventEngine::with_config(DmConfig::default())
+    }
+
+    /// Make an engine using the given configuration, which uses
+    /// `DM::arm_poll` if the kernel supports it.
+    pub fn with_config(config: DmConfig) -> DmResult<DmEventEngine> {
+        #[cfg(devicemapper437supported)]
+        {
+            let dm = DM::with_config(config.clone())?;
+            if dm.supports_arm_poll()? {
+                return Ok(DmEventEngine {
+                    dm: DM::with_config(config)?,
+                    watched: HashSet::new(),
+                    backend: Backend::ArmPoll(ArmPoll::new(dm)?),
+                });
+            }
+        }
+        DmEventEngine::with_dev_wait(config)
+    }
+
+    /// Make an engine using the given configuration, which uses DM_DEV_WAIT
+    /// whether or not the kernel supports `DM::arm_poll`.
+    pub fn with_dev_wait(config: DmConfig) -> DmResult<DmEventEngine> {
+        Ok(DmEventEngine {
+            dm: DM::with_config(config.clone())?,
+            watched: HashSet::new(),
+            backend: Backend::DevWait(DevWait::new(config)),
+        })
+    }
+
+    /// Whether the engine uses `DM::arm_poll`, rather than DM_DEV_WAIT.
+    pub fn uses_arm_poll(&self) -> bool {
+        match self.backend {
+            #[cfg(devicemapper437supported)]
+            Backend::ArmPoll(_) => true,
+            Backend::DevWait(_) => false,
+        }
+    }
+
+    /// Watch the named device for events. Watching a device which is
+    /// already watched has no effect. Return an error if the device does
+    /// not exist or has no active table.
+    pub fn watch(&mut self, name: &DmName) -> DmResult<()> {
+        if self.watched.contains(name) {
+            return Ok(());
+        }
+        let info = self.dm.device_info(&DevId::Name(name))?;
+        if !info.flags().contains(DmFlags::DM_ACTIVE_PRESENT) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("device {name} has no active table, so can not be watched"),
+            ));
+        }
+        match self.backend {
+            #[cfg(devicemapper437supported)]
+            Backend::ArmPoll(_) => {}
+            Backend::DevWait(ref mut dev_wait) => dev_wait.watch(name, info.event_nr())?,
+        }
+        self.watched.insert(name.to_owned());
+        Ok(())
+    }
+
+    /// Stop watching the named device.
+    pub fn unwatch(&mut self, name: &DmName) {
+        if self.watched.remove(name) {
+            match self.backend {
+                #[cfg(devicemapper437supported)]
+                Backend::ArmPoll(_) => {}
+                Backend::DevWait(ref mut dev_wait) => dev_wait.unwatch(name),
+            }
+        }
+    }
+
+    /// The names of the watched devices.
+    pub fn watched(&self) -> Vec<DmNameBuf> {
+        let mut watched: Vec<DmNameBuf> = self.watched.iter().cloned().collect();
+        watched.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        watched
+    }
+
+    /// Wait until some watched device reports an event, or is removed or
+    /// renamed, and return the names of the devices which did, or an empty
+    /// list if none did within `timeout`. If `timeout` is None, wait
+    /// indefinitely. A device which is removed or renamed is no longer
+    /// watched.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> DmResult<Vec<DmNameBuf>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let events = match self.backend {
+            #[cfg(devicemapper437supported)]
+            Backend::ArmPoll(ref mut arm_poll) => arm_poll.wait(&self.watched, deadline)?,
+            Backend::DevWait(ref mut dev_wait) => dev_wait.wait(&self.watched, deadline)?,
+        };
+        for name in &events {
+            if self.dm.device_info(&DevId::Name(name)).is_err() {
+                self.unwatch(name);
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        core::DmOptions,
+        testing::{test_name, TestGuard},
+    };
+
+    use super::*;
+
+    /// Verify that the engine reports the rename of a watched device, which
+    /// generates an event, and that the device is then no longer watched.
+    fn test_engine(mut engine: DmEventEngine) {
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let id = DevId::Name(&name);
+
+        assert_matches!(engine.watch(&name), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        engine.watch(&name).unwrap();
+        assert_eq!(engine.watched(), vec![name.clone()]);
+        assert_eq!(
+            engine.wait(Some(Duration::from_millis(100))).unwrap(),
+            vec![]
+        );
+
+        let new_name = test_name("example-dev-2").expect("is valid DM name");
+        dm.device_rename(&name, &DevId::Name(&new_name)).unwrap();
+        assert_eq!(
+            engine.wait(Some(Duration::from_secs(5))).unwrap(),
+            vec![name.clone()]
+        );
+        assert!(engine.watched().is_empty());
+
+        dm.device_remove(&DevId::Name(&new_name), DmOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn sudo_test_event_engine() {
+        let _guard = TestGuard::new();
+        test_engine(DmEventEngine::new().unwrap());
+    }
+
+    #[test]
+    fn sudo_test_event_engine_dev_wait() {
+        let _guard = TestGuard::new();
+        let engine = DmEventEngine::with_dev_wait(DmConfig::default()).unwrap();
+        assert!(!engine.uses_arm_poll());
+        test_engine(engine);
+    }
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Detection of changes to DM devices by comparing their event numbers.
+
+use std::collections::HashMap;
+
+use crate::core::{
+    device::Device,
+    types::{DmName, DmNameBuf},
+};
+
+/// The event numbers of the DM devices, as last seen by
+/// `DM::changed_since`. An empty snapshot, as made by `new`, has seen no
+/// devices, so the first call to `DM::changed_since` reports every device
+/// as added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventSnapshot {
+    devices: HashMap<DmNameBuf, (Device, u32)>,
+}
+
+impl EventSnapshot {
+    /// Make a new, empty snapshot.
+    pub fn new() -> EventSnapshot {
+        EventSnapshot::default()
+    }
+
+    /// The event number of the named device when it was last seen, if it
+    /// was seen.
+    pub fn event_nr(&self, name: &DmName) -> Option<u32> {
+        self.devices.get(name).map(|(_, event_nr)| *event_nr)
+    }
+
+    /// The number of devices in the snapshot.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Whether the snapshot contains no devices.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Replace the snapshot with the given devices and event numbers, and
+    /// return the differences from the previous snapshot. A device whose
+    /// device number has changed has been removed and recreated under the
+    /// same name, and is reported as both removed and added.
+    pub(crate) fn update(&mut self, devices: Vec<(DmNameBuf, Device, u32)>) -> DmChanges {
+        let mut previous = std::mem::take(&mut self.devices);
+        let mut changes = DmChanges::default();
+        for (name, device, event_nr) in devices {
+            match previous.remove(&name) {
+                None => changes.added.push(name.clone()),
+                Some((old_device, _)) if old_device != device => {
+                    changes.removed.push(name.clone());
+                    changes.added.push(name.clone());
+                }
+                Some((_, old_event_nr)) if old_event_nr != event_nr => {
+                    changes.changed.push(name.clone())
+                }
+                Some(_) => {}
+            }
+            self.devices.insert(name, (device, event_nr));
+        }
+        changes.removed.extend(previous.into_keys());
+        for names in [
+            &mut changes.added,
+            &mut changes.removed,
+            &mut changes.changed,
+        ] {
+            names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        }
+        changes
+    }
+}
+
+/// The differences between two observations of the DM devices, as returned
+/// by `DM::changed_since`. The names in each list are ordered.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DmChanges {
+    /// Devices which have been created
+    pub added: Vec<DmNameBuf>,
+    /// Devices which have been removed
+    pub removed: Vec<DmNameBuf>,
+    /// Devices whose event number has changed, e.g., because a table was
+    /// loaded, or a target raised an event
+    pub changed: Vec<DmNameBuf>,
+}
+
+impl DmChanges {
+    /// Whether nothing has changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> DmNameBuf {
+        DmNameBuf::new(name.to_string()).expect("is valid DM name")
+    }
+
+    fn device(minor: u32) -> Device {
+        Device { major: 253, minor }
+    }
+
+    #[test]
+    /// Verify that added, removed, recreated, and changed devices are
+    /// reported and that the snapshot is updated.
+    fn test_update() {
+        let mut snapshot = EventSnapshot::new();
+        let changes = snapshot.update(vec![
+            (name("b"), device(1), 0),
+            (name("a"), device(0), 0),
+            (name("c"), device(2), 0),
+        ]);
+        assert_eq!(changes.added, vec![name("a"), name("b"), name("c")]);
+        assert!(changes.removed.is_empty() && changes.changed.is_empty());
+
+        let changes = snapshot.update(vec![
+            (name("a"), device(0), 0),
+            (name("b"), device(1), 3),
+            (name("c"), device(4), 0),
+            (name("d"), device(5), 0),
+        ]);
+        assert_eq!(
+            changes,
+            DmChanges {
+                added: vec![name("c"), name("d")],
+                removed: vec![name("c")],
+                changed: vec![name("b")],
+            }
+        );
+        assert_eq!(snapshot.event_nr(&name("b")), Some(3));
+
+        let changes = snapshot.update(vec![(name("a"), device(0), 0)]);
+        assert_eq!(changes.removed, vec![name("b"), name("c"), name("d")]);
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot.update(vec![(name("a"), device(0), 0)]).is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Freezing and thawing of filesystems mounted on DM devices.
+
+use std::{
+    fs::File,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use nix::libc::c_int;
+
+use crate::{
+    core::{
+        device::Device,
+        dm::DM,
+        errors,
+        inuse::{mounts, stacked_devices, InUse},
+    },
+    result::{DmError, DmResult},
+};
+
+// Freeze a filesystem via FIFREEZE
+ioctl_readwrite!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    fifreeze,
+    b'X',
+    119,
+    c_int
+);
+
+// Thaw a filesystem via FITHAW
+ioctl_readwrite!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    fithaw,
+    b'X',
+    120,
+    c_int
+);
+
+/// A filesystem which has been frozen with FIFREEZE. The filesystem is
+/// thawed when this value is dropped, unless it was already frozen by
+/// someone else when it was frozen here, in which case it is left frozen.
+///
+/// Freezing the filesystems on a device and then suspending the device with
+/// `DM_SKIP_LOCKFS` gives a consistent view of the filesystem while
+/// allowing the caller to choose when the filesystem is frozen:
+///
+/// ```no_run
+/// use devicemapper::{freeze_filesystems, DevId, DmFlags, DmName, DmOptions, DM};
+///
+/// let dm = DM::new().unwrap();
+/// let name = DmName::new("example-dev").expect("is valid DM name");
+/// let id = DevId::Name(name);
+///
+/// let frozen = freeze_filesystems(&dm, dm.device_info(&id).unwrap().device()).unwrap();
+/// dm.device_suspend(
+///     &id,
+///     DmOptions::default().set_flags(DmFlags::DM_SUSPEND | DmFlags::DM_SKIP_LOCKFS),
+/// )
+/// .unwrap();
+/// // Take a snapshot, reload a table, ...
+/// dm.device_suspend(&id, DmOptions::default()).unwrap();
+/// drop(frozen);
+/// ```
+#[derive(Debug)]
+pub struct FrozenFs {
+    file: File,
+    mount_point: PathBuf,
+    already_frozen: bool,
+    thawed: bool,
+}
+
+impl FrozenFs {
+    /// Freeze the filesystem mounted at `mount_point`.
+    ///
+    /// If the filesystem is already frozen, this succeeds, but the
+    /// filesystem is not thawed when the result is thawed or dropped.
+    pub fn freeze(mount_point: &Path) -> DmResult<FrozenFs> {
+        let file = File::open(mount_point).map_err(|err| {
+            DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to open mount point {}: {}",
+                mount_point.display(),
+                err
+            )))
+        })?;
+
+        let mut arg: c_int = 0;
+        let already_frozen = match unsafe { fifreeze(file.as_raw_fd(), &mut arg) } {
+            Ok(_) => false,
+            Err(nix::errno::Errno::EBUSY) => {
+                warn!(
+                    "Filesystem at {} was already frozen, it will not be thawed",
+                    mount_point.display()
+                );
+                true
+            }
+            Err(err) => {
+                return Err(DmError::Core(errors::Error::GeneralIo(format!(
+                    "failed to freeze filesystem at {}: {}",
+                    mount_point.display(),
+                    err
+                ))))
+            }
+        };
+
+        debug!("Froze filesystem at {}", mount_point.display());
+        Ok(FrozenFs {
+            file,
+            mount_point: mount_point.to_owned(),
+            already_frozen,
+            thawed: false,
+        })
+    }
+
+    /// The mount point of the frozen filesystem.
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// Whether the filesystem was already frozen by someone else.
+    pub fn already_frozen(&self) -> bool {
+        self.already_frozen
+    }
+
+    fn do_thaw(&mut self) -> DmResult<()> {
+        if self.already_frozen || self.thawed {
+            return Ok(());
+        }
+        self.thawed = true;
+
+        let mut arg: c_int = 0;
+        match unsafe { fithaw(self.file.as_raw_fd(), &mut arg) } {
+            Ok(_) => {
+                debug!("Thawed filesystem at {}", self.mount_point.display());
+                Ok(())
+            }
+            // Someone else has thawed the filesystem already.
+            Err(nix::errno::Errno::EINVAL) => {
+                warn!(
+                    "Filesystem at {} was already thawed",
+                    self.mount_point.display()
+                );
+                Ok(())
+            }
+            Err(err) => Err(DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to thaw filesystem at {}: {}",
+                self.mount_point.display(),
+                err
+            )))),
+        }
+    }
+
+    /// Thaw the filesystem, reporting any error, rather than waiting for
+    /// the filesystem to be thawed on drop.
+    pub fn thaw(mut self) -> DmResult<()> {
+        self.do_thaw()
+    }
+}
+
+impl Drop for FrozenFs {
+    fn drop(&mut self) {
+        if let Err(err) = self.do_thaw() {
+            warn!("{}", err);
+        }
+    }
+}
+
+/// Freeze every filesystem mounted on the given device, or on any device
+/// stacked above it. If freezing any filesystem fails, the filesystems
+/// already frozen are thawed again and an error is returned.
+///
+/// In ioctl-only mode, the mounted filesystems can not be found, so none
+/// are frozen and an empty list is returned. The caller should then
+/// suspend the device without `DM_SKIP_LOCKFS`, so that the kernel freezes
+/// any filesystem mounted on it.
+pub fn freeze_filesystems(dm: &DM, device: Device) -> DmResult<Vec<FrozenFs>> {
+    if dm.config().ioctl_only() {
+        return Ok(Vec::new());
+    }
+
+    let mut frozen = Vec::new();
+    for in_use in mounts(&stacked_devices(dm, device)?)? {
+        if let InUse::Mounted { mount_point, .. } = in_use {
+            frozen.push(FrozenFs::freeze(&mount_point)?);
+        }
+    }
+    Ok(frozen)
+}
@@ -0,0 +1,398 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Detection of devices which are in use by some other part of the system.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{read_dir, read_to_string},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use nix::errno::Errno;
+
+use crate::{
+    blkdev::device_claimed,
+    core::{
+        device::{devnode_to_devno, Device, SYSFS_DEV_BLOCK_PATH},
+        dm::DM,
+        dm_options::DmOptions,
+        errors,
+        types::{DevId, DmNameBuf},
+    },
+    result::{DmError, DmResult},
+};
+
+/// Path to the mount information for the current process' mount namespace
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// Path to the list of active swap areas
+const SWAPS_PATH: &str = "/proc/swaps";
+
+/// A reason that a device is considered to be in use.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InUse {
+    /// A filesystem is mounted on the device.
+    Mounted {
+        /// The device the filesystem is on. This may be the device that was
+        /// checked or some device stacked above it.
+        device: Device,
+        /// The location at which the filesystem is mounted
+        mount_point: PathBuf,
+    },
+    /// The device is in use as an active swap area.
+    Swap {
+        /// The device used for swap. This may be the device that was
+        /// checked or some device stacked above it.
+        device: Device,
+        /// The path by which the swap area was activated
+        path: PathBuf,
+    },
+    /// Another block device, such as a DM device, is stacked on the device.
+    Held {
+        /// The device which is held
+        device: Device,
+        /// The device stacked above it
+        holder: Device,
+    },
+    /// The device is claimed exclusively by some user which could not be
+    /// identified.
+    Claimed {
+        /// The device which is claimed
+        device: Device,
+    },
+}
+
+impl fmt::Display for InUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InUse::Mounted {
+                device,
+                mount_point,
+            } => write!(f, "{} is mounted at {}", device, mount_point.display()),
+            InUse::Swap { device, path } => {
+                write!(f, "{} is in use as swap at {}", device, path.display())
+            }
+            InUse::Held { device, holder } => write!(f, "{device} is held by {holder}"),
+            InUse::Claimed { device } => {
+                write!(f, "{device} is claimed exclusively by an unknown user")
+            }
+        }
+    }
+}
+
+/// Something which holds a device open, as found by `DM::holders`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Holder {
+    /// A DM device whose active or inactive table refers to the device.
+    Dm {
+        /// The name of the DM device
+        name: DmNameBuf,
+        /// The DM device's device number
+        device: Device,
+    },
+    /// A block device, other than a DM device, which is stacked on the
+    /// device, such as an MD array.
+    Block {
+        /// The stacked device's device number
+        device: Device,
+    },
+    /// A user which is not a block device, such as a mounted filesystem or
+    /// an active swap area.
+    Other(InUse),
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Holder::Dm { name, device } => write!(f, "DM device {name} ({device})"),
+            Holder::Block { device } => write!(f, "block device {device}"),
+            Holder::Other(in_use) => write!(f, "{in_use}"),
+        }
+    }
+}
+
+/// Read the whole of a file in /proc or /sys into a string.
+fn read_file(path: &Path) -> DmResult<String> {
+    read_to_string(path).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to read {}: {}",
+            path.display(),
+            err
+        )))
+    })
+}
+
+/// Undo the octal escaping of whitespace and backslashes that the kernel
+/// applies to paths in /proc files such as mountinfo and swaps.
+fn unescape_octal(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'\\' && index + 3 < bytes.len() {
+            let escaped = &bytes[index + 1..index + 4];
+            if escaped.iter().all(|b| (b'0'..=b'7').contains(b)) {
+                result.push(
+                    escaped
+                        .iter()
+                        .fold(0u8, |acc, b| acc.wrapping_mul(8).wrapping_add(b - b'0')),
+                );
+                index += 4;
+                continue;
+            }
+        }
+        result.push(bytes[index]);
+        index += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Parse a single line of mountinfo, yielding the device number of the
+/// mounted filesystem and its mount point. The format of a line is:
+/// <mount id> <parent id> <major:minor> <root> <mount point> ...
+fn parse_mountinfo_line(line: &str) -> Option<(Device, PathBuf)> {
+    let mut fields = line.split(' ');
+    let device = fields.nth(2)?.parse::<Device>().ok()?;
+    let mount_point = fields.nth(1)?;
+    Some((device, PathBuf::from(unescape_octal(mount_point))))
+}
+
+/// Parse a single line of /proc/swaps, yielding the path of the swap area
+/// if it is a partition, i.e., a block device, rather than a file. The
+/// format of a line is:
+/// <filename> <type> <size> <used> <priority>
+fn parse_swaps_line(line: &str) -> Option<PathBuf> {
+    let mut fields = line.split_whitespace();
+    let path = fields.next()?;
+    if fields.next()? == "partition" {
+        Some(PathBuf::from(unescape_octal(path)))
+    } else {
+        None
+    }
+}
+
+/// The devices stacked directly above other devices: in ioctl-only mode,
+/// the DM devices, found once from the tables of all DM devices; otherwise,
+/// all the block devices, read from sysfs for each device as it is asked
+/// about.
+enum HolderMap {
+    Dm(HashMap<Device, Vec<Device>>),
+    Sysfs,
+}
+
+impl HolderMap {
+    fn new(dm: &DM) -> DmResult<HolderMap> {
+        if !dm.config().ioctl_only() {
+            return Ok(HolderMap::Sysfs);
+        }
+
+        let mut holders = HashMap::new();
+        for (name, dm_device, _) in dm.list_devices()? {
+            match dm.table_deps(&DevId::Name(&name), DmOptions::default()) {
+                Ok(deps) => {
+                    for dep in deps {
+                        holders.entry(dep).or_insert_with(Vec::new).push(dm_device);
+                    }
+                }
+                // The device was removed since it was listed.
+                Err(DmError::Core(errors::Error::Ioctl(_, _, _, err))) if *err == Errno::ENXIO => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(HolderMap::Dm(holders))
+    }
+
+    /// Get the devices which are stacked directly above the given device.
+    fn holders(&self, device: Device) -> DmResult<Vec<Device>> {
+        match self {
+            HolderMap::Dm(holders) => Ok(holders.get(&device).cloned().unwrap_or_default()),
+            HolderMap::Sysfs => sysfs_holders(device),
+        }
+    }
+
+    /// Get the given device and every device stacked above it, transitively.
+    fn stacked_devices(&self, device: Device) -> DmResult<Vec<Device>> {
+        let mut seen = HashSet::new();
+        let mut result = Vec::new();
+        let mut pending = vec![device];
+        while let Some(device) = pending.pop() {
+            if seen.insert(device) {
+                pending.extend(self.holders(device)?);
+                result.push(device);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Get the devices which are stacked directly above the given device, as
+/// recorded in the device's sysfs holders directory.
+fn sysfs_holders(device: Device) -> DmResult<Vec<Device>> {
+    let holders_path = [SYSFS_DEV_BLOCK_PATH, &device.to_string(), "holders"]
+        .iter()
+        .collect::<PathBuf>();
+    let entries = match read_dir(&holders_path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to read {}: {}",
+                holders_path.display(),
+                err
+            ))))
+        }
+    };
+
+    let mut result = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+        result.push(
+            read_file(&entry.path().join("dev"))?
+                .trim()
+                .parse::<Device>()?,
+        );
+    }
+    Ok(result)
+}
+
+/// Get the devices which are stacked directly above the given device, as
+/// recorded in the device's sysfs holders directory. In ioctl-only mode,
+/// only the DM devices stacked above the device are found, from the tables
+/// of the DM devices.
+pub(crate) fn holders(dm: &DM, device: Device) -> DmResult<Vec<Device>> {
+    HolderMap::new(dm)?.holders(device)
+}
+
+/// Get the given device and every device stacked above it, transitively.
+pub(crate) fn stacked_devices(dm: &DM, device: Device) -> DmResult<Vec<Device>> {
+    HolderMap::new(dm)?.stacked_devices(device)
+}
+
+/// Get all the mounts of filesystems on the given devices.
+/// This reads /proc, so it must not be called in ioctl-only mode.
+pub(crate) fn mounts(devices: &[Device]) -> DmResult<Vec<InUse>> {
+    Ok(read_file(Path::new(MOUNTINFO_PATH))?
+        .lines()
+        .filter_map(parse_mountinfo_line)
+        .filter(|(device, _)| devices.contains(device))
+        .map(|(device, mount_point)| InUse::Mounted {
+            device,
+            mount_point,
+        })
+        .collect())
+}
+
+/// Get all the active swap areas on the given devices.
+/// This reads /proc, so it must not be called in ioctl-only mode.
+pub(crate) fn swaps(devices: &[Device]) -> DmResult<Vec<InUse>> {
+    let mut result = Vec::new();
+    // The first line of the file is a header.
+    for path in read_file(Path::new(SWAPS_PATH))?
+        .lines()
+        .skip(1)
+        .filter_map(parse_swaps_line)
+    {
+        if let Some(device) = devnode_to_devno(&path)?.map(Device::from) {
+            if devices.contains(&device) {
+                result.push(InUse::Swap { device, path });
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Find all the ways in which the given device, or any device stacked above
+/// it, is in use. Returns an empty list if the device is not in use.
+///
+/// In ioctl-only mode, mounted filesystems and swap areas can not be
+/// identified; instead, each device at the top of the stack which is
+/// claimed exclusively is reported as `InUse::Claimed`.
+pub fn device_in_use(dm: &DM, device: Device) -> DmResult<Vec<InUse>> {
+    let holders = HolderMap::new(dm)?;
+    let devices = holders.stacked_devices(device)?;
+    if dm.config().ioctl_only() {
+        let mut result = Vec::new();
+        for device in devices {
+            if holders.holders(device)?.is_empty() && device_claimed(device)? {
+                result.push(InUse::Claimed { device });
+            }
+        }
+        return Ok(result);
+    }
+    let mut result = mounts(&devices)?;
+    result.extend(swaps(&devices)?);
+    Ok(result)
+}
+
+/// Find the users which may hold an exclusive claim on the given device:
+/// devices stacked directly above it, filesystems mounted on it, and swap
+/// areas activated on it. If none can be found, the device is reported as
+/// claimed by an unknown user. In ioctl-only mode, only DM devices stacked
+/// above the device can be found.
+pub(crate) fn claims(dm: &DM, device: Device) -> DmResult<Vec<InUse>> {
+    let mut result = holders(dm, device)?
+        .into_iter()
+        .map(|holder| InUse::Held { device, holder })
+        .collect::<Vec<_>>();
+    if !dm.config().ioctl_only() {
+        result.extend(mounts(&[device])?);
+        result.extend(swaps(&[device])?);
+    }
+    if result.is_empty() {
+        result.push(InUse::Claimed { device });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that escaped characters are restored and that other
+    /// characters are left alone.
+    fn test_unescape_octal() {
+        assert_eq!(unescape_octal("/mnt/a\\040b"), "/mnt/a b");
+        assert_eq!(unescape_octal("/mnt/a\\134b"), "/mnt/a\\b");
+        assert_eq!(unescape_octal("/mnt/ab\\"), "/mnt/ab\\");
+        assert_eq!(unescape_octal("/mnt/ab"), "/mnt/ab");
+    }
+
+    #[test]
+    /// Verify that a mountinfo line is parsed into the correct device and
+    /// mount point.
+    fn test_parse_mountinfo_line() {
+        assert_eq!(
+            parse_mountinfo_line(
+                "36 35 253:3 / /mnt/my\\040data rw,noatime master:1 - xfs /dev/dm-3 rw"
+            ),
+            Some((
+                Device {
+                    major: 253,
+                    minor: 3
+                },
+                PathBuf::from("/mnt/my data")
+            ))
+        );
+        assert_eq!(parse_mountinfo_line("36 35"), None);
+    }
+
+    #[test]
+    /// Verify that only partition entries in /proc/swaps are reported.
+    fn test_parse_swaps_line() {
+        assert_eq!(
+            parse_swaps_line("/dev/dm-1                               partition\t8388604\t0\t-2"),
+            Some(PathBuf::from("/dev/dm-1"))
+        );
+        assert_eq!(
+            parse_swaps_line(
+                "/swap\\040file                              file\t\t1048572\t\t0\t\t-3"
+            ),
+            None
+        );
+    }
+}
@@ -4,6 +4,9 @@
 
 //! Modules that support handling of devicemapper ioctls at a low-level.
 
+#[cfg(devicemapper41supported)]
+mod capabilities;
+mod config;
 mod device;
 mod deviceinfo;
 mod dm;
@@ -12,15 +15,39 @@ mod dm_ioctl;
 mod dm_options;
 mod dm_udev_sync;
 pub mod errors;
+mod event_engine;
+mod events;
+mod freeze;
+mod inuse;
+mod pool;
+mod privileges;
+mod registry;
+mod state;
 mod sysvsem;
 mod types;
 mod util;
 
+#[cfg(devicemapper41supported)]
+pub use self::capabilities::DmCapabilities;
+
 pub use self::{
+    config::DmConfig,
     device::{devnode_to_devno, Device},
     deviceinfo::DeviceInfo,
     dm::DM,
     dm_flags::{DmFlags, DmUdevFlags},
     dm_options::DmOptions,
-    types::{DevId, DmName, DmNameBuf, DmUuid, DmUuidBuf},
+    event_engine::DmEventEngine,
+    events::{DmChanges, EventSnapshot},
+    freeze::{freeze_filesystems, FrozenFs},
+    inuse::{device_in_use, Holder, InUse},
+    pool::{DmHandle, DmPool},
+    privileges::PrivilegeReport,
+    registry::{DmRegistry, DmRegistryEntry},
+    state::{DmDeviceState, DmState},
+    types::{DevId, DevIdBuf, DmName, DmNameBuf, DmUuid, DmUuidBuf, DmUuidPrefix},
+};
+
+pub(crate) use self::{
+    device::SYSFS_DEV_BLOCK_PATH, dm::DM_DEV_DIR, inuse::claims, util::redact_table_keys,
 };
@@ -0,0 +1,197 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    ops::Deref,
+    sync::{Condvar, Mutex, MutexGuard},
+};
+
+use crate::{
+    core::{config::DmConfig, dm::DM},
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+struct PoolState {
+    // Contexts which have been returned to the pool
+    idle: Vec<DM>,
+    // The number of contexts opened, whether idle or handed out
+    open: usize,
+}
+
+/// A bounded pool of DM contexts, each with its own file descriptor for the
+/// DM control node, for issuing ioctls from many threads at once.
+///
+/// Ioctls made through a single `DM` are serialized on its file descriptor.
+/// A thread takes a context from the pool with `DmPool::get`, which returns
+/// an idle context if there is one, opens a new one if fewer than the
+/// maximum are open, or else waits for one to be returned. The context is
+/// returned to the pool when the `DmHandle` is dropped.
+pub struct DmPool {
+    config: DmConfig,
+    max: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl DmPool {
+    /// Create a pool of at most `max` contexts, with the default
+    /// configuration. No context is opened until one is requested.
+    pub fn new(max: usize) -> DmResult<DmPool> {
+        DmPool::with_config(DmConfig::default(), max)
+    }
+
+    /// Create a pool of at most `max` contexts, using the given
+    /// configuration.
+    pub fn with_config(config: DmConfig, max: usize) -> DmResult<DmPool> {
+        if max == 0 {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                "a DM context pool must allow at least one context".to_string(),
+            ));
+        }
+        Ok(DmPool {
+            config,
+            max,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// The maximum number of contexts the pool opens.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state
+            .lock()
+            .expect("no thread panics while holding the lock")
+    }
+
+    /// Take a context from the pool, or None if every context is in use.
+    fn take(&self, state: &mut MutexGuard<'_, PoolState>) -> DmResult<Option<DM>> {
+        if let Some(dm) = state.idle.pop() {
+            return Ok(Some(dm));
+        }
+        if state.open < self.max {
+            let dm = DM::with_config(self.config.clone())?;
+            state.open += 1;
+            return Ok(Some(dm));
+        }
+        Ok(None)
+    }
+
+    /// Get a context, waiting for one to be returned to the pool if the
+    /// maximum number are in use.
+    pub fn get(&self) -> DmResult<DmHandle<'_>> {
+        let mut state = self.lock();
+        loop {
+            if let Some(dm) = self.take(&mut state)? {
+                return Ok(DmHandle {
+                    pool: self,
+                    dm: Some(dm),
+                });
+            }
+            state = self
+                .returned
+                .wait(state)
+                .expect("no thread panics while holding the lock");
+        }
+    }
+
+    /// Get a context, or None if the maximum number are in use.
+    pub fn try_get(&self) -> DmResult<Option<DmHandle<'_>>> {
+        let mut state = self.lock();
+        Ok(self.take(&mut state)?.map(|dm| DmHandle {
+            pool: self,
+            dm: Some(dm),
+        }))
+    }
+
+    /// The number of contexts which are open but not in use.
+    pub fn idle(&self) -> usize {
+        self.lock().idle.len()
+    }
+}
+
+/// A DM context taken from a `DmPool`, which is returned to the pool when
+/// the handle is dropped.
+pub struct DmHandle<'a> {
+    pool: &'a DmPool,
+    // Always Some until the handle is dropped
+    dm: Option<DM>,
+}
+
+impl Deref for DmHandle<'_> {
+    type Target = DM;
+
+    fn deref(&self) -> &DM {
+        self.dm.as_ref().expect("set until the handle is dropped")
+    }
+}
+
+impl Drop for DmHandle<'_> {
+    fn drop(&mut self) {
+        if let Some(dm) = self.dm.take() {
+            self.pool.lock().idle.push(dm);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::testing::TestGuard;
+
+    use super::*;
+
+    #[test]
+    /// Verify that a pool must allow some context.
+    fn test_empty_pool() {
+        assert_matches!(
+            DmPool::with_config(DmConfig::default(), 0).err(),
+            Some(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that the pool opens no more than the maximum number of
+    /// contexts, and reuses those which are returned.
+    fn sudo_test_pool_bounded() {
+        let _guard = TestGuard::new();
+        let pool = DmPool::new(2).unwrap();
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        assert!(pool.try_get().unwrap().is_none());
+        drop(first);
+        assert_eq!(pool.idle(), 1);
+        let third = pool.try_get().unwrap().unwrap();
+        assert_eq!(pool.idle(), 0);
+        drop(second);
+        drop(third);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    /// Verify that many threads can share a small pool.
+    fn sudo_test_pool_threads() {
+        let _guard = TestGuard::new();
+        let pool = DmPool::new(2).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        assert_matches!(pool.get().unwrap().version(), Ok(_));
+                    }
+                });
+            }
+        });
+        assert!(pool.idle() <= 2);
+    }
+}
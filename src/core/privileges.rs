@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Checking, before any DM operation is attempted, that the process has the
+// privileges which DM requires: CAP_SYS_ADMIN, without which the kernel
+// rejects every ioctl but DM_VERSION, and read-write access to the control
+// node.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+};
+
+use nix::{errno::Errno, libc};
+
+use crate::{core::errors, result::DmResult};
+
+/// The number of the CAP_SYS_ADMIN capability
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// The version of the capget interface which uses two data structs
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The effective capabilities of this process, as a bit set.
+fn effective_capabilities() -> Result<u64, Errno> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_capget,
+            &mut header as *mut CapUserHeader,
+            data.as_mut_ptr(),
+        )
+    };
+    Errno::result(res)?;
+    Ok(u64::from(data[0].effective) | u64::from(data[1].effective) << 32)
+}
+
+/// The privileges of this process which DM requires, as found by
+/// `DM::check_privileges`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrivilegeReport {
+    /// Whether the process has CAP_SYS_ADMIN in its effective set, or None
+    /// if its capabilities could not be determined
+    pub cap_sys_admin: Option<bool>,
+    /// The DM control node
+    pub control_path: PathBuf,
+    /// Why the control node could not be opened for reading and writing,
+    /// if it could not
+    pub control_error: Option<String>,
+}
+
+impl PrivilegeReport {
+    pub(crate) fn new(control_path: &Path) -> PrivilegeReport {
+        let cap_sys_admin = effective_capabilities()
+            .map(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+            .ok();
+        let control_error = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(control_path)
+            .err()
+            .map(|err| err.to_string());
+        PrivilegeReport {
+            cap_sys_admin,
+            control_path: control_path.to_owned(),
+            control_error,
+        }
+    }
+
+    /// Whether the process has all the privileges which DM requires.
+    pub fn is_sufficient(&self) -> bool {
+        self.cap_sys_admin == Some(true) && self.control_error.is_none()
+    }
+
+    /// Return an `InsufficientPrivileges` error describing the missing
+    /// privileges if the process does not have all that DM requires.
+    pub fn check(&self) -> DmResult<()> {
+        if self.is_sufficient() {
+            Ok(())
+        } else {
+            Err(errors::Error::InsufficientPrivileges(self.to_string()).into())
+        }
+    }
+}
+
+impl fmt::Display for PrivilegeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cap_sys_admin {
+            Some(true) => write!(f, "CAP_SYS_ADMIN: present")?,
+            Some(false) => write!(f, "CAP_SYS_ADMIN: missing, run as root or grant it")?,
+            None => write!(f, "CAP_SYS_ADMIN: unknown, capabilities could not be read")?,
+        }
+        match self.control_error {
+            None => write!(f, "; {}: accessible", self.control_path.display()),
+            Some(ref err) => write!(
+                f,
+                "; {}: not accessible for reading and writing: {}",
+                self.control_path.display(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that a control node which does not exist is reported as not
+    /// accessible, and that the report then fails the check.
+    fn test_missing_control_node() {
+        let report = PrivilegeReport::new(Path::new("/nonexistent/control"));
+        assert!(report.cap_sys_admin.is_some());
+        assert!(report.control_error.is_some());
+        assert!(!report.is_sufficient());
+        assert_matches!(
+            report.check(),
+            Err(crate::result::DmError::Core(
+                errors::Error::InsufficientPrivileges(_)
+            ))
+        );
+    }
+}
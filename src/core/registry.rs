@@ -0,0 +1,264 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// An in-process cache of the names, uuids, and device numbers of DM devices.
+
+use std::collections::HashMap;
+
+use crate::{
+    core::{
+        device::Device,
+        deviceinfo::DeviceInfo,
+        dm::DM,
+        types::{DevId, DmName, DmNameBuf, DmUuid, DmUuidBuf},
+    },
+    result::DmResult,
+};
+
+/// The identifiers of a single DM device, as recorded in a `DmRegistry`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmRegistryEntry {
+    name: DmNameBuf,
+    uuid: Option<DmUuidBuf>,
+    device: Device,
+    event_nr: Option<u32>,
+}
+
+impl DmRegistryEntry {
+    /// The device's name.
+    pub fn name(&self) -> &DmName {
+        &self.name
+    }
+
+    /// The device's devicemapper uuid, if it has one.
+    pub fn uuid(&self) -> Option<&DmUuid> {
+        self.uuid.as_deref()
+    }
+
+    /// The device's major and minor device numbers.
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    /// The device's event number when it was last recorded, if known.
+    pub fn event_nr(&self) -> Option<u32> {
+        self.event_nr
+    }
+}
+
+/// A cache of the mappings between the names, uuids, and device numbers of
+/// DM devices, so that repeated lookups do not each require an ioctl.
+///
+/// The registry is not updated automatically. It is filled by `refresh`,
+/// which lists the devices known to the kernel, and by `record`, which
+/// records the `DeviceInfo` returned by most DM operations. Callers which
+/// rename or remove devices must inform the registry with `rename` or
+/// `remove`, or `refresh` it afterwards.
+#[derive(Debug, Default)]
+pub struct DmRegistry {
+    entries: HashMap<DmNameBuf, DmRegistryEntry>,
+    uuids: HashMap<DmUuidBuf, DmNameBuf>,
+    devices: HashMap<Device, DmNameBuf>,
+}
+
+impl DmRegistry {
+    /// Make a new, empty registry.
+    pub fn new() -> DmRegistry {
+        DmRegistry::default()
+    }
+
+    /// Replace the contents of the registry with the devices currently
+    /// known to the kernel. The uuid of a device is fetched with an ioctl
+    /// only if the device was not already recorded with the same name and
+    /// device number.
+    pub fn refresh(&mut self, dm: &DM) -> DmResult<()> {
+        let mut old = std::mem::take(self);
+        for (name, device, event_nr) in dm.list_devices()? {
+            let uuid = match old.entries.remove(&name) {
+                Some(entry) if entry.device == device => entry.uuid,
+                _ => dm
+                    .device_info(&DevId::Name(&name))?
+                    .uuid()
+                    .map(|uuid| uuid.to_owned()),
+            };
+            self.insert(DmRegistryEntry {
+                name,
+                uuid,
+                device,
+                event_nr,
+            });
+        }
+        Ok(())
+    }
+
+    /// Record the identifiers in the given `DeviceInfo`, replacing any
+    /// entry with the same name. Information without a name is ignored.
+    ///
+    /// Note that the `DeviceInfo` returned by `DM::device_rename` holds the
+    /// previous identifiers, so `rename` should be used instead.
+    pub fn record(&mut self, info: &DeviceInfo) {
+        if let Some(name) = info.name() {
+            self.insert(DmRegistryEntry {
+                name: name.to_owned(),
+                uuid: info.uuid().map(|uuid| uuid.to_owned()),
+                device: info.device(),
+                event_nr: Some(info.event_nr()),
+            });
+        }
+    }
+
+    /// Record a rename of the device named `old_name`, made with the same
+    /// arguments as `DM::device_rename`.
+    pub fn rename(&mut self, old_name: &DmName, new: &DevId<'_>) {
+        if let Some(mut entry) = self.remove(old_name) {
+            match *new {
+                DevId::Name(name) => entry.name = name.to_owned(),
+                DevId::Uuid(uuid) => entry.uuid = Some(uuid.to_owned()),
+            }
+            self.insert(entry);
+        }
+    }
+
+    /// Forget the device with the given name, returning its entry if it
+    /// was recorded.
+    pub fn remove(&mut self, name: &DmName) -> Option<DmRegistryEntry> {
+        let entry = self.entries.remove(name)?;
+        if let Some(uuid) = &entry.uuid {
+            self.uuids.remove(uuid);
+        }
+        self.devices.remove(&entry.device);
+        Some(entry)
+    }
+
+    /// Forget all devices.
+    pub fn clear(&mut self) {
+        *self = DmRegistry::default();
+    }
+
+    /// Look up a device by name or uuid.
+    pub fn get(&self, id: &DevId<'_>) -> Option<&DmRegistryEntry> {
+        match *id {
+            DevId::Name(name) => self.entries.get(name),
+            DevId::Uuid(uuid) => self.by_uuid(uuid),
+        }
+    }
+
+    /// Look up a device by uuid.
+    pub fn by_uuid(&self, uuid: &DmUuid) -> Option<&DmRegistryEntry> {
+        self.uuids.get(uuid).and_then(|name| self.entries.get(name))
+    }
+
+    /// Look up a device by its device number.
+    pub fn by_device(&self, device: Device) -> Option<&DmRegistryEntry> {
+        self.devices
+            .get(&device)
+            .and_then(|name| self.entries.get(name))
+    }
+
+    /// All the recorded devices, in no particular order.
+    pub fn entries(&self) -> impl Iterator<Item = &DmRegistryEntry> {
+        self.entries.values()
+    }
+
+    fn insert(&mut self, entry: DmRegistryEntry) {
+        self.remove(&entry.name);
+        // A uuid or device number which has moved to a different name
+        // belongs to a device which has been removed or renamed.
+        if let Some(name) = entry.uuid.as_ref().and_then(|uuid| self.uuids.get(uuid)) {
+            let name = name.clone();
+            self.remove(&name);
+        }
+        if let Some(name) = self.devices.get(&entry.device) {
+            let name = name.clone();
+            self.remove(&name);
+        }
+
+        if let Some(uuid) = &entry.uuid {
+            self.uuids.insert(uuid.clone(), entry.name.clone());
+        }
+        self.devices.insert(entry.device, entry.name.clone());
+        self.entries.insert(entry.name.clone(), entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        core::DmOptions,
+        testing::{test_name, test_uuid, TestGuard},
+    };
+
+    use super::*;
+
+    fn entry(name: &str, uuid: Option<&str>, minor: u32) -> DmRegistryEntry {
+        DmRegistryEntry {
+            name: DmNameBuf::new(name.into()).unwrap(),
+            uuid: uuid.map(|uuid| DmUuidBuf::new(uuid.into()).unwrap()),
+            device: Device { major: 253, minor },
+            event_nr: None,
+        }
+    }
+
+    #[test]
+    /// Verify that lookups by each identifier agree, and that renames and
+    /// removals, including implicit ones, are reflected in all of them.
+    fn test_registry() {
+        let mut registry = DmRegistry::new();
+        registry.insert(entry("a", Some("uuid-a"), 0));
+        registry.insert(entry("b", None, 1));
+
+        let name_a = DmName::new("a").unwrap();
+        let uuid_a = DmUuid::new("uuid-a").unwrap();
+        let dev_0 = Device {
+            major: 253,
+            minor: 0,
+        };
+        assert_eq!(registry.by_uuid(uuid_a).unwrap().name(), name_a);
+        assert_eq!(registry.by_device(dev_0).unwrap().uuid(), Some(uuid_a));
+
+        let name_c = DmName::new("c").unwrap();
+        registry.rename(name_a, &DevId::Name(name_c));
+        assert_eq!(registry.get(&DevId::Name(name_a)), None);
+        assert_eq!(registry.get(&DevId::Uuid(uuid_a)).unwrap().name(), name_c);
+        assert_eq!(registry.by_device(dev_0).unwrap().name(), name_c);
+
+        let name_b = DmName::new("b").unwrap();
+        let uuid_b = DmUuid::new("uuid-b").unwrap();
+        registry.rename(name_b, &DevId::Uuid(uuid_b));
+        assert_eq!(registry.by_uuid(uuid_b).unwrap().name(), name_b);
+
+        // Device 0 was removed and its number reused.
+        registry.insert(entry("d", None, 0));
+        assert_eq!(registry.get(&DevId::Name(name_c)), None);
+        assert_eq!(registry.by_uuid(uuid_a), None);
+
+        assert!(registry.remove(name_b).is_some());
+        assert_eq!(registry.by_uuid(uuid_b), None);
+        assert_eq!(registry.entries().count(), 1);
+    }
+
+    #[test]
+    /// Verify that a refresh finds a newly created device by its uuid and
+    /// that a recorded device is forgotten once it has been removed.
+    fn sudo_test_registry_refresh() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
+
+        let mut registry = DmRegistry::new();
+        let info = dm
+            .device_create(&name, Some(&uuid), DmOptions::default())
+            .unwrap();
+        registry.refresh(&dm).unwrap();
+        let entry = registry.by_uuid(&uuid).unwrap();
+        assert_eq!(entry.name(), &*name);
+        assert_eq!(entry.device(), info.device());
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+        registry.refresh(&dm).unwrap();
+        assert_eq!(registry.by_uuid(&uuid), None);
+    }
+}
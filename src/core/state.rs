@@ -0,0 +1,503 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A serializable record of all DM devices and their tables.
+
+use std::collections::HashMap;
+
+use nix::errno;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{
+        device::Device,
+        deviceinfo::DeviceInfo,
+        dm::DM,
+        dm_flags::DmFlags,
+        dm_options::DmOptions,
+        errors,
+        types::{DevId, DmName, DmNameBuf, DmUuid, DmUuidBuf},
+        util::{has_redacted_key, redact_table_keys},
+    },
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+/// The state of a single DM device, as recorded by `DmState::capture`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmDeviceState {
+    name: DmNameBuf,
+    uuid: Option<DmUuidBuf>,
+    device: Device,
+    flags: u32,
+    table: Vec<(u64, u64, String, String)>,
+    deps: Vec<Device>,
+}
+
+impl DmDeviceState {
+    /// The device's name.
+    pub fn name(&self) -> &DmName {
+        &self.name
+    }
+
+    /// The device's devicemapper uuid, if it has one.
+    pub fn uuid(&self) -> Option<&DmUuid> {
+        self.uuid.as_deref()
+    }
+
+    /// The device's major and minor device numbers.
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    /// The device's flags, e.g., `DM_SUSPEND` and `DM_READONLY`.
+    pub fn flags(&self) -> DmFlags {
+        DmFlags::from_bits_truncate(self.flags)
+    }
+
+    /// The device's active table, as returned by `DM::table_status` with
+    /// the `DM_STATUS_TABLE` flag, with crypt keys redacted unless the
+    /// state was captured by `DmState::capture_with_keys`.
+    pub fn table(&self) -> &[(u64, u64, String, String)] {
+        &self.table
+    }
+
+    /// The devices referenced by the device's active table.
+    pub fn deps(&self) -> &[Device] {
+        &self.deps
+    }
+}
+
+/// A record of every DM device known to the kernel and its active table,
+/// which may be serialized, e.g., to be attached to a bug report or kept as
+/// a backup of the layout of the mappings.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmState {
+    devices: Vec<DmDeviceState>,
+}
+
+impl DmState {
+    /// Record the state of every DM device. Devices which are removed while
+    /// the state is being captured are omitted.
+    ///
+    /// The keys of crypt targets given as hex are redacted from the
+    /// recorded tables, so that the state may be shared safely; a state
+    /// with redacted keys can not be restored. Use `capture_with_keys` to
+    /// record the keys as well.
+    pub fn capture(dm: &DM) -> DmResult<DmState> {
+        DmState::capture_devices(dm, false)
+    }
+
+    /// Record the state of every DM device, as `capture` does, but keep the
+    /// keys of crypt targets in the recorded tables. The result must be
+    /// kept as secret as the keys themselves.
+    pub fn capture_with_keys(dm: &DM) -> DmResult<DmState> {
+        DmState::capture_devices(dm, true)
+    }
+
+    fn capture_devices(dm: &DM, show_keys: bool) -> DmResult<DmState> {
+        let mut devices = Vec::new();
+        for (name, _, _) in dm.list_devices()? {
+            match DmState::capture_device(dm, name, show_keys) {
+                Ok(device) => devices.push(device),
+                Err(DmError::Core(errors::Error::Ioctl(_, _, _, err)))
+                    if *err == errno::Errno::ENXIO =>
+                {
+                    debug!("Device removed while capturing DM state");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        devices.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+        Ok(DmState { devices })
+    }
+
+    fn capture_device(dm: &DM, name: DmNameBuf, show_keys: bool) -> DmResult<DmDeviceState> {
+        let id = DevId::Name(&name);
+        let (info, mut table) = dm.table_status(
+            &id,
+            DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+        )?;
+        if !show_keys {
+            redact_table_keys(&mut table);
+        }
+        let deps = dm.table_deps(&id, DmOptions::default())?;
+        Ok(DmDeviceState {
+            uuid: info.uuid().map(|uuid| uuid.to_owned()),
+            device: info.device(),
+            flags: info.flags().bits(),
+            table,
+            deps,
+            name,
+        })
+    }
+
+    /// Recreate the recorded devices which do not exist, load their tables,
+    /// and resume them. Devices are restored in dependency order, so that
+    /// the devices beneath a device are restored before it. Device numbers
+    /// in the recorded tables are replaced by those of the devices as they
+    /// exist now, since the kernel may assign a recreated device a
+    /// different minor number.
+    ///
+    /// Devices which already exist with a matching table are skipped. It is
+    /// an error if a device exists with a different table or uuid, or if
+    /// the key of a recorded crypt target was redacted. If any
+    /// device can not be restored, the devices already created are removed
+    /// and the error is returned.
+    ///
+    /// Returns the `DeviceInfo` of each device which was created.
+    pub fn restore(&self, dm: &DM) -> DmResult<Vec<DeviceInfo>> {
+        let mut created: Vec<DeviceInfo> = Vec::new();
+        let result = self.restore_devices(dm, &mut created);
+        if result.is_err() {
+            for name in created.iter().rev().filter_map(|info| info.name()) {
+                if let Err(err) = dm.device_remove(&DevId::Name(name), DmOptions::default()) {
+                    warn!(
+                        "Failed to remove partially restored device {}: {}",
+                        name, err
+                    );
+                }
+            }
+        }
+        result.map(|_| created)
+    }
+
+    fn restore_devices(&self, dm: &DM, created: &mut Vec<DeviceInfo>) -> DmResult<()> {
+        if let Some(device) = self
+            .devices
+            .iter()
+            .find(|device| has_redacted_key(&device.table))
+        {
+            let err_msg = format!(
+                "the crypt key of recorded device {} was redacted; the state must be captured with keys to be restored",
+                device.name
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let recorded = self
+            .devices
+            .iter()
+            .map(|device| device.device)
+            .collect::<Vec<_>>();
+        let mut devices: HashMap<Device, Device> = HashMap::new();
+        let mut pending = self.devices.iter().collect::<Vec<_>>();
+
+        while !pending.is_empty() {
+            let (ready, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|device| {
+                device
+                    .deps
+                    .iter()
+                    .all(|dep| !recorded.contains(dep) || devices.contains_key(dep))
+            });
+            if ready.is_empty() {
+                let err_msg = format!(
+                    "recorded devices {} depend on each other",
+                    waiting
+                        .iter()
+                        .map(|device| device.name.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+
+            for device in ready {
+                let id = DevId::Name(&device.name);
+                let table = remap_table(&device.table, &devices);
+                let current = if dm.device_exists(&id)? {
+                    let (info, current_table) = dm.table_status(
+                        &id,
+                        DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+                    )?;
+                    if info.uuid() != device.uuid() || current_table != table {
+                        let err_msg = format!(
+                            "device {} exists but does not match its recorded state",
+                            device.name
+                        );
+                        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+                    }
+                    debug!("Device {} already exists, skipping", device.name);
+                    info.device()
+                } else {
+                    let info =
+                        dm.device_create(&device.name, device.uuid(), DmOptions::default())?;
+                    created.push(info);
+                    if !table.is_empty() {
+                        let mut options = DmOptions::default();
+                        if device.flags().contains(DmFlags::DM_READONLY) {
+                            options = options.set_flags(DmFlags::DM_READONLY);
+                        }
+                        dm.table_load(&id, &table, options)?;
+                        dm.device_suspend(&id, DmOptions::default())?;
+                    }
+                    info.device()
+                };
+                devices.insert(device.device, current);
+            }
+            pending = waiting;
+        }
+        Ok(())
+    }
+
+    /// The recorded devices, ordered by name.
+    pub fn devices(&self) -> &[DmDeviceState] {
+        &self.devices
+    }
+
+    /// The recorded state of the device with the given name, if any.
+    pub fn device(&self, name: &DmName) -> Option<&DmDeviceState> {
+        self.devices.iter().find(|device| &*device.name == name)
+    }
+}
+
+/// Replace every device number in a table's params which is a key of
+/// `devices` with its value.
+fn remap_table(
+    table: &[(u64, u64, String, String)],
+    devices: &HashMap<Device, Device>,
+) -> Vec<(u64, u64, String, String)> {
+    table
+        .iter()
+        .map(|(start, length, target_type, params)| {
+            let params = params
+                .split(' ')
+                .map(|word| {
+                    match word
+                        .parse::<Device>()
+                        .ok()
+                        .and_then(|dev| devices.get(&dev))
+                    {
+                        Some(dev) => dev.to_string(),
+                        None => word.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            (*start, *length, target_type.clone(), params)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        core::util::REDACTED_KEY,
+        testing::{test_name, test_uuid, TestGuard},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that a captured device is recorded with its identifiers,
+    /// flags, and table, and that a removed device is not recorded.
+    fn sudo_test_capture() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
+        let info = dm
+            .device_create(&name, Some(&uuid), DmOptions::default())
+            .unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        let state = DmState::capture(&dm).unwrap();
+        let device = state.device(&name).unwrap();
+        assert_eq!(device.uuid(), Some(&*uuid));
+        assert_eq!(device.device(), info.device());
+        assert!(device.flags().contains(DmFlags::DM_ACTIVE_PRESENT));
+        assert!(!device.flags().contains(DmFlags::DM_SUSPEND));
+        assert_eq!(
+            device.table(),
+            &[(0, 1, "zero".to_string(), "".to_string())]
+        );
+        assert_eq!(device.deps(), &[]);
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+        assert_eq!(DmState::capture(&dm).unwrap().device(&name), None);
+    }
+
+    #[test]
+    /// Verify that device numbers in a table are replaced and that other
+    /// words are left alone.
+    fn test_remap_table() {
+        let devices = [(
+            Device {
+                major: 253,
+                minor: 1,
+            },
+            Device {
+                major: 253,
+                minor: 7,
+            },
+        )]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+        assert_eq!(
+            remap_table(
+                &[
+                    (0, 8, "linear".into(), "253:1 0".into()),
+                    (8, 8, "linear".into(), "8:16 2048".into()),
+                ],
+                &devices
+            ),
+            vec![
+                (0, 8, "linear".to_string(), "253:7 0".to_string()),
+                (8, 8, "linear".to_string(), "8:16 2048".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    /// Verify that only the hex keys of crypt targets are redacted.
+    fn test_redact_table_keys() {
+        let key = "0123456789abcdef".repeat(4);
+        let mut table = vec![
+            (
+                0,
+                8,
+                "crypt".into(),
+                format!("aes-xts-plain64 {key} 0 8:16 0"),
+            ),
+            (
+                8,
+                8,
+                "crypt".into(),
+                "aes-xts-plain64 :64:logon:vol 0 8:16 8".into(),
+            ),
+            (16, 8, "linear".into(), format!("8:16 {key}")),
+        ];
+        redact_table_keys(&mut table);
+        assert_eq!(
+            table,
+            vec![
+                (
+                    0,
+                    8,
+                    "crypt".to_string(),
+                    "aes-xts-plain64 <redacted> 0 8:16 0".to_string()
+                ),
+                (
+                    8,
+                    8,
+                    "crypt".to_string(),
+                    "aes-xts-plain64 :64:logon:vol 0 8:16 8".to_string()
+                ),
+                (16, 8, "linear".to_string(), format!("8:16 {key}")),
+            ]
+        );
+        assert!(has_redacted_key(&table));
+        assert!(!has_redacted_key(&table[1..]));
+    }
+
+    #[test]
+    /// Verify that the key of a crypt device is absent from a captured
+    /// state unless keys are captured explicitly, and that a state without
+    /// keys is not restored.
+    fn sudo_test_capture_crypt_key() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let lower = test_name("example-dev").expect("is valid DM name");
+        let lower_id = DevId::Name(&lower);
+        let lower_info = dm
+            .device_create(&lower, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &lower_id,
+            &[(0, 8, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&lower_id, DmOptions::default()).unwrap();
+
+        let key = "0123456789abcdef".repeat(4);
+        let upper = test_name("example-dev-2").expect("is valid DM name");
+        let upper_id = DevId::Name(&upper);
+        dm.device_create(&upper, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &upper_id,
+            &[(
+                0,
+                8,
+                "crypt".into(),
+                format!("aes-xts-plain64 {key} 0 {} 0", lower_info.device()),
+            )],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&upper_id, DmOptions::default()).unwrap();
+
+        let state = DmState::capture(&dm).unwrap();
+        let params = &state.device(&upper).unwrap().table()[0].3;
+        assert!(!params.contains(&key));
+        assert!(params.contains(REDACTED_KEY));
+
+        let state_with_keys = DmState::capture_with_keys(&dm).unwrap();
+        assert!(state_with_keys.device(&upper).unwrap().table()[0]
+            .3
+            .contains(&key));
+
+        dm.device_remove(&upper_id, DmOptions::default()).unwrap();
+        assert_matches!(state.restore(&dm), Err(_));
+        assert!(!dm.device_exists(&upper_id).unwrap());
+
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    /// Verify that a stack of removed devices is restored from its recorded
+    /// state, with the upper device's table referring to the new lower
+    /// device, and that restoring again changes nothing.
+    fn sudo_test_restore() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let lower = test_name("example-dev").expect("is valid DM name");
+        let lower_id = DevId::Name(&lower);
+        let lower_info = dm
+            .device_create(&lower, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &lower_id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&lower_id, DmOptions::default()).unwrap();
+
+        let upper = test_name("example-dev-2").expect("is valid DM name");
+        let upper_id = DevId::Name(&upper);
+        dm.device_create(&upper, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &upper_id,
+            &[(0, 1, "linear".into(), format!("{} 0", lower_info.device()))],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&upper_id, DmOptions::default()).unwrap();
+
+        let state = DmState::capture(&dm).unwrap();
+        dm.device_remove(&upper_id, DmOptions::default()).unwrap();
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+
+        let created = state.restore(&dm).unwrap();
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0].name(), Some(&*lower));
+        assert_eq!(
+            dm.table_deps(&upper_id, DmOptions::default()).unwrap(),
+            vec![created[0].device()]
+        );
+        assert!(state.restore(&dm).unwrap().is_empty());
+
+        dm.device_remove(&upper_id, DmOptions::default()).unwrap();
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+    }
+}
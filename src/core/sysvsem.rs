@@ -2,4 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use nix::libc;
+
 pub use devicemapper_sys::{semid_ds, seminfo, semun, GETVAL, SEM_INFO, SETVAL};
+
+#[cfg(not(target_os = "android"))]
+extern "C" {
+    /// semtimedop(2): semop(2), but failing with EAGAIN if the operation can
+    /// not be performed within `timeout`.
+    pub fn semtimedop(
+        semid: libc::c_int,
+        sops: *mut libc::sembuf,
+        nsops: libc::size_t,
+        timeout: *const libc::timespec,
+    ) -> libc::c_int;
+}
@@ -2,14 +2,21 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{fmt, ops::Deref};
+use std::{
+    fmt, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use crate::{
     core::{
+        device::{Device, SYSFS_DEV_BLOCK_PATH},
+        dm::{DM, DM_DEV_DIR},
         dm_ioctl::{DM_NAME_LEN, DM_UUID_LEN},
         errors,
     },
-    result::DmError,
+    result::{DmError, DmResult},
 };
 
 // Casts yield correct results since values generated by bindgen from
@@ -31,6 +38,82 @@ str_id!(DmName, DmNameBuf, DM_NAME_LEN_USIZE, err_func);
 // format.
 str_id!(DmUuid, DmUuidBuf, DM_UUID_LEN_USIZE, err_func);
 
+/// The subsystem prefix of a devicemapper uuid. udev rules, and tools such
+/// as lsblk, identify the owner of a DM device by the prefix of its uuid,
+/// which is separated from the rest of the uuid by a '-'.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DmUuidPrefix {
+    /// A logical volume managed by lvm2: "LVM"
+    Lvm,
+    /// A device managed by cryptsetup, with the kind of encryption, e.g.,
+    /// "LUKS2" or "PLAIN": "CRYPT-<kind>"
+    Crypt(String),
+    /// A multipath device: "mpath"
+    Mpath,
+    /// A device managed by Stratis: "stratis"
+    Stratis,
+    /// Any other prefix, e.g., "part1" for a kpartx partition. A head
+    /// made up only of hex digits is not a prefix, since it is the first
+    /// group of a plain RFC 4122 uuid.
+    Other(String),
+}
+
+impl fmt::Display for DmUuidPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmUuidPrefix::Lvm => write!(f, "LVM"),
+            DmUuidPrefix::Crypt(kind) => write!(f, "CRYPT-{kind}"),
+            DmUuidPrefix::Mpath => write!(f, "mpath"),
+            DmUuidPrefix::Stratis => write!(f, "stratis"),
+            DmUuidPrefix::Other(prefix) => write!(f, "{prefix}"),
+        }
+    }
+}
+
+impl DmUuid {
+    /// Split the uuid into its subsystem prefix and the remainder. Returns
+    /// None if the uuid has no prefix, which includes a plain RFC 4122
+    /// uuid.
+    pub fn split_prefix(&self) -> Option<(DmUuidPrefix, &str)> {
+        let (prefix, rest) = self.inner.split_once('-')?;
+        match prefix {
+            "LVM" => Some((DmUuidPrefix::Lvm, rest)),
+            "CRYPT" => {
+                let (kind, rest) = rest.split_once('-')?;
+                Some((DmUuidPrefix::Crypt(kind.to_owned()), rest))
+            }
+            "mpath" => Some((DmUuidPrefix::Mpath, rest)),
+            "stratis" => Some((DmUuidPrefix::Stratis, rest)),
+            _ if prefix.chars().all(|c| c.is_ascii_hexdigit()) => None,
+            _ => Some((DmUuidPrefix::Other(prefix.to_owned()), rest)),
+        }
+    }
+
+    /// The subsystem prefix of the uuid, if it has one.
+    pub fn prefix(&self) -> Option<DmUuidPrefix> {
+        self.split_prefix().map(|(prefix, _)| prefix)
+    }
+}
+
+impl DmUuidBuf {
+    /// Construct a uuid from a subsystem prefix, such as "CRYPT-LUKS2" or
+    /// "stratis", and a subsystem-specific id, separated by a '-'.
+    pub fn with_prefix(prefix: &str, id: &str) -> DmResult<DmUuidBuf> {
+        if prefix.chars().all(|c| c.is_ascii_hexdigit())
+            || prefix.starts_with('-')
+            || prefix.ends_with('-')
+        {
+            return Err(err_func(&format!(
+                "value {prefix} is not a valid uuid prefix"
+            )));
+        }
+        if id.is_empty() {
+            return Err(err_func("value has zero characters"));
+        }
+        DmUuidBuf::new(format!("{prefix}-{id}"))
+    }
+}
+
 /// Used as a parameter for functions that take either a Device name
 /// or a Device UUID.
 #[derive(Debug, PartialEq, Eq)]
@@ -49,3 +132,187 @@ impl<'a> fmt::Display for DevId<'a> {
         }
     }
 }
+
+impl<'a> DevId<'a> {
+    /// Parse a device identifier given by a user, e.g., on the command line
+    /// or in a configuration file. The forms accepted are, in order of
+    /// precedence:
+    ///
+    /// * "name:<name>": the device with the given name
+    /// * "uuid:<uuid>": the device with the given uuid
+    /// * "<major>:<minor>": the DM device with the given device number
+    /// * "/dev/mapper/<name>": the device with the given name
+    /// * any other absolute path: the DM device whose device node it is
+    /// * anything else: the device with the given name
+    ///
+    /// A name which could be mistaken for another form, e.g., "8:3", must
+    /// be given with the "name:" prefix. Device numbers and device nodes are
+    /// resolved to names through sysfs.
+    pub fn parse(s: &str) -> DmResult<DevIdBuf> {
+        s.parse::<DevIdBuf>()
+    }
+
+    /// Parse a device identifier as `Self::parse` does, but resolve device
+    /// numbers and device nodes to names with the given DM context, which,
+    /// in ioctl-only mode, finds them in the list of DM devices rather than
+    /// in sysfs.
+    pub fn parse_with(dm: &DM, s: &str) -> DmResult<DevIdBuf> {
+        if dm.config().ioctl_only() {
+            parse_dev_id(s, |device| {
+                dm.list_devices()?
+                    .into_iter()
+                    .find(|(_, dm_device, _)| *dm_device == device)
+                    .map(|(name, _, _)| name)
+                    .ok_or_else(|| err_func(&format!("{device} is not a DM device")))
+            })
+        } else {
+            s.parse::<DevIdBuf>()
+        }
+    }
+}
+
+/// An owned device identifier, as returned by `DevId::parse`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DevIdBuf {
+    /// The device's name
+    Name(DmNameBuf),
+    /// The device's devicemapper uuid
+    Uuid(DmUuidBuf),
+}
+
+impl DevIdBuf {
+    /// The identifier in the form taken by DM methods.
+    pub fn as_id(&self) -> DevId<'_> {
+        match self {
+            DevIdBuf::Name(name) => DevId::Name(name),
+            DevIdBuf::Uuid(uuid) => DevId::Uuid(uuid),
+        }
+    }
+}
+
+impl fmt::Display for DevIdBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DevIdBuf::Name(name) => write!(f, "{name}"),
+            DevIdBuf::Uuid(uuid) => write!(f, "{uuid}"),
+        }
+    }
+}
+
+/// The name of the DM device with the given device number, read from sysfs.
+fn dm_name_of_device(device: Device) -> DmResult<DmNameBuf> {
+    let path: PathBuf = [SYSFS_DEV_BLOCK_PATH, &device.to_string(), "dm", "name"]
+        .iter()
+        .collect();
+    match fs::read_to_string(&path) {
+        Ok(name) => DmNameBuf::new(name.trim_end().to_string()),
+        Err(_) => Err(err_func(&format!("{device} is not a DM device"))),
+    }
+}
+
+/// Parse a device identifier, as described at `DevId::parse`, resolving
+/// device numbers to the names of DM devices with `name_of`.
+fn parse_dev_id<F>(s: &str, name_of: F) -> DmResult<DevIdBuf>
+where
+    F: Fn(Device) -> DmResult<DmNameBuf>,
+{
+    if let Some(name) = s.strip_prefix("name:") {
+        return Ok(DevIdBuf::Name(DmNameBuf::new(name.to_string())?));
+    }
+    if let Some(uuid) = s.strip_prefix("uuid:") {
+        return Ok(DevIdBuf::Uuid(DmUuidBuf::new(uuid.to_string())?));
+    }
+    if let Some((major, minor)) = s.split_once(':') {
+        if let (Ok(major), Ok(minor)) = (major.parse::<u32>(), minor.parse::<u32>()) {
+            return Ok(DevIdBuf::Name(name_of(Device { major, minor })?));
+        }
+    }
+    let path = Path::new(s);
+    if path.is_absolute() {
+        if let Ok(name) = path.strip_prefix(DM_DEV_DIR) {
+            if name.components().count() == 1 && name != Path::new("control") {
+                return Ok(DevIdBuf::Name(DmNameBuf::new(
+                    name.to_string_lossy().into_owned(),
+                )?));
+            }
+        }
+        return Ok(DevIdBuf::Name(name_of(s.parse::<Device>()?)?));
+    }
+    Ok(DevIdBuf::Name(DmNameBuf::new(s.to_string())?))
+}
+
+impl FromStr for DevIdBuf {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<DevIdBuf> {
+        parse_dev_id(s, dm_name_of_device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that identifiers which need no lookup are parsed according
+    /// to their form.
+    fn test_dev_id_parse() {
+        assert_eq!(
+            DevId::parse("name:8:3").unwrap(),
+            DevIdBuf::Name(DmNameBuf::new("8:3".into()).unwrap())
+        );
+        assert_eq!(
+            DevId::parse("uuid:CRYPT-LUKS2-abc").unwrap(),
+            DevIdBuf::Uuid(DmUuidBuf::new("CRYPT-LUKS2-abc".into()).unwrap())
+        );
+        assert_eq!(
+            DevId::parse("/dev/mapper/foo").unwrap(),
+            DevIdBuf::Name(DmNameBuf::new("foo".into()).unwrap())
+        );
+        assert_eq!(
+            DevId::parse("foo").unwrap(),
+            DevIdBuf::Name(DmNameBuf::new("foo".into()).unwrap())
+        );
+        assert_eq!(
+            DevId::parse("uuid:abc").unwrap().as_id(),
+            DevId::Uuid(DmUuid::new("abc").unwrap())
+        );
+
+        assert!(DevId::parse("name:").is_err());
+        assert!(DevId::parse("/nonexistent/device").is_err());
+    }
+
+    #[test]
+    /// Verify that uuids constructed with a prefix are split into the same
+    /// prefix and id, and that uuids without a prefix have none.
+    fn test_uuid_prefix() {
+        let id = "0a4a7fa1-52a3-4d8b-9a1c-8e1b1bfc7c5e-luks";
+        for prefix in [
+            DmUuidPrefix::Lvm,
+            DmUuidPrefix::Crypt("LUKS2".into()),
+            DmUuidPrefix::Mpath,
+            DmUuidPrefix::Stratis,
+            DmUuidPrefix::Other("part1".into()),
+        ] {
+            let uuid = DmUuidBuf::with_prefix(&prefix.to_string(), id).unwrap();
+            assert_eq!(uuid.split_prefix(), Some((prefix, id)));
+        }
+
+        assert_eq!(DmUuid::new("nodashes").unwrap().prefix(), None);
+        assert_eq!(DmUuid::new("-leading").unwrap().prefix(), None);
+        assert_eq!(DmUuid::new("CRYPT-LUKS2").unwrap().prefix(), None);
+        assert_eq!(DmUuid::new(id).unwrap().prefix(), None);
+        assert_eq!(
+            DmUuid::new("0a4a7fa1-52a3-4d8b-9a1c-8e1b1bfc7c5e")
+                .unwrap()
+                .split_prefix(),
+            None
+        );
+
+        assert!(DmUuidBuf::with_prefix("", id).is_err());
+        assert!(DmUuidBuf::with_prefix("0a4a7fa1", id).is_err());
+        assert!(DmUuidBuf::with_prefix("LVM-", id).is_err());
+        assert!(DmUuidBuf::with_prefix("LVM", "").is_err());
+        assert!(DmUuidBuf::with_prefix("LVM", &"x".repeat(DM_UUID_LEN_USIZE)).is_err());
+    }
+}
@@ -6,16 +6,6 @@ use std::{mem::size_of, slice, str};
 
 use nix::libc::c_char;
 
-/// The smallest number divisible by `align_to` and at least `num`.
-/// Precondition: `align_to` is a power of 2.
-/// Precondition: `num` + `align_to` < usize::MAX + 1.
-#[inline]
-pub fn align_to(num: usize, align_to: usize) -> usize {
-    let agn = align_to - 1;
-
-    (num + agn) & !agn
-}
-
 /// Convert from a &[c_char] to a &[u8].
 pub fn byte_slice_from_c_str(c_str: &[c_char]) -> &[u8] {
     unsafe { slice::from_raw_parts(c_str as *const _ as *const u8, c_str.len()) }
@@ -48,3 +38,33 @@ pub fn slice_from_c_struct<T>(strct: &T) -> &[u8] {
 pub fn c_struct_from_slice<T>(slice: &[u8]) -> Option<&T> {
     unsafe { (slice as *const _ as *const T).as_ref() }
 }
+
+/// The word which replaces a crypt target's key in a table from which
+/// keys have been redacted.
+pub(crate) const REDACTED_KEY: &str = "<redacted>";
+
+/// Replace the key of every crypt target in `table` which is given as hex
+/// with `REDACTED_KEY`, as dmsetup does unless it is given `--showkeys`.
+/// A reference to a key in the kernel keyring, and an empty key, are left
+/// alone.
+pub(crate) fn redact_table_keys(table: &mut [(u64, u64, String, String)]) {
+    for (_, _, _, params) in table
+        .iter_mut()
+        .filter(|(_, _, target_type, _)| target_type == "crypt")
+    {
+        let mut words = params.split(' ').collect::<Vec<_>>();
+        match words.get_mut(1) {
+            Some(key) if !key.starts_with(':') && *key != "-" => *key = REDACTED_KEY,
+            _ => continue,
+        }
+        let redacted = words.join(" ");
+        *params = redacted;
+    }
+}
+
+/// Whether the key of any crypt target in `table` has been redacted.
+pub(crate) fn has_redacted_key(table: &[(u64, u64, String, String)]) -> bool {
+    table.iter().any(|(_, _, target_type, params)| {
+        target_type == "crypt" && params.split(' ').nth(1) == Some(REDACTED_KEY)
+    })
+}
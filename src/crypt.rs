@@ -0,0 +1,1639 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fmt, fs, str::FromStr};
+
+#[cfg(devicemapper41supported)]
+use semver::Version;
+
+#[cfg(devicemapper41supported)]
+use crate::core::DmCapabilities;
+use crate::{
+    blkdev::{check_signatures, device_topology},
+    core::{errors, DevId, Device, DmFlags, DmName, DmOptions, DmUuid, DM},
+    integrity::{IntegrityDevTargetTable, IntegrityLayout, IntegrityMode},
+    profiles::CryptProfile,
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{parse_device, parse_value, TargetLine, TargetParams, TargetTable, TargetTypeBuf},
+    stack::DeviceStack,
+    units::{Bytes, Sectors, SECTOR_SIZE},
+};
+
+const CRYPT_TARGET_NAME: &str = "crypt";
+
+/// The prefix of the optional param giving the encryption sector size
+const SECTOR_SIZE_PARAM: &str = "sector_size:";
+
+/// The prefix of the optional param giving the size and type of the
+/// integrity data stored with each sector
+const INTEGRITY_PARAM: &str = "integrity:";
+
+/// The optional param which makes the IV count in encryption sectors
+const IV_LARGE_SECTORS_PARAM: &str = "iv_large_sectors";
+
+/// The optional param which makes encryption happen on the CPU which
+/// submitted the I/O
+const SAME_CPU_CRYPT_PARAM: &str = "same_cpu_crypt";
+/// The optional param which makes writes be submitted from the encryption
+/// threads, rather than sorted by a single thread
+const SUBMIT_FROM_CRYPT_CPUS_PARAM: &str = "submit_from_crypt_cpus";
+/// The optional param which makes reads be decrypted synchronously, without
+/// a workqueue
+const NO_READ_WORKQUEUE_PARAM: &str = "no_read_workqueue";
+/// The optional param which makes writes be encrypted synchronously,
+/// without a workqueue
+const NO_WRITE_WORKQUEUE_PARAM: &str = "no_write_workqueue";
+
+/// The message which sets the key of a suspended crypt target
+const KEY_SET_MSG: &str = "key set";
+/// The message which wipes the key of a suspended crypt target from memory
+const KEY_WIPE_MSG: &str = "key wipe";
+
+/// The list of the algorithms of the kernel crypto API
+const PROC_CRYPTO: &str = "/proc/crypto";
+
+/// The prefix of a cipher specification given in the kernel crypto API's
+/// own syntax
+const CAPI_PREFIX: &str = "capi:";
+
+/// The IV generators of the crypt target, each with whether it requires,
+/// permits or forbids options
+const IV_MODES: &[(&str, IvOpts)] = &[
+    ("plain", IvOpts::Forbidden),
+    ("plain64", IvOpts::Forbidden),
+    ("plain64be", IvOpts::Forbidden),
+    ("essiv", IvOpts::Required),
+    ("benbi", IvOpts::Forbidden),
+    ("null", IvOpts::Forbidden),
+    ("lmk", IvOpts::Forbidden),
+    ("tcw", IvOpts::Forbidden),
+    ("random", IvOpts::Forbidden),
+    ("eboiv", IvOpts::Forbidden),
+    ("elephant", IvOpts::Required),
+];
+
+/// Whether an IV generator takes options, e.g., the hash of essiv.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum IvOpts {
+    Required,
+    Forbidden,
+}
+
+/// The largest encryption sector size the kernel accepts
+const MAX_CRYPT_SECTOR_SIZE: Bytes = Bytes(4096);
+
+/// The integrity data which a crypt target stores for each sector on the
+/// integrity device below it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptIntegrity {
+    /// The bytes of integrity data for each sector: the authentication tag
+    /// and, for a random IV, the IV
+    pub tag_size: u64,
+    /// "aead" if the cipher is an AEAD cipher, otherwise the name of the
+    /// authentication algorithm, or "none"
+    pub integrity_type: String,
+}
+
+impl CryptIntegrity {
+    /// Create a new CryptIntegrity struct
+    pub fn new(tag_size: u64, integrity_type: String) -> CryptIntegrity {
+        CryptIntegrity {
+            tag_size,
+            integrity_type,
+        }
+    }
+}
+
+/// Target params for crypt target
+#[derive(Clone, Eq, PartialEq)]
+pub struct CryptTargetParams {
+    /// The cipher specification, e.g., "aes-xts-plain64"
+    pub cipher: String,
+    /// The key, as hex, or a reference to a key in the kernel keyring of
+    /// the form ":<key size>:<key type>:<key description>"
+    pub key: String,
+    /// The offset added to the sector number when computing the IV
+    pub iv_offset: u64,
+    /// The device holding the encrypted data
+    pub device: Device,
+    /// The offset of the encrypted data on the device
+    pub offset: Sectors,
+    /// The size of the unit of encryption, 512 bytes if None
+    pub sector_size: Option<Bytes>,
+    /// Whether the IV is computed from the number of the encryption sector
+    /// rather than of the 512 byte sector
+    pub iv_large_sectors: bool,
+    /// Whether I/O is encrypted on the CPU which submitted it
+    pub same_cpu_crypt: bool,
+    /// Whether writes are submitted directly from the encryption threads
+    pub submit_from_crypt_cpus: bool,
+    /// Whether reads are decrypted without the use of a workqueue, which
+    /// reduces latency on fast devices such as NVMe
+    pub no_read_workqueue: bool,
+    /// Whether writes are encrypted without the use of a workqueue
+    pub no_write_workqueue: bool,
+    /// The integrity data stored for each sector, if the device below is
+    /// an integrity device
+    pub integrity: Option<CryptIntegrity>,
+    /// Other optional params, e.g., "allow_discards"
+    pub optional_args: Vec<String>,
+}
+
+impl CryptTargetParams {
+    /// Create a new CryptTargetParams struct
+    pub fn new(
+        cipher: String,
+        key: String,
+        iv_offset: u64,
+        device: Device,
+        offset: Sectors,
+    ) -> CryptTargetParams {
+        CryptTargetParams {
+            cipher,
+            key,
+            iv_offset,
+            device,
+            offset,
+            sector_size: None,
+            iv_large_sectors: false,
+            same_cpu_crypt: false,
+            submit_from_crypt_cpus: false,
+            no_read_workqueue: false,
+            no_write_workqueue: false,
+            integrity: None,
+            optional_args: Vec::new(),
+        }
+    }
+
+    /// Create a new CryptTargetParams struct with the cipher and sector
+    /// size of `profile`; as cryptsetup does for LUKS2, the IV is computed
+    /// from the number of the encryption sector if the sector size is
+    /// given. Returns an error if `key` is not of the key size of `profile`.
+    pub fn new_from_profile(
+        profile: &CryptProfile,
+        key: String,
+        iv_offset: u64,
+        device: Device,
+        offset: Sectors,
+    ) -> DmResult<CryptTargetParams> {
+        let key_size = if key.starts_with(':') {
+            key.parse::<CryptKeyringKey>()?.size
+        } else {
+            Bytes(key.len() as u128 / 2)
+        };
+        if key_size != profile.key_size {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "key of size {key_size} does not have the key size of the profile, {}",
+                    profile.key_size
+                ),
+            ));
+        }
+        Ok(CryptTargetParams {
+            sector_size: profile.sector_size,
+            iv_large_sectors: profile.sector_size.is_some(),
+            ..CryptTargetParams::new(profile.cipher.clone(), key, iv_offset, device, offset)
+        })
+    }
+
+    /// The performance flags, each with its name as an optional param.
+    fn perf_flags(&self) -> [(&'static str, bool); 4] {
+        [
+            (SAME_CPU_CRYPT_PARAM, self.same_cpu_crypt),
+            (SUBMIT_FROM_CRYPT_CPUS_PARAM, self.submit_from_crypt_cpus),
+            (NO_READ_WORKQUEUE_PARAM, self.no_read_workqueue),
+            (NO_WRITE_WORKQUEUE_PARAM, self.no_write_workqueue),
+        ]
+    }
+
+    /// Set a performance flag by the name of its optional param. Returns
+    /// false if the name is not that of a performance flag.
+    fn set_perf_flag(&mut self, name: &str, value: bool) -> bool {
+        let flag = match name {
+            SAME_CPU_CRYPT_PARAM => &mut self.same_cpu_crypt,
+            SUBMIT_FROM_CRYPT_CPUS_PARAM => &mut self.submit_from_crypt_cpus,
+            NO_READ_WORKQUEUE_PARAM => &mut self.no_read_workqueue,
+            NO_WRITE_WORKQUEUE_PARAM => &mut self.no_write_workqueue,
+            _ => return false,
+        };
+        *flag = value;
+        true
+    }
+
+    /// The size of the unit of encryption.
+    pub fn encryption_sector_size(&self) -> Bytes {
+        self.sector_size
+            .unwrap_or_else(|| Bytes(SECTOR_SIZE as u128))
+    }
+
+    /// Verify that the encryption sector size is one the kernel accepts for
+    /// a crypt target of the given length, over a device with the given
+    /// logical block size.
+    fn check_sector_size_for(&self, length: Sectors, logical_block_size: Bytes) -> DmResult<()> {
+        let sector_size = self.encryption_sector_size();
+        let invalid = |msg: String| Err(DmError::Dm(ErrorEnum::Invalid, msg));
+
+        if sector_size < Bytes(SECTOR_SIZE as u128)
+            || sector_size > MAX_CRYPT_SECTOR_SIZE
+            || !sector_size.is_power_of_two()
+        {
+            return invalid(format!(
+                "crypt sector size {sector_size} is not a power of two between {SECTOR_SIZE} and {MAX_CRYPT_SECTOR_SIZE}"
+            ));
+        }
+        let sector_size = sector_size.sectors();
+        if !length.is_aligned(sector_size) {
+            return invalid(format!(
+                "crypt target length {length} is not a multiple of the crypt sector size, {sector_size}"
+            ));
+        }
+        if self.iv_offset % *sector_size != 0 {
+            return invalid(format!(
+                "IV offset {} is not a multiple of the crypt sector size, {sector_size}",
+                self.iv_offset
+            ));
+        }
+        if logical_block_size > sector_size.bytes() {
+            return invalid(format!(
+                "crypt sector size {sector_size} is less than the logical block size, {} bytes, of device {}",
+                *logical_block_size, self.device
+            ));
+        }
+        if !self.offset.bytes().is_aligned(logical_block_size) {
+            return invalid(format!(
+                "offset {} is not a multiple of the logical block size, {} bytes, of device {}",
+                self.offset, *logical_block_size, self.device
+            ));
+        }
+        if !self.offset.is_aligned(sector_size) {
+            warn!(
+                "Offset {} on device {} is not a multiple of the crypt sector size, {}",
+                self.offset, self.device, sector_size
+            );
+        }
+        Ok(())
+    }
+
+    /// Verify, before the table is loaded, that the encryption sector size
+    /// is usable by a crypt target of the given length: it must be a power
+    /// of two between 512 and 4096 bytes, no smaller than the logical block
+    /// size of the device, and the length and IV offset must be multiples
+    /// of it. The offset on the device must be a multiple of the device's
+    /// logical block size; if it is not also a multiple of the encryption
+    /// sector size, a warning is logged, as I/O will be misaligned.
+    pub fn check_sector_size(&self, dm: &DM, length: Sectors) -> DmResult<()> {
+        let topology = device_topology(dm, self.device)?;
+        self.check_sector_size_for(length, topology.logical_block_size)
+    }
+}
+
+/// The version of the crypt target which introduced a performance flag.
+#[cfg(devicemapper41supported)]
+fn perf_flag_min_version(flag: &str) -> Option<Version> {
+    match flag {
+        SAME_CPU_CRYPT_PARAM | SUBMIT_FROM_CRYPT_CPUS_PARAM => Some(Version::new(1, 14, 0)),
+        NO_READ_WORKQUEUE_PARAM | NO_WRITE_WORKQUEUE_PARAM => Some(Version::new(1, 22, 0)),
+        _ => None,
+    }
+}
+
+#[cfg(devicemapper41supported)]
+impl CryptTargetParams {
+    /// The names of the performance flags which are set.
+    fn set_perf_flags(&self) -> Vec<String> {
+        self.perf_flags()
+            .iter()
+            .filter(|(_, value)| *value)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    /// Check that the running kernel's crypt target accepts every
+    /// performance flag which is set, so that a table with these params
+    /// does not fail to load. If the crypt target is not yet loaded, its
+    /// version is unknown and no flag is rejected.
+    pub fn check_features(&self, capabilities: &DmCapabilities) -> DmResult<()> {
+        capabilities.check_features(
+            CRYPT_TARGET_NAME,
+            &self.set_perf_flags(),
+            perf_flag_min_version,
+        )
+    }
+
+    /// Clear every performance flag which the running kernel's crypt
+    /// target does not accept, logging a warning for each.
+    pub fn remove_unsupported_features(&mut self, capabilities: &DmCapabilities) {
+        for (flag, min) in capabilities.unsupported_features(
+            CRYPT_TARGET_NAME,
+            &self.set_perf_flags(),
+            perf_flag_min_version,
+        ) {
+            warn!(
+                "Omitting optional param {}, which requires crypt target version {} or later",
+                flag, min
+            );
+            self.set_perf_flag(&flag, false);
+        }
+    }
+}
+
+impl fmt::Debug for CryptTargetParams {
+    /// As derived, except that a key given as hex is redacted, so that it
+    /// is not written to logs. A reference to a key in the kernel keyring
+    /// is shown.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = if self.key.starts_with(':') {
+            self.key.as_str()
+        } else {
+            "<redacted>"
+        };
+        f.debug_struct("CryptTargetParams")
+            .field("cipher", &self.cipher)
+            .field("key", &key)
+            .field("iv_offset", &self.iv_offset)
+            .field("device", &self.device)
+            .field("offset", &self.offset)
+            .field("sector_size", &self.sector_size)
+            .field("iv_large_sectors", &self.iv_large_sectors)
+            .field("same_cpu_crypt", &self.same_cpu_crypt)
+            .field("submit_from_crypt_cpus", &self.submit_from_crypt_cpus)
+            .field("no_read_workqueue", &self.no_read_workqueue)
+            .field("no_write_workqueue", &self.no_write_workqueue)
+            .field("integrity", &self.integrity)
+            .field("optional_args", &self.optional_args)
+            .finish()
+    }
+}
+
+impl fmt::Display for CryptTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <cipher> <key> <iv offset> <device> <offset> [<#opt params> <opt params>]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", CRYPT_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for CryptTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<CryptTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() < 6 {
+            let err_msg = format!(
+                "expected at least 6 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != CRYPT_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a crypt target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut params = CryptTargetParams::new(
+            vals[1].to_string(),
+            vals[2].to_string(),
+            parse_value(vals[3], "IV offset")?,
+            parse_device(vals[4], "block device for crypt target")?,
+            Sectors(parse_value(vals[5], "physical start offset")?),
+        );
+
+        if let Some(count) = vals.get(6) {
+            let count: usize = parse_value(count, "number of optional params")?;
+            if vals.len() != 7 + count {
+                let err_msg = format!(
+                    "expected {} optional params in params string \"{}\", found {}",
+                    count,
+                    s,
+                    vals.len() - 7
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+            for arg in &vals[7..] {
+                if let Some(size) = arg.strip_prefix(SECTOR_SIZE_PARAM) {
+                    params.sector_size = Some(Bytes(parse_value(size, "crypt sector size")?));
+                } else if let Some(integrity) = arg.strip_prefix(INTEGRITY_PARAM) {
+                    let (tag_size, integrity_type) =
+                        integrity.split_once(':').ok_or_else(|| {
+                            DmError::Dm(
+                                ErrorEnum::Invalid,
+                                format!("expected a size and a type in crypt param \"{arg}\""),
+                            )
+                        })?;
+                    params.integrity = Some(CryptIntegrity::new(
+                        parse_value(tag_size, "integrity tag size")?,
+                        integrity_type.to_string(),
+                    ));
+                } else if *arg == IV_LARGE_SECTORS_PARAM {
+                    params.iv_large_sectors = true;
+                } else if !params.set_perf_flag(arg, true) {
+                    params.optional_args.push(arg.to_string());
+                }
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+impl TargetParams for CryptTargetParams {
+    fn param_str(&self) -> String {
+        let mut optional_args = self.optional_args.clone();
+        optional_args.extend(
+            self.perf_flags()
+                .iter()
+                .filter(|(_, value)| *value)
+                .map(|(name, _)| name.to_string()),
+        );
+        if let Some(integrity) = &self.integrity {
+            optional_args.push(format!(
+                "{INTEGRITY_PARAM}{}:{}",
+                integrity.tag_size, integrity.integrity_type
+            ));
+        }
+        if let Some(sector_size) = self.sector_size {
+            optional_args.push(format!("{SECTOR_SIZE_PARAM}{}", *sector_size));
+        }
+        if self.iv_large_sectors {
+            optional_args.push(IV_LARGE_SECTORS_PARAM.to_string());
+        }
+
+        let mut params = format!(
+            "{} {} {} {} {}",
+            self.cipher, self.key, self.iv_offset, self.device, *self.offset
+        );
+        if !optional_args.is_empty() {
+            params.push_str(&format!(
+                " {} {}",
+                optional_args.len(),
+                optional_args.join(" ")
+            ));
+        }
+        params
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(CRYPT_TARGET_NAME.into()).expect("CRYPT_TARGET_NAME is valid")
+    }
+}
+
+/// A target table for a crypt device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptDevTargetTable {
+    /// The device's table
+    pub table: TargetLine<CryptTargetParams>,
+}
+
+impl CryptDevTargetTable {
+    /// Make a new CryptDevTargetTable from required input
+    pub fn new(start: Sectors, length: Sectors, params: CryptTargetParams) -> CryptDevTargetTable {
+        CryptDevTargetTable {
+            table: TargetLine::new(start, length, params),
+        }
+    }
+
+    /// Verify that the encrypted data does not begin on a signature of
+    /// existing data, such as a filesystem, on the backing device, which
+    /// encrypting the device would overwrite. If `force` is true, any
+    /// signatures found are logged and ignored.
+    pub fn check_signatures(&self, force: bool) -> DmResult<()> {
+        let params = &self.table.params;
+        check_signatures(params.device, params.offset, force)
+    }
+}
+
+impl fmt::Display for CryptDevTargetTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = &self.table;
+        writeln!(f, "{} {} {}", *table.start, *table.length, table.params)
+    }
+}
+
+impl TargetTable for CryptDevTargetTable {
+    fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<CryptDevTargetTable> {
+        if table.len() != 1 {
+            let err_msg = format!(
+                "CryptDev table should have exactly one line, has {} lines",
+                table.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let line = table.first().expect("table.len() == 1");
+        Ok(CryptDevTargetTable::new(
+            Sectors(line.0),
+            Sectors(line.1),
+            format!("{} {}", line.2, line.3).parse::<CryptTargetParams>()?,
+        ))
+    }
+
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        to_raw_table_unique!(self)
+    }
+}
+
+/// A key in the kernel keyring, by which a crypt target may be given its
+/// key, rather than as hex in its table and messages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptKeyringKey {
+    /// The size of the key
+    pub size: Bytes,
+    /// The type of the key, "logon", "user", "encrypted" or "trusted"
+    pub key_type: String,
+    /// The description by which the key is found in the keyring
+    pub description: String,
+}
+
+impl CryptKeyringKey {
+    /// Create a new CryptKeyringKey struct
+    pub fn new(size: Bytes, key_type: String, description: String) -> CryptKeyringKey {
+        CryptKeyringKey {
+            size,
+            key_type,
+            description,
+        }
+    }
+}
+
+impl fmt::Display for CryptKeyringKey {
+    /// The key as it is given to the crypt target:
+    ///
+    /// ```plain
+    /// :<key size>:<key type>:<key description>
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ":{}:{}:{}", *self.size, self.key_type, self.description)
+    }
+}
+
+impl FromStr for CryptKeyringKey {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<CryptKeyringKey> {
+        // The description may itself contain ':'.
+        match s.splitn(4, ':').collect::<Vec<_>>().as_slice() {
+            ["", size, key_type, description] if !description.is_empty() => {
+                Ok(CryptKeyringKey::new(
+                    Bytes(parse_value(size, "keyring key size")?),
+                    key_type.to_string(),
+                    description.to_string(),
+                ))
+            }
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("\"{s}\" is not a keyring key of the form :<size>:<type>:<description>"),
+            )),
+        }
+    }
+}
+
+/// Set the key of the suspended crypt device `id` to `key`, as hex or as a
+/// reference to a key in the kernel keyring, as for `CryptTargetParams`.
+pub fn crypt_key_set(dm: &DM, id: &DevId<'_>, key: &str) -> DmResult<()> {
+    dm.target_msg(id, None, &format!("{KEY_SET_MSG} {key}"))?;
+    Ok(())
+}
+
+/// Wipe the key of the suspended crypt device `id` from memory. The device
+/// can not be resumed until its key is set again with `crypt_key_set`.
+pub fn crypt_key_wipe(dm: &DM, id: &DevId<'_>) -> DmResult<()> {
+    dm.target_msg(id, None, KEY_WIPE_MSG)?;
+    Ok(())
+}
+
+/// Lock the crypt device `id`, as `cryptsetup luksSuspend` does: suspend
+/// it, flushing outstanding I/O, and wipe its key from memory. The device
+/// stays suspended, with new I/O queued, until it is unlocked with
+/// `crypt_unlock`. If the key can not be wiped, the device is resumed.
+pub fn crypt_lock(dm: &DM, id: &DevId<'_>) -> DmResult<()> {
+    dm.device_suspend(id, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))?;
+    if let Err(err) = crypt_key_wipe(dm, id) {
+        dm.device_suspend(id, DmOptions::private())?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Unlock the crypt device `id`, locked with `crypt_lock`, by setting its
+/// key to `key` and resuming it. The key must be the one the device was
+/// set up with; the crypt target can not tell a wrong key from a right one,
+/// and would present garbage.
+pub fn crypt_unlock(dm: &DM, id: &DevId<'_>, key: &str) -> DmResult<()> {
+    crypt_key_set(dm, id, key)?;
+    dm.device_suspend(id, DmOptions::private())?;
+    Ok(())
+}
+
+/// Give the active crypt device `id` its key from the kernel keyring,
+/// e.g., so that the key no longer appears as hex in its table. The device
+/// is suspended while its key is set, and resumed whether or not that
+/// succeeds. The keyring key must hold the key the device was set up with.
+pub fn crypt_rekey_from_keyring(dm: &DM, id: &DevId<'_>, key: &CryptKeyringKey) -> DmResult<()> {
+    dm.device_suspend(id, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))?;
+    let result = crypt_key_set(dm, id, &key.to_string());
+    dm.device_suspend(id, DmOptions::private())?;
+    result
+}
+
+/// An algorithm of the kernel crypto API, as listed in /proc/crypto.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CryptoAlgorithm {
+    /// The name of the algorithm, e.g., "xts(aes)"
+    pub name: String,
+    /// The name of the implementation, e.g., "xts-aes-aesni"
+    pub driver: String,
+    /// The type of the algorithm, e.g., "cipher", "skcipher", "aead" or
+    /// "shash"
+    pub alg_type: String,
+    /// The smallest key the algorithm accepts, if it takes a key
+    pub min_key_size: Option<Bytes>,
+    /// The largest key the algorithm accepts, if it takes a key
+    pub max_key_size: Option<Bytes>,
+    /// The size of the digest, if the algorithm is a hash
+    pub digest_size: Option<Bytes>,
+}
+
+impl CryptoAlgorithm {
+    /// Whether the algorithm accepts a key of the given size. An algorithm
+    /// which lists no key sizes is assumed to accept any key.
+    fn accepts_key_size(&self, key_size: Bytes) -> bool {
+        self.min_key_size.map_or(true, |min| key_size >= min)
+            && self.max_key_size.map_or(true, |max| key_size <= max)
+    }
+}
+
+/// Parse the contents of /proc/crypto, in which each algorithm is a block
+/// of "<field> : <value>" lines, and the blocks are separated by blank
+/// lines.
+fn parse_proc_crypto(contents: &str) -> DmResult<Vec<CryptoAlgorithm>> {
+    let mut algorithms = Vec::new();
+    let mut algorithm: Option<CryptoAlgorithm> = None;
+    for line in contents.lines() {
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field.trim(), value.trim()),
+            None => {
+                algorithms.extend(algorithm.take());
+                continue;
+            }
+        };
+        let size = |desc: &str| parse_value::<u128>(value, desc).map(|size| Some(Bytes(size)));
+        let current = algorithm.get_or_insert_with(CryptoAlgorithm::default);
+        match field {
+            "name" => current.name = value.to_string(),
+            "driver" => current.driver = value.to_string(),
+            "type" => current.alg_type = value.to_string(),
+            "min keysize" => current.min_key_size = size("min keysize")?,
+            "max keysize" => current.max_key_size = size("max keysize")?,
+            "digestsize" => current.digest_size = size("digestsize")?,
+            _ => (),
+        }
+    }
+    algorithms.extend(algorithm);
+    Ok(algorithms)
+}
+
+/// The algorithms of the kernel crypto API which are currently available.
+/// An algorithm whose module is not yet loaded, or which is built from a
+/// template, such as "xts(aes)", which has not yet been instantiated, is
+/// not listed, although the kernel will load or instantiate it on demand.
+pub fn crypto_algorithms() -> DmResult<Vec<CryptoAlgorithm>> {
+    let contents = fs::read_to_string(PROC_CRYPTO).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to read {PROC_CRYPTO}: {err}"
+        )))
+    })?;
+    parse_proc_crypto(&contents)
+}
+
+/// The authentication of the sectors of a crypt device whose cipher is
+/// not itself an authenticated encryption cipher.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CipherIntegrity {
+    /// The cipher is an authenticated encryption cipher, e.g., "gcm(aes)"
+    Aead,
+    /// The sectors are authenticated by the given MAC, e.g.,
+    /// "hmac(sha256)", combined with the cipher by the kernel's authenc
+    /// template; the key is the encryption key followed by the MAC key
+    Mac(String),
+}
+
+impl CipherIntegrity {
+    /// The type of the integrity param of the crypt target.
+    pub fn integrity_type(&self) -> &str {
+        match self {
+            CipherIntegrity::Aead => "aead",
+            CipherIntegrity::Mac(mac) => mac,
+        }
+    }
+}
+
+/// The cipher specification of a crypt target, built up from its parts
+/// and checked against the kernel crypto API before the table is loaded,
+/// so that a combination which the kernel would refuse is reported with a
+/// descriptive error rather than as EINVAL from the table load.
+///
+/// ```
+/// use devicemapper::CipherSpec;
+///
+/// let spec = CipherSpec::new("aes", Some("cbc"), Some("essiv")).set_iv_opts("sha256");
+/// assert_eq!(spec.to_string(), "aes-cbc-essiv:sha256");
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CipherSpec {
+    /// The cipher, e.g., "aes", or, for a specification in the kernel
+    /// crypto API's own syntax, the whole of it, e.g., "gcm(aes)"
+    pub cipher: String,
+    /// Whether the cipher is given in the kernel crypto API's own syntax
+    pub capi: bool,
+    /// The number of keys into which the key is divided, for loop-AES
+    /// compatible multi-key modes
+    pub key_count: Option<u32>,
+    /// The chaining mode, e.g., "xts" or "cbc"; None for a specification in
+    /// the kernel crypto API's syntax, in which it is part of the cipher
+    pub chain_mode: Option<String>,
+    /// The IV generator, e.g., "plain64"; None only with the ecb chaining
+    /// mode, which uses no IV
+    pub iv_mode: Option<String>,
+    /// The options of the IV generator, e.g., the hash of essiv
+    pub iv_opts: Option<String>,
+    /// The authentication of the sectors, if any
+    pub integrity: Option<CipherIntegrity>,
+}
+
+impl CipherSpec {
+    /// A specification of the form <cipher>-<chain mode>-<IV generator>,
+    /// e.g., "aes-xts-plain64".
+    pub fn new(cipher: &str, chain_mode: Option<&str>, iv_mode: Option<&str>) -> CipherSpec {
+        CipherSpec {
+            cipher: cipher.to_string(),
+            capi: false,
+            key_count: None,
+            chain_mode: chain_mode.map(|mode| mode.to_string()),
+            iv_mode: iv_mode.map(|mode| mode.to_string()),
+            iv_opts: None,
+            integrity: None,
+        }
+    }
+
+    /// A specification of the form capi:<kernel crypto API cipher>-<IV
+    /// generator>, e.g., "capi:gcm(aes)-random".
+    pub fn capi(cipher: &str, iv_mode: &str) -> CipherSpec {
+        CipherSpec {
+            capi: true,
+            ..CipherSpec::new(cipher, None, Some(iv_mode))
+        }
+    }
+
+    /// Divide the key into `key_count` keys.
+    pub fn set_key_count(mut self, key_count: u32) -> CipherSpec {
+        self.key_count = Some(key_count);
+        self
+    }
+
+    /// Give the IV generator options, e.g., the hash of essiv.
+    pub fn set_iv_opts(mut self, iv_opts: &str) -> CipherSpec {
+        self.iv_opts = Some(iv_opts.to_string());
+        self
+    }
+
+    /// Authenticate the sectors, which requires an integrity device below
+    /// the crypt device to hold the tags.
+    pub fn set_integrity(mut self, integrity: CipherIntegrity) -> CipherSpec {
+        self.integrity = Some(integrity);
+        self
+    }
+
+    /// The name of the cipher in the kernel crypto API, e.g., "xts(aes)",
+    /// without any MAC.
+    pub fn kernel_cipher(&self) -> String {
+        match &self.chain_mode {
+            Some(mode) if !self.capi => format!("{mode}({})", self.cipher),
+            _ => self.cipher.clone(),
+        }
+    }
+
+    /// The integrity param of a crypt target with this cipher, given the
+    /// size of the tag, including any stored IV.
+    pub fn crypt_integrity(&self, tag_size: u64) -> Option<CryptIntegrity> {
+        self.integrity
+            .as_ref()
+            .map(|integrity| CryptIntegrity::new(tag_size, integrity.integrity_type().to_string()))
+    }
+
+    /// Verify that the parts of the specification make a combination which
+    /// the crypt target accepts, independent of the kernel's algorithms.
+    fn check_combination(&self) -> DmResult<()> {
+        let invalid = |msg: String| Err(DmError::Dm(ErrorEnum::Invalid, msg));
+
+        if self.cipher.is_empty() || self.cipher.contains('-') {
+            return invalid(format!("\"{}\" is not a valid cipher", self.cipher));
+        }
+        if let Some(key_count) = self.key_count {
+            if !key_count.is_power_of_two() {
+                return invalid(format!("key count {key_count} is not a power of two"));
+            }
+        }
+        match (&self.chain_mode, self.capi) {
+            (Some(_), true) => {
+                return invalid(format!(
+                    "the chaining mode of the kernel crypto API cipher {} is part of it",
+                    self.cipher
+                ))
+            }
+            (None, false) => return invalid(format!("no chaining mode for {}", self.cipher)),
+            _ => (),
+        }
+
+        let iv_mode = match &self.iv_mode {
+            Some(iv_mode) => iv_mode,
+            None if self.chain_mode.as_deref() == Some("ecb") => {
+                if self.iv_opts.is_some() {
+                    return invalid("IV options given without an IV generator".to_string());
+                }
+                return Ok(());
+            }
+            None => {
+                return invalid(format!(
+                    "an IV generator is required with chaining mode {}",
+                    self.chain_mode.as_deref().unwrap_or_default()
+                ))
+            }
+        };
+        match IV_MODES.iter().find(|(mode, _)| mode == iv_mode) {
+            None => return invalid(format!("unknown IV generator {iv_mode}")),
+            Some((_, IvOpts::Required)) if self.iv_opts.is_none() => {
+                return invalid(format!("IV generator {iv_mode} requires options"))
+            }
+            Some((_, IvOpts::Forbidden)) if self.iv_opts.is_some() => {
+                return invalid(format!("IV generator {iv_mode} takes no options"))
+            }
+            _ => (),
+        }
+        if iv_mode == "random" && self.integrity.is_none() {
+            return invalid(
+                "the random IV generator requires integrity, to store the IVs".to_string(),
+            );
+        }
+        if self.integrity == Some(CipherIntegrity::Aead) && !self.capi {
+            return invalid(format!(
+                "AEAD cipher {self} must be given in the kernel crypto API syntax"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verify the specification, and that it accepts a key of `key_size`,
+    /// against the given algorithms of the kernel crypto API. An algorithm
+    /// which is not listed is not checked, as it may be loaded on demand.
+    fn check_with(&self, key_size: Bytes, algorithms: &[CryptoAlgorithm]) -> DmResult<()> {
+        self.check_combination()?;
+        let invalid = |msg: String| Err(DmError::Dm(ErrorEnum::Invalid, msg));
+        let find = |name: &str| algorithms.iter().find(|alg| alg.name == name);
+
+        let mut key_size = key_size;
+        if let Some(CipherIntegrity::Mac(mac)) = &self.integrity {
+            // The key of an HMAC is as long as the digest of its hash.
+            let hash = mac
+                .strip_prefix("hmac(")
+                .and_then(|hash| hash.strip_suffix(')'));
+            match find(mac).or_else(|| hash.and_then(find)) {
+                Some(alg) => {
+                    let mac_key_size = alg.digest_size.unwrap_or(Bytes(0));
+                    if key_size <= mac_key_size {
+                        return invalid(format!(
+                            "key of {key_size} does not hold both a cipher key and a {mac} key"
+                        ));
+                    }
+                    key_size = Bytes(*key_size - *mac_key_size);
+                }
+                None => {
+                    debug!("MAC {} is not listed in {}, not checked", mac, PROC_CRYPTO);
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(key_count) = self.key_count {
+            // The lmk IV generator may take a seed, and tcw takes a whitening
+            // key and an IV seed, each as long as one of the keys.
+            let extra_keys: &[u32] = match self.iv_mode.as_deref() {
+                Some("lmk") => &[0, 1],
+                Some("tcw") => &[2],
+                _ => &[0],
+            };
+            let parts = extra_keys
+                .iter()
+                .map(|extra| u128::from(key_count + extra))
+                .find(|parts| *key_size % parts == 0)
+                .ok_or_else(|| {
+                    DmError::Dm(
+                        ErrorEnum::Invalid,
+                        format!("key of {key_size} can not be divided into {key_count} keys"),
+                    )
+                })?;
+            key_size = Bytes(*key_size / parts);
+        }
+
+        if self.iv_mode.as_deref() == Some("essiv") {
+            let hash = self.iv_opts.as_deref().unwrap_or_default();
+            if let Some(alg) = find(hash) {
+                if alg.digest_size.is_none() {
+                    return invalid(format!("essiv option {hash} is not a hash"));
+                }
+            }
+        }
+
+        // The kernel cipher, e.g., "xts(aes)", if it has been instantiated,
+        // else the underlying block cipher, whose key is doubled by xts.
+        let kernel_cipher = self.kernel_cipher();
+        let (alg, key_size) = match find(&kernel_cipher) {
+            Some(alg) => (alg, key_size),
+            None if !self.capi => match find(&self.cipher) {
+                Some(alg) if self.chain_mode.as_deref() == Some("xts") => {
+                    if *key_size % 2 != 0 {
+                        return invalid(format!(
+                            "key of {key_size} can not be divided into the two keys of xts"
+                        ));
+                    }
+                    (alg, Bytes(*key_size / 2))
+                }
+                Some(alg) => (alg, key_size),
+                None => {
+                    debug!(
+                        "Cipher {} is not listed in {}, not checked",
+                        self.cipher, PROC_CRYPTO
+                    );
+                    return Ok(());
+                }
+            },
+            None => {
+                debug!(
+                    "Cipher {} is not listed in {}, not checked",
+                    kernel_cipher, PROC_CRYPTO
+                );
+                return Ok(());
+            }
+        };
+        if self.integrity == Some(CipherIntegrity::Aead) && alg.alg_type != "aead" {
+            return invalid(format!(
+                "{} is not an authenticated encryption cipher",
+                alg.name
+            ));
+        }
+        if !alg.accepts_key_size(key_size) {
+            return invalid(format!("{} does not accept a key of {key_size}", alg.name));
+        }
+        Ok(())
+    }
+
+    /// Verify, before the table is loaded, that the parts of the
+    /// specification make a combination the crypt target accepts, and that
+    /// the cipher, and any MAC or essiv hash, accept a key of `key_size`,
+    /// the size of the whole key given to the crypt target, according to
+    /// /proc/crypto.
+    pub fn check(&self, key_size: Bytes) -> DmResult<()> {
+        self.check_with(key_size, &crypto_algorithms()?)
+    }
+}
+
+impl fmt::Display for CipherSpec {
+    /// The cipher param of the crypt target, e.g., "aes-xts-plain64",
+    /// "aes:64-cbc-lmk", or "capi:gcm(aes)-random".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.capi {
+            write!(f, "{CAPI_PREFIX}")?;
+        }
+        write!(f, "{}", self.cipher)?;
+        if let Some(key_count) = self.key_count {
+            write!(f, ":{key_count}")?;
+        }
+        if let Some(chain_mode) = &self.chain_mode {
+            write!(f, "-{chain_mode}")?;
+        }
+        if let Some(iv_mode) = &self.iv_mode {
+            write!(f, "-{iv_mode}")?;
+        }
+        if let Some(iv_opts) = &self.iv_opts {
+            write!(f, ":{iv_opts}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An authenticated encryption cipher, as it is given to the crypt target
+/// when the tags are stored on an integrity device below it. These are the
+/// ciphers which `cryptsetup --integrity` sets up.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AeadCipher {
+    /// The cipher specification of the crypt target
+    pub cipher: String,
+    /// The size of the authentication tag
+    pub auth_tag_size: u64,
+    /// The size of the IV, which is stored with the tag as it is random
+    pub iv_size: u64,
+    /// The type of the crypt target's integrity param
+    pub integrity_type: String,
+}
+
+impl AeadCipher {
+    /// AES-GCM with a random IV; cryptsetup's "aes-gcm-random" with
+    /// integrity "aead". The key is the AES key.
+    pub fn aes_gcm_random() -> AeadCipher {
+        AeadCipher {
+            cipher: "capi:gcm(aes)-random".to_string(),
+            auth_tag_size: 16,
+            iv_size: 12,
+            integrity_type: "aead".to_string(),
+        }
+    }
+
+    /// ChaCha20 with Poly1305 and a random IV; cryptsetup's
+    /// "chacha20-random" with integrity "poly1305".
+    pub fn chacha20_poly1305_random() -> AeadCipher {
+        AeadCipher {
+            cipher: "capi:rfc7539(chacha20,poly1305)-random".to_string(),
+            auth_tag_size: 16,
+            iv_size: 12,
+            integrity_type: "aead".to_string(),
+        }
+    }
+
+    /// AES-XTS authenticated with HMAC-SHA256 and a random IV;
+    /// cryptsetup's "aes-xts-random" with integrity "hmac-sha256". The key
+    /// is the XTS key followed by the HMAC key.
+    pub fn aes_xts_hmac_sha256_random() -> AeadCipher {
+        AeadCipher {
+            cipher: "capi:authenc(hmac(sha256),xts(aes))-random".to_string(),
+            auth_tag_size: 32,
+            iv_size: 16,
+            integrity_type: "aead".to_string(),
+        }
+    }
+
+    /// The bytes of integrity data stored for each sector, which is the
+    /// tag size of the integrity device.
+    pub fn tag_size(&self) -> u64 {
+        self.auth_tag_size + self.iv_size
+    }
+}
+
+/// The tables of an integrity device, which stores the tags of an
+/// authenticated encryption cipher, and of the crypt device stacked on it,
+/// as set up by `cryptsetup --integrity`. The parameters of the two tables
+/// are derived from the same values, so that they agree: the tag size of
+/// the integrity device is the integrity data size of the crypt target,
+/// its block size is the crypt sector size, and the length of the crypt
+/// device is the number of data sectors the integrity device provides.
+#[derive(Clone, Debug)]
+pub struct AeadCryptStack {
+    integrity: IntegrityDevTargetTable,
+    crypt: CryptTargetParams,
+    length: Sectors,
+}
+
+impl AeadCryptStack {
+    /// Compute the tables of an authenticated encryption stack on `device`,
+    /// which has size `device_size`. `sector_size` is the size of the unit
+    /// of encryption and of the sectors which are each given a tag. The
+    /// integrity device is formatted, in journal mode, when it is first
+    /// activated if its superblock is zeroed.
+    pub fn new(
+        device: Device,
+        device_size: Sectors,
+        cipher: &AeadCipher,
+        key: String,
+        sector_size: Bytes,
+    ) -> DmResult<AeadCryptStack> {
+        let layout = IntegrityLayout::new(cipher.tag_size()).set_sector_size(sector_size);
+        let length = layout.provided_data_sectors(device_size)?;
+
+        let integrity = IntegrityDevTargetTable::new(
+            Sectors(0),
+            length,
+            layout.target_params(device, Sectors(0), IntegrityMode::Journal),
+        );
+
+        // The device is replaced by that of the integrity device once it is
+        // activated.
+        let mut crypt = CryptTargetParams::new(cipher.cipher.clone(), key, 0, device, Sectors(0));
+        if sector_size != Bytes(SECTOR_SIZE as u128) {
+            crypt.sector_size = Some(sector_size);
+        }
+        crypt.integrity = Some(CryptIntegrity::new(
+            cipher.tag_size(),
+            cipher.integrity_type.clone(),
+        ));
+        crypt.check_sector_size_for(length, sector_size)?;
+
+        Ok(AeadCryptStack {
+            integrity,
+            crypt,
+            length,
+        })
+    }
+
+    /// The table of the integrity device.
+    pub fn integrity_table(&self) -> &IntegrityDevTargetTable {
+        &self.integrity
+    }
+
+    /// The table of the crypt device, given the device number of the
+    /// integrity device.
+    pub fn crypt_table(&self, integrity_device: Device) -> CryptDevTargetTable {
+        let mut params = self.crypt.clone();
+        params.device = integrity_device;
+        CryptDevTargetTable::new(Sectors(0), self.length, params)
+    }
+
+    /// The size of the decrypted device.
+    pub fn size(&self) -> Sectors {
+        self.length
+    }
+
+    /// Verify that the device on which the stack is built holds no
+    /// signature of existing data, such as a filesystem, which formatting
+    /// the integrity device would overwrite. If `force` is true, any
+    /// signatures found are logged and ignored. The crypt device needs no
+    /// check of its own, as it is built on the integrity device.
+    pub fn check_signatures(&self, force: bool) -> DmResult<()> {
+        self.integrity.check_signatures(force)
+    }
+
+    /// A stack which activates the integrity device, and the crypt device
+    /// on it, with the given names and UUIDs.
+    pub fn device_stack(
+        self,
+        integrity: (&DmName, Option<&DmUuid>),
+        crypt: (&DmName, Option<&DmUuid>),
+    ) -> DeviceStack {
+        let integrity_table = self.integrity.clone();
+        DeviceStack::new()
+            .layer(integrity.0, integrity.1, move |_| Ok(integrity_table))
+            .layer(crypt.0, crypt.1, move |below| {
+                Ok(self.crypt_table(below[0].device()))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{Read, Seek, SeekFrom, Write},
+        path::Path,
+    };
+
+    use crate::{
+        core::devnode_to_devno,
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that the sector size params are parsed into typed fields and
+    /// generated after the other optional params.
+    fn test_crypt_target_params() {
+        let params = "crypt aes-xts-plain64 :64:logon:cryptsetup:key 0 8:16 4096 3 allow_discards sector_size:4096 iv_large_sectors"
+            .parse::<CryptTargetParams>()
+            .unwrap();
+        assert_eq!(params.sector_size, Some(Bytes(4096)));
+        assert!(params.iv_large_sectors);
+        assert_eq!(params.optional_args, vec!["allow_discards"]);
+        assert_eq!(
+            params.param_str(),
+            "aes-xts-plain64 :64:logon:cryptsetup:key 0 8:16 4096 3 allow_discards sector_size:4096 iv_large_sectors"
+        );
+
+        let params = "crypt aes-xts-plain64 00 0 8:16 0"
+            .parse::<CryptTargetParams>()
+            .unwrap();
+        assert_eq!(params.encryption_sector_size(), Bytes(512));
+        assert_eq!(params.param_str(), "aes-xts-plain64 00 0 8:16 0");
+    }
+
+    #[test]
+    /// Verify that the performance flags are parsed into typed fields, and
+    /// generated in the order in which the kernel reports them.
+    fn test_perf_flags() {
+        let params =
+            "crypt aes-xts-plain64 00 0 8:16 0 3 no_write_workqueue allow_discards same_cpu_crypt"
+                .parse::<CryptTargetParams>()
+                .unwrap();
+        assert!(params.same_cpu_crypt);
+        assert!(!params.submit_from_crypt_cpus);
+        assert!(!params.no_read_workqueue);
+        assert!(params.no_write_workqueue);
+        assert_eq!(params.optional_args, vec!["allow_discards"]);
+        assert_eq!(
+            params.param_str(),
+            "aes-xts-plain64 00 0 8:16 0 3 allow_discards same_cpu_crypt no_write_workqueue"
+        );
+    }
+
+    #[test]
+    /// Verify that the tables of an authenticated encryption stack agree on
+    /// the tag size, the sector size, and the length.
+    fn test_aead_crypt_stack() {
+        let device = Device::from_str("8:16").unwrap();
+        let stack = AeadCryptStack::new(
+            device,
+            Sectors(2 * 1024 * 1024),
+            &AeadCipher::aes_gcm_random(),
+            "00".to_string(),
+            Bytes(4096),
+        )
+        .unwrap();
+
+        let integrity = &stack.integrity_table().table;
+        assert_eq!(
+            integrity.params.to_string(),
+            "integrity 8:16 0 28 J 2 fix_padding block_size:4096"
+        );
+        assert_eq!(integrity.length, stack.size());
+
+        let crypt = stack.crypt_table(Device::from_str("253:1").unwrap()).table;
+        assert_eq!(crypt.length, stack.size());
+        assert_eq!(
+            crypt.params.to_string(),
+            "crypt capi:gcm(aes)-random 00 0 253:1 0 2 integrity:28:aead sector_size:4096"
+        );
+        assert_eq!(
+            crypt
+                .params
+                .to_string()
+                .parse::<CryptTargetParams>()
+                .unwrap(),
+            crypt.params
+        );
+
+        assert_matches!(
+            AeadCryptStack::new(
+                device,
+                Sectors(64),
+                &AeadCipher::aes_gcm_random(),
+                "00".to_string(),
+                Bytes(512),
+            ),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that an encryption sector size which the kernel would reject,
+    /// or which is smaller than the device's logical block size, is
+    /// rejected.
+    fn test_check_sector_size() {
+        let mut params = CryptTargetParams::new(
+            "aes-xts-plain64".to_string(),
+            "00".to_string(),
+            0,
+            Device::from_str("8:16").unwrap(),
+            Sectors(8),
+        );
+        let lbs = Bytes(512);
+        assert_matches!(params.check_sector_size_for(Sectors(9), lbs), Ok(_));
+        assert_matches!(
+            params.check_sector_size_for(Sectors(8), Bytes(4096)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        params.sector_size = Some(Bytes(4096));
+        assert_matches!(params.check_sector_size_for(Sectors(16), lbs), Ok(_));
+        assert_matches!(
+            params.check_sector_size_for(Sectors(16), Bytes(4096)),
+            Ok(_)
+        );
+        assert_matches!(
+            params.check_sector_size_for(Sectors(12), lbs),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        params.iv_offset = 4;
+        assert_matches!(
+            params.check_sector_size_for(Sectors(16), lbs),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        params.iv_offset = 0;
+
+        params.sector_size = Some(Bytes(8192));
+        assert_matches!(
+            params.check_sector_size_for(Sectors(16), lbs),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    /// An excerpt of /proc/crypto.
+    const PROC_CRYPTO_EXCERPT: &str = "name         : gcm(aes)
+driver       : generic-gcm-aesni
+module       : aesni_intel
+priority     : 400
+type         : aead
+async        : yes
+blocksize    : 1
+ivsize       : 12
+maxauthsize  : 16
+geniv        : <none>
+
+name         : sha256
+driver       : sha256-avx2
+module       : kernel
+type         : shash
+blocksize    : 64
+digestsize   : 32
+
+name         : aes
+driver       : aes-aesni
+module       : aesni_intel
+type         : cipher
+blocksize    : 16
+min keysize  : 16
+max keysize  : 32
+";
+
+    #[test]
+    /// Verify that the algorithms are parsed from /proc/crypto.
+    fn test_parse_proc_crypto() {
+        let algorithms = parse_proc_crypto(PROC_CRYPTO_EXCERPT).unwrap();
+        assert_eq!(
+            algorithms
+                .iter()
+                .map(|alg| alg.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["gcm(aes)", "sha256", "aes"]
+        );
+        assert_eq!(algorithms[1].digest_size, Some(Bytes(32)));
+        assert_eq!(algorithms[2].alg_type, "cipher");
+        assert_eq!(algorithms[2].max_key_size, Some(Bytes(32)));
+    }
+
+    #[test]
+    /// Verify that cipher specifications are generated, and that invalid
+    /// combinations and key sizes are rejected.
+    fn test_cipher_spec() {
+        let algorithms = parse_proc_crypto(PROC_CRYPTO_EXCERPT).unwrap();
+        let check =
+            |spec: &CipherSpec, key_size: u128| spec.check_with(Bytes(key_size), &algorithms);
+
+        let spec = CipherSpec::new("aes", Some("xts"), Some("plain64"));
+        assert_eq!(spec.to_string(), "aes-xts-plain64");
+        assert_eq!(spec.kernel_cipher(), "xts(aes)");
+        assert_matches!(check(&spec, 64), Ok(()));
+        assert_matches!(check(&spec, 32), Ok(()));
+        assert_matches!(check(&spec, 16), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+
+        let spec = CipherSpec::new("aes", Some("cbc"), Some("essiv")).set_iv_opts("sha256");
+        assert_eq!(spec.to_string(), "aes-cbc-essiv:sha256");
+        assert_matches!(check(&spec, 32), Ok(()));
+        assert_matches!(
+            check(&CipherSpec::new("aes", Some("cbc"), Some("essiv")), 32),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            check(
+                &CipherSpec::new("aes", Some("cbc"), Some("essiv")).set_iv_opts("aes"),
+                32
+            ),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let spec = CipherSpec::new("aes", Some("cbc"), Some("lmk")).set_key_count(64);
+        assert_eq!(spec.to_string(), "aes:64-cbc-lmk");
+        assert_matches!(check(&spec, 64 * 16), Ok(()));
+        assert_matches!(check(&spec, 65 * 16), Ok(()));
+        assert_matches!(
+            check(&spec, 64 * 8),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let spec = CipherSpec::capi("gcm(aes)", "random").set_integrity(CipherIntegrity::Aead);
+        assert_eq!(spec.to_string(), "capi:gcm(aes)-random");
+        assert_eq!(
+            spec.crypt_integrity(28),
+            Some(CryptIntegrity::new(28, "aead".to_string()))
+        );
+        assert_matches!(check(&spec, 32), Ok(()));
+        assert_matches!(
+            check(&CipherSpec::capi("gcm(aes)", "random"), 32),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let spec = CipherSpec::new("aes", Some("xts"), Some("random"))
+            .set_integrity(CipherIntegrity::Mac("hmac(sha256)".to_string()));
+        assert_matches!(check(&spec, 64 + 32), Ok(()));
+        assert_matches!(check(&spec, 32), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+        assert_matches!(
+            check(&CipherSpec::new("aes", Some("cbc"), None), 32),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            check(&CipherSpec::new("aes", Some("ecb"), None), 32),
+            Ok(())
+        );
+        assert_matches!(
+            check(&CipherSpec::new("aes", Some("xts"), Some("sideways")), 64),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            check(&CipherSpec::new("serpent", Some("xts"), Some("plain64")), 3),
+            Ok(())
+        );
+    }
+
+    #[test]
+    /// Verify that a hex key is redacted from the debug form of the params,
+    /// and that a keyring key is not.
+    fn test_debug_redacts_key() {
+        let device = Device::from_str("8:16").unwrap();
+        let key = "0123456789abcdef".repeat(4);
+        let params = CryptTargetParams::new(
+            "aes-xts-plain64".to_string(),
+            key.clone(),
+            0,
+            device,
+            Sectors(0),
+        );
+        let debug = format!("{params:?}");
+        assert!(!debug.contains(&key));
+        assert!(debug.contains("<redacted>"));
+        assert!(params.to_string().contains(&key));
+
+        let params = CryptTargetParams::new(
+            "aes-xts-plain64".to_string(),
+            ":64:logon:cryptsetup:key".to_string(),
+            0,
+            device,
+            Sectors(0),
+        );
+        assert!(format!("{params:?}").contains(":64:logon:cryptsetup:key"));
+    }
+
+    #[test]
+    /// Verify that keyring keys are parsed and printed, including one whose
+    /// description contains ':'.
+    fn test_keyring_key() {
+        let key = ":64:logon:cryptsetup:key"
+            .parse::<CryptKeyringKey>()
+            .unwrap();
+        assert_eq!(
+            key,
+            CryptKeyringKey::new(Bytes(64), "logon".to_string(), "cryptsetup:key".to_string())
+        );
+        assert_eq!(key.to_string(), ":64:logon:cryptsetup:key");
+        assert_matches!("64:logon:key".parse::<CryptKeyringKey>(), Err(_));
+        assert_matches!(":64:logon:".parse::<CryptKeyringKey>(), Err(_));
+        assert_matches!(":x:logon:key".parse::<CryptKeyringKey>(), Err(_));
+    }
+
+    #[test]
+    /// Verify that params made from a profile take its cipher and sector
+    /// size, and that a key not of its key size is rejected.
+    fn test_new_from_profile() {
+        let profile = CryptProfile {
+            sector_size: Some(Bytes(4096)),
+            ..CryptProfile::default()
+        };
+        let device = Device::from_str("8:16").unwrap();
+        let params =
+            CryptTargetParams::new_from_profile(&profile, "00".repeat(64), 0, device, Sectors(0))
+                .unwrap();
+        assert_eq!(params.cipher, "aes-xts-plain64");
+        assert_eq!(params.sector_size, Some(Bytes(4096)));
+        assert!(params.iv_large_sectors);
+        assert_matches!(
+            CryptTargetParams::new_from_profile(
+                &profile,
+                ":64:logon:key".to_string(),
+                0,
+                device,
+                Sectors(0)
+            ),
+            Ok(_)
+        );
+        assert_matches!(
+            CryptTargetParams::new_from_profile(&profile, "00".repeat(32), 0, device, Sectors(0)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    /// Verify that a locked crypt device is suspended with its key wiped,
+    /// so that it can not be resumed, and that it is usable again once it
+    /// is unlocked with its key.
+    fn test_lock_unlock(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let device = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let key = "0123456789abcdef".repeat(8);
+        let params = CryptTargetParams::new(
+            "aes-xts-plain64".to_string(),
+            key.clone(),
+            0,
+            device,
+            Sectors(0),
+        );
+        let table = CryptDevTargetTable::new(Sectors(0), Sectors(2048), params);
+
+        let name = test_name("crypt").expect("is valid DM name");
+        let id = DevId::Name(&name);
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        dm.table_load(&id, &table.to_raw_table(), DmOptions::default())
+            .unwrap();
+        dm.device_suspend(&id, DmOptions::private()).unwrap();
+
+        let path = Path::new("/dev/mapper").join(name.to_string());
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.write_all(&[7u8; 4096]).unwrap();
+        f.sync_all().unwrap();
+
+        crypt_lock(&dm, &id).unwrap();
+        assert!(dm
+            .device_info(&id)
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_SUSPEND));
+        assert!(dm.device_suspend(&id, DmOptions::private()).is_err());
+
+        crypt_unlock(&dm, &id, &key).unwrap();
+        assert!(!dm
+            .device_info(&id)
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_SUSPEND));
+        let mut buf = [0u8; 4096];
+        OpenOptions::new()
+            .read(true)
+            .open(&path)
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 7));
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_lock_unlock() {
+        test_with_spec(1, test_lock_unlock);
+    }
+
+    /// Verify that a crypt table, or an authenticated encryption stack,
+    /// over a device holding a filesystem signature is refused unless
+    /// forced.
+    fn test_check_signatures(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let device = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = CryptDevTargetTable::new(
+            Sectors(0),
+            Sectors(2048),
+            CryptTargetParams::new(
+                "aes-xts-plain64".to_string(),
+                "00".repeat(64),
+                0,
+                device,
+                Sectors(8),
+            ),
+        );
+        let stack = AeadCryptStack::new(
+            device,
+            Sectors(2 * 1024 * 1024),
+            &AeadCipher::aes_gcm_random(),
+            "00".repeat(32),
+            Bytes(4096),
+        )
+        .unwrap();
+        table.check_signatures(false).unwrap();
+        stack.check_signatures(false).unwrap();
+
+        let mut f = OpenOptions::new().write(true).open(paths[0]).unwrap();
+        f.write_all(b"XFSB").unwrap();
+        f.seek(SeekFrom::Start(4096)).unwrap();
+        f.write_all(b"XFSB").unwrap();
+        f.sync_all().unwrap();
+
+        assert_matches!(
+            table.check_signatures(false),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        table.check_signatures(true).unwrap();
+        assert_matches!(
+            stack.check_signatures(false),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        stack.check_signatures(true).unwrap();
+    }
+
+    #[test]
+    fn loop_test_check_signatures() {
+        test_with_spec(1, test_check_signatures);
+    }
+}
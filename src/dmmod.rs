@@ -0,0 +1,195 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Parameters of the dm_mod kernel module, which apply to every DM device,
+// e.g., the number of I/Os reserved for each device's mempools. They are
+// exposed by the kernel in sysfs; those which root may write can be tuned
+// at run time. Since they can only be found in sysfs, they are read there
+// even by a program whose DM contexts are in ioctl-only mode.
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use crate::{
+    core::errors,
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+/// The directory holding the parameters of the dm_mod module
+const DM_MOD_PARAMETERS_PATH: &str = "/sys/module/dm_mod/parameters";
+
+/// The number of I/Os reserved for the mempools of each bio-based device
+pub const DM_MOD_RESERVED_BIO_BASED_IOS: &str = "reserved_bio_based_ios";
+/// The number of I/Os reserved for the mempools of each request-based
+/// device
+pub const DM_MOD_RESERVED_RQ_BASED_IOS: &str = "reserved_rq_based_ios";
+/// The NUMA node on which the memory of new devices is allocated, -1 for
+/// any node
+pub const DM_MOD_NUMA_NODE: &str = "dm_numa_node";
+/// The number of hardware queues of request-based devices
+pub const DM_MOD_MQ_NR_HW_QUEUES: &str = "dm_mq_nr_hw_queues";
+/// The depth of the hardware queues of request-based devices
+pub const DM_MOD_MQ_QUEUE_DEPTH: &str = "dm_mq_queue_depth";
+/// The number of swap I/Os which may be in flight at once
+pub const DM_MOD_SWAP_BIOS: &str = "swap_bios";
+/// The memory allocated for dm-stats, in bytes; read-only
+pub const DM_MOD_STATS_CURRENT_ALLOCATED_BYTES: &str = "stats_current_allocated_bytes";
+
+/// A parameter of the dm_mod kernel module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DmModParameter {
+    /// The name of the parameter
+    pub name: String,
+    /// The value of the parameter, as the kernel formats it
+    pub value: String,
+    /// Whether the parameter may be changed at run time
+    pub writable: bool,
+}
+
+/// Verify that `name` names a single file in the parameters directory.
+fn check_parameter_name(name: &str) -> DmResult<()> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("\"{name}\" is not a valid module parameter name"),
+        ));
+    }
+    Ok(())
+}
+
+/// Read the parameter `name` from the parameters directory `dir`.
+fn read_parameter(dir: &Path, name: &str) -> DmResult<DmModParameter> {
+    check_parameter_name(name)?;
+    let path = dir.join(name);
+    let io_error = |err: std::io::Error| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to read {}: {}",
+            path.display(),
+            err
+        )))
+    };
+    let writable = fs::metadata(&path).map_err(io_error)?.permissions().mode() & 0o200 != 0;
+    let value = fs::read_to_string(&path).map_err(io_error)?;
+    Ok(DmModParameter {
+        name: name.to_string(),
+        value: value.trim_end().to_string(),
+        writable,
+    })
+}
+
+/// Read every parameter in the parameters directory `dir`, sorted by name.
+fn read_parameters(dir: &Path) -> DmResult<Vec<DmModParameter>> {
+    let entries = fs::read_dir(dir).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to read {}: {}",
+            dir.display(),
+            err
+        )))
+    })?;
+    let mut parameters = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+        parameters.push(read_parameter(dir, &entry.file_name().to_string_lossy())?);
+    }
+    parameters.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(parameters)
+}
+
+/// Write `value` to the parameter `name` in the parameters directory `dir`.
+fn write_parameter(dir: &Path, name: &str, value: &str) -> DmResult<()> {
+    let parameter = read_parameter(dir, name)?;
+    if !parameter.writable {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("module parameter {name} is read-only"),
+        ));
+    }
+    let path = dir.join(name);
+    fs::write(&path, value).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to write \"{}\" to {}: {}",
+            value,
+            path.display(),
+            err
+        )))
+    })?;
+    debug!(
+        "Changed dm_mod parameter {} from {} to {}",
+        name, parameter.value, value
+    );
+    Ok(())
+}
+
+/// Get every parameter of the dm_mod module, sorted by name. The
+/// parameters exist only once the module is loaded.
+pub fn dm_mod_parameters() -> DmResult<Vec<DmModParameter>> {
+    read_parameters(Path::new(DM_MOD_PARAMETERS_PATH))
+}
+
+/// Get the parameter `name` of the dm_mod module, e.g.,
+/// `DM_MOD_RESERVED_BIO_BASED_IOS`.
+pub fn dm_mod_parameter(name: &str) -> DmResult<DmModParameter> {
+    read_parameter(Path::new(DM_MOD_PARAMETERS_PATH), name)
+}
+
+/// Set the parameter `name` of the dm_mod module to `value`. Only some
+/// parameters may be changed at run time, and only by root; the kernel
+/// rejects values which are out of range. A change to a parameter such as
+/// the number of reserved I/Os takes effect for devices created after it.
+pub fn set_dm_mod_parameter(name: &str, value: &str) -> DmResult<()> {
+    write_parameter(Path::new(DM_MOD_PARAMETERS_PATH), name, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that parameters are read with their writability, that only
+    /// writable parameters are written, and that names which are not a
+    /// single file are refused.
+    fn test_parameters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        fs::write(path.join(DM_MOD_RESERVED_BIO_BASED_IOS), "16\n").unwrap();
+        fs::write(path.join(DM_MOD_STATS_CURRENT_ALLOCATED_BYTES), "0\n").unwrap();
+        fs::set_permissions(
+            path.join(DM_MOD_STATS_CURRENT_ALLOCATED_BYTES),
+            fs::Permissions::from_mode(0o444),
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_parameters(path).unwrap(),
+            vec![
+                DmModParameter {
+                    name: DM_MOD_RESERVED_BIO_BASED_IOS.to_string(),
+                    value: "16".to_string(),
+                    writable: true,
+                },
+                DmModParameter {
+                    name: DM_MOD_STATS_CURRENT_ALLOCATED_BYTES.to_string(),
+                    value: "0".to_string(),
+                    writable: false,
+                },
+            ]
+        );
+
+        write_parameter(path, DM_MOD_RESERVED_BIO_BASED_IOS, "64").unwrap();
+        assert_eq!(
+            read_parameter(path, DM_MOD_RESERVED_BIO_BASED_IOS)
+                .unwrap()
+                .value,
+            "64"
+        );
+        assert_matches!(
+            write_parameter(path, DM_MOD_STATS_CURRENT_ALLOCATED_BYTES, "1"),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            read_parameter(path, "../parameters"),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(read_parameter(path, DM_MOD_SWAP_BIOS), Err(_));
+    }
+}
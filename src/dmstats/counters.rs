@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{ops::AddAssign, str::FromStr};
+
+use crate::{
+    core::{DevId, DM},
+    dmstats::{histogram::StatsHistogram, region::parse_sector_range},
+    result::{DmError, DmResult},
+    shared::{get_status_line_fields, parse_value},
+    units::Sectors,
+};
+
+/// The I/O counters of a single area of a stats region. These correspond
+/// to the fields of /proc/diskstats. Times are in milliseconds, or in
+/// nanoseconds if the region records precise timestamps.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StatsCounters {
+    /// The number of reads completed
+    pub reads: u64,
+    /// The number of reads merged
+    pub reads_merged: u64,
+    /// The number of sectors read
+    pub read_sectors: u64,
+    /// The time spent reading
+    pub read_ticks: u64,
+    /// The number of writes completed
+    pub writes: u64,
+    /// The number of writes merged
+    pub writes_merged: u64,
+    /// The number of sectors written
+    pub write_sectors: u64,
+    /// The time spent writing
+    pub write_ticks: u64,
+    /// The number of I/Os currently in progress
+    pub in_flight: u64,
+    /// The time spent doing I/O
+    pub io_ticks: u64,
+    /// The time spent doing I/O, weighted by the number of I/Os in progress
+    pub weighted_io_ticks: u64,
+    /// The total time during which there were reads in progress
+    pub total_read_ticks: u64,
+    /// The total time during which there were writes in progress
+    pub total_write_ticks: u64,
+}
+
+impl AddAssign for StatsCounters {
+    /// Add the counters of another area, e.g., to total the counters of
+    /// all the areas of a region. The times spent doing I/O of areas which
+    /// were busy at the same time overlap, so their total may exceed the
+    /// time elapsed.
+    fn add_assign(&mut self, other: StatsCounters) {
+        self.reads += other.reads;
+        self.reads_merged += other.reads_merged;
+        self.read_sectors += other.read_sectors;
+        self.read_ticks += other.read_ticks;
+        self.writes += other.writes;
+        self.writes_merged += other.writes_merged;
+        self.write_sectors += other.write_sectors;
+        self.write_ticks += other.write_ticks;
+        self.in_flight += other.in_flight;
+        self.io_ticks += other.io_ticks;
+        self.weighted_io_ticks += other.weighted_io_ticks;
+        self.total_read_ticks += other.total_read_ticks;
+        self.total_write_ticks += other.total_write_ticks;
+    }
+}
+
+/// The counters of a single area of a stats region, as reported by
+/// "@stats_print".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsArea {
+    /// The first sector of the area
+    pub start: Sectors,
+    /// The length of the area
+    pub length: Sectors,
+    /// The I/O counters of the area
+    pub counters: StatsCounters,
+    /// The counts of the latency histogram of the area, empty if the
+    /// region has no histogram
+    pub histogram_counts: Vec<u64>,
+}
+
+impl StatsArea {
+    /// The latency histogram of the area, given the histogram boundaries of
+    /// the region to which it belongs. Returns None if the region has no
+    /// histogram.
+    pub fn histogram(&self, boundaries: &[u64]) -> DmResult<Option<StatsHistogram>> {
+        if self.histogram_counts.is_empty() {
+            return Ok(None);
+        }
+        StatsHistogram::new(boundaries, &self.histogram_counts).map(Some)
+    }
+}
+
+impl FromStr for StatsArea {
+    type Err = DmError;
+
+    /// Parse a line of "@stats_print" output, which has the format:
+    /// <start>+<length> <counters>... [<histogram counts separated by ':'>]
+    fn from_str(line: &str) -> DmResult<StatsArea> {
+        let vals = get_status_line_fields(line, 14)?;
+
+        let (start, length) = parse_sector_range(vals[0])?;
+        let histogram_counts = match vals.get(14) {
+            Some(counts) => counts
+                .split(':')
+                .map(|c| parse_value(c, "histogram count"))
+                .collect::<DmResult<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        Ok(StatsArea {
+            start,
+            length,
+            counters: StatsCounters {
+                reads: parse_value(vals[1], "reads")?,
+                reads_merged: parse_value(vals[2], "reads merged")?,
+                read_sectors: parse_value(vals[3], "sectors read")?,
+                read_ticks: parse_value(vals[4], "read ticks")?,
+                writes: parse_value(vals[5], "writes")?,
+                writes_merged: parse_value(vals[6], "writes merged")?,
+                write_sectors: parse_value(vals[7], "sectors written")?,
+                write_ticks: parse_value(vals[8], "write ticks")?,
+                in_flight: parse_value(vals[9], "I/Os in flight")?,
+                io_ticks: parse_value(vals[10], "I/O ticks")?,
+                weighted_io_ticks: parse_value(vals[11], "weighted I/O ticks")?,
+                total_read_ticks: parse_value(vals[12], "total read ticks")?,
+                total_write_ticks: parse_value(vals[13], "total write ticks")?,
+            },
+            histogram_counts,
+        })
+    }
+}
+
+/// Get the counters of every area of the stats region with the given id.
+/// If `clear` is true, the counters are atomically reset to zero, except
+/// for the count of I/Os in flight, after they are read.
+pub fn stats_print(
+    dm: &DM,
+    id: &DevId<'_>,
+    region_id: u64,
+    clear: bool,
+) -> DmResult<Vec<StatsArea>> {
+    let msg = if clear {
+        format!("@stats_print_clear {region_id}")
+    } else {
+        format!("@stats_print {region_id}")
+    };
+    dm.target_msg(id, None, &msg)?
+        .1
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(StatsArea::from_str)
+        .collect()
+}
+
+/// Reset the counters of every area of the stats region with the given id
+/// to zero, except for the count of I/Os in flight.
+pub fn stats_clear(dm: &DM, id: &DevId<'_>, region_id: u64) -> DmResult<()> {
+    dm.target_msg(id, None, &format!("@stats_clear {region_id}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::Path};
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        dmstats::{stats_create, stats_delete, StatsRange, StatsRegionSpec, StatsStep},
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that lines of "@stats_print" output are parsed correctly.
+    fn test_stats_area_parse() {
+        assert_eq!(
+            "128+128 1 2 3 4 5 6 7 8 9 10 11 12 13"
+                .parse::<StatsArea>()
+                .unwrap(),
+            StatsArea {
+                start: Sectors(128),
+                length: Sectors(128),
+                counters: StatsCounters {
+                    reads: 1,
+                    reads_merged: 2,
+                    read_sectors: 3,
+                    read_ticks: 4,
+                    writes: 5,
+                    writes_merged: 6,
+                    write_sectors: 7,
+                    write_ticks: 8,
+                    in_flight: 9,
+                    io_ticks: 10,
+                    weighted_io_ticks: 11,
+                    total_read_ticks: 12,
+                    total_write_ticks: 13,
+                },
+                histogram_counts: vec![],
+            }
+        );
+        let area = "0+128 1 2 3 4 5 6 7 8 9 10 11 12 13 4:5:6"
+            .parse::<StatsArea>()
+            .unwrap();
+        assert_eq!(area.histogram_counts, vec![4, 5, 6]);
+        assert_eq!(area.histogram(&[1, 10]).unwrap().unwrap().total(), 15);
+        assert_matches!("128+128 1 2 3".parse::<StatsArea>(), Err(_));
+    }
+
+    /// Verify that writes to a device are counted in the area they fall in
+    /// and that clearing the counters resets them.
+    fn test_print_clear(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1024),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let id = DevId::Name(ld.name());
+
+        let region_id = stats_create(
+            &dm,
+            &id,
+            &StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(2)),
+        )
+        .unwrap();
+        let histogram_id = stats_create(
+            &dm,
+            &id,
+            &StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1))
+                .set_histogram(&[1, 10])
+                .unwrap(),
+        )
+        .unwrap();
+
+        let mut f = OpenOptions::new().write(true).open(ld.devnode()).unwrap();
+        f.write_all(&[0u8; 4096]).unwrap();
+        f.sync_all().unwrap();
+
+        let areas = stats_print(&dm, &id, region_id, true).unwrap();
+        assert_eq!(areas.len(), 2);
+        assert_eq!(areas[1].start, Sectors(512));
+        assert!(areas[0].counters.writes > 0);
+        assert_eq!(areas[1].counters.writes, 0);
+
+        let areas = stats_print(&dm, &id, region_id, false).unwrap();
+        assert_eq!(areas[0].counters.writes, 0);
+
+        let areas = stats_print(&dm, &id, histogram_id, false).unwrap();
+        let histogram = areas[0].histogram(&[1, 10]).unwrap().unwrap();
+        assert_eq!(histogram.buckets().len(), 3);
+        assert_eq!(
+            histogram.total(),
+            areas[0].counters.reads + areas[0].counters.writes
+        );
+
+        stats_delete(&dm, &id, region_id).unwrap();
+        stats_delete(&dm, &id, histogram_id).unwrap();
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_print_clear() {
+        test_with_spec(1, test_print_clear);
+    }
+}
@@ -0,0 +1,314 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::File,
+    mem::size_of,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::Path,
+};
+
+use crate::{
+    core::{errors, DevId, Device, DM},
+    dmstats::{
+        group::stats_create_group,
+        region::{stats_create, stats_delete, StatsRange, StatsRegionSpec, StatsStep},
+    },
+    result::{DmError, DmResult, ErrorEnum},
+    units::{Bytes, Sectors},
+};
+
+// send IOCTL via fiemap
+ioctl_readwrite_bad!(
+    /// # Safety
+    ///
+    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
+    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
+    /// of the invoked `ioctl` command should be examined to determine the effect it will have
+    /// on the resources passed to the command.
+    fiemap,
+    request_code_readwrite!(b'f', 11, size_of::<Fiemap>()),
+    FiemapBuf
+);
+
+/// The number of extents requested from the kernel in a single FIEMAP call
+const FIEMAP_BATCH: usize = 64;
+
+/// Flush the file's dirty data before mapping it
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+
+/// The last extent of the file
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+
+/// Extents whose location on the device is not known or not meaningful
+const FIEMAP_EXTENT_NO_LOCATION: u32 = 0x2 // FIEMAP_EXTENT_UNKNOWN
+    | 0x4 // FIEMAP_EXTENT_DELALLOC
+    | 0x200; // FIEMAP_EXTENT_DATA_INLINE
+
+/// Layout of struct fiemap in linux/fiemap.h, without the trailing
+/// flexible array of extents
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+pub struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+/// Layout of struct fiemap_extent in linux/fiemap.h
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+pub struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+/// A struct fiemap followed by room for a batch of extents
+#[repr(C)]
+pub struct FiemapBuf {
+    header: Fiemap,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+/// An extent of a file, located on the device which holds the file's
+/// filesystem.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileExtent {
+    /// The offset of the extent within the file
+    pub logical: Bytes,
+    /// The first sector of the extent on the device
+    pub physical: Sectors,
+    /// The length of the extent on the device
+    pub length: Sectors,
+}
+
+/// Append an extent to a list of extents, merging it with the previous
+/// extent if the two are contiguous both in the file and on the device.
+fn push_extent(extents: &mut Vec<FileExtent>, extent: FileExtent) {
+    if let Some(last) = extents.last_mut() {
+        if last.physical + last.length == extent.physical
+            && last.logical + last.length.bytes() == extent.logical
+        {
+            last.length = last.length + extent.length;
+            return;
+        }
+    }
+    extents.push(extent);
+}
+
+/// Get the extents of the file at `path` via FIEMAP, merging extents which
+/// are contiguous. Extents which have no fixed location on the device,
+/// e.g., inline data, are omitted. Each extent is expanded, if necessary,
+/// to whole sectors.
+pub fn file_extents(path: &Path) -> DmResult<Vec<FileExtent>> {
+    let file = File::open(path).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to open {}: {}",
+            path.display(),
+            err
+        )))
+    })?;
+
+    let mut buf = FiemapBuf {
+        header: Fiemap::default(),
+        extents: [FiemapExtent::default(); FIEMAP_BATCH],
+    };
+    let mut extents = Vec::new();
+    let mut start = 0u64;
+    loop {
+        buf.header = Fiemap {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_extent_count: FIEMAP_BATCH as u32,
+            ..Default::default()
+        };
+        unsafe { fiemap(file.as_raw_fd(), &mut buf) }.map_err(|err| {
+            DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to map extents of {}: {}",
+                path.display(),
+                err
+            )))
+        })?;
+
+        let count = (buf.header.fm_mapped_extents as usize).min(FIEMAP_BATCH);
+        if count == 0 {
+            break;
+        }
+        for extent in &buf.extents[..count] {
+            if extent.fe_flags & FIEMAP_EXTENT_NO_LOCATION != 0 {
+                continue;
+            }
+            let physical = Bytes::from(extent.fe_physical).sectors();
+            let end = Bytes::from(extent.fe_physical + extent.fe_length + 511).sectors();
+            push_extent(
+                &mut extents,
+                FileExtent {
+                    logical: Bytes::from(extent.fe_logical),
+                    physical,
+                    length: end - physical,
+                },
+            );
+        }
+
+        let last = &buf.extents[count - 1];
+        if last.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+            break;
+        }
+        start = last.fe_logical + last.fe_length;
+    }
+    Ok(extents)
+}
+
+/// The stats regions created to map a file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsFileMap {
+    /// The id of the group which contains the regions
+    pub group_id: u64,
+    /// The ids of the regions, one for each extent of the file, in the
+    /// order of the extents in the file
+    pub regions: Vec<u64>,
+}
+
+/// Create a stats region for each extent of the file at `path`, which must
+/// be on a filesystem on the device `id`, and group them, using the file
+/// name as the group's alias where it is a valid alias. This provides the
+/// same per-file statistics as "dmstats create --filemap".
+///
+/// If creating any region fails, the regions already created are deleted.
+pub fn stats_create_filemap(dm: &DM, id: &DevId<'_>, path: &Path) -> DmResult<StatsFileMap> {
+    let metadata = path.metadata().map_err(|err| {
+        DmError::Core(errors::Error::MetadataIo(path.to_owned(), err.to_string()))
+    })?;
+    let file_device = Device::from(metadata.dev());
+    let device = dm.device_info(id)?.device();
+    if file_device != device {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "{} is on device {}, not on device {} ({})",
+                path.display(),
+                file_device,
+                id,
+                device
+            ),
+        ));
+    }
+
+    let extents = file_extents(path)?;
+    if extents.is_empty() {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("{} has no extents to map", path.display()),
+        ));
+    }
+
+    let alias = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| {
+            !name
+                .chars()
+                .any(|c| c.is_whitespace() || c == ':' || c == '#')
+        });
+
+    let mut regions = Vec::new();
+    let result = extents
+        .iter()
+        .try_for_each(|extent| {
+            let spec = StatsRegionSpec::new(
+                StatsRange::Range {
+                    start: extent.physical,
+                    length: extent.length,
+                },
+                StatsStep::AreaCount(1),
+            );
+            regions.push(stats_create(dm, id, &spec)?);
+            Ok(())
+        })
+        .and_then(|_| stats_create_group(dm, id, &regions, alias));
+
+    match result {
+        Ok(group_id) => Ok(StatsFileMap { group_id, regions }),
+        Err(err) => {
+            for region_id in regions {
+                if let Err(delete_err) = stats_delete(dm, id, region_id) {
+                    warn!(
+                        "Failed to delete stats region {} after error: {}",
+                        region_id, delete_err
+                    );
+                }
+            }
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the structs passed to FIEMAP have the layout the kernel
+    /// expects.
+    fn test_fiemap_struct_sizes() {
+        assert_eq!(size_of::<Fiemap>(), 32);
+        assert_eq!(size_of::<FiemapExtent>(), 56);
+    }
+
+    #[test]
+    /// Verify that only extents contiguous both in the file and on the
+    /// device are merged.
+    fn test_push_extent() {
+        let mut extents = Vec::new();
+        push_extent(
+            &mut extents,
+            FileExtent {
+                logical: Bytes(0),
+                physical: Sectors(64),
+                length: Sectors(8),
+            },
+        );
+        push_extent(
+            &mut extents,
+            FileExtent {
+                logical: Bytes(4096),
+                physical: Sectors(72),
+                length: Sectors(8),
+            },
+        );
+        push_extent(
+            &mut extents,
+            FileExtent {
+                logical: Bytes(8192),
+                physical: Sectors(128),
+                length: Sectors(8),
+            },
+        );
+        assert_eq!(
+            extents,
+            vec![
+                FileExtent {
+                    logical: Bytes(0),
+                    physical: Sectors(64),
+                    length: Sectors(16),
+                },
+                FileExtent {
+                    logical: Bytes(8192),
+                    physical: Sectors(128),
+                    length: Sectors(8),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,342 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    core::{DevId, DM},
+    dmstats::region::{check_stats_arg, stats_list, stats_set_aux, StatsRegion, DEFAULT_AUX_DATA},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::parse_value,
+};
+
+/// The prefix of aux data which records a group, as used by dmstats
+const DMS_GROUP_TAG: &str = "DMS_GROUP=";
+
+/// The separator between a group's alias and its list of regions
+const DMS_GROUP_SEP: char = ':';
+
+/// The separator between a group tag and the user's aux data
+const DMS_AUX_SEP: char = '#';
+
+/// A group of stats regions, recorded in the aux data of the region with
+/// the lowest id, which is also the id of the group.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsGroupTag {
+    /// The alias of the group, empty if it has none
+    pub alias: String,
+    /// The ids of the regions in the group, in increasing order
+    pub regions: Vec<u64>,
+}
+
+/// Format region ids as the comma separated list of ranges used by
+/// dmstats, e.g., "0-3,5".
+fn format_region_list(regions: &[u64]) -> String {
+    let mut ranges = Vec::new();
+    let mut iter = regions.iter().peekable();
+    while let Some(&first) = iter.next() {
+        let mut last = first;
+        while iter.peek() == Some(&&(last + 1)) {
+            last += 1;
+            iter.next();
+        }
+        ranges.push(if first == last {
+            first.to_string()
+        } else {
+            format!("{first}-{last}")
+        });
+    }
+    ranges.join(",")
+}
+
+/// Parse a comma separated list of ranges of region ids.
+fn parse_region_list(val: &str) -> DmResult<Vec<u64>> {
+    let mut regions = Vec::new();
+    for range in val.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let first: u64 = parse_value(first, "group region")?;
+                let last: u64 = parse_value(last, "group region")?;
+                regions.extend(first..=last);
+            }
+            None => regions.push(parse_value(range, "group region")?),
+        }
+    }
+    Ok(regions)
+}
+
+/// The aux data of a stats region, in the encoding used by dmstats: an
+/// optional group tag, followed by the user's aux data.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StatsAux {
+    /// The group of which this region is the first member, if any
+    pub group: Option<StatsGroupTag>,
+    /// The user's aux data, empty if there is none
+    pub user_data: String,
+}
+
+impl fmt::Display for StatsAux {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.group {
+            Some(ref group) => write!(
+                f,
+                "{}{}{}{}{}{}",
+                DMS_GROUP_TAG,
+                group.alias,
+                DMS_GROUP_SEP,
+                format_region_list(&group.regions),
+                DMS_AUX_SEP,
+                self.user_data
+            ),
+            None if self.user_data.is_empty() => write!(f, "{DEFAULT_AUX_DATA}"),
+            None => write!(f, "{}", self.user_data),
+        }
+    }
+}
+
+impl FromStr for StatsAux {
+    type Err = DmError;
+
+    fn from_str(val: &str) -> DmResult<StatsAux> {
+        if val == DEFAULT_AUX_DATA {
+            return Ok(StatsAux::default());
+        }
+        let tag = match val.strip_prefix(DMS_GROUP_TAG) {
+            Some(tag) => tag,
+            None => {
+                return Ok(StatsAux {
+                    group: None,
+                    user_data: val.to_owned(),
+                })
+            }
+        };
+
+        let (group, user_data) = tag.split_once(DMS_AUX_SEP).unwrap_or((tag, ""));
+        let (alias, regions) = group.rsplit_once(DMS_GROUP_SEP).ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("stats group tag \"{val}\" has no list of regions"),
+            )
+        })?;
+        Ok(StatsAux {
+            group: Some(StatsGroupTag {
+                alias: alias.to_owned(),
+                regions: parse_region_list(regions)?,
+            }),
+            user_data: user_data.to_owned(),
+        })
+    }
+}
+
+/// A group of stats regions on a device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsGroup {
+    /// The id of the group, which is the id of its first region
+    pub group_id: u64,
+    /// The alias of the group, empty if it has none
+    pub alias: String,
+    /// The regions in the group
+    pub regions: Vec<StatsRegion>,
+}
+
+/// Find the groups among the given regions, as listed by `stats_list`.
+/// Regions named in a group tag which are not among the given regions are
+/// omitted from the group.
+pub fn stats_groups(regions: &[StatsRegion]) -> DmResult<Vec<StatsGroup>> {
+    let mut groups = Vec::new();
+    for region in regions {
+        if let Some(tag) = region.aux()?.group {
+            groups.push(StatsGroup {
+                group_id: region.region_id,
+                alias: tag.alias,
+                regions: regions
+                    .iter()
+                    .filter(|r| tag.regions.contains(&r.region_id))
+                    .cloned()
+                    .collect(),
+            });
+        }
+    }
+    Ok(groups)
+}
+
+/// Group the stats regions with the given ids, giving the group the
+/// specified alias, if any. The group is recorded in the aux data of the
+/// region with the lowest id, in the same way as dmstats does, keeping the
+/// user's aux data for that region. Returns the id of the group.
+pub fn stats_create_group(
+    dm: &DM,
+    id: &DevId<'_>,
+    regions: &[u64],
+    alias: Option<&str>,
+) -> DmResult<u64> {
+    let mut regions = regions.to_vec();
+    regions.sort_unstable();
+    regions.dedup();
+
+    let group_id = *regions.first().ok_or_else(|| {
+        DmError::Dm(
+            ErrorEnum::Invalid,
+            "a stats group must contain at least one region".to_string(),
+        )
+    })?;
+    if let Some(alias) = alias {
+        check_stats_arg(alias, "group alias")?;
+        if alias.contains(DMS_GROUP_SEP) || alias.contains(DMS_AUX_SEP) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "group alias \"{alias}\" must not contain '{DMS_GROUP_SEP}' or '{DMS_AUX_SEP}'"
+                ),
+            ));
+        }
+    }
+
+    let existing = stats_list(dm, id, None)?;
+    for region_id in &regions {
+        let region = existing
+            .iter()
+            .find(|r| r.region_id == *region_id)
+            .ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::NotFound,
+                    format!("stats region {region_id} does not exist on device {id}"),
+                )
+            })?;
+        if region.aux()?.group.is_some() {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("stats region {region_id} is already the first region of a group"),
+            ));
+        }
+    }
+
+    let leader = existing
+        .iter()
+        .find(|r| r.region_id == group_id)
+        .expect("checked above");
+    let aux = StatsAux {
+        group: Some(StatsGroupTag {
+            alias: alias.unwrap_or_default().to_owned(),
+            regions,
+        }),
+        user_data: leader.aux()?.user_data,
+    };
+    stats_set_aux(dm, id, group_id, &aux.to_string())?;
+    Ok(group_id)
+}
+
+/// Remove the group with the given id, leaving its regions in place and
+/// keeping the user's aux data of its first region.
+pub fn stats_remove_group(dm: &DM, id: &DevId<'_>, group_id: u64) -> DmResult<()> {
+    let leader = stats_list(dm, id, None)?
+        .into_iter()
+        .find(|r| r.region_id == group_id)
+        .ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::NotFound,
+                format!("stats group {group_id} does not exist on device {id}"),
+            )
+        })?;
+    let aux = StatsAux {
+        group: None,
+        user_data: leader.aux()?.user_data,
+    };
+    stats_set_aux(dm, id, group_id, &aux.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        dmstats::{stats_create, StatsRange, StatsRegionSpec, StatsStep},
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+        units::Sectors,
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that region lists are formatted as ranges and parsed back.
+    fn test_region_list() {
+        assert_eq!(format_region_list(&[0, 1, 2, 3, 5, 7, 8]), "0-3,5,7-8");
+        assert_eq!(
+            parse_region_list("0-3,5,7-8").unwrap(),
+            vec![0, 1, 2, 3, 5, 7, 8]
+        );
+        assert_matches!(parse_region_list("0-x"), Err(_));
+    }
+
+    #[test]
+    /// Verify that aux data round trips through the dmstats encoding.
+    fn test_aux_round_trip() {
+        let aux = StatsAux {
+            group: Some(StatsGroupTag {
+                alias: "mygroup".to_owned(),
+                regions: vec![0, 1, 2],
+            }),
+            user_data: "label".to_owned(),
+        };
+        assert_eq!(aux.to_string(), "DMS_GROUP=mygroup:0-2#label");
+        assert_eq!(aux.to_string().parse::<StatsAux>().unwrap(), aux);
+
+        assert_eq!("-".parse::<StatsAux>().unwrap(), StatsAux::default());
+        assert_eq!(StatsAux::default().to_string(), "-");
+        assert_eq!(
+            "label".parse::<StatsAux>().unwrap(),
+            StatsAux {
+                group: None,
+                user_data: "label".to_owned(),
+            }
+        );
+        assert_matches!("DMS_GROUP=mygroup#label".parse::<StatsAux>(), Err(_));
+    }
+
+    /// Verify that a group can be created, found and removed, and that the
+    /// user's aux data survives.
+    fn test_groups(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1024),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let id = DevId::Name(ld.name());
+
+        let spec = StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1));
+        let first = stats_create(&dm, &id, &spec.clone().set_aux_data("label").unwrap()).unwrap();
+        let second = stats_create(&dm, &id, &spec).unwrap();
+
+        let group_id = stats_create_group(&dm, &id, &[second, first], Some("mygroup")).unwrap();
+        assert_eq!(group_id, first);
+
+        let regions = stats_list(&dm, &id, None).unwrap();
+        let groups = stats_groups(&regions).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].alias, "mygroup");
+        assert_eq!(groups[0].regions.len(), 2);
+
+        stats_remove_group(&dm, &id, group_id).unwrap();
+        let regions = stats_list(&dm, &id, None).unwrap();
+        assert!(stats_groups(&regions).unwrap().is_empty());
+        let leader = regions.iter().find(|r| r.region_id == first).unwrap();
+        assert_eq!(leader.aux_data, "label");
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_groups() {
+        test_with_spec(1, test_groups);
+    }
+}
@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::result::{DmError, DmResult, ErrorEnum};
+
+/// A single bucket of a latency histogram, counting the I/Os whose latency
+/// was at least `lower` and less than `upper`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HistogramBucket {
+    /// The lower bound of the bucket, inclusive
+    pub lower: u64,
+    /// The upper bound of the bucket, exclusive; None for the last bucket,
+    /// which has no upper bound
+    pub upper: Option<u64>,
+    /// The number of I/Os counted in the bucket
+    pub count: u64,
+}
+
+/// A latency histogram of a single area of a stats region. Latencies are
+/// in the units of the region's histogram boundaries: milliseconds, or
+/// nanoseconds if the region records precise timestamps.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsHistogram {
+    buckets: Vec<HistogramBucket>,
+}
+
+impl StatsHistogram {
+    /// Make a histogram from the boundaries with which the region was
+    /// created and the counts reported for an area of it. There must be one
+    /// more count than there are boundaries.
+    pub fn new(boundaries: &[u64], counts: &[u64]) -> DmResult<StatsHistogram> {
+        if counts.len() != boundaries.len() + 1 {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "{} histogram counts do not match {} histogram boundaries",
+                    counts.len(),
+                    boundaries.len()
+                ),
+            ));
+        }
+
+        let buckets = counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| HistogramBucket {
+                lower: if i == 0 { 0 } else { boundaries[i - 1] },
+                upper: boundaries.get(i).copied(),
+                count: *count,
+            })
+            .collect();
+        Ok(StatsHistogram { buckets })
+    }
+
+    /// The buckets of the histogram, in increasing order of latency.
+    pub fn buckets(&self) -> &[HistogramBucket] {
+        &self.buckets
+    }
+
+    /// The total number of I/Os counted in the histogram.
+    pub fn total(&self) -> u64 {
+        self.buckets.iter().map(|b| b.count).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that counts are assigned to buckets with the right bounds
+    /// and that mismatched counts are rejected.
+    fn test_histogram_new() {
+        let histogram = StatsHistogram::new(&[10, 100], &[1, 2, 3]).unwrap();
+        assert_eq!(
+            histogram.buckets(),
+            &[
+                HistogramBucket {
+                    lower: 0,
+                    upper: Some(10),
+                    count: 1
+                },
+                HistogramBucket {
+                    lower: 10,
+                    upper: Some(100),
+                    count: 2
+                },
+                HistogramBucket {
+                    lower: 100,
+                    upper: None,
+                    count: 3
+                },
+            ]
+        );
+        assert_eq!(histogram.total(), 6);
+
+        assert_matches!(StatsHistogram::new(&[10, 100], &[1, 2]), Err(_));
+    }
+}
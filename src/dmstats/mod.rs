@@ -0,0 +1,29 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Management of dm-stats regions, which collect I/O statistics for
+//! ranges of a DM device, via the "@stats_*" DM messages.
+
+mod counters;
+mod filemap;
+mod group;
+mod histogram;
+mod region;
+mod sampler;
+mod stack;
+
+pub use self::{
+    counters::{stats_clear, stats_print, StatsArea, StatsCounters},
+    filemap::{file_extents, stats_create_filemap, FileExtent, StatsFileMap},
+    group::{
+        stats_create_group, stats_groups, stats_remove_group, StatsAux, StatsGroup, StatsGroupTag,
+    },
+    histogram::{HistogramBucket, StatsHistogram},
+    region::{
+        stats_create, stats_delete, stats_list, stats_set_aux, StatsRange, StatsRegion,
+        StatsRegionSpec, StatsStep,
+    },
+    sampler::{StatsRates, StatsSample, StatsSampler},
+    stack::{StatsLayerSample, StatsStack, StatsStackLayer},
+};
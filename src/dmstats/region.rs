@@ -0,0 +1,494 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    core::{DevId, DM},
+    dmstats::group::StatsAux,
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{get_status_line_fields, parse_value},
+    units::Sectors,
+};
+
+/// The program id used by the kernel when none is specified
+const DEFAULT_PROGRAM_ID: &str = "-";
+
+/// The aux data used by the kernel when none is specified
+pub(crate) const DEFAULT_AUX_DATA: &str = "-";
+
+/// The range of a DM device covered by a stats region.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatsRange {
+    /// The whole device
+    WholeDevice,
+    /// A range starting at `start` and extending for `length` sectors
+    Range {
+        /// The first sector of the range
+        start: Sectors,
+        /// The length of the range
+        length: Sectors,
+    },
+}
+
+impl fmt::Display for StatsRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatsRange::WholeDevice => write!(f, "-"),
+            StatsRange::Range { start, length } => write!(f, "{}+{}", **start, **length),
+        }
+    }
+}
+
+/// How a stats region is divided into areas, each of which has its own
+/// set of counters.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatsStep {
+    /// Areas of the given size; the last area may be smaller.
+    AreaSize(Sectors),
+    /// The given number of areas of equal size.
+    AreaCount(u64),
+}
+
+impl fmt::Display for StatsStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatsStep::AreaSize(size) => write!(f, "{}", **size),
+            StatsStep::AreaCount(count) => write!(f, "/{count}"),
+        }
+    }
+}
+
+/// Verify that a value to be passed as a single argument of a stats
+/// message is non-empty and contains no whitespace.
+pub(crate) fn check_stats_arg(value: &str, desc: &str) -> DmResult<()> {
+    if value.is_empty() || value.chars().any(char::is_whitespace) {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("{desc} \"{value}\" must be non-empty and contain no whitespace"),
+        ));
+    }
+    Ok(())
+}
+
+/// Format histogram boundaries as the comma separated list used by stats
+/// messages.
+fn format_histogram_boundaries(boundaries: &[u64]) -> String {
+    boundaries
+        .iter()
+        .map(|b| b.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A specification of a stats region to create.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsRegionSpec {
+    range: StatsRange,
+    step: StatsStep,
+    precise_timestamps: bool,
+    histogram_boundaries: Vec<u64>,
+    program_id: Option<String>,
+    aux_data: Option<String>,
+}
+
+impl StatsRegionSpec {
+    /// Specify a region covering `range`, divided into areas by `step`.
+    pub fn new(range: StatsRange, step: StatsStep) -> StatsRegionSpec {
+        StatsRegionSpec {
+            range,
+            step,
+            precise_timestamps: false,
+            histogram_boundaries: Vec::new(),
+            program_id: None,
+            aux_data: None,
+        }
+    }
+
+    /// Set whether the region records times in nanoseconds rather than
+    /// milliseconds.
+    pub fn set_precise_timestamps(mut self, precise_timestamps: bool) -> StatsRegionSpec {
+        self.precise_timestamps = precise_timestamps;
+        self
+    }
+
+    /// Set the boundaries of a latency histogram to be collected for each
+    /// area of the region. The boundaries are in milliseconds, or in
+    /// nanoseconds if the region records precise timestamps, and must be
+    /// strictly increasing and non-zero. An empty list of boundaries
+    /// disables the histogram.
+    pub fn set_histogram(mut self, boundaries: &[u64]) -> DmResult<StatsRegionSpec> {
+        if boundaries.first() == Some(&0) || boundaries.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "histogram boundaries {boundaries:?} are not non-zero and strictly increasing"
+                ),
+            ));
+        }
+        self.histogram_boundaries = boundaries.to_vec();
+        Ok(self)
+    }
+
+    /// Set the program id of the region, which identifies the program which
+    /// created it. It must be non-empty and contain no whitespace.
+    pub fn set_program_id(mut self, program_id: &str) -> DmResult<StatsRegionSpec> {
+        check_stats_arg(program_id, "program id")?;
+        self.program_id = Some(program_id.to_owned());
+        Ok(self)
+    }
+
+    /// Set the aux data of the region, which is stored with the region for
+    /// use by the program which created it. It must be non-empty and
+    /// contain no whitespace. `StatsAux` may be used to construct aux data
+    /// which is compatible with the dmstats program.
+    pub fn set_aux_data(mut self, aux_data: &str) -> DmResult<StatsRegionSpec> {
+        check_stats_arg(aux_data, "aux data")?;
+        self.aux_data = Some(aux_data.to_owned());
+        Ok(self)
+    }
+
+    /// The range covered by the region.
+    pub fn range(&self) -> StatsRange {
+        self.range
+    }
+
+    /// The way the region is divided into areas.
+    pub fn step(&self) -> StatsStep {
+        self.step
+    }
+
+    /// Whether the region records times in nanoseconds.
+    pub fn precise_timestamps(&self) -> bool {
+        self.precise_timestamps
+    }
+
+    /// The boundaries of the latency histogram of the region, empty if it
+    /// has no histogram.
+    pub fn histogram_boundaries(&self) -> &[u64] {
+        &self.histogram_boundaries
+    }
+
+    /// The program id of the region, if one was set.
+    pub fn program_id(&self) -> Option<&str> {
+        self.program_id.as_deref()
+    }
+
+    /// The aux data of the region, if any was set.
+    pub fn aux_data(&self) -> Option<&str> {
+        self.aux_data.as_deref()
+    }
+
+    /// The optional arguments of the "@stats_create" message.
+    fn feature_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.precise_timestamps {
+            args.push("precise_timestamps".to_owned());
+        }
+        if !self.histogram_boundaries.is_empty() {
+            args.push(format!(
+                "histogram:{}",
+                format_histogram_boundaries(&self.histogram_boundaries)
+            ));
+        }
+        args
+    }
+
+    /// The "@stats_create" message which creates this region.
+    fn create_message(&self) -> String {
+        let feature_args = self.feature_args();
+        let mut msg = format!(
+            "@stats_create {} {} {}",
+            self.range,
+            self.step,
+            feature_args.len()
+        );
+        for arg in feature_args {
+            msg.push(' ');
+            msg.push_str(&arg);
+        }
+        msg.push(' ');
+        msg.push_str(self.program_id().unwrap_or(DEFAULT_PROGRAM_ID));
+        msg.push(' ');
+        msg.push_str(self.aux_data().unwrap_or(DEFAULT_AUX_DATA));
+        msg
+    }
+}
+
+/// A stats region, as reported by "@stats_list".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsRegion {
+    /// The id of the region
+    pub region_id: u64,
+    /// The first sector of the region
+    pub start: Sectors,
+    /// The length of the region
+    pub length: Sectors,
+    /// The size of each area of the region; the last area may be smaller
+    pub area_size: Sectors,
+    /// The program id of the region, "-" if none was set
+    pub program_id: String,
+    /// The aux data of the region, "-" if none was set
+    pub aux_data: String,
+    /// Whether the region records times in nanoseconds
+    pub precise_timestamps: bool,
+    /// The boundaries of the latency histogram of the region, empty if it
+    /// has no histogram
+    pub histogram_boundaries: Vec<u64>,
+}
+
+impl StatsRegion {
+    /// The aux data of the region, decoded into the dmstats group tag, if
+    /// any, and the user's aux data.
+    pub fn aux(&self) -> DmResult<StatsAux> {
+        self.aux_data.parse::<StatsAux>()
+    }
+}
+
+impl FromStr for StatsRegion {
+    type Err = DmError;
+
+    /// Parse a line of "@stats_list" output, which has the format:
+    /// <region_id>: <start>+<length> <area_size> <program_id> <aux_data> [<features>]
+    fn from_str(line: &str) -> DmResult<StatsRegion> {
+        let vals = get_status_line_fields(line, 5)?;
+
+        let region_id = parse_value(vals[0].trim_end_matches(':'), "region id")?;
+        let (start, length) = parse_sector_range(vals[1])?;
+        let area_size = Sectors(parse_value(vals[2], "area size")?);
+
+        let mut precise_timestamps = false;
+        let mut histogram_boundaries = Vec::new();
+        for feature in &vals[5..] {
+            if *feature == "precise_timestamps" {
+                precise_timestamps = true;
+            } else if let Some(boundaries) = feature.strip_prefix("histogram:") {
+                histogram_boundaries = boundaries
+                    .split(',')
+                    .map(|b| parse_value(b, "histogram boundary"))
+                    .collect::<DmResult<Vec<_>>>()?;
+            } else {
+                debug!("Ignoring unknown stats region feature \"{}\"", feature);
+            }
+        }
+
+        Ok(StatsRegion {
+            region_id,
+            start,
+            length,
+            area_size,
+            program_id: vals[3].to_owned(),
+            aux_data: vals[4].to_owned(),
+            precise_timestamps,
+            histogram_boundaries,
+        })
+    }
+}
+
+/// Parse a range of sectors in the "<start>+<length>" format used by
+/// stats messages.
+pub(crate) fn parse_sector_range(val: &str) -> DmResult<(Sectors, Sectors)> {
+    let (start, length) = val.split_once('+').ok_or_else(|| {
+        DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("stats range \"{val}\" is not in the format <start>+<length>"),
+        )
+    })?;
+    Ok((
+        Sectors(parse_value(start, "range start")?),
+        Sectors(parse_value(length, "range length")?),
+    ))
+}
+
+/// Send a stats message to the device and return its output, which every
+/// stats message that succeeds is expected to produce.
+pub(crate) fn stats_message(dm: &DM, id: &DevId<'_>, msg: &str) -> DmResult<String> {
+    dm.target_msg(id, None, msg)?.1.ok_or_else(|| {
+        DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("no output from message \"{msg}\" to device {id}"),
+        )
+    })
+}
+
+/// Create a stats region on the device, returning the id of the region.
+pub fn stats_create(dm: &DM, id: &DevId<'_>, spec: &StatsRegionSpec) -> DmResult<u64> {
+    let output = stats_message(dm, id, &spec.create_message())?;
+    parse_value(output.trim(), "region id")
+}
+
+/// Replace the aux data of the stats region with the given id. The aux
+/// data must be non-empty and contain no whitespace.
+pub fn stats_set_aux(dm: &DM, id: &DevId<'_>, region_id: u64, aux_data: &str) -> DmResult<()> {
+    check_stats_arg(aux_data, "aux data")?;
+    dm.target_msg(id, None, &format!("@stats_set_aux {region_id} {aux_data}"))?;
+    Ok(())
+}
+
+/// Delete the stats region with the given id from the device.
+pub fn stats_delete(dm: &DM, id: &DevId<'_>, region_id: u64) -> DmResult<()> {
+    dm.target_msg(id, None, &format!("@stats_delete {region_id}"))?;
+    Ok(())
+}
+
+/// List the stats regions of the device. If `program_id` is specified,
+/// list only the regions created with that program id.
+pub fn stats_list(dm: &DM, id: &DevId<'_>, program_id: Option<&str>) -> DmResult<Vec<StatsRegion>> {
+    let msg = match program_id {
+        Some(program_id) => {
+            check_stats_arg(program_id, "program id")?;
+            format!("@stats_list {program_id}")
+        }
+        None => "@stats_list".to_owned(),
+    };
+    // There is no output if there are no regions.
+    dm.target_msg(id, None, &msg)?
+        .1
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(StatsRegion::from_str)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that region specifications produce the expected messages.
+    fn test_create_message() {
+        assert_eq!(
+            StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1)).create_message(),
+            "@stats_create - /1 0 - -"
+        );
+        assert_eq!(
+            StatsRegionSpec::new(
+                StatsRange::Range {
+                    start: Sectors(8),
+                    length: Sectors(1024)
+                },
+                StatsStep::AreaSize(Sectors(128))
+            )
+            .set_precise_timestamps(true)
+            .set_program_id("myprog")
+            .unwrap()
+            .set_aux_data("mydata")
+            .unwrap()
+            .create_message(),
+            "@stats_create 8+1024 128 1 precise_timestamps myprog mydata"
+        );
+        assert_eq!(
+            StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1))
+                .set_histogram(&[1, 10, 100])
+                .unwrap()
+                .create_message(),
+            "@stats_create - /1 1 histogram:1,10,100 - -"
+        );
+        assert_matches!(
+            StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1))
+                .set_histogram(&[10, 10]),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1))
+                .set_program_id("my prog"),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that lines of "@stats_list" output are parsed correctly.
+    fn test_stats_region_parse() {
+        assert_eq!(
+            "0: 0+1024 1024 - -".parse::<StatsRegion>().unwrap(),
+            StatsRegion {
+                region_id: 0,
+                start: Sectors(0),
+                length: Sectors(1024),
+                area_size: Sectors(1024),
+                program_id: "-".to_owned(),
+                aux_data: "-".to_owned(),
+                precise_timestamps: false,
+                histogram_boundaries: vec![],
+            }
+        );
+        let region = "3: 8+512 128 myprog mydata precise_timestamps histogram:10,100,1000"
+            .parse::<StatsRegion>()
+            .unwrap();
+        assert_eq!(region.region_id, 3);
+        assert_eq!(region.start, Sectors(8));
+        assert_eq!(region.area_size, Sectors(128));
+        assert_eq!(region.program_id, "myprog");
+        assert!(region.precise_timestamps);
+        assert_eq!(region.histogram_boundaries, vec![10, 100, 1000]);
+
+        assert_matches!("3: 8-512 128 - -".parse::<StatsRegion>(), Err(_));
+        assert_matches!("3: 8+512 128".parse::<StatsRegion>(), Err(_));
+    }
+
+    /// Verify that regions can be created on and deleted from a device, and
+    /// that deleting a region which does not exist fails.
+    fn test_create_delete(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1024),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let id = DevId::Name(ld.name());
+
+        let whole = stats_create(
+            &dm,
+            &id,
+            &StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1)),
+        )
+        .unwrap();
+        let part = stats_create(
+            &dm,
+            &id,
+            &StatsRegionSpec::new(
+                StatsRange::Range {
+                    start: Sectors(0),
+                    length: Sectors(512),
+                },
+                StatsStep::AreaSize(Sectors(128)),
+            ),
+        )
+        .unwrap();
+        assert_ne!(whole, part);
+
+        let regions = stats_list(&dm, &id, None).unwrap();
+        assert_eq!(regions.len(), 2);
+        let region = regions.iter().find(|r| r.region_id == part).unwrap();
+        assert_eq!(region.length, Sectors(512));
+        assert_eq!(region.area_size, Sectors(128));
+
+        stats_delete(&dm, &id, whole).unwrap();
+        stats_delete(&dm, &id, part).unwrap();
+        assert_matches!(stats_delete(&dm, &id, part), Err(_));
+        assert!(stats_list(&dm, &id, None).unwrap().is_empty());
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_create_delete() {
+        test_with_spec(1, test_create_delete);
+    }
+}
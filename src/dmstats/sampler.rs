@@ -0,0 +1,366 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    core::{DevId, DmNameBuf, DM},
+    dmstats::{
+        counters::{stats_print, StatsArea, StatsCounters},
+        region::stats_list,
+    },
+    result::{DmError, DmResult, ErrorEnum},
+    units::SECTOR_SIZE,
+};
+
+/// Rates of I/O over a sampling interval, derived from the counters of a
+/// single area of a stats region.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StatsRates {
+    /// Reads completed per second
+    pub reads_per_sec: f64,
+    /// Writes completed per second
+    pub writes_per_sec: f64,
+    /// Bytes read per second
+    pub read_bytes_per_sec: f64,
+    /// Bytes written per second
+    pub write_bytes_per_sec: f64,
+    /// The average time taken to complete a read, None if there were no
+    /// reads
+    pub avg_read_latency: Option<Duration>,
+    /// The average time taken to complete a write, None if there were no
+    /// writes
+    pub avg_write_latency: Option<Duration>,
+    /// The fraction of the interval during which I/O was in progress
+    pub utilization: f64,
+}
+
+impl StatsRates {
+    /// Compute rates from counters accumulated over `interval`. If
+    /// `precise_timestamps` is true, the times in the counters are in
+    /// nanoseconds, otherwise they are in milliseconds.
+    pub fn new(
+        counters: &StatsCounters,
+        interval: Duration,
+        precise_timestamps: bool,
+    ) -> StatsRates {
+        let secs = interval.as_secs_f64();
+        if secs == 0.0 {
+            return StatsRates::default();
+        }
+
+        let ticks = |val: u64| {
+            if precise_timestamps {
+                Duration::from_nanos(val)
+            } else {
+                Duration::from_millis(val)
+            }
+        };
+        let avg_latency = |total: u64, count: u64| {
+            if count == 0 {
+                None
+            } else {
+                Some(ticks(total / count))
+            }
+        };
+
+        StatsRates {
+            reads_per_sec: counters.reads as f64 / secs,
+            writes_per_sec: counters.writes as f64 / secs,
+            read_bytes_per_sec: (counters.read_sectors as f64 * SECTOR_SIZE as f64) / secs,
+            write_bytes_per_sec: (counters.write_sectors as f64 * SECTOR_SIZE as f64) / secs,
+            avg_read_latency: avg_latency(counters.read_ticks, counters.reads),
+            avg_write_latency: avg_latency(counters.write_ticks, counters.writes),
+            utilization: (ticks(counters.io_ticks).as_secs_f64() / secs).min(1.0),
+        }
+    }
+}
+
+/// A sample of a single stats region, taken by a `StatsSampler`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsSample {
+    /// The name of the device
+    pub name: DmNameBuf,
+    /// The id of the region
+    pub region_id: u64,
+    /// The length of the interval over which the sample was taken
+    pub interval: Duration,
+    /// The counters accumulated by each area of the region over the
+    /// interval, with the rates derived from them
+    pub areas: Vec<(StatsArea, StatsRates)>,
+}
+
+/// A stats region registered with a sampler
+struct SampledRegion {
+    name: DmNameBuf,
+    region_id: u64,
+    precise_timestamps: bool,
+}
+
+impl SampledRegion {
+    /// Look up the region to find whether it records precise timestamps.
+    fn new(dm: &DM, name: DmNameBuf, region_id: u64) -> DmResult<SampledRegion> {
+        let region = stats_list(dm, &DevId::Name(&name), None)?
+            .into_iter()
+            .find(|r| r.region_id == region_id)
+            .ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::NotFound,
+                    format!("stats region {region_id} does not exist on device {name}"),
+                )
+            })?;
+        Ok(SampledRegion {
+            name,
+            region_id,
+            precise_timestamps: region.precise_timestamps,
+        })
+    }
+
+    /// Read and clear the counters of the region.
+    fn print_clear(&self, dm: &DM) -> DmResult<Vec<StatsArea>> {
+        stats_print(dm, &DevId::Name(&self.name), self.region_id, true)
+    }
+
+    /// Read and clear the counters of the region, converting them to a
+    /// sample covering the given interval.
+    fn sample(&self, dm: &DM, interval: Duration) -> DmResult<StatsSample> {
+        let areas = self
+            .print_clear(dm)?
+            .into_iter()
+            .map(|area| {
+                let rates = StatsRates::new(&area.counters, interval, self.precise_timestamps);
+                (area, rates)
+            })
+            .collect();
+        Ok(StatsSample {
+            name: self.name.clone(),
+            region_id: self.region_id,
+            interval,
+            areas,
+        })
+    }
+}
+
+/// A background thread which periodically samples a set of stats regions,
+/// clearing their counters each time, and delivers the counters and the
+/// rates derived from them to a callback. Sampling stops when the sampler
+/// is stopped or dropped.
+///
+/// Since the counters are cleared on each sample, the regions should not
+/// be sampled by anything else at the same time.
+///
+/// Samples may be delivered over a channel:
+///
+/// ```no_run
+/// use std::{sync::mpsc::channel, time::Duration};
+///
+/// use devicemapper::{DmNameBuf, StatsSampler};
+///
+/// let (sender, receiver) = channel();
+/// let sampler = StatsSampler::start(
+///     vec![(DmNameBuf::new("example-dev".to_string()).unwrap(), 0)],
+///     Duration::from_secs(1),
+///     move |sample| {
+///         sender.send(sample).ok();
+///     },
+/// );
+/// for sample in receiver.iter().take(10) {
+///     println!("{:?}", sample);
+/// }
+/// sampler.stop();
+/// ```
+#[derive(Debug)]
+pub struct StatsSampler {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatsSampler {
+    /// Start sampling each of the given regions, identified by the name of
+    /// the device and the region id, every `interval`. For each region, the
+    /// callback receives either a sample or the error which prevented one
+    /// from being taken.
+    pub fn start<F>(
+        regions: Vec<(DmNameBuf, u64)>,
+        interval: Duration,
+        mut callback: F,
+    ) -> StatsSampler
+    where
+        F: FnMut(DmResult<StatsSample>) + Send + 'static,
+    {
+        let (stop, stopped) = channel::<()>();
+        let handle = thread::spawn(move || {
+            let dm = match DM::new() {
+                Ok(dm) => dm,
+                Err(err) => {
+                    callback(Err(err));
+                    return;
+                }
+            };
+
+            let mut sampled = Vec::new();
+            for (name, region_id) in regions {
+                match SampledRegion::new(&dm, name, region_id) {
+                    Ok(region) => sampled.push(region),
+                    Err(err) => callback(Err(err)),
+                }
+            }
+
+            // Clear the counters so that the first sample covers only the
+            // first interval.
+            for region in &sampled {
+                if let Err(err) = region.print_clear(&dm) {
+                    callback(Err(err));
+                }
+            }
+
+            let mut last = Instant::now();
+            loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Ok(_) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+                let now = Instant::now();
+                let elapsed = now.duration_since(last);
+                last = now;
+                for region in &sampled {
+                    callback(region.sample(&dm, elapsed));
+                }
+            }
+        });
+
+        StatsSampler {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop sampling and wait for the sampling thread to exit.
+    pub fn stop(mut self) {
+        self.do_stop();
+    }
+
+    fn do_stop(&mut self) {
+        // Dropping the sender wakes the sampling thread.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("Stats sampling thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for StatsSampler {
+    fn drop(&mut self) {
+        self.do_stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::Path};
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        dmstats::{stats_create, stats_delete, StatsRange, StatsRegionSpec, StatsStep},
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+        units::Sectors,
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that rates are computed correctly from counters, in both
+    /// millisecond and nanosecond units.
+    fn test_stats_rates() {
+        let counters = StatsCounters {
+            reads: 100,
+            read_sectors: 800,
+            read_ticks: 200,
+            writes: 0,
+            io_ticks: 500,
+            ..Default::default()
+        };
+
+        let rates = StatsRates::new(&counters, Duration::from_secs(2), false);
+        assert_eq!(rates.reads_per_sec, 50.0);
+        assert_eq!(rates.read_bytes_per_sec, 204800.0);
+        assert_eq!(rates.avg_read_latency, Some(Duration::from_millis(2)));
+        assert_eq!(rates.avg_write_latency, None);
+        assert_eq!(rates.utilization, 0.25);
+
+        let rates = StatsRates::new(&counters, Duration::from_secs(2), true);
+        assert_eq!(rates.avg_read_latency, Some(Duration::from_nanos(2)));
+
+        assert_eq!(
+            StatsRates::new(&counters, Duration::from_secs(0), false),
+            StatsRates::default()
+        );
+    }
+
+    /// Verify that a sampler delivers samples for a registered region and
+    /// reports an error for a region which does not exist.
+    fn test_sampler(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1024),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let id = DevId::Name(ld.name());
+
+        let region_id = stats_create(
+            &dm,
+            &id,
+            &StatsRegionSpec::new(StatsRange::WholeDevice, StatsStep::AreaCount(1)),
+        )
+        .unwrap();
+
+        let (sender, receiver) = channel();
+        let sampler = StatsSampler::start(
+            vec![(name.clone(), region_id), (name.clone(), region_id + 1)],
+            Duration::from_millis(100),
+            move |sample| {
+                sender.send(sample).ok();
+            },
+        );
+
+        assert_matches!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Err(DmError::Dm(ErrorEnum::NotFound, _))
+        );
+
+        let mut f = OpenOptions::new().write(true).open(ld.devnode()).unwrap();
+        f.write_all(&[0u8; 4096]).unwrap();
+        f.sync_all().unwrap();
+
+        let sample = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sample.region_id, region_id);
+        assert_eq!(sample.areas.len(), 1);
+
+        sampler.stop();
+        stats_delete(&dm, &id, region_id).unwrap();
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_sampler() {
+        test_with_spec(1, test_sampler);
+    }
+}
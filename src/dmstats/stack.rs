@@ -0,0 +1,541 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Statistics for a stack of DM devices, e.g., a thin device on a thin pool
+// on a crypt device on a raid device. A stats region is created on each
+// layer of the stack, covering the part of that layer to which the range
+// of interest of the top device is mapped, so that the time spent by an
+// I/O may be attributed to each layer which it passes through.
+//
+// The range is followed down through targets which map it linearly to the
+// device beneath; for any other target, e.g., thin or raid, the whole of
+// each device beneath is covered, as the mapping is not known.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    core::{DevId, Device, DmFlags, DmName, DmNameBuf, DmOptions, DM},
+    dmstats::{
+        counters::{stats_print, StatsCounters},
+        region::{stats_create, stats_delete, StatsRange, StatsRegionSpec, StatsStep},
+        sampler::StatsRates,
+    },
+    result::{DmError, DmResult, ErrorEnum},
+    shared::parse_value,
+    units::Sectors,
+};
+
+/// The program id of the regions created for a stack
+const STACK_PROGRAM_ID: &str = "dmstats_stack";
+
+/// A layer of a stack of DM devices, with the stats regions created on it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsStackLayer {
+    /// The name of the device
+    pub name: DmNameBuf,
+    /// The depth of the device in the stack, 0 for the top device
+    pub depth: usize,
+    /// The ranges of the device covered by the stats regions
+    pub ranges: Vec<StatsRange>,
+    /// The DM devices directly beneath this one in the stack
+    pub lower: Vec<DmNameBuf>,
+    region_ids: Vec<u64>,
+}
+
+impl StatsStackLayer {
+    /// The ids of the stats regions created on the device, one for each
+    /// range.
+    pub fn region_ids(&self) -> &[u64] {
+        &self.region_ids
+    }
+}
+
+/// A sample of a single layer of a stack, taken by `StatsStack::sample`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsLayerSample {
+    /// The name of the device
+    pub name: DmNameBuf,
+    /// The depth of the device in the stack, 0 for the top device
+    pub depth: usize,
+    /// The counters of all the regions of the layer, totalled
+    pub counters: StatsCounters,
+    /// The rates derived from the counters
+    pub rates: StatsRates,
+    /// The part of the average read latency of the layer not accounted for
+    /// by the DM devices beneath it, None if there were no reads
+    pub added_read_latency: Option<Duration>,
+    /// The part of the average write latency of the layer not accounted
+    /// for by the DM devices beneath it, None if there were no writes
+    pub added_write_latency: Option<Duration>,
+}
+
+/// Stats regions on each layer of a stack of DM devices, which together
+/// report where in the stack the time taken by I/O to a range of the top
+/// device is spent.
+///
+/// The regions record precise timestamps and are created with the program
+/// id "dmstats_stack". Since the counters are cleared on each sample, the
+/// regions should not be sampled by anything else at the same time.
+#[derive(Debug)]
+pub struct StatsStack {
+    layers: Vec<StatsStackLayer>,
+    last_sample: Instant,
+}
+
+/// Parse a "major:minor" argument of a table, returning None if the
+/// argument is not of that form.
+fn parse_devno_arg(arg: &str) -> Option<Device> {
+    let (major, minor) = arg.split_once(':')?;
+    let numeric = |val: &str| !val.is_empty() && val.bytes().all(|b| b.is_ascii_digit());
+    if numeric(major) && numeric(minor) {
+        arg.parse::<Device>().ok()
+    } else {
+        None
+    }
+}
+
+/// The ranges of the devices beneath a device with the given table to
+/// which `range` of the device is mapped, in the order of the table.
+fn lower_ranges(
+    table: &[(u64, u64, String, String)],
+    range: StatsRange,
+) -> DmResult<Vec<(Device, StatsRange)>> {
+    let (start, end) = match range {
+        StatsRange::WholeDevice => (0, u64::MAX),
+        StatsRange::Range { start, length } => (*start, *start + *length),
+    };
+
+    let mut result = Vec::new();
+    for (line_start, line_length, target_type, params) in table {
+        let overlap_start = start.max(*line_start);
+        let overlap_end = end.min(line_start + line_length);
+        if overlap_start >= overlap_end {
+            continue;
+        }
+
+        let args = params.split(' ').collect::<Vec<_>>();
+        // The positions of the device and the offset into it of targets
+        // which map their range linearly onto a single device
+        let linear_args = match target_type.as_str() {
+            "linear" | "flakey" | "dust" => Some((0, 1)),
+            "crypt" => Some((3, 4)),
+            _ => None,
+        };
+        match linear_args {
+            Some((device_index, offset_index)) => {
+                let (device, offset) = match (args.get(device_index), args.get(offset_index)) {
+                    (Some(device), Some(offset)) => (device, offset),
+                    _ => {
+                        return Err(DmError::Dm(
+                            ErrorEnum::Invalid,
+                            format!("too few arguments for {target_type} target: \"{params}\""),
+                        ))
+                    }
+                };
+                let offset: u64 = parse_value(offset, "offset")?;
+                result.push((
+                    device.parse::<Device>()?,
+                    StatsRange::Range {
+                        start: Sectors(offset + overlap_start - line_start),
+                        length: Sectors(overlap_end - overlap_start),
+                    },
+                ));
+            }
+            None => result.extend(
+                args.iter()
+                    .filter_map(|arg| parse_devno_arg(arg))
+                    .map(|device| (device, StatsRange::WholeDevice)),
+            ),
+        }
+    }
+    Ok(result)
+}
+
+/// Add `range` to the ranges of `device`, merging it with the last range
+/// if they are contiguous. A device of which the whole is covered has a
+/// single range.
+fn add_range(ranges: &mut Vec<(Device, Vec<StatsRange>)>, device: Device, range: StatsRange) {
+    let device_ranges = match ranges.iter_mut().find(|(dev, _)| *dev == device) {
+        Some((_, device_ranges)) => device_ranges,
+        None => {
+            ranges.push((device, vec![range]));
+            return;
+        }
+    };
+    match (device_ranges.last_mut(), range) {
+        (Some(StatsRange::WholeDevice), _) => (),
+        (_, StatsRange::WholeDevice) => *device_ranges = vec![StatsRange::WholeDevice],
+        (
+            Some(StatsRange::Range {
+                start: last_start,
+                length: last_length,
+            }),
+            StatsRange::Range { start, length },
+        ) if *last_start + *last_length == start => *last_length += length,
+        _ => device_ranges.push(range),
+    }
+}
+
+/// The part of the average latency `latency` of a layer not accounted for
+/// by the average latencies of the layers beneath it. I/O to a layer which
+/// maps to several devices, e.g., a mirror, is taken to wait for the
+/// slowest of them.
+fn added_latency(
+    latency: Option<Duration>,
+    lower_latencies: impl Iterator<Item = Option<Duration>>,
+) -> Option<Duration> {
+    latency.map(|latency| {
+        latency.saturating_sub(lower_latencies.flatten().max().unwrap_or(Duration::ZERO))
+    })
+}
+
+impl StatsStack {
+    /// Create stats regions covering `range` of the device `top` and the
+    /// parts of each DM device beneath it to which the range is mapped.
+    /// If a region can not be created, those already created are deleted.
+    pub fn create(dm: &DM, top: &DmName, range: StatsRange) -> DmResult<StatsStack> {
+        let dm_devices = dm
+            .list_devices()?
+            .into_iter()
+            .map(|(name, device, _)| (device, name))
+            .collect::<HashMap<_, _>>();
+
+        let mut layers = Vec::new();
+        let mut visited = HashSet::new();
+        visited.insert(top.to_owned());
+        let mut level = vec![(top.to_owned(), vec![range])];
+        let mut depth = 0;
+        while !level.is_empty() {
+            let mut next = Vec::new();
+            for (name, ranges) in level {
+                let (_, table) = dm.table_status(
+                    &DevId::Name(&name),
+                    DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+                )?;
+                let mut lower = Vec::new();
+                for range in &ranges {
+                    for (device, lower_range) in lower_ranges(&table, *range)? {
+                        if let Some(lower_name) = dm_devices.get(&device) {
+                            if !lower.contains(lower_name) {
+                                lower.push(lower_name.clone());
+                            }
+                        }
+                        add_range(&mut next, device, lower_range);
+                    }
+                }
+                layers.push(StatsStackLayer {
+                    name,
+                    depth,
+                    ranges,
+                    lower,
+                    region_ids: Vec::new(),
+                });
+            }
+            level = next
+                .into_iter()
+                .filter_map(|(device, ranges)| {
+                    dm_devices
+                        .get(&device)
+                        .filter(|name| visited.insert((*name).clone()))
+                        .map(|name| (name.clone(), ranges))
+                })
+                .collect();
+            depth += 1;
+        }
+
+        let mut stack = StatsStack {
+            layers,
+            last_sample: Instant::now(),
+        };
+        if let Err(err) = stack.create_regions(dm) {
+            if let Err(delete_err) = stack.delete_regions(dm) {
+                warn!(
+                    "Failed to delete stats regions of stack on {} after error: {}",
+                    top, delete_err
+                );
+            }
+            return Err(err);
+        }
+        stack.last_sample = Instant::now();
+        Ok(stack)
+    }
+
+    /// Create a region for each range of each layer.
+    fn create_regions(&mut self, dm: &DM) -> DmResult<()> {
+        for layer in self.layers.iter_mut() {
+            for range in &layer.ranges {
+                let spec = StatsRegionSpec::new(*range, StatsStep::AreaCount(1))
+                    .set_precise_timestamps(true)
+                    .set_program_id(STACK_PROGRAM_ID)?;
+                layer
+                    .region_ids
+                    .push(stats_create(dm, &DevId::Name(&layer.name), &spec)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete every region created, returning the first error.
+    fn delete_regions(&mut self, dm: &DM) -> DmResult<()> {
+        let mut result = Ok(());
+        for layer in self.layers.iter_mut() {
+            for region_id in layer.region_ids.drain(..) {
+                let deleted = stats_delete(dm, &DevId::Name(&layer.name), region_id);
+                if result.is_ok() {
+                    result = deleted;
+                }
+            }
+        }
+        result
+    }
+
+    /// The layers of the stack, in order of depth.
+    pub fn layers(&self) -> &[StatsStackLayer] {
+        &self.layers
+    }
+
+    /// Read and clear the counters of every layer, returning for each
+    /// layer, in order of depth, the counters and rates over the interval
+    /// since the stack was created or last sampled, and the latency added
+    /// by the layer.
+    pub fn sample(&mut self, dm: &DM) -> DmResult<Vec<StatsLayerSample>> {
+        let interval = self.last_sample.elapsed();
+        self.last_sample = Instant::now();
+
+        let mut counters = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            let mut total = StatsCounters::default();
+            for region_id in &layer.region_ids {
+                for area in stats_print(dm, &DevId::Name(&layer.name), *region_id, true)? {
+                    total += area.counters;
+                }
+            }
+            counters.push(total);
+        }
+
+        let rates = counters
+            .iter()
+            .map(|counters| StatsRates::new(counters, interval, true))
+            .collect::<Vec<_>>();
+        let lower_rates = |layer: &StatsStackLayer| {
+            self.layers
+                .iter()
+                .zip(rates.iter())
+                .filter(|(lower, _)| layer.lower.contains(&lower.name))
+                .map(|(_, rates)| *rates)
+                .collect::<Vec<_>>()
+        };
+
+        Ok(self
+            .layers
+            .iter()
+            .zip(counters.into_iter().zip(rates.iter()))
+            .map(|(layer, (counters, rates))| {
+                let lower = lower_rates(layer);
+                StatsLayerSample {
+                    name: layer.name.clone(),
+                    depth: layer.depth,
+                    counters,
+                    rates: *rates,
+                    added_read_latency: added_latency(
+                        rates.avg_read_latency,
+                        lower.iter().map(|rates| rates.avg_read_latency),
+                    ),
+                    added_write_latency: added_latency(
+                        rates.avg_write_latency,
+                        lower.iter().map(|rates| rates.avg_write_latency),
+                    ),
+                }
+            })
+            .collect())
+    }
+
+    /// Delete the stats regions of every layer.
+    pub fn delete(mut self, dm: &DM) -> DmResult<()> {
+        self.delete_regions(dm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::Path};
+
+    use crate::{
+        core::devnode_to_devno,
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    fn line(
+        start: u64,
+        length: u64,
+        target_type: &str,
+        params: &str,
+    ) -> (u64, u64, String, String) {
+        (start, length, target_type.to_owned(), params.to_owned())
+    }
+
+    fn range(start: u64, length: u64) -> StatsRange {
+        StatsRange::Range {
+            start: Sectors(start),
+            length: Sectors(length),
+        }
+    }
+
+    #[test]
+    /// Verify that ranges are mapped through linear and crypt targets, and
+    /// that the whole of the devices beneath other targets is covered.
+    fn test_lower_ranges() {
+        let table = vec![
+            line(0, 100, "linear", "8:16 1000"),
+            line(
+                100,
+                100,
+                "crypt",
+                "aes-xts-plain64 :64:logon:key 0 8:32 2048",
+            ),
+        ];
+        assert_eq!(
+            lower_ranges(&table, range(50, 100)).unwrap(),
+            vec![
+                (Device::from_kdev_t(0x810), range(1050, 50)),
+                (Device::from_kdev_t(0x820), range(2048, 50)),
+            ]
+        );
+        assert_eq!(
+            lower_ranges(&table, StatsRange::WholeDevice).unwrap(),
+            vec![
+                (Device::from_kdev_t(0x810), range(1000, 100)),
+                (Device::from_kdev_t(0x820), range(2048, 100)),
+            ]
+        );
+        assert_eq!(lower_ranges(&table, range(200, 10)).unwrap(), vec![]);
+
+        let table = vec![line(
+            0,
+            100,
+            "thin-pool",
+            "253:1 253:2 128 0 1 skip_block_zeroing",
+        )];
+        assert_eq!(
+            lower_ranges(&table, range(0, 10)).unwrap(),
+            vec![
+                (Device::from_kdev_t(0xfd01), StatsRange::WholeDevice),
+                (Device::from_kdev_t(0xfd02), StatsRange::WholeDevice),
+            ]
+        );
+
+        assert_matches!(
+            lower_ranges(&[line(0, 100, "linear", "8:16")], range(0, 10)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that contiguous ranges of a device are merged, and that
+    /// covering the whole of a device supersedes its ranges.
+    fn test_add_range() {
+        let first = Device::from_kdev_t(0x810);
+        let second = Device::from_kdev_t(0x820);
+        let mut ranges = Vec::new();
+        add_range(&mut ranges, first, range(0, 10));
+        add_range(&mut ranges, first, range(10, 10));
+        add_range(&mut ranges, first, range(30, 10));
+        add_range(&mut ranges, second, range(0, 10));
+        assert_eq!(
+            ranges,
+            vec![
+                (first, vec![range(0, 20), range(30, 10)]),
+                (second, vec![range(0, 10)]),
+            ]
+        );
+        add_range(&mut ranges, first, StatsRange::WholeDevice);
+        add_range(&mut ranges, first, range(50, 10));
+        assert_eq!(ranges[0], (first, vec![StatsRange::WholeDevice]));
+    }
+
+    #[test]
+    /// Verify that the latency added by a layer excludes that of the
+    /// slowest layer beneath it.
+    fn test_added_latency() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            added_latency(Some(ms(10)), [Some(ms(4)), Some(ms(6)), None].into_iter()),
+            Some(ms(4))
+        );
+        assert_eq!(added_latency(Some(ms(10)), [].into_iter()), Some(ms(10)));
+        assert_eq!(
+            added_latency(Some(ms(3)), [Some(ms(4))].into_iter()),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(added_latency(None, [Some(ms(4))].into_iter()), None);
+    }
+
+    /// Verify that a stack of two linear devices is sampled at both layers.
+    fn test_stack_sample(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let lower_name = test_name("lower").expect("valid format");
+        let mut lower = LinearDev::setup(
+            &dm,
+            &lower_name,
+            None,
+            vec![TargetLine::new(
+                Sectors(0),
+                Sectors(2048),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+            )],
+        )
+        .unwrap();
+        let upper_name = test_name("upper").expect("valid format");
+        let mut upper = LinearDev::setup(
+            &dm,
+            &upper_name,
+            None,
+            vec![TargetLine::new(
+                Sectors(0),
+                Sectors(1024),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(
+                    lower.device(),
+                    Sectors(1024),
+                )),
+            )],
+        )
+        .unwrap();
+
+        let mut stack = StatsStack::create(&dm, &upper_name, StatsRange::WholeDevice).unwrap();
+        assert_eq!(stack.layers().len(), 2);
+        assert_eq!(stack.layers()[0].lower, vec![lower_name.clone()]);
+        assert_eq!(stack.layers()[1].name, lower_name);
+        assert_eq!(stack.layers()[1].ranges, vec![range(1024, 1024)]);
+
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(upper.devnode())
+            .unwrap();
+        f.write_all(&[0u8; 4096]).unwrap();
+        f.sync_all().unwrap();
+
+        let samples = stack.sample(&dm).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(samples.iter().all(|sample| sample.counters.writes > 0));
+
+        stack.delete(&dm).unwrap();
+        upper.teardown(&dm).unwrap();
+        lower.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_stack_sample() {
+        test_with_spec(1, test_stack_sample);
+    }
+}
@@ -0,0 +1,268 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Checkpoints of an era device. The era target records, for each block of
+// its origin, the era in which the block was last written. The era is
+// advanced by the checkpoint message; a backup or cache invalidation which
+// runs at some time can later find every block written since then by asking
+// for the blocks written since the era which began at that time.
+
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    core::{DevId, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{get_status_line_fields, parse_value},
+    thintools::era_invalidate,
+    units::{DataBlocks, MetaBlocks, Sectors},
+};
+
+const ERA_TARGET_NAME: &str = "era";
+
+/// The status of an era device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EraStatus {
+    /// The size of a metadata block
+    pub metadata_block_size: Sectors,
+    /// The number of metadata blocks in use
+    pub used_meta: MetaBlocks,
+    /// The number of metadata blocks
+    pub total_meta: MetaBlocks,
+    /// The current era
+    pub current_era: u32,
+    /// The location of the held metadata snapshot, if any
+    pub held_metadata_root: Option<MetaBlocks>,
+}
+
+impl FromStr for EraStatus {
+    type Err = DmError;
+
+    fn from_str(status_line: &str) -> DmResult<EraStatus> {
+        let status_vals = get_status_line_fields(status_line, 4)?;
+
+        let (used_meta, total_meta) = status_vals[1].split_once('/').ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "expected used/total metadata blocks in era status, found \"{}\"",
+                    status_vals[1]
+                ),
+            )
+        })?;
+        let held_metadata_root = match status_vals[3] {
+            "-" => None,
+            val => Some(MetaBlocks(parse_value(val, "held metadata root")?)),
+        };
+
+        Ok(EraStatus {
+            metadata_block_size: Sectors(parse_value(status_vals[0], "metadata block size")?),
+            used_meta: MetaBlocks(parse_value(used_meta, "used metadata blocks")?),
+            total_meta: MetaBlocks(parse_value(total_meta, "total metadata blocks")?),
+            current_era: parse_value(status_vals[2], "current era")?,
+            held_metadata_root,
+        })
+    }
+}
+
+/// Get the status of the era device `id`.
+pub fn era_status(dm: &DM, id: &DevId<'_>) -> DmResult<EraStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    match status.as_slice() {
+        [(_, _, target_type, status_line)] if target_type == ERA_TARGET_NAME => {
+            status_line.parse::<EraStatus>()
+        }
+        _ => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("{id} is not an era device with a single target"),
+        )),
+    }
+}
+
+/// Advance the era of the era device `id`, and return the new era.
+pub fn era_checkpoint(dm: &DM, id: &DevId<'_>) -> DmResult<u32> {
+    dm.target_msg(id, None, "checkpoint")?;
+    Ok(era_status(dm, id)?.current_era)
+}
+
+/// The blocks of the active era device `id`, whose metadata device is at
+/// `meta_path`, which have been written since the start of era `era`, up
+/// to the last checkpoint. A metadata snapshot is taken, and held while
+/// the blocks are read from it with era_invalidate.
+pub fn era_written_since(
+    dm: &DM,
+    id: &DevId<'_>,
+    meta_path: &Path,
+    era: u32,
+) -> DmResult<Vec<Range<DataBlocks>>> {
+    dm.target_msg(id, None, "take_metadata_snap")?;
+    let result = era_invalidate(meta_path, era);
+    let dropped = dm.target_msg(id, None, "drop_metadata_snap");
+    let blocks = result?;
+    dropped?;
+    Ok(blocks)
+}
+
+/// A scheduler of checkpoints of an era device, which advances the era
+/// every `interval`, if one is given, or whenever asked to, and records
+/// when each era began, so that the blocks written since some time may be
+/// found.
+///
+/// The scheduler does not run by itself; `tick` should be called
+/// periodically.
+#[derive(Clone, Debug)]
+pub struct EraCheckpointScheduler {
+    interval: Option<Duration>,
+    last_checkpoint: Option<Instant>,
+    eras: BTreeMap<u32, SystemTime>,
+}
+
+impl EraCheckpointScheduler {
+    /// Make a new scheduler, which checkpoints every `interval`, if given.
+    pub fn new(interval: Option<Duration>) -> EraCheckpointScheduler {
+        EraCheckpointScheduler {
+            interval,
+            last_checkpoint: None,
+            eras: BTreeMap::new(),
+        }
+    }
+
+    /// Advance the era of the era device `id` now, and record the time at
+    /// which the new era began. Returns the new era.
+    pub fn checkpoint(&mut self, dm: &DM, id: &DevId<'_>) -> DmResult<u32> {
+        let era = era_checkpoint(dm, id)?;
+        self.last_checkpoint = Some(Instant::now());
+        self.eras.insert(era, SystemTime::now());
+        debug!("Era device {} began era {}", id, era);
+        Ok(era)
+    }
+
+    /// Whether a scheduled checkpoint is due. The first checkpoint is due
+    /// at once.
+    pub fn checkpoint_due(&self) -> bool {
+        match (self.interval, self.last_checkpoint) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(interval), Some(last)) => last.elapsed() >= interval,
+        }
+    }
+
+    /// Checkpoint the era device `id` if a scheduled checkpoint is due.
+    /// Returns the new era, if a checkpoint was made.
+    pub fn tick(&mut self, dm: &DM, id: &DevId<'_>) -> DmResult<Option<u32>> {
+        if self.checkpoint_due() {
+            self.checkpoint(dm, id).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The eras begun by this scheduler, with the time at which each began.
+    pub fn eras(&self) -> &BTreeMap<u32, SystemTime> {
+        &self.eras
+    }
+
+    /// The era which was current at `time`, i.e., the last era begun by
+    /// this scheduler no later than `time`, if any.
+    pub fn era_at(&self, time: SystemTime) -> Option<u32> {
+        self.eras
+            .iter()
+            .rev()
+            .find(|(_, began)| **began <= time)
+            .map(|(era, _)| *era)
+    }
+
+    /// The blocks of the era device `id`, whose metadata device is at
+    /// `meta_path`, which have been written since the start of era `era`.
+    /// The blocks are in units of the era device's block size, and are
+    /// returned as ranges, in order.
+    ///
+    /// The era device is checkpointed first, so that the blocks written in
+    /// the current era are included, and a metadata snapshot is held while
+    /// the blocks are read from it.
+    pub fn changed_since(
+        &mut self,
+        dm: &DM,
+        id: &DevId<'_>,
+        meta_path: &Path,
+        era: u32,
+    ) -> DmResult<Vec<Range<DataBlocks>>> {
+        self.checkpoint(dm, id)?;
+        era_written_since(dm, id, meta_path, era)
+    }
+
+    /// The blocks of the era device `id` which have been written since
+    /// `time`, as for `changed_since`. Returns an error if no era begun
+    /// by this scheduler was current at `time`, as the blocks written
+    /// since then are not known.
+    pub fn changed_since_time(
+        &mut self,
+        dm: &DM,
+        id: &DevId<'_>,
+        meta_path: &Path,
+        time: SystemTime,
+    ) -> DmResult<Vec<Range<DataBlocks>>> {
+        let era = self.era_at(time).ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::NotFound,
+                format!("no recorded era of {id} was current at {time:?}"),
+            )
+        })?;
+        self.changed_since(dm, id, meta_path, era)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the era status is parsed, with and without a held
+    /// metadata snapshot.
+    fn test_era_status() {
+        assert_eq!(
+            "8 76/1024 3 -".parse::<EraStatus>().unwrap(),
+            EraStatus {
+                metadata_block_size: Sectors(8),
+                used_meta: MetaBlocks(76),
+                total_meta: MetaBlocks(1024),
+                current_era: 3,
+                held_metadata_root: None,
+            }
+        );
+        assert_eq!(
+            "8 76/1024 3 12"
+                .parse::<EraStatus>()
+                .unwrap()
+                .held_metadata_root,
+            Some(MetaBlocks(12))
+        );
+        assert_matches!("8 76 3 -".parse::<EraStatus>(), Err(_));
+    }
+
+    #[test]
+    /// Verify that scheduled checkpoints are due at once and then after the
+    /// interval, and that the era current at a time is found.
+    fn test_scheduler() {
+        assert!(!EraCheckpointScheduler::new(None).checkpoint_due());
+
+        let mut scheduler = EraCheckpointScheduler::new(Some(Duration::from_secs(3600)));
+        assert!(scheduler.checkpoint_due());
+        scheduler.last_checkpoint = Some(Instant::now());
+        assert!(!scheduler.checkpoint_due());
+
+        let start = SystemTime::now();
+        scheduler.eras.insert(2, start);
+        scheduler.eras.insert(3, start + Duration::from_secs(60));
+        assert_eq!(scheduler.era_at(start - Duration::from_secs(1)), None);
+        assert_eq!(scheduler.era_at(start), Some(2));
+        assert_eq!(scheduler.era_at(start + Duration::from_secs(59)), Some(2));
+        assert_eq!(scheduler.era_at(start + Duration::from_secs(3600)), Some(3));
+    }
+}
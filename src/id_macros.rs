@@ -104,6 +104,25 @@ macro_rules! str_id {
                 $B::new(&self.inner).expect("inner satisfies all correctness criteria for $B::new")
             }
         }
+
+        impl serde::Serialize for $O {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(&self.inner)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $O {
+            fn deserialize<D>(deserializer: D) -> Result<$O, D::Error>
+            where
+                D: serde::de::Deserializer<'de>,
+            {
+                $O::new(serde::Deserialize::deserialize(deserializer)?)
+                    .map_err(serde::de::Error::custom)
+            }
+        }
     };
 }
 
@@ -0,0 +1,799 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// The on-disk layout of a dm-integrity device without a separate metadata
+// device, as computed by drivers/md/dm-integrity.c when it formats the
+// device. The superblock occupies the first 4 KiB. The journal follows, in
+// sections, each holding a fixed number of journal entries and a commit
+// block. In bitmap mode the bitmap is kept in the journal area. The rest of
+// the device is divided into areas, each of a run of tags followed by the
+// interleaved data sectors which the tags protect.
+//
+// The integrity target's params select the same layout when the device is
+// formatted, which the kernel does on activation if the superblock is
+// zeroed.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::{
+    blkdev::check_signatures,
+    core::{DevId, Device, DmEventEngine, DmName, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{
+        get_status, get_status_line_fields, parse_device, parse_value, TargetLine, TargetParams,
+        TargetTable, TargetTypeBuf,
+    },
+    units::{Bytes, Sectors, SECTOR_SIZE},
+};
+
+const INTEGRITY_TARGET_NAME: &str = "integrity";
+
+/// The prefix of the optional param giving the size of the sectors which
+/// are each given a tag
+const BLOCK_SIZE_PARAM: &str = "block_size:";
+/// The prefix of the optional param giving the size of the journal
+const JOURNAL_SECTORS_PARAM: &str = "journal_sectors:";
+/// The prefix of the optional param giving the interleave sectors
+const INTERLEAVE_SECTORS_PARAM: &str = "interleave_sectors:";
+/// The prefix of the optional param giving the sectors covered by a bit of
+/// the bitmap
+const SECTORS_PER_BIT_PARAM: &str = "sectors_per_bit:";
+/// The optional param which selects the fixed padding of runs of tags
+const FIX_PADDING_PARAM: &str = "fix_padding";
+
+/// The optional param which recalculates the tags of the device from its
+/// data, with the internal hash, in the background
+const RECALCULATE_PARAM: &str = "recalculate";
+
+/// The size of the superblock
+const SB_SECTORS: u64 = 8;
+
+/// The number of sectors in a journal block, and in the commit block which
+/// ends each journal section
+const JOURNAL_BLOCK_SECTORS: u64 = 8;
+
+/// The bytes of a journal sector which may hold entries, the rest being the
+/// commit id
+const JOURNAL_SECTOR_DATA: u64 = SECTOR_SIZE as u64 - 8;
+
+/// The size of a journal entry is rounded up to a multiple of this
+const JOURNAL_ENTRY_ROUNDUP: u64 = 8;
+
+/// The largest journal the kernel makes by default
+const DEFAULT_MAX_JOURNAL_SECTORS: u64 = 131072;
+
+/// The kernel's default journal is 1/2^7 of the device, up to the maximum
+const DEFAULT_JOURNAL_SIZE_FACTOR: u32 = 7;
+
+/// The default number of data sectors in an area
+const DEFAULT_INTERLEAVE_SECTORS: Sectors = Sectors(32768);
+
+/// The permitted range of the log2 of the interleave sectors
+const MIN_LOG2_INTERLEAVE_SECTORS: u32 = 3;
+const MAX_LOG2_INTERLEAVE_SECTORS: u32 = 31;
+
+/// The padding of a run of tags, as set by the fix_padding option
+const FIXED_PADDING_BYTES: u64 = 8 << 9;
+/// The padding of a run of tags in devices formatted without fix_padding
+const LEGACY_PADDING_BYTES: u64 = 1 << 9 << 8;
+
+/// The default number of sectors covered by a bit of the bitmap
+const DEFAULT_SECTORS_PER_BITMAP_BIT: Sectors = Sectors(32768);
+
+/// Round `value` up to a multiple of `multiple`.
+fn round_up(value: u64, multiple: u64) -> u64 {
+    (value + multiple - 1) / multiple * multiple
+}
+
+/// The number of data sectors provided by a dm-integrity device which
+/// stores its tags, journal, and superblock on the device itself. The
+/// result depends on the parameters with which the device is formatted,
+/// which are set with the `set_*` methods; the defaults are those of the
+/// kernel and of `integritysetup`.
+///
+/// The data sectors provided are what a target stacked on the integrity
+/// device, such as a crypt target using authenticated encryption, may
+/// map, so it can be calculated without activating the integrity device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IntegrityLayout {
+    tag_size: u64,
+    sector_size: Bytes,
+    journal_size: Option<Sectors>,
+    interleave_sectors: Sectors,
+    fix_padding: bool,
+    bitmap_sectors_per_bit: Option<Sectors>,
+}
+
+impl IntegrityLayout {
+    /// The layout of a device storing a tag of `tag_size` bytes for each
+    /// sector, e.g., 4 for crc32c, or the tag size of an AEAD cipher.
+    pub fn new(tag_size: u64) -> IntegrityLayout {
+        IntegrityLayout {
+            tag_size,
+            sector_size: Bytes(SECTOR_SIZE as u128),
+            journal_size: None,
+            interleave_sectors: DEFAULT_INTERLEAVE_SECTORS,
+            fix_padding: true,
+            bitmap_sectors_per_bit: None,
+        }
+    }
+
+    /// Set the size of the sectors which are each given a tag, a power of
+    /// two between 512 and 4096 bytes. Consumes self.
+    pub fn set_sector_size(mut self, sector_size: Bytes) -> IntegrityLayout {
+        self.sector_size = sector_size;
+        self
+    }
+
+    /// Set the size of the journal. If not set, the kernel's default is
+    /// used: 1/128 of the device, but no more than 64 MiB. The journal is
+    /// made of whole sections, of at least one. Consumes self.
+    pub fn set_journal_size(mut self, journal_size: Sectors) -> IntegrityLayout {
+        self.journal_size = Some(journal_size);
+        self
+    }
+
+    /// Set the number of data sectors interleaved with each run of tags. It
+    /// is rounded down to a power of two. Consumes self.
+    pub fn set_interleave_sectors(mut self, interleave_sectors: Sectors) -> IntegrityLayout {
+        self.interleave_sectors = interleave_sectors;
+        self
+    }
+
+    /// Set whether runs of tags are padded as with the fix_padding option,
+    /// which is the default of `integritysetup` and makes smaller padding.
+    /// Devices formatted by older tools did not use it. Consumes self.
+    pub fn set_fix_padding(mut self, fix_padding: bool) -> IntegrityLayout {
+        self.fix_padding = fix_padding;
+        self
+    }
+
+    /// Use bitmap mode, in which each bit of a bitmap kept in the journal
+    /// area covers the given number of sectors, rather than journal mode.
+    /// Bitmap mode does not change the layout, but the bitmap must fit in
+    /// the journal area. Consumes self.
+    pub fn set_bitmap_mode(mut self, sectors_per_bit: Option<Sectors>) -> IntegrityLayout {
+        self.bitmap_sectors_per_bit =
+            Some(sectors_per_bit.unwrap_or(DEFAULT_SECTORS_PER_BITMAP_BIT));
+        self
+    }
+
+    /// The number of data sectors provided by an integrity device formatted
+    /// with this layout on a device of `device_size`. Returns an error if
+    /// the parameters are invalid or the device is too small to provide
+    /// any data sectors.
+    pub fn provided_data_sectors(&self, device_size: Sectors) -> DmResult<Sectors> {
+        let invalid = |msg: String| Err(DmError::Dm(ErrorEnum::Invalid, msg));
+
+        let sector_size = *self.sector_size as u64;
+        if !(512..=4096).contains(&sector_size) || !sector_size.is_power_of_two() {
+            return invalid(format!(
+                "integrity sector size {} is not a power of two between 512 and 4096",
+                self.sector_size
+            ));
+        }
+        if self.tag_size == 0 {
+            return invalid("integrity tag size must not be 0".to_string());
+        }
+        let log2_sectors_per_block = (sector_size / SECTOR_SIZE as u64).trailing_zeros();
+
+        // The journal entry for a block holds its sector, the last bytes of
+        // each of its sectors, and its tag.
+        let entry_size = round_up(
+            8 + 8 * (1 << log2_sectors_per_block) + self.tag_size,
+            JOURNAL_ENTRY_ROUNDUP,
+        );
+        let entries_per_sector = JOURNAL_SECTOR_DATA / entry_size;
+        if entries_per_sector == 0 {
+            return invalid(format!("integrity tag size {} is too large", self.tag_size));
+        }
+        let section_entries = entries_per_sector * JOURNAL_BLOCK_SECTORS;
+        let section_sectors = (section_entries << log2_sectors_per_block) + JOURNAL_BLOCK_SECTORS;
+        let journal_sectors = match self.journal_size {
+            Some(size) => *size,
+            None => DEFAULT_MAX_JOURNAL_SECTORS.min(*device_size >> DEFAULT_JOURNAL_SIZE_FACTOR),
+        };
+        let journal_sections = (journal_sectors / section_sectors).max(1);
+        let initial_sectors = SB_SECTORS + section_sectors * journal_sections;
+
+        let log2_interleave = (63u32.saturating_sub(self.interleave_sectors.leading_zeros()))
+            .clamp(MIN_LOG2_INTERLEAVE_SECTORS, MAX_LOG2_INTERLEAVE_SECTORS);
+        if log2_interleave < log2_sectors_per_block {
+            return invalid(format!(
+                "integrity interleave sectors {} is less than the sector size",
+                self.interleave_sectors
+            ));
+        }
+        let padding = if self.fix_padding {
+            FIXED_PADDING_BYTES
+        } else {
+            LEGACY_PADDING_BYTES
+        };
+        let metadata_run = round_up(
+            self.tag_size << (log2_interleave - log2_sectors_per_block),
+            padding,
+        ) / SECTOR_SIZE as u64;
+
+        // As the kernel does, find the most data sectors, to a multiple of
+        // 4 KiB, whose last sector is on the device.
+        let fits = |data_sectors: u64| {
+            let last = data_sectors - 1;
+            let area = last >> log2_interleave;
+            let offset = last & ((1 << log2_interleave) - 1);
+            (area << log2_interleave)
+                .checked_add((area + 1).saturating_mul(metadata_run))
+                .and_then(|sector| sector.checked_add(initial_sectors + offset))
+                .map_or(false, |sector| sector < *device_size)
+        };
+        let mut provided = 0u64;
+        for bit in (3..(64 - device_size.leading_zeros())).rev() {
+            if fits(provided | (1 << bit)) {
+                provided |= 1 << bit;
+            }
+        }
+        if provided == 0 {
+            return invalid(format!(
+                "device of size {device_size} is too small to hold an integrity device"
+            ));
+        }
+
+        if let Some(sectors_per_bit) = self.bitmap_sectors_per_bit {
+            let bits = round_up(provided, (*sectors_per_bit).max(1)) / (*sectors_per_bit).max(1);
+            let bitmap_sectors = round_up(bits, 8 * SECTOR_SIZE as u64) / (8 * SECTOR_SIZE as u64);
+            if bitmap_sectors > journal_sections * section_sectors {
+                return invalid(format!(
+                    "a bitmap of {bitmap_sectors} sectors does not fit in the integrity journal of {} sectors",
+                    journal_sections * section_sectors
+                ));
+            }
+        }
+
+        Ok(Sectors(provided))
+    }
+
+    /// The params of an integrity target, in the given mode, which formats
+    /// the device with this layout, or uses a device already formatted
+    /// with it. The tags are supplied by the target stacked on the
+    /// integrity device, as no internal hash is set. Bitmap mode is used
+    /// if it has been set, regardless of `mode`.
+    pub fn target_params(
+        &self,
+        device: Device,
+        offset: Sectors,
+        mode: IntegrityMode,
+    ) -> IntegrityTargetParams {
+        let mut params = IntegrityTargetParams::new(device, offset, Some(self.tag_size), mode);
+        if self.sector_size != Bytes(SECTOR_SIZE as u128) {
+            params.block_size = Some(self.sector_size);
+        }
+        if let Some(journal_size) = self.journal_size {
+            params
+                .optional_args
+                .push(format!("{JOURNAL_SECTORS_PARAM}{}", *journal_size));
+        }
+        if self.interleave_sectors != DEFAULT_INTERLEAVE_SECTORS {
+            params.optional_args.push(format!(
+                "{INTERLEAVE_SECTORS_PARAM}{}",
+                *self.interleave_sectors
+            ));
+        }
+        if let Some(sectors_per_bit) = self.bitmap_sectors_per_bit {
+            params.mode = IntegrityMode::Bitmap;
+            params
+                .optional_args
+                .push(format!("{SECTORS_PER_BIT_PARAM}{}", *sectors_per_bit));
+        }
+        if self.fix_padding {
+            params.optional_args.push(FIX_PADDING_PARAM.to_string());
+        }
+        params
+    }
+}
+
+/// The mode in which an integrity target keeps its data and tags
+/// consistent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IntegrityMode {
+    /// Writes are written to the journal first.
+    Journal,
+    /// Writes are written directly, without crash consistency.
+    Direct,
+    /// Writes are written directly, and a bitmap records the regions
+    /// whose tags must be recalculated after a crash.
+    Bitmap,
+    /// The journal is not replayed and no tags are checked; for data
+    /// recovery only.
+    Recovery,
+}
+
+impl IntegrityMode {
+    /// The mode as it appears in a table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntegrityMode::Journal => "J",
+            IntegrityMode::Direct => "D",
+            IntegrityMode::Bitmap => "B",
+            IntegrityMode::Recovery => "R",
+        }
+    }
+}
+
+impl FromStr for IntegrityMode {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<IntegrityMode> {
+        match s {
+            "J" => Ok(IntegrityMode::Journal),
+            "D" => Ok(IntegrityMode::Direct),
+            "B" => Ok(IntegrityMode::Bitmap),
+            "R" => Ok(IntegrityMode::Recovery),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown integrity mode \"{s}\""),
+            )),
+        }
+    }
+}
+
+/// Target params for integrity target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IntegrityTargetParams {
+    /// The device holding the data and the tags
+    pub device: Device,
+    /// The offset of the integrity superblock on the device
+    pub offset: Sectors,
+    /// The size of the tag of each sector, or None if it is the size of
+    /// the internal hash
+    pub tag_size: Option<u64>,
+    /// The consistency mode
+    pub mode: IntegrityMode,
+    /// The size of the sectors which are each given a tag, 512 bytes if
+    /// None
+    pub block_size: Option<Bytes>,
+    /// Other optional params, e.g., "journal_sectors:1024"
+    pub optional_args: Vec<String>,
+}
+
+impl IntegrityTargetParams {
+    /// Create a new IntegrityTargetParams struct
+    pub fn new(
+        device: Device,
+        offset: Sectors,
+        tag_size: Option<u64>,
+        mode: IntegrityMode,
+    ) -> IntegrityTargetParams {
+        IntegrityTargetParams {
+            device,
+            offset,
+            tag_size,
+            mode,
+            block_size: None,
+            optional_args: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for IntegrityTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <device> <offset> <tag size> <mode> [<#opt params> <opt params>]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", INTEGRITY_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for IntegrityTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<IntegrityTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() < 5 {
+            let err_msg = format!(
+                "expected at least 5 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != INTEGRITY_TARGET_NAME {
+            let err_msg = format!(
+                "Expected an integrity target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let tag_size = match vals[3] {
+            "-" => None,
+            val => Some(parse_value(val, "tag size")?),
+        };
+        let mut params = IntegrityTargetParams::new(
+            parse_device(vals[1], "block device for integrity target")?,
+            Sectors(parse_value(vals[2], "offset")?),
+            tag_size,
+            vals[4].parse::<IntegrityMode>()?,
+        );
+
+        if let Some(count) = vals.get(5) {
+            let count: usize = parse_value(count, "number of optional params")?;
+            if vals.len() != 6 + count {
+                let err_msg = format!(
+                    "expected {} optional params in params string \"{}\", found {}",
+                    count,
+                    s,
+                    vals.len() - 6
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+            for arg in &vals[6..] {
+                if let Some(size) = arg.strip_prefix(BLOCK_SIZE_PARAM) {
+                    params.block_size = Some(Bytes(parse_value(size, "integrity block size")?));
+                } else {
+                    params.optional_args.push(arg.to_string());
+                }
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+impl TargetParams for IntegrityTargetParams {
+    fn param_str(&self) -> String {
+        let mut optional_args = self.optional_args.clone();
+        if let Some(block_size) = self.block_size {
+            optional_args.push(format!("{BLOCK_SIZE_PARAM}{}", *block_size));
+        }
+
+        let mut params = format!(
+            "{} {} {} {}",
+            self.device,
+            *self.offset,
+            self.tag_size
+                .map_or_else(|| "-".to_string(), |size| size.to_string()),
+            self.mode.as_str()
+        );
+        if !optional_args.is_empty() {
+            params.push_str(&format!(
+                " {} {}",
+                optional_args.len(),
+                optional_args.join(" ")
+            ));
+        }
+        params
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(INTEGRITY_TARGET_NAME.into()).expect("INTEGRITY_TARGET_NAME is valid")
+    }
+}
+
+/// A target table for an integrity device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IntegrityDevTargetTable {
+    /// The device's table
+    pub table: TargetLine<IntegrityTargetParams>,
+}
+
+impl IntegrityDevTargetTable {
+    /// Make a new IntegrityDevTargetTable from required input
+    pub fn new(
+        start: Sectors,
+        length: Sectors,
+        params: IntegrityTargetParams,
+    ) -> IntegrityDevTargetTable {
+        IntegrityDevTargetTable {
+            table: TargetLine::new(start, length, params),
+        }
+    }
+
+    /// Verify that the device holds no signature of existing data, such as
+    /// a filesystem, where the integrity superblock would be written when
+    /// the device is formatted. If `force` is true, any signatures found
+    /// are logged and ignored.
+    pub fn check_signatures(&self, force: bool) -> DmResult<()> {
+        let params = &self.table.params;
+        check_signatures(params.device, params.offset, force)
+    }
+}
+
+impl fmt::Display for IntegrityDevTargetTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = &self.table;
+        writeln!(f, "{} {} {}", *table.start, *table.length, table.params)
+    }
+}
+
+impl TargetTable for IntegrityDevTargetTable {
+    fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<IntegrityDevTargetTable> {
+        if table.len() != 1 {
+            let err_msg = format!(
+                "IntegrityDev table should have exactly one line, has {} lines",
+                table.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let line = table.first().expect("table.len() == 1");
+        Ok(IntegrityDevTargetTable::new(
+            Sectors(line.0),
+            Sectors(line.1),
+            format!("{} {}", line.2, line.3).parse::<IntegrityTargetParams>()?,
+        ))
+    }
+
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        to_raw_table_unique!(self)
+    }
+}
+
+/// The status of an integrity target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IntegrityStatus {
+    /// The number of sectors whose tags have failed to match their data
+    pub mismatches: u64,
+    /// The number of data sectors which the device provides
+    pub provided_data_sectors: Sectors,
+    /// The sector up to which the tags have been recalculated, if the
+    /// device was activated with the recalculate param
+    pub recalculate_sector: Option<Sectors>,
+}
+
+impl IntegrityStatus {
+    /// Whether the tags are still being recalculated. Once the
+    /// recalculation is complete, the kernel continues to report its
+    /// sector, which is then the end of the data.
+    pub fn is_recalculating(&self) -> bool {
+        self.recalculate_sector
+            .map_or(false, |sector| sector < self.provided_data_sectors)
+    }
+
+    /// The percentage of the data sectors whose tags have been
+    /// recalculated, or None if the tags are not being recalculated.
+    pub fn recalculate_percent(&self) -> Option<f64> {
+        self.recalculate_sector.map(|sector| {
+            if *self.provided_data_sectors == 0 {
+                100.0
+            } else {
+                (*sector).min(*self.provided_data_sectors) as f64 * 100.0
+                    / *self.provided_data_sectors as f64
+            }
+        })
+    }
+}
+
+impl FromStr for IntegrityStatus {
+    type Err = DmError;
+
+    /// Parse a status line of the form:
+    ///
+    /// ```plain
+    /// <#mismatches> <provided data sectors> <recalculate sector | ->
+    /// ```
+    fn from_str(status_line: &str) -> DmResult<IntegrityStatus> {
+        let status_vals = get_status_line_fields(status_line, 3)?;
+        Ok(IntegrityStatus {
+            mismatches: parse_value(status_vals[0], "integrity mismatches")?,
+            provided_data_sectors: Sectors(parse_value(
+                status_vals[1],
+                "integrity provided data sectors",
+            )?),
+            recalculate_sector: match status_vals[2] {
+                "-" => None,
+                sector => Some(Sectors(parse_value(
+                    sector,
+                    "integrity recalculate sector",
+                )?)),
+            },
+        })
+    }
+}
+
+/// The status of the integrity device `id`, which has a single integrity
+/// target.
+pub fn integrity_status(dm: &DM, id: &DevId<'_>) -> DmResult<IntegrityStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    get_status(&status)?.parse()
+}
+
+/// The progress of the recalculation of the tags of an integrity device,
+/// as reported by `wait_for_recalculation`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntegrityRecalcProgress {
+    /// The tags have been recalculated up to `sector`, `percent` of the
+    /// data sectors
+    Recalculating {
+        /// The sector up to which the tags have been recalculated
+        sector: Sectors,
+        /// The percentage of the data sectors recalculated
+        percent: f64,
+    },
+    /// The tags of all the data sectors have been recalculated
+    Complete,
+}
+
+/// Wait for the recalculation of the tags of the integrity device `name`,
+/// activated with the "recalculate" param, to complete.
+///
+/// The device is watched with `engine`, so the status is sampled as soon
+/// as the device reports an event, and otherwise every `interval`, since
+/// the kernel does not report an event as the recalculation advances.
+/// `progress` is called with each sample, and with `Complete` when the
+/// recalculation has completed, when the device is no longer watched.
+/// Returns an error if the device is not recalculating its tags, or if it
+/// is removed or renamed while it is being waited for.
+pub fn wait_for_recalculation<F>(
+    engine: &mut DmEventEngine,
+    dm: &DM,
+    name: &DmName,
+    interval: Duration,
+    mut progress: F,
+) -> DmResult<()>
+where
+    F: FnMut(&IntegrityRecalcProgress),
+{
+    let status = integrity_status(dm, &DevId::Name(name))?;
+    if status.recalculate_sector.is_none() {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("integrity device {name} was not activated with the {RECALCULATE_PARAM} param"),
+        ));
+    }
+
+    let was_watched = engine.watched().iter().any(|watched| **watched == *name);
+    engine.watch(name)?;
+    let result = (|| {
+        let mut status = status;
+        while status.is_recalculating() {
+            progress(&IntegrityRecalcProgress::Recalculating {
+                sector: status.recalculate_sector.unwrap_or_default(),
+                percent: status.recalculate_percent().unwrap_or_default(),
+            });
+            let events = engine.wait(Some(interval))?;
+            if events.iter().any(|event| **event == *name)
+                && !engine.watched().iter().any(|watched| **watched == *name)
+            {
+                return Err(DmError::Dm(
+                    ErrorEnum::NotFound,
+                    format!("integrity device {name} was removed or renamed during recalculation"),
+                ));
+            }
+            status = integrity_status(dm, &DevId::Name(name))?;
+        }
+        progress(&IntegrityRecalcProgress::Complete);
+        Ok(())
+    })();
+    if !was_watched {
+        engine.unwatch(name);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify the data sectors provided on a 1 GiB device by some layouts.
+    fn test_provided_data_sectors() {
+        let size = Sectors(2 * 1024 * 1024);
+        assert_eq!(
+            IntegrityLayout::new(4).provided_data_sectors(size).unwrap(),
+            Sectors(2064392)
+        );
+        assert_eq!(
+            IntegrityLayout::new(4)
+                .set_sector_size(Bytes(4096))
+                .provided_data_sectors(size)
+                .unwrap(),
+            Sectors(2079024)
+        );
+        assert_eq!(
+            IntegrityLayout::new(32)
+                .set_sector_size(Bytes(4096))
+                .provided_data_sectors(size)
+                .unwrap(),
+            Sectors(2064392)
+        );
+        assert!(
+            IntegrityLayout::new(4)
+                .set_journal_size(Sectors(0))
+                .provided_data_sectors(size)
+                .unwrap()
+                > Sectors(2064392)
+        );
+    }
+
+    #[test]
+    /// Verify that the target params of a layout describe the same layout,
+    /// and that they are parsed and printed.
+    fn test_target_params() {
+        let device = Device::from_str("8:16").unwrap();
+        let params = IntegrityLayout::new(28)
+            .set_sector_size(Bytes(4096))
+            .target_params(device, Sectors(0), IntegrityMode::Journal);
+        assert_eq!(
+            params.to_string(),
+            "integrity 8:16 0 28 J 2 fix_padding block_size:4096"
+        );
+        assert_eq!(
+            params.to_string().parse::<IntegrityTargetParams>().unwrap(),
+            params
+        );
+
+        let params = IntegrityLayout::new(4)
+            .set_journal_size(Sectors(2048))
+            .set_bitmap_mode(None)
+            .set_fix_padding(false)
+            .target_params(device, Sectors(8), IntegrityMode::Journal);
+        assert_eq!(
+            params.param_str(),
+            "8:16 8 4 B 2 journal_sectors:2048 sectors_per_bit:32768"
+        );
+
+        let params = "integrity 8:16 0 - D 1 internal_hash:crc32c"
+            .parse::<IntegrityTargetParams>()
+            .unwrap();
+        assert_eq!(params.tag_size, None);
+        assert_eq!(params.mode, IntegrityMode::Direct);
+        assert_eq!(params.optional_args, vec!["internal_hash:crc32c"]);
+        assert_matches!(
+            "integrity 8:16 0 4 X".parse::<IntegrityTargetParams>(),
+            Err(_)
+        );
+    }
+
+    #[test]
+    /// Verify that the status is parsed, and the progress of the
+    /// recalculation of the tags reported.
+    fn test_integrity_status() {
+        let status = "0 2064392 -".parse::<IntegrityStatus>().unwrap();
+        assert_eq!(status.provided_data_sectors, Sectors(2064392));
+        assert!(!status.is_recalculating());
+        assert_eq!(status.recalculate_percent(), None);
+
+        let status = "3 2000 500".parse::<IntegrityStatus>().unwrap();
+        assert_eq!(status.mismatches, 3);
+        assert!(status.is_recalculating());
+        assert_eq!(status.recalculate_percent(), Some(25.0));
+
+        let status = "0 2000 2000".parse::<IntegrityStatus>().unwrap();
+        assert!(!status.is_recalculating());
+        assert_eq!(status.recalculate_percent(), Some(100.0));
+
+        assert_matches!("0 2000".parse::<IntegrityStatus>(), Err(_));
+        assert_matches!("0 2000 x".parse::<IntegrityStatus>(), Err(_));
+    }
+
+    #[test]
+    /// Verify that invalid parameters and tiny devices are rejected.
+    fn test_invalid_layout() {
+        let size = Sectors(2 * 1024 * 1024);
+        assert_matches!(
+            IntegrityLayout::new(4)
+                .set_sector_size(Bytes(1024 + 512))
+                .provided_data_sectors(size),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            IntegrityLayout::new(4).provided_data_sectors(Sectors(64)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            IntegrityLayout::new(4)
+                .set_journal_size(Sectors(0))
+                .set_bitmap_mode(Some(Sectors(1)))
+                .provided_data_sectors(size),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            IntegrityLayout::new(4)
+                .set_bitmap_mode(None)
+                .provided_data_sectors(size),
+            Ok(_)
+        );
+    }
+}
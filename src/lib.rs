@@ -61,6 +61,30 @@
 //! device. Handle the event(s). Update the list of last-seen `event_nr`s.
 //! 6. Optionally loop and re-invoke `poll()` on the fd to wait for more
 //! events.
+//!
+//! # Ioctl-only Mode
+//!
+//! A DM context whose `DmConfig` is set with `DmConfig::set_ioctl_only`,
+//! or created by `DM::from_env` with `DM_IOCTL_ONLY` set, never reads /proc
+//! or /sys, so that it can be used under strict seccomp or landlock
+//! policies; it uses only the DM control device, block device nodes, and
+//! their ioctls. The functions which take such a context find whatever can
+//! not be derived from ioctls by other means, or do without it:
+//!
+//! * the holders of a device are found from the tables of the DM devices,
+//! so other kinds of holders are not found
+//! * a device is found to be in use by `device_in_use` if the device at the
+//! top of its stack is claimed exclusively, but the user, e.g., a mounted
+//! filesystem, is not identified
+//! * `freeze_filesystems` can not find the mounted filesystems, so it
+//! freezes none, and the device should be suspended without
+//! `DM_SKIP_LOCKFS` so that the kernel freezes them
+//! * `device_topology` reports no discard support
+//!
+//! Functions which exist only to read or write /proc or /sys, and so take
+//! no DM context, such as `dm_mod_parameters`,
+//! `thin_pool_no_space_timeout` and `crypto_algorithms`, read them whatever
+//! the mode.
 
 #![allow(clippy::doc_markdown)]
 #![warn(missing_docs)]
@@ -77,6 +101,9 @@ extern crate log;
 /// Range macros
 #[macro_use]
 mod range_macros;
+/// proptest strategies for target params and tables
+#[cfg(feature = "proptest")]
+mod arbitrary;
 /// ID macros
 #[macro_use]
 mod id_macros;
@@ -87,22 +114,74 @@ mod core;
 /// Macros shared by device mapper devices.
 #[macro_use]
 mod shared_macros;
+/// functionality for querying and manipulating backing block devices
+mod blkdev;
 /// cachedev
 mod cachedev;
+/// the crypt target
+mod crypt;
+/// parameters of the dm_mod kernel module
+mod dmmod;
+/// per-region I/O statistics for DM devices
+mod dmstats;
+/// checkpoints of era devices
+mod era;
+/// the integrity target and the on-disk layout of its devices
+mod integrity;
 /// functions to create continuous linear space given device segments
 mod lineardev;
+/// logging writes with the log-writes target and replaying the log
+mod logwrites;
+/// prometheus-style metrics for DM devices
+#[cfg(feature = "metrics")]
+mod metrics;
+/// the mirror target and its dirty region logs
+mod mirror;
+/// dmeventd-style monitoring of DM devices
+mod monitor;
+/// path control and checking for multipath devices
+mod multipath;
+/// watching the device nodes in /dev/mapper
+mod nodewatch;
+/// per-target default parameters
+mod profiles;
+/// the raid target and control of its sync actions
+mod raid;
+/// JSON reports on DM devices
+#[cfg(feature = "json")]
+mod report;
 /// return results container
 mod result;
+/// progress events for the resynchronization of raid and mirror targets
+mod resyncmonitor;
 /// functionality shared between devices
 mod shared;
+/// snapshots of an origin device, and merging them into it
+mod snapshot;
+/// watching snapshot COW usage and extending or invalidating snapshots
+mod snapshotmonitor;
+/// activation of stacks of layered devices
+mod stack;
+/// programming the region table of switch devices
+mod switch;
 /// allocate a device from a pool
 mod thindev;
 /// the id the pool uses to track its devices
 mod thindevid;
 /// thinpooldev is shared space for  other thin provisioned devices to use
 mod thinpooldev;
+/// watching thin pool usage and extending the pool as it fills
+mod thinpoolmonitor;
+/// batches of thin pool metadata operations guarded by the transaction id
+mod thinpooltxn;
+/// checking and repairing thin pool metadata with thin-provisioning-tools
+mod thintools;
 /// representation of units used by the outer layers
 mod units;
+/// the verity target and the superblock of its hash device
+mod verity;
+/// the writecache target and writing back its dirty blocks
+mod writecache;
 
 #[cfg(test)]
 mod testing;
@@ -112,29 +191,126 @@ mod testing;
 #[macro_use]
 extern crate assert_matches;
 
+#[cfg(devicemapper41supported)]
+pub use crate::core::DmCapabilities;
+
+#[cfg(feature = "json")]
+pub use crate::report::{DmReport, ReportField};
+
+#[cfg(feature = "metrics")]
+pub use crate::metrics::{
+    record_cache_metrics, record_stats_metrics, record_thinpool_metrics, MetricsRecorder,
+    PrometheusText,
+};
+
 pub use crate::{
+    blkdev::{
+        blkdev_size, check_chunk_size, check_exclusive, check_segment_fits, check_signatures,
+        check_stripe_geometry, check_zone_aligned, device_size, device_topology, discard_sectors,
+        probe_signatures, report_zones, reset_zones, stripe_geometry, wipe_metadata_superblock,
+        wipe_sectors, zone_size, BlkDevTopology, DeviceSignature, DiscardKind, StripeGeometry,
+        Zone, ZoneCondition, ZoneType,
+    },
     cachedev::{
         CacheDev, CacheDevPerformance, CacheDevStatus, CacheDevTargetTable, CacheDevUsage,
-        CacheDevWorkingStatus, CacheTargetParams, MAX_CACHE_BLOCK_SIZE, MIN_CACHE_BLOCK_SIZE,
+        CacheDevWorkingStatus, CacheIoMode, CacheMetadataFormat, CachePolicy, CacheTargetParams,
+        CacheTunable, MAX_CACHE_BLOCK_SIZE, MIN_CACHE_BLOCK_SIZE,
     },
     consts::IEC,
     core::{
-        devnode_to_devno, errors, DevId, Device, DeviceInfo, DmFlags, DmName, DmNameBuf, DmOptions,
-        DmUdevFlags, DmUuid, DmUuidBuf, DM,
+        device_in_use, devnode_to_devno, errors, freeze_filesystems, DevId, DevIdBuf, Device,
+        DeviceInfo, DmChanges, DmConfig, DmDeviceState, DmEventEngine, DmFlags, DmHandle, DmName,
+        DmNameBuf, DmOptions, DmPool, DmRegistry, DmRegistryEntry, DmState, DmUdevFlags, DmUuid,
+        DmUuidBuf, DmUuidPrefix, EventSnapshot, FrozenFs, Holder, InUse, PrivilegeReport, DM,
+    },
+    crypt::{
+        crypt_key_set, crypt_key_wipe, crypt_lock, crypt_rekey_from_keyring, crypt_unlock,
+        crypto_algorithms, AeadCipher, AeadCryptStack, CipherIntegrity, CipherSpec,
+        CryptDevTargetTable, CryptIntegrity, CryptKeyringKey, CryptTargetParams, CryptoAlgorithm,
+    },
+    dmmod::{
+        dm_mod_parameter, dm_mod_parameters, set_dm_mod_parameter, DmModParameter,
+        DM_MOD_MQ_NR_HW_QUEUES, DM_MOD_MQ_QUEUE_DEPTH, DM_MOD_NUMA_NODE,
+        DM_MOD_RESERVED_BIO_BASED_IOS, DM_MOD_RESERVED_RQ_BASED_IOS,
+        DM_MOD_STATS_CURRENT_ALLOCATED_BYTES, DM_MOD_SWAP_BIOS,
+    },
+    dmstats::{
+        file_extents, stats_clear, stats_create, stats_create_filemap, stats_create_group,
+        stats_delete, stats_groups, stats_list, stats_print, stats_remove_group, stats_set_aux,
+        FileExtent, HistogramBucket, StatsArea, StatsAux, StatsCounters, StatsFileMap, StatsGroup,
+        StatsGroupTag, StatsHistogram, StatsLayerSample, StatsRange, StatsRates, StatsRegion,
+        StatsRegionSpec, StatsSample, StatsSampler, StatsStack, StatsStackLayer, StatsStep,
+    },
+    era::{era_checkpoint, era_status, era_written_since, EraCheckpointScheduler, EraStatus},
+    integrity::{
+        integrity_status, wait_for_recalculation, IntegrityDevTargetTable, IntegrityLayout,
+        IntegrityMode, IntegrityRecalcProgress, IntegrityStatus, IntegrityTargetParams,
     },
     lineardev::{
-        FlakeyTargetParams, LinearDev, LinearDevTargetParams, LinearDevTargetTable,
-        LinearTargetParams,
+        DustTargetParams, FlakeyTargetParams, LinearDev, LinearDevTargetParams,
+        LinearDevTargetTable, LinearTargetParams,
+    },
+    logwrites::{
+        log_writes_mark, LogWritesEntry, LogWritesFlags, LogWritesLog, LogWritesReplayEnd,
+        LogWritesTargetParams,
+    },
+    mirror::{
+        mirror_status, MirrorDevTargetTable, MirrorLegHealth, MirrorLog, MirrorLogStatus,
+        MirrorStatus, MirrorSync, MirrorTargetParams,
+    },
+    monitor::{DmMonitor, EventHandler, MonitorEvent, TargetStatus},
+    multipath::{
+        multipath_fail_if_no_path, multipath_fail_path, multipath_queue_if_no_path,
+        multipath_reinstate_path, multipath_status, multipath_switch_group, readsector0,
+        MultipathChecker, MultipathGroupState, MultipathGroupStatus, MultipathPathEvent,
+        MultipathPathStatus, MultipathStatus,
+    },
+    nodewatch::{DevMapperWatcher, NodeEvent},
+    profiles::{CacheProfile, CryptProfile, Profiles, ThinPoolProfile},
+    raid::{
+        raid_replace_device, raid_scrub, raid_status, raid_sync_action, raid_wait_sync_action,
+        Raid10Format, RaidDevTargetTable, RaidDevice, RaidDeviceHealth, RaidStatus,
+        RaidTargetParams,
     },
     result::{DmError, DmResult, ErrorEnum},
+    resyncmonitor::{ResyncAction, ResyncEvent, ResyncEventKind, ResyncMonitor, ResyncStatus},
     shared::{
-        device_exists, DmDevice, TargetLine, TargetParams, TargetTable, TargetType, TargetTypeBuf,
+        device_exists, ensure_device, read_only_options, DmDevice, TableChanges, TableSlots,
+        TargetLine, TargetParams, TargetTable, TargetType, TargetTypeBuf,
+    },
+    snapshot::{
+        merge_snapshot, revert_to_snapshot, SnapshotOriginTargetParams, SnapshotPersistence,
+        SnapshotRevertProgress, SnapshotStatus, SnapshotTargetParams,
+    },
+    snapshotmonitor::{SnapshotCowAction, SnapshotCowRequest, SnapshotMonitor},
+    stack::DeviceStack,
+    switch::{switch_region_mapping_messages, switch_set_region_mappings, SWITCH_MAX_MESSAGE_SIZE},
+    thindev::{
+        ThinDev, ThinDevTargetTable, ThinDevWorkingStatus, ThinSnapshotSpec, ThinSnapshotTree,
+        ThinStatus, ThinTargetParams,
     },
-    thindev::{ThinDev, ThinDevTargetTable, ThinDevWorkingStatus, ThinStatus, ThinTargetParams},
     thindevid::ThinDevId,
     thinpooldev::{
-        ThinPoolDev, ThinPoolDevTargetTable, ThinPoolNoSpacePolicy, ThinPoolStatus,
-        ThinPoolStatusSummary, ThinPoolTargetParams, ThinPoolUsage, ThinPoolWorkingStatus,
+        set_thin_pool_no_space_timeout, thin_pool_no_space_timeout, ThinPoolDev,
+        ThinPoolDevTargetTable, ThinPoolFeature, ThinPoolMetadataSnap, ThinPoolNoSpacePolicy,
+        ThinPoolStatus, ThinPoolStatusSummary, ThinPoolTargetParams, ThinPoolUsage,
+        ThinPoolWorkingStatus, MAX_DATA_BLOCK_SIZE, MIN_DATA_BLOCK_SIZE,
+        MIN_RECOMMENDED_METADATA_SIZE,
+    },
+    thinpoolmonitor::{ThinPoolExtendRequest, ThinPoolMonitor, ThinPoolResource},
+    thinpooltxn::{
+        set_thin_pool_transaction_id, thin_pool_transaction_id, ThinPoolOp, ThinPoolTransaction,
+    },
+    thintools::{
+        era_check, era_dump, era_invalidate, thin_check, thin_repair, EraCheckResult, EraDump,
+        ThinCheckResult,
+    },
+    units::{Bytes, DataBlocks, MetaBlocks, Sectors, MAX_META_DEV_SIZE, SECTOR_SIZE},
+    verity::{
+        verity_digest_size, VerityCorruptionMode, VerityFec, VeritySuperblock, VerityTargetParams,
+    },
+    writecache::{
+        writecache_message, writecache_status, writecache_wait_clean, WritecacheDevTargetTable,
+        WritecacheMessage, WritecacheMode, WritecacheStatus, WritecacheTargetParams,
     },
-    units::{Bytes, DataBlocks, MetaBlocks, Sectors, SECTOR_SIZE},
 };
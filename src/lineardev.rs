@@ -2,18 +2,28 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{collections::HashSet, fmt, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashSet,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use crate::{
+    blkdev::{
+        check_exclusive, check_segment_fits, check_signatures, check_zone_aligned, device_path,
+    },
     core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
     result::{DmError, DmResult, ErrorEnum},
     shared::{
         device_create, device_exists, device_match, parse_device, parse_value, DmDevice,
         TargetLine, TargetParams, TargetTable, TargetTypeBuf,
     },
-    units::Sectors,
+    units::{Bytes, Sectors},
 };
 
+const DUST_TARGET_NAME: &str = "dust";
 const FLAKEY_TARGET_NAME: &str = "flakey";
 const LINEAR_TARGET_NAME: &str = "linear";
 
@@ -315,15 +325,15 @@ impl TargetParams for FlakeyTargetParams {
         let feature_args = if self.feature_args.is_empty() {
             "0".to_owned()
         } else {
-            format!(
-                "{} {}",
-                self.feature_args.len(),
-                self.feature_args
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
+            let feature_args = self
+                .feature_args
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            // The count is of the arguments, not of the features, some of
+            // which take arguments of their own.
+            format!("{} {}", feature_args.split(' ').count(), feature_args)
         };
 
         format!(
@@ -337,9 +347,91 @@ impl TargetParams for FlakeyTargetParams {
     }
 }
 
-/// Target params for linear dev. These are either flakey or linear.
+/// Target params for dust target, which behaves as a linear target
+/// except that reads of the blocks in its bad block list fail. Bad blocks
+/// are added and removed, and failing enabled, with target messages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DustTargetParams {
+    /// The device on which this segment resides
+    pub device: Device,
+    /// The starting offset of this segment in the device
+    pub start_offset: Sectors,
+    /// The size of the blocks in the bad block list
+    pub block_size: Bytes,
+}
+
+impl DustTargetParams {
+    /// Create a new dust target param struct.
+    pub fn new(device: Device, start_offset: Sectors, block_size: Bytes) -> DustTargetParams {
+        DustTargetParams {
+            device,
+            start_offset,
+            block_size,
+        }
+    }
+}
+
+impl fmt::Display for DustTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <dev path> <offset> <block size>
+    /// ```
+    ///
+    /// where `<block size>` is in bytes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", DUST_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for DustTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<DustTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() != 4 {
+            let err_msg = format!(
+                "expected 4 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != DUST_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a dust target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let device = parse_device(vals[1], "block device for dust target")?;
+        let start_offset = Sectors(parse_value(vals[2], "physical start offset")?);
+        let block_size = Bytes(parse_value(vals[3], "block size")?);
+
+        Ok(DustTargetParams::new(device, start_offset, block_size))
+    }
+}
+
+impl TargetParams for DustTargetParams {
+    fn param_str(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.device, *self.start_offset, *self.block_size
+        )
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(DUST_TARGET_NAME.into()).expect("DUST_TARGET_NAME is valid")
+    }
+}
+
+/// Target params for linear dev. These are dust, flakey, or linear.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum LinearDevTargetParams {
+    /// A dust target
+    Dust(DustTargetParams),
     /// A flakey target
     Flakey(FlakeyTargetParams),
     /// A linear target
@@ -349,6 +441,7 @@ pub enum LinearDevTargetParams {
 impl fmt::Display for LinearDevTargetParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
+            LinearDevTargetParams::Dust(ref dust) => dust.fmt(f),
             LinearDevTargetParams::Flakey(ref flakey) => flakey.fmt(f),
             LinearDevTargetParams::Linear(ref linear) => linear.fmt(f),
         }
@@ -365,7 +458,9 @@ impl FromStr for LinearDevTargetParams {
                 format!("target line string \"{s}\" did not contain any values"),
             )
         })?;
-        if target_type == FLAKEY_TARGET_NAME {
+        if target_type == DUST_TARGET_NAME {
+            Ok(LinearDevTargetParams::Dust(s.parse::<DustTargetParams>()?))
+        } else if target_type == FLAKEY_TARGET_NAME {
             Ok(LinearDevTargetParams::Flakey(
                 s.parse::<FlakeyTargetParams>()?,
             ))
@@ -385,6 +480,7 @@ impl FromStr for LinearDevTargetParams {
 impl TargetParams for LinearDevTargetParams {
     fn param_str(&self) -> String {
         match *self {
+            LinearDevTargetParams::Dust(ref dust) => dust.param_str(),
             LinearDevTargetParams::Flakey(ref flakey) => flakey.param_str(),
             LinearDevTargetParams::Linear(ref linear) => linear.param_str(),
         }
@@ -392,14 +488,15 @@ impl TargetParams for LinearDevTargetParams {
 
     fn target_type(&self) -> TargetTypeBuf {
         match *self {
+            LinearDevTargetParams::Dust(ref dust) => dust.target_type(),
             LinearDevTargetParams::Flakey(ref flakey) => flakey.target_type(),
             LinearDevTargetParams::Linear(ref linear) => linear.target_type(),
         }
     }
 }
 
-/// A target table for a linear device. Such a table allows flakey targets
-/// as well as linear targets.
+/// A target table for a linear device. Such a table allows dust and flakey
+/// targets as well as linear targets.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct LinearDevTargetTable {
     /// The device's table
@@ -411,6 +508,127 @@ impl LinearDevTargetTable {
     pub fn new(table: Vec<TargetLine<LinearDevTargetParams>>) -> LinearDevTargetTable {
         LinearDevTargetTable { table }
     }
+
+    /// The total length of the table.
+    pub fn size(&self) -> Sectors {
+        self.table.iter().map(|line| line.length).sum()
+    }
+
+    /// Append segments, each given as a length and the params of its
+    /// target, to the end of the table.
+    pub fn append(&mut self, segments: Vec<(Sectors, LinearDevTargetParams)>) {
+        for (length, params) in segments {
+            let start = self.size();
+            self.table.push(TargetLine::new(start, length, params));
+        }
+        self.compact();
+    }
+
+    /// Merge each run of adjacent linear segments which map a contiguous
+    /// region of the same device into a single segment. A table built from
+    /// a fragmented allocation may otherwise have many more targets than
+    /// are needed, each of which the kernel must search on every I/O. Dust
+    /// and flakey segments are never merged.
+    pub fn compact(&mut self) {
+        let mut table: Vec<TargetLine<LinearDevTargetParams>> =
+            Vec::with_capacity(self.table.len());
+        for line in self.table.drain(..) {
+            if let Some(last) = table.last_mut() {
+                if let (LinearDevTargetParams::Linear(prev), LinearDevTargetParams::Linear(next)) =
+                    (&last.params, &line.params)
+                {
+                    if last.start + last.length == line.start
+                        && prev.device == next.device
+                        && prev.start_offset + last.length == next.start_offset
+                    {
+                        last.length += line.length;
+                        continue;
+                    }
+                }
+            }
+            table.push(line);
+        }
+        self.table = table;
+    }
+
+    /// Shorten the table to `size`, removing or shortening segments at its
+    /// end. Returns an error if `size` is zero or larger than the table.
+    pub fn truncate(&mut self, size: Sectors) -> DmResult<()> {
+        if size == Sectors(0) || size > self.size() {
+            let err_msg = format!(
+                "can not truncate table of length {} to {}",
+                self.size(),
+                size
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        self.table.retain(|line| line.start < size);
+        if let Some(last) = self.table.last_mut() {
+            last.length = size - last.start;
+        }
+        Ok(())
+    }
+
+    /// Verify that every segment in the table fits on its backing device.
+    pub fn check_segments_fit(&self) -> DmResult<()> {
+        for line in &self.table {
+            let (device, start_offset) = match line.params {
+                LinearDevTargetParams::Dust(ref dust) => (dust.device, dust.start_offset),
+                LinearDevTargetParams::Flakey(ref flakey) => (flakey.device, flakey.start_offset),
+                LinearDevTargetParams::Linear(ref linear) => (linear.device, linear.start_offset),
+            };
+            check_segment_fits(device, start_offset, line.length)?;
+        }
+        Ok(())
+    }
+
+    /// Verify that every segment in the table starts and ends on a zone
+    /// boundary of its backing device, as is required when the backing
+    /// devices are zoned.
+    pub fn check_zones_aligned(&self) -> DmResult<()> {
+        for line in &self.table {
+            let (device, start_offset) = match line.params {
+                LinearDevTargetParams::Dust(ref dust) => (dust.device, dust.start_offset),
+                LinearDevTargetParams::Flakey(ref flakey) => (flakey.device, flakey.start_offset),
+                LinearDevTargetParams::Linear(ref linear) => (linear.device, linear.start_offset),
+            };
+            check_zone_aligned(&device_path(device), start_offset, line.length)?;
+        }
+        Ok(())
+    }
+
+    /// Verify that no backing device of the table is claimed by any other
+    /// user, such as a mounted filesystem or another DM device.
+    pub fn check_exclusive(&self, dm: &DM) -> DmResult<()> {
+        let mut devices = HashSet::new();
+        for line in &self.table {
+            let device = match line.params {
+                LinearDevTargetParams::Dust(ref dust) => dust.device,
+                LinearDevTargetParams::Flakey(ref flakey) => flakey.device,
+                LinearDevTargetParams::Linear(ref linear) => linear.device,
+            };
+            if devices.insert(device) {
+                check_exclusive(dm, device)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify that no segment in the table begins on a signature of
+    /// existing data, such as a filesystem or a LUKS header, on its backing
+    /// device. If `force` is true, any signatures found are logged and
+    /// ignored.
+    pub fn check_signatures(&self, force: bool) -> DmResult<()> {
+        for line in &self.table {
+            let (device, start_offset) = match line.params {
+                LinearDevTargetParams::Dust(ref dust) => (dust.device, dust.start_offset),
+                LinearDevTargetParams::Flakey(ref flakey) => (flakey.device, flakey.start_offset),
+                LinearDevTargetParams::Linear(ref linear) => (linear.device, linear.start_offset),
+            };
+            check_signatures(device, start_offset, force)?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for LinearDevTargetTable {
@@ -470,13 +688,19 @@ impl DmDevice<LinearDevTargetTable> for LinearDev {
         devnode!(self)
     }
 
-    // Since linear devices have no default or configuration parameters,
-    // and the ordering of segments matters, two linear devices represent
-    // the same linear device only if their tables match exactly.
+    /// Linear devices have no default or configuration parameters, and the
+    /// ordering of segments matters, so tables are equivalent only if they
+    /// are equal once both are compacted. A table given as several
+    /// contiguous segments then matches the single segment which the
+    /// kernel was given for them.
     fn equivalent_tables(
         left: &LinearDevTargetTable,
         right: &LinearDevTargetTable,
     ) -> DmResult<bool> {
+        let mut left = left.clone();
+        left.compact();
+        let mut right = right.clone();
+        right.compact();
         Ok(left == right)
     }
 
@@ -514,6 +738,11 @@ impl LinearDev {
     /// the behavior of the linear device in that case should be treated as
     /// undefined.
     ///
+    /// Adjacent linear segments which map contiguous regions of the same
+    /// device are merged when the device is created; see
+    /// `LinearDevTargetTable::compact`. The segments of an existing device
+    /// are compared with the kernel's table once both are compacted.
+    ///
     /// Note: A linear device is just a mapping in the kernel from locations
     /// in that device to locations on other devices which are specified by
     /// their device number. There is usually a device node so that data can
@@ -539,6 +768,8 @@ impl LinearDev {
             device_match(dm, &dev, uuid)?;
             dev
         } else {
+            let mut table = table;
+            table.compact();
             let dev_info = device_create(dm, name, uuid, &table, DmOptions::private())?;
             LinearDev {
                 dev_info: Box::new(dev_info),
@@ -548,24 +779,70 @@ impl LinearDev {
         Ok(dev)
     }
 
+    /// Set up a linear device, as `setup` does, but if the device does not
+    /// already exist, refuse to create it if any of its segments begins on
+    /// a signature of existing data, unless `force` is true.
+    pub fn setup_checked(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        table: Vec<TargetLine<LinearDevTargetParams>>,
+        force: bool,
+    ) -> DmResult<LinearDev> {
+        if !device_exists(dm, name)? {
+            LinearDevTargetTable::new(table.clone()).check_signatures(force)?;
+        }
+        LinearDev::setup(dm, name, uuid, table)
+    }
+
     /// Set the segments for this linear device.
     /// This action puts the device in a state where it is ready to be resumed.
     /// Warning: It is the client's responsibility to make sure the designated
     /// segments are compatible with the device's existing segments.
     /// If they are not, this function will still succeed, but some kind of
     /// data corruption will be the inevitable result.
+    /// Adjacent linear segments which map contiguous regions of the same
+    /// device are merged into one.
     pub fn set_table(
         &mut self,
         dm: &DM,
         table: Vec<TargetLine<LinearDevTargetParams>>,
     ) -> DmResult<()> {
-        let table = LinearDevTargetTable::new(table);
+        let mut table = LinearDevTargetTable::new(table);
+        table.compact();
         self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
         self.table_load(dm, &table, DmOptions::default())?;
         self.table = table;
         Ok(())
     }
 
+    /// Grow the device by appending segments, each given as a length and the
+    /// params of its target, to its table. The device is resumed with the
+    /// new table.
+    pub fn extend(
+        &mut self,
+        dm: &DM,
+        segments: Vec<(Sectors, LinearDevTargetParams)>,
+    ) -> DmResult<()> {
+        let mut table = self.table.clone();
+        table.append(segments);
+        self.set_table(dm, table.table)?;
+        self.resume(dm)
+    }
+
+    /// Shrink the device to `size` by removing or shortening segments at
+    /// the end of its table. The device is resumed with the new table.
+    /// Use `extend` to grow the device.
+    pub fn set_size(&mut self, dm: &DM, size: Sectors) -> DmResult<()> {
+        if size == self.size() {
+            return Ok(());
+        }
+        let mut table = self.table.clone();
+        table.truncate(size)?;
+        self.set_table(dm, table.table)?;
+        self.resume(dm)
+    }
+
     /// Set the name for this LinearDev.
     pub fn set_name(&mut self, dm: &DM, name: &DmName) -> DmResult<()> {
         if self.name() == name {
@@ -575,14 +852,118 @@ impl LinearDev {
         self.dev_info = Box::new(dm.device_info(&DevId::Name(name))?);
         Ok(())
     }
+
+    /// Replace each linear segment of this device with a dust segment
+    /// mapping the same sectors, mark the given sectors of the device as
+    /// bad, so that reads of them fail, and run `f`. Afterwards, even if `f`
+    /// panics, the device's original table is restored.
+    ///
+    /// Returns an error if any segment of the device is not linear, or if
+    /// any bad sector is beyond the end of the device.
+    pub fn with_bad_blocks<F, T>(&mut self, dm: &DM, bad_sectors: &[Sectors], f: F) -> DmResult<T>
+    where
+        F: FnOnce(&LinearDev) -> T,
+    {
+        if let Some(sector) = bad_sectors.iter().find(|sector| **sector >= self.size()) {
+            let err_msg = format!(
+                "bad sector {} is beyond the end of device {}",
+                sector,
+                self.name()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let original = self.table.clone();
+        let dust_table = original
+            .table
+            .iter()
+            .map(|line| match line.params {
+                LinearDevTargetParams::Linear(ref linear) => Ok(TargetLine::new(
+                    line.start,
+                    line.length,
+                    LinearDevTargetParams::Dust(DustTargetParams::new(
+                        linear.device,
+                        linear.start_offset,
+                        Sectors(1).bytes(),
+                    )),
+                )),
+                _ => {
+                    let err_msg = format!(
+                        "segment at {} of device {} is not linear",
+                        line.start,
+                        self.name()
+                    );
+                    Err(DmError::Dm(ErrorEnum::Invalid, err_msg))
+                }
+            })
+            .collect::<DmResult<Vec<_>>>()?;
+
+        self.set_table(dm, dust_table)?;
+        self.resume(dm)?;
+
+        // The device is restored before any panic in f is resumed.
+        let result = self
+            .program_bad_blocks(dm, bad_sectors)
+            .map(|_| panic::catch_unwind(AssertUnwindSafe(|| f(self))));
+
+        let restored = self
+            .set_table(dm, original.table)
+            .and_then(|_| self.resume(dm));
+
+        match result {
+            Ok(Ok(value)) => restored.map(|_| value),
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Add the given sectors to the bad block lists of this device's dust
+    /// segments, and enable failing reads of them. dm-dust numbers the
+    /// blocks of its list from the start of the underlying device, not of
+    /// the segment, in units of its block size.
+    fn program_bad_blocks(&self, dm: &DM, bad_sectors: &[Sectors]) -> DmResult<()> {
+        let id = DevId::Name(self.name());
+        for sector in bad_sectors {
+            let (line, dust) = self
+                .table
+                .table
+                .iter()
+                .find(|line| line.start <= *sector && *sector < line.start + line.length)
+                .and_then(|line| match line.params {
+                    LinearDevTargetParams::Dust(ref dust) => Some((line, dust)),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    let err_msg = format!(
+                        "bad sector {} is not within a dust segment of device {}",
+                        sector,
+                        self.name()
+                    );
+                    DmError::Dm(ErrorEnum::Invalid, err_msg)
+                })?;
+            let block =
+                (*dust.start_offset + (**sector - *line.start)) / *dust.block_size.sectors();
+            dm.target_msg(&id, Some(*line.start), &format!("addbadblock {block}"))?;
+        }
+        for line in &self.table.table {
+            dm.target_msg(&id, Some(*line.start), "enable")?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{clone::Clone, fs::OpenOptions, path::Path};
+    use std::{
+        clone::Clone,
+        fs::OpenOptions,
+        io::{Read, Seek, SeekFrom, Write},
+        path::Path,
+    };
 
     use crate::{
-        core::{devnode_to_devno, Device},
+        core::{devnode_to_devno, errors::Error, Device, InUse},
+        shared::{ensure_device, TableSlots},
         testing::{blkdev_size, test_name, test_with_spec},
     };
 
@@ -706,30 +1087,95 @@ mod tests {
         ld.teardown(&dm).unwrap();
     }
 
-    /// Use five segments, each distinct. If parsing works correctly,
-    /// default table should match extracted table.
+    /// Verify that a loaded but unresumed table is read as the inactive
+    /// table, and that the changes a resume would make are reported.
+    fn test_table_slots(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let line = |start, offset| {
+            TargetLine::new(
+                Sectors(start),
+                Sectors(1),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(offset))),
+            )
+        };
+        // The segments are not contiguous, so that they are not merged.
+        let mut ld = LinearDev::setup(&dm, &name, None, vec![line(0, 0), line(1, 2)]).unwrap();
+        let id = DevId::Name(ld.name());
+
+        let slots = TableSlots::<LinearDevTargetTable>::read(&dm, &id).unwrap();
+        assert!(slots.active.is_some());
+        assert!(!slots.resume_pending());
+        assert_eq!(slots.resume_changes(), None);
+        assert_eq!(
+            LinearDev::read_inactive_kernel_table(&dm, &id).unwrap(),
+            None
+        );
+
+        let new_table = LinearDevTargetTable::new(vec![line(0, 0), line(1, 3)]);
+        ld.table_load(&dm, &new_table, DmOptions::default())
+            .unwrap();
+
+        let slots = TableSlots::<LinearDevTargetTable>::read(&dm, &id).unwrap();
+        assert!(slots.resume_pending());
+        assert!(!slots.suspended);
+        assert_eq!(slots.inactive.as_ref(), Some(&new_table));
+        let changes = slots.resume_changes().unwrap();
+        assert_eq!(changes.removed, line_table(&[line(1, 2)]));
+        assert_eq!(changes.added, line_table(&[line(1, 3)]));
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    fn line_table(lines: &[TargetLine<LinearDevTargetParams>]) -> Vec<(u64, u64, String, String)> {
+        LinearDevTargetTable::new(lines.to_vec()).to_raw_table()
+    }
+
+    /// Use five segments, each distinct and none contiguous with the next,
+    /// so that none are merged. If parsing works correctly, default table
+    /// should match extracted table. Then verify that contiguous segments,
+    /// which are merged into one, are equivalent to the merged table.
     fn test_several_segments(paths: &[&Path]) {
         assert!(!paths.is_empty());
 
         let dm = DM::new().unwrap();
         let name = test_name("name").expect("valid format");
         let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
-        let table = (0..5)
-            .map(|n| {
-                TargetLine::new(
-                    Sectors(n),
-                    Sectors(1),
-                    LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(n))),
-                )
-            })
-            .collect::<Vec<_>>();
-        let mut ld = LinearDev::setup(&dm, &name, None, table.clone()).unwrap();
+        let table = |stride| {
+            (0..5)
+                .map(|n| {
+                    TargetLine::new(
+                        Sectors(n),
+                        Sectors(1),
+                        LinearDevTargetParams::Linear(LinearTargetParams::new(
+                            dev,
+                            Sectors(n * stride),
+                        )),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut ld = LinearDev::setup(&dm, &name, None, table(2)).unwrap();
 
         let loaded_table = LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name())).unwrap();
+        assert_eq!(loaded_table, LinearDevTargetTable::new(table(2)));
         assert!(
-            LinearDev::equivalent_tables(&LinearDevTargetTable::new(table), &loaded_table).unwrap()
+            LinearDev::equivalent_tables(&LinearDevTargetTable::new(table(2)), &loaded_table)
+                .unwrap()
         );
+        ld.teardown(&dm).unwrap();
 
+        let mut ld = LinearDev::setup(&dm, &name, None, table(1)).unwrap();
+        let loaded_table = LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name())).unwrap();
+        assert_eq!(loaded_table.table.len(), 1);
+        assert!(
+            LinearDev::equivalent_tables(&LinearDevTargetTable::new(table(1)), &loaded_table)
+                .unwrap()
+        );
+        assert_matches!(LinearDev::setup(&dm, &name, None, table(1)), Ok(_));
         ld.teardown(&dm).unwrap();
     }
 
@@ -806,6 +1252,30 @@ mod tests {
         ld.teardown(&dm).unwrap();
     }
 
+    /// Verify that a table which fits on its backing device is accepted and
+    /// that one which extends beyond the end of the device is rejected.
+    fn test_segments_fit(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let dev_size =
+            blkdev_size(&OpenOptions::new().read(true).open(paths[0]).unwrap()).sectors();
+
+        let table = LinearDevTargetTable::new(vec![TargetLine::new(
+            Sectors(0),
+            dev_size,
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )]);
+        table.check_segments_fit().unwrap();
+
+        let table = LinearDevTargetTable::new(vec![TargetLine::new(
+            Sectors(0),
+            dev_size,
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(1))),
+        )]);
+        assert_matches!(table.check_segments_fit(), Err(_));
+    }
+
     #[test]
     fn test_flakey_target_params_zero() {
         let result = "flakey 8:32 0 16 2 0"
@@ -814,6 +1284,14 @@ mod tests {
         assert_eq!(result.feature_args, HashSet::new());
     }
 
+    #[test]
+    fn test_dust_target_params() {
+        let result = "dust 8:32 16 512".parse::<DustTargetParams>().unwrap();
+        assert_eq!(result.start_offset, Sectors(16));
+        assert_eq!(result.block_size, Bytes(512));
+        assert_eq!(result.to_string(), "dust 8:32 16 512");
+    }
+
     #[test]
     fn test_flakey_target_params_none() {
         let result = "flakey 8:32 0 16 2".parse::<FlakeyTargetParams>().unwrap();
@@ -910,11 +1388,80 @@ mod tests {
         assert_eq!(result.feature_args, expected);
     }
 
+    #[test]
+    /// Verify that the feature argument count of a generated table counts
+    /// the arguments of each feature, so that the table round-trips.
+    fn test_flakey_target_params_feature_arg_count() {
+        let params = "flakey 8:32 0 16 2 6 corrupt_bio_byte 32 r 1 0 drop_writes"
+            .parse::<FlakeyTargetParams>()
+            .unwrap();
+        let table = params.to_string();
+        assert!(table.contains(" 6 "));
+        assert_eq!(table.parse::<FlakeyTargetParams>().unwrap(), params);
+
+        let params = "flakey 8:32 0 16 2 1 error_writes"
+            .parse::<FlakeyTargetParams>()
+            .unwrap();
+        assert_eq!(params.to_string(), "flakey 8:32 0 16 2 1 error_writes");
+    }
+
+    #[test]
+    /// Verify that only adjacent linear segments which map contiguous
+    /// regions of the same device are merged.
+    fn test_compact() {
+        let dev1 = Device::from_str("8:32").unwrap();
+        let dev2 = Device::from_str("8:48").unwrap();
+        let linear = |dev, offset| {
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(offset)))
+        };
+        let mut table = LinearDevTargetTable::new(vec![]);
+        table.append(vec![
+            (Sectors(8), linear(dev1, 0)),
+            (Sectors(8), linear(dev1, 8)),
+            (Sectors(8), linear(dev1, 16)),
+            (Sectors(8), linear(dev2, 24)),
+            (Sectors(8), linear(dev1, 40)),
+            (
+                Sectors(8),
+                LinearDevTargetParams::Dust(DustTargetParams::new(dev1, Sectors(48), Bytes(512))),
+            ),
+            (Sectors(8), linear(dev1, 56)),
+        ]);
+        assert_eq!(
+            table.table,
+            vec![
+                TargetLine::new(Sectors(0), Sectors(24), linear(dev1, 0)),
+                TargetLine::new(Sectors(24), Sectors(8), linear(dev2, 24)),
+                TargetLine::new(Sectors(32), Sectors(8), linear(dev1, 40)),
+                TargetLine::new(
+                    Sectors(40),
+                    Sectors(8),
+                    LinearDevTargetParams::Dust(DustTargetParams::new(
+                        dev1,
+                        Sectors(48),
+                        Bytes(512)
+                    )),
+                ),
+                TargetLine::new(Sectors(48), Sectors(8), linear(dev1, 56)),
+            ]
+        );
+        assert_eq!(table.size(), Sectors(56));
+
+        table.append(vec![(Sectors(8), linear(dev1, 64))]);
+        assert_eq!(table.table.len(), 5);
+        assert_eq!(table.size(), Sectors(64));
+    }
+
     #[test]
     fn loop_test_duplicate_segments() {
         test_with_spec(1, test_duplicate_segments);
     }
 
+    #[test]
+    fn loop_test_table_slots() {
+        test_with_spec(1, test_table_slots);
+    }
+
     #[test]
     fn loop_test_empty() {
         test_with_spec(0, test_empty);
@@ -945,6 +1492,250 @@ mod tests {
         test_with_spec(1, test_same_segment);
     }
 
+    #[test]
+    fn loop_test_segments_fit() {
+        test_with_spec(1, test_segments_fit);
+    }
+
+    /// Verify that a table over an unused device passes the exclusive check
+    /// and that one over a device held by a DM device fails it.
+    fn test_check_exclusive(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        LinearDevTargetTable::new(table.clone())
+            .check_exclusive(&dm)
+            .unwrap();
+
+        let name = test_name("name").expect("valid format");
+        let mut ld = LinearDev::setup(&dm, &name, None, table.clone()).unwrap();
+
+        assert_matches!(
+            LinearDevTargetTable::new(table).check_exclusive(&dm),
+            Err(DmError::Core(Error::InUse(_, in_use))) if in_use.contains(&InUse::Held {
+                device: dev,
+                holder: ld.device(),
+            })
+        );
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_check_exclusive() {
+        test_with_spec(1, test_check_exclusive);
+    }
+
+    /// Verify that a device is not set up over a filesystem signature
+    /// unless forced.
+    fn test_setup_checked(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(8))),
+        )];
+        let name = test_name("name").expect("valid format");
+
+        let mut f = OpenOptions::new().write(true).open(paths[0]).unwrap();
+        f.seek(SeekFrom::Start(4096)).unwrap();
+        f.write_all(b"XFSB").unwrap();
+        f.sync_all().unwrap();
+
+        assert_matches!(
+            LinearDev::setup_checked(&dm, &name, None, table.clone(), false),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert!(!device_exists(&dm, &name).unwrap());
+
+        let mut ld = LinearDev::setup_checked(&dm, &name, None, table, true).unwrap();
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_setup_checked() {
+        test_with_spec(1, test_setup_checked);
+    }
+
+    /// Verify that ensure_device creates a missing device, accepts an
+    /// existing equivalent device, activates a device which has only an
+    /// inactive table, and rejects a device with a different table, or
+    /// with a different inactive table, which it leaves in place.
+    fn test_ensure_device(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let id = DevId::Name(&name);
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = |offset| {
+            LinearDevTargetTable::new(vec![TargetLine::new(
+                Sectors(0),
+                Sectors(1),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, offset)),
+            )])
+        };
+
+        let ensure = |table: &LinearDevTargetTable| {
+            ensure_device::<_, LinearDev>(&dm, &name, None, table, DmOptions::private())
+        };
+
+        let info = ensure(&table(Sectors(0))).unwrap();
+        assert!(info.flags().contains(DmFlags::DM_ACTIVE_PRESENT));
+        assert_eq!(ensure(&table(Sectors(0))).unwrap().device(), info.device());
+        assert_matches!(ensure(&table(Sectors(1))), Err(_));
+
+        dm.table_load(&id, &table(Sectors(1)).to_raw_table(), DmOptions::default())
+            .unwrap();
+        assert_matches!(ensure(&table(Sectors(0))), Err(_));
+        assert!(dm
+            .device_info(&id)
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_INACTIVE_PRESENT));
+        dm.table_clear(&id).unwrap();
+        dm.table_load(&id, &table(Sectors(0)).to_raw_table(), DmOptions::default())
+            .unwrap();
+        let info = ensure(&table(Sectors(0))).unwrap();
+        assert!(!info.flags().contains(DmFlags::DM_INACTIVE_PRESENT));
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        dm.table_load(&id, &table(Sectors(0)).to_raw_table(), DmOptions::default())
+            .unwrap();
+        assert_matches!(ensure(&table(Sectors(1))), Err(_));
+        let info = ensure(&table(Sectors(0))).unwrap();
+        assert!(info.flags().contains(DmFlags::DM_ACTIVE_PRESENT));
+        assert!(!info.flags().contains(DmFlags::DM_SUSPEND));
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_ensure_device() {
+        test_with_spec(1, test_ensure_device);
+    }
+
+    #[test]
+    /// Verify that appending and truncating segments gives the expected
+    /// starts and lengths.
+    fn test_append_truncate() {
+        let dev = Device { major: 7, minor: 0 };
+        let params = |offset| LinearDevTargetParams::Linear(LinearTargetParams::new(dev, offset));
+        let mut table = LinearDevTargetTable::new(vec![]);
+        table.append(vec![
+            (Sectors(4), params(Sectors(0))),
+            (Sectors(4), params(Sectors(8))),
+        ]);
+        assert_eq!(table.size(), Sectors(8));
+        assert_eq!(table.table[1].start, Sectors(4));
+
+        assert_matches!(table.truncate(Sectors(9)), Err(_));
+        assert_matches!(table.truncate(Sectors(0)), Err(_));
+        table.truncate(Sectors(6)).unwrap();
+        assert_eq!(table.table.len(), 2);
+        assert_eq!(table.table[1].length, Sectors(2));
+        table.truncate(Sectors(4)).unwrap();
+        assert_eq!(table.table.len(), 1);
+        assert_eq!(table.size(), Sectors(4));
+    }
+
+    /// Verify that a linear device can be grown and shrunk online.
+    fn test_resize(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let params = |offset| LinearDevTargetParams::Linear(LinearTargetParams::new(dev, offset));
+        let mut ld = LinearDev::setup(
+            &dm,
+            &name,
+            None,
+            vec![TargetLine::new(Sectors(0), Sectors(8), params(Sectors(0)))],
+        )
+        .unwrap();
+
+        ld.extend(&dm, vec![(Sectors(8), params(Sectors(16)))])
+            .unwrap();
+        assert_eq!(ld.size(), Sectors(16));
+        assert_eq!(
+            blkdev_size(&OpenOptions::new().read(true).open(ld.devnode()).unwrap()).sectors(),
+            Sectors(16)
+        );
+
+        ld.set_size(&dm, Sectors(4)).unwrap();
+        assert_eq!(
+            LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name()))
+                .unwrap()
+                .size(),
+            Sectors(4)
+        );
+        assert_matches!(ld.set_size(&dm, Sectors(8)), Err(_));
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    /// Verify that reads of the bad sectors of a device fail while they are
+    /// marked bad, and that the device's table is restored afterwards.
+    fn test_with_bad_blocks(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let params = |offset| LinearDevTargetParams::Linear(LinearTargetParams::new(dev, offset));
+        let mut ld = LinearDev::setup(
+            &dm,
+            &name,
+            None,
+            vec![
+                TargetLine::new(Sectors(0), Sectors(16), params(Sectors(0))),
+                TargetLine::new(Sectors(16), Sectors(16), params(Sectors(32))),
+            ],
+        )
+        .unwrap();
+        let table = ld.table().clone();
+
+        let read_at = |ld: &LinearDev, sector: Sectors| {
+            let mut f = OpenOptions::new().read(true).open(ld.devnode()).unwrap();
+            f.seek(SeekFrom::Start(*sector.bytes() as u64)).unwrap();
+            f.read_exact(&mut [0u8; 4096])
+        };
+        ld.with_bad_blocks(&dm, &[Sectors(20)], |ld| {
+            assert!(read_at(ld, Sectors(0)).is_ok());
+            assert!(read_at(ld, Sectors(16)).is_err());
+        })
+        .unwrap();
+
+        assert_eq!(
+            LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name())).unwrap(),
+            table
+        );
+        assert!(read_at(&ld, Sectors(16)).is_ok());
+        assert_matches!(ld.with_bad_blocks(&dm, &[Sectors(32)], |_| ()), Err(_));
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_with_bad_blocks() {
+        test_with_spec(1, test_with_bad_blocks);
+    }
+
+    #[test]
+    fn loop_test_resize() {
+        test_with_spec(1, test_resize);
+    }
+
     #[test]
     fn loop_test_several_segments() {
         test_with_spec(1, test_several_segments);
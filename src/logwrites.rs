@@ -0,0 +1,441 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Support for the log-writes target, which passes writes through to a device
+// and also records each of them, in order, on a log device. Replaying the
+// log onto a copy of the device's original contents, up to some mark,
+// reconstructs the state of the device at the time the mark was made, which
+// is how crash consistency tests simulate a crash at that point.
+//
+// The log format is that written by drivers/md/dm-log-writes.c and read by
+// the replay-log tool. Sector 0 of the log device holds the super block.
+// The entries follow, starting at the second log sector, where a log sector
+// is the logical block size of the logged device. Each entry is a log
+// sector holding the entry header, and the mark name if the entry is a
+// mark, followed by the data written, if any.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    os::unix::fs::FileExt,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{
+    core::{errors, DevId, Device, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{parse_device, TargetParams, TargetTypeBuf},
+};
+
+const LOG_WRITES_TARGET_NAME: &str = "log-writes";
+
+/// The magic number in the super block of a log
+const LOG_WRITES_MAGIC: u64 = 0x6a73_6677_7368_72;
+
+/// The only version of the log format
+const LOG_WRITES_VERSION: u64 = 1;
+
+/// The size of the super block: magic, version, nr_entries, sectorsize
+const SUPER_SIZE: usize = 28;
+
+/// The size of an entry header: sector, nr_sectors, flags, data_len
+const ENTRY_SIZE: usize = 32;
+
+/// The largest number of bytes read from the log or written to the replay
+/// device at once
+const MAX_IO_SIZE: u64 = 1 << 20;
+
+bitflags! {
+    /// The kind of a log-writes log entry.
+    pub struct LogWritesFlags: u64 {
+        /// The entry records a flush.
+        const FLUSH    = 1 << 0;
+        /// The entry records a FUA write.
+        const FUA      = 1 << 1;
+        /// The entry records a discard.
+        const DISCARD  = 1 << 2;
+        /// The entry is a mark.
+        const MARK     = 1 << 3;
+        /// The entry records a write of filesystem metadata.
+        const METADATA = 1 << 4;
+    }
+}
+
+/// Target params for log-writes target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogWritesTargetParams {
+    /// The device to which writes are passed through
+    pub device: Device,
+    /// The device on which writes are logged
+    pub log_device: Device,
+}
+
+impl LogWritesTargetParams {
+    /// Create a new LogWritesTargetParams struct
+    pub fn new(device: Device, log_device: Device) -> LogWritesTargetParams {
+        LogWritesTargetParams { device, log_device }
+    }
+}
+
+impl fmt::Display for LogWritesTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <dev path> <log dev path>
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", LOG_WRITES_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for LogWritesTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<LogWritesTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() != 3 {
+            let err_msg = format!(
+                "expected 3 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != LOG_WRITES_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a log-writes target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let device = parse_device(vals[1], "block device for log-writes target")?;
+        let log_device = parse_device(vals[2], "log device for log-writes target")?;
+
+        Ok(LogWritesTargetParams::new(device, log_device))
+    }
+}
+
+impl TargetParams for LogWritesTargetParams {
+    fn param_str(&self) -> String {
+        format!("{} {}", self.device, self.log_device)
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(LOG_WRITES_TARGET_NAME.into()).expect("LOG_WRITES_TARGET_NAME is valid")
+    }
+}
+
+/// Add a mark with the given name to the log of the log-writes device `id`.
+/// The log can later be replayed up to the mark.
+pub fn log_writes_mark(dm: &DM, id: &DevId<'_>, mark: &str) -> DmResult<()> {
+    dm.target_msg(id, None, &format!("mark {mark}"))?;
+    Ok(())
+}
+
+/// An entry in a log-writes log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogWritesEntry {
+    /// The first sector written or discarded, in log sectors
+    pub sector: u64,
+    /// The number of sectors written or discarded, in log sectors
+    pub nr_sectors: u64,
+    /// The kind of the entry
+    pub flags: LogWritesFlags,
+    /// The name of the mark, if the entry is a mark
+    pub mark: Option<String>,
+    // The offset of the data written in the log, in bytes
+    data_offset: u64,
+}
+
+/// Where to stop replaying a log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogWritesReplayEnd {
+    /// Replay every entry in the log.
+    All,
+    /// Replay the entries up to and including the first mark with the
+    /// given name. It is an error if there is no such mark.
+    Mark(String),
+    /// Replay the given number of entries.
+    Entries(u64),
+}
+
+/// A log-writes log, read from a log device or a copy of one.
+#[derive(Debug)]
+pub struct LogWritesLog {
+    file: File,
+    sector_size: u64,
+    entries: Vec<LogWritesEntry>,
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(
+        buf[offset..offset + 8]
+            .try_into()
+            .expect("slice is 8 bytes long"),
+    )
+}
+
+fn io_error(action: &str, path: &Path, err: std::io::Error) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to {} {}: {}",
+        action,
+        path.display(),
+        err
+    )))
+}
+
+fn invalid_log(msg: String) -> DmError {
+    DmError::Dm(ErrorEnum::Invalid, format!("invalid log-writes log: {msg}"))
+}
+
+impl LogWritesLog {
+    /// Open the log on the device or file at `path` and read its entries.
+    /// The log device must not be in use by a log-writes target.
+    pub fn open(path: &Path) -> DmResult<LogWritesLog> {
+        let file = File::open(path).map_err(|err| io_error("open", path, err))?;
+
+        let mut sup = [0u8; SUPER_SIZE];
+        file.read_exact_at(&mut sup, 0)
+            .map_err(|err| io_error("read super block of", path, err))?;
+        if read_u64(&sup, 0) != LOG_WRITES_MAGIC {
+            return Err(invalid_log("bad magic number".to_string()));
+        }
+        let version = read_u64(&sup, 8);
+        if version != LOG_WRITES_VERSION {
+            return Err(invalid_log(format!("unsupported version {version}")));
+        }
+        let nr_entries = read_u64(&sup, 16);
+        let sector_size = u64::from(u32::from_le_bytes(
+            sup[24..28].try_into().expect("slice is 4 bytes long"),
+        ));
+        if sector_size < ENTRY_SIZE as u64 {
+            return Err(invalid_log(format!("bad sector size {sector_size}")));
+        }
+
+        let mut entries = Vec::new();
+        let mut pos = sector_size;
+        let mut header = vec![0u8; sector_size as usize];
+        for index in 0..nr_entries {
+            file.read_exact_at(&mut header, pos)
+                .map_err(|err| io_error("read log entry from", path, err))?;
+            let flags = LogWritesFlags::from_bits_truncate(read_u64(&header, 16));
+            let data_len = read_u64(&header, 24) as usize;
+            let mark = if flags.contains(LogWritesFlags::MARK) {
+                let name = header
+                    .get(ENTRY_SIZE..ENTRY_SIZE + data_len)
+                    .ok_or_else(|| invalid_log(format!("mark in entry {index} is too long")))?;
+                Some(String::from_utf8_lossy(name).into_owned())
+            } else {
+                None
+            };
+            let entry = LogWritesEntry {
+                sector: read_u64(&header, 0),
+                nr_sectors: read_u64(&header, 8),
+                flags,
+                mark,
+                data_offset: pos + sector_size,
+            };
+            pos = entry.data_offset;
+            if !entry.flags.contains(LogWritesFlags::DISCARD) {
+                pos += entry.nr_sectors * sector_size;
+            }
+            entries.push(entry);
+        }
+
+        Ok(LogWritesLog {
+            file,
+            sector_size,
+            entries,
+        })
+    }
+
+    /// The size of a log sector, the logical block size of the logged
+    /// device, in bytes.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// The entries in the log, in the order in which they were logged.
+    pub fn entries(&self) -> &[LogWritesEntry] {
+        &self.entries
+    }
+
+    /// The number of entries which must be replayed to replay up to `end`.
+    fn replay_count(&self, end: &LogWritesReplayEnd) -> DmResult<usize> {
+        match end {
+            LogWritesReplayEnd::All => Ok(self.entries.len()),
+            LogWritesReplayEnd::Mark(name) => self
+                .entries
+                .iter()
+                .position(|entry| entry.mark.as_deref() == Some(name))
+                .map(|index| index + 1)
+                .ok_or_else(|| {
+                    DmError::Dm(
+                        ErrorEnum::NotFound,
+                        format!("no mark \"{name}\" in log-writes log"),
+                    )
+                }),
+            LogWritesReplayEnd::Entries(count) => {
+                if *count > self.entries.len() as u64 {
+                    Err(DmError::Dm(
+                        ErrorEnum::Invalid,
+                        format!(
+                            "log-writes log has {} entries, can not replay {}",
+                            self.entries.len(),
+                            count
+                        ),
+                    ))
+                } else {
+                    Ok(*count as usize)
+                }
+            }
+        }
+    }
+
+    /// Replay the log onto the device or file at `path` up to `end`. The
+    /// device should hold the contents which the logged device had when
+    /// the log was started. Discarded ranges are zeroed, so that the
+    /// result does not depend on whether the device supports discards.
+    ///
+    /// Returns the number of entries replayed.
+    pub fn replay(&self, path: &Path, end: &LogWritesReplayEnd) -> DmResult<u64> {
+        let count = self.replay_count(end)?;
+        let target = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|err| io_error("open", path, err))?;
+
+        let mut buf = Vec::new();
+        for entry in &self.entries[..count] {
+            let mut offset = entry.sector * self.sector_size;
+            let mut remaining = entry.nr_sectors * self.sector_size;
+            let mut data_offset = entry.data_offset;
+            while remaining > 0 {
+                let len = remaining.min(MAX_IO_SIZE);
+                buf.resize(len as usize, 0);
+                if entry.flags.contains(LogWritesFlags::DISCARD) {
+                    buf.fill(0);
+                } else {
+                    self.file
+                        .read_exact_at(&mut buf, data_offset)
+                        .map_err(|err| invalid_log(format!("failed to read data: {err}")))?;
+                }
+                target
+                    .write_all_at(&buf, offset)
+                    .map_err(|err| io_error("write to", path, err))?;
+                offset += len;
+                data_offset += len;
+                remaining -= len;
+            }
+        }
+        target
+            .sync_all()
+            .map_err(|err| io_error("sync", path, err))?;
+
+        Ok(count as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const SECTOR_SIZE: u64 = 512;
+
+    /// Write a log with a write of one sector of "a"s at sector 1, a mark
+    /// "one", a discard of sector 1, and a write of two sectors of "b"s at
+    /// sector 2.
+    fn write_log(path: &Path) {
+        let log = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        let mut sup = Vec::new();
+        sup.extend_from_slice(&LOG_WRITES_MAGIC.to_le_bytes());
+        sup.extend_from_slice(&LOG_WRITES_VERSION.to_le_bytes());
+        sup.extend_from_slice(&4u64.to_le_bytes());
+        sup.extend_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        log.write_all_at(&sup, 0).unwrap();
+
+        let entry = |sector: u64, nr_sectors: u64, flags: LogWritesFlags, data: &[u8]| {
+            let mut header = Vec::new();
+            for val in [sector, nr_sectors, flags.bits(), data.len() as u64] {
+                header.extend_from_slice(&val.to_le_bytes());
+            }
+            header.extend_from_slice(data);
+            header
+        };
+        let mut pos = SECTOR_SIZE;
+        for (header, data) in [
+            (entry(1, 1, LogWritesFlags::empty(), &[]), vec![b'a'; 512]),
+            (entry(0, 0, LogWritesFlags::MARK, b"one"), vec![]),
+            (entry(1, 1, LogWritesFlags::DISCARD, &[]), vec![]),
+            (entry(2, 2, LogWritesFlags::FUA, &[]), vec![b'b'; 1024]),
+        ] {
+            log.write_all_at(&header, pos).unwrap();
+            pos += SECTOR_SIZE;
+            log.write_all_at(&data, pos).unwrap();
+            pos += data.len() as u64;
+        }
+    }
+
+    #[test]
+    /// Verify that a log is parsed and replayed up to a mark and to its end.
+    fn test_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("log");
+        let replay_path = dir.path().join("replay");
+        write_log(&log_path);
+
+        let log = LogWritesLog::open(&log_path).unwrap();
+        assert_eq!(log.sector_size(), SECTOR_SIZE);
+        assert_eq!(log.entries().len(), 4);
+        assert_eq!(log.entries()[1].mark.as_deref(), Some("one"));
+        assert!(log.entries()[3].flags.contains(LogWritesFlags::FUA));
+
+        fs::write(&replay_path, vec![b'z'; 4 * SECTOR_SIZE as usize]).unwrap();
+        let replayed = log
+            .replay(&replay_path, &LogWritesReplayEnd::Mark("one".into()))
+            .unwrap();
+        assert_eq!(replayed, 2);
+        let contents = fs::read(&replay_path).unwrap();
+        assert!(contents[512..1024].iter().all(|b| *b == b'a'));
+        assert!(contents[1024..].iter().all(|b| *b == b'z'));
+
+        assert_eq!(
+            log.replay(&replay_path, &LogWritesReplayEnd::All).unwrap(),
+            4
+        );
+        let contents = fs::read(&replay_path).unwrap();
+        assert!(contents[..512].iter().all(|b| *b == b'z'));
+        assert!(contents[512..1024].iter().all(|b| *b == 0));
+        assert!(contents[1024..].iter().all(|b| *b == b'b'));
+
+        assert_matches!(
+            log.replay(&replay_path, &LogWritesReplayEnd::Mark("two".into())),
+            Err(_)
+        );
+        assert_matches!(
+            log.replay(&replay_path, &LogWritesReplayEnd::Entries(5)),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn test_log_writes_target_params() {
+        let result = "log-writes 8:32 8:48"
+            .parse::<LogWritesTargetParams>()
+            .unwrap();
+        assert_eq!(result.to_string(), "log-writes 8:32 8:48");
+        assert_matches!("log-writes 8:32".parse::<LogWritesTargetParams>(), Err(_));
+    }
+}
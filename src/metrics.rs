@@ -0,0 +1,298 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt::Write;
+
+use crate::{
+    cachedev::{CacheDev, CacheDevStatus},
+    core::{DevId, DmFlags, DmOptions, DM},
+    dmstats::{stats_list, stats_print, StatsCounters},
+    result::DmResult,
+    shared::DmDevice,
+    thinpooldev::{ThinPoolDev, ThinPoolStatus},
+};
+
+/// A sink for metrics, such as a client of a metrics library or a
+/// `PrometheusText` exposition.
+///
+/// Metric names follow the prometheus conventions: they are prefixed with
+/// "dm_", are in snake case, and counters end in "_total". Every metric has
+/// a "device" label with the name of the DM device.
+pub trait MetricsRecorder {
+    /// Record the current value of a gauge, a value which may go up or down.
+    fn gauge(&mut self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    /// Record the current value of a counter, a value which only increases
+    /// while the device exists.
+    fn counter(&mut self, name: &str, labels: &[(&str, &str)], value: u64);
+}
+
+/// A recorder which renders metrics in the prometheus text exposition
+/// format. The samples of each metric are grouped together, under a single
+/// type declaration, in the order in which the metrics were first recorded.
+#[derive(Debug, Default)]
+pub struct PrometheusText {
+    metrics: Vec<(String, &'static str, Vec<String>)>,
+}
+
+impl PrometheusText {
+    /// Make a new, empty exposition.
+    pub fn new() -> PrometheusText {
+        PrometheusText::default()
+    }
+
+    /// Render the metrics recorded so far in the text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for (name, metric_type, samples) in &self.metrics {
+            let _ = writeln!(output, "# TYPE {name} {metric_type}");
+            for sample in samples {
+                let _ = writeln!(output, "{sample}");
+            }
+        }
+        output
+    }
+
+    fn sample(
+        &mut self,
+        name: &str,
+        metric_type: &'static str,
+        labels: &[(&str, &str)],
+        value: &str,
+    ) {
+        let sample = if labels.is_empty() {
+            format!("{name} {value}")
+        } else {
+            let labels = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!("{name}{{{labels}}} {value}")
+        };
+        match self.metrics.iter_mut().find(|(n, _, _)| n == name) {
+            Some((_, _, samples)) => samples.push(sample),
+            None => self
+                .metrics
+                .push((name.to_string(), metric_type, vec![sample])),
+        }
+    }
+}
+
+impl MetricsRecorder for PrometheusText {
+    fn gauge(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.sample(name, "gauge", labels, &value.to_string());
+    }
+
+    fn counter(&mut self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.sample(name, "counter", labels, &value.to_string());
+    }
+}
+
+/// Escape a label value as required by the text exposition format.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The fraction of `total` which is `used`, or 0 if `total` is 0.
+fn ratio(used: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        used as f64 / total as f64
+    }
+}
+
+/// Record the counters of each of the device's stats regions, summed over
+/// the areas of the region. Each metric has a "region" label with the id
+/// of the region. A device without an active table has no stats regions.
+pub fn record_stats_metrics(
+    dm: &DM,
+    id: &DevId<'_>,
+    recorder: &mut dyn MetricsRecorder,
+) -> DmResult<()> {
+    let info = dm.device_info(id)?;
+    if !info.flags().contains(DmFlags::DM_ACTIVE_PRESENT) {
+        return Ok(());
+    }
+    let device = id.to_string();
+    for region in stats_list(dm, id, None)? {
+        let mut counters = StatsCounters::default();
+        for area in stats_print(dm, id, region.region_id, false)? {
+            counters += area.counters;
+        }
+        let region_id = region.region_id.to_string();
+        let labels = [("device", device.as_str()), ("region", region_id.as_str())];
+        recorder.counter("dm_stats_reads_total", &labels, counters.reads);
+        recorder.counter(
+            "dm_stats_reads_merged_total",
+            &labels,
+            counters.reads_merged,
+        );
+        recorder.counter(
+            "dm_stats_read_sectors_total",
+            &labels,
+            counters.read_sectors,
+        );
+        recorder.counter("dm_stats_read_ticks_total", &labels, counters.read_ticks);
+        recorder.counter("dm_stats_writes_total", &labels, counters.writes);
+        recorder.counter(
+            "dm_stats_writes_merged_total",
+            &labels,
+            counters.writes_merged,
+        );
+        recorder.counter(
+            "dm_stats_write_sectors_total",
+            &labels,
+            counters.write_sectors,
+        );
+        recorder.counter("dm_stats_write_ticks_total", &labels, counters.write_ticks);
+        recorder.counter("dm_stats_io_ticks_total", &labels, counters.io_ticks);
+        recorder.counter(
+            "dm_stats_weighted_io_ticks_total",
+            &labels,
+            counters.weighted_io_ticks,
+        );
+        recorder.gauge("dm_stats_in_flight", &labels, counters.in_flight as f64);
+    }
+    Ok(())
+}
+
+/// Record the usage of a thin pool's data and metadata devices, and whether
+/// the pool has failed. If the pool has failed, only "dm_thinpool_failed"
+/// is recorded.
+pub fn record_thinpool_metrics(
+    dm: &DM,
+    pool: &ThinPoolDev,
+    recorder: &mut dyn MetricsRecorder,
+) -> DmResult<()> {
+    let device = pool.name().to_string();
+    let labels = [("device", device.as_str())];
+    match pool.status(dm, DmOptions::default())? {
+        ThinPoolStatus::Working(status) => {
+            let usage = &status.usage;
+            recorder.gauge("dm_thinpool_failed", &labels, 0.0);
+            recorder.gauge(
+                "dm_thinpool_data_used_blocks",
+                &labels,
+                *usage.used_data as f64,
+            );
+            recorder.gauge(
+                "dm_thinpool_data_total_blocks",
+                &labels,
+                *usage.total_data as f64,
+            );
+            recorder.gauge(
+                "dm_thinpool_data_used_ratio",
+                &labels,
+                ratio(*usage.used_data, *usage.total_data),
+            );
+            recorder.gauge(
+                "dm_thinpool_meta_used_blocks",
+                &labels,
+                *usage.used_meta as f64,
+            );
+            recorder.gauge(
+                "dm_thinpool_meta_total_blocks",
+                &labels,
+                *usage.total_meta as f64,
+            );
+            recorder.gauge(
+                "dm_thinpool_meta_used_ratio",
+                &labels,
+                ratio(*usage.used_meta, *usage.total_meta),
+            );
+        }
+        ThinPoolStatus::Error | ThinPoolStatus::Fail => {
+            recorder.gauge("dm_thinpool_failed", &labels, 1.0);
+        }
+    }
+    Ok(())
+}
+
+/// Record the usage of a cache's cache and metadata devices, its hit and
+/// miss counts, and whether the cache has failed. If the cache has failed,
+/// only "dm_cache_failed" is recorded.
+pub fn record_cache_metrics(
+    dm: &DM,
+    cache: &CacheDev,
+    recorder: &mut dyn MetricsRecorder,
+) -> DmResult<()> {
+    let device = cache.name().to_string();
+    let labels = [("device", device.as_str())];
+    match cache.status(dm, DmOptions::default())? {
+        CacheDevStatus::Working(status) => {
+            let usage = &status.usage;
+            let performance = &status.performance;
+            recorder.gauge("dm_cache_failed", &labels, 0.0);
+            recorder.gauge("dm_cache_used_blocks", &labels, *usage.used_cache as f64);
+            recorder.gauge("dm_cache_total_blocks", &labels, *usage.total_cache as f64);
+            recorder.gauge(
+                "dm_cache_used_ratio",
+                &labels,
+                ratio(*usage.used_cache, *usage.total_cache),
+            );
+            recorder.gauge(
+                "dm_cache_meta_used_blocks",
+                &labels,
+                *usage.used_meta as f64,
+            );
+            recorder.gauge(
+                "dm_cache_meta_total_blocks",
+                &labels,
+                *usage.total_meta as f64,
+            );
+            recorder.gauge(
+                "dm_cache_meta_used_ratio",
+                &labels,
+                ratio(*usage.used_meta, *usage.total_meta),
+            );
+            recorder.gauge("dm_cache_dirty_blocks", &labels, performance.dirty as f64);
+            recorder.counter("dm_cache_read_hits_total", &labels, performance.read_hits);
+            recorder.counter(
+                "dm_cache_read_misses_total",
+                &labels,
+                performance.read_misses,
+            );
+            recorder.counter("dm_cache_write_hits_total", &labels, performance.write_hits);
+            recorder.counter(
+                "dm_cache_write_misses_total",
+                &labels,
+                performance.write_misses,
+            );
+            recorder.counter("dm_cache_promotions_total", &labels, performance.promotions);
+            recorder.counter("dm_cache_demotions_total", &labels, performance.demotions);
+        }
+        CacheDevStatus::Error | CacheDevStatus::Fail => {
+            recorder.gauge("dm_cache_failed", &labels, 1.0);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the samples of a metric are grouped under a single type
+    /// declaration and that label values are escaped.
+    fn test_prometheus_text() {
+        let mut text = PrometheusText::new();
+        text.gauge("dm_a", &[("device", "x\"y")], 0.5);
+        text.counter("dm_b_total", &[], 3);
+        text.gauge("dm_a", &[("device", "z")], 1.0);
+        assert_eq!(
+            text.render(),
+            "# TYPE dm_a gauge\n\
+             dm_a{device=\"x\\\"y\"} 0.5\n\
+             dm_a{device=\"z\"} 1\n\
+             # TYPE dm_b_total counter\n\
+             dm_b_total 3\n"
+        );
+    }
+}
@@ -0,0 +1,572 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Support for the mirror target, the older, DM-native mirror which lvm2
+// calls "mirror" as opposed to "raid1". Its legs are kept in sync region
+// by region, and which regions are in sync is recorded by a dirty region
+// log, either kept in memory, the core log, or on a device of its own, the
+// disk log, so that a mirror with a disk log need not be resynchronized
+// in full after an unclean shutdown.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    core::{DevId, Device, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{
+        get_status, get_status_line_fields, make_unexpected_value_error, parse_device, parse_value,
+        TargetLine, TargetParams, TargetTable, TargetTypeBuf,
+    },
+    units::Sectors,
+};
+
+pub(crate) const MIRROR_TARGET_NAME: &str = "mirror";
+
+const CORE_LOG_NAME: &str = "core";
+const DISK_LOG_NAME: &str = "disk";
+
+/// The feature which makes the mirror handle failures of its legs, by
+/// raising an event and ceasing to use the failed leg, rather than
+/// erroring I/O
+const HANDLE_ERRORS_FEATURE: &str = "handle_errors";
+/// The feature which keeps the disk log in use when it fails, rather than
+/// falling back to a core log; requires handle_errors
+const KEEP_LOG_FEATURE: &str = "keep_log";
+
+/// The dirty region log of a mirror.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MirrorLog {
+    /// The log is kept in memory, so the whole mirror is resynchronized
+    /// whenever it is activated
+    Core,
+    /// The log is kept on the given device
+    Disk(Device),
+}
+
+/// Whether the legs of a mirror are synchronized when it is activated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MirrorSync {
+    /// Every region is synchronized, whatever the log records
+    Sync,
+    /// No region is synchronized; the legs are assumed to be in sync, e.g.,
+    /// because they are known to be zeroed
+    NoSync,
+}
+
+impl MirrorSync {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MirrorSync::Sync => "sync",
+            MirrorSync::NoSync => "nosync",
+        }
+    }
+}
+
+/// Target params for mirror target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MirrorTargetParams {
+    /// The dirty region log
+    pub log: MirrorLog,
+    /// The size of the regions in which the legs are kept in sync
+    pub region_size: Sectors,
+    /// Whether the legs are synchronized on activation; if None, only the
+    /// regions which the log records as out of sync are synchronized
+    pub sync: Option<MirrorSync>,
+    /// The legs of the mirror, each a device and the offset on it
+    pub legs: Vec<(Device, Sectors)>,
+    /// Whether failures of legs are handled, see `HANDLE_ERRORS_FEATURE`
+    pub handle_errors: bool,
+    /// Whether a failed disk log is kept, see `KEEP_LOG_FEATURE`
+    pub keep_log: bool,
+}
+
+impl MirrorTargetParams {
+    /// Create a new MirrorTargetParams struct
+    pub fn new(
+        log: MirrorLog,
+        region_size: Sectors,
+        legs: Vec<(Device, Sectors)>,
+    ) -> MirrorTargetParams {
+        MirrorTargetParams {
+            log,
+            region_size,
+            sync: None,
+            legs,
+            handle_errors: false,
+            keep_log: false,
+        }
+    }
+
+    /// Verify that the region size is a power of two, that the mirror has
+    /// legs, and that keep_log is given only with a disk log and
+    /// handle_errors, so that a table with these params is not refused by
+    /// the kernel for these reasons.
+    pub fn check(&self) -> DmResult<()> {
+        let invalid = |msg: String| Err(DmError::Dm(ErrorEnum::Invalid, msg));
+        if !(*self.region_size).is_power_of_two() {
+            return invalid(format!(
+                "mirror region size {} is not a power of two",
+                self.region_size
+            ));
+        }
+        if self.legs.is_empty() {
+            return invalid("a mirror must have at least one leg".to_string());
+        }
+        if self.keep_log && !(self.handle_errors && matches!(self.log, MirrorLog::Disk(_))) {
+            return invalid(format!(
+                "{KEEP_LOG_FEATURE} requires a disk log and {HANDLE_ERRORS_FEATURE}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MirrorTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <log_type> <#log_args> <log_args> <#mirrors> <device> <offset> ... [<#features> <features>]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", MIRROR_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for MirrorTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<MirrorTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        let too_few = || {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("too few values in params string \"{s}\""),
+            )
+        };
+        if vals.len() < 4 {
+            return Err(too_few());
+        }
+
+        if vals[0] != MIRROR_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a mirror target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let num_log_args: usize = parse_value(vals[2], "number of mirror log args")?;
+        let end_log_args_index = 3 + num_log_args;
+        let log_args = vals.get(3..end_log_args_index).ok_or_else(too_few)?;
+        let (log, log_args) = match (vals[1], log_args) {
+            (CORE_LOG_NAME, log_args) => (MirrorLog::Core, log_args),
+            (DISK_LOG_NAME, [device, log_args @ ..]) => (
+                MirrorLog::Disk(parse_device(device, "log device for mirror target")?),
+                log_args,
+            ),
+            (log_type, _) => {
+                let err_msg = format!("unsupported mirror log type \"{log_type}\" in \"{s}\"");
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        };
+        let (region_size, sync) = match log_args {
+            [region_size] => (region_size, None),
+            [region_size, "sync"] => (region_size, Some(MirrorSync::Sync)),
+            [region_size, "nosync"] => (region_size, Some(MirrorSync::NoSync)),
+            _ => {
+                let err_msg = format!("unexpected mirror log args in \"{s}\"");
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        };
+
+        let num_legs: usize = parse_value(
+            vals.get(end_log_args_index).ok_or_else(too_few)?,
+            "number of mirror legs",
+        )?;
+        let start_legs_index = end_log_args_index + 1;
+        let end_legs_index = start_legs_index + 2 * num_legs;
+        let legs = vals
+            .get(start_legs_index..end_legs_index)
+            .ok_or_else(too_few)?
+            .chunks(2)
+            .map(|leg| {
+                Ok((
+                    parse_device(leg[0], "leg device for mirror target")?,
+                    Sectors(parse_value(leg[1], "leg offset")?),
+                ))
+            })
+            .collect::<DmResult<Vec<_>>>()?;
+
+        let mut params =
+            MirrorTargetParams::new(log, Sectors(parse_value(region_size, "region size")?), legs);
+        params.sync = sync;
+
+        if let Some(num_features) = vals.get(end_legs_index) {
+            let num_features: usize = parse_value(num_features, "number of mirror features")?;
+            if vals.len() != end_legs_index + 1 + num_features {
+                let err_msg =
+                    format!("expected {num_features} mirror features in params string \"{s}\"");
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+            for feature in &vals[end_legs_index + 1..] {
+                match *feature {
+                    HANDLE_ERRORS_FEATURE => params.handle_errors = true,
+                    KEEP_LOG_FEATURE => params.keep_log = true,
+                    _ => {
+                        let err_msg = format!("unknown mirror feature \"{feature}\"");
+                        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+                    }
+                }
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+impl TargetParams for MirrorTargetParams {
+    fn param_str(&self) -> String {
+        let mut log_args = match self.log {
+            MirrorLog::Core => vec![CORE_LOG_NAME.to_string()],
+            MirrorLog::Disk(device) => vec![DISK_LOG_NAME.to_string(), device.to_string()],
+        };
+        log_args.push((*self.region_size).to_string());
+        if let Some(sync) = self.sync {
+            log_args.push(sync.as_str().to_string());
+        }
+
+        let mut features = Vec::new();
+        if self.handle_errors {
+            features.push(HANDLE_ERRORS_FEATURE);
+        }
+        if self.keep_log {
+            features.push(KEEP_LOG_FEATURE);
+        }
+
+        let mut params = format!(
+            "{} {} {} {} {}",
+            log_args[0],
+            log_args.len() - 1,
+            log_args[1..].join(" "),
+            self.legs.len(),
+            self.legs
+                .iter()
+                .map(|(device, offset)| format!("{} {}", device, **offset))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        if !features.is_empty() {
+            params.push_str(&format!(" {} {}", features.len(), features.join(" ")));
+        }
+        params
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(MIRROR_TARGET_NAME.into()).expect("MIRROR_TARGET_NAME is valid")
+    }
+}
+
+/// A target table for a mirror device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MirrorDevTargetTable {
+    /// The device's table
+    pub table: TargetLine<MirrorTargetParams>,
+}
+
+impl MirrorDevTargetTable {
+    /// Make a new MirrorDevTargetTable from required input
+    pub fn new(
+        start: Sectors,
+        length: Sectors,
+        params: MirrorTargetParams,
+    ) -> MirrorDevTargetTable {
+        MirrorDevTargetTable {
+            table: TargetLine::new(start, length, params),
+        }
+    }
+}
+
+impl fmt::Display for MirrorDevTargetTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = &self.table;
+        writeln!(f, "{} {} {}", *table.start, *table.length, table.params)
+    }
+}
+
+impl TargetTable for MirrorDevTargetTable {
+    fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<MirrorDevTargetTable> {
+        if table.len() != 1 {
+            let err_msg = format!(
+                "MirrorDev table should have exactly one line, has {} lines",
+                table.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let line = table.first().expect("table.len() == 1");
+        Ok(MirrorDevTargetTable::new(
+            Sectors(line.0),
+            Sectors(line.1),
+            format!("{} {}", line.2, line.3).parse::<MirrorTargetParams>()?,
+        ))
+    }
+
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        to_raw_table_unique!(self)
+    }
+}
+
+/// The health of a leg of a mirror, as reported in the status. A leg
+/// which has had errors is marked with the most serious kind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MirrorLegHealth {
+    /// The leg has had no errors ('A')
+    Alive,
+    /// A flush to the leg failed ('F')
+    FlushFailed,
+    /// A write to the leg failed ('D')
+    WriteFailed,
+    /// Synchronizing the leg failed ('S')
+    SyncFailed,
+    /// A read from the leg failed ('R')
+    ReadFailed,
+    /// The leg had an error of unknown kind ('U')
+    Unknown,
+}
+
+impl MirrorLegHealth {
+    fn from_char(c: char) -> Option<MirrorLegHealth> {
+        match c {
+            'A' => Some(MirrorLegHealth::Alive),
+            'F' => Some(MirrorLegHealth::FlushFailed),
+            'D' => Some(MirrorLegHealth::WriteFailed),
+            'S' => Some(MirrorLegHealth::SyncFailed),
+            'R' => Some(MirrorLegHealth::ReadFailed),
+            'U' => Some(MirrorLegHealth::Unknown),
+            _ => None,
+        }
+    }
+}
+
+/// The status of the dirty region log of a mirror.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MirrorLogStatus {
+    /// The log is kept in memory
+    Core,
+    /// The log is kept on a device, which has failed if `failed`
+    Disk {
+        /// The log device
+        device: Device,
+        /// Whether the log device has failed
+        failed: bool,
+    },
+}
+
+/// Status of a mirror target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MirrorStatus {
+    /// Each leg of the mirror, with its health
+    pub legs: Vec<(Device, MirrorLegHealth)>,
+    /// The number of regions which are in sync
+    pub in_sync_regions: u64,
+    /// The number of regions of the mirror
+    pub total_regions: u64,
+    /// The status of the log
+    pub log: MirrorLogStatus,
+}
+
+impl MirrorStatus {
+    /// Whether every region is in sync.
+    pub fn is_in_sync(&self) -> bool {
+        self.in_sync_regions == self.total_regions
+    }
+
+    /// The indices of the legs which have had errors.
+    pub fn failed_legs(&self) -> Vec<usize> {
+        self.legs
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, health))| *health != MirrorLegHealth::Alive)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+impl FromStr for MirrorStatus {
+    type Err = DmError;
+
+    /// Parse a status line of the form:
+    ///
+    /// ```plain
+    /// <#mirrors> <device> ... <in_sync>/<total> 1 <health_chars> <#log_status> <log_type> [<log_device> <log_health>]
+    /// ```
+    fn from_str(status_line: &str) -> DmResult<MirrorStatus> {
+        let status_vals = get_status_line_fields(status_line, 1)?;
+        let num_legs: usize = parse_value(status_vals[0], "number of mirror legs")?;
+        let status_vals = get_status_line_fields(status_line, num_legs + 6)?;
+
+        let ratio_index = num_legs + 1;
+        let (in_sync, total) = status_vals[ratio_index].split_once('/').ok_or_else(|| {
+            make_unexpected_value_error(
+                ratio_index + 1,
+                status_vals[ratio_index],
+                "mirror sync ratio",
+            )
+        })?;
+
+        let health_index = ratio_index + 2;
+        let health = status_vals[health_index]
+            .chars()
+            .map(MirrorLegHealth::from_char)
+            .collect::<Option<Vec<_>>>()
+            .filter(|health| health.len() == num_legs)
+            .ok_or_else(|| {
+                make_unexpected_value_error(
+                    health_index + 1,
+                    status_vals[health_index],
+                    "mirror leg health",
+                )
+            })?;
+        let legs = status_vals[1..ratio_index]
+            .iter()
+            .map(|device| parse_device(device, "mirror leg"))
+            .zip(health)
+            .map(|(device, health)| device.map(|device| (device, health)))
+            .collect::<DmResult<Vec<_>>>()?;
+
+        let log_index = health_index + 2;
+        let log = match &status_vals[log_index..] {
+            [CORE_LOG_NAME] => MirrorLogStatus::Core,
+            [DISK_LOG_NAME, device, health] => MirrorLogStatus::Disk {
+                device: parse_device(device, "mirror log device")?,
+                failed: match *health {
+                    "A" => false,
+                    "D" => true,
+                    _ => {
+                        return Err(make_unexpected_value_error(
+                            log_index + 3,
+                            health,
+                            "mirror log health",
+                        ))
+                    }
+                },
+            },
+            _ => {
+                return Err(make_unexpected_value_error(
+                    log_index + 1,
+                    status_vals[log_index],
+                    "mirror log status",
+                ))
+            }
+        };
+
+        Ok(MirrorStatus {
+            legs,
+            in_sync_regions: parse_value(in_sync, "in sync regions")?,
+            total_regions: parse_value(total, "total regions")?,
+            log,
+        })
+    }
+}
+
+/// The status of the mirror device `id`, which has a single mirror target.
+pub fn mirror_status(dm: &DM, id: &DevId<'_>) -> DmResult<MirrorStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    get_status(&status)?.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that mirror params with core and disk logs and with features
+    /// round-trip, and that invalid combinations are rejected.
+    fn test_mirror_target_params() {
+        let s = "mirror core 2 1024 nosync 2 8:16 0 8:32 0";
+        let params = s.parse::<MirrorTargetParams>().unwrap();
+        assert_eq!(params.log, MirrorLog::Core);
+        assert_eq!(params.region_size, Sectors(1024));
+        assert_eq!(params.sync, Some(MirrorSync::NoSync));
+        assert_eq!(params.legs.len(), 2);
+        assert!(!params.handle_errors);
+        assert_eq!(params.to_string(), s);
+        assert_matches!(params.check(), Ok(_));
+
+        let s = "mirror disk 2 8:48 1024 2 8:16 0 8:32 128 2 handle_errors keep_log";
+        let mut params = s.parse::<MirrorTargetParams>().unwrap();
+        assert_eq!(
+            params.log,
+            MirrorLog::Disk(Device::from_str("8:48").unwrap())
+        );
+        assert_eq!(params.sync, None);
+        assert_eq!(params.legs[1].1, Sectors(128));
+        assert!(params.handle_errors && params.keep_log);
+        assert_eq!(params.to_string(), s);
+        assert_matches!(params.check(), Ok(_));
+
+        params.handle_errors = false;
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+        params.handle_errors = true;
+        params.region_size = Sectors(1000);
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+
+        assert_matches!(
+            "mirror userspace 1 x 1 8:16 0".parse::<MirrorTargetParams>(),
+            Err(_)
+        );
+        assert_matches!(
+            "mirror core 1 1024 2 8:16 0".parse::<MirrorTargetParams>(),
+            Err(_)
+        );
+        assert_matches!(
+            "mirror core 1 1024 1 8:16 0 1 unknown".parse::<MirrorTargetParams>(),
+            Err(_)
+        );
+    }
+
+    #[test]
+    /// Verify that mirror status lines with core and disk logs are parsed.
+    fn test_mirror_status() {
+        let status = "2 8:16 8:32 10/40 1 AD 3 disk 8:48 A"
+            .parse::<MirrorStatus>()
+            .unwrap();
+        assert_eq!(
+            status.legs,
+            vec![
+                (Device::from_str("8:16").unwrap(), MirrorLegHealth::Alive),
+                (
+                    Device::from_str("8:32").unwrap(),
+                    MirrorLegHealth::WriteFailed
+                ),
+            ]
+        );
+        assert_eq!(status.in_sync_regions, 10);
+        assert_eq!(status.total_regions, 40);
+        assert_eq!(
+            status.log,
+            MirrorLogStatus::Disk {
+                device: Device::from_str("8:48").unwrap(),
+                failed: false
+            }
+        );
+        assert_eq!(status.failed_legs(), vec![1]);
+        assert!(!status.is_in_sync());
+
+        let status = "2 8:16 8:32 40/40 1 AA 1 core"
+            .parse::<MirrorStatus>()
+            .unwrap();
+        assert_eq!(status.log, MirrorLogStatus::Core);
+        assert!(status.is_in_sync());
+
+        assert_matches!("2 8:16 8:32".parse::<MirrorStatus>(), Err(_));
+        assert_matches!(
+            "2 8:16 8:32 40/40 1 A 1 core".parse::<MirrorStatus>(),
+            Err(_)
+        );
+        assert_matches!(
+            "2 8:16 8:32 40/40 1 AA 3 disk 8:48 X".parse::<MirrorStatus>(),
+            Err(_)
+        );
+    }
+}
@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    cachedev::{CacheDevStatus, CACHE_TARGET_NAME},
+    core::{DevId, DeviceInfo, DmChanges, DmName, DmNameBuf, DmOptions, EventSnapshot, DM},
+    raid::{RaidStatus, RAID_TARGET_NAME},
+    result::DmResult,
+    thindev::{ThinStatus, THIN_TARGET_NAME},
+    thinpooldev::{ThinPoolStatus, THINPOOL_TARGET_NAME},
+};
+
+const SNAPSHOT_TARGET_NAME: &str = "snapshot";
+
+/// The status of a single target of a device, parsed if this crate knows
+/// the format of the target's status.
+#[derive(Debug)]
+pub enum TargetStatus {
+    /// The status of a thin-pool target
+    ThinPool(ThinPoolStatus),
+    /// The status of a thin target
+    Thin(ThinStatus),
+    /// The status of a cache target
+    Cache(CacheDevStatus),
+    /// The unparsed status of any other target, or of a target whose status
+    /// could not be parsed
+    Other(String),
+}
+
+impl TargetStatus {
+    fn parse(target_type: &str, status: &str) -> TargetStatus {
+        let parsed = match target_type {
+            THINPOOL_TARGET_NAME => status.parse().map(TargetStatus::ThinPool),
+            THIN_TARGET_NAME => status.parse().map(TargetStatus::Thin),
+            CACHE_TARGET_NAME => status.parse().map(TargetStatus::Cache),
+            _ => return TargetStatus::Other(status.to_string()),
+        };
+        parsed.unwrap_or_else(|err| {
+            warn!("Failed to parse status of {} target: {}", target_type, err);
+            TargetStatus::Other(status.to_string())
+        })
+    }
+}
+
+/// An event on a single target of a monitored device, as passed to an
+/// `EventHandler`.
+#[derive(Debug)]
+pub struct MonitorEvent<'a> {
+    /// The device's info, as returned with its status
+    pub info: &'a DeviceInfo,
+    /// The start of the target within the device, in sectors
+    pub start: u64,
+    /// The length of the target, in sectors
+    pub length: u64,
+    /// The target's type
+    pub target_type: &'a str,
+    /// The target's status
+    pub status: &'a TargetStatus,
+}
+
+impl<'a> MonitorEvent<'a> {
+    /// The name of the device on which the event occurred.
+    pub fn name(&self) -> Option<&DmName> {
+        self.info.name()
+    }
+
+    /// For a raid target, the indices of the legs which have failed, which
+    /// the kernel marks 'D' in the status's health characters. None if the
+    /// target is not a raid target or its status is malformed.
+    pub fn raid_failed_legs(&self) -> Option<Vec<usize>> {
+        raid_failed_legs(self.target_type, self.status)
+    }
+
+    /// For a snapshot target, whether the snapshot has been invalidated,
+    /// e.g., because its exception store overflowed. None if the target is
+    /// not a snapshot target.
+    pub fn snapshot_invalid(&self) -> Option<bool> {
+        snapshot_invalid(self.target_type, self.status)
+    }
+}
+
+fn raid_failed_legs(target_type: &str, status: &TargetStatus) -> Option<Vec<usize>> {
+    match (target_type, status) {
+        (RAID_TARGET_NAME, TargetStatus::Other(status)) => status
+            .parse::<RaidStatus>()
+            .ok()
+            .map(|status| status.failed_devices()),
+        _ => None,
+    }
+}
+
+fn snapshot_invalid(target_type: &str, status: &TargetStatus) -> Option<bool> {
+    match (target_type, status) {
+        (SNAPSHOT_TARGET_NAME, TargetStatus::Other(status)) => {
+            Some(status.starts_with("Invalid") || status.starts_with("Overflow"))
+        }
+        _ => None,
+    }
+}
+
+/// A handler for events on monitored devices. Handlers are implemented for
+/// closures, so a closure may be registered directly.
+pub trait EventHandler {
+    /// Handle an event on a target of a device. An error is logged, and
+    /// does not prevent other handlers from being invoked.
+    fn handle(&mut self, dm: &DM, event: &MonitorEvent<'_>) -> DmResult<()>;
+}
+
+impl<F> EventHandler for F
+where
+    F: FnMut(&DM, &MonitorEvent<'_>) -> DmResult<()>,
+{
+    fn handle(&mut self, dm: &DM, event: &MonitorEvent<'_>) -> DmResult<()> {
+        self(dm, event)
+    }
+}
+
+enum Registration {
+    Device(DmNameBuf),
+    TargetType(String),
+}
+
+/// A framework for monitoring DM devices, in the manner of dmeventd.
+/// Handlers are registered for a device, or for every target of a given
+/// type, such as all thin pools. Whenever a monitored device is added or
+/// reports an event, its status is retrieved and parsed, and the matching
+/// handlers are invoked for each of its targets.
+///
+/// The monitor does not run by itself; `check` should be called
+/// periodically, or whenever `DM::file` becomes readable after
+/// `DM::arm_poll`.
+///
+/// ```no_run
+/// use devicemapper::{DmMonitor, MonitorEvent, TargetStatus, DM};
+///
+/// let dm = DM::new().unwrap();
+/// let mut monitor = DmMonitor::new();
+/// monitor.register_target_type("thin-pool", |_: &DM, event: &MonitorEvent<'_>| {
+///     if let TargetStatus::ThinPool(status) = event.status {
+///         println!("{:?}: {:?}", event.name(), status);
+///     }
+///     Ok(())
+/// });
+/// monitor.check(&dm).unwrap();
+/// ```
+#[derive(Default)]
+pub struct DmMonitor {
+    snapshot: EventSnapshot,
+    handlers: Vec<(Registration, Box<dyn EventHandler>)>,
+}
+
+impl DmMonitor {
+    /// Make a new monitor with no handlers.
+    pub fn new() -> DmMonitor {
+        DmMonitor::default()
+    }
+
+    /// Register a handler for events on the named device.
+    pub fn register_device<H>(&mut self, name: &DmName, handler: H)
+    where
+        H: EventHandler + 'static,
+    {
+        self.handlers
+            .push((Registration::Device(name.to_owned()), Box::new(handler)));
+    }
+
+    /// Register a handler for events on every target of the given type,
+    /// e.g., "thin-pool", "raid", or "snapshot".
+    pub fn register_target_type<H>(&mut self, target_type: &str, handler: H)
+    where
+        H: EventHandler + 'static,
+    {
+        self.handlers.push((
+            Registration::TargetType(target_type.to_string()),
+            Box::new(handler),
+        ));
+    }
+
+    /// Find the devices which have been added or have reported an event
+    /// since the last check, and invoke the matching handlers for each of
+    /// their targets. On the first check, every device is considered to
+    /// have been added. Returns the changes found.
+    pub fn check(&mut self, dm: &DM) -> DmResult<DmChanges> {
+        let changes = dm.changed_since(&mut self.snapshot)?;
+        for name in changes.added.iter().chain(changes.changed.iter()) {
+            if let Err(err) = self.dispatch(dm, name) {
+                warn!("Failed to handle event on device {}: {}", name, err);
+            }
+        }
+        Ok(changes)
+    }
+
+    fn dispatch(&mut self, dm: &DM, name: &DmName) -> DmResult<()> {
+        if !self.handlers.iter().any(|(registration, _)| {
+            matches!(registration, Registration::TargetType(_))
+                || matches!(registration, Registration::Device(n) if **n == *name)
+        }) {
+            return Ok(());
+        }
+
+        let (info, status) = dm.table_status(&DevId::Name(name), DmOptions::default())?;
+        for (start, length, target_type, params) in &status {
+            let status = TargetStatus::parse(target_type, params);
+            let event = MonitorEvent {
+                info: &info,
+                start: *start,
+                length: *length,
+                target_type,
+                status: &status,
+            };
+            for (registration, handler) in self.handlers.iter_mut() {
+                let matched = match registration {
+                    Registration::Device(n) => **n == *name,
+                    Registration::TargetType(t) => t == target_type,
+                };
+                if matched {
+                    if let Err(err) = handler.handle(dm, &event) {
+                        warn!(
+                            "Event handler for {} target of device {} failed: {}",
+                            target_type, name, err
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        core::DmFlags,
+        testing::{test_name, TestGuard},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that the failed legs of a raid target and an invalidated
+    /// snapshot are recognized from their status.
+    fn test_event_helpers() {
+        let check = |target_type, status| {
+            let status = TargetStatus::Other(String::from(status));
+            (
+                raid_failed_legs(target_type, &status),
+                snapshot_invalid(target_type, &status),
+            )
+        };
+        assert_eq!(
+            check("raid", "raid1 3 ADA 1024/1024 idle 0 0 -"),
+            (Some(vec![1]), None)
+        );
+        assert_eq!(check("snapshot", "Invalid"), (None, Some(true)));
+        assert_eq!(check("snapshot", "16/2048 16"), (None, Some(false)));
+        assert_eq!(check("zero", ""), (None, None));
+    }
+
+    #[test]
+    /// Verify that handlers registered for a device and for a target type
+    /// are invoked when a device is added, and not when nothing changed.
+    fn sudo_test_monitor() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut monitor = DmMonitor::new();
+        let device_events = Rc::clone(&events);
+        monitor.register_device(&name, move |_: &DM, event: &MonitorEvent<'_>| {
+            device_events
+                .borrow_mut()
+                .push(("device", event.target_type.to_string()));
+            Ok(())
+        });
+        let target_events = Rc::clone(&events);
+        monitor.register_target_type("zero", move |_: &DM, event: &MonitorEvent<'_>| {
+            if event.name() == Some(&*test_name("example-dev").unwrap()) {
+                assert!(!event.info.flags().contains(DmFlags::DM_SUSPEND));
+                target_events
+                    .borrow_mut()
+                    .push(("target", event.target_type.to_string()));
+            }
+            Ok(())
+        });
+
+        assert!(monitor.check(&dm).unwrap().added.contains(&name));
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                ("device", "zero".to_string()),
+                ("target", "zero".to_string())
+            ]
+        );
+        assert!(monitor.check(&dm).unwrap().is_empty());
+        assert_eq!(events.borrow().len(), 2);
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Control of the paths of a multipath device. The multipath target sends
+// I/O down the paths of its current priority group, and fails a path, and
+// switches to another group if need be, when I/O on the path fails. It
+// does not reinstate a failed path by itself, nor notice a path which has
+// failed while idle; that is the job of a daemon which checks every path
+// periodically and sends the target messages accordingly.
+
+use std::{
+    fs::OpenOptions,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use nix::libc::O_DIRECT;
+
+use crate::{
+    blkdev::{device_path, open_blkdev, DIRECT_IO_ALIGN},
+    core::{DevId, Device, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{parse_device, parse_value},
+};
+
+const MULTIPATH_TARGET_NAME: &str = "multipath";
+
+/// Mark the path `path` of the multipath device `id` as failed, so that no
+/// more I/O is sent down it.
+pub fn multipath_fail_path(dm: &DM, id: &DevId<'_>, path: Device) -> DmResult<()> {
+    dm.target_msg(id, None, &format!("fail_path {path}"))?;
+    Ok(())
+}
+
+/// Reinstate the failed path `path` of the multipath device `id`.
+pub fn multipath_reinstate_path(dm: &DM, id: &DevId<'_>, path: Device) -> DmResult<()> {
+    dm.target_msg(id, None, &format!("reinstate_path {path}"))?;
+    Ok(())
+}
+
+/// Make the priority group `group`, numbered from 1 in table order, the
+/// group to which the multipath device `id` sends I/O.
+pub fn multipath_switch_group(dm: &DM, id: &DevId<'_>, group: u32) -> DmResult<()> {
+    if group == 0 {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            "multipath priority groups are numbered from 1".to_string(),
+        ));
+    }
+    dm.target_msg(id, None, &format!("switch_group {group}"))?;
+    Ok(())
+}
+
+/// Make the multipath device `id` queue I/O, rather than fail it, when it
+/// has no usable path.
+pub fn multipath_queue_if_no_path(dm: &DM, id: &DevId<'_>) -> DmResult<()> {
+    dm.target_msg(id, None, "queue_if_no_path")?;
+    Ok(())
+}
+
+/// Make the multipath device `id` fail I/O when it has no usable path,
+/// including any I/O already queued.
+pub fn multipath_fail_if_no_path(dm: &DM, id: &DevId<'_>) -> DmResult<()> {
+    dm.target_msg(id, None, "fail_if_no_path")?;
+    Ok(())
+}
+
+/// The state of a priority group of a multipath device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MultipathGroupState {
+    /// The group to which I/O is currently sent
+    Active,
+    /// A group which may be switched to
+    Enabled,
+    /// A group which is bypassed until no other group is usable
+    Disabled,
+}
+
+/// The status of a path of a multipath device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultipathPathStatus {
+    /// The path's device
+    pub device: Device,
+    /// Whether the path is usable, rather than failed
+    pub active: bool,
+    /// The number of times the path has failed
+    pub fail_count: u32,
+    /// The path selector's status of the path
+    pub selector_args: Vec<String>,
+}
+
+/// The status of a priority group of a multipath device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultipathGroupStatus {
+    /// The group's state
+    pub state: MultipathGroupState,
+    /// The path selector's status of the group
+    pub selector_args: Vec<String>,
+    /// The group's paths, in table order
+    pub paths: Vec<MultipathPathStatus>,
+}
+
+/// The status of a multipath device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultipathStatus {
+    /// Feature status values, the number of queued I/Os and the number of
+    /// path group initializations
+    pub feature_args: Vec<String>,
+    /// The hardware handler's status
+    pub handler_args: Vec<String>,
+    /// The group, numbered from 1, to be used next
+    pub next_group: u32,
+    /// The priority groups, in table order
+    pub groups: Vec<MultipathGroupStatus>,
+}
+
+impl MultipathStatus {
+    /// Every path of the device, in table order.
+    pub fn paths(&self) -> impl Iterator<Item = &MultipathPathStatus> {
+        self.groups.iter().flat_map(|group| group.paths.iter())
+    }
+}
+
+/// The values of a multipath status line, consumed in order.
+struct StatusVals<'a> {
+    status_line: &'a str,
+    vals: std::str::SplitWhitespace<'a>,
+}
+
+impl<'a> StatusVals<'a> {
+    fn next(&mut self, desc: &str) -> DmResult<&'a str> {
+        self.vals.next().ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "multipath status \"{}\" ends before the {}",
+                    self.status_line, desc
+                ),
+            )
+        })
+    }
+
+    fn value<T: FromStr>(&mut self, desc: &str) -> DmResult<T> {
+        let val = self.next(desc)?;
+        parse_value(val, desc)
+    }
+
+    /// A count followed by that many values.
+    fn counted(&mut self, desc: &str) -> DmResult<Vec<String>> {
+        let count: usize = self.value(&format!("number of {desc}"))?;
+        (0..count)
+            .map(|_| self.next(desc).map(|val| val.to_string()))
+            .collect()
+    }
+}
+
+impl FromStr for MultipathStatus {
+    type Err = DmError;
+
+    fn from_str(status_line: &str) -> DmResult<MultipathStatus> {
+        let mut vals = StatusVals {
+            status_line,
+            vals: status_line.split_whitespace(),
+        };
+
+        let feature_args = vals.counted("feature args")?;
+        let handler_args = vals.counted("hardware handler args")?;
+        let num_groups: usize = vals.value("number of priority groups")?;
+        let next_group = vals.value("next priority group")?;
+
+        let mut groups = Vec::with_capacity(num_groups);
+        for _ in 0..num_groups {
+            let state = match vals.next("priority group state")? {
+                "A" => MultipathGroupState::Active,
+                "E" => MultipathGroupState::Enabled,
+                "D" => MultipathGroupState::Disabled,
+                val => {
+                    return Err(DmError::Dm(
+                        ErrorEnum::Invalid,
+                        format!("unexpected multipath priority group state \"{val}\""),
+                    ))
+                }
+            };
+            let selector_args = vals.counted("path selector args")?;
+            let num_paths: usize = vals.value("number of paths")?;
+            let num_path_args: usize = vals.value("number of path selector path args")?;
+
+            let mut paths = Vec::with_capacity(num_paths);
+            for _ in 0..num_paths {
+                let device = parse_device(vals.next("path")?, "multipath path")?;
+                let active = match vals.next("path state")? {
+                    "A" => true,
+                    "F" => false,
+                    val => {
+                        return Err(DmError::Dm(
+                            ErrorEnum::Invalid,
+                            format!("unexpected multipath path state \"{val}\""),
+                        ))
+                    }
+                };
+                let fail_count = vals.value("path fail count")?;
+                let selector_args = (0..num_path_args)
+                    .map(|_| vals.next("path selector path args").map(|v| v.to_string()))
+                    .collect::<DmResult<Vec<_>>>()?;
+                paths.push(MultipathPathStatus {
+                    device,
+                    active,
+                    fail_count,
+                    selector_args,
+                });
+            }
+            groups.push(MultipathGroupStatus {
+                state,
+                selector_args,
+                paths,
+            });
+        }
+
+        Ok(MultipathStatus {
+            feature_args,
+            handler_args,
+            next_group,
+            groups,
+        })
+    }
+}
+
+/// Get the status of the multipath device `id`.
+pub fn multipath_status(dm: &DM, id: &DevId<'_>) -> DmResult<MultipathStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    match status.as_slice() {
+        [(_, _, target_type, status_line)] if target_type == MULTIPATH_TARGET_NAME => {
+            status_line.parse::<MultipathStatus>()
+        }
+        _ => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("{id} is not a multipath device with a single target"),
+        )),
+    }
+}
+
+/// A path checker in the style of multipathd's readsector0 checker: the
+/// path is usable if the first 4 KiB of it can be read, bypassing the page
+/// cache. A TUR checker, or any other, may be supplied to
+/// `MultipathChecker` in its place.
+pub fn readsector0(path: Device) -> bool {
+    let file = match open_blkdev(
+        &device_path(path),
+        OpenOptions::new().read(true).custom_flags(O_DIRECT),
+    ) {
+        Ok(file) => file,
+        Err(err) => {
+            debug!("Path {} failed readsector0 check: {}", path, err);
+            return false;
+        }
+    };
+
+    // O_DIRECT requires that the buffer be aligned in memory, so pick out an
+    // aligned region of a slightly oversized buffer.
+    let mut buf = vec![0u8; 2 * DIRECT_IO_ALIGN];
+    let align = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    match file.read_exact_at(&mut buf[align..align + DIRECT_IO_ALIGN], 0) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("Path {} failed readsector0 check: {}", path, err);
+            false
+        }
+    }
+}
+
+/// A change to a path made by a `MultipathChecker`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MultipathPathEvent {
+    /// A usable path failed its check and has been failed.
+    Failed(Device),
+    /// A failed path passed its check and has been reinstated.
+    Reinstated(Device),
+}
+
+/// A path checker for a multipath device, which checks every path with a
+/// user-supplied callback and fails or reinstates it according to the
+/// result, as multipathd does. `readsector0` is a suitable callback.
+///
+/// The checker does not run by itself; `check` should be called
+/// periodically, or `run` called to do so.
+pub struct MultipathChecker<F>
+where
+    F: FnMut(Device) -> bool,
+{
+    check_path: F,
+}
+
+impl<F> MultipathChecker<F>
+where
+    F: FnMut(Device) -> bool,
+{
+    /// Make a new checker, which calls `check_path` with each path and
+    /// considers the path usable if it returns true.
+    pub fn new(check_path: F) -> MultipathChecker<F> {
+        MultipathChecker { check_path }
+    }
+
+    /// Check every path of the multipath device `id` once, failing usable
+    /// paths which fail the check and reinstating failed paths which pass
+    /// it. Returns the changes made.
+    pub fn check(&mut self, dm: &DM, id: &DevId<'_>) -> DmResult<Vec<MultipathPathEvent>> {
+        let status = multipath_status(dm, id)?;
+
+        let mut events = Vec::new();
+        for path in status.paths() {
+            let usable = (self.check_path)(path.device);
+            if path.active && !usable {
+                multipath_fail_path(dm, id, path.device)?;
+                debug!("Failed path {} of multipath device {}", path.device, id);
+                events.push(MultipathPathEvent::Failed(path.device));
+            } else if !path.active && usable {
+                multipath_reinstate_path(dm, id, path.device)?;
+                debug!("Reinstated path {} of multipath device {}", path.device, id);
+                events.push(MultipathPathEvent::Reinstated(path.device));
+            }
+        }
+        Ok(events)
+    }
+
+    /// Check the paths of the multipath device `id` every `interval`,
+    /// calling `on_events` with the changes made by each check, until it
+    /// returns false or a check fails.
+    pub fn run<E>(
+        &mut self,
+        dm: &DM,
+        id: &DevId<'_>,
+        interval: Duration,
+        mut on_events: E,
+    ) -> DmResult<()>
+    where
+        E: FnMut(&[MultipathPathEvent]) -> bool,
+    {
+        loop {
+            let events = self.check(dm, id)?;
+            if !on_events(&events) {
+                return Ok(());
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, path::Path};
+
+    use crate::{
+        core::devnode_to_devno,
+        testing::{blkdev_size, test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that the status of a device with groups using path selectors
+    /// with and without per-path status is parsed.
+    fn test_multipath_status() {
+        let status = "2 0 0 0 2 1 A 0 2 0 8:16 A 0 8:32 F 1 E 0 1 2 8:48 A 3 0 1"
+            .parse::<MultipathStatus>()
+            .unwrap();
+        assert_eq!(status.feature_args, vec!["0", "0"]);
+        assert_eq!(status.handler_args, Vec::<String>::new());
+        assert_eq!(status.next_group, 1);
+        assert_eq!(status.groups.len(), 2);
+        assert_eq!(status.groups[0].state, MultipathGroupState::Active);
+        assert_eq!(status.groups[1].state, MultipathGroupState::Enabled);
+        assert_eq!(
+            status
+                .paths()
+                .map(|path| (path.device.to_string(), path.active, path.fail_count))
+                .collect::<Vec<_>>(),
+            vec![
+                ("8:16".to_string(), true, 0),
+                ("8:32".to_string(), false, 1),
+                ("8:48".to_string(), true, 3)
+            ]
+        );
+        assert_eq!(status.groups[1].paths[0].selector_args, vec!["0", "1"]);
+
+        assert_matches!(
+            "2 0 0 0 1 1 A 0 2 0 8:16 A 0".parse::<MultipathStatus>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            "2 0 0 0 1 1 X 0 1 0 8:16 A 0".parse::<MultipathStatus>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    /// Verify that the checker fails and reinstates paths according to its
+    /// callback, and that the path control messages are accepted.
+    fn test_multipath_checker(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = DM::new().unwrap();
+        let devs = paths
+            .iter()
+            .map(|path| Device::from(devnode_to_devno(path).unwrap().unwrap()))
+            .collect::<Vec<_>>();
+        let length = blkdev_size(&OpenOptions::new().read(true).open(paths[0]).unwrap()).sectors();
+
+        let name = test_name("multipath").expect("is valid DM name");
+        let id = DevId::Name(&name);
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let table = [(
+            0,
+            *length,
+            MULTIPATH_TARGET_NAME.to_string(),
+            format!(
+                "0 0 2 1 round-robin 0 1 1 {} 1000 round-robin 0 1 1 {} 1000",
+                devs[0], devs[1]
+            ),
+        )];
+        dm.table_load(&id, &table, DmOptions::default()).unwrap();
+        dm.device_suspend(&id, DmOptions::private()).unwrap();
+
+        assert!(readsector0(devs[0]));
+
+        let down = devs[1];
+        let mut checker = MultipathChecker::new(|path| path != down);
+        assert_eq!(
+            checker.check(&dm, &id).unwrap(),
+            vec![MultipathPathEvent::Failed(down)]
+        );
+        assert_eq!(checker.check(&dm, &id).unwrap(), vec![]);
+        assert!(multipath_status(&dm, &id)
+            .unwrap()
+            .paths()
+            .any(|path| path.device == down && !path.active));
+
+        let mut checks = 0;
+        MultipathChecker::new(readsector0)
+            .run(&dm, &id, Duration::from_millis(10), |events| {
+                checks += 1;
+                if checks == 1 {
+                    assert_eq!(events, [MultipathPathEvent::Reinstated(down)]);
+                }
+                checks < 2
+            })
+            .unwrap();
+
+        multipath_switch_group(&dm, &id, 2).unwrap();
+        assert_matches!(multipath_switch_group(&dm, &id, 0), Err(_));
+        multipath_queue_if_no_path(&dm, &id).unwrap();
+        multipath_fail_if_no_path(&dm, &id).unwrap();
+        multipath_fail_path(&dm, &id, devs[0]).unwrap();
+        multipath_reinstate_path(&dm, &id, devs[0]).unwrap();
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_multipath_checker() {
+        test_with_spec(2, test_multipath_checker);
+    }
+}
@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+};
+
+use nix::{
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent},
+    unistd::close,
+};
+
+use crate::{
+    core::{errors, DmNameBuf, DM_DEV_DIR},
+    result::{DmError, DmResult},
+};
+
+/// The name of the DM control node, which is not a device
+const DM_CONTROL_NAME: &str = "control";
+
+/// A change to the nodes in /dev/mapper, as reported by `DevMapperWatcher`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NodeEvent {
+    /// A node for the named device has appeared.
+    Added(DmNameBuf),
+    /// The node for the named device has gone.
+    Removed(DmNameBuf),
+    /// The node of a device has been renamed, as udev does when a DM device
+    /// is renamed.
+    Renamed {
+        /// The previous name
+        from: DmNameBuf,
+        /// The new name
+        to: DmNameBuf,
+    },
+}
+
+/// The name of a DM device from the name of its node, or None if the node
+/// is not that of a DM device.
+fn node_name(name: Option<&OsStr>) -> Option<DmNameBuf> {
+    let name = name?.to_str()?;
+    if name == DM_CONTROL_NAME {
+        return None;
+    }
+    DmNameBuf::new(name.to_string()).ok()
+}
+
+/// Watches /dev/mapper with inotify and reports the device nodes, usually
+/// symlinks made by udev, which appear, disappear, or are renamed. The
+/// ioctl and uevent based event sources report changes to the devices; the
+/// watcher reports when their nodes are usable by name.
+///
+/// `DevMapperWatcher::read_events` blocks until some events are available.
+/// The watcher's file descriptor may be polled for readability first. The
+/// watcher is also an iterator over the events, which blocks likewise.
+pub struct DevMapperWatcher {
+    inotify: Inotify,
+    pending: VecDeque<NodeEvent>,
+}
+
+impl DevMapperWatcher {
+    /// Watch /dev/mapper.
+    pub fn new() -> DmResult<DevMapperWatcher> {
+        DevMapperWatcher::with_dir(Path::new(DM_DEV_DIR))
+    }
+
+    /// Watch the given directory in place of /dev/mapper.
+    pub fn with_dir(dir: &Path) -> DmResult<DevMapperWatcher> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC).map_err(|err| {
+            DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to initialize inotify: {err}"
+            )))
+        })?;
+        let watcher = DevMapperWatcher {
+            inotify,
+            pending: VecDeque::new(),
+        };
+        watcher
+            .inotify
+            .add_watch(
+                dir,
+                AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_DELETE
+                    | AddWatchFlags::IN_MOVED_FROM
+                    | AddWatchFlags::IN_MOVED_TO
+                    | AddWatchFlags::IN_ONLYDIR,
+            )
+            .map_err(|err| {
+                DmError::Core(errors::Error::GeneralIo(format!(
+                    "failed to watch {}: {}",
+                    dir.display(),
+                    err
+                )))
+            })?;
+        Ok(watcher)
+    }
+
+    /// Convert a batch of inotify events into node events. A node moved
+    /// out of the directory is reported as removed, and one moved into it
+    /// as added, unless both moves are in the batch, when the node is
+    /// reported as renamed.
+    fn node_events(events: Vec<InotifyEvent>) -> Vec<NodeEvent> {
+        let moved = |flag: AddWatchFlags| {
+            events
+                .iter()
+                .filter(move |event| event.mask.contains(flag))
+                .filter_map(|event| Some((event.cookie, node_name(event.name.as_deref())?)))
+        };
+        let mut moved_from: HashMap<_, _> = moved(AddWatchFlags::IN_MOVED_FROM).collect();
+        let moved_to: HashSet<u32> = moved(AddWatchFlags::IN_MOVED_TO)
+            .map(|(cookie, _)| cookie)
+            .collect();
+
+        let mut node_events = Vec::new();
+        for event in &events {
+            let name = match node_name(event.name.as_deref()) {
+                Some(name) => name,
+                None => continue,
+            };
+            if event.mask.contains(AddWatchFlags::IN_CREATE) {
+                node_events.push(NodeEvent::Added(name));
+            } else if event.mask.contains(AddWatchFlags::IN_DELETE) {
+                node_events.push(NodeEvent::Removed(name));
+            } else if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                // A node renamed within the directory is reported when the
+                // MOVED_TO event, which follows, is found.
+                if !moved_to.contains(&event.cookie) {
+                    node_events.push(NodeEvent::Removed(name));
+                }
+            } else if event.mask.contains(AddWatchFlags::IN_MOVED_TO) {
+                match moved_from.remove(&event.cookie) {
+                    Some(from) => node_events.push(NodeEvent::Renamed { from, to: name }),
+                    None => node_events.push(NodeEvent::Added(name)),
+                }
+            }
+        }
+        node_events
+    }
+
+    /// Wait for changes to the directory, and return them in the order in
+    /// which they occurred.
+    pub fn read_events(&mut self) -> DmResult<Vec<NodeEvent>> {
+        if !self.pending.is_empty() {
+            return Ok(self.pending.drain(..).collect());
+        }
+        loop {
+            let events = self.inotify.read_events().map_err(|err| {
+                DmError::Core(errors::Error::GeneralIo(format!(
+                    "failed to read inotify events: {err}"
+                )))
+            })?;
+            let node_events = DevMapperWatcher::node_events(events);
+            if !node_events.is_empty() {
+                return Ok(node_events);
+            }
+        }
+    }
+}
+
+impl Iterator for DevMapperWatcher {
+    type Item = DmResult<NodeEvent>;
+
+    fn next(&mut self) -> Option<DmResult<NodeEvent>> {
+        if self.pending.is_empty() {
+            match self.read_events() {
+                Ok(events) => self.pending.extend(events),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+impl AsRawFd for DevMapperWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+impl Drop for DevMapperWatcher {
+    fn drop(&mut self) {
+        let _ = close(self.inotify.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn name(name: &str) -> DmNameBuf {
+        DmNameBuf::new(name.to_string()).unwrap()
+    }
+
+    #[test]
+    /// Verify that nodes which are created, renamed, and removed are
+    /// reported, and that the control node is ignored.
+    fn test_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = DevMapperWatcher::with_dir(dir.path()).unwrap();
+
+        fs::write(dir.path().join("control"), "").unwrap();
+        fs::write(dir.path().join("a"), "").unwrap();
+        assert_eq!(
+            watcher.read_events().unwrap(),
+            vec![NodeEvent::Added(name("a"))]
+        );
+
+        fs::rename(dir.path().join("a"), dir.path().join("b")).unwrap();
+        assert_eq!(
+            watcher.next().unwrap().unwrap(),
+            NodeEvent::Renamed {
+                from: name("a"),
+                to: name("b")
+            }
+        );
+
+        fs::remove_file(dir.path().join("b")).unwrap();
+        assert_eq!(
+            watcher.read_events().unwrap(),
+            vec![NodeEvent::Removed(name("b"))]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+#[cfg(feature = "json")]
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "json")]
+use crate::result::{DmError, DmResult, ErrorEnum};
+use crate::{
+    cachedev::{CacheIoMode, CachePolicy, MIN_CACHE_BLOCK_SIZE},
+    thinpooldev::{ThinPoolFeature, MIN_DATA_BLOCK_SIZE},
+    units::{Bytes, DataBlocks, Sectors},
+};
+
+/// Default parameters for a thin pool.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThinPoolProfile {
+    /// Block size for allocations within the pool
+    pub data_block_size: Sectors,
+    /// Amount of free space left at which to trigger the low water mark
+    pub low_water_mark: DataBlocks,
+    /// Feature arguments
+    pub features: Vec<ThinPoolFeature>,
+}
+
+impl Default for ThinPoolProfile {
+    fn default() -> ThinPoolProfile {
+        ThinPoolProfile {
+            data_block_size: MIN_DATA_BLOCK_SIZE,
+            low_water_mark: DataBlocks(1),
+            features: Vec::new(),
+        }
+    }
+}
+
+/// Default parameters for a cache.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheProfile {
+    /// Cache block size
+    pub cache_block_size: Sectors,
+    /// IO mode
+    pub io_mode: CacheIoMode,
+    /// Replacement policy
+    pub policy: CachePolicy,
+}
+
+impl Default for CacheProfile {
+    fn default() -> CacheProfile {
+        CacheProfile {
+            cache_block_size: MIN_CACHE_BLOCK_SIZE,
+            io_mode: CacheIoMode::Writethrough,
+            policy: CachePolicy::Default,
+        }
+    }
+}
+
+/// Default parameters for a crypt device.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CryptProfile {
+    /// The cipher specification
+    pub cipher: String,
+    /// The size of the key
+    pub key_size: Bytes,
+    /// The size of the unit of encryption, 512 bytes if None
+    pub sector_size: Option<Bytes>,
+}
+
+impl Default for CryptProfile {
+    /// The defaults of cryptsetup for LUKS2 devices.
+    fn default() -> CryptProfile {
+        CryptProfile {
+            cipher: "aes-xts-plain64".to_string(),
+            key_size: Bytes(64),
+            sector_size: None,
+        }
+    }
+}
+
+/// Default parameters per target type, for the constructors which do not
+/// take every parameter explicitly, e.g., `CacheDev::new_from_profile` and
+/// `ThinPoolDev::new_from_profile`. The profiles are passed to each such
+/// constructor, so that a program may keep its site-wide policy in one
+/// `Profiles` value.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profiles {
+    /// Defaults for thin pools
+    pub thin_pool: ThinPoolProfile,
+    /// Defaults for caches
+    pub cache: CacheProfile,
+    /// Defaults for crypt devices
+    pub crypt: CryptProfile,
+}
+
+#[cfg(feature = "json")]
+impl Profiles {
+    /// Parse profiles from a JSON object of the form:
+    ///
+    /// ```json
+    /// {
+    ///     "thin_pool": {
+    ///         "data_block_size": 128,
+    ///         "low_water_mark": 1,
+    ///         "features": ["skip_block_zeroing"]
+    ///     },
+    ///     "cache": {
+    ///         "cache_block_size": 64,
+    ///         "io_mode": "writeback",
+    ///         "policy": "smq"
+    ///     },
+    ///     "crypt": {
+    ///         "cipher": "aes-xts-plain64",
+    ///         "key_size": 64,
+    ///         "sector_size": 4096
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// Block sizes are in sectors, the low water mark in data blocks, and
+    /// the crypt sizes in bytes. Any member may be omitted, in which case
+    /// the default is used.
+    pub fn from_json(json: &str) -> DmResult<Profiles> {
+        serde_json::from_str(json).map_err(|err| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("failed to parse profiles: {err}"),
+            )
+        })
+    }
+
+    /// Read profiles from a JSON file, in the format accepted by
+    /// `Profiles::from_json`.
+    pub fn load(path: &Path) -> DmResult<Profiles> {
+        let json = fs::read_to_string(path).map_err(|err| {
+            DmError::Dm(
+                ErrorEnum::Error,
+                format!("failed to read profiles from {}: {err}", path.display()),
+            )
+        })?;
+        Profiles::from_json(&json)
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that members present in the JSON override the defaults and
+    /// that absent members keep them.
+    fn test_from_json() {
+        let profiles = Profiles::from_json(
+            r#"{
+                "thin_pool": {"data_block_size": 256, "features": ["skip_block_zeroing"]},
+                "cache": {"policy": "smq"},
+                "crypt": {"sector_size": 4096}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(profiles.thin_pool.data_block_size, Sectors(256));
+        assert_eq!(
+            profiles.thin_pool.low_water_mark,
+            ThinPoolProfile::default().low_water_mark
+        );
+        assert_eq!(
+            profiles.thin_pool.features,
+            vec![ThinPoolFeature::SkipBlockZeroing]
+        );
+        assert_eq!(profiles.cache.policy, CachePolicy::Smq);
+        assert_eq!(profiles.cache.io_mode, CacheIoMode::Writethrough);
+        assert_eq!(profiles.crypt.sector_size, Some(Bytes(4096)));
+        assert_eq!(profiles.crypt.cipher, CryptProfile::default().cipher);
+
+        assert_eq!(Profiles::from_json("{}").unwrap(), Profiles::default());
+    }
+
+    #[test]
+    /// Verify that malformed profiles are rejected.
+    fn test_from_json_invalid() {
+        assert_matches!(Profiles::from_json("1"), Err(_));
+        assert_matches!(
+            Profiles::from_json(r#"{"thin_pool": {"data_block_size": "big"}}"#),
+            Err(_)
+        );
+        assert_matches!(
+            Profiles::from_json(r#"{"cache": {"io_mode": "writearound"}}"#),
+            Err(_)
+        );
+        assert_matches!(Profiles::from_json(r#"{"raid": {}}"#), Err(_));
+    }
+}
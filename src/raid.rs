@@ -0,0 +1,949 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Support for the raid target, which provides the md raid personalities,
+// raid1, raid4/5/6 and raid10, over a set of devices. Each member of the
+// array is a data device and, optionally, a metadata device on which md
+// keeps its superblock and write-intent bitmap. A member which is missing
+// is given as "-".
+//
+// The array is scrubbed, or its background synchronization controlled, by
+// sending the target a message naming a sync action; the action in
+// progress, and how far it has got, is reported in the target's status.
+
+use std::{fmt, str::FromStr, thread, time::Duration};
+
+use crate::{
+    core::{DevId, Device, DmFlags, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    resyncmonitor::ResyncAction,
+    shared::{
+        get_status, get_status_line_fields, make_unexpected_value_error, parse_device, parse_value,
+        TargetLine, TargetParams, TargetTable, TargetTypeBuf,
+    },
+    units::Sectors,
+};
+
+pub(crate) const RAID_TARGET_NAME: &str = "raid";
+
+/// The placeholder for a missing metadata or data device
+const MISSING_DEVICE: &str = "-";
+
+/// The raid params which are flags, taking no value; every other optional
+/// raid param is a key followed by a value
+const RAID_FLAG_PARAMS: &[&str] = &["sync", "nosync"];
+
+/// The raid param naming a member to be rebuilt
+const REBUILD_PARAM: &str = "rebuild";
+/// The raid param naming a member of a raid1 array to which reads are not
+/// sent unless necessary
+const WRITE_MOSTLY_PARAM: &str = "write_mostly";
+/// The raid10 param giving the number of copies of each chunk
+const RAID10_COPIES_PARAM: &str = "raid10_copies";
+/// The raid10 param giving the layout of the copies of each chunk
+const RAID10_FORMAT_PARAM: &str = "raid10_format";
+
+/// The raid level which alone takes the raid10 params
+const RAID10_TYPE: &str = "raid10";
+
+/// The layout of the copies of each chunk of a raid10 array.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Raid10Format {
+    /// The copies of a chunk are on adjacent members, in the same stripe
+    Near,
+    /// The copies of a chunk are in different parts of the members, far
+    /// apart
+    Far,
+    /// The copies of a chunk are in adjacent stripes, each offset by one
+    /// member from the last
+    Offset,
+}
+
+impl fmt::Display for Raid10Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Raid10Format::Near => write!(f, "near"),
+            Raid10Format::Far => write!(f, "far"),
+            Raid10Format::Offset => write!(f, "offset"),
+        }
+    }
+}
+
+impl FromStr for Raid10Format {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<Raid10Format> {
+        match s {
+            "near" => Ok(Raid10Format::Near),
+            "far" => Ok(Raid10Format::Far),
+            "offset" => Ok(Raid10Format::Offset),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown raid10 format \"{s}\""),
+            )),
+        }
+    }
+}
+
+/// A member of a raid array.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RaidDevice {
+    /// The device holding the member's superblock and bitmap, if any
+    pub metadata: Option<Device>,
+    /// The device holding the member's data, None if the member is missing
+    pub data: Option<Device>,
+}
+
+impl RaidDevice {
+    /// Create a new RaidDevice struct
+    pub fn new(metadata: Option<Device>, data: Option<Device>) -> RaidDevice {
+        RaidDevice { metadata, data }
+    }
+}
+
+fn format_member(device: Option<Device>) -> String {
+    device.map_or_else(|| MISSING_DEVICE.to_string(), |device| device.to_string())
+}
+
+fn parse_member(val: &str, desc: &str) -> DmResult<Option<Device>> {
+    if val == MISSING_DEVICE {
+        Ok(None)
+    } else {
+        parse_device(val, desc).map(Some)
+    }
+}
+
+/// Target params for raid target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RaidTargetParams {
+    /// The raid level and layout, e.g., "raid1", "raid5_ls" or "raid10"
+    pub raid_type: String,
+    /// The chunk size; ignored by raid1, which has no chunks
+    pub chunk_size: Sectors,
+    /// The indices of the members to be rebuilt, e.g., because they have
+    /// been replaced
+    pub rebuild: Vec<usize>,
+    /// The indices of the members of a raid1 array which are written to,
+    /// but read from only if no other member can be, e.g., because they
+    /// are slow or remote
+    pub write_mostly: Vec<usize>,
+    /// For a raid10 array, the number of copies of each chunk; the kernel
+    /// keeps 2 if None
+    pub raid10_copies: Option<usize>,
+    /// For a raid10 array, the layout of the copies; the kernel uses near
+    /// if None
+    pub raid10_format: Option<Raid10Format>,
+    /// Other optional raid params, e.g., "region_size 1024", as they appear
+    /// in the table
+    pub optional_args: Vec<String>,
+    /// The members of the array
+    pub devices: Vec<RaidDevice>,
+}
+
+impl RaidTargetParams {
+    /// Create a new RaidTargetParams struct
+    pub fn new(
+        raid_type: String,
+        chunk_size: Sectors,
+        devices: Vec<RaidDevice>,
+    ) -> RaidTargetParams {
+        RaidTargetParams {
+            raid_type,
+            chunk_size,
+            rebuild: Vec::new(),
+            write_mostly: Vec::new(),
+            raid10_copies: None,
+            raid10_format: None,
+            optional_args: Vec::new(),
+            devices,
+        }
+    }
+
+    /// The raid params, as they appear in the table, beginning with the
+    /// chunk size.
+    fn raid_params(&self) -> Vec<String> {
+        let mut raid_params = vec![(*self.chunk_size).to_string()];
+        raid_params.extend(self.optional_args.iter().cloned());
+        if let Some(copies) = self.raid10_copies {
+            raid_params.push(RAID10_COPIES_PARAM.to_string());
+            raid_params.push(copies.to_string());
+        }
+        if let Some(format) = self.raid10_format {
+            raid_params.push(RAID10_FORMAT_PARAM.to_string());
+            raid_params.push(format.to_string());
+        }
+        for (param, indices) in [
+            (REBUILD_PARAM, &self.rebuild),
+            (WRITE_MOSTLY_PARAM, &self.write_mostly),
+        ] {
+            for index in indices {
+                raid_params.push(param.to_string());
+                raid_params.push(index.to_string());
+            }
+        }
+        raid_params
+    }
+
+    /// Take account of the optional raid params, as they appear in the
+    /// table, each with its value, if it has one.
+    fn set_raid_params(&mut self, params: &[&str]) -> DmResult<()> {
+        let mut params = params.iter();
+        while let Some(param) = params.next() {
+            if RAID_FLAG_PARAMS.contains(param) {
+                self.optional_args.push(param.to_string());
+                continue;
+            }
+            let value = params.next().ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!("raid param \"{param}\" has no value"),
+                )
+            })?;
+            match *param {
+                REBUILD_PARAM => self
+                    .rebuild
+                    .push(parse_value(value, "index of member to rebuild")?),
+                WRITE_MOSTLY_PARAM => self
+                    .write_mostly
+                    .push(parse_value(value, "index of write mostly member")?),
+                RAID10_COPIES_PARAM => {
+                    self.raid10_copies = Some(parse_value(value, "number of raid10 copies")?)
+                }
+                RAID10_FORMAT_PARAM => self.raid10_format = Some(value.parse()?),
+                _ => {
+                    self.optional_args.push(param.to_string());
+                    self.optional_args.push(value.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify that the params name only members of the array, that members
+    /// are made write mostly only in a raid1 array, and that the raid10
+    /// params are given only for a raid10 array, with at least two copies
+    /// and no more copies than members, so that a table with these params
+    /// is not refused by the kernel for these reasons.
+    pub fn check(&self) -> DmResult<()> {
+        for (param, indices) in [
+            (REBUILD_PARAM, &self.rebuild),
+            (WRITE_MOSTLY_PARAM, &self.write_mostly),
+        ] {
+            if let Some(index) = indices.iter().find(|i| **i >= self.devices.len()) {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "{param} names member {index}, but the array has only {} members",
+                        self.devices.len()
+                    ),
+                ));
+            }
+        }
+        if !self.write_mostly.is_empty() && self.raid_type != "raid1" {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "{WRITE_MOSTLY_PARAM} applies only to raid1, not {}",
+                    self.raid_type
+                ),
+            ));
+        }
+        if (self.raid10_copies.is_some() || self.raid10_format.is_some())
+            && self.raid_type != RAID10_TYPE
+        {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "{RAID10_COPIES_PARAM} and {RAID10_FORMAT_PARAM} apply only to raid10, not {}",
+                    self.raid_type
+                ),
+            ));
+        }
+        if let Some(copies) = self.raid10_copies {
+            if copies < 2 || copies > self.devices.len() {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "{RAID10_COPIES_PARAM} must be from 2 to {}, is {copies}",
+                        self.devices.len()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RaidTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <raid_type> <#raid_params> <chunk_size> [<raid_params>] <#raid_devs> <metadata_dev> <data_dev> ...
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", RAID_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for RaidTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<RaidTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() < 5 {
+            let err_msg = format!(
+                "expected at least 5 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != RAID_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a raid target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let num_raid_params: usize = parse_value(vals[2], "number of raid params")?;
+        if num_raid_params == 0 {
+            let err_msg = format!("raid params in params string \"{s}\" have no chunk size");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let end_raid_params_index = 3 + num_raid_params;
+        let num_devs: usize = parse_value(
+            vals.get(end_raid_params_index).ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!("expected {num_raid_params} raid params in params string \"{s}\""),
+                )
+            })?,
+            "number of raid devices",
+        )?;
+        if vals.len() != end_raid_params_index + 1 + 2 * num_devs {
+            let err_msg = format!(
+                "expected {} raid devices in params string \"{}\", found {} values",
+                num_devs,
+                s,
+                vals.len() - end_raid_params_index - 1
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let devices = vals[end_raid_params_index + 1..]
+            .chunks(2)
+            .map(|member| {
+                Ok(RaidDevice::new(
+                    parse_member(member[0], "metadata device for raid target")?,
+                    parse_member(member[1], "data device for raid target")?,
+                ))
+            })
+            .collect::<DmResult<Vec<_>>>()?;
+
+        let mut params = RaidTargetParams::new(
+            vals[1].to_string(),
+            Sectors(parse_value(vals[3], "chunk size")?),
+            devices,
+        );
+        params.set_raid_params(&vals[4..end_raid_params_index])?;
+        Ok(params)
+    }
+}
+
+impl TargetParams for RaidTargetParams {
+    fn param_str(&self) -> String {
+        let raid_params = self.raid_params();
+        format!(
+            "{} {} {} {} {}",
+            self.raid_type,
+            raid_params.len(),
+            raid_params.join(" "),
+            self.devices.len(),
+            self.devices
+                .iter()
+                .map(|member| format!(
+                    "{} {}",
+                    format_member(member.metadata),
+                    format_member(member.data)
+                ))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(RAID_TARGET_NAME.into()).expect("RAID_TARGET_NAME is valid")
+    }
+}
+
+/// A target table for a raid device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RaidDevTargetTable {
+    /// The device's table
+    pub table: TargetLine<RaidTargetParams>,
+}
+
+impl RaidDevTargetTable {
+    /// Make a new RaidDevTargetTable from required input
+    pub fn new(start: Sectors, length: Sectors, params: RaidTargetParams) -> RaidDevTargetTable {
+        RaidDevTargetTable {
+            table: TargetLine::new(start, length, params),
+        }
+    }
+}
+
+impl fmt::Display for RaidDevTargetTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = &self.table;
+        writeln!(f, "{} {} {}", *table.start, *table.length, table.params)
+    }
+}
+
+impl TargetTable for RaidDevTargetTable {
+    fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<RaidDevTargetTable> {
+        if table.len() != 1 {
+            let err_msg = format!(
+                "RaidDev table should have exactly one line, has {} lines",
+                table.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let line = table.first().expect("table.len() == 1");
+        Ok(RaidDevTargetTable::new(
+            Sectors(line.0),
+            Sectors(line.1),
+            format!("{} {}", line.2, line.3).parse::<RaidTargetParams>()?,
+        ))
+    }
+
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        to_raw_table_unique!(self)
+    }
+}
+
+/// The health of a member of a raid array, as reported in the status.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RaidDeviceHealth {
+    /// The member is alive and in sync ('A')
+    InSync,
+    /// The member is alive, but not yet in sync, e.g., while it is being
+    /// rebuilt ('a')
+    Syncing,
+    /// The member has failed ('D')
+    Failed,
+    /// The member is missing ('-')
+    Missing,
+}
+
+impl RaidDeviceHealth {
+    fn from_char(c: char) -> Option<RaidDeviceHealth> {
+        match c {
+            'A' => Some(RaidDeviceHealth::InSync),
+            'a' => Some(RaidDeviceHealth::Syncing),
+            'D' => Some(RaidDeviceHealth::Failed),
+            '-' => Some(RaidDeviceHealth::Missing),
+            _ => None,
+        }
+    }
+}
+
+/// Status of a raid target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RaidStatus {
+    /// The raid level and layout
+    pub raid_type: String,
+    /// The health of each member of the array
+    pub health: Vec<RaidDeviceHealth>,
+    /// How much of the array has been synchronized, or processed by the
+    /// sync action in progress
+    pub in_sync: Sectors,
+    /// The size of the array, per member
+    pub total: Sectors,
+    /// The sync action in progress
+    pub sync_action: ResyncAction,
+    /// The number of discrepancies found by the last check or repair
+    pub mismatch_count: u64,
+    /// The offset of the data on each data device; reported by version
+    /// 1.9.0 and later
+    pub data_offset: Option<Sectors>,
+}
+
+impl RaidStatus {
+    /// The indices of the members of the array which have failed.
+    pub fn failed_devices(&self) -> Vec<usize> {
+        self.health
+            .iter()
+            .enumerate()
+            .filter(|(_, health)| **health == RaidDeviceHealth::Failed)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Whether every member of the array is in sync and no sync action is
+    /// in progress.
+    pub fn is_in_sync(&self) -> bool {
+        self.sync_action == ResyncAction::Idle
+            && self.in_sync == self.total
+            && self
+                .health
+                .iter()
+                .all(|health| *health == RaidDeviceHealth::InSync)
+    }
+}
+
+impl FromStr for RaidStatus {
+    type Err = DmError;
+
+    /// Parse a status line of the form:
+    ///
+    /// ```plain
+    /// <raid_type> <#devices> <health_chars> <sync_ratio> <sync_action> <mismatch_cnt> [<data_offset> [<journal_char>]]
+    /// ```
+    fn from_str(status_line: &str) -> DmResult<RaidStatus> {
+        let status_vals = get_status_line_fields(status_line, 6)?;
+
+        let num_devices: usize = parse_value(status_vals[1], "number of raid devices")?;
+        let health = status_vals[2]
+            .chars()
+            .map(|c| {
+                RaidDeviceHealth::from_char(c)
+                    .ok_or_else(|| make_unexpected_value_error(3, status_vals[2], "raid health"))
+            })
+            .collect::<DmResult<Vec<_>>>()?;
+        if health.len() != num_devices {
+            return Err(make_unexpected_value_error(
+                3,
+                status_vals[2],
+                "raid health",
+            ));
+        }
+
+        let (in_sync, total) = status_vals[3]
+            .split_once('/')
+            .ok_or_else(|| make_unexpected_value_error(4, status_vals[3], "raid sync ratio"))?;
+
+        Ok(RaidStatus {
+            raid_type: status_vals[0].to_string(),
+            health,
+            in_sync: Sectors(parse_value(in_sync, "in sync sectors")?),
+            total: Sectors(parse_value(total, "total sectors")?),
+            sync_action: status_vals[4].parse()?,
+            mismatch_count: parse_value(status_vals[5], "mismatch count")?,
+            data_offset: status_vals
+                .get(6)
+                .map(|offset| parse_value(offset, "data offset").map(Sectors))
+                .transpose()?,
+        })
+    }
+}
+
+/// The status of the raid device `id`, which has a single raid target.
+pub fn raid_status(dm: &DM, id: &DevId<'_>) -> DmResult<RaidStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    get_status(&status)?.parse()
+}
+
+/// Start the sync action `action` on the raid device `id`, e.g.,
+/// `ResyncAction::Check` to scrub the array, counting discrepancies, or
+/// `ResyncAction::Repair` to scrub it, repairing them.
+/// `ResyncAction::Frozen` suspends all sync actions, and
+/// `ResyncAction::Idle` interrupts the action in progress, which is
+/// restarted later if it is needed to bring the array into sync.
+///
+/// The kernel refuses to start an action while another is in progress.
+/// A reshape is started by reloading the table with a new layout, not by a
+/// message, so `ResyncAction::Reshape` is refused.
+pub fn raid_sync_action(dm: &DM, id: &DevId<'_>, action: ResyncAction) -> DmResult<()> {
+    if action == ResyncAction::Reshape {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            "a reshape can not be started by a sync action message".to_string(),
+        ));
+    }
+    dm.target_msg(id, None, &action.to_string())?;
+    Ok(())
+}
+
+/// Wait until no sync action is in progress on the raid device `id`,
+/// polling its status every `interval`. `progress` is called with each
+/// status sampled. Returns the last status, from which the mismatch count
+/// of a check or repair may be read. A frozen array is not waited for.
+pub fn raid_wait_sync_action<F>(
+    dm: &DM,
+    id: &DevId<'_>,
+    interval: Duration,
+    mut progress: F,
+) -> DmResult<RaidStatus>
+where
+    F: FnMut(&RaidStatus),
+{
+    loop {
+        let status = raid_status(dm, id)?;
+        progress(&status);
+        if !status.sync_action.in_progress() {
+            return Ok(status);
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Scrub the raid device `id`: check every stripe of the array, repairing
+/// discrepancies if `repair` is true, and wait for the scrub to complete,
+/// polling every `interval`. Returns the number of discrepancies found.
+pub fn raid_scrub(dm: &DM, id: &DevId<'_>, repair: bool, interval: Duration) -> DmResult<u64> {
+    let action = if repair {
+        ResyncAction::Repair
+    } else {
+        ResyncAction::Check
+    };
+    raid_sync_action(dm, id, action)?;
+    let status = raid_wait_sync_action(dm, id, interval, |_| ())?;
+    if status.sync_action == ResyncAction::Frozen {
+        return Err(DmError::Dm(
+            ErrorEnum::Error,
+            format!("sync actions on {id} were frozen before the {action} completed"),
+        ));
+    }
+    Ok(status.mismatch_count)
+}
+
+/// Replace the failed or missing member `slot` of the raid device `id`
+/// with `replacement`, and rebuild it. The device is reloaded with a table
+/// which names the replacement and marks it for rebuild, and its status is
+/// then polled every `interval`, with `progress` called with each status
+/// sampled, until the array is in sync.
+///
+/// Returns the table which the array should be given when it is next
+/// activated, which is the one loaded without the rebuild of `slot`, since
+/// a table which still names it would rebuild it again.
+pub fn raid_replace_device<F>(
+    dm: &DM,
+    id: &DevId<'_>,
+    slot: usize,
+    replacement: RaidDevice,
+    interval: Duration,
+    mut progress: F,
+) -> DmResult<RaidDevTargetTable>
+where
+    F: FnMut(&RaidStatus),
+{
+    let (_, table) =
+        dm.table_status(id, DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE))?;
+    let mut table = RaidDevTargetTable::from_raw_table(&table)?;
+    let status = raid_status(dm, id)?;
+    match status.health.get(slot) {
+        Some(RaidDeviceHealth::Failed) | Some(RaidDeviceHealth::Missing) => (),
+        Some(health) => {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("member {slot} of {id} has not failed, its health is {health:?}"),
+            ))
+        }
+        None => {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "{id} has no member {slot}, it has only {} members",
+                    status.health.len()
+                ),
+            ))
+        }
+    }
+
+    let params = &mut table.table.params;
+    params.devices[slot] = replacement;
+    if !params.rebuild.contains(&slot) {
+        params.rebuild.push(slot);
+    }
+    params.check()?;
+
+    // Load the new table while the device is still live, so that a table
+    // which the kernel rejects never leaves the array suspended.
+    dm.table_load(id, &table.to_raw_table(), DmOptions::default())?;
+    if let Err(err) = dm.device_suspend(id, DmOptions::default().set_flags(DmFlags::DM_SUSPEND)) {
+        dm.table_clear(id)?;
+        return Err(err);
+    }
+    dm.device_suspend(id, DmOptions::private())?;
+
+    loop {
+        let status = raid_wait_sync_action(dm, id, interval, &mut progress)?;
+        if status.is_in_sync() {
+            break;
+        }
+        if status.sync_action == ResyncAction::Frozen
+            || status.health.get(slot) == Some(&RaidDeviceHealth::Failed)
+        {
+            return Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("rebuild of member {slot} of {id} did not complete: {status:?}"),
+            ));
+        }
+        // The rebuild has not yet been started by md.
+        thread::sleep(interval);
+    }
+
+    table.table.params.rebuild.retain(|index| *index != slot);
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::devnode_to_devno,
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that raid params round-trip, with flag and valued raid
+    /// params and missing members.
+    fn test_raid_target_params() {
+        let s = "raid raid1 4 0 nosync region_size 1024 2 - 8:16 8:32 8:48";
+        let params = s.parse::<RaidTargetParams>().unwrap();
+        assert_eq!(params.raid_type, "raid1");
+        assert_eq!(params.chunk_size, Sectors(0));
+        assert_eq!(params.optional_args, vec!["nosync", "region_size", "1024"]);
+        assert_eq!(
+            params.devices,
+            vec![
+                RaidDevice::new(None, Some(Device::from_str("8:16").unwrap())),
+                RaidDevice::new(
+                    Some(Device::from_str("8:32").unwrap()),
+                    Some(Device::from_str("8:48").unwrap())
+                ),
+            ]
+        );
+        assert_eq!(params.to_string(), s);
+
+        assert_matches!(
+            "raid raid1 2 0 region_size 2 - 8:16 - 8:32".parse::<RaidTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            "raid raid1 1 0 2 - 8:16".parse::<RaidTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            "raid raid1 0 2 - 8:16 - 8:32".parse::<RaidTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that the members to rebuild and the write mostly members are
+    /// parsed into typed fields, and that members outside the array are
+    /// rejected.
+    fn test_raid_member_params() {
+        let s = "raid raid1 5 0 region_size 1024 rebuild 1 2 - 8:16 - 8:32";
+        let mut params = s.parse::<RaidTargetParams>().unwrap();
+        assert_eq!(params.rebuild, vec![1]);
+        assert!(params.write_mostly.is_empty());
+        assert_eq!(params.optional_args, vec!["region_size", "1024"]);
+        assert_eq!(params.to_string(), s);
+        assert_matches!(params.check(), Ok(_));
+
+        params.write_mostly.push(0);
+        assert_eq!(
+            params.param_str(),
+            "raid1 7 0 region_size 1024 rebuild 1 write_mostly 0 2 - 8:16 - 8:32"
+        );
+        assert_eq!(
+            params.to_string().parse::<RaidTargetParams>().unwrap(),
+            params
+        );
+
+        params.rebuild.push(2);
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+        params.rebuild.pop();
+        params.raid_type = "raid5_ls".to_string();
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+    }
+
+    #[test]
+    /// Verify that the raid10 layout is parsed into typed fields, and that
+    /// invalid numbers of copies, and raid10 params on other raid levels,
+    /// are rejected.
+    fn test_raid10_params() {
+        let s = "raid raid10 5 128 raid10_copies 3 raid10_format far 3 - 8:16 - 8:32 - 8:48";
+        let mut params = s.parse::<RaidTargetParams>().unwrap();
+        assert_eq!(params.raid10_copies, Some(3));
+        assert_eq!(params.raid10_format, Some(Raid10Format::Far));
+        assert!(params.optional_args.is_empty());
+        assert_eq!(params.to_string(), s);
+        assert_matches!(params.check(), Ok(_));
+
+        params.raid10_copies = Some(4);
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+        params.raid10_copies = Some(1);
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+        params.raid10_copies = None;
+        assert_matches!(params.check(), Ok(_));
+        params.raid_type = "raid1".to_string();
+        assert_matches!(params.check(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+
+        assert_matches!(
+            "raid raid10 3 128 raid10_format sideways 2 - 8:16 - 8:32".parse::<RaidTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that raid status lines are parsed, with and without the
+    /// fields added by later versions of the target.
+    fn test_raid_status() {
+        let status = "raid1 3 ADa 1024/2048 recover 0 0 -"
+            .parse::<RaidStatus>()
+            .unwrap();
+        assert_eq!(
+            status.health,
+            vec![
+                RaidDeviceHealth::InSync,
+                RaidDeviceHealth::Failed,
+                RaidDeviceHealth::Syncing
+            ]
+        );
+        assert_eq!(status.in_sync, Sectors(1024));
+        assert_eq!(status.total, Sectors(2048));
+        assert_eq!(status.sync_action, ResyncAction::Recover);
+        assert_eq!(status.data_offset, Some(Sectors(0)));
+        assert_eq!(status.failed_devices(), vec![1]);
+        assert!(!status.is_in_sync());
+
+        let status = "raid5_ls 3 AAA 2048/2048 idle 12"
+            .parse::<RaidStatus>()
+            .unwrap();
+        assert_eq!(status.mismatch_count, 12);
+        assert_eq!(status.data_offset, None);
+        assert!(status.is_in_sync());
+
+        assert_matches!("raid1 3 AA 0/2048 idle 0".parse::<RaidStatus>(), Err(_));
+        assert_matches!("raid1 2 AX 0/2048 idle 0".parse::<RaidStatus>(), Err(_));
+        assert_matches!("raid1 2 AA 0/2048 idle".parse::<RaidStatus>(), Err(_));
+    }
+
+    /// Verify that a raid1 array is brought into sync, that a check of it
+    /// finds no discrepancies, and that a reshape can not be requested by
+    /// a message.
+    fn test_raid_scrub(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = DM::new().unwrap();
+        let devices = paths
+            .iter()
+            .take(2)
+            .map(|path| {
+                RaidDevice::new(
+                    None,
+                    Some(Device::from(devnode_to_devno(path).unwrap().unwrap())),
+                )
+            })
+            .collect();
+        let table = RaidDevTargetTable::new(
+            Sectors(0),
+            Sectors(32768),
+            RaidTargetParams::new("raid1".to_string(), Sectors(0), devices),
+        );
+
+        let name = test_name("raid").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(&id, &table.to_raw_table(), DmOptions::default())
+            .unwrap();
+        dm.device_suspend(&id, DmOptions::private()).unwrap();
+
+        let status = raid_wait_sync_action(&dm, &id, Duration::from_millis(100), |_| ()).unwrap();
+        assert!(status.is_in_sync());
+        assert_eq!(
+            raid_scrub(&dm, &id, false, Duration::from_millis(100)).unwrap(),
+            0
+        );
+        assert_matches!(
+            raid_sync_action(&dm, &id, ResyncAction::Reshape),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_raid_scrub() {
+        test_with_spec(2, test_raid_scrub);
+    }
+
+    /// Verify that a missing member of a raid1 array is replaced and
+    /// rebuilt, and that the table returned for later activations does not
+    /// rebuild it again.
+    fn test_raid_replace_device(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = DM::new().unwrap();
+        let device = |path: &Path| Device::from(devnode_to_devno(path).unwrap().unwrap());
+        let table = RaidDevTargetTable::new(
+            Sectors(0),
+            Sectors(32768),
+            RaidTargetParams::new(
+                "raid1".to_string(),
+                Sectors(0),
+                vec![
+                    RaidDevice::new(None, Some(device(paths[0]))),
+                    RaidDevice::new(None, None),
+                ],
+            ),
+        );
+
+        let name = test_name("raid").expect("is valid DM name");
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(&id, &table.to_raw_table(), DmOptions::default())
+            .unwrap();
+        dm.device_suspend(&id, DmOptions::private()).unwrap();
+        assert_eq!(
+            raid_status(&dm, &id).unwrap().health[1],
+            RaidDeviceHealth::Missing
+        );
+
+        assert_matches!(
+            raid_replace_device(
+                &dm,
+                &id,
+                0,
+                RaidDevice::new(None, Some(device(paths[1]))),
+                Duration::from_millis(100),
+                |_| ()
+            ),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let mut samples = 0;
+        let new_table = raid_replace_device(
+            &dm,
+            &id,
+            1,
+            RaidDevice::new(None, Some(device(paths[1]))),
+            Duration::from_millis(100),
+            |_| samples += 1,
+        )
+        .unwrap();
+        assert!(samples > 0);
+        assert!(raid_status(&dm, &id).unwrap().is_in_sync());
+        assert!(new_table.table.params.rebuild.is_empty());
+        assert_eq!(
+            new_table.table.params.devices[1].data,
+            Some(device(paths[1]))
+        );
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_raid_replace_device() {
+        test_with_spec(2, test_raid_replace_device);
+    }
+}
@@ -30,6 +30,9 @@ macro_rules! range {
         pub struct $T(pub $inner);
 
         checked_add!($T);
+        checked_sub!($T);
+        checked_mul!($T, $inner);
+        align!($T);
         debug_macro!($T);
         display!($T, $display_name);
         serde_macro!($T, $serde_method);
@@ -295,6 +298,64 @@ macro_rules! checked_add {
     };
 }
 
+macro_rules! checked_sub {
+    ($T: ident) => {
+        impl $T {
+            /// Subtract an item of the same type, return None if underflow.
+            pub fn checked_sub(&self, other: $T) -> Option<$T> {
+                self.0.checked_sub(other.0).map($T)
+            }
+        }
+    };
+}
+
+macro_rules! checked_mul {
+    ($T: ident, $inner: ty) => {
+        impl $T {
+            /// Multiply by a scalar, return None if overflow.
+            pub fn checked_mul(&self, other: $inner) -> Option<$T> {
+                self.0.checked_mul(other).map($T)
+            }
+        }
+    };
+}
+
+// Define rounding to, and checking for, multiples of an alignment. An
+// alignment of zero imposes no constraint.
+macro_rules! align {
+    ($T: ident) => {
+        impl $T {
+            /// Round up to the nearest multiple of `align`, return None if
+            /// overflow. An alignment of zero leaves the value unchanged.
+            pub fn round_up_to(&self, align: $T) -> Option<$T> {
+                if align.0 == 0 {
+                    return Some(*self);
+                }
+                match self.0 % align.0 {
+                    0 => Some(*self),
+                    rem => self.0.checked_add(align.0 - rem).map($T),
+                }
+            }
+
+            /// Round down to the nearest multiple of `align`. An alignment
+            /// of zero leaves the value unchanged.
+            pub fn round_down_to(&self, align: $T) -> $T {
+                if align.0 == 0 {
+                    *self
+                } else {
+                    $T(self.0 - self.0 % align.0)
+                }
+            }
+
+            /// Whether the value is a multiple of `align`. Every value is
+            /// aligned to an alignment of zero.
+            pub fn is_aligned(&self, align: $T) -> bool {
+                align.0 == 0 || self.0 % align.0 == 0
+            }
+        }
+    };
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -370,6 +431,9 @@ mod tests {
         let mut z = Units(3);
         z -= Units(1);
         assert_eq!(z, Units(2));
+
+        assert_eq!(Units(3).checked_sub(Units(1)), Some(Units(2)));
+        assert_eq!(Units(0).checked_sub(Units(1)), None);
     }
 
     #[test]
@@ -389,6 +453,26 @@ mod tests {
 
         assert_eq!(Units(3) * 2usize, Units(6));
         assert_eq!(2usize * Units(3), Units(6));
+
+        assert_eq!(Units(3).checked_mul(2), Some(Units(6)));
+        assert_eq!(Units(u64::MAX).checked_mul(2), None);
+    }
+
+    #[test]
+    /// Test rounding to and checking alignment
+    fn test_alignment() {
+        assert_eq!(Units(5).round_up_to(Units(4)), Some(Units(8)));
+        assert_eq!(Units(8).round_up_to(Units(4)), Some(Units(8)));
+        assert_eq!(Units(u64::MAX).round_up_to(Units(4)), None);
+        assert_eq!(Units(5).round_up_to(Units(0)), Some(Units(5)));
+
+        assert_eq!(Units(7).round_down_to(Units(4)), Units(4));
+        assert_eq!(Units(8).round_down_to(Units(4)), Units(8));
+        assert_eq!(Units(7).round_down_to(Units(0)), Units(7));
+
+        assert!(Units(8).is_aligned(Units(4)));
+        assert!(!Units(7).is_aligned(Units(4)));
+        assert!(Units(7).is_aligned(Units(0)));
     }
 
     #[test]
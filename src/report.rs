@@ -0,0 +1,402 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fmt, str::FromStr};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    core::{redact_table_keys, DevId, DeviceInfo, DmFlags, DmOptions, DM},
+    dmstats::{stats_list, stats_print, StatsArea, StatsRegion},
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+/// A field which may be included in a JSON report on a device. The key of
+/// the field in the report is the string returned by `as_str`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ReportField {
+    /// The device's name, a string
+    Name,
+    /// The device's uuid, a string, or null if the device has none
+    Uuid,
+    /// The device number, a string in "<major>:<minor>" format
+    Device,
+    /// The number of openers of the device
+    OpenCount,
+    /// The device's event number
+    EventNr,
+    /// An object with the boolean members "suspended", "read_only",
+    /// "active_table", and "inactive_table"
+    Flags,
+    /// The device's active table, a list of objects with the members
+    /// "start", "length", "target_type", and "params"; the keys of crypt
+    /// targets are redacted unless `DmReport::set_show_keys` is used
+    Table,
+    /// The status of each target of the device's active table, in the same
+    /// format as the table
+    Status,
+    /// The device's stats regions, a list of objects each of which has an
+    /// "areas" member with the counters of the areas of the region
+    Stats,
+}
+
+impl ReportField {
+    /// All the fields, in the order in which they appear in a report.
+    pub const ALL: [ReportField; 9] = [
+        ReportField::Name,
+        ReportField::Uuid,
+        ReportField::Device,
+        ReportField::OpenCount,
+        ReportField::EventNr,
+        ReportField::Flags,
+        ReportField::Table,
+        ReportField::Status,
+        ReportField::Stats,
+    ];
+
+    /// The key of this field in a report.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportField::Name => "name",
+            ReportField::Uuid => "uuid",
+            ReportField::Device => "device",
+            ReportField::OpenCount => "open_count",
+            ReportField::EventNr => "event_nr",
+            ReportField::Flags => "flags",
+            ReportField::Table => "table",
+            ReportField::Status => "status",
+            ReportField::Stats => "stats",
+        }
+    }
+
+    /// Parse a comma separated list of fields, e.g., "name,uuid,table".
+    pub fn parse_list(list: &str) -> DmResult<Vec<ReportField>> {
+        list.split(',')
+            .map(|field| field.trim().parse::<ReportField>())
+            .collect()
+    }
+}
+
+impl fmt::Display for ReportField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ReportField {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<ReportField> {
+        ReportField::ALL
+            .iter()
+            .find(|field| field.as_str() == s)
+            .copied()
+            .ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!("\"{s}\" is not a known report field"),
+                )
+            })
+    }
+}
+
+/// Renders DM devices as JSON objects containing a selected set of fields,
+/// whose keys are in the order in which the fields were given. The keys
+/// and the format of the values are stable, so that the output may be
+/// consumed by scripts and monitoring agents.
+///
+/// ```no_run
+/// use devicemapper::{DmReport, ReportField, DM};
+///
+/// let dm = DM::new().unwrap();
+/// let report = DmReport::new(&[ReportField::Name, ReportField::Table]);
+/// println!("{}", report.devices(&dm).unwrap());
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DmReport {
+    fields: Vec<ReportField>,
+    show_keys: bool,
+}
+
+impl Default for DmReport {
+    fn default() -> DmReport {
+        DmReport::new(&ReportField::ALL)
+    }
+}
+
+impl DmReport {
+    /// Make a report which includes the given fields, in the given order.
+    pub fn new(fields: &[ReportField]) -> DmReport {
+        let mut unique = Vec::with_capacity(fields.len());
+        for field in fields {
+            if !unique.contains(field) {
+                unique.push(*field);
+            }
+        }
+        DmReport {
+            fields: unique,
+            show_keys: false,
+        }
+    }
+
+    /// Include the keys of crypt targets in the "table" field. By default
+    /// they are redacted, as dmsetup does unless it is given `--showkeys`.
+    pub fn set_show_keys(mut self, show_keys: bool) -> DmReport {
+        self.show_keys = show_keys;
+        self
+    }
+
+    /// The fields included in the report.
+    pub fn fields(&self) -> &[ReportField] {
+        &self.fields
+    }
+
+    /// Render a single device as a JSON object.
+    pub fn device(&self, dm: &DM, id: &DevId<'_>) -> DmResult<Value> {
+        let info = dm.device_info(id)?;
+        let mut object = Map::new();
+        for field in &self.fields {
+            let value = match field {
+                ReportField::Name => json!(info.name().map(|name| name.to_string())),
+                ReportField::Uuid => json!(info.uuid().map(|uuid| uuid.to_string())),
+                ReportField::Device => json!(info.device().to_string()),
+                ReportField::OpenCount => json!(info.open_count()),
+                ReportField::EventNr => json!(info.event_nr()),
+                ReportField::Flags => flags_value(&info),
+                ReportField::Table => {
+                    let (_, mut table) = dm.table_status(
+                        id,
+                        DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+                    )?;
+                    if !self.show_keys {
+                        redact_table_keys(&mut table);
+                    }
+                    table_value(&table)
+                }
+                ReportField::Status => table_value(&dm.table_status(id, DmOptions::default())?.1),
+                ReportField::Stats => {
+                    // Stats messages require an active table.
+                    let mut regions = Vec::new();
+                    if info.flags().contains(DmFlags::DM_ACTIVE_PRESENT) {
+                        for region in stats_list(dm, id, None)? {
+                            let areas = stats_print(dm, id, region.region_id, false)?;
+                            regions.push(stats_region_value(&region, &areas));
+                        }
+                    }
+                    Value::Array(regions)
+                }
+            };
+            object.insert(field.as_str().to_string(), value);
+        }
+        Ok(Value::Object(object))
+    }
+
+    /// Render every DM device as a JSON list of objects, ordered by name.
+    pub fn devices(&self, dm: &DM) -> DmResult<Value> {
+        let mut names = dm
+            .list_devices()?
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect::<Vec<_>>();
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        names
+            .iter()
+            .map(|name| self.device(dm, &DevId::Name(name)))
+            .collect::<DmResult<Vec<_>>>()
+            .map(Value::Array)
+    }
+}
+
+fn flags_value(info: &DeviceInfo) -> Value {
+    let flags = info.flags();
+    json!({
+        "suspended": flags.contains(DmFlags::DM_SUSPEND),
+        "read_only": flags.contains(DmFlags::DM_READONLY),
+        "active_table": flags.contains(DmFlags::DM_ACTIVE_PRESENT),
+        "inactive_table": flags.contains(DmFlags::DM_INACTIVE_PRESENT),
+    })
+}
+
+fn table_value(table: &[(u64, u64, String, String)]) -> Value {
+    Value::Array(
+        table
+            .iter()
+            .map(|(start, length, target_type, params)| {
+                json!({
+                    "start": start,
+                    "length": length,
+                    "target_type": target_type,
+                    "params": params,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn stats_area_value(area: &StatsArea) -> Value {
+    let counters = &area.counters;
+    json!({
+        "start": *area.start,
+        "length": *area.length,
+        "reads": counters.reads,
+        "reads_merged": counters.reads_merged,
+        "read_sectors": counters.read_sectors,
+        "read_ticks": counters.read_ticks,
+        "writes": counters.writes,
+        "writes_merged": counters.writes_merged,
+        "write_sectors": counters.write_sectors,
+        "write_ticks": counters.write_ticks,
+        "in_flight": counters.in_flight,
+        "io_ticks": counters.io_ticks,
+        "weighted_io_ticks": counters.weighted_io_ticks,
+        "total_read_ticks": counters.total_read_ticks,
+        "total_write_ticks": counters.total_write_ticks,
+        "histogram_counts": area.histogram_counts,
+    })
+}
+
+fn stats_region_value(region: &StatsRegion, areas: &[StatsArea]) -> Value {
+    json!({
+        "region_id": region.region_id,
+        "start": *region.start,
+        "length": *region.length,
+        "area_size": *region.area_size,
+        "program_id": region.program_id,
+        "aux_data": region.aux_data,
+        "precise_timestamps": region.precise_timestamps,
+        "histogram_boundaries": region.histogram_boundaries,
+        "areas": areas.iter().map(stats_area_value).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{test_name, test_uuid, TestGuard};
+
+    use super::*;
+
+    #[test]
+    /// Verify that lists of fields are parsed and that unknown fields are
+    /// rejected.
+    fn test_parse_fields() {
+        assert_eq!(
+            ReportField::parse_list("name, table,stats").unwrap(),
+            vec![ReportField::Name, ReportField::Table, ReportField::Stats]
+        );
+        assert_matches!(ReportField::parse_list("name,size"), Err(_));
+        for field in ReportField::ALL {
+            assert_eq!(field.to_string().parse::<ReportField>().unwrap(), field);
+        }
+    }
+
+    #[test]
+    /// Verify the format of a rendered table.
+    fn test_table_value() {
+        assert_eq!(
+            table_value(&[(0, 8, "linear".into(), "8:16 2048".into())]),
+            json!([{"start": 0, "length": 8, "target_type": "linear", "params": "8:16 2048"}])
+        );
+    }
+
+    #[test]
+    /// Verify that a report on a device contains exactly the selected
+    /// fields, in the order in which they were given.
+    fn sudo_test_report_device() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let uuid = test_uuid("example-363333333333333").expect("is valid DM uuid");
+        dm.device_create(&name, Some(&uuid), DmOptions::default())
+            .unwrap();
+        let id = DevId::Name(&name);
+        dm.table_load(
+            &id,
+            &[(0, 1, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        let report = DmReport::new(&[ReportField::Uuid, ReportField::Table, ReportField::Uuid]);
+        assert_eq!(
+            report.device(&dm, &id).unwrap(),
+            json!({
+                "uuid": uuid.to_string(),
+                "table": [{"start": 0, "length": 1, "target_type": "zero", "params": ""}],
+            })
+        );
+
+        let value = DmReport::new(&[ReportField::Table, ReportField::Name])
+            .device(&dm, &id)
+            .unwrap();
+        assert_eq!(
+            value.as_object().unwrap().keys().collect::<Vec<_>>(),
+            vec!["table", "name"]
+        );
+
+        let value = DmReport::default().device(&dm, &id).unwrap();
+        assert_eq!(value["name"], json!(name.to_string()));
+        assert_eq!(value["flags"]["active_table"], json!(true));
+        assert_eq!(value["stats"], json!([]));
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+    #[test]
+    /// Verify that the key of a crypt device is redacted from its table
+    /// unless keys are requested.
+    fn sudo_test_report_crypt_key() {
+        let _guard = TestGuard::new();
+        let dm = DM::new().unwrap();
+        let lower = test_name("example-dev").expect("is valid DM name");
+        let lower_id = DevId::Name(&lower);
+        let lower_info = dm
+            .device_create(&lower, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &lower_id,
+            &[(0, 8, "zero".into(), "".into())],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&lower_id, DmOptions::default()).unwrap();
+
+        let key = "0123456789abcdef".repeat(4);
+        let upper = test_name("example-dev-2").expect("is valid DM name");
+        let upper_id = DevId::Name(&upper);
+        dm.device_create(&upper, None, DmOptions::default())
+            .unwrap();
+        dm.table_load(
+            &upper_id,
+            &[(
+                0,
+                8,
+                "crypt".into(),
+                format!("aes-xts-plain64 {key} 0 {} 0", lower_info.device()),
+            )],
+            DmOptions::default(),
+        )
+        .unwrap();
+        dm.device_suspend(&upper_id, DmOptions::default()).unwrap();
+
+        let report = DmReport::new(&[ReportField::Table]);
+        let value = report.device(&dm, &upper_id).unwrap();
+        assert!(!value.to_string().contains(&key));
+        assert_eq!(
+            value["table"][0]["params"],
+            json!(format!(
+                "aes-xts-plain64 <redacted> 0 {} 0",
+                lower_info.device()
+            ))
+        );
+        assert!(report
+            .set_show_keys(true)
+            .device(&dm, &upper_id)
+            .unwrap()
+            .to_string()
+            .contains(&key));
+
+        dm.device_remove(&upper_id, DmOptions::default()).unwrap();
+        dm.device_remove(&lower_id, DmOptions::default()).unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Progress of the resynchronization of raid and mirror targets. The kernel
+// reports how much of the target is in sync in its status, and raises an
+// event only when the resync starts or finishes, so the progress is found
+// by polling the status; the monitor turns successive samples into events
+// reporting the action in progress, the percentage complete and, from the
+// rate of progress, the time remaining.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    core::{DevId, DmName, DmNameBuf, DmOptions, DM},
+    mirror::{MirrorStatus, MIRROR_TARGET_NAME},
+    monitor::{EventHandler, MonitorEvent, TargetStatus},
+    raid::{RaidStatus, RAID_TARGET_NAME},
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+/// The sync action of a raid target, or, for a mirror target, whether it
+/// is resynchronizing.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ResyncAction {
+    /// No action is in progress
+    Idle,
+    /// Actions are suspended
+    Frozen,
+    /// The target is being synchronized after it was created or after an
+    /// unclean shutdown
+    Resync,
+    /// Replaced devices are being rebuilt
+    Recover,
+    /// The target is being scrubbed, counting discrepancies
+    Check,
+    /// The target is being scrubbed, repairing discrepancies
+    Repair,
+    /// The layout of the target is being changed
+    Reshape,
+}
+
+impl ResyncAction {
+    /// Whether the action is one whose progress is reported.
+    pub fn in_progress(self) -> bool {
+        !matches!(self, ResyncAction::Idle | ResyncAction::Frozen)
+    }
+}
+
+impl fmt::Display for ResyncAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResyncAction::Idle => "idle",
+            ResyncAction::Frozen => "frozen",
+            ResyncAction::Resync => "resync",
+            ResyncAction::Recover => "recover",
+            ResyncAction::Check => "check",
+            ResyncAction::Repair => "repair",
+            ResyncAction::Reshape => "reshape",
+        })
+    }
+}
+
+impl FromStr for ResyncAction {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<ResyncAction> {
+        match s {
+            "idle" => Ok(ResyncAction::Idle),
+            "frozen" => Ok(ResyncAction::Frozen),
+            "resync" => Ok(ResyncAction::Resync),
+            "recover" => Ok(ResyncAction::Recover),
+            "check" => Ok(ResyncAction::Check),
+            "repair" => Ok(ResyncAction::Repair),
+            "reshape" => Ok(ResyncAction::Reshape),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unrecognized sync action \"{s}\""),
+            )),
+        }
+    }
+}
+
+/// The resync state of a raid or mirror target.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ResyncStatus {
+    /// The action in progress
+    pub action: ResyncAction,
+    /// How much of the target has been synchronized, or processed by the
+    /// action in progress; sectors for a raid target, regions for a mirror
+    /// target
+    pub in_sync: u64,
+    /// The size of the target, in the same units as `in_sync`
+    pub total: u64,
+}
+
+impl ResyncStatus {
+    /// Parse the status of a raid or mirror target. Returns None if the
+    /// target is of some other type.
+    pub fn parse(target_type: &str, status: &str) -> Option<DmResult<ResyncStatus>> {
+        match target_type {
+            RAID_TARGET_NAME => Some(parse_raid_status(status)),
+            MIRROR_TARGET_NAME => Some(parse_mirror_status(status)),
+            _ => None,
+        }
+    }
+
+    /// The percentage of the target which is in sync, or processed.
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            self.in_sync as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+fn parse_raid_status(status: &str) -> DmResult<ResyncStatus> {
+    let status = status.parse::<RaidStatus>()?;
+    Ok(ResyncStatus {
+        action: status.sync_action,
+        in_sync: *status.in_sync,
+        total: *status.total,
+    })
+}
+
+/// A mirror target has no sync action; it is resynchronizing until all its
+/// regions are in sync.
+fn parse_mirror_status(status: &str) -> DmResult<ResyncStatus> {
+    let status = status.parse::<MirrorStatus>()?;
+    Ok(ResyncStatus {
+        action: if status.is_in_sync() {
+            ResyncAction::Idle
+        } else {
+            ResyncAction::Resync
+        },
+        in_sync: status.in_sync_regions,
+        total: status.total_regions,
+    })
+}
+
+/// What happened to a raid or mirror target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResyncEventKind {
+    /// The action in progress changed, e.g., from recover to idle when a
+    /// rebuild finished. `from` is None when the target is first seen.
+    ActionChanged {
+        /// The previous action
+        from: Option<ResyncAction>,
+        /// The new action
+        to: ResyncAction,
+    },
+    /// The action in progress advanced.
+    Progress {
+        /// The action in progress
+        action: ResyncAction,
+        /// The percentage complete
+        percent: f64,
+        /// The estimated time until the action completes, from its rate of
+        /// progress since it began; None until the rate is known
+        eta: Option<Duration>,
+    },
+}
+
+/// An event on a raid or mirror target, as delivered by a `ResyncMonitor`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResyncEvent {
+    /// The name of the device
+    pub name: DmNameBuf,
+    /// The start of the target within the device, in sectors
+    pub start: u64,
+    /// What happened
+    pub kind: ResyncEventKind,
+}
+
+/// What is known of a target from earlier samples.
+struct TrackedTarget {
+    status: ResyncStatus,
+    /// When the current action was first seen, and how far it had got
+    action_began: (Instant, u64),
+}
+
+/// The estimated time for the action of `status` to complete, given that
+/// `began_in_sync` was in sync at `elapsed` before the status was sampled.
+fn estimate_eta(status: &ResyncStatus, began_in_sync: u64, elapsed: Duration) -> Option<Duration> {
+    let done = status.in_sync.checked_sub(began_in_sync)?;
+    if done == 0 || elapsed.is_zero() {
+        return None;
+    }
+    let remaining = status.total.saturating_sub(status.in_sync);
+    Some(elapsed.mul_f64(remaining as f64 / done as f64))
+}
+
+/// A monitor of the resynchronization of raid and mirror targets, which
+/// turns samples of their status into `ResyncEvent`s, delivered to a
+/// callback. A change of action is reported whenever it is seen, and the
+/// progress of an action whenever it has advanced.
+///
+/// The kernel raises a DM event only when a resync starts or finishes, so
+/// to follow its progress `poll` should be called periodically. The monitor
+/// is also an `EventHandler`, and may be registered with a `DmMonitor` for
+/// the "raid" and "mirror" target types.
+pub struct ResyncMonitor<F>
+where
+    F: FnMut(&ResyncEvent),
+{
+    callback: F,
+    targets: HashMap<(DmNameBuf, u64), TrackedTarget>,
+}
+
+impl<F> ResyncMonitor<F>
+where
+    F: FnMut(&ResyncEvent),
+{
+    /// Make a new monitor, which delivers its events to `callback`.
+    pub fn new(callback: F) -> ResyncMonitor<F> {
+        ResyncMonitor {
+            callback,
+            targets: HashMap::new(),
+        }
+    }
+
+    /// Sample the status of each raid or mirror target of the device
+    /// `name`, delivering any events.
+    pub fn poll(&mut self, dm: &DM, name: &DmName) -> DmResult<()> {
+        let (_, status) = dm.table_status(&DevId::Name(name), DmOptions::default())?;
+        let now = Instant::now();
+        for (start, _, target_type, status_line) in &status {
+            self.observe(name, *start, target_type, status_line, now)?;
+        }
+        Ok(())
+    }
+
+    /// Forget what is known of the targets of the device `name`, e.g.,
+    /// when it has been removed.
+    pub fn forget(&mut self, name: &DmName) {
+        self.targets.retain(|(n, _), _| **n != *name);
+    }
+
+    /// Take account of the status of a target, sampled at `now`.
+    fn observe(
+        &mut self,
+        name: &DmName,
+        start: u64,
+        target_type: &str,
+        status_line: &str,
+        now: Instant,
+    ) -> DmResult<()> {
+        let status = match ResyncStatus::parse(target_type, status_line) {
+            Some(status) => status?,
+            None => return Ok(()),
+        };
+
+        let key = (name.to_owned(), start);
+        let previous = self.targets.get(&key).map(|tracked| tracked.status);
+        let action_began = match self.targets.get(&key) {
+            Some(tracked) if tracked.status.action == status.action => tracked.action_began,
+            _ => (now, status.in_sync),
+        };
+
+        let mut kinds = Vec::new();
+        if previous.map(|previous| previous.action) != Some(status.action) {
+            if let Some(previous) = previous {
+                // Report the completion of an action which finished between
+                // samples. An action which was interrupted, e.g., frozen,
+                // or replaced by another did not complete.
+                if previous.action.in_progress()
+                    && previous.in_sync < previous.total
+                    && status.action == ResyncAction::Idle
+                    && status.in_sync == status.total
+                {
+                    kinds.push(ResyncEventKind::Progress {
+                        action: previous.action,
+                        percent: 100.0,
+                        eta: Some(Duration::ZERO),
+                    });
+                }
+            }
+            kinds.push(ResyncEventKind::ActionChanged {
+                from: previous.map(|previous| previous.action),
+                to: status.action,
+            });
+        }
+        if status.action.in_progress()
+            && previous.map(|previous| previous.in_sync) != Some(status.in_sync)
+        {
+            let (began, began_in_sync) = action_began;
+            kinds.push(ResyncEventKind::Progress {
+                action: status.action,
+                percent: status.percent(),
+                eta: estimate_eta(&status, began_in_sync, now.saturating_duration_since(began)),
+            });
+        }
+
+        self.targets.insert(
+            key,
+            TrackedTarget {
+                status,
+                action_began,
+            },
+        );
+        for kind in kinds {
+            (self.callback)(&ResyncEvent {
+                name: name.to_owned(),
+                start,
+                kind,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<F> EventHandler for ResyncMonitor<F>
+where
+    F: FnMut(&ResyncEvent),
+{
+    fn handle(&mut self, _: &DM, event: &MonitorEvent<'_>) -> DmResult<()> {
+        match (event.name(), event.status) {
+            (Some(name), TargetStatus::Other(status)) => {
+                self.observe(name, event.start, event.target_type, status, Instant::now())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the resync state of raid and mirror targets is parsed.
+    fn test_parse() {
+        assert_eq!(
+            ResyncStatus::parse("raid", "raid1 2 aA 512/1024 recover 0 0 -")
+                .unwrap()
+                .unwrap(),
+            ResyncStatus {
+                action: ResyncAction::Recover,
+                in_sync: 512,
+                total: 1024,
+            }
+        );
+        assert_eq!(
+            ResyncStatus::parse("mirror", "2 8:16 8:32 10/40 1 AA 3 disk 8:48 A")
+                .unwrap()
+                .unwrap(),
+            ResyncStatus {
+                action: ResyncAction::Resync,
+                in_sync: 10,
+                total: 40,
+            }
+        );
+        assert_eq!(
+            ResyncStatus::parse("mirror", "2 8:16 8:32 40/40 1 AA 1 core")
+                .unwrap()
+                .unwrap()
+                .action,
+            ResyncAction::Idle
+        );
+        assert_matches!(
+            ResyncStatus::parse("raid", "raid1 2 AA 1024/1024 unknown 0 0 -"),
+            Some(Err(_))
+        );
+        assert_matches!(ResyncStatus::parse("mirror", "2 8:16 8:32"), Some(Err(_)));
+        assert_matches!(ResyncStatus::parse("zero", ""), None);
+    }
+
+    #[test]
+    /// Verify that the time remaining is estimated from the rate of
+    /// progress.
+    fn test_estimate_eta() {
+        let status = ResyncStatus {
+            action: ResyncAction::Resync,
+            in_sync: 300,
+            total: 1000,
+        };
+        assert_eq!(
+            estimate_eta(&status, 100, Duration::from_secs(10)),
+            Some(Duration::from_secs(35))
+        );
+        assert_eq!(estimate_eta(&status, 300, Duration::from_secs(10)), None);
+        assert_eq!(estimate_eta(&status, 100, Duration::ZERO), None);
+    }
+
+    #[test]
+    /// Verify that samples of a recovery are turned into progress events
+    /// and state transitions, including a recovery which finishes between
+    /// samples.
+    fn test_events() {
+        let mut events = Vec::new();
+        let name = DmNameBuf::new("raid-dev".to_string()).unwrap();
+        let start = Instant::now();
+        {
+            let mut monitor = ResyncMonitor::new(|event: &ResyncEvent| {
+                events.push(event.kind.clone());
+            });
+            let mut observe = |status: &str, secs: u64| {
+                monitor
+                    .observe(&name, 0, "raid", status, start + Duration::from_secs(secs))
+                    .unwrap();
+            };
+            observe("raid1 2 aA 0/1000 recover 0 0 -", 0);
+            observe("raid1 2 aA 250/1000 recover 0 0 -", 10);
+            observe("raid1 2 aA 250/1000 recover 0 0 -", 20);
+            observe("raid1 2 AA 1000/1000 idle 0 0 -", 30);
+            observe("raid1 2 AA 1000/1000 idle 0 0 -", 40);
+            monitor.observe(&name, 0, "zero", "", start).unwrap();
+        }
+        assert_eq!(
+            events,
+            vec![
+                ResyncEventKind::ActionChanged {
+                    from: None,
+                    to: ResyncAction::Recover,
+                },
+                ResyncEventKind::Progress {
+                    action: ResyncAction::Recover,
+                    percent: 0.0,
+                    eta: None,
+                },
+                ResyncEventKind::Progress {
+                    action: ResyncAction::Recover,
+                    percent: 25.0,
+                    eta: Some(Duration::from_secs(30)),
+                },
+                ResyncEventKind::Progress {
+                    action: ResyncAction::Recover,
+                    percent: 100.0,
+                    eta: Some(Duration::ZERO),
+                },
+                ResyncEventKind::ActionChanged {
+                    from: Some(ResyncAction::Recover),
+                    to: ResyncAction::Idle,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    /// Verify that an action which is frozen part way through is not
+    /// reported as complete, and that its resumption is reported.
+    fn test_frozen() {
+        let mut events = Vec::new();
+        let name = DmNameBuf::new("raid-dev".to_string()).unwrap();
+        let start = Instant::now();
+        {
+            let mut monitor = ResyncMonitor::new(|event: &ResyncEvent| {
+                events.push(event.kind.clone());
+            });
+            let mut observe = |status: &str, secs: u64| {
+                monitor
+                    .observe(&name, 0, "raid", status, start + Duration::from_secs(secs))
+                    .unwrap();
+            };
+            observe("raid1 2 aA 250/1000 resync 0 0 -", 0);
+            observe("raid1 2 aA 250/1000 frozen 0 0 -", 10);
+            observe("raid1 2 aA 250/1000 resync 0 0 -", 20);
+        }
+        assert_eq!(
+            events,
+            vec![
+                ResyncEventKind::ActionChanged {
+                    from: None,
+                    to: ResyncAction::Resync,
+                },
+                ResyncEventKind::Progress {
+                    action: ResyncAction::Resync,
+                    percent: 25.0,
+                    eta: None,
+                },
+                ResyncEventKind::ActionChanged {
+                    from: Some(ResyncAction::Resync),
+                    to: ResyncAction::Frozen,
+                },
+                ResyncEventKind::ActionChanged {
+                    from: Some(ResyncAction::Frozen),
+                    to: ResyncAction::Resync,
+                },
+            ]
+        );
+    }
+}
@@ -25,6 +25,21 @@ fn err_func(err_msg: &str) -> DmError {
 /// Number of bytes in Struct_dm_target_spec::target_type field.
 const DM_TARGET_TYPE_LEN: usize = 16;
 
+/// Targets whose constructors refuse a table which is not read-only
+const READ_ONLY_TARGETS: &[&str] = &["verity"];
+
+/// Targets which are refused in a read-only table. The thin-pool target
+/// opens its devices read-write whatever the mode of its table, and has its
+/// own "read_only" feature to stop it from changing its metadata.
+const READ_WRITE_TARGETS: &[&str] = &["thin-pool"];
+
+/// Targets whose constructors open some of their devices read-write
+/// whatever the mode of their table, e.g., to record metadata or, for
+/// snapshot-merge, to copy chunks back to the origin. A read-only table of
+/// one of these is legitimate, e.g., a read-only cache LV, but its devices
+/// are still written, so a warning is logged.
+const WRITE_MODE_TARGETS: &[&str] = &["cache", "clone", "era", "snapshot-merge"];
+
 str_id!(TargetType, TargetTypeBuf, DM_TARGET_TYPE_LEN, err_func);
 
 /// The trait for properties of the params string of TargetType
@@ -85,6 +100,13 @@ pub trait DmDevice<T: TargetTable> {
         T::from_raw_table(&table)
     }
 
+    /// Read the devicemapper inactive table, i.e., the table which has been
+    /// loaded but which will not become active until the device is resumed.
+    /// Return None if there is no inactive table.
+    fn read_inactive_kernel_table(dm: &DM, id: &DevId<'_>) -> DmResult<Option<T>> {
+        read_inactive_table(dm, id)
+    }
+
     /// The device's name.
     fn name(&self) -> &DmName;
 
@@ -111,9 +133,16 @@ pub trait DmDevice<T: TargetTable> {
     /// What the device thinks its table is.
     fn table(&self) -> &T;
 
-    /// Load a table
+    /// Load a table. The options are made consistent with the table as by
+    /// `read_only_options`. Returns an error if the device is read-only and
+    /// the table contains a thin-pool target.
     fn table_load(&self, dm: &DM, table: &T, options: DmOptions) -> DmResult<()> {
-        dm.table_load(&DevId::Name(self.name()), &table.to_raw_table(), options)?;
+        let raw_table = table.to_raw_table();
+        let id = DevId::Name(self.name());
+        if dm.device_info(&id)?.flags().contains(DmFlags::DM_READONLY) {
+            refuse_read_write_targets(&raw_table, "the device is read-only")?;
+        }
+        dm.table_load(&id, &raw_table, read_only_options(&raw_table, options)?)?;
         Ok(())
     }
 
@@ -125,14 +154,169 @@ pub trait DmDevice<T: TargetTable> {
     fn uuid(&self) -> Option<&DmUuid>;
 }
 
+/// Read the inactive table of a device, or None if it has none.
+fn read_inactive_table<T: TargetTable>(dm: &DM, id: &DevId<'_>) -> DmResult<Option<T>> {
+    if !dm
+        .device_info(id)?
+        .flags()
+        .contains(DmFlags::DM_INACTIVE_PRESENT)
+    {
+        return Ok(None);
+    }
+    let (_, table) = dm.table_status(
+        id,
+        DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE | DmFlags::DM_QUERY_INACTIVE_TABLE),
+    )?;
+    T::from_raw_table(&table).map(Some)
+}
+
+/// The lines which would be removed from and added to a device's table by
+/// resuming it, as returned by `TableSlots::resume_changes`. Lines which
+/// are in both tables are omitted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TableChanges {
+    /// Lines of the active table which are not in the inactive table
+    pub removed: Vec<(u64, u64, String, String)>,
+    /// Lines of the inactive table which are not in the active table
+    pub added: Vec<(u64, u64, String, String)>,
+}
+
+impl TableChanges {
+    /// Whether the tables have the same lines.
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Both tables of a device: the active table, which maps its I/O, and the
+/// inactive table, which has been loaded and becomes active when the device
+/// is resumed. A device found with an inactive table, e.g., after a crash
+/// between loading a table and resuming the device, has a resume pending.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TableSlots<T: TargetTable> {
+    /// The active table, if there is one
+    pub active: Option<T>,
+    /// The inactive table, if there is one
+    pub inactive: Option<T>,
+    /// Whether the device is suspended
+    pub suspended: bool,
+}
+
+impl<T: TargetTable> TableSlots<T> {
+    /// Read both tables of a device.
+    pub fn read(dm: &DM, id: &DevId<'_>) -> DmResult<TableSlots<T>> {
+        let flags = dm.device_info(id)?.flags();
+        let active = if flags.contains(DmFlags::DM_ACTIVE_PRESENT) {
+            let (_, table) =
+                dm.table_status(id, DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE))?;
+            Some(T::from_raw_table(&table)?)
+        } else {
+            None
+        };
+        Ok(TableSlots {
+            active,
+            inactive: read_inactive_table(dm, id)?,
+            suspended: flags.contains(DmFlags::DM_SUSPEND),
+        })
+    }
+
+    /// Whether resuming the device would make a new table active.
+    pub fn resume_pending(&self) -> bool {
+        self.inactive.is_some()
+    }
+
+    /// The changes to the active table which resuming the device would
+    /// make, or None if no resume is pending.
+    pub fn resume_changes(&self) -> Option<TableChanges> {
+        let inactive = self.inactive.as_ref()?.to_raw_table();
+        let active = self
+            .active
+            .as_ref()
+            .map(|table| table.to_raw_table())
+            .unwrap_or_default();
+        Some(TableChanges {
+            removed: active
+                .iter()
+                .filter(|line| !inactive.contains(line))
+                .cloned()
+                .collect(),
+            added: inactive
+                .iter()
+                .filter(|line| !active.contains(line))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
 /// Send a message that expects no reply to target device.
 pub fn message<T: TargetTable, D: DmDevice<T>>(dm: &DM, target: &D, msg: &str) -> DmResult<()> {
     dm.target_msg(&DevId::Name(target.name()), None, msg)?;
     Ok(())
 }
 
+/// Make the options with which `table` is to be loaded consistent with its
+/// targets: `DM_READONLY` is set if any target may only be loaded in a
+/// read-only table, e.g., verity. Returns an error if the table is then
+/// read-only and contains a thin-pool target, which should be made
+/// read-only with its own "read_only" feature instead. Logs a warning if
+/// the table is read-only and contains a target which writes to its
+/// devices regardless, e.g., cache.
+pub fn read_only_options(
+    table: &[(u64, u64, String, String)],
+    options: DmOptions,
+) -> DmResult<DmOptions> {
+    let read_only_target = table
+        .iter()
+        .find(|(_, _, target_type, _)| READ_ONLY_TARGETS.contains(&target_type.as_str()));
+    let options = match read_only_target {
+        Some(_) if !options.flags().contains(DmFlags::DM_READONLY) => {
+            options.set_flags(options.flags() | DmFlags::DM_READONLY)
+        }
+        _ => options,
+    };
+    if !options.flags().contains(DmFlags::DM_READONLY) {
+        return Ok(options);
+    }
+
+    let reason = match read_only_target {
+        Some((_, _, read_only_type, _)) => {
+            format!("the {read_only_type} target in the same table must be read-only")
+        }
+        None => "the table is loaded read-only".to_string(),
+    };
+    refuse_read_write_targets(table, &reason)?;
+    for (start, _, target_type, _) in table
+        .iter()
+        .filter(|(_, _, target_type, _)| WRITE_MODE_TARGETS.contains(&target_type.as_str()))
+    {
+        warn!(
+            "{} target at sector {} writes to its devices although its table is read-only",
+            target_type, start
+        );
+    }
+    Ok(options)
+}
+
+/// Return an error if `table` contains a target which can not be loaded
+/// read-only, giving the reason that it would be.
+fn refuse_read_write_targets(table: &[(u64, u64, String, String)], reason: &str) -> DmResult<()> {
+    match table
+        .iter()
+        .find(|(_, _, target_type, _)| READ_WRITE_TARGETS.contains(&target_type.as_str()))
+    {
+        Some((start, _, target_type, _)) => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "{target_type} target at sector {start} can not be loaded read-only, but {reason}"
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
 /// Create a device, load a table, and resume it allowing the caller to specify the DmOptions for
-/// resuming.
+/// resuming. The table is loaded with the options given by `read_only_options`.
 pub fn device_create<T: TargetTable>(
     dm: &DM,
     name: &DmName,
@@ -140,10 +324,12 @@ pub fn device_create<T: TargetTable>(
     table: &T,
     suspend_options: DmOptions,
 ) -> DmResult<DeviceInfo> {
+    let raw_table = table.to_raw_table();
+    let load_options = read_only_options(&raw_table, DmOptions::default())?;
     dm.device_create(name, uuid, DmOptions::default())?;
 
     let id = DevId::Name(name);
-    let dev_info = match dm.table_load(&id, &table.to_raw_table(), DmOptions::default()) {
+    let dev_info = match dm.table_load(&id, &raw_table, load_options) {
         Err(e) => {
             dm.device_remove(&id, DmOptions::default())?;
             return Err(e);
@@ -155,6 +341,104 @@ pub fn device_create<T: TargetTable>(
     Ok(dev_info)
 }
 
+/// Make sure that a device with the given name, uuid, and table exists and
+/// is active, so that provisioning may be safely re-run after a crash.
+///
+/// * If the device does not exist, it is created as by `device_create`.
+/// * If it exists, its uuid must match `uuid`, and its active table, or,
+///   if it has none, its inactive table, must be equivalent to `table`
+///   according to `D::equivalent_tables`; otherwise an error is returned.
+/// * If it has no table at all, `table` is loaded.
+/// * If it has both an active and an inactive table, the inactive table,
+///   which would otherwise become active when the device is next resumed,
+///   must also be equivalent to `table`, and is then cleared; an inactive
+///   table which differs is left in place and an error is returned, since
+///   it may have been loaded by another user of the device.
+/// * The device is resumed if it is suspended or has no active table.
+pub fn ensure_device<T: TargetTable, D: DmDevice<T>>(
+    dm: &DM,
+    name: &DmName,
+    uuid: Option<&DmUuid>,
+    table: &T,
+    suspend_options: DmOptions,
+) -> DmResult<DeviceInfo> {
+    let id = DevId::Name(name);
+    if !dm.device_exists(&id)? {
+        return device_create(dm, name, uuid, table, suspend_options);
+    }
+
+    let info = dm.device_info(&id)?;
+    if info.uuid() != uuid {
+        let err_msg = format!(
+            "Specified uuid \"{:?}\" does not match kernel uuid \"{:?}\" of device {}",
+            uuid,
+            info.uuid(),
+            name
+        );
+        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+    }
+
+    let read_inactive_table = || -> DmResult<T> {
+        let (_, inactive) = dm.table_status(
+            &id,
+            DmOptions::default()
+                .set_flags(DmFlags::DM_STATUS_TABLE | DmFlags::DM_QUERY_INACTIVE_TABLE),
+        )?;
+        T::from_raw_table(&inactive)
+    };
+
+    let flags = info.flags();
+    let (kernel_table, inactive_unused) = if flags.contains(DmFlags::DM_ACTIVE_PRESENT) {
+        (
+            Some(D::read_kernel_table(dm, &id)?),
+            flags.contains(DmFlags::DM_INACTIVE_PRESENT),
+        )
+    } else if flags.contains(DmFlags::DM_INACTIVE_PRESENT) {
+        (Some(read_inactive_table()?), false)
+    } else {
+        (None, false)
+    };
+
+    match kernel_table {
+        Some(kernel_table) => {
+            if !D::equivalent_tables(&kernel_table, table)? {
+                let err_msg = format!(
+                    "Specified table \"{table:?}\" does not match kernel table \"{kernel_table:?}\" of device {name}"
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        None => {
+            let raw_table = table.to_raw_table();
+            dm.table_load(
+                &id,
+                &raw_table,
+                read_only_options(&raw_table, DmOptions::default())?,
+            )?;
+        }
+    }
+
+    if inactive_unused {
+        let inactive_table = read_inactive_table()?;
+        if !D::equivalent_tables(&inactive_table, table)? {
+            let err_msg = format!(
+                "Device {name} has an inactive table \"{inactive_table:?}\" which does not match the specified table \"{table:?}\"; it must be cleared or activated explicitly"
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        dm.table_clear(&id)?;
+    }
+
+    let info = dm.device_info(&id)?;
+    if info.flags().contains(DmFlags::DM_SUSPEND)
+        || !info.flags().contains(DmFlags::DM_ACTIVE_PRESENT)
+    {
+        dm.device_suspend(&id, suspend_options)?;
+        return dm.device_info(&id);
+    }
+    Ok(info)
+}
+
 /// Verify that kernel data matches arguments passed.
 pub fn device_match<T: TargetTable, D: DmDevice<T>>(
     dm: &DM,
@@ -185,8 +469,7 @@ pub fn device_match<T: TargetTable, D: DmDevice<T>>(
 
 /// Check if a device of the given name exists.
 pub fn device_exists(dm: &DM, name: &DmName) -> DmResult<bool> {
-    dm.list_devices()
-        .map(|l| l.iter().any(|(n, _, _)| &**n == name))
+    dm.device_exists(&DevId::Name(name))
 }
 
 /// Parse a device from either of a path or a maj:min pair
@@ -267,3 +550,51 @@ pub fn make_unexpected_value_error(value_index: usize, value: &str, item_name: &
         ),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(target_type: &str) -> (u64, u64, String, String) {
+        (0, 8, target_type.to_string(), String::new())
+    }
+
+    #[test]
+    /// Verify that tables with read-only targets are made read-only, that
+    /// thin-pool is refused in read-only tables, and that other targets
+    /// are accepted in them.
+    fn test_read_only_options() {
+        let read_only = DmOptions::default().set_flags(DmFlags::DM_READONLY);
+
+        assert!(read_only_options(&[line("verity")], DmOptions::default())
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_READONLY));
+        assert!(!read_only_options(&[line("linear")], DmOptions::default())
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_READONLY));
+        assert!(read_only_options(&[line("linear")], read_only)
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_READONLY));
+        for target_type in ["cache", "integrity", "mirror", "raid", "snapshot"] {
+            assert!(read_only_options(&[line(target_type)], read_only)
+                .unwrap()
+                .flags()
+                .contains(DmFlags::DM_READONLY));
+        }
+        assert_matches!(
+            read_only_options(&[line("thin-pool")], read_only),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            read_only_options(&[line("verity"), line("thin-pool")], DmOptions::default()),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            read_only_options(&[line("thin-pool")], DmOptions::default()),
+            Ok(_)
+        );
+    }
+}
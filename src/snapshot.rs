@@ -0,0 +1,874 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Support for the non-thin snapshot targets. A snapshot-origin device maps
+// the origin, copying each chunk to the COW device of every snapshot of the
+// origin before the chunk is first overwritten. A snapshot device presents
+// the origin as it was when the snapshot was made. Reverting the origin to a
+// snapshot replaces the origin's table with a snapshot-merge target, which
+// copies the chunks recorded on the COW device back to the origin in the
+// background while presenting the merged contents. Merging a snapshot, as
+// lvconvert --merge does, reverts the origin and then discards the COW
+// device, all of whose exceptions have been merged.
+
+use std::{fmt, str::FromStr, thread, time::Duration};
+
+use crate::{
+    blkdev::{check_chunk_size, device_size},
+    core::{DevId, Device, DmFlags, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{parse_device, parse_value, TargetParams, TargetTypeBuf},
+    units::Sectors,
+};
+
+const SNAPSHOT_TARGET_NAME: &str = "snapshot";
+const SNAPSHOT_MERGE_TARGET_NAME: &str = "snapshot-merge";
+const SNAPSHOT_ORIGIN_TARGET_NAME: &str = "snapshot-origin";
+
+/// The largest chunk size the kernel accepts, the largest power of two
+/// number of sectors whose size in bytes fits in an int
+const MAX_SNAPSHOT_CHUNK_SIZE: Sectors = Sectors(1 << 21);
+
+/// Whether a snapshot's COW device survives a restart of the snapshot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SnapshotPersistence {
+    /// The exceptions are recorded on the COW device.
+    Persistent,
+    /// The exceptions are recorded on the COW device, and writes to the
+    /// snapshot are errored, rather than the snapshot invalidated, when the
+    /// COW device is full.
+    PersistentOverflow,
+    /// The exceptions are kept in memory only.
+    Transient,
+}
+
+impl SnapshotPersistence {
+    /// The persistence as it appears in a table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotPersistence::Persistent => "P",
+            SnapshotPersistence::PersistentOverflow => "PO",
+            SnapshotPersistence::Transient => "N",
+        }
+    }
+}
+
+impl FromStr for SnapshotPersistence {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<SnapshotPersistence> {
+        match s {
+            "P" => Ok(SnapshotPersistence::Persistent),
+            "PO" => Ok(SnapshotPersistence::PersistentOverflow),
+            "N" => Ok(SnapshotPersistence::Transient),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown snapshot persistence \"{s}\""),
+            )),
+        }
+    }
+}
+
+/// Target params for snapshot-origin target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotOriginTargetParams {
+    /// The device holding the origin
+    pub origin: Device,
+}
+
+impl SnapshotOriginTargetParams {
+    /// Create a new SnapshotOriginTargetParams struct
+    pub fn new(origin: Device) -> SnapshotOriginTargetParams {
+        SnapshotOriginTargetParams { origin }
+    }
+}
+
+impl fmt::Display for SnapshotOriginTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <origin>
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", SNAPSHOT_ORIGIN_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for SnapshotOriginTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<SnapshotOriginTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() != 2 {
+            let err_msg = format!(
+                "expected 2 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != SNAPSHOT_ORIGIN_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a snapshot-origin target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let origin = parse_device(vals[1], "origin device for snapshot-origin target")?;
+
+        Ok(SnapshotOriginTargetParams::new(origin))
+    }
+}
+
+impl TargetParams for SnapshotOriginTargetParams {
+    fn param_str(&self) -> String {
+        self.origin.to_string()
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(SNAPSHOT_ORIGIN_TARGET_NAME.into())
+            .expect("SNAPSHOT_ORIGIN_TARGET_NAME is valid")
+    }
+}
+
+/// Target params for snapshot and snapshot-merge targets
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotTargetParams {
+    /// The device holding the origin
+    pub origin: Device,
+    /// The device holding the chunks of the origin which have changed
+    pub cow: Device,
+    /// Whether the exceptions are recorded on the COW device
+    pub persistence: SnapshotPersistence,
+    /// The size of the chunks copied to the COW device
+    pub chunk_size: Sectors,
+    /// Feature arguments
+    pub feature_args: Vec<String>,
+    /// Whether the target merges the snapshot into the origin, rather than
+    /// presenting the snapshot
+    pub merge: bool,
+}
+
+impl SnapshotTargetParams {
+    /// Create a new SnapshotTargetParams struct for a snapshot target
+    pub fn new(
+        origin: Device,
+        cow: Device,
+        persistence: SnapshotPersistence,
+        chunk_size: Sectors,
+    ) -> SnapshotTargetParams {
+        SnapshotTargetParams {
+            origin,
+            cow,
+            persistence,
+            chunk_size,
+            feature_args: Vec::new(),
+            merge: false,
+        }
+    }
+
+    /// The params of a snapshot-merge target which merges this snapshot
+    /// into its origin.
+    pub fn to_merge(&self) -> SnapshotTargetParams {
+        SnapshotTargetParams {
+            merge: true,
+            ..self.clone()
+        }
+    }
+
+    /// Verify that the chunk size is usable with the origin and COW
+    /// devices: a power of two, no larger than the origin, and a multiple
+    /// of the logical block sizes of both devices, as the kernel requires.
+    pub fn check_chunk_size(&self, dm: &DM) -> DmResult<()> {
+        if !(*self.chunk_size).is_power_of_two() {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "snapshot chunk size {} is not a power of two",
+                    self.chunk_size
+                ),
+            ));
+        }
+        let origin_size = device_size(self.origin)?.sectors();
+        if self.chunk_size > origin_size {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "snapshot chunk size {} is larger than origin {}, of {}",
+                    self.chunk_size, self.origin, origin_size
+                ),
+            ));
+        }
+        for device in [self.origin, self.cow] {
+            check_chunk_size(
+                dm,
+                device,
+                self.chunk_size,
+                Sectors(1),
+                MAX_SNAPSHOT_CHUNK_SIZE,
+                Sectors(1),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn target_name(&self) -> &'static str {
+        if self.merge {
+            SNAPSHOT_MERGE_TARGET_NAME
+        } else {
+            SNAPSHOT_TARGET_NAME
+        }
+    }
+}
+
+impl fmt::Display for SnapshotTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <origin> <COW device> <persistent?> <chunksize> [<#feature args> [<arg>]*]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.target_name(), self.param_str())
+    }
+}
+
+impl FromStr for SnapshotTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<SnapshotTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() < 5 {
+            let err_msg = format!(
+                "expected at least 5 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let merge = match vals[0] {
+            SNAPSHOT_TARGET_NAME => false,
+            SNAPSHOT_MERGE_TARGET_NAME => true,
+            _ => {
+                let err_msg = format!(
+                    "Expected a snapshot or snapshot-merge target entry but found target type {}",
+                    vals[0]
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        };
+
+        let origin = parse_device(vals[1], "origin device for snapshot target")?;
+        let cow = parse_device(vals[2], "COW device for snapshot target")?;
+        let persistence = vals[3].parse::<SnapshotPersistence>()?;
+        let chunk_size = Sectors(parse_value(vals[4], "chunk size")?);
+
+        let feature_args = if vals.len() == 5 {
+            Vec::new()
+        } else {
+            let num_args = parse_value::<usize>(vals[5], "number of feature args")?;
+            if vals.len() != 6 + num_args {
+                let err_msg = format!(
+                    "expected {} feature args in params string \"{}\", found {}",
+                    num_args,
+                    s,
+                    vals.len() - 6
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+            vals[6..].iter().map(|s| s.to_string()).collect()
+        };
+
+        Ok(SnapshotTargetParams {
+            origin,
+            cow,
+            persistence,
+            chunk_size,
+            feature_args,
+            merge,
+        })
+    }
+}
+
+impl TargetParams for SnapshotTargetParams {
+    fn param_str(&self) -> String {
+        let mut params = format!(
+            "{} {} {} {}",
+            self.origin,
+            self.cow,
+            self.persistence.as_str(),
+            *self.chunk_size
+        );
+        if !self.feature_args.is_empty() {
+            params.push_str(&format!(
+                " {} {}",
+                self.feature_args.len(),
+                self.feature_args.join(" ")
+            ));
+        }
+        params
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(self.target_name().into()).expect("snapshot target names are valid")
+    }
+}
+
+/// Status of a snapshot or snapshot-merge target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SnapshotStatus {
+    /// The snapshot is usable.
+    Working {
+        /// The sectors of the COW device in use, including its metadata
+        allocated: Sectors,
+        /// The size of the COW device
+        total: Sectors,
+        /// The sectors of the COW device holding metadata
+        metadata: Sectors,
+    },
+    /// The snapshot has been invalidated, e.g., because its COW device
+    /// filled up.
+    Invalid,
+    /// The COW device of a snapshot with overflow is full.
+    Overflow,
+    /// Merging the snapshot into its origin has failed.
+    MergeFailed,
+}
+
+impl SnapshotStatus {
+    /// The number of sectors of changed chunks which are yet to be merged
+    /// into the origin, for a working snapshot-merge target.
+    pub fn merge_remaining(&self) -> Option<Sectors> {
+        match self {
+            SnapshotStatus::Working {
+                allocated,
+                metadata,
+                ..
+            } => Some(Sectors(allocated.saturating_sub(**metadata))),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for SnapshotStatus {
+    type Err = DmError;
+
+    fn from_str(status_line: &str) -> DmResult<SnapshotStatus> {
+        match status_line {
+            "Invalid" => return Ok(SnapshotStatus::Invalid),
+            "Overflow" => return Ok(SnapshotStatus::Overflow),
+            "Merge failed" => return Ok(SnapshotStatus::MergeFailed),
+            _ => (),
+        }
+
+        let invalid = || {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unexpected snapshot status \"{status_line}\""),
+            )
+        };
+        let (usage, metadata) = status_line.split_once(' ').ok_or_else(invalid)?;
+        let (allocated, total) = usage.split_once('/').ok_or_else(invalid)?;
+        Ok(SnapshotStatus::Working {
+            allocated: Sectors(parse_value(allocated, "allocated sectors")?),
+            total: Sectors(parse_value(total, "total sectors")?),
+            metadata: Sectors(parse_value(metadata, "metadata sectors")?),
+        })
+    }
+}
+
+/// A stage of reverting an origin to a snapshot, as reported to the
+/// progress callback of `revert_to_snapshot`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SnapshotRevertProgress {
+    /// The origin and the snapshot have been suspended.
+    Suspended,
+    /// The origin has been resumed with a snapshot-merge table, and is
+    /// usable, with the snapshot's contents, from now on.
+    MergeStarted,
+    /// The snapshot device, which no longer presents the snapshot, has
+    /// been removed.
+    SnapshotRemoved,
+    /// The merge is in progress, with the given number of sectors of
+    /// changed chunks remaining out of those when it started.
+    Merging {
+        /// The sectors remaining to be merged
+        remaining: Sectors,
+        /// The sectors to be merged when the merge started
+        initial: Sectors,
+    },
+    /// The merge has completed and the origin has been restored to a
+    /// snapshot-origin table.
+    Complete,
+    /// The COW device, a DM device whose exceptions have all been merged,
+    /// has been removed; reported only by `merge_snapshot`.
+    CowRemoved,
+}
+
+/// The single line of the active table of a device, as its params.
+pub(crate) fn single_line<T>(dm: &DM, id: &DevId<'_>) -> DmResult<(u64, u64, T)>
+where
+    T: FromStr<Err = DmError>,
+{
+    let (_, table) =
+        dm.table_status(id, DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE))?;
+    match table.as_slice() {
+        [(start, length, target_type, params)] => Ok((
+            *start,
+            *length,
+            format!("{target_type} {params}").parse::<T>()?,
+        )),
+        _ => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("expected a single line in the table of {id}"),
+        )),
+    }
+}
+
+/// The status of the single snapshot target of a device.
+pub(crate) fn snapshot_status(dm: &DM, id: &DevId<'_>) -> DmResult<SnapshotStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    match status.as_slice() {
+        [(_, _, _, status_line)] => status_line.parse::<SnapshotStatus>(),
+        _ => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("expected a single line in the status of {id}"),
+        )),
+    }
+}
+
+/// Revert the snapshot-origin device `origin` to the snapshot device
+/// `snapshot`, a snapshot of it, by merging the snapshot into the origin.
+///
+/// Both devices are suspended, and the origin is resumed with a
+/// snapshot-merge table, from when it presents the snapshot's contents.
+/// The snapshot device, whose exceptions have been handed over to the
+/// merge, is then removed, so it must not be open. The merge is polled
+/// every `interval` until it completes, and the origin is then restored to
+/// a snapshot-origin table. The COW device is left in place, and may be
+/// reused or discarded by the caller.
+///
+/// `progress` is called as each stage is reached, and with the remaining
+/// sectors each time the merge is polled.
+pub fn revert_to_snapshot<F>(
+    dm: &DM,
+    origin: &DevId<'_>,
+    snapshot: &DevId<'_>,
+    interval: Duration,
+    mut progress: F,
+) -> DmResult<()>
+where
+    F: FnMut(&SnapshotRevertProgress),
+{
+    let (origin_start, origin_length, origin_params) =
+        single_line::<SnapshotOriginTargetParams>(dm, origin)?;
+    let (_, _, snapshot_params) = single_line::<SnapshotTargetParams>(dm, snapshot)?;
+    if snapshot_params.merge {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("{snapshot} is already being merged"),
+        ));
+    }
+    if snapshot_params.origin != origin_params.origin {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "{} is a snapshot of {}, but {} is the origin {}",
+                snapshot, snapshot_params.origin, origin, origin_params.origin
+            ),
+        ));
+    }
+    if snapshot_params.persistence == SnapshotPersistence::Transient {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("{snapshot} is not persistent and can not be merged"),
+        ));
+    }
+    if dm.device_info(snapshot)?.open_count() != 0 {
+        return Err(DmError::Dm(
+            ErrorEnum::Error,
+            format!("{snapshot} is open and can not be removed to merge it"),
+        ));
+    }
+    match snapshot_status(dm, snapshot)? {
+        SnapshotStatus::Working { .. } => (),
+        status => {
+            return Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("{snapshot} can not be merged, its status is {status:?}"),
+            ))
+        }
+    }
+
+    // The snapshot-merge target takes over the exceptions of the snapshot
+    // which shares its COW device only if that snapshot is suspended.
+    dm.device_suspend(
+        snapshot,
+        DmOptions::default().set_flags(DmFlags::DM_SUSPEND),
+    )?;
+    if let Err(err) = dm.device_suspend(origin, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))
+    {
+        dm.device_suspend(snapshot, DmOptions::private())?;
+        return Err(err);
+    }
+    progress(&SnapshotRevertProgress::Suspended);
+
+    let merge_params = snapshot_params.to_merge();
+    let merge_table = [(
+        origin_start,
+        origin_length,
+        merge_params.target_type().to_string(),
+        merge_params.param_str(),
+    )];
+    if let Err(err) = dm.table_load(origin, &merge_table, DmOptions::default()) {
+        dm.device_suspend(origin, DmOptions::private())?;
+        dm.device_suspend(snapshot, DmOptions::private())?;
+        return Err(err);
+    }
+    dm.device_suspend(origin, DmOptions::private())?;
+    progress(&SnapshotRevertProgress::MergeStarted);
+
+    dm.device_remove(snapshot, DmOptions::default())?;
+    progress(&SnapshotRevertProgress::SnapshotRemoved);
+
+    let mut initial_remaining = None;
+    loop {
+        let remaining = match snapshot_status(dm, origin)? {
+            SnapshotStatus::Working {
+                allocated,
+                metadata,
+                ..
+            } => Sectors(allocated.saturating_sub(*metadata)),
+            status => {
+                return Err(DmError::Dm(
+                    ErrorEnum::Error,
+                    format!("merging into {origin} failed, its status is {status:?}"),
+                ))
+            }
+        };
+        let initial = *initial_remaining.get_or_insert(remaining);
+        progress(&SnapshotRevertProgress::Merging { remaining, initial });
+        if remaining == Sectors(0) {
+            break;
+        }
+        thread::sleep(interval);
+    }
+
+    let origin_table = [(
+        origin_start,
+        origin_length,
+        origin_params.target_type().to_string(),
+        origin_params.param_str(),
+    )];
+    dm.table_load(origin, &origin_table, DmOptions::default())?;
+    dm.device_suspend(origin, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))?;
+    dm.device_suspend(origin, DmOptions::private())?;
+    progress(&SnapshotRevertProgress::Complete);
+
+    Ok(())
+}
+
+/// Merge the snapshot device `snapshot` into the snapshot-origin device
+/// `origin`, as `lvconvert --merge` does.
+///
+/// The origin is reverted to the snapshot by `revert_to_snapshot`, which
+/// swaps the origin's table for a snapshot-merge table, polls the merge
+/// every `interval`, and restores the origin's snapshot-origin table once
+/// the merge completes. The snapshot's COW device, which then holds nothing
+/// of use, is removed if it is a DM device; any other COW device is left in
+/// place.
+///
+/// `progress` is called as for `revert_to_snapshot`, and finally with
+/// `SnapshotRevertProgress::CowRemoved` if the COW device was removed.
+pub fn merge_snapshot<F>(
+    dm: &DM,
+    origin: &DevId<'_>,
+    snapshot: &DevId<'_>,
+    interval: Duration,
+    mut progress: F,
+) -> DmResult<()>
+where
+    F: FnMut(&SnapshotRevertProgress),
+{
+    let (_, _, snapshot_params) = single_line::<SnapshotTargetParams>(dm, snapshot)?;
+    revert_to_snapshot(dm, origin, snapshot, interval, &mut progress)?;
+
+    match dm
+        .list_devices()?
+        .into_iter()
+        .find(|(_, device, _)| *device == snapshot_params.cow)
+    {
+        Some((cow_name, _, _)) => {
+            dm.device_remove(&DevId::Name(&cow_name), DmOptions::default())?;
+            progress(&SnapshotRevertProgress::CowRemoved);
+        }
+        None => debug!(
+            "COW device {} of merged snapshot {} is not a DM device, leaving it in place",
+            snapshot_params.cow, snapshot
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{Read, Seek, SeekFrom, Write},
+        path::Path,
+    };
+
+    use crate::{
+        core::devnode_to_devno,
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that snapshot params are parsed and printed, with or without
+    /// feature arguments.
+    fn test_snapshot_params() {
+        let params = "snapshot 8:1 8:2 P 8"
+            .parse::<SnapshotTargetParams>()
+            .unwrap();
+        assert_eq!(
+            params,
+            SnapshotTargetParams::new(
+                Device::from_str("8:1").unwrap(),
+                Device::from_str("8:2").unwrap(),
+                SnapshotPersistence::Persistent,
+                Sectors(8)
+            )
+        );
+        assert_eq!(params.to_merge().to_string(), "snapshot-merge 8:1 8:2 P 8");
+
+        let s = "snapshot 8:1 8:2 PO 16 1 discard_zeroes_cow";
+        assert_eq!(s.parse::<SnapshotTargetParams>().unwrap().to_string(), s);
+        assert_matches!(
+            "snapshot 8:1 8:2 PO 16 2 discard_zeroes_cow".parse::<SnapshotTargetParams>(),
+            Err(_)
+        );
+        assert_matches!(
+            "snapshot 8:1 8:2 X 16".parse::<SnapshotTargetParams>(),
+            Err(_)
+        );
+        assert_eq!(
+            "snapshot-origin 8:1"
+                .parse::<SnapshotOriginTargetParams>()
+                .unwrap()
+                .to_string(),
+            "snapshot-origin 8:1"
+        );
+    }
+
+    #[test]
+    /// Verify that snapshot status lines are parsed.
+    fn test_snapshot_status() {
+        let status = "24/2048 16".parse::<SnapshotStatus>().unwrap();
+        assert_eq!(
+            status,
+            SnapshotStatus::Working {
+                allocated: Sectors(24),
+                total: Sectors(2048),
+                metadata: Sectors(16)
+            }
+        );
+        assert_eq!(status.merge_remaining(), Some(Sectors(8)));
+        assert_eq!(
+            "Merge failed".parse::<SnapshotStatus>().unwrap(),
+            SnapshotStatus::MergeFailed
+        );
+        assert_eq!(
+            "Invalid"
+                .parse::<SnapshotStatus>()
+                .unwrap()
+                .merge_remaining(),
+            None
+        );
+        assert_matches!("24/2048".parse::<SnapshotStatus>(), Err(_));
+    }
+
+    /// Verify that data written to the origin after a snapshot is made is
+    /// reverted by merging the snapshot, and that the progress is reported
+    /// in order.
+    fn test_revert_to_snapshot(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = DM::new().unwrap();
+        let origin_dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let cow_dev = Device::from(devnode_to_devno(paths[1]).unwrap().unwrap());
+        let length = Sectors(2048);
+
+        let create = |name: &str, target_type: TargetTypeBuf, params: String| {
+            let name = test_name(name).expect("is valid DM name");
+            dm.device_create(&name, None, DmOptions::default()).unwrap();
+            let id = DevId::Name(&name);
+            let table = [(0, *length, target_type.to_string(), params)];
+            dm.table_load(&id, &table, DmOptions::default()).unwrap();
+            dm.device_suspend(&id, DmOptions::private()).unwrap();
+            name
+        };
+        let origin_params = SnapshotOriginTargetParams::new(origin_dev);
+        let origin_name = create(
+            "origin",
+            origin_params.target_type(),
+            origin_params.param_str(),
+        );
+        let origin_path = Path::new("/dev/mapper").join(origin_name.to_string());
+        let write_origin = |byte: u8| {
+            let mut f = OpenOptions::new().write(true).open(&origin_path).unwrap();
+            f.write_all(&[byte; 4096]).unwrap();
+            f.sync_all().unwrap();
+        };
+
+        write_origin(1);
+        let snapshot_params = SnapshotTargetParams::new(
+            origin_dev,
+            cow_dev,
+            SnapshotPersistence::Persistent,
+            Sectors(8),
+        );
+        let snapshot_name = create(
+            "snapshot",
+            snapshot_params.target_type(),
+            snapshot_params.param_str(),
+        );
+        write_origin(2);
+
+        let origin = DevId::Name(&origin_name);
+        let mut stages = Vec::new();
+        revert_to_snapshot(
+            &dm,
+            &origin,
+            &DevId::Name(&snapshot_name),
+            Duration::from_millis(100),
+            |stage| stages.push(stage.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(stages[0], SnapshotRevertProgress::Suspended);
+        assert_eq!(stages[1], SnapshotRevertProgress::MergeStarted);
+        assert_eq!(stages[2], SnapshotRevertProgress::SnapshotRemoved);
+        assert_matches!(
+            stages[stages.len() - 2],
+            SnapshotRevertProgress::Merging {
+                remaining: Sectors(0),
+                ..
+            }
+        );
+        assert_eq!(stages[stages.len() - 1], SnapshotRevertProgress::Complete);
+        assert!(!dm.device_exists(&DevId::Name(&snapshot_name)).unwrap());
+
+        let mut buf = [0u8; 4096];
+        let mut f = OpenOptions::new().read(true).open(&origin_path).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 1));
+
+        assert_matches!(
+            single_line::<SnapshotOriginTargetParams>(&dm, &origin),
+            Ok((_, _, params)) if params.origin == origin_dev
+        );
+
+        dm.device_remove(&origin, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_revert_to_snapshot() {
+        test_with_spec(2, test_revert_to_snapshot);
+    }
+
+    /// Verify that merging a snapshot whose COW device is a DM device
+    /// reverts the origin, restores its snapshot-origin table, and removes
+    /// the COW device.
+    fn test_merge_snapshot(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = DM::new().unwrap();
+        let origin_dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let length = Sectors(2048);
+
+        let create = |name: &str, target_type: String, params: String| {
+            let name = test_name(name).expect("is valid DM name");
+            let info = dm.device_create(&name, None, DmOptions::default()).unwrap();
+            let id = DevId::Name(&name);
+            let table = [(0, *length, target_type, params)];
+            dm.table_load(&id, &table, DmOptions::default()).unwrap();
+            dm.device_suspend(&id, DmOptions::private()).unwrap();
+            (name, info.device())
+        };
+        let (cow_name, cow_dev) = create(
+            "cow",
+            "linear".to_string(),
+            format!("{} 0", paths[1].display()),
+        );
+        let origin_params = SnapshotOriginTargetParams::new(origin_dev);
+        let (origin_name, _) = create(
+            "origin",
+            origin_params.target_type().to_string(),
+            origin_params.param_str(),
+        );
+        let origin_path = Path::new("/dev/mapper").join(origin_name.to_string());
+        let write_origin = |byte: u8| {
+            let mut f = OpenOptions::new().write(true).open(&origin_path).unwrap();
+            f.write_all(&[byte; 4096]).unwrap();
+            f.sync_all().unwrap();
+        };
+
+        write_origin(1);
+        let snapshot_params = SnapshotTargetParams::new(
+            origin_dev,
+            cow_dev,
+            SnapshotPersistence::Persistent,
+            Sectors(8),
+        );
+        let (snapshot_name, _) = create(
+            "snapshot",
+            snapshot_params.target_type().to_string(),
+            snapshot_params.param_str(),
+        );
+        write_origin(2);
+
+        let origin = DevId::Name(&origin_name);
+        let mut stages = Vec::new();
+        merge_snapshot(
+            &dm,
+            &origin,
+            &DevId::Name(&snapshot_name),
+            Duration::from_millis(100),
+            |stage| stages.push(stage.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stages[stages.len() - 2..],
+            [
+                SnapshotRevertProgress::Complete,
+                SnapshotRevertProgress::CowRemoved
+            ]
+        );
+        assert!(!dm.device_exists(&DevId::Name(&snapshot_name)).unwrap());
+        assert!(!dm.device_exists(&DevId::Name(&cow_name)).unwrap());
+
+        let mut buf = [0u8; 4096];
+        let mut f = OpenOptions::new().read(true).open(&origin_path).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 1));
+
+        assert_matches!(
+            single_line::<SnapshotOriginTargetParams>(&dm, &origin),
+            Ok((_, _, params)) if params == origin_params
+        );
+
+        dm.device_remove(&origin, DmOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn loop_test_merge_snapshot() {
+        test_with_spec(2, test_merge_snapshot);
+    }
+}
@@ -0,0 +1,306 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    core::{DevId, DmFlags, DmNameBuf, DmOptions, DM},
+    lineardev::{LinearDevTargetParams, LinearDevTargetTable},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{TargetLine, TargetTable},
+    snapshot::{single_line, snapshot_status, SnapshotStatus, SnapshotTargetParams},
+    thinpoolmonitor::threshold_crossed,
+    units::Sectors,
+};
+
+/// A request to the callback of a `SnapshotMonitor` to decide what to do
+/// about a snapshot whose COW device is filling up.
+#[derive(Clone, Debug)]
+pub struct SnapshotCowRequest {
+    /// The sectors of the COW device in use, including its metadata
+    pub allocated: Sectors,
+    /// The size of the COW device
+    pub total: Sectors,
+    /// The current table of the COW device, if it is a DM device with a
+    /// linear table, which may then be extended
+    pub table: Option<Vec<TargetLine<LinearDevTargetParams>>>,
+}
+
+/// What a `SnapshotMonitor` should do about a snapshot whose COW device has
+/// crossed its threshold.
+#[derive(Clone, Debug)]
+pub enum SnapshotCowAction {
+    /// Reload the COW device with this table, which must be longer than
+    /// its current table and should begin with it
+    Extend(Vec<TargetLine<LinearDevTargetParams>>),
+    /// Invalidate the snapshot now, by replacing its table with an error
+    /// target, rather than leave the kernel to invalidate it when its COW
+    /// device overflows
+    Invalidate,
+    /// Do nothing
+    Decline,
+}
+
+/// A monitor which examines the usage of the COW device of a snapshot and,
+/// when it crosses a threshold, asks a user-supplied callback whether to
+/// extend the COW device, by reloading it with a longer table, or to
+/// invalidate the snapshot gracefully. This is the mechanism of lvm2's
+/// snapshot autoextend.
+///
+/// The monitor does not run by itself; `check` should be called
+/// periodically, since a snapshot does not report an event as it fills.
+pub struct SnapshotMonitor<F>
+where
+    F: FnMut(&SnapshotCowRequest) -> DmResult<SnapshotCowAction>,
+{
+    percent: u8,
+    action: F,
+}
+
+impl<F> SnapshotMonitor<F>
+where
+    F: FnMut(&SnapshotCowRequest) -> DmResult<SnapshotCowAction>,
+{
+    /// Make a new monitor, which calls `action` when the usage of the COW
+    /// device reaches `percent` percent.
+    pub fn new(percent: u8, action: F) -> DmResult<SnapshotMonitor<F>> {
+        if percent > 100 {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("threshold {percent}% is greater than 100%"),
+            ));
+        }
+        Ok(SnapshotMonitor { percent, action })
+    }
+
+    /// Examine the usage of the COW device of the snapshot device
+    /// `snapshot`, and act on it if it has crossed the threshold. Returns
+    /// the action taken, or None if the threshold was not crossed.
+    pub fn check(&mut self, dm: &DM, snapshot: &DevId<'_>) -> DmResult<Option<SnapshotCowAction>> {
+        let (allocated, total) = match snapshot_status(dm, snapshot)? {
+            SnapshotStatus::Working {
+                allocated, total, ..
+            } => (allocated, total),
+            status => {
+                return Err(DmError::Dm(
+                    ErrorEnum::Error,
+                    format!("snapshot {snapshot} is no longer usable, its status is {status:?}"),
+                ))
+            }
+        };
+        if !threshold_crossed(*allocated, *total, self.percent) {
+            return Ok(None);
+        }
+
+        let (start, length, params) = single_line::<SnapshotTargetParams>(dm, snapshot)?;
+        let cow = cow_name(dm, &params)?;
+        let table = match &cow {
+            Some(cow) => cow_table(dm, cow)?,
+            None => None,
+        };
+        let request = SnapshotCowRequest {
+            allocated,
+            total,
+            table,
+        };
+
+        let action = (self.action)(&request)?;
+        match (&action, &cow, &request.table) {
+            (SnapshotCowAction::Extend(new_table), Some(cow), Some(table)) => {
+                extend_cow(dm, cow, table, new_table)?;
+                debug!("Extended COW device {} of snapshot {}", cow, snapshot);
+            }
+            (SnapshotCowAction::Extend(_), _, _) => {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "COW device {} of snapshot {} is not a linear DM device and can not be extended",
+                        params.cow, snapshot
+                    ),
+                ));
+            }
+            (SnapshotCowAction::Invalidate, _, _) => {
+                let table = [(start, length, "error".to_string(), String::new())];
+                dm.table_load(snapshot, &table, DmOptions::default())?;
+                dm.device_suspend(
+                    snapshot,
+                    DmOptions::default().set_flags(DmFlags::DM_SUSPEND),
+                )?;
+                dm.device_suspend(snapshot, DmOptions::private())?;
+                warn!(
+                    "Invalidated snapshot {} with {} of {} of its COW device in use",
+                    snapshot, allocated, total
+                );
+            }
+            (SnapshotCowAction::Decline, _, _) => {
+                debug!("Action on COW device of snapshot {} declined", snapshot);
+                return Ok(None);
+            }
+        }
+        Ok(Some(action))
+    }
+}
+
+/// The name of the COW device of a snapshot, if it is a DM device.
+fn cow_name(dm: &DM, params: &SnapshotTargetParams) -> DmResult<Option<DmNameBuf>> {
+    Ok(dm
+        .list_devices()?
+        .into_iter()
+        .find(|(_, device, _)| *device == params.cow)
+        .map(|(name, _, _)| name))
+}
+
+/// The table of the COW device, if it is a linear table.
+fn cow_table(dm: &DM, cow: &DmNameBuf) -> DmResult<Option<Vec<TargetLine<LinearDevTargetParams>>>> {
+    let (_, table) = dm.table_status(
+        &DevId::Name(cow),
+        DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+    )?;
+    Ok(LinearDevTargetTable::from_raw_table(&table)
+        .ok()
+        .map(|table| table.table))
+}
+
+/// Reload the COW device with a longer table.
+fn extend_cow(
+    dm: &DM,
+    cow: &DmNameBuf,
+    table: &[TargetLine<LinearDevTargetParams>],
+    new_table: &[TargetLine<LinearDevTargetParams>],
+) -> DmResult<()> {
+    let current: Sectors = table.iter().map(|line| line.length).sum();
+    let new: Sectors = new_table.iter().map(|line| line.length).sum();
+    if new <= current {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("new table of length {new} does not extend current table of length {current}"),
+        ));
+    }
+
+    let id = DevId::Name(cow);
+    dm.table_load(
+        &id,
+        &LinearDevTargetTable::new(new_table.to_vec()).to_raw_table(),
+        DmOptions::default(),
+    )?;
+    dm.device_suspend(&id, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))?;
+    dm.device_suspend(&id, DmOptions::private())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        lineardev::LinearTargetParams,
+        shared::TargetParams,
+        snapshot::{SnapshotOriginTargetParams, SnapshotPersistence},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that a threshold greater than 100% is rejected.
+    fn test_new() {
+        assert_matches!(
+            SnapshotMonitor::new(101, |_| Ok(SnapshotCowAction::Decline)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    /// Verify that a snapshot whose COW device crosses its threshold has its
+    /// COW device extended with the table supplied by the callback, and is
+    /// invalidated when the callback asks for that.
+    fn test_cow_actions(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = DM::new().unwrap();
+        let origin_dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let cow_backing = Device::from(devnode_to_devno(paths[1]).unwrap().unwrap());
+        let length = Sectors(2048);
+
+        let create = |name: &str, table: Vec<(u64, u64, String, String)>| {
+            let name = test_name(name).expect("is valid DM name");
+            let info = dm.device_create(&name, None, DmOptions::default()).unwrap();
+            let id = DevId::Name(&name);
+            dm.table_load(&id, &table, DmOptions::default()).unwrap();
+            dm.device_suspend(&id, DmOptions::private()).unwrap();
+            (name, info.device())
+        };
+        let cow_line = |start: Sectors, offset: Sectors| {
+            TargetLine::new(
+                start,
+                length,
+                LinearDevTargetParams::Linear(LinearTargetParams::new(cow_backing, offset)),
+            )
+        };
+        let (cow_name, cow_dev) = create(
+            "cow",
+            LinearDevTargetTable::new(vec![cow_line(Sectors(0), Sectors(0))]).to_raw_table(),
+        );
+        let origin_params = SnapshotOriginTargetParams::new(origin_dev);
+        let (origin_name, _) = create(
+            "origin",
+            vec![(
+                0,
+                *length,
+                origin_params.target_type().to_string(),
+                origin_params.param_str(),
+            )],
+        );
+        let snapshot_params = SnapshotTargetParams::new(
+            origin_dev,
+            cow_dev,
+            SnapshotPersistence::Persistent,
+            Sectors(8),
+        );
+        snapshot_params.check_chunk_size(&dm).unwrap();
+        let (snapshot_name, _) = create(
+            "snapshot",
+            vec![(
+                0,
+                *length,
+                snapshot_params.target_type().to_string(),
+                snapshot_params.param_str(),
+            )],
+        );
+        let snapshot = DevId::Name(&snapshot_name);
+
+        let mut monitor = SnapshotMonitor::new(100, |_| Ok(SnapshotCowAction::Decline)).unwrap();
+        assert_matches!(monitor.check(&dm, &snapshot), Ok(None));
+
+        let mut monitor = SnapshotMonitor::new(0, |request| {
+            let mut table = request.table.clone().unwrap();
+            table.push(cow_line(length, length));
+            Ok(SnapshotCowAction::Extend(table))
+        })
+        .unwrap();
+        assert_matches!(
+            monitor.check(&dm, &snapshot),
+            Ok(Some(SnapshotCowAction::Extend(_)))
+        );
+        assert_matches!(
+            snapshot_status(&dm, &snapshot),
+            Ok(SnapshotStatus::Working { total, .. }) if total == length * 2u64
+        );
+
+        let mut monitor = SnapshotMonitor::new(0, |_| Ok(SnapshotCowAction::Invalidate)).unwrap();
+        assert_matches!(
+            monitor.check(&dm, &snapshot),
+            Ok(Some(SnapshotCowAction::Invalidate))
+        );
+        assert_matches!(monitor.check(&dm, &snapshot), Err(_));
+
+        for name in [&snapshot_name, &origin_name, &cow_name] {
+            dm.device_remove(&DevId::Name(name), DmOptions::default())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn loop_test_cow_actions() {
+        test_with_spec(2, test_cow_actions);
+    }
+}
@@ -0,0 +1,422 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+
+use crate::{
+    core::{DevId, DeviceInfo, DmName, DmNameBuf, DmOptions, DmUuid, DmUuidBuf, DM},
+    crypt::{CipherSpec, CryptDevTargetTable, CryptTargetParams},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{device_create, TargetParams, TargetTable},
+    thinpooldev::{ThinPoolDevTargetTable, ThinPoolTargetParams},
+    units::{DataBlocks, Sectors},
+    verity::VerityTargetParams,
+};
+
+type RawTable = Vec<(u64, u64, String, String)>;
+
+/// Generates the table of a layer from the `DeviceInfo` of each layer
+/// below it, and the length of each of their tables.
+type TableFn = Box<dyn FnOnce(&[DeviceInfo], &[Sectors]) -> DmResult<RawTable>>;
+
+/// The device and the length of the layer `index`, for the layer `name`
+/// above it.
+fn below_layer(
+    name: &DmName,
+    index: usize,
+    infos: &[DeviceInfo],
+    lengths: &[Sectors],
+) -> DmResult<(DeviceInfo, Sectors)> {
+    match (infos.get(index), lengths.get(index)) {
+        (Some(info), Some(length)) => Ok((info.clone(), *length)),
+        _ => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "layer {name} refers to layer {index}, but only {} layers are below it",
+                infos.len()
+            ),
+        )),
+    }
+}
+
+struct Layer {
+    name: DmNameBuf,
+    uuid: Option<DmUuidBuf>,
+    table: TableFn,
+}
+
+/// A builder for a stack of DM devices, each of which may be built on the
+/// devices below it, such as a thin pool over linear metadata and data
+/// devices.
+///
+/// Any layer may be added with `layer`. The common layerings of a crypt
+/// device over the whole of a layer below, a thin pool over metadata and
+/// data layers, and a verity device over data and hash layers, may be
+/// added with `crypt_layer`, `thin_pool_layer`, and `verity_layer`, which
+/// refer to the layers below by the order in which they were added.
+///
+/// The table of each layer is generated when the layer is activated, from
+/// the `DeviceInfo` of the layers already activated, so that it may refer to
+/// their device numbers. Layers are activated in the order in which they
+/// were added. If any layer can not be activated, the layers already
+/// activated are removed again, in reverse order.
+///
+/// ```no_run
+/// use devicemapper::{
+///     Device, DeviceStack, DmName, LinearDevTargetParams, LinearDevTargetTable,
+///     LinearTargetParams, Sectors, TargetLine, DM,
+/// };
+///
+/// let dm = DM::new().unwrap();
+/// let linear = |device: Device, length: Sectors| {
+///     LinearDevTargetTable::new(vec![TargetLine::new(
+///         Sectors(0),
+///         length,
+///         LinearDevTargetParams::Linear(LinearTargetParams::new(device, Sectors(0))),
+///     )])
+/// };
+///
+/// let infos = DeviceStack::new()
+///     .layer(DmName::new("lower").unwrap(), None, move |_| {
+///         Ok(linear(Device { major: 8, minor: 0 }, Sectors(2048)))
+///     })
+///     .layer(DmName::new("upper").unwrap(), None, move |below| {
+///         Ok(linear(below[0].device(), Sectors(2048)))
+///     })
+///     .activate(&dm)
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct DeviceStack {
+    layers: Vec<Layer>,
+}
+
+impl fmt::Debug for DeviceStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceStack")
+            .field(
+                "layers",
+                &self
+                    .layers
+                    .iter()
+                    .map(|layer| (&layer.name, &layer.uuid))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl DeviceStack {
+    /// Make a new, empty stack.
+    pub fn new() -> DeviceStack {
+        DeviceStack::default()
+    }
+
+    /// Add a layer on top of the stack. `table` is called with the
+    /// `DeviceInfo` of every layer below, in the order in which they were
+    /// added, to generate the table of the new layer.
+    pub fn layer<T, F>(self, name: &DmName, uuid: Option<&DmUuid>, table: F) -> DeviceStack
+    where
+        T: TargetTable,
+        F: FnOnce(&[DeviceInfo]) -> DmResult<T> + 'static,
+    {
+        self.raw_layer(name, uuid, move |below, _| {
+            table(below).map(|table| table.to_raw_table())
+        })
+    }
+
+    fn raw_layer<F>(mut self, name: &DmName, uuid: Option<&DmUuid>, table: F) -> DeviceStack
+    where
+        F: FnOnce(&[DeviceInfo], &[Sectors]) -> DmResult<RawTable> + 'static,
+    {
+        self.layers.push(Layer {
+            name: name.to_owned(),
+            uuid: uuid.map(|uuid| uuid.to_owned()),
+            table: Box::new(table),
+        });
+        self
+    }
+
+    /// Add a crypt layer on top of the stack, which encrypts the whole of
+    /// the layer `below` with `cipher` and `key`.
+    pub fn crypt_layer(
+        self,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        below: usize,
+        cipher: &CipherSpec,
+        key: &str,
+    ) -> DeviceStack {
+        let layer_name = name.to_owned();
+        let cipher = cipher.to_string();
+        let key = key.to_string();
+        self.raw_layer(name, uuid, move |infos, lengths| {
+            let (info, length) = below_layer(&layer_name, below, infos, lengths)?;
+            let params = CryptTargetParams::new(cipher, key, 0, info.device(), Sectors(0));
+            Ok(CryptDevTargetTable::new(Sectors(0), length, params).to_raw_table())
+        })
+    }
+
+    /// Add a thin pool layer on top of the stack, which keeps its metadata
+    /// on the layer `meta` and its data on the whole of the layer `data`.
+    pub fn thin_pool_layer(
+        self,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        (meta, data): (usize, usize),
+        data_block_size: Sectors,
+        low_water_mark: DataBlocks,
+    ) -> DeviceStack {
+        let layer_name = name.to_owned();
+        self.raw_layer(name, uuid, move |infos, lengths| {
+            let (meta_info, _) = below_layer(&layer_name, meta, infos, lengths)?;
+            let (data_info, length) = below_layer(&layer_name, data, infos, lengths)?;
+            let params = ThinPoolTargetParams::new(
+                meta_info.device(),
+                data_info.device(),
+                data_block_size,
+                low_water_mark,
+                Vec::new(),
+            );
+            Ok(ThinPoolDevTargetTable::new(Sectors(0), length, params).to_raw_table())
+        })
+    }
+
+    /// Add a verity layer on top of the stack, which verifies the data of
+    /// the layer `data` against the hash tree on the layer `hash`. The data
+    /// and hash devices of `params` are replaced by those of the layers.
+    pub fn verity_layer(
+        self,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        (data, hash): (usize, usize),
+        params: VerityTargetParams,
+    ) -> DeviceStack {
+        let layer_name = name.to_owned();
+        self.raw_layer(name, uuid, move |infos, lengths| {
+            let (data_info, data_length) = below_layer(&layer_name, data, infos, lengths)?;
+            let (hash_info, _) = below_layer(&layer_name, hash, infos, lengths)?;
+            let length = params.data_block_size.sectors() * params.num_data_blocks;
+            if length > data_length {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "verity layer {layer_name} of {length} is longer than its data layer of {data_length}"
+                    ),
+                ));
+            }
+            let params = VerityTargetParams {
+                data_dev: data_info.device(),
+                hash_dev: hash_info.device(),
+                ..params
+            };
+            Ok(vec![(
+                0,
+                *length,
+                params.target_type().to_string(),
+                params.param_str(),
+            )])
+        })
+    }
+
+    /// Activate every layer of the stack, from the bottom up. Returns the
+    /// `DeviceInfo` of each layer, in the order in which they were added.
+    ///
+    /// If any layer fails, the layers already activated are removed and the
+    /// error is returned.
+    pub fn activate(self, dm: &DM) -> DmResult<Vec<DeviceInfo>> {
+        let mut infos: Vec<DeviceInfo> = Vec::with_capacity(self.layers.len());
+        let mut lengths: Vec<Sectors> = Vec::with_capacity(self.layers.len());
+        for layer in self.layers {
+            let result = (layer.table)(&infos, &lengths).and_then(|table| {
+                let length = table.iter().map(|(_, length, _, _)| Sectors(*length)).sum();
+                device_create(
+                    dm,
+                    &layer.name,
+                    layer.uuid.as_deref(),
+                    &RawTableWrapper(table),
+                    DmOptions::private(),
+                )
+                .map(|info| (info, length))
+            });
+            match result {
+                Ok((info, length)) => {
+                    infos.push(info);
+                    lengths.push(length);
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to activate layer {} of device stack: {}",
+                        layer.name, err
+                    );
+                    if let Err(cleanup_err) = DeviceStack::teardown(dm, &infos) {
+                        warn!(
+                            "Failed to remove partially activated device stack: {}",
+                            cleanup_err
+                        );
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(infos)
+    }
+
+    /// Remove the devices of an activated stack, from the top down. Every
+    /// removal is attempted; the first error encountered is returned.
+    pub fn teardown(dm: &DM, infos: &[DeviceInfo]) -> DmResult<()> {
+        let mut result = Ok(());
+        for info in infos.iter().rev() {
+            if let Some(name) = info.name() {
+                if let Err(err) = dm.device_remove(&DevId::Name(name), DmOptions::default()) {
+                    warn!("Failed to remove device {}: {}", name, err);
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+// A raw table, so that a table generated by any layer may be passed to
+// device_create.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct RawTableWrapper(RawTable);
+
+impl fmt::Display for RawTableWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (start, length, target_type, params) in &self.0 {
+            writeln!(f, "{start} {length} {target_type} {params}")?;
+        }
+        Ok(())
+    }
+}
+
+impl TargetTable for RawTableWrapper {
+    fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<RawTableWrapper> {
+        Ok(RawTableWrapper(table.to_vec()))
+    }
+
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::{devnode_to_devno, Device, DmFlags},
+        lineardev::{LinearDevTargetParams, LinearDevTargetTable, LinearTargetParams},
+        result::{DmError, ErrorEnum},
+        shared::{device_exists, TargetLine},
+        testing::{test_name, test_with_spec},
+        units::Sectors,
+    };
+
+    use super::*;
+
+    fn linear(device: Device, length: Sectors) -> LinearDevTargetTable {
+        LinearDevTargetTable::new(vec![TargetLine::new(
+            Sectors(0),
+            length,
+            LinearDevTargetParams::Linear(LinearTargetParams::new(device, Sectors(0))),
+        )])
+    }
+
+    /// Verify that a stack is activated in order with each layer built on
+    /// the one below, and that a failing layer causes the layers below it
+    /// to be removed.
+    fn test_stack(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let lower = test_name("lower").expect("valid format");
+        let upper = test_name("upper").expect("valid format");
+
+        let infos = DeviceStack::new()
+            .layer(&lower, None, move |_| Ok(linear(dev, Sectors(1))))
+            .layer(&upper, None, |below| {
+                Ok(linear(below[0].device(), Sectors(1)))
+            })
+            .activate(&dm)
+            .unwrap();
+        assert_eq!(infos.len(), 2);
+        assert_eq!(
+            dm.table_deps(&DevId::Name(&upper), DmOptions::default())
+                .unwrap(),
+            vec![infos[0].device()]
+        );
+        DeviceStack::teardown(&dm, &infos).unwrap();
+        assert!(!device_exists(&dm, &lower).unwrap());
+
+        assert_matches!(
+            DeviceStack::new()
+                .layer(&lower, None, move |_| Ok(linear(dev, Sectors(1))))
+                .layer(&upper, None, |_| -> DmResult<LinearDevTargetTable> {
+                    Err(DmError::Dm(ErrorEnum::Invalid, "no table".into()))
+                })
+                .activate(&dm),
+            Err(_)
+        );
+        assert!(!device_exists(&dm, &lower).unwrap());
+        assert!(!device_exists(&dm, &upper).unwrap());
+    }
+
+    #[test]
+    fn loop_test_stack() {
+        test_with_spec(1, test_stack);
+    }
+
+    #[test]
+    /// Verify that a layer may refer only to the layers below it.
+    fn test_below_layer() {
+        let name = DmName::new("upper").expect("valid format");
+        assert_matches!(
+            below_layer(name, 0, &[], &[]),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    /// Verify that a crypt layer covers the whole of the layer below it.
+    fn test_crypt_stack(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let lower = test_name("lower").expect("valid format");
+        let upper = test_name("upper").expect("valid format");
+
+        let infos = DeviceStack::new()
+            .layer(&lower, None, move |_| Ok(linear(dev, Sectors(16))))
+            .crypt_layer(
+                &upper,
+                None,
+                0,
+                &CipherSpec::new("aes", Some("xts"), Some("plain64")),
+                &"00".repeat(64),
+            )
+            .activate(&dm)
+            .unwrap();
+        let (_, table) = dm
+            .table_status(
+                &DevId::Name(&upper),
+                DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+            )
+            .unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].1, 16);
+        assert_eq!(table[0].2, "crypt");
+        DeviceStack::teardown(&dm, &infos).unwrap();
+    }
+
+    #[test]
+    fn loop_test_crypt_stack() {
+        test_with_spec(1, test_crypt_stack);
+    }
+}
@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Programming the region table of a switch device. The switch target divides
+// its device into fixed-size regions, and sends the I/O to each region down
+// the path which the region table gives for it. The table is programmed with
+// set_region_mappings messages, whose arguments are, in hexadecimal:
+//
+// <index>:<path>  maps region <index> to <path>
+// :<path>         maps the region after the last one mapped to <path>
+// R<n>,<m>        maps each of the next <m> regions to the path of the region
+//                 <n> regions before it, repeating the last <n> mappings
+//
+// A map of a million regions, sent as one mapping per region, would take
+// many large messages; most maps, e.g., those striped across the paths,
+// repeat, and are sent in a handful of messages using the repeat syntax.
+
+use crate::{
+    core::{DevId, DM},
+    result::{DmError, DmResult, ErrorEnum},
+};
+
+/// The message which programs the region table
+const SET_REGION_MAPPINGS: &str = "set_region_mappings";
+
+/// The default size of the longest message sent to program a region table,
+/// the size of the smallest buffer used for DM ioctls
+pub const SWITCH_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+/// The longest repeated pattern of mappings which is looked for
+const MAX_CYCLE_LENGTH: usize = 64;
+
+/// A repeat of the last `cycle` mappings over the next `count` regions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Repeat {
+    cycle: usize,
+    count: usize,
+}
+
+/// The longest run of regions, starting at `index`, whose paths repeat
+/// those of the regions before them, with the shortest cycle for that run.
+fn longest_repeat(map: &[u32], index: usize) -> Option<Repeat> {
+    let mut best: Option<Repeat> = None;
+    for cycle in 1..=MAX_CYCLE_LENGTH.min(index) {
+        let count = map[index..]
+            .iter()
+            .zip(&map[index - cycle..])
+            .take_while(|(path, source)| path == source)
+            .count();
+        if count > best.map_or(0, |best| best.count) {
+            best = Some(Repeat { cycle, count });
+        }
+    }
+    best
+}
+
+/// The length of the arguments mapping the given paths one at a time with
+/// the index omitted: ":<path>" each, and a separating space.
+fn explicit_len(paths: &[u32]) -> usize {
+    paths.iter().map(|path| format!(" :{path:x}").len()).sum()
+}
+
+/// Compute the set_region_mappings messages which program the region table
+/// of a switch device with `map`, which gives the path, numbered from 0, of
+/// each region in turn. Runs of regions which repeat the mappings before
+/// them are sent with the repeat syntax where that is shorter. No message
+/// is longer than `max_message_size` bytes.
+///
+/// The messages must be sent in order, as each may repeat the mappings
+/// made by those before it.
+pub fn switch_region_mapping_messages(
+    map: &[u32],
+    max_message_size: usize,
+) -> DmResult<Vec<String>> {
+    let mut messages = Vec::new();
+    let mut message = String::new();
+    let mut index = 0;
+    while index < map.len() {
+        // The first argument of a message must give an index, and the
+        // repeat syntax is relative to the index last given, so a message
+        // begins with an explicit mapping.
+        let (arg, regions) = if message.is_empty() {
+            (
+                format!("{} {:x}:{:x}", SET_REGION_MAPPINGS, index, map[index]),
+                1,
+            )
+        } else {
+            match longest_repeat(map, index) {
+                Some(Repeat { cycle, count })
+                    if format!(" R{cycle:x},{count:x}").len()
+                        < explicit_len(&map[index..index + count]) =>
+                {
+                    (format!(" R{cycle:x},{count:x}"), count)
+                }
+                _ => (format!(" :{:x}", map[index]), 1),
+            }
+        };
+
+        if message.len() + arg.len() > max_message_size {
+            if message.is_empty() {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "message size limit of {max_message_size} bytes is too small to map region {index:x}"
+                    ),
+                ));
+            }
+            messages.push(std::mem::take(&mut message));
+            continue;
+        }
+        message.push_str(&arg);
+        index += regions;
+    }
+    if !message.is_empty() {
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Program the region table of the switch device `id` with `map`, which
+/// gives the path, numbered from 0, of each region in turn, using as few
+/// messages as possible, none longer than `SWITCH_MAX_MESSAGE_SIZE`.
+pub fn switch_set_region_mappings(dm: &DM, id: &DevId<'_>, map: &[u32]) -> DmResult<()> {
+    let messages = switch_region_mapping_messages(map, SWITCH_MAX_MESSAGE_SIZE)?;
+    debug!(
+        "Programming {} regions of switch device {} with {} messages",
+        map.len(),
+        id,
+        messages.len()
+    );
+    for message in messages {
+        dm.target_msg(id, None, &message)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apply messages to a region table as the kernel does.
+    fn apply(table: &mut [u32], messages: &[String]) {
+        for message in messages {
+            let mut args = message.split(' ');
+            assert_eq!(args.next(), Some(SET_REGION_MAPPINGS));
+            let mut index = 0;
+            for arg in args {
+                if let Some(repeat) = arg.strip_prefix('R') {
+                    let (cycle, count) = repeat.split_once(',').unwrap();
+                    let cycle = usize::from_str_radix(cycle, 16).unwrap();
+                    let count = usize::from_str_radix(count, 16).unwrap();
+                    assert!(cycle - 1 <= index);
+                    for _ in 0..count {
+                        index += 1;
+                        table[index] = table[index - cycle];
+                    }
+                } else {
+                    let (region, path) = arg.split_once(':').unwrap();
+                    index = if region.is_empty() {
+                        index + 1
+                    } else {
+                        usize::from_str_radix(region, 16).unwrap()
+                    };
+                    table[index] = u32::from_str_radix(path, 16).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    /// Verify that repeating maps are sent in few messages, that the
+    /// messages respect the size limit, and that they program the map.
+    fn test_region_mapping_messages() {
+        let striped = (0..1_000_000u32).map(|i| i % 3).collect::<Vec<_>>();
+        let messages = switch_region_mapping_messages(&striped, SWITCH_MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(messages, vec!["set_region_mappings 0:0 :1 :2 R3,f423d"]);
+        let mut table = vec![u32::MAX; striped.len()];
+        apply(&mut table, &messages);
+        assert_eq!(table, striped);
+
+        let irregular = (0..10_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 7) % 5)
+            .collect::<Vec<_>>();
+        let messages = switch_region_mapping_messages(&irregular, 256).unwrap();
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|message| message.len() <= 256));
+        let mut table = vec![u32::MAX; irregular.len()];
+        apply(&mut table, &messages);
+        assert_eq!(table, irregular);
+
+        assert_eq!(
+            switch_region_mapping_messages(&[], SWITCH_MAX_MESSAGE_SIZE).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_matches!(
+            switch_region_mapping_messages(&striped, 8),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+}
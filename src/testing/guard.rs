@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use devicemapper_sys::{DM_COOKIE_MAGIC, DM_UDEV_FLAGS_SHIFT};
+use nix::libc::{semctl, IPC_RMID};
+
+use crate::core::{DevId, DmFlags, DmName, DmNameBuf, DmOptions, DM};
+
+thread_local! {
+    // The names of the devices created by the test running on this thread.
+    static DEVICES: RefCell<HashSet<DmNameBuf>> = RefCell::new(HashSet::new());
+    // The cookies and semaphore ids of the udev sync semaphores created by
+    // the test running on this thread which have not yet been destroyed.
+    static SEMAPHORES: RefCell<HashMap<i32, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Record that the current test created the device `name`.
+pub(crate) fn record_device(name: &DmName) {
+    DEVICES.with(|devices| devices.borrow_mut().insert(name.to_owned()));
+}
+
+/// Record that the current test renamed the device `old_name` to
+/// `new_name`.
+pub(crate) fn record_rename(old_name: &DmName, new_name: &DmName) {
+    DEVICES.with(|devices| {
+        let mut devices = devices.borrow_mut();
+        if devices.remove(old_name) {
+            devices.insert(new_name.to_owned());
+        }
+    });
+}
+
+/// Record that the current test created the udev sync semaphore `semid`
+/// for `cookie`.
+pub(crate) fn record_semaphore(cookie: u32, semid: i32) {
+    SEMAPHORES.with(|semaphores| semaphores.borrow_mut().insert(semid, cookie));
+}
+
+/// Record that the udev sync semaphore `semid` has been destroyed.
+pub(crate) fn forget_semaphore(semid: i32) {
+    SEMAPHORES.with(|semaphores| semaphores.borrow_mut().remove(&semid));
+}
+
+/// The udev sync cookie semaphores which exist now, by id, with their
+/// keys, as listed in /proc/sysvipc/sem, where each line begins
+/// "<key> <semid>".
+fn cookie_semaphores() -> HashMap<i32, u32> {
+    let sems = match fs::read_to_string("/proc/sysvipc/sem") {
+        Ok(sems) => sems,
+        Err(_) => return HashMap::new(),
+    };
+    sems.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let key = fields.next()?.parse::<i32>().ok()? as u32;
+            let semid = fields.next()?.parse::<i32>().ok()?;
+            if key >> DM_UDEV_FLAGS_SHIFT == DM_COOKIE_MAGIC {
+                Some((semid, key))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// A guard which, when dropped, removes the test devices and destroys the
+/// udev sync cookie semaphores which the test created and left behind.
+/// Devices and semaphores created by anything else, including tests
+/// running in parallel on other threads, are left alone. The guard is
+/// dropped while a test unwinds, so a test which fails part way through
+/// does not leave its devices behind to break the tests which follow.
+///
+/// Devices which could not be removed, e.g., because they are still open,
+/// are removed with DM_DEFERRED_REMOVE, so that they go away once they are
+/// closed.
+///
+/// `test_with_spec` holds a guard for each loop test; every sudo test makes
+/// its own as its first statement.
+pub struct TestGuard {
+    _private: (),
+}
+
+impl TestGuard {
+    /// Start recording the devices and cookie semaphores which the test
+    /// running on this thread creates.
+    pub fn new() -> TestGuard {
+        DEVICES.with(|devices| devices.borrow_mut().clear());
+        SEMAPHORES.with(|semaphores| semaphores.borrow_mut().clear());
+        TestGuard { _private: () }
+    }
+
+    fn remove_devices(&self, dm: &DM, created: &HashSet<DmNameBuf>) {
+        // Remove devices until no more can be removed, so that a device
+        // held open by another test device is removed once its holder is.
+        loop {
+            let remaining: Vec<DmNameBuf> = dm
+                .list_test_devices()
+                .map(|devs| devs.into_iter().map(|(name, _, _)| name).collect())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|name| created.contains(name))
+                .collect();
+            let removed = remaining
+                .iter()
+                .filter(|name| {
+                    dm.device_remove(&DevId::Name(name), DmOptions::default())
+                        .is_ok()
+                })
+                .count();
+            if removed == 0 {
+                for name in remaining {
+                    warn!("Scheduling deferred removal of test device {}", name);
+                    let _ = dm.device_remove(
+                        &DevId::Name(&name),
+                        DmOptions::default().set_flags(DmFlags::DM_DEFERRED_REMOVE),
+                    );
+                }
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for TestGuard {
+    fn drop(&mut self) {
+        let created = DEVICES.with(|devices| devices.take());
+        if !created.is_empty() {
+            if let Ok(dm) = DM::new() {
+                self.remove_devices(&dm, &created);
+            }
+        }
+
+        // Removing the devices may itself create and destroy semaphores,
+        // so collect the ones left over only once it is done.
+        let semaphores = SEMAPHORES.with(|semaphores| semaphores.take());
+        let existing = cookie_semaphores();
+        for (semid, cookie) in semaphores {
+            // The id may have been reused since the semaphore was destroyed
+            // by another thread; destroy it only if it still has the key.
+            if existing.get(&semid) == Some(&cookie) {
+                warn!("Destroying udev sync semaphore {}", semid);
+                unsafe { semctl(semid, 0, IPC_RMID) };
+            }
+        }
+    }
+}
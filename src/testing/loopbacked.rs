@@ -15,7 +15,7 @@ use tempfile::{self, TempDir};
 
 use crate::{
     consts::IEC,
-    testing::{logger::init_logger, test_lib::clean_up},
+    testing::{guard::TestGuard, logger::init_logger, test_lib::clean_up},
     units::{Bytes, Sectors, SECTOR_SIZE},
 };
 
@@ -128,7 +128,10 @@ where
     let device_paths: Vec<PathBuf> = loop_devices.iter().map(|x| x.path()).collect();
     let device_paths: Vec<&Path> = device_paths.iter().map(|x| x.as_path()).collect();
 
-    let result = panic::catch_unwind(|| test(&device_paths));
+    let result = panic::catch_unwind(|| {
+        let _guard = TestGuard::new();
+        test(&device_paths)
+    });
     let tear_down = clean_up();
 
     result.unwrap();
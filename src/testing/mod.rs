@@ -4,11 +4,15 @@
 
 //! Modules that support testing.
 
+mod guard;
 mod logger;
 mod loopbacked;
 mod test_lib;
 
+pub(crate) use self::guard::{forget_semaphore, record_device, record_rename, record_semaphore};
+
 pub use self::{
+    guard::TestGuard,
     logger::init_logger,
     loopbacked::test_with_spec,
     test_lib::{
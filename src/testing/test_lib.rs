@@ -5,7 +5,6 @@
 use std::{
     fs::File,
     io::Read,
-    os::unix::io::AsRawFd,
     panic::catch_unwind,
     path::{Path, PathBuf},
     process::Command,
@@ -16,6 +15,7 @@ use nix::mount::{umount2, MntFlags};
 use uuid::Uuid;
 
 use crate::{
+    blkdev::blkdev_file_size,
     core::{DevId, Device, DmNameBuf, DmOptions, DmUuidBuf, DM},
     result::{DmError, DmResult, ErrorEnum},
     units::Bytes,
@@ -36,26 +36,9 @@ impl DM {
     }
 }
 
-// send IOCTL via blkgetsize64
-ioctl_read!(
-    /// # Safety
-    ///
-    /// This function is a wrapper for `libc::ioctl` and therefore is unsafe for the same reasons
-    /// as other libc bindings. It accepts a file descriptor and mutable pointer so the semantics
-    /// of the invoked `ioctl` command should be examined to determine the effect it will have
-    /// on the resources passed to the command.
-    blkgetsize64,
-    0x12,
-    114,
-    u64
-);
-
 /// get the size of a given block device file
 pub fn blkdev_size(file: &File) -> Bytes {
-    let mut val: u64 = 0;
-
-    unsafe { blkgetsize64(file.as_raw_fd(), &mut val) }.unwrap();
-    Bytes(u128::from(val))
+    blkdev_file_size(file).unwrap()
 }
 
 fn get_dm() -> &'static DM {
@@ -5,7 +5,10 @@
 use std::{fmt, path::PathBuf, str::FromStr};
 
 use crate::{
-    core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
+    blkdev::device_size,
+    core::{
+        DevId, Device, DeviceInfo, DmFlags, DmName, DmNameBuf, DmOptions, DmUuid, DmUuidBuf, DM,
+    },
     result::{DmError, DmResult, ErrorEnum},
     shared::{
         device_create, device_exists, device_match, get_status, get_status_line_fields, message,
@@ -16,7 +19,7 @@ use crate::{
     units::Sectors,
 };
 
-const THIN_TARGET_NAME: &str = "thin";
+pub(crate) const THIN_TARGET_NAME: &str = "thin";
 
 /// Struct representing params for a thin target
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -322,6 +325,66 @@ impl ThinDev {
         Ok(dev)
     }
 
+    /// Create a thin device in thin_pool which is a snapshot of the block
+    /// device `origin`, which lies outside the pool. Reads of blocks which
+    /// have not been written to the thin device are passed through to the
+    /// origin, which is never written to. The thin device has the same size
+    /// as the origin.
+    ///
+    /// The origin must not be written to while any thin device uses it as
+    /// an external origin; ideally it should be made read-only.
+    pub fn new_external_snapshot(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        thin_pool: &ThinPoolDev,
+        thin_id: ThinDevId,
+        origin: Device,
+    ) -> DmResult<ThinDev> {
+        let length = device_size(origin)?.sectors();
+        ThinDev::check_external_origin(origin, length)?;
+
+        if device_exists(dm, name)? {
+            let err_msg = "Uncreated device should not be known to kernel";
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg.into()));
+        }
+
+        message(dm, thin_pool, &format!("create_thin {thin_id}"))?;
+
+        let table = ThinDevTargetTable::new(
+            Sectors::default(),
+            length,
+            ThinTargetParams::new(thin_pool.device(), thin_id, Some(origin)),
+        );
+        let dev_info = device_create(dm, name, uuid, &table, DmOptions::default())?;
+
+        Ok(ThinDev {
+            dev_info: Box::new(dev_info),
+            table,
+        })
+    }
+
+    /// Verify that the size of the external origin device `origin` matches
+    /// the length of a thin device which uses it.
+    pub fn check_external_origin(origin: Device, length: Sectors) -> DmResult<()> {
+        let origin_size = device_size(origin)?.sectors();
+        if origin_size == Sectors(0) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("external origin {origin} is empty"),
+            ));
+        }
+        if origin_size != length {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "external origin {origin} has size {origin_size}, but the thin device has length {length}"
+                ),
+            ));
+        }
+        Ok(())
+    }
+
     /// Create a snapshot of a ThinDev.  Once created a snapshot
     /// is the same as any other thin provisioned device.  There is
     /// no need to track any connection between the source and the
@@ -339,15 +402,17 @@ impl ThinDev {
             &source_id,
             DmOptions::default().set_flags(DmFlags::DM_SUSPEND),
         )?;
-        message(
+        // Resume the source even if the snapshot could not be created.
+        let res = message(
             dm,
             thin_pool,
             &format!(
                 "create_snap {} {}",
                 snapshot_thin_id, self.table.table.params.thin_id
             ),
-        )?;
+        );
         dm.device_suspend(&source_id, DmOptions::default())?;
+        res?;
         let table = ThinDev::gen_default_table(self.size(), thin_pool.device(), snapshot_thin_id);
         let dev_info = Box::new(device_create(
             dm,
@@ -359,6 +424,62 @@ impl ThinDev {
         Ok(ThinDev { dev_info, table })
     }
 
+    /// Create a tree of snapshots of this device, in which each snapshot
+    /// may itself have snapshots, as specified by `specs`. Each snapshot is
+    /// created and activated before its own snapshots, and its origin is
+    /// suspended while the pool creates it, as `snapshot` does.
+    ///
+    /// If any snapshot can not be created, the snapshots already created
+    /// by this call are destroyed and the error is returned.
+    pub fn snapshot_tree(
+        &self,
+        dm: &DM,
+        thin_pool: &ThinPoolDev,
+        specs: &[ThinSnapshotSpec],
+    ) -> DmResult<Vec<ThinSnapshotTree>> {
+        let mut trees: Vec<ThinSnapshotTree> = Vec::new();
+        for spec in specs {
+            match self.snapshot_subtree(dm, thin_pool, spec) {
+                Ok(tree) => trees.push(tree),
+                Err(err) => {
+                    for tree in trees.iter_mut().rev() {
+                        if let Err(destroy_err) = tree.destroy(dm, thin_pool) {
+                            warn!(
+                                "Failed to destroy snapshot {}: {}",
+                                tree.dev.name(),
+                                destroy_err
+                            );
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(trees)
+    }
+
+    fn snapshot_subtree(
+        &self,
+        dm: &DM,
+        thin_pool: &ThinPoolDev,
+        spec: &ThinSnapshotSpec,
+    ) -> DmResult<ThinSnapshotTree> {
+        let name = match spec.name {
+            Some(ref name) => name.clone(),
+            None => DmNameBuf::new(format!("{}-snap{}", self.name(), spec.thin_id))?,
+        };
+        let mut dev = self.snapshot(dm, &name, spec.uuid.as_deref(), thin_pool, spec.thin_id)?;
+        match dev.snapshot_tree(dm, thin_pool, &spec.children) {
+            Ok(children) => Ok(ThinSnapshotTree { dev, children }),
+            Err(err) => {
+                if let Err(destroy_err) = dev.destroy(dm, thin_pool) {
+                    warn!("Failed to destroy snapshot {}: {}", name, destroy_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
     /// Generate a table to be passed to DM. The format of the table
     /// entries is:
     /// <start (0)> <length> "thin" <thin device specific string>
@@ -389,7 +510,12 @@ impl ThinDev {
     }
 
     /// Set the table for the thin device's target
+    /// If the table names an external origin, its size must match the
+    /// length of the table.
     pub fn set_table(&mut self, dm: &DM, table: TargetLine<ThinTargetParams>) -> DmResult<()> {
+        if let Some(origin) = table.params.external_origin_dev {
+            ThinDev::check_external_origin(origin, table.length)?;
+        }
         let table = ThinDevTargetTable::new(table.start, table.length, table.params);
         self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
         self.table_load(dm, &table, DmOptions::default())?;
@@ -399,6 +525,22 @@ impl ThinDev {
         Ok(())
     }
 
+    /// Grow or shrink the thin device to `size`. Shrinking the device does
+    /// not release the pool's space beyond the new end; it should be
+    /// discarded first.
+    pub fn set_size(&mut self, dm: &DM, size: Sectors) -> DmResult<()> {
+        if size == Sectors(0) {
+            let err_msg = "a thin device may not have zero size";
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg.into()));
+        }
+        if size == self.size() {
+            return Ok(());
+        }
+        let mut table = self.table.table.clone();
+        table.length = size;
+        self.set_table(dm, table)
+    }
+
     /// Tear down the DM device, and also delete resources associated
     /// with its thin id from the thinpool.
     pub fn destroy(&mut self, dm: &DM, thin_pool: &ThinPoolDev) -> DmResult<()> {
@@ -409,13 +551,78 @@ impl ThinDev {
     }
 }
 
+/// A snapshot to be created by `ThinDev::snapshot_tree`, with the
+/// snapshots to be created of it in turn.
+#[derive(Clone, Debug)]
+pub struct ThinSnapshotSpec {
+    thin_id: ThinDevId,
+    name: Option<DmNameBuf>,
+    uuid: Option<DmUuidBuf>,
+    children: Vec<ThinSnapshotSpec>,
+}
+
+impl ThinSnapshotSpec {
+    /// Specify a snapshot with the given thin id. Unless a name is set, the
+    /// snapshot is named "<origin>-snap<thin_id>", where <origin> is the
+    /// name of the device of which it is a snapshot.
+    pub fn new(thin_id: ThinDevId) -> ThinSnapshotSpec {
+        ThinSnapshotSpec {
+            thin_id,
+            name: None,
+            uuid: None,
+            children: Vec::new(),
+        }
+    }
+
+    /// Set the name of the snapshot.
+    pub fn set_name(mut self, name: &DmName) -> ThinSnapshotSpec {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Set the uuid of the snapshot.
+    pub fn set_uuid(mut self, uuid: &DmUuid) -> ThinSnapshotSpec {
+        self.uuid = Some(uuid.to_owned());
+        self
+    }
+
+    /// Add a snapshot to be created of this snapshot.
+    pub fn add_child(mut self, child: ThinSnapshotSpec) -> ThinSnapshotSpec {
+        self.children.push(child);
+        self
+    }
+}
+
+/// A snapshot created by `ThinDev::snapshot_tree`, with the snapshots
+/// created of it.
+#[derive(Debug)]
+pub struct ThinSnapshotTree {
+    /// The snapshot
+    pub dev: ThinDev,
+    /// The snapshots of the snapshot, in the order in which they were
+    /// specified
+    pub children: Vec<ThinSnapshotTree>,
+}
+
+impl ThinSnapshotTree {
+    /// Destroy the snapshot and all the snapshots of it, the snapshots of
+    /// it first.
+    pub fn destroy(&mut self, dm: &DM, thin_pool: &ThinPoolDev) -> DmResult<()> {
+        for child in self.children.iter_mut().rev() {
+            child.destroy(dm, thin_pool)?;
+        }
+        self.dev.destroy(dm, thin_pool)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use std::{
         fs::{canonicalize, OpenOptions},
-        io::Write,
+        io::{Read, Write},
         path::Path,
+        time::Duration,
     };
 
     use nix::mount::{mount, umount2, MntFlags, MsFlags};
@@ -423,7 +630,7 @@ mod tests {
 
     use crate::{
         consts::IEC,
-        core::errors::Error,
+        core::{devnode_to_devno, errors::Error},
         shared::DmDevice,
         testing::{
             blkdev_size, test_name, test_string, test_uuid, test_with_spec, udev_settle,
@@ -679,6 +886,59 @@ mod tests {
         tp.teardown(&dm).unwrap();
     }
 
+    /// Verify that a tree of snapshots is created with the given and the
+    /// derived names, that a snapshot of a snapshot has the size of the
+    /// origin, and that a tree which can not be created is cleaned up.
+    fn test_snapshot_tree(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+
+        let thin_id = ThinDevId::new_u64(0).expect("is below limit");
+        let thin_name = test_name("name").expect("is valid DM name");
+        let mut td = ThinDev::new(&dm, &thin_name, None, MIN_THIN_DEV_SIZE, &tp, thin_id).unwrap();
+        udev_settle().unwrap();
+
+        let ss_name = test_name("snap_name").expect("is valid DM name");
+        let specs = [
+            ThinSnapshotSpec::new(ThinDevId::new_u64(1).expect("is below limit"))
+                .set_name(&ss_name)
+                .add_child(ThinSnapshotSpec::new(
+                    ThinDevId::new_u64(2).expect("is below limit"),
+                )),
+            ThinSnapshotSpec::new(ThinDevId::new_u64(3).expect("is below limit")),
+        ];
+        let mut trees = td.snapshot_tree(&dm, &tp, &specs).unwrap();
+        udev_settle().unwrap();
+
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees[0].dev.name(), &*ss_name);
+        assert_eq!(
+            trees[0].children[0].dev.name().to_string(),
+            format!("{ss_name}-snap2")
+        );
+        assert_eq!(trees[0].children[0].dev.size(), td.size());
+        assert_eq!(
+            trees[1].dev.name().to_string(),
+            format!("{thin_name}-snap3")
+        );
+
+        // Thin id 1 is already in use, so the second snapshot fails.
+        let failing = [
+            ThinSnapshotSpec::new(ThinDevId::new_u64(4).expect("is below limit")),
+            ThinSnapshotSpec::new(ThinDevId::new_u64(1).expect("is below limit")),
+        ];
+        assert_matches!(trees[1].dev.snapshot_tree(&dm, &tp, &failing), Err(_));
+        let snap4 = DmNameBuf::new(format!("{}-snap4", trees[1].dev.name())).unwrap();
+        assert!(!device_exists(&dm, &snap4).unwrap());
+
+        for tree in trees.iter_mut().rev() {
+            tree.destroy(&dm, &tp).unwrap();
+        }
+        td.destroy(&dm, &tp).unwrap();
+        tp.teardown(&dm).unwrap();
+    }
+
     /// Verify no failures when creating a thindev from a pool, mounting a
     /// filesystem on the thin device, and writing to that filesystem.
     /// Verify reasonable usage behavior.
@@ -869,6 +1129,99 @@ mod tests {
         tp.teardown(&dm).unwrap();
     }
 
+    /// Verify that an external snapshot reads the contents of its origin,
+    /// that writes to the snapshot do not reach the origin, and that an
+    /// origin whose size does not match the thin device is rejected.
+    fn test_external_snapshot(paths: &[&Path]) {
+        assert!(paths.len() >= 2);
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        let origin = Device::from(devnode_to_devno(paths[1]).unwrap().unwrap());
+        let origin_size = device_size(origin).unwrap().sectors();
+
+        OpenOptions::new()
+            .write(true)
+            .open(paths[1])
+            .unwrap()
+            .write_all(&[0xa5; 4096])
+            .unwrap();
+
+        assert_matches!(
+            ThinDev::check_external_origin(origin, origin_size + Sectors(1)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let thin_id = ThinDevId::new_u64(0).expect("is below limit");
+        let mut td = ThinDev::new_external_snapshot(
+            &dm,
+            &test_name("name").expect("is valid DM name"),
+            None,
+            &tp,
+            thin_id,
+            origin,
+        )
+        .unwrap();
+        udev_settle().unwrap();
+        assert_eq!(td.size(), origin_size);
+
+        let mut buf = [0u8; 4096];
+        OpenOptions::new()
+            .read(true)
+            .open(td.devnode())
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+
+        let mut file = OpenOptions::new().write(true).open(td.devnode()).unwrap();
+        file.write_all(&[0x5a; 4096]).unwrap();
+        file.sync_all().unwrap();
+
+        OpenOptions::new()
+            .read(true)
+            .open(paths[1])
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+
+        td.destroy(&dm, &tp).unwrap();
+        tp.teardown(&dm).unwrap();
+    }
+
+    /// Verify that a thin device can be grown and shrunk, and that zero
+    /// size is rejected.
+    fn test_set_size(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        let thin_id = ThinDevId::new_u64(0).expect("is below limit");
+        let mut td = ThinDev::new(
+            &dm,
+            &test_name("name").expect("is valid DM name"),
+            None,
+            tp.size(),
+            &tp,
+            thin_id,
+        )
+        .unwrap();
+
+        for size in [2u64 * tp.size(), tp.size() / 2u64] {
+            td.set_size(&dm, size).unwrap();
+            assert_eq!(td.size(), size);
+            assert_eq!(
+                blkdev_size(&OpenOptions::new().read(true).open(td.devnode()).unwrap()).sectors(),
+                size
+            );
+        }
+        assert_matches!(td.set_size(&dm, Sectors(0)), Err(_));
+
+        td.destroy(&dm, &tp).unwrap();
+        tp.teardown(&dm).unwrap();
+    }
+
     #[test]
     fn loop_test_basic() {
         test_with_spec(1, test_basic);
@@ -884,6 +1237,11 @@ mod tests {
         test_with_spec(1, test_zero_size);
     }
 
+    #[test]
+    fn loop_test_set_size() {
+        test_with_spec(1, test_set_size);
+    }
+
     #[test]
     fn loop_test_setup_without_new() {
         test_with_spec(1, test_setup_without_new);
@@ -894,18 +1252,66 @@ mod tests {
         test_with_spec(1, test_snapshot);
     }
 
+    #[test]
+    fn loop_test_snapshot_tree() {
+        test_with_spec(1, test_snapshot_tree);
+    }
+
     #[test]
     fn loop_test_snapshot_usage() {
         test_with_spec(1, test_snapshot_usage);
     }
 
+    #[test]
+    fn loop_test_external_snapshot() {
+        test_with_spec(2, test_external_snapshot);
+    }
+
     #[test]
     fn loop_test_filesystem() {
         test_with_spec(1, test_filesystem);
     }
 
+    /// Verify that a thin device is internally suspended while its pool is
+    /// suspended, and that waiting for it to be resumed fails until the pool
+    /// is resumed.
+    fn test_internal_suspend(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+
+        let thin_id = ThinDevId::new_u64(0).expect("is below limit");
+        let thin_name = test_name("name").expect("is valid DM name");
+        let mut td = ThinDev::new(&dm, &thin_name, None, tp.size(), &tp, thin_id).unwrap();
+        let id = DevId::Name(td.name());
+
+        assert!(!dm.device_info(&id).unwrap().is_internally_suspended());
+
+        tp.suspend(&dm, DmOptions::default()).unwrap();
+        assert!(dm.device_info(&id).unwrap().is_internally_suspended());
+        assert_matches!(
+            dm.wait_until_not_internally_suspended(&id, Duration::from_millis(50)),
+            Err(DmError::Core(Error::InternallySuspended(_, _)))
+        );
+
+        tp.resume(&dm).unwrap();
+        assert_matches!(
+            dm.wait_until_not_internally_suspended(&id, Duration::from_secs(1)),
+            Ok(info) if !info.is_internally_suspended()
+        );
+
+        td.destroy(&dm, &tp).unwrap();
+        tp.teardown(&dm).unwrap();
+    }
+
     #[test]
     fn loop_test_thindev_destroy() {
         test_with_spec(1, test_thindev_destroy);
     }
+
+    #[test]
+    fn loop_test_internal_suspend() {
+        test_with_spec(1, test_internal_suspend);
+    }
 }
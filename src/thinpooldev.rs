@@ -2,27 +2,134 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{collections::hash_set::HashSet, fmt, path::PathBuf, str::FromStr};
+use std::{
+    collections::hash_set::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+#[cfg(devicemapper41supported)]
+use semver::Version;
 
+#[cfg(devicemapper41supported)]
+use crate::core::DmCapabilities;
 use crate::{
-    core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
+    blkdev::check_chunk_size,
+    consts::IEC,
+    core::{errors, DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
     lineardev::{LinearDev, LinearDevTargetParams},
+    profiles::ThinPoolProfile,
     result::{DmError, DmResult, ErrorEnum},
     shared::{
         device_create, device_exists, device_match, get_status, get_status_line_fields,
-        make_unexpected_value_error, parse_device, parse_value, DmDevice, TargetLine, TargetParams,
-        TargetTable, TargetTypeBuf,
+        make_unexpected_value_error, message, parse_device, parse_value, DmDevice, TargetLine,
+        TargetParams, TargetTable, TargetTypeBuf,
     },
-    units::{DataBlocks, MetaBlocks, Sectors},
+    units::{DataBlocks, MetaBlocks, Sectors, MAX_META_DEV_SIZE},
 };
 
-#[cfg(test)]
-use std::path::Path;
-
 #[cfg(test)]
 use crate::core::devnode_to_devno;
 
-const THINPOOL_TARGET_NAME: &str = "thin-pool";
+// Specified in kernel docs
+/// The minimum size of a thin pool data block.
+pub const MIN_DATA_BLOCK_SIZE: Sectors = Sectors(128); // 64 KiB
+/// The maximum size of a thin pool data block.
+pub const MAX_DATA_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
+
+// Values are explicitly stated in the device-mapper kernel documentation.
+/// The smallest recommended size of a thin pool metadata device.
+pub const MIN_RECOMMENDED_METADATA_SIZE: Sectors = Sectors(4 * IEC::Ki); // 2 MiB
+
+/// The metadata required by each block of the pool's data device, as
+/// estimated by lvm2. This covers the space maps and the mappings of the
+/// origin devices.
+const METADATA_BYTES_PER_DATA_BLOCK: u64 = 64;
+/// The additional metadata required by each block of the pool's data device
+/// for each snapshot, should the snapshot diverge entirely from its origin:
+/// a mapping is 16 bytes, and the nodes of the mapping tree may be only half
+/// full.
+const SNAPSHOT_METADATA_BYTES_PER_DATA_BLOCK: u64 = 32;
+
+pub(crate) const THINPOOL_TARGET_NAME: &str = "thin-pool";
+
+/// The dm_thin_pool module parameter which holds the number of seconds for
+/// which a pool queues IO when out of space, 0 meaning indefinitely
+const NO_SPACE_TIMEOUT_PATH: &str = "/sys/module/dm_thin_pool/parameters/no_space_timeout";
+
+/// A feature argument of a thin pool target.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ThinPoolFeature {
+    /// Discards are not processed by the pool at all. The kernel does not
+    /// permit discard support to be enabled on a live pool once it has
+    /// been disabled with this feature.
+    IgnoreDiscard,
+    /// Discards are processed by the pool but are not passed down to the
+    /// data device.
+    NoDiscardPassdown,
+    /// IO is errored, rather than queued, when the pool is out of space.
+    ErrorIfNoSpace,
+    /// Newly allocated data blocks are not zeroed.
+    SkipBlockZeroing,
+}
+
+impl ThinPoolFeature {
+    /// The feature argument as it appears in a table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThinPoolFeature::IgnoreDiscard => "ignore_discard",
+            ThinPoolFeature::NoDiscardPassdown => "no_discard_passdown",
+            ThinPoolFeature::ErrorIfNoSpace => "error_if_no_space",
+            ThinPoolFeature::SkipBlockZeroing => "skip_block_zeroing",
+        }
+    }
+}
+
+impl fmt::Display for ThinPoolFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ThinPoolFeature {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<ThinPoolFeature> {
+        match s {
+            "ignore_discard" => Ok(ThinPoolFeature::IgnoreDiscard),
+            "no_discard_passdown" => Ok(ThinPoolFeature::NoDiscardPassdown),
+            "error_if_no_space" => Ok(ThinPoolFeature::ErrorIfNoSpace),
+            "skip_block_zeroing" => Ok(ThinPoolFeature::SkipBlockZeroing),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown thin pool feature argument \"{s}\""),
+            )),
+        }
+    }
+}
+
+impl serde::Serialize for ThinPoolFeature {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ThinPoolFeature {
+    fn deserialize<D>(deserializer: D) -> Result<ThinPoolFeature, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value
+            .parse::<ThinPoolFeature>()
+            .map_err(serde::de::Error::custom)
+    }
+}
 
 /// Struct representing params for a thin pool target
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -56,6 +163,82 @@ impl ThinPoolTargetParams {
             feature_args: feature_args.into_iter().collect::<HashSet<_>>(),
         }
     }
+
+    /// Whether the given feature argument is present.
+    pub fn has_feature(&self, feature: ThinPoolFeature) -> bool {
+        self.feature_args.contains(feature.as_str())
+    }
+
+    /// The policy of the pool when it is out of space, as set by the
+    /// `error_if_no_space` feature argument.
+    pub fn no_space_policy(&self) -> ThinPoolNoSpacePolicy {
+        if self.has_feature(ThinPoolFeature::ErrorIfNoSpace) {
+            ThinPoolNoSpacePolicy::Error
+        } else {
+            ThinPoolNoSpacePolicy::Queue
+        }
+    }
+
+    /// Set the policy of the pool when it is out of space, leaving all
+    /// other parameters unchanged.
+    pub fn set_no_space_policy(&mut self, policy: ThinPoolNoSpacePolicy) {
+        self.set_feature(
+            ThinPoolFeature::ErrorIfNoSpace,
+            policy == ThinPoolNoSpacePolicy::Error,
+        );
+    }
+
+    /// Add or remove the given feature argument, leaving all other
+    /// parameters unchanged.
+    pub fn set_feature(&mut self, feature: ThinPoolFeature, enabled: bool) {
+        if enabled {
+            self.feature_args.insert(feature.as_str().to_owned());
+        } else {
+            self.feature_args.remove(feature.as_str());
+        }
+    }
+}
+
+/// The version of the thin-pool target which introduced a feature argument,
+/// if it has not always been accepted.
+#[cfg(devicemapper41supported)]
+fn feature_min_version(feature: &str) -> Option<Version> {
+    match feature {
+        "ignore_discard" | "no_discard_passdown" => Some(Version::new(1, 1, 0)),
+        "error_if_no_space" => Some(Version::new(1, 10, 0)),
+        _ => None,
+    }
+}
+
+#[cfg(devicemapper41supported)]
+impl ThinPoolTargetParams {
+    /// Check that the running kernel's thin-pool target accepts every
+    /// feature argument, so that a table with these params does not fail
+    /// to load. If the thin-pool target is not yet loaded, its version is
+    /// unknown and no feature argument is rejected.
+    pub fn check_features(&self, capabilities: &DmCapabilities) -> DmResult<()> {
+        capabilities.check_features(
+            THINPOOL_TARGET_NAME,
+            &self.feature_args,
+            feature_min_version,
+        )
+    }
+
+    /// Remove every feature argument which the running kernel's thin-pool
+    /// target does not accept, logging a warning for each.
+    pub fn remove_unsupported_features(&mut self, capabilities: &DmCapabilities) {
+        for (feature, min) in capabilities.unsupported_features(
+            THINPOOL_TARGET_NAME,
+            &self.feature_args,
+            feature_min_version,
+        ) {
+            warn!(
+                "Omitting feature argument {}, which requires thin-pool target version {} or later",
+                feature, min
+            );
+            self.feature_args.remove(&feature);
+        }
+    }
 }
 
 impl fmt::Display for ThinPoolTargetParams {
@@ -287,6 +470,102 @@ pub enum ThinPoolNoSpacePolicy {
     Queue,
 }
 
+impl ThinPoolNoSpacePolicy {
+    /// The policy as it appears in the pool's status.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThinPoolNoSpacePolicy::Error => "error_if_no_space",
+            ThinPoolNoSpacePolicy::Queue => "queue_if_no_space",
+        }
+    }
+}
+
+impl fmt::Display for ThinPoolNoSpacePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ThinPoolNoSpacePolicy {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<ThinPoolNoSpacePolicy> {
+        match s {
+            "error_if_no_space" => Ok(ThinPoolNoSpacePolicy::Error),
+            "queue_if_no_space" => Ok(ThinPoolNoSpacePolicy::Queue),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown thin pool no space policy \"{s}\""),
+            )),
+        }
+    }
+}
+
+/// Read the timeout after which thin pools which queue IO when out of space
+/// start to error it instead, from the given module parameter.
+fn read_no_space_timeout(path: &Path) -> DmResult<Option<Duration>> {
+    let value = fs::read_to_string(path).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to read {}: {}",
+            path.display(),
+            err
+        )))
+    })?;
+    let secs = value.trim().parse::<u64>().map_err(|_| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "unexpected value \"{}\" in {}",
+            value.trim(),
+            path.display()
+        )))
+    })?;
+    Ok(if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    })
+}
+
+/// Write the timeout after which thin pools which queue IO when out of
+/// space start to error it instead, to the given module parameter.
+fn write_no_space_timeout(path: &Path, timeout: Option<Duration>) -> DmResult<()> {
+    let secs = match timeout {
+        None => 0,
+        Some(timeout) if timeout.as_secs() == 0 => {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                "a thin pool no space timeout must be at least one second".to_string(),
+            ));
+        }
+        Some(timeout) => timeout.as_secs(),
+    };
+    fs::write(path, secs.to_string()).map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to write {}: {}",
+            path.display(),
+            err
+        )))
+    })
+}
+
+/// The time for which a thin pool with the `Queue` no space policy queues
+/// IO once it is out of space, after which it errors the IO instead. None
+/// means that the IO is queued indefinitely. The timeout is a parameter of
+/// the dm_thin_pool module, so it is shared by all pools, and it can only
+/// be read once the module is loaded. It can only be found in sysfs, so it
+/// is read there even by a program whose DM contexts are in ioctl-only
+/// mode.
+pub fn thin_pool_no_space_timeout() -> DmResult<Option<Duration>> {
+    read_no_space_timeout(Path::new(NO_SPACE_TIMEOUT_PATH))
+}
+
+/// Set the time for which thin pools with the `Queue` no space policy queue
+/// IO once they are out of space, in whole seconds. None means that the IO
+/// is queued indefinitely. The timeout applies to all pools. It is written
+/// to sysfs.
+pub fn set_thin_pool_no_space_timeout(timeout: Option<Duration>) -> DmResult<()> {
+    write_no_space_timeout(Path::new(NO_SPACE_TIMEOUT_PATH), timeout)
+}
+
 /// Status of a working thin pool, i.e, one that does not have status Fail
 #[derive(Debug, Clone)]
 pub struct ThinPoolWorkingStatus {
@@ -395,13 +674,9 @@ impl FromStr for ThinPoolStatus {
             }
         };
 
-        let no_space_policy = match status_vals[6] {
-            "error_if_no_space" => ThinPoolNoSpacePolicy::Error,
-            "queue_if_no_space" => ThinPoolNoSpacePolicy::Queue,
-            val => {
-                return Err(make_unexpected_value_error(7, val, "no space policy"));
-            }
-        };
+        let no_space_policy = status_vals[6]
+            .parse::<ThinPoolNoSpacePolicy>()
+            .map_err(|_| make_unexpected_value_error(7, status_vals[6], "no space policy"))?;
 
         let needs_check = match status_vals[7] {
             "-" => false,
@@ -438,7 +713,14 @@ impl ThinPoolDev {
     /// Construct a new `ThinPoolDev` with the given data and meta devs.
     /// Returns an error if the device is already known to the kernel.
     /// Returns an error if `data_block_size` is not within required range.
+    /// Returns an error if the metadata device is too small; see
+    /// `ThinPoolDev::check_meta_size`.
     /// Precondition: the metadata device does not contain any pool metadata.
+    /// `wipe_metadata_superblock` may be used to ensure this.
+    /// The backing devices of the metadata and data devices are not probed
+    /// for signatures of existing data here; to refuse to build a new pool
+    /// over a filesystem or other volume, set up those devices with
+    /// `LinearDev::setup_checked`.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         dm: &DM,
@@ -454,6 +736,7 @@ impl ThinPoolDev {
             let err_msg = format!("thinpooldev {name} already exists");
             return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
         }
+        ThinPoolDev::check_meta_size(&meta, &data, data_block_size)?;
 
         let table =
             ThinPoolDev::gen_table(&meta, &data, data_block_size, low_water_mark, feature_args);
@@ -467,6 +750,123 @@ impl ThinPoolDev {
         })
     }
 
+    /// Construct a new `ThinPoolDev`, as `ThinPoolDev::new` does, with the
+    /// data block size, low water mark, and feature args of `profile`.
+    pub fn new_from_profile(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        meta: LinearDev,
+        data: LinearDev,
+        profile: &ThinPoolProfile,
+    ) -> DmResult<ThinPoolDev> {
+        ThinPoolDev::new(
+            dm,
+            name,
+            uuid,
+            meta,
+            data,
+            profile.data_block_size,
+            profile.low_water_mark,
+            profile
+                .features
+                .iter()
+                .map(|feature| feature.as_str().to_owned())
+                .collect(),
+        )
+    }
+
+    /// Verify that `data_block_size` is a valid data block size for a thin
+    /// pool with the given data device: it must be within the range
+    /// permitted by the kernel, a multiple of the minimum data block size,
+    /// and a multiple of the data device's logical block size.
+    pub fn check_data_block_size(
+        dm: &DM,
+        data: &LinearDev,
+        data_block_size: Sectors,
+    ) -> DmResult<()> {
+        check_chunk_size(
+            dm,
+            data.device(),
+            data_block_size,
+            MIN_DATA_BLOCK_SIZE,
+            MAX_DATA_BLOCK_SIZE,
+            MIN_DATA_BLOCK_SIZE,
+        )
+    }
+
+    /// The recommended size of the metadata device of a thin pool with a
+    /// data device of `data_size` and the given data block size, which is
+    /// expected to hold `snapshots` snapshots.
+    ///
+    /// As lvm2 does, 64 bytes of metadata are allowed for each data block.
+    /// A further 32 bytes are allowed for each data block for each snapshot,
+    /// enough for its mappings should it diverge entirely from its origin.
+    /// The result is a whole number of metadata blocks, no smaller than
+    /// `MIN_RECOMMENDED_METADATA_SIZE`, and no larger than
+    /// `MAX_META_DEV_SIZE`, as the kernel uses no more.
+    pub fn recommended_meta_size(
+        data_size: Sectors,
+        data_block_size: Sectors,
+        snapshots: u64,
+    ) -> Sectors {
+        // A smaller data block size is not permitted by the kernel, and
+        // would only overstate the metadata required.
+        let data_blocks = *data_size.datablocks(data_block_size.max(MIN_DATA_BLOCK_SIZE));
+        let bytes =
+            u128::from(data_blocks)
+                * u128::from(METADATA_BYTES_PER_DATA_BLOCK.saturating_add(
+                    SNAPSHOT_METADATA_BYTES_PER_DATA_BLOCK.saturating_mul(snapshots),
+                ));
+        let meta_block_bytes = *MetaBlocks(1).bytes();
+        let meta_blocks = MetaBlocks(
+            u64::try_from((bytes + meta_block_bytes - 1) / meta_block_bytes).unwrap_or(u64::MAX),
+        );
+        meta_blocks
+            .min(MAX_META_DEV_SIZE)
+            .sectors()
+            .max(MIN_RECOMMENDED_METADATA_SIZE)
+    }
+
+    /// Verify that `meta` is large enough to be the metadata device of a
+    /// new thin pool over `data` with the given data block size: it must be
+    /// no smaller than `MIN_RECOMMENDED_METADATA_SIZE`. A metadata device
+    /// smaller than the size recommended for a pool without snapshots, or
+    /// larger than the kernel uses, is permitted, but a warning is logged.
+    pub fn check_meta_size(
+        meta: &LinearDev,
+        data: &LinearDev,
+        data_block_size: Sectors,
+    ) -> DmResult<()> {
+        let meta_size = meta.size();
+        if meta_size < MIN_RECOMMENDED_METADATA_SIZE {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "metadata device size {meta_size} is less than the minimum, {MIN_RECOMMENDED_METADATA_SIZE}"
+                ),
+            ));
+        }
+        let recommended = ThinPoolDev::recommended_meta_size(data.size(), data_block_size, 0);
+        if meta_size < recommended {
+            warn!(
+                "Metadata device size {} is less than the {} recommended for a data device of size {} with data block size {}",
+                meta_size,
+                recommended,
+                data.size(),
+                data_block_size
+            );
+        }
+        if meta_size > MAX_META_DEV_SIZE.sectors() {
+            warn!(
+                "Metadata device size {} is greater than {}, the most the kernel will use",
+                meta_size,
+                MAX_META_DEV_SIZE.sectors()
+            );
+        }
+        Ok(())
+    }
+
     /// Obtain the meta device that backs this thin pool device.
     pub fn meta_dev(&self) -> &LinearDev {
         &self.meta_dev
@@ -615,30 +1015,52 @@ impl ThinPoolDev {
         Ok(())
     }
 
-    fn set_feature_arg(&mut self, feature_arg: &str, dm: &DM) -> DmResult<()> {
-        let mut table = self.table().clone();
-        if !table.table.params.feature_args.contains(feature_arg) {
-            table
-                .table
-                .params
-                .feature_args
-                .insert(feature_arg.to_string());
-
-            self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
-            self.table_load(dm, &table, DmOptions::default())?;
-            self.table = table;
+    /// Grow the pool's data device by appending segments, each given as a
+    /// length and the params of its target, to its table. The pool is
+    /// resumed with the new size; a thin pool may only be grown while it
+    /// is active.
+    pub fn extend_data(
+        &mut self,
+        dm: &DM,
+        segments: Vec<(Sectors, LinearDevTargetParams)>,
+    ) -> DmResult<()> {
+        let mut table = self.data_dev.table().clone();
+        table.append(segments);
+        self.set_data_table(dm, table.table)?;
+        self.resume(dm)
+    }
 
-            self.resume(dm)?;
-        }
+    /// Grow the pool's metadata device by appending segments, each given as
+    /// a length and the params of its target, to its table. The pool is
+    /// resumed with the new size; a thin pool may only be grown while it
+    /// is active.
+    pub fn extend_meta(
+        &mut self,
+        dm: &DM,
+        segments: Vec<(Sectors, LinearDevTargetParams)>,
+    ) -> DmResult<()> {
+        let mut table = self.meta_dev.table().clone();
+        table.append(segments);
+        self.set_meta_table(dm, table.table)?;
+        self.resume(dm)
+    }
 
-        Ok(())
+    /// Whether the pool's table has the given feature argument.
+    pub fn has_feature(&self, feature: ThinPoolFeature) -> bool {
+        self.table.table.params.has_feature(feature)
     }
 
-    fn unset_feature_arg(&mut self, feature_arg: &str, dm: &DM) -> DmResult<()> {
+    /// Add or remove each of the given feature arguments, and reload the
+    /// pool's table, with all other parameters unchanged, if any feature
+    /// argument has changed. The pool is suspended while the table is
+    /// reloaded.
+    pub fn set_features(&mut self, dm: &DM, features: &[(ThinPoolFeature, bool)]) -> DmResult<()> {
         let mut table = self.table().clone();
-        if table.table.params.feature_args.contains(feature_arg) {
-            table.table.params.feature_args.remove(feature_arg);
+        for (feature, enabled) in features {
+            table.table.params.set_feature(*feature, *enabled);
+        }
 
+        if table != self.table {
             self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
             self.table_load(dm, &table, DmOptions::default())?;
             self.table = table;
@@ -649,6 +1071,26 @@ impl ThinPoolDev {
         Ok(())
     }
 
+    /// The policy of the pool when it is out of space, according to its
+    /// table. The policy in effect is also reported by the pool's status.
+    pub fn no_space_policy(&self) -> ThinPoolNoSpacePolicy {
+        self.table.table.params.no_space_policy()
+    }
+
+    /// Set the policy of the pool when it is out of space, reloading the
+    /// pool's table, with all other parameters unchanged, if the policy
+    /// has changed. How long IO is queued under the `Queue` policy is set
+    /// with `set_thin_pool_no_space_timeout`.
+    pub fn set_no_space_policy(&mut self, dm: &DM, policy: ThinPoolNoSpacePolicy) -> DmResult<()> {
+        self.set_features(
+            dm,
+            &[(
+                ThinPoolFeature::ErrorIfNoSpace,
+                policy == ThinPoolNoSpacePolicy::Error,
+            )],
+        )
+    }
+
     /// Default behavior for devicemapper thin pools is to queue requests if
     /// the thin pool is out of space to allow time for the thin pool to extend.
     /// This behavior can be changed by adding the feature argument
@@ -657,7 +1099,7 @@ impl ThinPoolDev {
     /// This method will add `error_if_no_space` from the devicemapper table
     /// if it is not present.
     pub fn error_if_no_space(&mut self, dm: &DM) -> DmResult<()> {
-        self.set_feature_arg("error_if_no_space", dm)
+        self.set_no_space_policy(dm, ThinPoolNoSpacePolicy::Error)
     }
 
     /// Default behavior for devicemapper thin pools is to queue requests if
@@ -668,7 +1110,7 @@ impl ThinPoolDev {
     /// This method will remove `error_if_no_space` from the devicemapper table
     /// if it is present.
     pub fn queue_if_no_space(&mut self, dm: &DM) -> DmResult<()> {
-        self.unset_feature_arg("error_if_no_space", dm)
+        self.set_no_space_policy(dm, ThinPoolNoSpacePolicy::Queue)
     }
 
     /// Default behavior for devicemapper thin pools is to zero newly allocated
@@ -678,7 +1120,7 @@ impl ThinPoolDev {
     /// This method will add `skip_block_zeroing` from the devicemapper table
     /// if it is not present.
     pub fn skip_block_zeroing(&mut self, dm: &DM) -> DmResult<()> {
-        self.set_feature_arg("skip_block_zeroing", dm)
+        self.set_features(dm, &[(ThinPoolFeature::SkipBlockZeroing, true)])
     }
 
     /// Default behavior for devicemapper thin pools is to zero newly allocated
@@ -688,7 +1130,7 @@ impl ThinPoolDev {
     /// This method will remove `skip_block_zeroing` from the devicemapper table
     /// if it is present.
     pub fn require_block_zeroing(&mut self, dm: &DM) -> DmResult<()> {
-        self.unset_feature_arg("skip_block_zeroing", dm)
+        self.set_features(dm, &[(ThinPoolFeature::SkipBlockZeroing, false)])
     }
 
     /// Default behavior for devicemapper thin pools is to pass down discards.
@@ -698,7 +1140,7 @@ impl ThinPoolDev {
     /// This method will add `no_discard_passdown` to the devicemapper table
     /// if it is not present.
     pub fn no_discard_passdown(&mut self, dm: &DM) -> DmResult<()> {
-        self.set_feature_arg("no_discard_passdown", dm)
+        self.set_features(dm, &[(ThinPoolFeature::NoDiscardPassdown, true)])
     }
 
     /// Default behavior for devicemapper thin pools is to pass down discards.
@@ -708,7 +1150,98 @@ impl ThinPoolDev {
     /// This method will remove `no_discard_passdown` from the devicemapper
     /// table if it is present.
     pub fn discard_passdown(&mut self, dm: &DM) -> DmResult<()> {
-        self.unset_feature_arg("no_discard_passdown", dm)
+        self.set_features(dm, &[(ThinPoolFeature::NoDiscardPassdown, false)])
+    }
+
+    /// Reserve a snapshot of the pool's metadata, so that tools such as
+    /// thin_dump and thin_delta can read a consistent copy of the metadata
+    /// of the live pool. The snapshot is released when the returned value
+    /// is dropped.
+    pub fn reserve_metadata_snap<'a>(&'a self, dm: &'a DM) -> DmResult<ThinPoolMetadataSnap<'a>> {
+        message(dm, self, "reserve_metadata_snap")?;
+        // Hold the snapshot before examining the status, so that it is
+        // released if the held root can not be found.
+        let mut snap = ThinPoolMetadataSnap {
+            dm,
+            pool: self,
+            root: MetaBlocks(0),
+            released: false,
+        };
+        match self.status(dm, DmOptions::default())? {
+            ThinPoolStatus::Working(ref status) => match status.held_metadata_root {
+                Some(root) => {
+                    snap.root = root;
+                    Ok(snap)
+                }
+                None => Err(DmError::Dm(
+                    ErrorEnum::Error,
+                    format!(
+                        "thin pool {} reports no held metadata root after reserving a metadata snapshot",
+                        self.name()
+                    ),
+                )),
+            },
+            _ => Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!(
+                    "thin pool {} has failed, its held metadata root is unavailable",
+                    self.name()
+                ),
+            )),
+        }
+    }
+}
+
+/// A snapshot of a thin pool's metadata, reserved with
+/// `ThinPoolDev::reserve_metadata_snap`. The snapshot is released when
+/// this value is dropped.
+///
+/// Tools may read the snapshot from the pool's metadata device, e.g.,
+/// `thin_dump --metadata-snap=<root> <metadata device>`.
+#[derive(Debug)]
+pub struct ThinPoolMetadataSnap<'a> {
+    dm: &'a DM,
+    pool: &'a ThinPoolDev,
+    root: MetaBlocks,
+    released: bool,
+}
+
+impl<'a> ThinPoolMetadataSnap<'a> {
+    /// The location of the root of the metadata snapshot on the metadata
+    /// device.
+    pub fn root(&self) -> MetaBlocks {
+        self.root
+    }
+
+    /// The path of the metadata device which holds the snapshot.
+    pub fn meta_devnode(&self) -> PathBuf {
+        self.pool.meta_dev().devnode()
+    }
+
+    fn do_release(&mut self) -> DmResult<()> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+        message(self.dm, self.pool, "release_metadata_snap")
+    }
+
+    /// Release the snapshot, reporting any error, rather than waiting for
+    /// it to be released on drop.
+    pub fn release(mut self) -> DmResult<()> {
+        self.do_release()
+    }
+}
+
+impl<'a> Drop for ThinPoolMetadataSnap<'a> {
+    fn drop(&mut self) {
+        if let Err(err) = self.do_release() {
+            warn!(
+                "Failed to release metadata snapshot of thin pool {}: {}",
+                self.pool.name(),
+                err
+            );
+        }
     }
 }
 
@@ -717,26 +1250,10 @@ use std::fs::OpenOptions;
 
 #[cfg(test)]
 use crate::{
-    consts::IEC,
     lineardev::LinearTargetParams,
     testing::{blkdev_size, test_name},
 };
 
-/// Values are explicitly stated in the device-mapper kernel documentation.
-#[cfg(test)]
-const MIN_DATA_BLOCK_SIZE: Sectors = Sectors(128); // 64 KiB
-#[cfg(test)]
-#[allow(dead_code)]
-const MAX_DATA_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
-#[cfg(test)]
-const MIN_RECOMMENDED_METADATA_SIZE: Sectors = Sectors(4 * IEC::Ki); // 2 MiB
-#[cfg(test)]
-#[allow(dead_code)]
-// Note that this value is stated in the kernel docs to be 16 GiB, but the
-// devicemapper source gives a different value for THIN_METADATA_MAX_SECTORS,
-// which is the actual maximum size.
-const MAX_METADATA_SIZE: MetaBlocks = MetaBlocks(255 * ((1 << 14) - 64));
-
 #[cfg(test)]
 /// Generate a minimal thinpool dev. Use all the space available not consumed
 /// by the metadata device for the data device.
@@ -798,6 +1315,34 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    /// Verify that the recommended metadata size follows the data block
+    /// count and the number of snapshots, within the permitted range.
+    fn test_recommended_meta_size() {
+        // 1 TiB of 64 KiB blocks needs 1 GiB of metadata
+        let data_size = Sectors(2 * IEC::Gi);
+        assert_eq!(
+            ThinPoolDev::recommended_meta_size(data_size, MIN_DATA_BLOCK_SIZE, 0),
+            Sectors(2 * IEC::Mi)
+        );
+        assert_eq!(
+            ThinPoolDev::recommended_meta_size(data_size, MIN_DATA_BLOCK_SIZE, 2),
+            Sectors(4 * IEC::Mi)
+        );
+        assert_eq!(
+            ThinPoolDev::recommended_meta_size(data_size, Sectors(1024), 0),
+            Sectors(256 * IEC::Ki)
+        );
+        assert_eq!(
+            ThinPoolDev::recommended_meta_size(Sectors(2 * IEC::Ki), MIN_DATA_BLOCK_SIZE, 0),
+            MIN_RECOMMENDED_METADATA_SIZE
+        );
+        assert_eq!(
+            ThinPoolDev::recommended_meta_size(data_size, MIN_DATA_BLOCK_SIZE, u64::MAX),
+            MAX_META_DEV_SIZE.sectors()
+        );
+    }
+
     /// Verify success when constructing a new ThinPoolDev with minimum values
     /// for data block size and metadata device. Check that the status of the
     /// device is as expected.
@@ -866,6 +1411,15 @@ mod tests {
         )];
         let data = LinearDev::setup(&dm, &data_name, None, data_table).unwrap();
 
+        assert_matches!(
+            ThinPoolDev::check_data_block_size(&dm, &data, MIN_DATA_BLOCK_SIZE / 2u64),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            ThinPoolDev::check_data_block_size(&dm, &data, MIN_DATA_BLOCK_SIZE),
+            Ok(_)
+        );
+
         assert_matches!(
             ThinPoolDev::new(
                 &dm,
@@ -992,6 +1546,40 @@ mod tests {
         test_with_spec(1, test_suspend);
     }
 
+    /// Verify that a thin-pool table is not loaded onto a device which is
+    /// read-only.
+    fn test_load_read_only(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        let id = DevId::Name(tp.name());
+        dm.table_load(
+            &id,
+            &[(0, *tp.size(), "error".to_string(), String::new())],
+            DmOptions::default().set_flags(DmFlags::DM_READONLY),
+        )
+        .unwrap();
+        dm.device_suspend(&id, DmOptions::default()).unwrap();
+
+        assert_matches!(
+            tp.table_load(&dm, tp.table(), DmOptions::default()),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert!(!dm
+            .device_info(&id)
+            .unwrap()
+            .flags()
+            .contains(DmFlags::DM_INACTIVE_PRESENT));
+
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_load_read_only() {
+        test_with_spec(1, test_load_read_only);
+    }
+
     fn test_status_noflush(paths: &[&Path]) {
         assert!(!paths.is_empty());
 
@@ -1007,6 +1595,180 @@ mod tests {
         test_with_spec(1, test_status_noflush);
     }
 
+    /// Verify that reserving a metadata snapshot sets the held metadata root
+    /// and that dropping it releases the snapshot.
+    fn test_metadata_snap(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+
+        let held_root = |tp: &ThinPoolDev| match tp.status(&dm, DmOptions::default()).unwrap() {
+            ThinPoolStatus::Working(ref status) => status.held_metadata_root,
+            status => panic!("unexpected thinpool status: {status:?}"),
+        };
+
+        let snap = tp.reserve_metadata_snap(&dm).unwrap();
+        assert_eq!(held_root(&tp), Some(snap.root()));
+        assert_eq!(snap.meta_devnode(), tp.meta_dev().devnode());
+        assert_matches!(tp.reserve_metadata_snap(&dm), Err(_));
+        drop(snap);
+        assert_eq!(held_root(&tp), None);
+
+        tp.reserve_metadata_snap(&dm).unwrap().release().unwrap();
+        assert_eq!(held_root(&tp), None);
+
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_metadata_snap() {
+        test_with_spec(1, test_metadata_snap);
+    }
+
+    /// Verify that feature arguments changed on a live pool are reflected
+    /// in its status and that the rest of the table is preserved.
+    fn test_set_features(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        let table = tp.table().clone();
+
+        let status = |tp: &ThinPoolDev| match tp.status(&dm, DmOptions::default()).unwrap() {
+            ThinPoolStatus::Working(status) => status,
+            status => panic!("unexpected thinpool status: {status:?}"),
+        };
+        assert!(!status(&tp).discard_passdown);
+
+        tp.set_features(
+            &dm,
+            &[
+                (ThinPoolFeature::NoDiscardPassdown, false),
+                (ThinPoolFeature::ErrorIfNoSpace, true),
+            ],
+        )
+        .unwrap();
+        assert!(status(&tp).discard_passdown);
+        assert_eq!(status(&tp).no_space_policy, ThinPoolNoSpacePolicy::Error);
+        assert!(tp.has_feature(ThinPoolFeature::SkipBlockZeroing));
+        assert_eq!(
+            ThinPoolDev::read_kernel_table(&dm, &DevId::Name(tp.name()))
+                .unwrap()
+                .table,
+            tp.table().table
+        );
+
+        tp.set_features(
+            &dm,
+            &[
+                (ThinPoolFeature::NoDiscardPassdown, true),
+                (ThinPoolFeature::ErrorIfNoSpace, false),
+            ],
+        )
+        .unwrap();
+        assert_eq!(tp.table(), &table);
+
+        tp.set_no_space_policy(&dm, ThinPoolNoSpacePolicy::Error)
+            .unwrap();
+        assert_eq!(tp.no_space_policy(), ThinPoolNoSpacePolicy::Error);
+        assert_eq!(status(&tp).no_space_policy, ThinPoolNoSpacePolicy::Error);
+        tp.set_no_space_policy(&dm, ThinPoolNoSpacePolicy::Queue)
+            .unwrap();
+        assert_eq!(status(&tp).no_space_policy, ThinPoolNoSpacePolicy::Queue);
+        assert_eq!(tp.table(), &table);
+
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_set_features() {
+        test_with_spec(1, test_set_features);
+    }
+
+    #[test]
+    /// Verify that features are parsed, printed, and set without
+    /// disturbing other feature arguments.
+    fn test_thinpool_features() {
+        for feature in [
+            ThinPoolFeature::IgnoreDiscard,
+            ThinPoolFeature::NoDiscardPassdown,
+            ThinPoolFeature::ErrorIfNoSpace,
+            ThinPoolFeature::SkipBlockZeroing,
+        ] {
+            assert_eq!(
+                feature.to_string().parse::<ThinPoolFeature>().unwrap(),
+                feature
+            );
+        }
+        assert_matches!("read_only".parse::<ThinPoolFeature>(), Err(_));
+
+        let mut params = "thin-pool 42:42 42:43 16 2 2 skip_block_zeroing read_only"
+            .parse::<ThinPoolTargetParams>()
+            .unwrap();
+        assert!(params.has_feature(ThinPoolFeature::SkipBlockZeroing));
+        params.set_feature(ThinPoolFeature::ErrorIfNoSpace, true);
+        params.set_feature(ThinPoolFeature::SkipBlockZeroing, false);
+        assert_eq!(
+            params.feature_args,
+            ["error_if_no_space", "read_only"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    #[test]
+    /// Verify that the no space policy is parsed, printed, and set as the
+    /// error_if_no_space feature argument.
+    fn test_no_space_policy() {
+        for policy in [ThinPoolNoSpacePolicy::Error, ThinPoolNoSpacePolicy::Queue] {
+            assert_eq!(
+                policy.to_string().parse::<ThinPoolNoSpacePolicy>().unwrap(),
+                policy
+            );
+        }
+        assert_matches!("error".parse::<ThinPoolNoSpacePolicy>(), Err(_));
+
+        let mut params = "thin-pool 42:42 42:43 16 2 1 skip_block_zeroing"
+            .parse::<ThinPoolTargetParams>()
+            .unwrap();
+        assert_eq!(params.no_space_policy(), ThinPoolNoSpacePolicy::Queue);
+        params.set_no_space_policy(ThinPoolNoSpacePolicy::Error);
+        assert_eq!(params.no_space_policy(), ThinPoolNoSpacePolicy::Error);
+        assert!(params.has_feature(ThinPoolFeature::SkipBlockZeroing));
+        params.set_no_space_policy(ThinPoolNoSpacePolicy::Queue);
+        assert!(!params.has_feature(ThinPoolFeature::ErrorIfNoSpace));
+    }
+
+    #[test]
+    /// Verify that the no space timeout is read and written in seconds,
+    /// with 0 meaning that IO is queued indefinitely.
+    fn test_no_space_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("no_space_timeout");
+
+        std::fs::write(&path, "60\n").unwrap();
+        assert_eq!(
+            read_no_space_timeout(&path).unwrap(),
+            Some(Duration::from_secs(60))
+        );
+
+        write_no_space_timeout(&path, None).unwrap();
+        assert_eq!(read_no_space_timeout(&path).unwrap(), None);
+
+        write_no_space_timeout(&path, Some(Duration::from_millis(2500))).unwrap();
+        assert_eq!(
+            read_no_space_timeout(&path).unwrap(),
+            Some(Duration::from_secs(2))
+        );
+
+        assert_matches!(
+            write_no_space_timeout(&path, Some(Duration::from_millis(500))),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
     #[test]
     fn test_thinpool_target_params_zero() {
         let result = "thin-pool 42:42 42:43 16 2 0"
@@ -0,0 +1,272 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    core::{DevId, DmOptions, DM},
+    lineardev::LinearDevTargetParams,
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{DmDevice, TargetLine},
+    thinpooldev::{ThinPoolDev, ThinPoolStatus, ThinPoolUsage},
+    units::Sectors,
+};
+
+/// A resource of a thin pool which may run short and need to be extended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThinPoolResource {
+    /// The pool's data device
+    Data,
+    /// The pool's metadata device
+    Metadata,
+}
+
+/// A request to the extend callback of a `ThinPoolMonitor` to provide a
+/// larger table for one of the pool's devices.
+#[derive(Clone, Debug)]
+pub struct ThinPoolExtendRequest {
+    /// The resource which has crossed its threshold
+    pub resource: ThinPoolResource,
+    /// The usage of the pool which triggered the request
+    pub usage: ThinPoolUsage,
+    /// The current table of the device which holds the resource
+    pub table: Vec<TargetLine<LinearDevTargetParams>>,
+}
+
+/// Whether `used` out of `total` is at least `percent` percent.
+pub(crate) fn threshold_crossed(used: u64, total: u64, percent: u8) -> bool {
+    u128::from(used) * 100 >= u128::from(total) * u128::from(percent)
+}
+
+/// The total length of a table.
+fn table_length(table: &[TargetLine<LinearDevTargetParams>]) -> Sectors {
+    table.iter().map(|line| line.length).sum()
+}
+
+/// A monitor which examines the usage of a thin pool and, when the usage
+/// of its data or metadata device crosses a threshold, or the free space
+/// on its data device falls to the pool's low water mark, asks a
+/// user-supplied callback for a larger table for that device and reloads
+/// the pool with it. This is the mechanism of lvm2's thin pool autoextend.
+///
+/// The monitor does not run by itself; `check` should be called
+/// periodically, or whenever the pool reports an event.
+pub struct ThinPoolMonitor<F>
+where
+    F: FnMut(&ThinPoolExtendRequest) -> DmResult<Option<Vec<TargetLine<LinearDevTargetParams>>>>,
+{
+    data_percent: Option<u8>,
+    meta_percent: Option<u8>,
+    extend: F,
+    last_event_nr: Option<u32>,
+}
+
+impl<F> ThinPoolMonitor<F>
+where
+    F: FnMut(&ThinPoolExtendRequest) -> DmResult<Option<Vec<TargetLine<LinearDevTargetParams>>>>,
+{
+    /// Make a new monitor, which requests an extension of the data device
+    /// when its usage reaches `data_percent` percent, if specified, and of
+    /// the metadata device when its usage reaches `meta_percent` percent,
+    /// if specified. The data device is also extended whenever its free
+    /// space falls to the pool's low water mark.
+    ///
+    /// `extend` is called with each request and returns the new table for
+    /// the device, which must be longer than its current table and should
+    /// begin with it, or None to decline the request.
+    pub fn new(
+        data_percent: Option<u8>,
+        meta_percent: Option<u8>,
+        extend: F,
+    ) -> DmResult<ThinPoolMonitor<F>> {
+        for percent in [data_percent, meta_percent].iter().flatten() {
+            if *percent > 100 {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!("threshold {percent}% is greater than 100%"),
+                ));
+            }
+        }
+        Ok(ThinPoolMonitor {
+            data_percent,
+            meta_percent,
+            extend,
+            last_event_nr: None,
+        })
+    }
+
+    /// Whether the pool has reported an event since the last check. The
+    /// kernel reports an event when the pool's free data space falls to its
+    /// low water mark, among other occasions.
+    pub fn event_pending(&self, dm: &DM, pool: &ThinPoolDev) -> DmResult<bool> {
+        let event_nr = dm.device_info(&DevId::Name(pool.name()))?.event_nr();
+        Ok(self.last_event_nr != Some(event_nr))
+    }
+
+    /// Examine the pool's usage and extend whichever of its devices need
+    /// it. Returns the resources which were extended.
+    pub fn check(&mut self, dm: &DM, pool: &mut ThinPoolDev) -> DmResult<Vec<ThinPoolResource>> {
+        self.last_event_nr = Some(dm.device_info(&DevId::Name(pool.name()))?.event_nr());
+
+        let status = match pool.status(dm, DmOptions::default())? {
+            ThinPoolStatus::Working(status) => status,
+            ThinPoolStatus::Error | ThinPoolStatus::Fail => {
+                return Err(DmError::Dm(
+                    ErrorEnum::Error,
+                    format!(
+                        "thin pool {} has failed and can not be extended",
+                        pool.name()
+                    ),
+                ))
+            }
+        };
+        let usage = &status.usage;
+
+        let low_water_mark = pool.table().table.params.low_water_mark;
+        let data_needed = usage.total_data.saturating_sub(*usage.used_data) <= *low_water_mark
+            || self.data_percent.map_or(false, |percent| {
+                threshold_crossed(*usage.used_data, *usage.total_data, percent)
+            });
+        let meta_needed = self.meta_percent.map_or(false, |percent| {
+            threshold_crossed(*usage.used_meta, *usage.total_meta, percent)
+        });
+
+        let mut extended = Vec::new();
+        if data_needed && self.extend_resource(dm, pool, ThinPoolResource::Data, usage)? {
+            extended.push(ThinPoolResource::Data);
+        }
+        if meta_needed && self.extend_resource(dm, pool, ThinPoolResource::Metadata, usage)? {
+            extended.push(ThinPoolResource::Metadata);
+        }
+        Ok(extended)
+    }
+
+    /// Ask the callback for a new table for the device holding `resource`
+    /// and load it. Returns false if the callback declined.
+    fn extend_resource(
+        &mut self,
+        dm: &DM,
+        pool: &mut ThinPoolDev,
+        resource: ThinPoolResource,
+        usage: &ThinPoolUsage,
+    ) -> DmResult<bool> {
+        let table = match resource {
+            ThinPoolResource::Data => pool.data_dev().table().table.clone(),
+            ThinPoolResource::Metadata => pool.meta_dev().table().table.clone(),
+        };
+        let current = table_length(&table);
+        let request = ThinPoolExtendRequest {
+            resource,
+            usage: usage.clone(),
+            table,
+        };
+
+        let new_table = match (self.extend)(&request)? {
+            Some(new_table) => new_table,
+            None => {
+                debug!(
+                    "Extension of {:?} of thin pool {} declined",
+                    resource,
+                    pool.name()
+                );
+                return Ok(false);
+            }
+        };
+        let new = table_length(&new_table);
+        if new <= current {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "new table of length {new} does not extend current table of length {current}"
+                ),
+            ));
+        }
+
+        let result = match resource {
+            ThinPoolResource::Data => pool.set_data_table(dm, new_table),
+            ThinPoolResource::Metadata => pool.set_meta_table(dm, new_table),
+        };
+        // The pool is suspended whether or not the new table was loaded.
+        pool.resume(dm)?;
+        result?;
+
+        debug!(
+            "Extended {:?} of thin pool {} from {} to {}",
+            resource,
+            pool.name(),
+            current,
+            new
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, path::Path};
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        lineardev::LinearTargetParams,
+        testing::{blkdev_size, test_with_spec},
+        thinpooldev::minimal_thinpool,
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify threshold arithmetic at and around the boundary.
+    fn test_threshold_crossed() {
+        assert!(threshold_crossed(80, 100, 80));
+        assert!(!threshold_crossed(79, 100, 80));
+        assert!(threshold_crossed(0, 100, 0));
+        assert!(threshold_crossed(u64::MAX, u64::MAX, 100));
+    }
+
+    /// Verify that a pool whose usage crosses its threshold is extended
+    /// with the table supplied by the callback, and that a declined request
+    /// leaves the pool unchanged.
+    fn test_extend(paths: &[&Path]) {
+        assert!(paths.len() >= 2);
+
+        let dm = DM::new().unwrap();
+        let mut pool = minimal_thinpool(&dm, paths[0]);
+        let dev = Device::from(devnode_to_devno(paths[1]).unwrap().unwrap());
+        let extra = blkdev_size(&OpenOptions::new().read(true).open(paths[1]).unwrap()).sectors();
+
+        let mut declining = ThinPoolMonitor::new(Some(0), None, |_| Ok(None)).unwrap();
+        assert_eq!(declining.check(&dm, &mut pool).unwrap(), vec![]);
+
+        let mut monitor = ThinPoolMonitor::new(Some(0), None, |request| {
+            assert_eq!(request.resource, ThinPoolResource::Data);
+            let mut table = request.table.clone();
+            table.push(TargetLine::new(
+                table_length(&table),
+                extra,
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+            ));
+            Ok(Some(table))
+        })
+        .unwrap();
+        assert!(monitor.event_pending(&dm, &pool).unwrap());
+
+        let total_data = |pool: &ThinPoolDev| match pool.status(&dm, DmOptions::default()).unwrap()
+        {
+            ThinPoolStatus::Working(status) => status.usage.total_data,
+            status => panic!("unexpected thinpool status: {status:?}"),
+        };
+        let before = total_data(&pool);
+        assert_eq!(
+            monitor.check(&dm, &mut pool).unwrap(),
+            vec![ThinPoolResource::Data]
+        );
+        assert!(total_data(&pool) > before);
+        assert!(!monitor.event_pending(&dm, &pool).unwrap());
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_extend() {
+        test_with_spec(2, test_extend);
+    }
+}
@@ -0,0 +1,231 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    core::{errors, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{message, DmDevice},
+    thindevid::ThinDevId,
+    thinpooldev::{ThinPoolDev, ThinPoolStatus},
+};
+
+/// An operation on the metadata of a thin pool, performed as part of a
+/// `ThinPoolTransaction`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThinPoolOp {
+    /// Create a new thin device with the given id.
+    CreateThin(ThinDevId),
+    /// Create a snapshot with the given id of the thin device `origin`.
+    /// The origin must be suspended, if it is active, while the
+    /// transaction is committed.
+    CreateSnap {
+        /// The id of the new snapshot
+        thin_id: ThinDevId,
+        /// The id of the device of which it is a snapshot
+        origin: ThinDevId,
+    },
+    /// Delete the thin device with the given id.
+    Delete(ThinDevId),
+}
+
+impl ThinPoolOp {
+    /// The message which performs this operation.
+    fn message(&self) -> String {
+        match self {
+            ThinPoolOp::CreateThin(thin_id) => format!("create_thin {thin_id}"),
+            ThinPoolOp::CreateSnap { thin_id, origin } => {
+                format!("create_snap {thin_id} {origin}")
+            }
+            ThinPoolOp::Delete(thin_id) => format!("delete {thin_id}"),
+        }
+    }
+}
+
+/// Get the transaction id of a thin pool from its status.
+pub fn thin_pool_transaction_id(dm: &DM, pool: &ThinPoolDev) -> DmResult<u64> {
+    match pool.status(dm, DmOptions::default())? {
+        ThinPoolStatus::Working(status) => Ok(status.transaction_id),
+        ThinPoolStatus::Error | ThinPoolStatus::Fail => Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            format!(
+                "thin pool {} has failed, its transaction id is unavailable",
+                pool.name()
+            ),
+        )),
+    }
+}
+
+/// Make the error returned when a pool's transaction id differs from that
+/// which was expected.
+fn make_mismatch_error(pool: &ThinPoolDev, expected: u64, actual: u64) -> DmError {
+    DmError::Core(errors::Error::TransactionIdMismatch(
+        pool.name().to_string(),
+        expected,
+        actual,
+    ))
+}
+
+/// Change the transaction id of a thin pool from `current` to `new`.
+/// Returns an `errors::Error::TransactionIdMismatch` error if the pool's
+/// transaction id is not `current`.
+pub fn set_thin_pool_transaction_id(
+    dm: &DM,
+    pool: &ThinPoolDev,
+    current: u64,
+    new: u64,
+) -> DmResult<()> {
+    match message(dm, pool, &format!("set_transaction_id {current} {new}")) {
+        Ok(_) => Ok(()),
+        Err(err) => match thin_pool_transaction_id(dm, pool) {
+            Ok(actual) if actual != current => Err(make_mismatch_error(pool, current, actual)),
+            _ => Err(err),
+        },
+    }
+}
+
+/// A batch of operations on the metadata of a thin pool, guarded by the
+/// pool's transaction id.
+///
+/// The transaction records the pool's transaction id when it is begun.
+/// When it is committed, it verifies that the transaction id is unchanged,
+/// performs its operations, and increments the transaction id with a
+/// compare-and-set. If the pool's transaction id has been changed by some
+/// other party in the meantime, the commit fails with an
+/// `errors::Error::TransactionIdMismatch` error, allowing concurrent
+/// modification of the pool to be detected.
+///
+/// The kernel does not make the operations atomic: if one fails, those
+/// before it have still been performed, and the transaction id is not
+/// incremented.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ThinPoolTransaction {
+    transaction_id: u64,
+    ops: Vec<ThinPoolOp>,
+}
+
+impl ThinPoolTransaction {
+    /// Begin a transaction on the given pool.
+    pub fn begin(dm: &DM, pool: &ThinPoolDev) -> DmResult<ThinPoolTransaction> {
+        Ok(ThinPoolTransaction {
+            transaction_id: thin_pool_transaction_id(dm, pool)?,
+            ops: Vec::new(),
+        })
+    }
+
+    /// The transaction id of the pool when the transaction was begun.
+    pub fn transaction_id(&self) -> u64 {
+        self.transaction_id
+    }
+
+    /// The operations of the transaction, in the order in which they will
+    /// be performed.
+    pub fn ops(&self) -> &[ThinPoolOp] {
+        &self.ops
+    }
+
+    /// Add an operation to the transaction.
+    pub fn push(&mut self, op: ThinPoolOp) -> &mut ThinPoolTransaction {
+        self.ops.push(op);
+        self
+    }
+
+    /// Add the creation of a thin device to the transaction.
+    pub fn create_thin(&mut self, thin_id: ThinDevId) -> &mut ThinPoolTransaction {
+        self.push(ThinPoolOp::CreateThin(thin_id))
+    }
+
+    /// Add the creation of a snapshot to the transaction.
+    pub fn create_snap(
+        &mut self,
+        thin_id: ThinDevId,
+        origin: ThinDevId,
+    ) -> &mut ThinPoolTransaction {
+        self.push(ThinPoolOp::CreateSnap { thin_id, origin })
+    }
+
+    /// Add the deletion of a thin device to the transaction.
+    pub fn delete(&mut self, thin_id: ThinDevId) -> &mut ThinPoolTransaction {
+        self.push(ThinPoolOp::Delete(thin_id))
+    }
+
+    /// Commit the transaction, returning the pool's new transaction id.
+    pub fn commit(self, dm: &DM, pool: &ThinPoolDev) -> DmResult<u64> {
+        let actual = thin_pool_transaction_id(dm, pool)?;
+        if actual != self.transaction_id {
+            return Err(make_mismatch_error(pool, self.transaction_id, actual));
+        }
+
+        for op in &self.ops {
+            message(dm, pool, &op.message())?;
+        }
+
+        let new = self.transaction_id + 1;
+        set_thin_pool_transaction_id(dm, pool, self.transaction_id, new)?;
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{testing::test_with_spec, thinpooldev::minimal_thinpool};
+
+    use super::*;
+
+    #[test]
+    /// Verify that operations produce the expected messages.
+    fn test_op_message() {
+        let id = |v| ThinDevId::new_u64(v).expect("is below limit");
+        assert_eq!(ThinPoolOp::CreateThin(id(1)).message(), "create_thin 1");
+        assert_eq!(
+            ThinPoolOp::CreateSnap {
+                thin_id: id(2),
+                origin: id(1)
+            }
+            .message(),
+            "create_snap 2 1"
+        );
+        assert_eq!(ThinPoolOp::Delete(id(1)).message(), "delete 1");
+    }
+
+    /// Verify that a transaction increments the transaction id, and that a
+    /// transaction begun before a concurrent change fails with a mismatch.
+    fn test_transaction(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut pool = minimal_thinpool(&dm, paths[0]);
+        let id = |v| ThinDevId::new_u64(v).expect("is below limit");
+
+        let mut txn = ThinPoolTransaction::begin(&dm, &pool).unwrap();
+        let stale = txn.clone();
+        txn.create_thin(id(0)).create_snap(id(1), id(0));
+        let new = txn.commit(&dm, &pool).unwrap();
+        assert_eq!(new, stale.transaction_id() + 1);
+        assert_eq!(thin_pool_transaction_id(&dm, &pool).unwrap(), new);
+
+        assert_matches!(
+            stale.commit(&dm, &pool),
+            Err(DmError::Core(errors::Error::TransactionIdMismatch(_, expected, actual)))
+                if expected == stale.transaction_id() && actual == new
+        );
+        assert_matches!(
+            set_thin_pool_transaction_id(&dm, &pool, new + 1, new + 2),
+            Err(DmError::Core(errors::Error::TransactionIdMismatch(_, expected, actual)))
+                if expected == new + 1 && actual == new
+        );
+
+        let mut txn = ThinPoolTransaction::begin(&dm, &pool).unwrap();
+        txn.delete(id(1)).delete(id(0));
+        txn.commit(&dm, &pool).unwrap();
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_transaction() {
+        test_with_spec(1, test_transaction);
+    }
+}
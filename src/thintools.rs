@@ -0,0 +1,405 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Invocation of the thin-provisioning-tools metadata checker and repairer,
+// and of the era metadata checker, dumper and reader.
+//
+// The checkers, the repairer and the dumper open the metadata device
+// exclusively, so they can not be run on the metadata device of an active
+// pool or era device. They should be run on the metadata device before the
+// device is set up, or after it is torn down. The era metadata reader,
+// era_invalidate, may be run on the metadata device of an active era
+// device, provided that it reads a metadata snapshot.
+
+use std::{
+    ops::Range,
+    path::Path,
+    process::{Command, Output},
+};
+
+use crate::{
+    core::errors,
+    result::{DmError, DmResult, ErrorEnum},
+    shared::parse_value,
+    units::{DataBlocks, Sectors},
+};
+
+/// The thin-provisioning-tools metadata checker
+const THIN_CHECK: &str = "thin_check";
+
+/// The thin-provisioning-tools metadata repairer
+const THIN_REPAIR: &str = "thin_repair";
+
+/// The thin-provisioning-tools era metadata checker
+const ERA_CHECK: &str = "era_check";
+
+/// The thin-provisioning-tools era metadata dumper
+const ERA_DUMP: &str = "era_dump";
+
+/// The thin-provisioning-tools reader of the blocks written to an era device
+const ERA_INVALIDATE: &str = "era_invalidate";
+
+/// The result of checking thin pool metadata with thin_check.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ThinCheckResult {
+    /// No errors were found in the metadata.
+    Clean,
+    /// Errors were found in the metadata; it should be repaired with
+    /// `thin_repair` before the pool is set up again.
+    Damaged {
+        /// The diagnostic output of thin_check
+        output: String,
+    },
+}
+
+/// The result of checking era metadata with era_check.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EraCheckResult {
+    /// No errors were found in the metadata.
+    Clean,
+    /// Errors were found in the metadata.
+    Damaged {
+        /// The diagnostic output of era_check
+        output: String,
+    },
+}
+
+/// The contents of era metadata, as dumped by era_dump.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EraDump {
+    /// The block size of the era device
+    pub block_size: Sectors,
+    /// The current era
+    pub current_era: u32,
+    /// The era in which each block of the era device was last written,
+    /// indexed by block
+    pub eras: Vec<u32>,
+}
+
+impl EraDump {
+    /// The blocks which have been written since the start of era `era`, as
+    /// ranges, in order, as era_invalidate would report them.
+    pub fn written_since(&self, era: u32) -> Vec<Range<DataBlocks>> {
+        let mut ranges: Vec<Range<DataBlocks>> = Vec::new();
+        for (block, _) in self
+            .eras
+            .iter()
+            .enumerate()
+            .filter(|(_, block_era)| **block_era >= era)
+        {
+            let block = DataBlocks(block as u64);
+            match ranges.last_mut() {
+                Some(last) if last.end == block => last.end = block + DataBlocks(1),
+                _ => ranges.push(block..block + DataBlocks(1)),
+            }
+        }
+        ranges
+    }
+}
+
+/// Run a command, returning its output whether or not it succeeded.
+fn run(command: &mut Command) -> DmResult<Output> {
+    command.output().map_err(|err| {
+        DmError::Core(errors::Error::GeneralIo(format!(
+            "failed to run {command:?}: {err}"
+        )))
+    })
+}
+
+/// The combined stdout and stderr of a command.
+fn output_text(output: &Output) -> String {
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    text.trim().to_owned()
+}
+
+/// Check the thin pool metadata on the device at `meta_path` with
+/// thin_check. If `clear_needs_check` is true and no errors are found, the
+/// needs_check flag in the metadata superblock is cleared, so that the pool
+/// may be set up read-write again.
+///
+/// Returns an error only if thin_check could not be run.
+pub fn thin_check(meta_path: &Path, clear_needs_check: bool) -> DmResult<ThinCheckResult> {
+    let mut command = Command::new(THIN_CHECK);
+    command.arg("-q");
+    if clear_needs_check {
+        command.arg("--clear-needs-check-flag");
+    }
+    command.arg(meta_path);
+
+    let output = run(&mut command)?;
+    if output.status.success() {
+        Ok(ThinCheckResult::Clean)
+    } else {
+        // thin_check is silenced by -q; run it again for the diagnostics.
+        let output = run(Command::new(THIN_CHECK).arg(meta_path))?;
+        warn!(
+            "thin_check found errors in metadata on {}",
+            meta_path.display()
+        );
+        Ok(ThinCheckResult::Damaged {
+            output: output_text(&output),
+        })
+    }
+}
+
+/// Repair the thin pool metadata on the device at `input` with thin_repair,
+/// writing the repaired metadata to the device at `output`. The output
+/// device must be at least as large as the input device and must not be in
+/// use. The input device is left unchanged.
+pub fn thin_repair(input: &Path, output: &Path) -> DmResult<()> {
+    let result = run(Command::new(THIN_REPAIR)
+        .arg("-i")
+        .arg(input)
+        .arg("-o")
+        .arg(output))?;
+    if result.status.success() {
+        debug!(
+            "Repaired thin pool metadata on {} to {}",
+            input.display(),
+            output.display()
+        );
+        Ok(())
+    } else {
+        Err(DmError::Core(errors::Error::GeneralIo(format!(
+            "thin_repair of {} to {} failed: {}",
+            input.display(),
+            output.display(),
+            output_text(&result)
+        ))))
+    }
+}
+
+/// The value of the attribute `name` of an XML element.
+fn xml_attr<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let start = element.find(&format!(" {name}=\""))? + name.len() + 3;
+    let len = element[start..].find('"')?;
+    Some(&element[start..start + len])
+}
+
+/// The value of the attribute `name` of an XML element, parsed.
+fn parse_xml_attr<T>(element: &str, name: &str, tool: &str) -> DmResult<T>
+where
+    T: std::str::FromStr,
+{
+    let value = xml_attr(element, name).ok_or_else(|| {
+        DmError::Dm(
+            ErrorEnum::Invalid,
+            format!("no {name} in {tool} output \"{element}\""),
+        )
+    })?;
+    parse_value(value, &format!("{tool} {name}"))
+}
+
+/// Check the era metadata on the device at `meta_path` with era_check.
+///
+/// Returns an error only if era_check could not be run.
+pub fn era_check(meta_path: &Path) -> DmResult<EraCheckResult> {
+    let output = run(Command::new(ERA_CHECK).arg(meta_path))?;
+    if output.status.success() {
+        Ok(EraCheckResult::Clean)
+    } else {
+        warn!(
+            "era_check found errors in metadata on {}",
+            meta_path.display()
+        );
+        Ok(EraCheckResult::Damaged {
+            output: output_text(&output),
+        })
+    }
+}
+
+/// Parse the output of era_dump --logical, in which the era of each block
+/// is given in the era array, the write sets having been folded into it.
+fn parse_era_dump(output: &str) -> DmResult<EraDump> {
+    let mut superblock = None;
+    let mut eras = Vec::new();
+    for line in output.lines().map(str::trim) {
+        if line.starts_with("<superblock ") {
+            let nr_blocks: usize = parse_xml_attr(line, "nr_blocks", ERA_DUMP)?;
+            superblock = Some((
+                Sectors(parse_xml_attr(line, "block_size", ERA_DUMP)?),
+                parse_xml_attr(line, "current_era", ERA_DUMP)?,
+            ));
+            eras = vec![0; nr_blocks];
+        } else if line.starts_with("<era ") {
+            let block: usize = parse_xml_attr(line, "block", ERA_DUMP)?;
+            let era = parse_xml_attr(line, "era", ERA_DUMP)?;
+            *eras.get_mut(block).ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!("block {block} in era_dump output is beyond the era device"),
+                )
+            })? = era;
+        }
+    }
+    let (block_size, current_era) = superblock.ok_or_else(|| {
+        DmError::Dm(
+            ErrorEnum::Invalid,
+            "no superblock in era_dump output".to_string(),
+        )
+    })?;
+    Ok(EraDump {
+        block_size,
+        current_era,
+        eras,
+    })
+}
+
+/// Dump the era metadata on the device at `meta_path` with era_dump.
+pub fn era_dump(meta_path: &Path) -> DmResult<EraDump> {
+    let output = run(Command::new(ERA_DUMP).arg("--logical").arg(meta_path))?;
+    if !output.status.success() {
+        return Err(DmError::Core(errors::Error::GeneralIo(format!(
+            "era_dump of {} failed: {}",
+            meta_path.display(),
+            output_text(&output)
+        ))));
+    }
+    parse_era_dump(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the output of era_invalidate, a list of blocks and ranges of
+/// blocks, into ranges of blocks, in order, with adjacent ranges merged.
+fn parse_era_invalidate(output: &str) -> DmResult<Vec<Range<DataBlocks>>> {
+    let mut ranges: Vec<Range<DataBlocks>> = Vec::new();
+    for line in output.lines().map(str::trim) {
+        let range = if line.starts_with("<block ") {
+            let block = DataBlocks(parse_xml_attr(line, "block", ERA_INVALIDATE)?);
+            block..block + DataBlocks(1)
+        } else if line.starts_with("<range ") {
+            DataBlocks(parse_xml_attr(line, "begin", ERA_INVALIDATE)?)
+                ..DataBlocks(parse_xml_attr(line, "end", ERA_INVALIDATE)?)
+        } else {
+            continue;
+        };
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        }
+    }
+    Ok(ranges)
+}
+
+/// The blocks of an era device which have been written since the start of
+/// era `era`, read with era_invalidate from the metadata snapshot held on
+/// the era metadata device at `meta_path`. The blocks are in units of the
+/// era device's block size, and are returned as ranges, in order.
+///
+/// The era device must hold a metadata snapshot, taken with the
+/// `take_metadata_snap` message, while era_invalidate runs.
+pub fn era_invalidate(meta_path: &Path, era: u32) -> DmResult<Vec<Range<DataBlocks>>> {
+    let output = run(Command::new(ERA_INVALIDATE)
+        .arg("--metadata-snapshot")
+        .arg("--written-since")
+        .arg(era.to_string())
+        .arg(meta_path))?;
+    if !output.status.success() {
+        return Err(DmError::Core(errors::Error::GeneralIo(format!(
+            "era_invalidate of {} failed: {}",
+            meta_path.display(),
+            output_text(&output)
+        ))));
+    }
+    parse_era_invalidate(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        blkdev::wipe_metadata_superblock, core::DM, shared::DmDevice, testing::test_with_spec,
+        thinpooldev::minimal_thinpool,
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that blocks and ranges of blocks are parsed and merged.
+    fn test_parse_era_invalidate() {
+        let output = r#"<blocks>
+  <range begin="0" end="16"/>
+  <block block="16"/>
+  <block block="20"/>
+  <range begin="32" end="64"/>
+</blocks>
+"#;
+        assert_eq!(
+            parse_era_invalidate(output).unwrap(),
+            vec![
+                DataBlocks(0)..DataBlocks(17),
+                DataBlocks(20)..DataBlocks(21),
+                DataBlocks(32)..DataBlocks(64)
+            ]
+        );
+        assert_eq!(
+            parse_era_invalidate("<blocks>\n</blocks>\n").unwrap(),
+            vec![]
+        );
+        assert_matches!(parse_era_invalidate("<block block=\"x\"/>"), Err(_));
+    }
+
+    #[test]
+    /// Verify that the era of each block is parsed from era_dump output,
+    /// and that the blocks written since an era agree with era_invalidate.
+    fn test_parse_era_dump() {
+        let output = r#"<superblock uuid="" block_size="128" nr_blocks="6" current_era="4">
+  <era_array>
+    <era block="0" era="1"/>
+    <era block="1" era="3"/>
+    <era block="2" era="4"/>
+    <era block="4" era="3"/>
+    <era block="5" era="2"/>
+  </era_array>
+</superblock>
+"#;
+        let dump = parse_era_dump(output).unwrap();
+        assert_eq!(
+            dump,
+            EraDump {
+                block_size: Sectors(128),
+                current_era: 4,
+                eras: vec![1, 3, 4, 0, 3, 2],
+            }
+        );
+        assert_eq!(
+            dump.written_since(3),
+            vec![DataBlocks(1)..DataBlocks(3), DataBlocks(4)..DataBlocks(5)]
+        );
+        assert_eq!(dump.written_since(5), vec![]);
+
+        assert_matches!(parse_era_dump("<era_array>\n</era_array>\n"), Err(_));
+        let beyond = r#"<superblock uuid="" block_size="128" nr_blocks="1" current_era="1">
+  <era block="1" era="1"/>
+"#;
+        assert_matches!(parse_era_dump(beyond), Err(_));
+    }
+
+    /// Verify that the metadata left by a pool which has been torn down
+    /// is clean, that it can be repaired to another device, and that
+    /// metadata with a wiped superblock is reported as damaged.
+    fn test_check_repair(paths: &[&Path]) {
+        assert!(paths.len() >= 2);
+
+        let dm = DM::new().unwrap();
+        let mut pool = minimal_thinpool(&dm, paths[0]);
+        pool.teardown(&dm).unwrap();
+
+        assert_eq!(thin_check(paths[0], true).unwrap(), ThinCheckResult::Clean);
+
+        thin_repair(paths[0], paths[1]).unwrap();
+        assert_eq!(thin_check(paths[1], false).unwrap(), ThinCheckResult::Clean);
+
+        wipe_metadata_superblock(paths[0]).unwrap();
+        assert_matches!(
+            thin_check(paths[0], false).unwrap(),
+            ThinCheckResult::Damaged { .. }
+        );
+        assert!(thin_repair(paths[0], paths[1]).is_err());
+    }
+
+    #[test]
+    fn loop_test_check_repair() {
+        test_with_spec(2, test_check_repair);
+    }
+}
@@ -16,8 +16,9 @@ const META_BLOCK_SIZE: Sectors = Sectors(8);
 /// DM_SM_METADATA_MAX_BLOCKS.
 /// As far as I can tell, this is not a limit on the size of a designated
 /// metadata device, but instead on the possible usage of that device.
-#[allow(dead_code)]
-const MAX_META_DEV_SIZE: MetaBlocks = MetaBlocks(255 * ((1 << 14) - 64));
+/// Note that the kernel docs state the maximum size of a thin pool
+/// metadata device to be 16 GiB, but this is the actual maximum size.
+pub const MAX_META_DEV_SIZE: MetaBlocks = MetaBlocks(255 * ((1 << 14) - 64));
 
 range_u64!(
     /// A type for data blocks
@@ -25,6 +26,14 @@ range_u64!(
     "data blocks"
 );
 
+impl DataBlocks {
+    /// Return the number of Sectors in the DataBlocks, given the size of a
+    /// data block.
+    pub fn sectors(self, block_size: Sectors) -> Sectors {
+        self.0 * block_size
+    }
+}
+
 range_u64!(
     /// A type for meta blocks
     MetaBlocks,
@@ -36,6 +45,11 @@ impl MetaBlocks {
     pub fn sectors(self) -> Sectors {
         self.0 * META_BLOCK_SIZE
     }
+
+    /// Return the number of bytes in the MetaBlocks.
+    pub fn bytes(self) -> Bytes {
+        self.sectors().bytes()
+    }
 }
 
 range_u128!(
@@ -68,4 +82,34 @@ impl Sectors {
     pub fn metablocks(self) -> MetaBlocks {
         MetaBlocks(self / META_BLOCK_SIZE)
     }
+
+    /// The number of whole data blocks of the given size contained in these
+    /// sectors.
+    pub fn datablocks(self, block_size: Sectors) -> DataBlocks {
+        DataBlocks(self / block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that conversions between the unit types agree with each
+    /// other and that partial blocks are not counted.
+    fn test_conversions() {
+        assert_eq!(Sectors(3).bytes(), Bytes(1536));
+        assert_eq!(Bytes(1535).sectors(), Sectors(2));
+
+        assert_eq!(MetaBlocks(2).sectors(), Sectors(16));
+        assert_eq!(MetaBlocks(2).bytes(), Bytes(8192));
+        assert_eq!(Sectors(17).metablocks(), MetaBlocks(2));
+
+        assert_eq!(DataBlocks(3).sectors(Sectors(128)), Sectors(384));
+        assert_eq!(Sectors(383).datablocks(Sectors(128)), DataBlocks(2));
+        assert_eq!(
+            DataBlocks(5).sectors(Sectors(256)).datablocks(Sectors(256)),
+            DataBlocks(5)
+        );
+    }
 }
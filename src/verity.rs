@@ -0,0 +1,1209 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Support for the verity target, which verifies each block read from a
+// read-only data device against a Merkle tree of hashes on a hash device.
+//
+// A hash device formatted by veritysetup starts, at its hash offset, with a
+// superblock recording the parameters with which the tree was built. The
+// hash tree itself starts at the first hash block after the superblock. The
+// root digest is not recorded on disk; it must be obtained from a trusted
+// source, as it is what the tree is verified against.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+    str::FromStr,
+};
+
+#[cfg(devicemapper41supported)]
+use semver::Version;
+
+#[cfg(devicemapper41supported)]
+use crate::core::DmCapabilities;
+use crate::{
+    core::{errors, Device},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{parse_device, parse_value, TargetParams, TargetTypeBuf},
+    units::Bytes,
+};
+
+const VERITY_TARGET_NAME: &str = "verity";
+
+/// The signature at the start of a verity superblock
+const VERITY_SIGNATURE: &[u8; 8] = b"verity\0\0";
+
+/// The only version of the superblock format
+const VERITY_SB_VERSION: u32 = 1;
+
+/// The size of the superblock
+const VERITY_SB_SIZE: usize = 512;
+
+/// The largest salt the superblock can hold
+const VERITY_MAX_SALT_SIZE: usize = 256;
+
+/// The salt of the target params when there is none
+const NO_SALT: &str = "-";
+
+/// The optional args giving the forward error correction params
+const FEC_DEVICE_ARG: &str = "use_fec_from_device";
+const FEC_ROOTS_ARG: &str = "fec_roots";
+const FEC_BLOCKS_ARG: &str = "fec_blocks";
+const FEC_START_ARG: &str = "fec_start";
+
+/// The optional args giving the response to a block which fails
+/// verification; by default, the read fails with EIO
+const IGNORE_CORRUPTION_ARG: &str = "ignore_corruption";
+const RESTART_ON_CORRUPTION_ARG: &str = "restart_on_corruption";
+const PANIC_ON_CORRUPTION_ARG: &str = "panic_on_corruption";
+/// The optional arg which makes blocks of zeroes be returned for blocks
+/// whose hash is that of a block of zeroes, without reading them
+const IGNORE_ZERO_BLOCKS_ARG: &str = "ignore_zero_blocks";
+/// The optional arg which makes each data block be verified only the first
+/// time it is read
+const CHECK_AT_MOST_ONCE_ARG: &str = "check_at_most_once";
+
+/// The number of bytes in a Reed-Solomon codeword, data and parity
+const FEC_RSM: u64 = 255;
+/// The permitted range of the number of parity bytes in a codeword
+const MIN_FEC_ROOTS: u8 = 2;
+const MAX_FEC_ROOTS: u8 = 24;
+/// The primitive polynomial of the field GF(2^8) of the Reed-Solomon code,
+/// x^8 + x^4 + x^3 + x^2 + 1
+const FEC_GF_POLY: u16 = 0x11d;
+
+/// The forward error correction params of a verity target, with which the
+/// kernel corrects blocks which fail verification, using Reed-Solomon
+/// parity computed over the data and hash blocks and stored on the FEC
+/// device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerityFec {
+    /// The device holding the parity
+    pub device: Device,
+    /// The number of parity bytes in each Reed-Solomon codeword
+    pub roots: u8,
+    /// The number of blocks, of the data block size, covered by the parity:
+    /// the data blocks followed by the blocks of the hash device from the
+    /// start of the hash tree
+    pub blocks: u64,
+    /// The offset of the parity on the FEC device, in data blocks
+    pub start: u64,
+}
+
+impl VerityFec {
+    /// Create a new VerityFec struct. Returns an error if `roots` is not
+    /// in the range the kernel accepts, from 2 to 24.
+    pub fn new(device: Device, roots: u8, blocks: u64, start: u64) -> DmResult<VerityFec> {
+        if !(MIN_FEC_ROOTS..=MAX_FEC_ROOTS).contains(&roots) {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("FEC roots {roots} is not between {MIN_FEC_ROOTS} and {MAX_FEC_ROOTS}"),
+            ));
+        }
+        Ok(VerityFec {
+            device,
+            roots,
+            blocks,
+            start,
+        })
+    }
+
+    /// The number of rounds of interleaved codewords needed to cover the
+    /// blocks.
+    pub fn rounds(&self) -> u64 {
+        let data_bytes = FEC_RSM - u64::from(self.roots);
+        (self.blocks + data_bytes - 1) / data_bytes
+    }
+
+    /// The size of the parity on the FEC device, for the given data block
+    /// size.
+    pub fn parity_size(&self, data_block_size: Bytes) -> Bytes {
+        Bytes(u128::from(self.rounds() * u64::from(self.roots))) * *data_block_size
+    }
+
+    /// Compute the Reed-Solomon parity of a device formatted with the
+    /// superblock `sb` at `hash_offset` on the hash device, reading the data
+    /// and hash blocks from the files or devices at `data` and `hash`, and
+    /// write it at the start of these params on the file or device at
+    /// `fec`, as veritysetup does.
+    ///
+    /// The blocks covered are interleaved, so that each codeword of a round
+    /// takes one byte from each of blocks which are `rounds` blocks apart,
+    /// and a run of corrupt blocks is spread over many codewords. Blocks
+    /// beyond the end of those covered are taken to be zeroes.
+    ///
+    /// Returns an error if the data and hash block sizes differ, or if
+    /// these params do not cover exactly the data and the hash tree, i.e.,
+    /// if the number of blocks is not that given by
+    /// `VeritySuperblock::fec_blocks`. The kernel accepts more, but would
+    /// then read whatever follows the hash tree on the hash device.
+    pub fn write_parity(
+        &self,
+        sb: &VeritySuperblock,
+        data: &Path,
+        hash: &Path,
+        hash_offset: Bytes,
+        fec: &Path,
+    ) -> DmResult<()> {
+        if sb.data_block_size != sb.hash_block_size {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "FEC requires equal block sizes, but the data block size is {} and the hash block size is {}",
+                    sb.data_block_size, sb.hash_block_size
+                ),
+            ));
+        }
+        let fec_blocks = sb.fec_blocks()?;
+        if self.blocks != fec_blocks {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "FEC covers {} blocks, but the data and hash tree are {}",
+                    self.blocks, fec_blocks
+                ),
+            ));
+        }
+
+        let data_file = File::open(data).map_err(|err| io_error("open", data, err))?;
+        let hash_file = File::open(hash).map_err(|err| io_error("open", hash, err))?;
+        let fec_file = OpenOptions::new()
+            .write(true)
+            .open(fec)
+            .map_err(|err| io_error("open", fec, err))?;
+
+        let block_size = *sb.data_block_size as usize;
+        let roots = usize::from(self.roots);
+        let rsn = FEC_RSM as usize - roots;
+        let rounds = self.rounds();
+        let hash_start = sb.hash_start_block(hash_offset);
+        let rs = ReedSolomon::new(self.roots);
+
+        // Read block `index` of the area covered: the data blocks, followed
+        // by the hash blocks from the start of the hash tree.
+        let read_block = |index: u64, buf: &mut [u8]| -> DmResult<()> {
+            let (file, path, block) = if index >= self.blocks {
+                buf.fill(0);
+                return Ok(());
+            } else if index < sb.data_blocks {
+                (&data_file, data, index)
+            } else {
+                (&hash_file, hash, hash_start + index - sb.data_blocks)
+            };
+            file.read_exact_at(buf, block * block_size as u64)
+                .map_err(|err| io_error("read block for FEC from", path, err))
+        };
+
+        let mut blocks = vec![0u8; rsn * block_size];
+        let mut codeword = vec![0u8; rsn];
+        let mut parity = vec![0u8; roots * block_size];
+        for round in 0..rounds {
+            for (i, buf) in blocks.chunks_exact_mut(block_size).enumerate() {
+                read_block(round + i as u64 * rounds, buf)?;
+            }
+            for (byte, codeword_parity) in parity.chunks_exact_mut(roots).enumerate() {
+                for (i, symbol) in codeword.iter_mut().enumerate() {
+                    *symbol = blocks[i * block_size + byte];
+                }
+                rs.encode(&codeword, codeword_parity);
+            }
+            let offset = (self.start + round * u64::from(self.roots)) * block_size as u64;
+            fec_file
+                .write_all_at(&parity, offset)
+                .map_err(|err| io_error("write FEC parity to", fec, err))?;
+        }
+        fec_file
+            .sync_all()
+            .map_err(|err| io_error("sync", fec, err))
+    }
+
+    /// The optional args giving these params.
+    fn args(&self) -> Vec<String> {
+        vec![
+            FEC_DEVICE_ARG.to_string(),
+            self.device.to_string(),
+            FEC_BLOCKS_ARG.to_string(),
+            self.blocks.to_string(),
+            FEC_START_ARG.to_string(),
+            self.start.to_string(),
+            FEC_ROOTS_ARG.to_string(),
+            self.roots.to_string(),
+        ]
+    }
+}
+
+/// A Reed-Solomon encoder over GF(2^8) for the codes of dm-verity, whose
+/// generator polynomial has the roots 1, alpha, alpha^2, ..., where alpha is
+/// the primitive element.
+struct ReedSolomon {
+    /// The powers of alpha, alpha^i at index i
+    exp: [u8; 255],
+    /// The logarithms of the nonzero elements, i at index alpha^i
+    log: [u8; 256],
+    /// The coefficients of the generator polynomial, from that of the
+    /// highest power of x, omitting the leading coefficient, which is 1
+    generator: Vec<u8>,
+}
+
+impl ReedSolomon {
+    /// The encoder for codewords with `roots` parity bytes.
+    fn new(roots: u8) -> ReedSolomon {
+        let mut exp = [0u8; 255];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        for (i, power) in exp.iter_mut().enumerate() {
+            *power = x as u8;
+            log[usize::from(x)] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= FEC_GF_POLY;
+            }
+        }
+        let mut rs = ReedSolomon {
+            exp,
+            log,
+            generator: Vec::new(),
+        };
+
+        // The product of (x + alpha^i) for i from 0 to roots - 1.
+        let mut generator = vec![1u8];
+        for i in 0..roots {
+            let root = rs.exp[usize::from(i)];
+            let mut product = vec![0u8; generator.len() + 1];
+            for (j, coefficient) in generator.iter().enumerate() {
+                product[j] ^= coefficient;
+                product[j + 1] ^= rs.mul(*coefficient, root);
+            }
+            generator = product;
+        }
+        generator.remove(0);
+        rs.generator = generator;
+        rs
+    }
+
+    /// The product of two elements of the field.
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            let log = usize::from(self.log[usize::from(a)]) + usize::from(self.log[usize::from(b)]);
+            self.exp[log % 255]
+        }
+    }
+
+    /// Compute into `parity` the parity of `data`: the remainder of the
+    /// division by the generator polynomial of the polynomial whose
+    /// coefficients, from that of the highest power of x, are the bytes of
+    /// `data` followed by as many zeroes as there are bytes of parity.
+    fn encode(&self, data: &[u8], parity: &mut [u8]) {
+        parity.fill(0);
+        for byte in data {
+            let feedback = byte ^ parity[0];
+            parity.copy_within(1.., 0);
+            parity[parity.len() - 1] = 0;
+            if feedback != 0 {
+                for (symbol, coefficient) in parity.iter_mut().zip(&self.generator) {
+                    *symbol ^= self.mul(feedback, *coefficient);
+                }
+            }
+        }
+    }
+}
+
+/// The response of a verity target to a block which fails verification,
+/// other than failing the read with EIO.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VerityCorruptionMode {
+    /// The corruption is logged, and the block is returned as read
+    Ignore,
+    /// The system is restarted
+    Restart,
+    /// The kernel panics
+    Panic,
+}
+
+impl VerityCorruptionMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VerityCorruptionMode::Ignore => IGNORE_CORRUPTION_ARG,
+            VerityCorruptionMode::Restart => RESTART_ON_CORRUPTION_ARG,
+            VerityCorruptionMode::Panic => PANIC_ON_CORRUPTION_ARG,
+        }
+    }
+}
+
+/// Target params for verity target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerityTargetParams {
+    /// The hash format: 0 for the original Chrome OS format, 1 for the
+    /// format in which the salt is hashed first and hash blocks are padded
+    pub version: u32,
+    /// The device holding the data to be verified
+    pub data_dev: Device,
+    /// The device holding the hash tree
+    pub hash_dev: Device,
+    /// The size of a data block
+    pub data_block_size: Bytes,
+    /// The size of a hash block
+    pub hash_block_size: Bytes,
+    /// The number of data blocks which are verified
+    pub num_data_blocks: u64,
+    /// The offset of the root of the hash tree on the hash device, in hash
+    /// blocks
+    pub hash_start_block: u64,
+    /// The hash algorithm, e.g., "sha256"
+    pub algorithm: String,
+    /// The root digest, as hex
+    pub root_digest: String,
+    /// The salt, as hex, if any
+    pub salt: Option<String>,
+    /// The forward error correction params, if any
+    pub fec: Option<VerityFec>,
+    /// The response to a block which fails verification; if None, the
+    /// read fails with EIO
+    pub corruption_mode: Option<VerityCorruptionMode>,
+    /// Whether blocks whose hash is that of a block of zeroes are returned
+    /// as zeroes without being read
+    pub ignore_zero_blocks: bool,
+    /// Whether each data block is verified only the first time it is read
+    pub check_at_most_once: bool,
+    /// Other optional arguments, e.g., "root_hash_sig_key_desc <key>"
+    pub optional_args: Vec<String>,
+}
+
+impl VerityTargetParams {
+    /// Create a new VerityTargetParams struct
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        version: u32,
+        data_dev: Device,
+        hash_dev: Device,
+        data_block_size: Bytes,
+        hash_block_size: Bytes,
+        num_data_blocks: u64,
+        hash_start_block: u64,
+        algorithm: String,
+        root_digest: String,
+        salt: Option<String>,
+        optional_args: Vec<String>,
+    ) -> VerityTargetParams {
+        VerityTargetParams {
+            version,
+            data_dev,
+            hash_dev,
+            data_block_size,
+            hash_block_size,
+            num_data_blocks,
+            hash_start_block,
+            algorithm,
+            root_digest,
+            salt,
+            fec: None,
+            corruption_mode: None,
+            ignore_zero_blocks: false,
+            check_at_most_once: false,
+            optional_args,
+        }
+    }
+
+    /// The typed optional args which are set, as they appear in the table.
+    fn typed_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(mode) = self.corruption_mode {
+            args.push(mode.as_str().to_string());
+        }
+        if self.ignore_zero_blocks {
+            args.push(IGNORE_ZERO_BLOCKS_ARG.to_string());
+        }
+        if self.check_at_most_once {
+            args.push(CHECK_AT_MOST_ONCE_ARG.to_string());
+        }
+        args
+    }
+}
+
+/// The version of the verity target which introduced an optional arg.
+#[cfg(devicemapper41supported)]
+fn optional_arg_min_version(arg: &str) -> Option<Version> {
+    match arg {
+        IGNORE_CORRUPTION_ARG
+        | RESTART_ON_CORRUPTION_ARG
+        | IGNORE_ZERO_BLOCKS_ARG
+        | FEC_DEVICE_ARG => Some(Version::new(1, 3, 0)),
+        CHECK_AT_MOST_ONCE_ARG => Some(Version::new(1, 4, 0)),
+        PANIC_ON_CORRUPTION_ARG => Some(Version::new(1, 8, 0)),
+        _ => None,
+    }
+}
+
+#[cfg(devicemapper41supported)]
+impl VerityTargetParams {
+    /// The optional args whose support depends on the target version.
+    fn versioned_args(&self) -> Vec<String> {
+        let mut args = self.typed_args();
+        if self.fec.is_some() {
+            args.push(FEC_DEVICE_ARG.to_string());
+        }
+        args
+    }
+
+    /// Check that the running kernel's verity target accepts every typed
+    /// optional arg which is set, and forward error correction if it is
+    /// used, so that a table with these params does not fail to load. If
+    /// the verity target is not yet loaded, its version is unknown and no
+    /// arg is rejected.
+    pub fn check_features(&self, capabilities: &DmCapabilities) -> DmResult<()> {
+        capabilities.check_features(
+            VERITY_TARGET_NAME,
+            &self.versioned_args(),
+            optional_arg_min_version,
+        )
+    }
+
+    /// Clear every typed optional arg which the running kernel's verity
+    /// target does not accept, logging a warning for each. Forward error
+    /// correction is left as it is.
+    pub fn remove_unsupported_features(&mut self, capabilities: &DmCapabilities) {
+        for (arg, min) in capabilities.unsupported_features(
+            VERITY_TARGET_NAME,
+            &self.typed_args(),
+            optional_arg_min_version,
+        ) {
+            warn!(
+                "Omitting optional arg {}, which requires verity target version {} or later",
+                arg, min
+            );
+            match arg.as_str() {
+                IGNORE_ZERO_BLOCKS_ARG => self.ignore_zero_blocks = false,
+                CHECK_AT_MOST_ONCE_ARG => self.check_at_most_once = false,
+                _ => self.corruption_mode = None,
+            }
+        }
+    }
+}
+
+impl fmt::Display for VerityTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <version> <data dev> <hash dev> <data block size> <hash block size>
+    /// <#data blocks> <hash start block> <algorithm> <digest> <salt>
+    /// [<#opt args> <opt args>]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", VERITY_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for VerityTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<VerityTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() < 11 {
+            let err_msg = format!(
+                "expected at least 11 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != VERITY_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a verity target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut optional_args = Vec::new();
+        let mut corruption_mode = None;
+        let (mut ignore_zero_blocks, mut check_at_most_once) = (false, false);
+        let (mut fec_device, mut fec_roots, mut fec_blocks, mut fec_start) =
+            (None, None, None, None);
+        if let Some(count) = vals.get(11) {
+            let count: usize = parse_value(count, "number of optional args")?;
+            if vals.len() != 12 + count {
+                let err_msg = format!(
+                    "expected {} optional args in params string \"{}\", found {}",
+                    count,
+                    s,
+                    vals.len() - 12
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+            let mut args = vals[12..].iter();
+            while let Some(arg) = args.next() {
+                let mut value = |desc: &str| {
+                    args.next().copied().ok_or_else(|| {
+                        DmError::Dm(
+                            ErrorEnum::Invalid,
+                            format!("no value for {desc} in params string \"{s}\""),
+                        )
+                    })
+                };
+                match *arg {
+                    FEC_DEVICE_ARG => {
+                        fec_device = Some(parse_device(
+                            value(FEC_DEVICE_ARG)?,
+                            "FEC device for verity target",
+                        )?)
+                    }
+                    FEC_ROOTS_ARG => {
+                        fec_roots = Some(parse_value(value(FEC_ROOTS_ARG)?, "FEC roots")?)
+                    }
+                    FEC_BLOCKS_ARG => {
+                        fec_blocks = Some(parse_value(value(FEC_BLOCKS_ARG)?, "FEC blocks")?)
+                    }
+                    FEC_START_ARG => {
+                        fec_start = Some(parse_value(value(FEC_START_ARG)?, "FEC start")?)
+                    }
+                    IGNORE_CORRUPTION_ARG | RESTART_ON_CORRUPTION_ARG | PANIC_ON_CORRUPTION_ARG => {
+                        let mode = match *arg {
+                            IGNORE_CORRUPTION_ARG => VerityCorruptionMode::Ignore,
+                            RESTART_ON_CORRUPTION_ARG => VerityCorruptionMode::Restart,
+                            _ => VerityCorruptionMode::Panic,
+                        };
+                        if corruption_mode.replace(mode).is_some() {
+                            let err_msg = format!(
+                                "more than one response to corruption in params string \"{s}\""
+                            );
+                            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+                        }
+                    }
+                    IGNORE_ZERO_BLOCKS_ARG => ignore_zero_blocks = true,
+                    CHECK_AT_MOST_ONCE_ARG => check_at_most_once = true,
+                    arg => optional_args.push(arg.to_string()),
+                }
+            }
+        }
+        let fec = match (fec_device, fec_roots, fec_blocks, fec_start) {
+            (None, None, None, None) => None,
+            (Some(device), Some(roots), Some(blocks), Some(start)) => {
+                Some(VerityFec::new(device, roots, blocks, start)?)
+            }
+            _ => {
+                let err_msg = format!("incomplete FEC args in params string \"{s}\"");
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        };
+
+        let mut params = VerityTargetParams::new(
+            parse_value(vals[1], "hash format version")?,
+            parse_device(vals[2], "data device for verity target")?,
+            parse_device(vals[3], "hash device for verity target")?,
+            Bytes(parse_value(vals[4], "data block size")?),
+            Bytes(parse_value(vals[5], "hash block size")?),
+            parse_value(vals[6], "number of data blocks")?,
+            parse_value(vals[7], "hash start block")?,
+            vals[8].to_string(),
+            vals[9].to_string(),
+            match vals[10] {
+                NO_SALT => None,
+                salt => Some(salt.to_string()),
+            },
+            optional_args,
+        );
+        params.fec = fec;
+        params.corruption_mode = corruption_mode;
+        params.ignore_zero_blocks = ignore_zero_blocks;
+        params.check_at_most_once = check_at_most_once;
+        Ok(params)
+    }
+}
+
+impl TargetParams for VerityTargetParams {
+    fn param_str(&self) -> String {
+        let mut params = format!(
+            "{} {} {} {} {} {} {} {} {} {}",
+            self.version,
+            self.data_dev,
+            self.hash_dev,
+            *self.data_block_size,
+            *self.hash_block_size,
+            self.num_data_blocks,
+            self.hash_start_block,
+            self.algorithm,
+            self.root_digest,
+            self.salt.as_deref().unwrap_or(NO_SALT),
+        );
+        let mut optional_args = self.typed_args();
+        optional_args.extend(self.optional_args.iter().cloned());
+        if let Some(ref fec) = self.fec {
+            optional_args.extend(fec.args());
+        }
+        if !optional_args.is_empty() {
+            params.push_str(&format!(
+                " {} {}",
+                optional_args.len(),
+                optional_args.join(" ")
+            ));
+        }
+        params
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(VERITY_TARGET_NAME.into()).expect("VERITY_TARGET_NAME is valid")
+    }
+}
+
+/// The size in bytes of a digest made by the given hash algorithm, if it
+/// is one commonly used with verity.
+pub fn verity_digest_size(algorithm: &str) -> Option<usize> {
+    match algorithm {
+        "md5" => Some(16),
+        "sha1" => Some(20),
+        "sha224" | "sha3-224" => Some(28),
+        "sha256" | "sha3-256" | "blake2s-256" => Some(32),
+        "sha384" | "sha3-384" => Some(48),
+        "sha512" | "sha3-512" | "blake2b-512" => Some(64),
+        _ => None,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn io_error(action: &str, path: &Path, err: io::Error) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to {} {}: {}",
+        action,
+        path.display(),
+        err
+    )))
+}
+
+fn invalid_superblock(msg: String) -> DmError {
+    DmError::Dm(
+        ErrorEnum::Invalid,
+        format!("invalid verity superblock: {msg}"),
+    )
+}
+
+/// The superblock written by veritysetup at the start of a hash device, or
+/// at its hash offset, which records how the hash tree was built.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VeritySuperblock {
+    /// The hash format, which is the version of the target params
+    pub hash_type: u32,
+    /// The UUID of the verity device, in the usual hyphenated form
+    pub uuid: String,
+    /// The hash algorithm, e.g., "sha256"
+    pub algorithm: String,
+    /// The size of a data block
+    pub data_block_size: Bytes,
+    /// The size of a hash block
+    pub hash_block_size: Bytes,
+    /// The number of data blocks covered by the hash tree
+    pub data_blocks: u64,
+    /// The salt
+    pub salt: Vec<u8>,
+}
+
+impl VeritySuperblock {
+    /// Parse a superblock from the bytes at its start.
+    pub fn parse(buf: &[u8]) -> DmResult<VeritySuperblock> {
+        if buf.len() < VERITY_SB_SIZE {
+            return Err(invalid_superblock(format!(
+                "expected {VERITY_SB_SIZE} bytes, found {}",
+                buf.len()
+            )));
+        }
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes(
+                buf[offset..offset + 4]
+                    .try_into()
+                    .expect("slice is 4 bytes long"),
+            )
+        };
+
+        if &buf[0..8] != VERITY_SIGNATURE {
+            return Err(invalid_superblock("bad signature".to_string()));
+        }
+        let version = read_u32(8);
+        if version != VERITY_SB_VERSION {
+            return Err(invalid_superblock(format!("unsupported version {version}")));
+        }
+        let hash_type = read_u32(12);
+        if hash_type > 1 {
+            return Err(invalid_superblock(format!(
+                "unsupported hash type {hash_type}"
+            )));
+        }
+
+        let uuid = &buf[16..32];
+        let uuid = format!(
+            "{}-{}-{}-{}-{}",
+            hex(&uuid[0..4]),
+            hex(&uuid[4..6]),
+            hex(&uuid[6..8]),
+            hex(&uuid[8..10]),
+            hex(&uuid[10..16])
+        );
+
+        let algorithm = &buf[32..64];
+        let algorithm = algorithm
+            .iter()
+            .position(|byte| *byte == 0)
+            .map_or(algorithm, |len| &algorithm[..len]);
+        let algorithm = std::str::from_utf8(algorithm)
+            .map_err(|_| invalid_superblock("algorithm is not valid UTF-8".to_string()))?
+            .to_string();
+        if algorithm.is_empty() {
+            return Err(invalid_superblock("no hash algorithm".to_string()));
+        }
+
+        let data_block_size = read_u32(64);
+        let hash_block_size = read_u32(68);
+        for (desc, size) in [("data", data_block_size), ("hash", hash_block_size)] {
+            if size < 512 || !size.is_power_of_two() {
+                return Err(invalid_superblock(format!("bad {desc} block size {size}")));
+            }
+        }
+        let data_blocks =
+            u64::from_le_bytes(buf[72..80].try_into().expect("slice is 8 bytes long"));
+
+        let salt_size = usize::from(u16::from_le_bytes(
+            buf[80..82].try_into().expect("slice is 2 bytes long"),
+        ));
+        if salt_size > VERITY_MAX_SALT_SIZE {
+            return Err(invalid_superblock(format!("bad salt size {salt_size}")));
+        }
+        let salt = buf[88..88 + salt_size].to_vec();
+
+        Ok(VeritySuperblock {
+            hash_type,
+            uuid,
+            algorithm,
+            data_block_size: Bytes(u128::from(data_block_size)),
+            hash_block_size: Bytes(u128::from(hash_block_size)),
+            data_blocks,
+            salt,
+        })
+    }
+
+    /// Read the superblock at `hash_offset` on the hash device or file at
+    /// `path`. The hash offset is 0 unless the hash tree shares a device
+    /// with other data, e.g., when it follows the data on the data device.
+    pub fn read(path: &Path, hash_offset: Bytes) -> DmResult<VeritySuperblock> {
+        let file = File::open(path).map_err(|err| io_error("open", path, err))?;
+        let offset = u64::try_from(*hash_offset).map_err(|_| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("hash offset {hash_offset} is too large"),
+            )
+        })?;
+        let mut buf = [0u8; VERITY_SB_SIZE];
+        file.read_exact_at(&mut buf, offset)
+            .map_err(|err| io_error("read verity superblock from", path, err))?;
+        VeritySuperblock::parse(&buf)
+    }
+
+    /// The offset of the root of the hash tree, in hash blocks, when the
+    /// superblock is at `hash_offset`: the first hash block after the
+    /// superblock.
+    pub fn hash_start_block(&self, hash_offset: Bytes) -> u64 {
+        let end = *hash_offset + VERITY_SB_SIZE as u128;
+        ((end + *self.hash_block_size - 1) / *self.hash_block_size) as u64
+    }
+
+    /// The number of hash blocks in each level of the hash tree, from the
+    /// root level down. Returns an error if the hash algorithm is not known.
+    fn hash_levels(&self) -> DmResult<Vec<u64>> {
+        let digest_size = verity_digest_size(&self.algorithm).ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown verity hash algorithm \"{}\"", self.algorithm),
+            )
+        })?;
+        // In format 1, each digest is padded to a power of two.
+        let digest_size = if self.hash_type == 0 {
+            digest_size
+        } else {
+            digest_size.next_power_of_two()
+        };
+        let hashes_per_block = (*self.hash_block_size / digest_size as u128) as u64;
+        if hashes_per_block < 2 {
+            return Err(invalid_superblock(format!(
+                "hash block size {} is too small for {}",
+                self.hash_block_size, self.algorithm
+            )));
+        }
+        let mut levels = Vec::new();
+        let mut blocks = self.data_blocks;
+        loop {
+            blocks = (blocks + hashes_per_block - 1) / hashes_per_block;
+            levels.push(blocks);
+            if blocks <= 1 {
+                break;
+            }
+        }
+        levels.reverse();
+        Ok(levels)
+    }
+
+    /// The number of blocks covered by forward error correction of a device
+    /// formatted with this superblock: the data blocks, followed by the
+    /// hash tree, in blocks of the data block size. The kernel requires the
+    /// FEC params to cover at least these. Returns an error if the hash
+    /// algorithm is not known.
+    pub fn fec_blocks(&self) -> DmResult<u64> {
+        let hash_blocks: u64 = self.hash_levels()?.iter().sum();
+        let hash_size = u128::from(hash_blocks) * *self.hash_block_size;
+        let hash_data_blocks = (hash_size + *self.data_block_size - 1) / *self.data_block_size;
+        Ok(self.data_blocks + hash_data_blocks as u64)
+    }
+
+    /// The FEC params for a device formatted with this superblock, with
+    /// `roots` parity bytes per codeword and the parity at `start`, in data
+    /// blocks, on the FEC device, which cover the blocks given by
+    /// `fec_blocks`. The parity itself is written by
+    /// `VerityFec::write_parity`.
+    pub fn fec(&self, device: Device, roots: u8, start: u64) -> DmResult<VerityFec> {
+        VerityFec::new(device, roots, self.fec_blocks()?, start)
+    }
+
+    /// The target params for a verity target over the data and hash
+    /// devices, as formatted with this superblock at `hash_offset` on the
+    /// hash device. Returns an error if `root_digest` is not a hex digest
+    /// of the length made by the superblock's hash algorithm.
+    pub fn target_params(
+        &self,
+        data_dev: Device,
+        hash_dev: Device,
+        hash_offset: Bytes,
+        root_digest: &str,
+    ) -> DmResult<VerityTargetParams> {
+        let is_hex = root_digest.chars().all(|c| c.is_ascii_hexdigit());
+        let len_ok = verity_digest_size(&self.algorithm).map_or(!root_digest.is_empty(), |size| {
+            root_digest.len() == 2 * size
+        });
+        if !is_hex || !len_ok {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!(
+                    "\"{root_digest}\" is not a valid {} root digest",
+                    self.algorithm
+                ),
+            ));
+        }
+        Ok(VerityTargetParams::new(
+            self.hash_type,
+            data_dev,
+            hash_dev,
+            self.data_block_size,
+            self.hash_block_size,
+            self.data_blocks,
+            self.hash_start_block(hash_offset),
+            self.algorithm.clone(),
+            root_digest.to_lowercase(),
+            if self.salt.is_empty() {
+                None
+            } else {
+                Some(hex(&self.salt))
+            },
+            Vec::new(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A superblock as written by veritysetup format with a 4 KiB data
+    /// and hash block size, sha256, and a 4 byte salt.
+    fn superblock() -> Vec<u8> {
+        let mut sb = vec![0u8; VERITY_SB_SIZE];
+        sb[0..8].copy_from_slice(VERITY_SIGNATURE);
+        sb[8..12].copy_from_slice(&1u32.to_le_bytes());
+        sb[12..16].copy_from_slice(&1u32.to_le_bytes());
+        sb[16..32].copy_from_slice(&[
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef,
+        ]);
+        sb[32..38].copy_from_slice(b"sha256");
+        sb[64..68].copy_from_slice(&4096u32.to_le_bytes());
+        sb[68..72].copy_from_slice(&4096u32.to_le_bytes());
+        sb[72..80].copy_from_slice(&256u64.to_le_bytes());
+        sb[80..82].copy_from_slice(&4u16.to_le_bytes());
+        sb[88..92].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        sb
+    }
+
+    #[test]
+    /// Verify that a superblock is parsed, and that target params are made
+    /// from it which survive a round trip through their string form.
+    fn test_superblock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hash");
+        let mut contents = vec![0u8; 4096];
+        contents.extend(superblock());
+        fs::write(&path, contents).unwrap();
+
+        let sb = VeritySuperblock::read(&path, Bytes(4096)).unwrap();
+        assert_eq!(sb.hash_type, 1);
+        assert_eq!(sb.uuid, "12345678-9abc-def0-0123-456789abcdef");
+        assert_eq!(sb.algorithm, "sha256");
+        assert_eq!(sb.data_block_size, Bytes(4096));
+        assert_eq!(sb.data_blocks, 256);
+        assert_eq!(sb.salt, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(sb.hash_start_block(Bytes(0)), 1);
+        assert_eq!(sb.hash_start_block(Bytes(4096)), 2);
+
+        let dev = Device::from_str("7:0").unwrap();
+        let digest = "ab".repeat(32);
+        let params = sb.target_params(dev, dev, Bytes(0), &digest).unwrap();
+        assert_eq!(
+            params.param_str(),
+            format!("1 7:0 7:0 4096 4096 256 1 sha256 {digest} deadbeef")
+        );
+        assert_eq!(
+            params.to_string().parse::<VerityTargetParams>().unwrap(),
+            params
+        );
+
+        assert_matches!(
+            sb.target_params(dev, dev, Bytes(0), "abcd"),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify the extent of the hash tree and of the FEC parity of a
+    /// formatted device, and that the FEC params survive a round trip
+    /// through their string form.
+    fn test_fec() {
+        let sb = VeritySuperblock::parse(&superblock()).unwrap();
+        // 256 data blocks need 2 blocks of 128 sha256 hashes, and a root
+        assert_eq!(sb.hash_levels().unwrap(), vec![1, 2]);
+        assert_eq!(sb.fec_blocks().unwrap(), 256 + 3);
+
+        let dev = Device::from_str("7:0").unwrap();
+        let fec_dev = Device::from_str("7:1").unwrap();
+        let fec = sb.fec(fec_dev, 2, 0).unwrap();
+        assert_eq!(fec.rounds(), 2);
+        assert_eq!(fec.parity_size(sb.data_block_size), Bytes(4 * 4096));
+
+        let mut params = sb
+            .target_params(dev, dev, Bytes(0), &"ab".repeat(32))
+            .unwrap();
+        params.ignore_zero_blocks = true;
+        params.fec = Some(fec);
+        assert!(params.param_str().ends_with(
+            "9 ignore_zero_blocks use_fec_from_device 7:1 fec_blocks 259 fec_start 0 fec_roots 2"
+        ));
+        assert_eq!(
+            params.to_string().parse::<VerityTargetParams>().unwrap(),
+            params
+        );
+
+        assert_matches!(
+            VerityFec::new(fec_dev, 25, 259, 0),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            "verity 1 8:1 8:2 4096 4096 100 1 sha256 00 - 2 fec_roots 2"
+                .parse::<VerityTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    /// The values at alpha^0, alpha^1, ... of the polynomial whose
+    /// coefficients, from that of the highest power of x, are `codeword`,
+    /// all of which are 0 if it is a codeword.
+    fn syndromes(rs: &ReedSolomon, codeword: &[u8], roots: u8) -> Vec<u8> {
+        (0..roots)
+            .map(|i| {
+                let x = rs.exp[usize::from(i)];
+                codeword
+                    .iter()
+                    .fold(0, |value, coefficient| rs.mul(value, x) ^ coefficient)
+            })
+            .collect()
+    }
+
+    #[test]
+    /// Verify that the parity makes codewords of the data, i.e., that each
+    /// root of the generator polynomial is a root of data and parity.
+    fn test_reed_solomon() {
+        for roots in [MIN_FEC_ROOTS, 7, MAX_FEC_ROOTS] {
+            let rs = ReedSolomon::new(roots);
+            assert_eq!(rs.generator.len(), usize::from(roots));
+
+            let data = (0..FEC_RSM - u64::from(roots))
+                .map(|i| (i * 31 + 7) as u8)
+                .collect::<Vec<_>>();
+            let mut codeword = data.clone();
+            codeword.resize(FEC_RSM as usize, 0);
+            rs.encode(&data, &mut codeword[data.len()..]);
+            assert!(codeword[data.len()..].iter().any(|byte| *byte != 0));
+            assert!(syndromes(&rs, &codeword, roots)
+                .iter()
+                .all(|value| *value == 0));
+
+            codeword[3] ^= 1;
+            assert!(syndromes(&rs, &codeword, roots)
+                .iter()
+                .any(|value| *value != 0));
+
+            let mut parity = vec![0xff; usize::from(roots)];
+            rs.encode(&vec![0; data.len()], &mut parity);
+            assert!(parity.iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    /// Verify that the parity written for a formatted device makes a
+    /// codeword of each set of interleaved bytes of the data and hash
+    /// blocks, and that too few or too many blocks are rejected.
+    fn test_write_parity() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, hash, fec_path) = (
+            dir.path().join("data"),
+            dir.path().join("hash"),
+            dir.path().join("fec"),
+        );
+        let sb = VeritySuperblock::parse(&superblock()).unwrap();
+        let block_size = *sb.data_block_size as usize;
+        let contents = |blocks: usize, seed: usize| {
+            (0..blocks * block_size)
+                .map(|i| (i / 7 + seed) as u8)
+                .collect::<Vec<_>>()
+        };
+        let data_contents = contents(256, 0);
+        let mut hash_contents = superblock();
+        hash_contents.resize(block_size, 0);
+        hash_contents.extend(contents(3, 1));
+        fs::write(&data, &data_contents).unwrap();
+        fs::write(&hash, &hash_contents).unwrap();
+
+        let fec = sb.fec(Device::from_str("7:1").unwrap(), 2, 1).unwrap();
+        fs::write(&fec_path, vec![0u8; block_size]).unwrap();
+        fec.write_parity(&sb, &data, &hash, Bytes(0), &fec_path)
+            .unwrap();
+        let parity = fs::read(&fec_path).unwrap();
+        assert_eq!(
+            parity.len() as u128,
+            *sb.data_block_size + *fec.parity_size(sb.data_block_size)
+        );
+
+        // The blocks covered: the data blocks, then the hash tree, which
+        // follows the superblock.
+        let covered = [&data_contents[..], &hash_contents[block_size..]].concat();
+        let rs = ReedSolomon::new(fec.roots);
+        let rsn = (FEC_RSM - u64::from(fec.roots)) as usize;
+        let rounds = fec.rounds() as usize;
+        let roots = usize::from(fec.roots);
+        for (round, byte) in [(0, 0), (0, block_size - 1), (1, 17)] {
+            let mut codeword = (0..rsn)
+                .map(|i| {
+                    covered
+                        .get((round + i * rounds) * block_size + byte)
+                        .copied()
+                        .unwrap_or(0)
+                })
+                .collect::<Vec<_>>();
+            let offset = (1 + round * roots) * block_size + byte * roots;
+            codeword.extend(&parity[offset..offset + roots]);
+            assert!(syndromes(&rs, &codeword, fec.roots)
+                .iter()
+                .all(|value| *value == 0));
+        }
+
+        let short = VerityFec::new(fec.device, 2, 258, 1).unwrap();
+        assert_matches!(
+            short.write_parity(&sb, &data, &hash, Bytes(0), &fec_path),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        let long = VerityFec::new(fec.device, 2, fec.blocks + 1, 1).unwrap();
+        assert_matches!(
+            long.write_parity(&sb, &data, &hash, Bytes(0), &fec_path),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that a block which is not a verity superblock is rejected.
+    fn test_bad_superblock() {
+        let mut sb = superblock();
+        sb[0] = b'V';
+        assert_matches!(
+            VeritySuperblock::parse(&sb),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let mut sb = superblock();
+        sb[64..68].copy_from_slice(&1000u32.to_le_bytes());
+        assert_matches!(
+            VeritySuperblock::parse(&sb),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that optional args are parsed into typed fields where they
+    /// have them, and generated with the typed fields first.
+    fn test_optional_args() {
+        let params = "verity 1 8:1 8:2 4096 4096 100 1 sha256 00 - 4 ignore_zero_blocks root_hash_sig_key_desc key restart_on_corruption"
+            .parse::<VerityTargetParams>()
+            .unwrap();
+        assert_eq!(params.salt, None);
+        assert_eq!(params.corruption_mode, Some(VerityCorruptionMode::Restart));
+        assert!(params.ignore_zero_blocks);
+        assert!(!params.check_at_most_once);
+        assert_eq!(params.optional_args, vec!["root_hash_sig_key_desc", "key"]);
+        assert_eq!(
+            params.param_str(),
+            "1 8:1 8:2 4096 4096 100 1 sha256 00 - 4 restart_on_corruption ignore_zero_blocks root_hash_sig_key_desc key"
+        );
+
+        assert_matches!(
+            "verity 1 8:1 8:2 4096 4096 100 1 sha256 00 - 2 ignore_corruption panic_on_corruption"
+                .parse::<VerityTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[cfg(devicemapper41supported)]
+    #[test]
+    /// Verify that the typed optional args and FEC are checked against the
+    /// version of the verity target, and that unsupported args are cleared.
+    fn sudo_test_check_features() {
+        let _guard = TestGuard::new();
+        use crate::{core::DM, testing::TestGuard};
+
+        let mut params =
+            "verity 1 8:1 8:2 4096 4096 100 1 sha256 00 - 2 panic_on_corruption check_at_most_once"
+                .parse::<VerityTargetParams>()
+                .unwrap();
+        assert_eq!(
+            optional_arg_min_version(PANIC_ON_CORRUPTION_ARG),
+            Some(Version::new(1, 8, 0))
+        );
+        assert_eq!(
+            params.versioned_args(),
+            vec![PANIC_ON_CORRUPTION_ARG, CHECK_AT_MOST_ONCE_ARG]
+        );
+
+        let capabilities = DmCapabilities::new(&DM::new().unwrap()).unwrap();
+        match capabilities.target_version(VERITY_TARGET_NAME) {
+            Some(version) if *version < Version::new(1, 8, 0) => {
+                assert_matches!(params.check_features(&capabilities), Err(_));
+                params.remove_unsupported_features(&capabilities);
+                assert_eq!(params.corruption_mode, None);
+            }
+            _ => {
+                assert_matches!(params.check_features(&capabilities), Ok(()));
+            }
+        }
+    }
+}
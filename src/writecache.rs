@@ -0,0 +1,424 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Support for the writecache target, which caches writes, but not reads,
+// on persistent memory or on an SSD, and writes them back to the origin in
+// the background. Data written to the cache is dirty until it has been
+// written back, after which its block of the cache is freed, so the cache
+// is clean when every block is free. A cache must be clean before it is
+// detached from its origin, or the origin is missing the dirty data.
+
+use std::{
+    fmt,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    core::{DevId, Device, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{
+        get_status, get_status_line_fields, make_unexpected_value_error, parse_device, parse_value,
+        TargetLine, TargetParams, TargetTable, TargetTypeBuf,
+    },
+    units::{Bytes, Sectors},
+};
+
+const WRITECACHE_TARGET_NAME: &str = "writecache";
+
+/// The kind of device holding a writecache.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WritecacheMode {
+    /// Persistent memory, accessed with DAX ('p')
+    Pmem,
+    /// A block device, such as an SSD ('s')
+    Ssd,
+}
+
+impl WritecacheMode {
+    /// The mode as it appears in a table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WritecacheMode::Pmem => "p",
+            WritecacheMode::Ssd => "s",
+        }
+    }
+}
+
+impl FromStr for WritecacheMode {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<WritecacheMode> {
+        match s {
+            "p" => Ok(WritecacheMode::Pmem),
+            "s" => Ok(WritecacheMode::Ssd),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown writecache mode \"{s}\""),
+            )),
+        }
+    }
+}
+
+/// Target params for writecache target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WritecacheTargetParams {
+    /// The kind of the cache device
+    pub mode: WritecacheMode,
+    /// The device to which the cached writes are written back
+    pub origin: Device,
+    /// The device holding the cache
+    pub cache: Device,
+    /// The size of a block of the cache, usually the page size
+    pub block_size: Bytes,
+    /// The optional params, e.g., "high_watermark 50"; params which take a
+    /// value are given as two entries, as they are counted by the kernel
+    pub optional_args: Vec<String>,
+}
+
+impl WritecacheTargetParams {
+    /// Create a new WritecacheTargetParams struct
+    pub fn new(
+        mode: WritecacheMode,
+        origin: Device,
+        cache: Device,
+        block_size: Bytes,
+    ) -> WritecacheTargetParams {
+        WritecacheTargetParams {
+            mode,
+            origin,
+            cache,
+            block_size,
+            optional_args: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for WritecacheTargetParams {
+    /// Generate params to be passed to DM.  The format of the params is:
+    ///
+    /// ```plain
+    /// <p|s> <origin> <cache> <block size> <#opt params> [<opt params>]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", WRITECACHE_TARGET_NAME, self.param_str())
+    }
+}
+
+impl FromStr for WritecacheTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<WritecacheTargetParams> {
+        let vals = s.split(' ').collect::<Vec<_>>();
+        if vals.len() < 6 {
+            let err_msg = format!(
+                "expected at least 6 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != WRITECACHE_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a writecache target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut params = WritecacheTargetParams::new(
+            vals[1].parse::<WritecacheMode>()?,
+            parse_device(vals[2], "origin device for writecache target")?,
+            parse_device(vals[3], "cache device for writecache target")?,
+            Bytes(parse_value(vals[4], "writecache block size")?),
+        );
+
+        let count: usize = parse_value(vals[5], "number of optional params")?;
+        if vals.len() != 6 + count {
+            let err_msg = format!(
+                "expected {} optional params in params string \"{}\", found {}",
+                count,
+                s,
+                vals.len() - 6
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        params.optional_args = vals[6..].iter().map(|arg| arg.to_string()).collect();
+
+        Ok(params)
+    }
+}
+
+impl TargetParams for WritecacheTargetParams {
+    fn param_str(&self) -> String {
+        let mut params = format!(
+            "{} {} {} {} {}",
+            self.mode.as_str(),
+            self.origin,
+            self.cache,
+            *self.block_size,
+            self.optional_args.len()
+        );
+        if !self.optional_args.is_empty() {
+            params.push_str(&format!(" {}", self.optional_args.join(" ")));
+        }
+        params
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetTypeBuf::new(WRITECACHE_TARGET_NAME.into()).expect("WRITECACHE_TARGET_NAME is valid")
+    }
+}
+
+/// A target table for a writecache device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WritecacheDevTargetTable {
+    /// The device's table
+    pub table: TargetLine<WritecacheTargetParams>,
+}
+
+impl WritecacheDevTargetTable {
+    /// Make a new WritecacheDevTargetTable from required input
+    pub fn new(
+        start: Sectors,
+        length: Sectors,
+        params: WritecacheTargetParams,
+    ) -> WritecacheDevTargetTable {
+        WritecacheDevTargetTable {
+            table: TargetLine::new(start, length, params),
+        }
+    }
+}
+
+impl fmt::Display for WritecacheDevTargetTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = &self.table;
+        writeln!(f, "{} {} {}", *table.start, *table.length, table.params)
+    }
+}
+
+impl TargetTable for WritecacheDevTargetTable {
+    fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<WritecacheDevTargetTable> {
+        if table.len() != 1 {
+            let err_msg = format!(
+                "WritecacheDev table should have exactly one line, has {} lines",
+                table.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let line = table.first().expect("table.len() == 1");
+        Ok(WritecacheDevTargetTable::new(
+            Sectors(line.0),
+            Sectors(line.1),
+            format!("{} {}", line.2, line.3).parse::<WritecacheTargetParams>()?,
+        ))
+    }
+
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        to_raw_table_unique!(self)
+    }
+}
+
+/// Status of a writecache target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WritecacheStatus {
+    /// The error with which the cache failed, as a negative errno, or 0
+    pub error: i32,
+    /// The number of blocks of the cache
+    pub total_blocks: u64,
+    /// The number of blocks of the cache which hold no data
+    pub free_blocks: u64,
+    /// The number of blocks being written back
+    pub writeback_blocks: u64,
+}
+
+impl WritecacheStatus {
+    /// The number of blocks holding data which has not been written back
+    /// to the origin.
+    pub fn dirty_blocks(&self) -> u64 {
+        self.total_blocks.saturating_sub(self.free_blocks)
+    }
+}
+
+impl FromStr for WritecacheStatus {
+    type Err = DmError;
+
+    /// Parse a status line of the form:
+    ///
+    /// ```plain
+    /// <error> <#total blocks> <#free blocks> <#writeback blocks> [<statistics>]
+    /// ```
+    ///
+    /// The statistics of newer kernels are ignored.
+    fn from_str(status_line: &str) -> DmResult<WritecacheStatus> {
+        let status_vals = get_status_line_fields(status_line, 4)?;
+        let status = WritecacheStatus {
+            error: parse_value(status_vals[0], "writecache error")?,
+            total_blocks: parse_value(status_vals[1], "writecache total blocks")?,
+            free_blocks: parse_value(status_vals[2], "writecache free blocks")?,
+            writeback_blocks: parse_value(status_vals[3], "writecache writeback blocks")?,
+        };
+        if status.free_blocks > status.total_blocks {
+            return Err(make_unexpected_value_error(
+                3,
+                status_vals[2],
+                "writecache free blocks",
+            ));
+        }
+        Ok(status)
+    }
+}
+
+/// The status of the writecache device `id`, which has a single writecache
+/// target.
+pub fn writecache_status(dm: &DM, id: &DevId<'_>) -> DmResult<WritecacheStatus> {
+    let (_, status) = dm.table_status(id, DmOptions::default())?;
+    get_status(&status)?.parse()
+}
+
+/// A message to a writecache target.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WritecacheMessage {
+    /// Commit the data written to the cache, and write back all the dirty
+    /// blocks, waiting until they have been written back
+    Flush,
+    /// Write back all the dirty blocks when the device is next suspended
+    FlushOnSuspend,
+}
+
+impl fmt::Display for WritecacheMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WritecacheMessage::Flush => "flush",
+            WritecacheMessage::FlushOnSuspend => "flush_on_suspend",
+        })
+    }
+}
+
+/// Send `message` to the writecache device `id`.
+pub fn writecache_message(dm: &DM, id: &DevId<'_>, message: WritecacheMessage) -> DmResult<()> {
+    dm.target_msg(id, None, &message.to_string())?;
+    Ok(())
+}
+
+/// Wait until the writecache device `id` is clean, that is, until its
+/// status reports no dirty blocks, so that the origin holds all the data
+/// and the cache may be detached or the system shut down. The status is
+/// polled every `interval`; `progress` is called with each status
+/// sampled. Returns an error if the cache has failed, or if dirty blocks
+/// remain after `timeout`.
+///
+/// The cache writes back only as much as its watermarks require, so the
+/// caller should first make it write back everything, e.g., with
+/// `WritecacheMessage::Flush`.
+pub fn writecache_wait_clean<F>(
+    dm: &DM,
+    id: &DevId<'_>,
+    interval: Duration,
+    timeout: Duration,
+    mut progress: F,
+) -> DmResult<WritecacheStatus>
+where
+    F: FnMut(&WritecacheStatus),
+{
+    let start = Instant::now();
+    loop {
+        let status = writecache_status(dm, id)?;
+        progress(&status);
+        if status.error != 0 {
+            return Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("writecache {id} has failed with error {}", status.error),
+            ));
+        }
+        if status.dirty_blocks() == 0 {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            return Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!(
+                    "writecache {} still has {} dirty blocks after {:?}",
+                    id,
+                    status.dirty_blocks(),
+                    timeout
+                ),
+            ));
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that writecache params are parsed and printed.
+    fn test_writecache_target_params() {
+        let s = "writecache s 8:16 8:32 4096 4 high_watermark 50 writeback_jobs 1024";
+        let params = s.parse::<WritecacheTargetParams>().unwrap();
+        assert_eq!(params.mode, WritecacheMode::Ssd);
+        assert_eq!(params.block_size, Bytes(4096));
+        assert_eq!(params.optional_args.len(), 4);
+        assert_eq!(params.to_string(), s);
+
+        let params = WritecacheTargetParams::new(
+            WritecacheMode::Pmem,
+            Device::from_str("8:16").unwrap(),
+            Device::from_str("259:0").unwrap(),
+            Bytes(4096),
+        );
+        assert_eq!(params.param_str(), "p 8:16 259:0 4096 0");
+        assert_eq!(
+            params
+                .to_string()
+                .parse::<WritecacheTargetParams>()
+                .unwrap(),
+            params
+        );
+
+        assert_matches!(
+            "writecache x 8:16 8:32 4096 0".parse::<WritecacheTargetParams>(),
+            Err(_)
+        );
+        assert_matches!(
+            "writecache s 8:16 8:32 4096 2 fua".parse::<WritecacheTargetParams>(),
+            Err(_)
+        );
+    }
+
+    #[test]
+    /// Verify that the status is parsed, with or without statistics, and
+    /// the dirty blocks counted.
+    fn test_writecache_status() {
+        let status = "0 1024 1000 8".parse::<WritecacheStatus>().unwrap();
+        assert_eq!(status.dirty_blocks(), 24);
+        assert_eq!(status.writeback_blocks, 8);
+
+        let status = "0 1024 1024 0 10 5 20 4 0 0 0 0 0 0"
+            .parse::<WritecacheStatus>()
+            .unwrap();
+        assert_eq!(status.dirty_blocks(), 0);
+
+        assert_eq!(
+            "-5 1024 1024 0".parse::<WritecacheStatus>().unwrap().error,
+            -5
+        );
+        assert_matches!("0 1024 2048 0".parse::<WritecacheStatus>(), Err(_));
+        assert_matches!("0 1024".parse::<WritecacheStatus>(), Err(_));
+    }
+
+    #[test]
+    /// Verify the text of the messages.
+    fn test_writecache_message() {
+        assert_eq!(WritecacheMessage::Flush.to_string(), "flush");
+        assert_eq!(
+            WritecacheMessage::FlushOnSuspend.to_string(),
+            "flush_on_suspend"
+        );
+    }
+}